@@ -0,0 +1,176 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+
+/// Output is compressed a block at a time once a block holds this many
+/// bytes, when `threshold` is not set
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 256 * 1024;
+
+/// zstd's level when `level` is not set; fast, and still several times
+/// smaller for terminal output
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// How output a daemon is holding on to gets compressed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// Smaller; the default
+    #[default]
+    Zstd,
+    /// Faster, but not as small
+    Lz4,
+    /// Not compressed at all
+    None,
+}
+
+/// The `compression` setting in config.json. Having it at all turns
+/// compression on; `"compression": {}` uses zstd with the defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub codec: Codec,
+    /// Compress scrollback in blocks of this many bytes (default: 256 KiB)
+    pub threshold: Option<usize>,
+    /// zstd level, 1 to 22 (default: 3)
+    pub level: Option<i32>,
+}
+
+/// A codec with its settings resolved, for compressing blocks of output
+/// that are no longer being written to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compressor {
+    pub codec: Codec,
+    pub threshold: usize,
+    pub level: i32,
+}
+
+impl Compressor {
+    /// The compressor `config` asks for, or `None` if it compresses nothing
+    pub fn from_config(config: &CompressionConfig) -> Option<Self> {
+        (config.codec != Codec::None).then(|| Self {
+            codec: config.codec,
+            threshold: config
+                .threshold
+                .unwrap_or(DEFAULT_COMPRESSION_THRESHOLD)
+                .max(1),
+            level: config.level.unwrap_or(DEFAULT_ZSTD_LEVEL),
+        })
+    }
+
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self.codec {
+            Codec::Zstd => zstd::bulk::compress(data, self.level),
+            Codec::Lz4 => Ok(lz4_flex::compress(data)),
+            Codec::None => Ok(data.to_vec()),
+        }
+    }
+
+    /// Undo `compress`, given how big the data was to begin with
+    pub fn decompress(&self, data: &[u8], size: usize) -> io::Result<Vec<u8>> {
+        match self.codec {
+            Codec::Zstd => zstd::bulk::decompress(data, size),
+            Codec::Lz4 => lz4_flex::decompress(data, size)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Codec::None => Ok(data.to_vec()),
+        }
+    }
+}
+
+/// Bytes that may be compressed, along with how big they really are
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packed {
+    Plain(Vec<u8>),
+    Compressed { data: Vec<u8>, size: usize },
+}
+
+impl Default for Packed {
+    fn default() -> Self {
+        Packed::Plain(Vec::new())
+    }
+}
+
+impl Packed {
+    /// Compress `data` if that makes it smaller
+    pub fn pack(data: Vec<u8>, compressor: &Compressor) -> Self {
+        match compressor.compress(&data) {
+            Ok(compressed) if compressed.len() < data.len() => Packed::Compressed {
+                data: compressed,
+                size: data.len(),
+            },
+            _ => Packed::Plain(data),
+        }
+    }
+
+    /// The bytes, decompressed with `compressor` if they were compressed
+    pub fn unpack(self, compressor: Option<&Compressor>) -> io::Result<Vec<u8>> {
+        match self {
+            Packed::Plain(data) => Ok(data),
+            Packed::Compressed { data, size } => unpack(&data, size, compressor),
+        }
+    }
+
+    /// A copy of the bytes, decompressed if need be
+    pub fn to_vec(&self, compressor: Option<&Compressor>) -> io::Result<Vec<u8>> {
+        match self {
+            Packed::Plain(data) => Ok(data.clone()),
+            Packed::Compressed { data, size } => unpack(data, *size, compressor),
+        }
+    }
+
+    /// Bytes before compression
+    pub fn size(&self) -> usize {
+        match self {
+            Packed::Plain(data) => data.len(),
+            Packed::Compressed { size, .. } => *size,
+        }
+    }
+
+    /// Bytes taken up in memory
+    pub fn stored(&self) -> usize {
+        match self {
+            Packed::Plain(data) => data.len(),
+            Packed::Compressed { data, .. } => data.len(),
+        }
+    }
+}
+
+fn unpack(data: &[u8], size: usize, compressor: Option<&Compressor>) -> io::Result<Vec<u8>> {
+    let compressor = compressor
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "compressed without a codec"))?;
+    compressor.decompress(data, size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_with_each_codec() {
+        let data = "total 48\r\ndrwxr-xr-x  2 root root 4096 .\r\n".repeat(200);
+        for codec in [Codec::Zstd, Codec::Lz4] {
+            let compressor = Compressor::from_config(&CompressionConfig {
+                codec,
+                ..Default::default()
+            })
+            .unwrap();
+            let packed = Packed::pack(data.clone().into_bytes(), &compressor);
+            assert!(packed.stored() < data.len() / 4, "{:?}", codec);
+            assert_eq!(packed.size(), data.len());
+            assert_eq!(packed.to_vec(Some(&compressor)).unwrap(), data.as_bytes());
+            assert_eq!(packed.unpack(Some(&compressor)).unwrap(), data.as_bytes());
+        }
+    }
+
+    #[test]
+    fn keeps_what_does_not_shrink() {
+        let compressor = Compressor::from_config(&CompressionConfig::default()).unwrap();
+        assert_eq!(
+            Packed::pack(b"ab".to_vec(), &compressor),
+            Packed::Plain(b"ab".to_vec())
+        );
+        let off = CompressionConfig {
+            codec: Codec::None,
+            ..Default::default()
+        };
+        assert_eq!(Compressor::from_config(&off), None);
+    }
+}
@@ -0,0 +1,215 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::compression::CompressionConfig;
+use crate::error::{NdsError, Result};
+use crate::history_v2::HistoryConfig;
+use crate::hooks::Hooks;
+use crate::keymap::KeyMap;
+use crate::pty::{ClipboardConfig, ResizePolicy};
+use crate::pty_buffer::BufferConfig;
+use crate::remote::RemoteConfig;
+use crate::resolve::ResolveOptions;
+use crate::session_log::LogConfig;
+use crate::session_sort::ListConfig;
+use crate::ui::Theme;
+
+/// Variables that go stale when you reconnect from a new login, refreshed on
+/// attach when `update_environment` is not set. The same list as tmux's.
+pub const DEFAULT_UPDATE_ENVIRONMENT: &[&str] = &[
+    "DISPLAY",
+    "KRB5CCNAME",
+    "SSH_ASKPASS",
+    "SSH_AUTH_SOCK",
+    "SSH_AGENT_PID",
+    "SSH_CONNECTION",
+    "WINDOWID",
+    "XAUTHORITY",
+];
+
+/// User settings read from `~/.nds/config.json`. Every field is optional so
+/// NDS keeps working with no config file at all.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Shell for new sessions when `--shell` and `NDS_SHELL` are not given
+    pub default_shell: Option<String>,
+    /// How new sessions size the PTY for several clients when `--resize` is
+    /// not given (default: smallest)
+    pub resize_policy: Option<ResizePolicy>,
+    /// Bytes of output each session's daemon keeps for the scrollback
+    /// viewer (default: 10 MiB)
+    pub scrollback_limit: Option<usize>,
+    /// How much output each session's daemon keeps, in memory and spilled
+    /// to disk, for the next client to attach (default: 2 MiB in memory)
+    pub output_buffer: Option<BufferConfig>,
+    /// Compress output the daemon holds on to once it is no longer being
+    /// written to; off unless set
+    pub compression: Option<CompressionConfig>,
+    /// What programs may do with clients' clipboards through OSC 52
+    /// (default: pass writes of up to 1 MiB on)
+    pub clipboard: Option<ClipboardConfig>,
+    /// Log every session's output to `~/.nds/logs/<session>.log` for
+    /// `nds logs`; off unless set
+    pub logging: Option<LogConfig>,
+    /// Remove the history of sessions that ended long ago, once a day;
+    /// kept forever unless set
+    pub history: Option<HistoryConfig>,
+    /// Variables such as `SSH_AUTH_SOCK` and `DISPLAY` taken from the
+    /// terminal on every attach and pushed into the session's shell, like
+    /// tmux's `update-environment` (default: `DEFAULT_UPDATE_ENVIRONMENT`)
+    pub update_environment: Option<Vec<String>>,
+    /// Other users, by name or uid, whose connections sessions' daemons
+    /// accept; only your own (and root's) otherwise. The socket directory's
+    /// permissions have to let them in as well.
+    pub allow_users: Option<Vec<String>>,
+    /// Have new sessions' daemons listen on Linux abstract-namespace
+    /// sockets, which leave no file behind to go stale, instead of socket
+    /// files; off unless set
+    pub abstract_sockets: bool,
+    /// TLS certificate and key for sessions started with `--listen`; a
+    /// self-signed pair is made if unset
+    pub remote: Option<RemoteConfig>,
+    /// How session arguments are matched (see `ResolveOptions`)
+    pub matching: ResolveOptions,
+    /// How `nds list` and the picker order sessions (see `ListConfig`)
+    pub list: ListConfig,
+    /// Colors and icons of the picker, the switcher and the scrollback
+    /// viewer (see `Theme`)
+    pub theme: Theme,
+    /// Keys of the escapes, the picker and the scrollback viewer (see
+    /// `KeyMap`)
+    pub keymap: KeyMap,
+    /// Shell commands run when sessions are created, attached to, exit and
+    /// so on (see `Hook`)
+    pub hooks: Hooks,
+}
+
+impl Config {
+    pub fn path() -> Result<PathBuf> {
+        let dir = if let Ok(nds_home) = std::env::var("NDS_HOME") {
+            PathBuf::from(nds_home)
+        } else {
+            directories::BaseDirs::new()
+                .ok_or_else(|| {
+                    NdsError::DirectoryCreationError("Could not find home directory".to_string())
+                })?
+                .home_dir()
+                .join(".nds")
+        };
+        Ok(dir.join("config.json"))
+    }
+
+    pub fn load() -> Result<Self> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content = fs::read_to_string(&path)?;
+        let config: Self = serde_json::from_str(&content)
+            .map_err(|e| NdsError::ConfigError(format!("{}: {}", path.display(), e)))?;
+        let keep_days = config.history.as_ref().and_then(|h| h.keep_days);
+        if let Some(days) = keep_days.filter(|days| *days < 0) {
+            return Err(NdsError::ConfigError(format!(
+                "{}: history.keep_days must not be negative, got {}",
+                path.display(),
+                days
+            )));
+        }
+        Ok(config)
+    }
+
+    /// Set one top-level setting in the config file, leaving the rest of
+    /// the file as it is
+    pub fn save_setting<T: Serialize>(key: &str, value: &T) -> Result<()> {
+        let path = Self::path()?;
+        let mut config = if path.exists() {
+            let content = fs::read_to_string(&path)?;
+            serde_json::from_str(&content)
+                .map_err(|e| NdsError::ConfigError(format!("{}: {}", path.display(), e)))?
+        } else {
+            serde_json::Value::Object(Default::default())
+        };
+        let Some(settings) = config.as_object_mut() else {
+            return Err(NdsError::ConfigError(format!(
+                "{}: not a JSON object",
+                path.display()
+            )));
+        };
+        let value =
+            serde_json::to_value(value).map_err(|e| NdsError::ConfigError(e.to_string()))?;
+        settings.insert(key.to_string(), value);
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let content = serde_json::to_string_pretty(&config)
+            .map_err(|e| NdsError::ConfigError(e.to_string()))?;
+        // Write beside the file and rename over it, so every other nds
+        // command reading it meanwhile sees either the old file or the new
+        let tmp_path = path.with_extension(format!("json.{}.tmp", std::process::id()));
+        fs::write(&tmp_path, content + "\n")?;
+        if let Ok(metadata) = fs::metadata(&path) {
+            fs::set_permissions(&tmp_path, metadata.permissions())?;
+        }
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// Variables to refresh from the attaching terminal
+    pub fn update_environment(&self) -> Vec<String> {
+        match &self.update_environment {
+            Some(vars) => vars.clone(),
+            None => DEFAULT_UPDATE_ENVIRONMENT
+                .iter()
+                .map(|var| var.to_string())
+                .collect(),
+        }
+    }
+
+    /// Shell to exec for a new session: `--shell`, then `NDS_SHELL`, then the
+    /// configured default, then the creator's `$SHELL`, then `/bin/sh`
+    pub fn resolve_shell(&self, explicit: Option<&str>) -> String {
+        explicit
+            .map(str::to_string)
+            .or_else(|| non_empty_var("NDS_SHELL"))
+            .or_else(|| self.default_shell.clone())
+            .or_else(|| non_empty_var("SHELL"))
+            .unwrap_or_else(|| "/bin/sh".to_string())
+    }
+}
+
+fn non_empty_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+/// Check that `shell` names an executable, either as a path or on `$PATH`
+pub fn validate_shell(shell: &str) -> Result<()> {
+    if is_executable(shell) {
+        Ok(())
+    } else {
+        Err(NdsError::ShellNotFound(shell.to_string()))
+    }
+}
+
+/// Whether `program` is an executable file, either as a path or on `$PATH`
+pub fn is_executable(program: &str) -> bool {
+    let is_executable_file = |path: &std::path::Path| {
+        use std::os::unix::fs::PermissionsExt;
+        fs::metadata(path)
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    };
+
+    if program.contains('/') {
+        is_executable_file(std::path::Path::new(program))
+    } else {
+        std::env::var_os("PATH")
+            .map(|paths| {
+                std::env::split_paths(&paths).any(|dir| is_executable_file(&dir.join(program)))
+            })
+            .unwrap_or(false)
+    }
+}
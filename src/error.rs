@@ -9,6 +9,12 @@ pub enum NdsError {
     #[error("Session not found: {0}")]
     SessionNotFound(String),
 
+    #[error("{} sessions match '{query}'; be more specific", matches.len())]
+    AmbiguousSession {
+        query: String,
+        matches: Vec<crate::session::Session>,
+    },
+
     #[error("Session already exists: {0}")]
     SessionAlreadyExists(String),
 
@@ -30,9 +36,42 @@ pub enum NdsError {
     #[error("Invalid session ID: {0}")]
     InvalidSessionId(String),
 
+    #[error("Invalid session name: {0}")]
+    InvalidSessionName(String),
+
+    #[error("Shell not found or not executable: {0}")]
+    ShellNotFound(String),
+
+    #[error("Command not found or not executable: {0}")]
+    CommandNotFound(String),
+
+    #[error("Invalid configuration: {0}")]
+    ConfigError(String),
+
+    #[error("Self-test failed: {0}")]
+    SelfTestFailed(String),
+
+    #[error("Invalid tag: {0}")]
+    InvalidTag(String),
+
+    #[error("Invalid group: {0}")]
+    InvalidGroup(String),
+
+    #[error("Invalid environment: {0}")]
+    InvalidEnvironment(String),
+
     #[error("Session is already attached")]
     SessionAlreadyAttached,
 
+    #[error("Client not found: {0}")]
+    ClientNotFound(String),
+
+    #[error("Window not found: {0}")]
+    WindowNotFound(String),
+
+    #[error("Not inside an nds session (NDS_SESSION_ID is not set)")]
+    NotInSession,
+
     #[error("Failed to create session directory: {0}")]
     DirectoryCreationError(String),
 
@@ -44,6 +83,24 @@ pub enum NdsError {
 
     #[error("Process error: {0}")]
     ProcessError(String),
+
+    #[error("Recording error: {0}")]
+    RecordingError(String),
+
+    #[error("History error: {0}")]
+    HistoryError(String),
+
+    #[error("Remote error: {0}")]
+    RemoteError(String),
+
+    #[error("Server error: {0}")]
+    ServerError(String),
+}
+
+impl From<rusqlite::Error> for NdsError {
+    fn from(e: rusqlite::Error) -> Self {
+        NdsError::HistoryError(e.to_string())
+    }
 }
 
 pub type Result<T> = std::result::Result<T, NdsError>;
@@ -0,0 +1,170 @@
+//! Fuzzy matching for the `/` filter of the session picker and switcher.
+//!
+//! A query matches text that has the query's characters in the same order,
+//! ignoring case, with anything in between. Matches score higher the more of
+//! their characters run together or start words, and the earlier they
+//! start, so `bf` ranks `build-fix` above `abuffer`.
+
+use ratatui::{style::Style, text::Span};
+
+use crate::session::Session;
+
+/// Points for each character matched
+const CHAR_SCORE: i32 = 16;
+/// Extra points for a character right after the one matched before it
+const RUN_BONUS: i32 = 8;
+/// Extra points for a character that starts a word
+const WORD_BONUS: i32 = 10;
+
+/// Where a query matched some text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuzzyMatch {
+    pub score: i32,
+    /// Indices of the matched characters in the text, in characters
+    pub positions: Vec<usize>,
+}
+
+/// Match `query` against `text`; an empty query matches anything
+pub fn fuzzy_match(query: &str, text: &str) -> Option<FuzzyMatch> {
+    let query: Vec<char> = query.chars().flat_map(char::to_lowercase).collect();
+    let chars: Vec<char> = text.chars().collect();
+    let lower: Vec<char> = chars
+        .iter()
+        .map(|c| c.to_lowercase().next().unwrap_or(*c))
+        .collect();
+
+    // The first place the whole query fits, then the tightest run of text
+    // ending there that still holds it
+    let mut end = 0;
+    let mut matched = 0;
+    while matched < query.len() {
+        let found = lower[end..].iter().position(|&c| c == query[matched])?;
+        end += found + 1;
+        matched += 1;
+    }
+    let mut positions = vec![0; query.len()];
+    let mut at = end;
+    for (slot, wanted) in positions.iter_mut().zip(&query).rev() {
+        at = lower[..at].iter().rposition(|c| c == wanted)?;
+        *slot = at;
+    }
+
+    let score = score(&chars, &positions);
+    Some(FuzzyMatch { score, positions })
+}
+
+fn score(chars: &[char], positions: &[usize]) -> i32 {
+    let mut score = 0;
+    for (n, &at) in positions.iter().enumerate() {
+        score += CHAR_SCORE;
+        if n > 0 && positions[n - 1] + 1 == at {
+            score += RUN_BONUS;
+        }
+        let starts_word = match at.checked_sub(1).map(|before| chars[before]) {
+            None => true,
+            Some(before) => {
+                !before.is_alphanumeric() || (before.is_lowercase() && chars[at].is_uppercase())
+            }
+        };
+        if starts_word {
+            score += WORD_BONUS;
+        }
+    }
+    if let (Some(first), Some(last)) = (positions.first(), positions.last()) {
+        let gaps = (last - first + 1 - positions.len()) as i32;
+        score -= gaps + (*first as i32).min(10);
+    }
+    score
+}
+
+/// Which of a session's fields a filter matched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionField {
+    Name,
+    Id,
+    WorkingDir,
+    /// What is running in the foreground, as the picker shows it
+    Command,
+}
+
+/// Where a filter matched a session: its best field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionMatch {
+    pub field: SessionField,
+    pub score: i32,
+    pub positions: Vec<usize>,
+}
+
+impl SessionMatch {
+    /// The matched characters in the session's `display_name`, if the name
+    /// or ID matched
+    pub fn display_name_positions(&self, session: &Session) -> Vec<usize> {
+        let offset = match (self.field, &session.name) {
+            (SessionField::Name, _) | (SessionField::Id, None) => 0,
+            // After "name ["
+            (SessionField::Id, Some(name)) => name.chars().count() + 2,
+            _ => return Vec::new(),
+        };
+        self.positions.iter().map(|at| at + offset).collect()
+    }
+
+    /// The matched characters in `field`, if that is what matched
+    pub fn positions_in(&self, field: SessionField) -> &[usize] {
+        if self.field == field {
+            &self.positions
+        } else {
+            &[]
+        }
+    }
+}
+
+/// Match `query` against a session's name, ID, working directory and
+/// `command`, keeping the best; the name wins ties
+pub fn match_session(query: &str, session: &Session, command: &str) -> Option<SessionMatch> {
+    let fields = [
+        (SessionField::Name, session.name.as_deref().unwrap_or("")),
+        (SessionField::Id, session.id.as_str()),
+        (SessionField::WorkingDir, session.working_dir.as_str()),
+        (SessionField::Command, command),
+    ];
+    let mut best: Option<SessionMatch> = None;
+    for (field, text) in fields {
+        let Some(found) = fuzzy_match(query, text) else {
+            continue;
+        };
+        if best.as_ref().is_none_or(|best| found.score > best.score) {
+            best = Some(SessionMatch {
+                field,
+                score: found.score,
+                positions: found.positions,
+            });
+        }
+    }
+    best
+}
+
+/// `text` as spans in `style`, with the characters at `positions` in
+/// `matched` instead
+pub fn highlight(
+    text: &str,
+    positions: &[usize],
+    style: Style,
+    matched: Style,
+) -> Vec<Span<'static>> {
+    let mut spans = Vec::new();
+    let mut run = String::new();
+    let mut run_matched = false;
+    for (at, c) in text.chars().enumerate() {
+        let is_matched = positions.contains(&at);
+        if is_matched != run_matched && !run.is_empty() {
+            let style = if run_matched { matched } else { style };
+            spans.push(Span::styled(std::mem::take(&mut run), style));
+        }
+        run_matched = is_matched;
+        run.push(c);
+    }
+    if !run.is_empty() {
+        spans.push(Span::styled(run, if run_matched { matched } else { style }));
+    }
+    spans
+}
@@ -1,144 +1,78 @@
-use detached_shell::{NdsError, Result, SessionManager};
-use std::io::Write;
-use std::os::unix::net::UnixStream;
+use chrono::{Local, Utc};
+use detached_shell::pty::user_name;
+use detached_shell::{NdsError, Result, SessionHistory, SessionManager};
+
+use super::session::find_session;
 
 /// List all clients connected to a session
 pub fn handle_list_clients(session_id_or_name: &str) -> Result<()> {
-    // Find the session
-    let sessions = SessionManager::list_sessions()?;
-
-    let matching_sessions: Vec<_> = sessions
-        .iter()
-        .filter(|s| {
-            s.id.starts_with(session_id_or_name)
-                || s.name
-                    .as_ref()
-                    .map_or(false, |n| n.starts_with(session_id_or_name))
-        })
-        .collect();
+    let session = find_session(session_id_or_name)?;
+    let clients = SessionManager::session_clients(&session.id)?;
 
-    match matching_sessions.len() {
-        0 => {
-            eprintln!("No session found matching: {}", session_id_or_name);
-            Err(NdsError::SessionNotFound(session_id_or_name.to_string()))
-        }
-        1 => {
-            let session = matching_sessions[0];
-            println!("Clients connected to session {}:", session.display_name());
-            println!();
+    if clients.is_empty() {
+        println!("No clients attached to session {}", session.display_name());
+        return Ok(());
+    }
 
-            // Send a command to the session to list clients
-            match send_client_command(&session.socket_path, "list_clients") {
-                Ok(response) => {
-                    println!("{}", response);
-                }
-                Err(e) => {
-                    eprintln!("Failed to get client list: {}", e);
-                    eprintln!("Session may not support client management yet.");
-                }
-            }
-            Ok(())
-        }
-        _ => {
-            eprintln!(
-                "Multiple sessions match '{}'. Please be more specific:",
-                session_id_or_name
-            );
-            for session in matching_sessions {
-                eprintln!("  - {}", session.display_name());
-            }
-            Err(NdsError::InvalidSessionId(session_id_or_name.to_string()))
-        }
+    println!("Clients attached to session {}:", session.display_name());
+    println!();
+    println!(
+        "  {:<10} {:<10} {:<14} {:<18} {:<22} INPUT",
+        "ID", "SIZE", "TTY", "USER (PID)", "CONNECTED"
+    );
+    for client in clients {
+        let connected_for = (Utc::now() - client.connected_at).num_seconds();
+        let connected = format!(
+            "{} ({} ago)",
+            client.connected_at.with_timezone(&Local).format("%H:%M:%S"),
+            SessionHistory::format_duration(connected_for)
+        );
+        let user = match (client.uid, client.pid) {
+            (Some(uid), Some(pid)) => format!("{} ({})", user_name(uid), pid),
+            (Some(uid), None) => user_name(uid),
+            _ => "-".to_string(),
+        };
+        let guest = client
+            .guest
+            .map(|role| format!(" [{} guest]", role))
+            .unwrap_or_default();
+        println!(
+            "  {:<10} {:<10} {:<14} {:<18} {:<22} {} B/min{}{}{}",
+            client.id,
+            format!("{}x{}", client.cols, client.rows),
+            client.tty.as_deref().unwrap_or("-"),
+            user,
+            connected,
+            client.input_bytes_per_minute,
+            if client.input_flagged {
+                " [HIGH INPUT]"
+            } else {
+                ""
+            },
+            guest,
+            if client.broadcast { " [BROADCAST]" } else { "" }
+        );
     }
+    Ok(())
 }
 
 /// Disconnect a specific client from a session
 pub fn handle_disconnect_client(session_id_or_name: &str, client_id: &str) -> Result<()> {
-    // Find the session
-    let sessions = SessionManager::list_sessions()?;
-
-    let matching_sessions: Vec<_> = sessions
-        .iter()
-        .filter(|s| {
-            s.id.starts_with(session_id_or_name)
-                || s.name
-                    .as_ref()
-                    .map_or(false, |n| n.starts_with(session_id_or_name))
-        })
-        .collect();
-
-    match matching_sessions.len() {
-        0 => {
-            eprintln!("No session found matching: {}", session_id_or_name);
-            Err(NdsError::SessionNotFound(session_id_or_name.to_string()))
-        }
-        1 => {
-            let session = matching_sessions[0];
-            println!(
-                "Disconnecting client {} from session {}...",
-                client_id,
-                session.display_name()
-            );
-
-            // Send disconnect command to the session
-            let command = format!("disconnect_client:{}", client_id);
-            match send_client_command(&session.socket_path, &command) {
-                Ok(response) => {
-                    println!("{}", response);
-                }
-                Err(e) => {
-                    eprintln!("Failed to disconnect client: {}", e);
-                }
-            }
-            Ok(())
-        }
-        _ => {
-            eprintln!(
-                "Multiple sessions match '{}'. Please be more specific:",
-                session_id_or_name
-            );
-            for session in matching_sessions {
-                eprintln!("  - {}", session.display_name());
-            }
-            Err(NdsError::InvalidSessionId(session_id_or_name.to_string()))
-        }
+    let session = find_session(session_id_or_name)?;
+
+    if !SessionManager::disconnect_client(&session.id, client_id)? {
+        eprintln!(
+            "No client {} attached to session {} (see 'nds clients list')",
+            client_id,
+            session.display_name()
+        );
+        return Err(NdsError::ClientNotFound(client_id.to_string()));
     }
-}
-
-/// Send a command to the session for client management
-fn send_client_command(socket_path: &std::path::Path, command: &str) -> Result<String> {
-    use std::time::Duration;
 
-    let mut socket = UnixStream::connect(socket_path)
-        .map_err(|e| NdsError::SocketError(format!("Failed to connect to session: {}", e)))?;
-
-    // Set timeout
-    socket.set_read_timeout(Some(Duration::from_secs(2)))?;
-    socket.set_write_timeout(Some(Duration::from_secs(2)))?;
-
-    // Send command with special NDS prefix
-    let cmd = format!("\x1b]NDS:{};\x07", command);
-    socket.write_all(cmd.as_bytes())?;
-    socket.flush()?;
-
-    // Read response
-    let mut response = Vec::new();
-    let mut buffer = [0u8; 1024];
-
-    // Read for a short time to get the response
-    match socket.read(&mut buffer) {
-        Ok(n) if n > 0 => {
-            response.extend_from_slice(&buffer[..n]);
-        }
-        _ => {
-            return Ok(
-                "No response from session (client management may not be implemented)".to_string(),
-            );
-        }
-    }
-
-    Ok(String::from_utf8_lossy(&response).to_string())
+    println!(
+        "Disconnected client {} from session {}",
+        client_id,
+        session.display_name()
+    );
+    Ok(())
 }
-
-// Re-export for easier imports
-use std::io::Read;
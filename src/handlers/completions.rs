@@ -0,0 +1,335 @@
+//! `nds completions`: clap_complete's scripts, with session IDs and names
+//! completed from `nds list --format plain` wherever a command takes one
+
+use clap::{Arg, ArgAction, Command};
+use clap_complete::Shell;
+use detached_shell::{NdsError, Result};
+
+/// Shells `nds completions` writes scripts for
+pub const SHELLS: [&str; 3] = ["bash", "zsh", "fish"];
+
+/// Names of the arguments that take a session ID or name
+const SESSION_ARGS: &[&str] = &["id", "ids", "session", "sessions", "others"];
+
+/// Commands whose sessions aren't this machine's
+const REMOTE_COMMANDS: &[&str] = &["remote", "help"];
+
+/// Where one command takes sessions, as the scripts look it up
+struct Slots {
+    /// The command's subcommand names from `nds` on, once per alias
+    paths: Vec<String>,
+    /// The positional arguments from `first` to `last` (-1 for any after)
+    /// take sessions; -1 if none do
+    first: i32,
+    last: i32,
+    /// Options that take a value, so it isn't counted as a positional one
+    values: Vec<String>,
+    /// Options whose value is a session
+    session_options: Vec<String>,
+}
+
+fn is_session(arg: &Arg) -> bool {
+    SESSION_ARGS.contains(&arg.get_id().as_str())
+}
+
+/// An option's spellings, `--long` and `-s` with their aliases
+fn spellings(arg: &Arg) -> Vec<String> {
+    let longs = arg
+        .get_long_and_visible_aliases()
+        .into_iter()
+        .flatten()
+        .map(|long| format!("--{}", long));
+    let shorts = arg
+        .get_short_and_visible_aliases()
+        .into_iter()
+        .flatten()
+        .map(|short| format!("-{}", short));
+    longs.chain(shorts).collect()
+}
+
+/// Slots of `command`, reached as any of `paths`, and of its subcommands
+fn collect(command: &Command, paths: Vec<String>, out: &mut Vec<Slots>) {
+    let mut first = -1;
+    let mut last = -1;
+    for (index, arg) in command.get_positionals().enumerate() {
+        if is_session(arg) {
+            if first < 0 {
+                first = index as i32;
+            }
+            last = if matches!(arg.get_action(), ArgAction::Append) {
+                -1
+            } else {
+                index as i32
+            };
+        }
+    }
+    let options: Vec<&Arg> = command
+        .get_opts()
+        .filter(|arg| arg.get_action().takes_values())
+        .collect();
+    let values = options.iter().flat_map(|arg| spellings(arg)).collect();
+    let session_options = options
+        .iter()
+        .filter(|arg| is_session(arg))
+        .flat_map(|arg| spellings(arg))
+        .collect();
+
+    for sub in command.get_subcommands() {
+        if REMOTE_COMMANDS.contains(&sub.get_name()) {
+            continue;
+        }
+        let names: Vec<&str> = std::iter::once(sub.get_name())
+            .chain(sub.get_all_aliases())
+            .collect();
+        let sub_paths = paths
+            .iter()
+            .flat_map(|path| {
+                names.iter().map(move |name| match path.as_str() {
+                    "" => name.to_string(),
+                    _ => format!("{} {}", path, name),
+                })
+            })
+            .collect();
+        collect(sub, sub_paths, out);
+    }
+    out.push(Slots {
+        paths,
+        first,
+        last,
+        values,
+        session_options,
+    });
+}
+
+/// The `_nds_spec` function of the bash and zsh scripts: sets what
+/// `_nds_wants_session` needs to know of the command at path `$1`
+fn sh_spec(slots: &[Slots]) -> String {
+    let mut spec = String::from("_nds_spec() {\n    case \"$1\" in\n");
+    for command in slots {
+        let paths: Vec<String> = command.paths.iter().map(|p| format!("'{}'", p)).collect();
+        spec.push_str(&format!(
+            "        {}) _nds_first={} _nds_last={} _nds_values='{}' _nds_session_options='{}' ;;\n",
+            paths.join("|"),
+            command.first,
+            command.last,
+            command.values.join(" "),
+            command.session_options.join(" ")
+        ));
+    }
+    spec.push_str("        *) return 1 ;;\n    esac\n}\n");
+    spec
+}
+
+/// The same for fish, as `__nds_spec`
+fn fish_spec(slots: &[Slots]) -> String {
+    let mut spec = String::from("function __nds_spec\n    switch $argv[1]\n");
+    for command in slots {
+        let paths: Vec<String> = command.paths.iter().map(|p| format!("'{}'", p)).collect();
+        spec.push_str(&format!("        case {}\n", paths.join(" ")));
+        for (name, values) in [
+            ("first", vec![command.first.to_string()]),
+            ("last", vec![command.last.to_string()]),
+            ("values", command.values.clone()),
+            ("session_options", command.session_options.clone()),
+        ] {
+            let values: String = values.iter().map(|value| format!(" {}", value)).collect();
+            spec.push_str(&format!("            set -g -- __nds_{}{}\n", name, values));
+        }
+    }
+    spec.push_str("        case '*'\n            return 1\n    end\nend\n");
+    spec
+}
+
+/// Reads the words before the one being completed, as far as bash and zsh
+/// share a syntax, to tell whether a session goes there
+const SH_WANTS_SESSION: &str = r#"
+# Whether the word after the ones given is a session: the value of an option
+# taking one, or a positional argument that is one
+_nds_wants_session() {
+    local at='' positional=0 skip=0 word prev=''
+    local _nds_first _nds_last _nds_values _nds_session_options
+    _nds_spec ''
+    for word in "$@"; do
+        prev=$word
+        if [ "$skip" = 1 ]; then
+            skip=0
+            continue
+        fi
+        case "$word" in
+            --) return 1 ;;
+            -*) case " $_nds_values " in *" $word "*) skip=1 ;; esac ;;
+            *)
+                if [ "$positional" = 0 ] && _nds_spec "${at:+$at }$word"; then
+                    at="${at:+$at }$word"
+                else
+                    positional=$((positional + 1))
+                fi
+                ;;
+        esac
+    done
+    if [ "$skip" = 1 ]; then
+        case " $_nds_session_options " in *" $prev "*) return 0 ;; esac
+        return 1
+    fi
+    [ "$_nds_first" -ge 0 ] && [ "$positional" -ge "$_nds_first" ] &&
+        { [ "$_nds_last" -lt 0 ] || [ "$positional" -le "$_nds_last" ]; }
+}
+"#;
+
+const BASH: &str = r#"
+_nds_sessions() {
+    command nds list --format plain 2>/dev/null | while IFS=$'\t' read -r id name; do
+        printf '%s\n' "$id"
+        [ -z "$name" ] || printf '%s\n' "$name"
+    done
+}
+
+_nds_complete() {
+    local cur="${COMP_WORDS[COMP_CWORD]}"
+    if [[ "$cur" != -* ]] && _nds_wants_session "${COMP_WORDS[@]:1:COMP_CWORD-1}"; then
+        local IFS=$'\n'
+        COMPREPLY=($(compgen -W "$(_nds_sessions)" -- "$cur"))
+        return 0
+    fi
+    _nds "$@"
+}
+
+complete -F _nds_complete -o bashdefault -o default nds
+"#;
+
+const ZSH: &str = r#"
+_nds_sessions() {
+    local id name
+    command nds list --format plain 2>/dev/null | while IFS=$'\t' read -r id name; do
+        print -r -- "${id//:/\\:}:$name"
+        [[ -z $name ]] || print -r -- "${name//:/\\:}:$id"
+    done
+}
+
+# clap_complete's _nds, checked after sessions
+functions[_nds_clap]=$functions[_nds]
+_nds() {
+    if [[ $PREFIX != -* ]] && _nds_wants_session "${(@)words[2,CURRENT-1]}"; then
+        local -a sessions
+        sessions=(${(f)"$(_nds_sessions)"})
+        _describe -t sessions session sessions
+        return
+    fi
+    _nds_clap "$@"
+}
+"#;
+
+/// How clap_complete's zsh script ends: running `_nds` when it was loaded
+/// as the function itself, or else registering it
+const ZSH_TAIL: &str = "\nif [ \"$funcstack[1]\" = \"_nds\" ]; then";
+
+const FISH: &str = r#"
+function __nds_sessions
+    command nds list --format plain 2>/dev/null | while read -l --delimiter \t id name
+        printf '%s\t%s\n' $id "$name"
+        test -z "$name"; or printf '%s\t%s\n' $name $id
+    end
+end
+
+# Whether the word being completed is a session: the value of an option
+# taking one, or a positional argument that is one
+function __nds_wants_session
+    set -l words (commandline -opc)
+    set -e words[1]
+    set -l at ''
+    set -l positional 0
+    set -l skip 0
+    set -l prev ''
+    __nds_spec ''
+    for word in $words
+        set prev $word
+        if test $skip = 1
+            set skip 0
+            continue
+        end
+        switch $word
+            case '--'
+                return 1
+            case '-*'
+                contains -- $word $__nds_values; and set skip 1
+            case '*'
+                set -l next $word
+                test -n "$at"; and set next "$at $word"
+                if test $positional = 0; and __nds_spec $next
+                    set at $next
+                else
+                    set positional (math $positional + 1)
+                end
+        end
+    end
+    if test $skip = 1
+        contains -- $prev $__nds_session_options
+        return
+    end
+    string match -q -- '-*' (commandline -ct); and return 1
+    test $__nds_first -ge 0 -a $positional -ge $__nds_first
+    and test $__nds_last -lt 0 -o $positional -le $__nds_last
+end
+
+complete -c nds -f -n __nds_wants_session -a '(__nds_sessions)'
+"#;
+
+/// The completion script for `shell`, with sessions completed wherever
+/// `command` takes one
+pub fn completion_script(shell: &str, mut command: Command) -> Result<String> {
+    let generator = match shell {
+        "bash" => Shell::Bash,
+        "zsh" => Shell::Zsh,
+        "fish" => Shell::Fish,
+        _ => {
+            return Err(NdsError::ConfigError(format!(
+                "no completions for '{}' (supported: {})",
+                shell,
+                SHELLS.join(", ")
+            )))
+        }
+    };
+    command.build();
+    let mut slots = Vec::new();
+    collect(&command, vec![String::new()], &mut slots);
+
+    let mut script = Vec::new();
+    clap_complete::generate(generator, &mut command, "nds", &mut script);
+    let script = String::from_utf8_lossy(&script).into_owned();
+
+    Ok(match generator {
+        Shell::Bash => format!(
+            "{}\n{}{}{}",
+            script,
+            sh_spec(&slots),
+            SH_WANTS_SESSION,
+            BASH
+        ),
+        Shell::Zsh => {
+            // Take over _nds before the script runs or registers it
+            let (body, tail) = script
+                .rsplit_once(ZSH_TAIL)
+                .map_or((script.as_str(), ""), |(body, tail)| (body, tail));
+            let tail = if tail.is_empty() {
+                "\ncompdef _nds nds\n".to_string()
+            } else {
+                format!("{}{}", ZSH_TAIL, tail)
+            };
+            format!(
+                "{}\n{}{}{}{}",
+                body,
+                sh_spec(&slots),
+                SH_WANTS_SESSION,
+                ZSH,
+                tail
+            )
+        }
+        _ => format!("{}\n{}{}", script, fish_spec(&slots), FISH),
+    })
+}
+
+/// Print the completion script for `shell`
+pub fn handle_completions(shell: &str, command: Command) -> Result<()> {
+    print!("{}", completion_script(shell, command)?);
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use chrono::Local;
+use detached_shell::config::Config;
+use detached_shell::session_env::{client_env, parse_assignment, SessionEnv};
+use detached_shell::shell_init;
+use detached_shell::{NdsError, Result, SessionManager};
+use std::collections::BTreeMap;
+
+use super::session::find_session;
+
+/// Show the environment a session was started with and, when the shell
+/// integration hook is installed, the live environment of its shell
+pub fn handle_show_env(session_id_or_name: &str, live_only: bool) -> Result<()> {
+    let session = find_session(session_id_or_name)?;
+    let live = SessionEnv::load_live(&session.id)?;
+
+    if !live_only {
+        println!(
+            "Environment at start of session {}:",
+            session.display_name()
+        );
+        if session.env.is_empty() {
+            println!("  (not recorded; session was created by an older nds)");
+        }
+        for (key, value) in &session.env {
+            println!("  {}={}", key, value);
+        }
+        println!();
+    }
+
+    match live {
+        Some(live) => {
+            println!(
+                "Live environment (as of {}):",
+                live.captured_at
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M:%S")
+            );
+            for (key, value) in &live.vars {
+                match session.env.get(key) {
+                    Some(start) if start == value => println!("  {}={}", key, value),
+                    Some(_) => println!("~ {}={}", key, value),
+                    None => println!("+ {}={}", key, value),
+                }
+            }
+            for key in session.env.keys().filter(|k| !live.vars.contains_key(*k)) {
+                println!("- {}", key);
+            }
+        }
+        None => {
+            println!("Live environment not available.");
+            println!("Add `eval \"$(nds env hook bash)\"` (or zsh) to your shell's rc file.");
+        }
+    }
+
+    let pending = SessionEnv::pending_exports(&session.id)?;
+    if !pending.is_empty() {
+        println!();
+        println!("Queued for the next prompt:");
+        for line in pending {
+            println!("  {}", line);
+        }
+    }
+
+    Ok(())
+}
+
+/// Queue `export` statements that the session's shell runs at its next prompt
+pub fn handle_set_env(session_id_or_name: &str, assignments: &[String]) -> Result<()> {
+    let vars = assignments
+        .iter()
+        .map(|a| parse_assignment(a))
+        .collect::<Result<Vec<_>>>()?;
+
+    let session = find_session(session_id_or_name)?;
+    SessionEnv::queue_exports(&session.id, &vars)?;
+
+    let keys: Vec<_> = vars.iter().map(|(k, _)| k.as_str()).collect();
+    println!(
+        "Queued {} for session {}; it takes effect at the shell's next prompt.",
+        keys.join(", "),
+        session.display_name()
+    );
+    if SessionEnv::load_live(&session.id)?.is_none() {
+        println!("Note: the session's shell doesn't appear to have the nds env hook installed.");
+    }
+    Ok(())
+}
+
+/// Update the environment the session's daemon starts its shell with, for
+/// restarts and respawns: the given variables, or this terminal's. Those in
+/// `update_environment` also reach the running shell through its prompt hook.
+pub fn handle_setenv(session_id_or_name: &str, assignments: &[String]) -> Result<()> {
+    let update = Config::load().unwrap_or_default().update_environment();
+    let vars: BTreeMap<String, String> = if assignments.is_empty() {
+        client_env(&update)
+    } else {
+        assignments
+            .iter()
+            .map(|a| parse_assignment(a))
+            .collect::<Result<_>>()?
+    };
+
+    let session = find_session(session_id_or_name)?;
+    SessionManager::set_session_client_env(&session.id, &vars)?;
+
+    let shown: Vec<_> = vars
+        .iter()
+        .map(|(k, v)| {
+            if v.is_empty() {
+                format!("-{}", k)
+            } else {
+                format!("{}={}", k, v)
+            }
+        })
+        .collect();
+    println!(
+        "Session {} starts its shell with {} from now on.",
+        session.display_name(),
+        shown.join(" ")
+    );
+    let live: Vec<_> = vars
+        .keys()
+        .filter(|k| update.contains(k))
+        .cloned()
+        .collect();
+    if !live.is_empty() {
+        println!(
+            "With the nds env hook installed, its shell picks up any change to {} at its next prompt.",
+            live.join(" ")
+        );
+    }
+    Ok(())
+}
+
+/// Print the prompt-marking shell integration for `eval`
+pub fn handle_shell_init(shell: &str) -> Result<()> {
+    match shell_init::script(shell) {
+        Some(script) => {
+            print!("{}", script);
+            Ok(())
+        }
+        None => Err(NdsError::ConfigError(format!(
+            "no shell integration for '{}' (supported: {})",
+            shell,
+            shell_init::SHELLS.join(", ")
+        ))),
+    }
+}
+
+/// Print the shell integration hook for `eval`
+pub fn handle_env_hook(shell: &str) -> Result<()> {
+    match SessionEnv::hook_script(shell) {
+        Some(script) => {
+            print!("{}", script);
+            Ok(())
+        }
+        None => Err(NdsError::InvalidEnvironment(format!(
+            "no hook available for shell '{}' (supported: bash, zsh)",
+            shell
+        ))),
+    }
+}
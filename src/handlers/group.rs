@@ -0,0 +1,58 @@
+use std::collections::BTreeMap;
+
+use detached_shell::{Result, Session, SessionManager};
+
+use super::session::find_session;
+
+/// Lists the groups with the sessions in them
+pub fn handle_list_groups() -> Result<()> {
+    let mut groups: BTreeMap<String, Vec<Session>> = BTreeMap::new();
+    for session in SessionManager::list_sessions()? {
+        if let Some(group) = session.group.clone() {
+            groups.entry(group).or_default().push(session);
+        }
+    }
+
+    if groups.is_empty() {
+        println!("No groups (put sessions in one with 'nds group add')");
+        return Ok(());
+    }
+    for (group, sessions) in &groups {
+        let names: Vec<String> = sessions.iter().map(Session::display_name).collect();
+        println!("@{:<15} {}", group, names.join(", "));
+    }
+    Ok(())
+}
+
+/// Puts sessions in `group`
+pub fn handle_group_add(group: &str, sessions: &[String]) -> Result<()> {
+    let group = group.strip_prefix('@').unwrap_or(group);
+    Session::validate_group(group)?;
+
+    let sessions = sessions
+        .iter()
+        .map(|query| find_session(query))
+        .collect::<Result<Vec<_>>>()?;
+    for session in &sessions {
+        SessionManager::set_group(&session.id, Some(group))?;
+    }
+    let names: Vec<String> = sessions.iter().map(Session::display_name).collect();
+    println!("Added {} to group {}", names.join(", "), group);
+    println!("Attach with: nds attach @{}", group);
+    Ok(())
+}
+
+/// Takes sessions out of their group
+pub fn handle_group_remove(sessions: &[String]) -> Result<()> {
+    for query in sessions {
+        let session = find_session(query)?;
+        match &session.group {
+            Some(group) => {
+                SessionManager::set_group(&session.id, None)?;
+                println!("Removed {} from group {}", session.display_name(), group);
+            }
+            None => println!("Session {} isn't in a group", session.display_name()),
+        }
+    }
+    Ok(())
+}
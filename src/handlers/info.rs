@@ -1,22 +1,69 @@
 use chrono::{DateTime, Local};
+use detached_shell::config::Config;
+use detached_shell::history_export::{export_history, import_history, HistoryFormat};
+use detached_shell::history_v2::{HistoryQuery, RetentionPolicy};
+use detached_shell::session_log::{log_path, tail_offset};
+use detached_shell::stats::format_bytes;
+use detached_shell::status_line::StatusLine;
 use detached_shell::{
-    NdsError, Result, Session, SessionEvent, SessionHistory, SessionManager, SessionTable,
+    ExitStatus, ListConfig, NdsError, RedrawStrategy, RespawnPolicy, Result, Session, SessionEvent,
+    SessionHistory, SessionManager, SessionSort, SessionTable,
 };
 use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use super::session::{find_session, unresolved};
+
+/// How `nds list` prints sessions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListFormat {
+    Table,
+    /// The table with each session's CPU, memory and process count
+    Stats,
+    /// A line of `ID<TAB>NAME` per session, the name empty if it has none,
+    /// for shell completion
+    Plain,
+}
+
+/// Lists all active sessions in `sort` order, or the configured one, with
+/// optional interactive mode, in `format`. `attached` keeps only sessions
+/// with (`Some(true)`) or without clients. With `watch` the list is drawn
+/// again whenever it changes, and every `watch` anyway.
+pub fn handle_list_sessions(
+    interactive: bool,
+    tags: &[String],
+    sort: Option<SessionSort>,
+    reverse: bool,
+    attached: Option<bool>,
+    format: ListFormat,
+    watch: Option<chrono::Duration>,
+) -> Result<()> {
+    let mut order = Config::load().unwrap_or_default().list;
+    if let Some(sort) = sort {
+        order.sort = sort;
+    }
+    order.reverse ^= reverse;
 
-/// Lists all active sessions with optional interactive mode
-pub fn handle_list_sessions(interactive: bool) -> Result<()> {
     if interactive {
         // Interactive mode - let user select and attach
         use detached_shell::interactive::InteractivePicker;
 
-        match InteractivePicker::new() {
+        match InteractivePicker::with_tags(tags) {
             Ok(mut picker) => {
+                picker.sort_by(order);
                 match picker.run()? {
                     Some(session_id) => {
                         // User selected a session, attach to it
                         println!("Attaching to session {}...", session_id);
-                        crate::handlers::session::handle_attach_session(&session_id)?;
+                        crate::handlers::session::handle_attach_session(
+                            &session_id,
+                            detached_shell::AttachOptions::default(),
+                        )?;
                     }
                     None => {
                         // User quit without selecting
@@ -35,86 +82,200 @@ pub fn handle_list_sessions(interactive: bool) -> Result<()> {
                 }
             }
         }
+    } else if let Some(interval) = watch {
+        let interval = interval
+            .to_std()
+            .unwrap_or_default()
+            .max(MIN_WATCH_INTERVAL);
+        // Without a way to hear about changes, drawing every interval will do
+        let changes = SessionManager::subscribe_sessions().ok();
+        loop {
+            print!("\x1b[H\x1b[2J");
+            println!(
+                "Every {}s: nds list{:>50}",
+                interval.as_secs(),
+                Local::now().format("%H:%M:%S")
+            );
+            println!();
+            print_sessions(tags, order, attached, format)?;
+            io::stdout().flush()?;
+
+            match &changes {
+                Some(changes) => {
+                    if changes.next_timeout(interval).is_some() {
+                        // Take a burst of changes, like a kill --all, in one go
+                        while changes.next_timeout(WATCH_SETTLE).is_some() {}
+                    }
+                }
+                None => thread::sleep(interval),
+            }
+        }
     } else {
         // Normal list mode
-        let sessions = SessionManager::list_sessions()?;
-        let table = SessionTable::new(sessions);
-        table.print();
+        print_sessions(tags, order, attached, format)?;
     }
     Ok(())
 }
 
-/// Shows detailed information about a specific session
-pub fn handle_session_info(session_id_or_name: &str) -> Result<()> {
-    // Allow partial ID or name matching
-    let sessions = SessionManager::list_sessions()?;
-
-    // First try to match by ID
-    let mut matching_sessions: Vec<_> = sessions
-        .iter()
-        .filter(|s| s.id.starts_with(session_id_or_name))
+/// Shortest time between redraws of `nds list --watch` when nothing changes
+const MIN_WATCH_INTERVAL: Duration = Duration::from_secs(1);
+/// How long `nds list --watch` waits for more changes before drawing one
+const WATCH_SETTLE: Duration = Duration::from_millis(100);
+
+/// Prints the sessions tagged with any of `tags` in `order`
+fn print_sessions(
+    tags: &[String],
+    order: ListConfig,
+    attached: Option<bool>,
+    format: ListFormat,
+) -> Result<()> {
+    let mut sessions: Vec<Session> = SessionManager::list_sessions()?
+        .into_iter()
+        .filter(|s| s.matches_tags(tags))
+        .filter(|s| attached.is_none_or(|attached| (s.get_client_count() > 0) == attached))
         .collect();
-
-    // If no ID matches, try matching by name
-    if matching_sessions.is_empty() {
-        matching_sessions = sessions
-            .iter()
-            .filter(|s| {
-                if let Some(ref name) = s.name {
-                    name == session_id_or_name
-                        || name.starts_with(session_id_or_name)
-                        || name
-                            .to_lowercase()
-                            .starts_with(&session_id_or_name.to_lowercase())
-                } else {
-                    false
-                }
-            })
-            .collect();
+    order.apply(&mut sessions);
+    if format == ListFormat::Plain {
+        let mut out = io::stdout().lock();
+        for session in &sessions {
+            writeln!(
+                out,
+                "{}\t{}",
+                session.id,
+                session.name.as_deref().unwrap_or_default()
+            )?;
+        }
+        return Ok(());
+    }
+    let mut table = SessionTable::new(sessions);
+    if format == ListFormat::Stats {
+        table = table.with_stats();
     }
+    table.print();
+    Ok(())
+}
 
-    match matching_sessions.len() {
-        0 => {
-            eprintln!(
-                "No session found matching ID or name: {}",
-                session_id_or_name
-            );
-            Err(NdsError::SessionNotFound(session_id_or_name.to_string()))
-        }
-        1 => {
-            let session = matching_sessions[0];
-            let client_count = session.get_client_count();
+/// Prints the status line for prompts. Nothing is printed if the sessions
+/// can't be read, so a prompt never shows an error.
+pub fn handle_status_line() -> Result<()> {
+    let sessions = Session::list_cached().unwrap_or_default();
+    let current = std::env::var("NDS_SESSION_ID").ok();
+    let line = StatusLine::new(&sessions, current.as_deref(), chrono::Utc::now());
+    println!("{}", line);
+    Ok(())
+}
 
-            println!("Session ID: {}", session.id);
-            if let Some(ref name) = session.name {
-                println!("Session Name: {}", name);
-            }
-            println!("PID: {}", session.pid);
-            println!("Created: {}", session.created_at);
-            println!("Socket: {}", session.socket_path.display());
-            println!("Shell: {}", session.shell);
-            println!("Working Directory: {}", session.working_dir);
-            println!(
-                "Status: {}",
-                if client_count > 0 {
-                    format!("Attached ({} client(s))", client_count)
-                } else {
-                    "Detached".to_string()
-                }
-            );
-            Ok(())
+/// Shows detailed information about a specific session
+pub fn handle_session_info(session_id_or_name: &str) -> Result<()> {
+    let session = find_session(session_id_or_name)?;
+    let clients = session.clients().unwrap_or_default();
+    let foreground = session.foreground_process().ok().flatten();
+
+    println!("Session ID: {}", session.id);
+    println!("Index: {}", session.index_label());
+    if let Some(ref name) = session.name {
+        println!("Session Name: {}", name);
+    }
+    if let Some(ref note) = session.note {
+        println!("Note: {}", note);
+    }
+    if let Some(ref group) = session.group {
+        println!("Group: {}", group);
+    }
+    println!("PID: {}", session.pid);
+    println!("Created: {}", session.created_at);
+    println!("Last Output: {}", format_last_seen(session.last_output));
+    println!("Last Input: {}", format_last_seen(session.last_input));
+    if session.bell.is_some() {
+        println!("Bell: {} (unseen)", format_last_seen(session.bell));
+    }
+    println!("Socket: {}", session.socket_label());
+    if let Some(ref remote) = session.remote {
+        println!("Remote: {} (TLS)", remote.address);
+        println!("Remote Attach: {}", remote.attach_command(&session.id));
+    }
+    println!("Shell: {}", session.shell);
+    if let Some(ref command) = session.command {
+        println!("Command: {}", command.join(" "));
+    }
+    println!("Working Directory: {}", session.working_dir);
+    if let Some(ref process) = foreground {
+        println!(
+            "Running: {} (process group {})",
+            process.command.join(" "),
+            process.pgid
+        );
+    }
+    if !session.links.is_empty() {
+        println!("Linked: {}", session.links.join(", "));
+    }
+    if !session.extra_env.is_empty() {
+        println!("Environment:");
+        for (key, value) in &session.extra_env {
+            println!("  {}={}", key, value);
         }
-        _ => {
-            eprintln!(
-                "Multiple sessions match '{}'. Please be more specific:",
-                session_id_or_name
-            );
-            for session in matching_sessions {
-                eprintln!("  - {}", session.display_name());
-            }
-            Err(NdsError::InvalidSessionId(session_id_or_name.to_string()))
+    }
+    if let Some(stats) = session.stats() {
+        println!("CPU: {:.1}%", stats.cpu_percent);
+        println!("Memory: {} RSS", format_bytes(stats.rss_bytes));
+        println!("Processes: {}", stats.processes);
+    }
+    if let Some((cols, rows)) = session.size {
+        println!("Size: {}x{}", cols, rows);
+    }
+    println!(
+        "Redraw: {}",
+        session
+            .redraw
+            .map(|r| r.to_string())
+            .unwrap_or_else(|| format!("{} (default)", RedrawStrategy::resolve(None)))
+    );
+    println!("Resize: {}", session.resize);
+    if session.protocol == 0 {
+        println!("Protocol: 0 (daemon predates the framed protocol; commands are sent in-band)");
+    } else {
+        println!("Protocol: {}", session.protocol);
+    }
+    println!(
+        "Status: {}",
+        if clients.is_empty() {
+            "Detached".to_string()
+        } else {
+            format!("Attached ({} client(s))", clients.len())
         }
+    );
+    for client in &clients {
+        println!(
+            "  Client {}: {}x{}, since {}{}",
+            client.id,
+            client.cols,
+            client.rows,
+            client.connected_at.with_timezone(&Local).format("%H:%M:%S"),
+            client
+                .tty
+                .as_deref()
+                .map(|tty| format!(", on {}", tty))
+                .unwrap_or_default()
+        );
+    }
+    if let Some(status) = session.exit_status {
+        println!("Exited: {}", status);
+    }
+    if session.respawn != RespawnPolicy::Never || session.respawn_count > 0 {
+        println!(
+            "Respawn: {} (respawned {} time(s))",
+            session.respawn, session.respawn_count
+        );
     }
+    Ok(())
+}
+
+/// Prints the session daemon's internal state as JSON
+pub fn handle_debug_dump(session_id_or_name: &str) -> Result<()> {
+    let session = find_session(session_id_or_name)?;
+    let dump = SessionManager::debug_dump(&session.id)?;
+    println!("{}", serde_json::to_string_pretty(&dump)?);
+    Ok(())
 }
 
 /// Shows session history with various filtering options
@@ -122,27 +283,192 @@ pub fn handle_session_history(
     session_id_or_name: Option<String>,
     all: bool,
     limit: usize,
+    event: Option<String>,
+    since: Option<chrono::Duration>,
 ) -> Result<()> {
     // Migrate old format if needed
     let _ = SessionHistory::migrate_from_single_file();
 
+    let query = HistoryQuery {
+        event,
+        since: since.map(|since| chrono::Utc::now() - since),
+        limit: Some(limit),
+        ..Default::default()
+    };
     if let Some(ref id_or_name) = session_id_or_name {
         // Show history for specific session
-        handle_specific_session_history(id_or_name, limit)
+        handle_specific_session_history(id_or_name, query)
     } else {
         // Show all history or active sessions only
-        handle_general_session_history(all, limit)
+        handle_general_session_history(all, query)
+    }
+}
+
+/// Summarizes the history recorded in the last `since`, or all of it
+pub fn handle_history_stats(since: Option<chrono::Duration>, top: usize) -> Result<()> {
+    let _ = SessionHistory::migrate_from_single_file();
+
+    let from = since.map(|since| chrono::Utc::now() - since);
+    let stats = SessionHistory::stats(from, top)?;
+
+    match from {
+        Some(from) => {
+            let local: DateTime<Local> = from.into();
+            println!("Session history since {}", local.format("%Y-%m-%d %H:%M"));
+        }
+        None => println!("All session history"),
+    }
+    println!("{:-<60}", "");
+    println!(
+        "Sessions:          {} ({} created)",
+        stats.sessions, stats.created
+    );
+    println!("Attaches:          {}", stats.attaches);
+    match stats.crash_rate() {
+        Some(rate) => println!(
+            "Ended:             {} ({} crashed, {:.1}% crash rate)",
+            stats.ended,
+            stats.crashed,
+            rate * 100.0
+        ),
+        None => println!("Ended:             0"),
+    }
+    if let Some(average) = stats.average_duration() {
+        println!(
+            "Total duration:    {}",
+            SessionHistory::format_duration(stats.total_duration)
+        );
+        println!(
+            "Average duration:  {}",
+            SessionHistory::format_duration(average)
+        );
+    }
+
+    if !stats.busiest_dirs.is_empty() {
+        println!();
+        println!("Busiest directories:");
+        for dir in &stats.busiest_dirs {
+            println!(
+                "  {:>4} sessions {:>5} attaches  {}",
+                dir.sessions, dir.attaches, dir.working_dir
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Applies the configured history retention if it is a day since it last
+/// was. Nothing here is worth failing a command over.
+pub fn clean_history_if_due() {
+    let Some(history) = Config::load().ok().and_then(|config| config.history) else {
+        return;
+    };
+    let _ = SessionHistory::clean_if_due(&RetentionPolicy::from_config(&history));
+}
+
+/// Removes old archived history now, with the configured policy unless
+/// overridden, and reports what went
+pub fn handle_history_clean(
+    keep_days: Option<i64>,
+    max_archived: Option<usize>,
+    dry_run: bool,
+) -> Result<()> {
+    let configured = Config::load()?.history;
+    let mut policy = match &configured {
+        Some(history) => RetentionPolicy::from_config(history),
+        None => RetentionPolicy {
+            keep_days: None,
+            max_archived: None,
+        },
+    };
+    if keep_days.is_some() {
+        policy.keep_days = keep_days;
+    }
+    if max_archived.is_some() {
+        policy.max_archived = max_archived;
+    }
+    if policy.keep_days.is_none() && policy.max_archived.is_none() {
+        println!("No retention policy: set \"history\" in config.json, or pass --keep-days or --max-archived.");
+        return Ok(());
+    }
+
+    let size_before = SessionHistory::database_size()?;
+    let report = SessionHistory::clean(&policy, dry_run)?;
+    let verb = if dry_run { "Would remove" } else { "Removed" };
+    println!(
+        "{} the history of {} ended session(s); {} kept.",
+        verb,
+        report.removed.len(),
+        report.kept_archived
+    );
+    if dry_run {
+        for session_id in &report.removed {
+            println!("  {}", session_id);
+        }
+    } else {
+        println!(
+            "History database: {} (was {})",
+            format_bytes(SessionHistory::database_size()?),
+            format_bytes(size_before)
+        );
+    }
+    Ok(())
+}
+
+/// Writes history to `output`, or stdout, entry by entry
+pub fn handle_history_export(
+    format: HistoryFormat,
+    since: Option<chrono::Duration>,
+    output: Option<&Path>,
+) -> Result<()> {
+    let _ = SessionHistory::migrate_from_single_file();
+
+    let query = HistoryQuery {
+        since: since.map(|since| chrono::Utc::now() - since),
+        include_archived: true,
+        ..Default::default()
+    };
+    match output {
+        Some(path) => {
+            let file = io::BufWriter::new(File::create(path)?);
+            let count = export_history(&query, format, file)?;
+            println!("Exported {} history entries to {}", count, path.display());
+        }
+        None => {
+            export_history(&query, format, io::stdout().lock())?;
+        }
     }
+    Ok(())
+}
+
+/// Adds history from an export, leaving out entries already recorded
+pub fn handle_history_import(file: &Path, format: Option<HistoryFormat>) -> Result<()> {
+    let format = format
+        .or_else(|| HistoryFormat::from_path(file))
+        .unwrap_or_default();
+    let (added, skipped) = if file == Path::new("-") {
+        import_history(io::stdin().lock(), format)?
+    } else {
+        import_history(io::BufReader::new(File::open(file)?), format)?
+    };
+    println!(
+        "Imported {} history entries ({} already recorded)",
+        added, skipped
+    );
+    Ok(())
 }
 
 /// Helper function to handle history for a specific session
-fn handle_specific_session_history(id_or_name: &str, limit: usize) -> Result<()> {
-    // First try to resolve session name to ID
-    let sessions = SessionManager::list_sessions()?;
-    let resolved_id = resolve_session_id(id_or_name, &sessions)?;
+fn handle_specific_session_history(id_or_name: &str, query: HistoryQuery) -> Result<()> {
+    let resolved_id = resolve_session_id(id_or_name)?;
 
     // Show history for specific session
-    let entries = SessionHistory::get_session_history(&resolved_id)?;
+    let entries = SessionHistory::query(&HistoryQuery {
+        session_id: Some(resolved_id.clone()),
+        include_archived: true,
+        oldest_first: true,
+        ..query
+    })?;
 
     if entries.is_empty() {
         println!("No history found for session: {}", resolved_id);
@@ -152,7 +478,7 @@ fn handle_specific_session_history(id_or_name: &str, limit: usize) -> Result<()>
     println!("History for session {}:", resolved_id);
     println!("{:-<80}", "");
 
-    for entry in entries.iter().take(limit) {
+    for entry in &entries {
         let local_time: DateTime<Local> = entry.timestamp.into();
         let time_str = local_time.format("%Y-%m-%d %H:%M:%S").to_string();
 
@@ -168,8 +494,11 @@ fn handle_specific_session_history(id_or_name: &str, limit: usize) -> Result<()>
 }
 
 /// Helper function to handle general session history
-fn handle_general_session_history(all: bool, limit: usize) -> Result<()> {
-    let entries = SessionHistory::load_all_history(all, Some(limit))?;
+fn handle_general_session_history(all: bool, query: HistoryQuery) -> Result<()> {
+    let entries = SessionHistory::query(&HistoryQuery {
+        include_archived: all,
+        ..query
+    })?;
 
     let filtered_entries: Vec<_> = if !all {
         // Filter to show only entries for currently active sessions
@@ -197,41 +526,13 @@ fn handle_general_session_history(all: bool, limit: usize) -> Result<()> {
     Ok(())
 }
 
-/// Helper function to resolve session name to ID
-fn resolve_session_id(id_or_name: &str, sessions: &[Session]) -> Result<String> {
-    if sessions.iter().any(|s| s.id == id_or_name) {
-        // It's already a session ID
-        return Ok(id_or_name.to_string());
-    }
-
-    // Try to find by name (case-insensitive partial matching)
-    let matches: Vec<&Session> = sessions
-        .iter()
-        .filter(|s| {
-            if let Some(ref name) = s.name {
-                name.to_lowercase().contains(&id_or_name.to_lowercase())
-            } else {
-                false
-            }
-        })
-        .collect();
-
-    match matches.len() {
-        0 => {
-            println!("No session found with ID or name matching: {}", id_or_name);
-            Err(NdsError::SessionNotFound(id_or_name.to_string()))
-        }
-        1 => Ok(matches[0].id.clone()),
-        _ => {
-            println!(
-                "Multiple sessions match '{}'. Please be more specific:",
-                id_or_name
-            );
-            for session in matches {
-                println!("  {} [{}]", session.display_name(), session.id);
-            }
-            Err(NdsError::InvalidSessionId(id_or_name.to_string()))
-        }
+/// The ID whose history `id_or_name` asks for: a live session as
+/// `SessionManager::resolve` finds it, or else an ended session's ID
+fn resolve_session_id(id_or_name: &str) -> Result<String> {
+    match SessionManager::resolve(id_or_name) {
+        Ok(resolved) => Ok(resolved.session.id),
+        Err(NdsError::SessionNotFound(_)) => Ok(id_or_name.to_string()),
+        Err(e) => Err(unresolved(id_or_name, e)),
     }
 }
 
@@ -244,19 +545,78 @@ fn format_session_event(event: &SessionEvent, duration: Option<i64>) -> String {
         SessionEvent::Killed => format!(
             "Killed (duration: {})",
             duration
-                .map(|d| SessionHistory::format_duration(d))
+                .map(SessionHistory::format_duration)
                 .unwrap_or_else(|| "unknown".to_string())
         ),
         SessionEvent::Crashed => format!(
             "Crashed (duration: {})",
             duration
-                .map(|d| SessionHistory::format_duration(d))
+                .map(SessionHistory::format_duration)
                 .unwrap_or_else(|| "unknown".to_string())
         ),
         SessionEvent::Renamed { from, to } => match from {
             Some(old) => format!("Renamed from '{}' to '{}'", old, to),
             None => format!("Named as '{}'", to),
         },
+        SessionEvent::ClientConnected {
+            client_id,
+            uid,
+            user,
+            pid,
+            tty,
+        } => {
+            let mut who = match (user, uid) {
+                (Some(user), Some(uid)) => format!("user {} (uid {})", user, uid),
+                _ => "unknown user".to_string(),
+            };
+            if let Some(pid) = pid {
+                who.push_str(&format!(", pid {}", pid));
+            }
+            if let Some(tty) = tty {
+                who.push_str(&format!(", {}", tty));
+            }
+            format!("Client {} connected: {}", client_id, who)
+        }
+        SessionEvent::RemoteConnected { address } => {
+            format!("Remote client connected from {}", address)
+        }
+        SessionEvent::InputFlagged {
+            client_id,
+            bytes_per_minute,
+        } => format!(
+            "Client {} flagged for high input ({} B/min)",
+            client_id, bytes_per_minute
+        ),
+        SessionEvent::Exited { code, signal } => format!(
+            "Exited ({}, after {})",
+            describe_exit(*code, *signal),
+            duration
+                .map(SessionHistory::format_duration)
+                .unwrap_or_else(|| "unknown".to_string())
+        ),
+        SessionEvent::Bell => "Rang the bell".to_string(),
+    }
+}
+
+/// When something last happened, and how long ago
+fn format_last_seen(at: Option<DateTime<chrono::Utc>>) -> String {
+    match at {
+        Some(at) => {
+            let ago = (chrono::Utc::now() - at).num_seconds().max(0);
+            format!(
+                "{} ({} ago)",
+                at.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"),
+                SessionHistory::format_duration(ago)
+            )
+        }
+        None => "never".to_string(),
+    }
+}
+
+fn describe_exit(code: i32, signal: Option<i32>) -> String {
+    match signal {
+        Some(signal) => ExitStatus::Signal(signal).to_string(),
+        None => ExitStatus::Code(code).to_string(),
     }
 }
 
@@ -296,17 +656,33 @@ fn print_history_table(entries: &[detached_shell::HistoryEntry], all: bool) {
                 "Killed".to_string(),
                 entry
                     .duration_seconds
-                    .map(|d| SessionHistory::format_duration(d))
+                    .map(SessionHistory::format_duration)
                     .unwrap_or_else(|| "-".to_string()),
             ),
             SessionEvent::Crashed => (
                 "Crashed".to_string(),
                 entry
                     .duration_seconds
-                    .map(|d| SessionHistory::format_duration(d))
+                    .map(SessionHistory::format_duration)
                     .unwrap_or_else(|| "-".to_string()),
             ),
             SessionEvent::Renamed { .. } => ("Renamed".to_string(), "-".to_string()),
+            SessionEvent::InputFlagged { .. } => ("High input".to_string(), "-".to_string()),
+            SessionEvent::ClientConnected { user, .. } => (
+                format!("Client in ({})", user.as_deref().unwrap_or("?")),
+                "-".to_string(),
+            ),
+            SessionEvent::Exited { code, signal } => (
+                format!("Exited ({})", describe_exit(*code, *signal)),
+                entry
+                    .duration_seconds
+                    .map(SessionHistory::format_duration)
+                    .unwrap_or_else(|| "-".to_string()),
+            ),
+            SessionEvent::Bell => ("Bell".to_string(), "-".to_string()),
+            SessionEvent::RemoteConnected { address } => {
+                (format!("Remote in ({})", address), "-".to_string())
+            }
         };
 
         let working_dir = if entry.working_dir.len() > 30 {
@@ -321,3 +697,66 @@ fn print_history_table(entries: &[detached_shell::HistoryEntry], all: bool) {
         );
     }
 }
+
+/// Prints the end of a session's output log, and with `follow` what it
+/// logs next until the session ends, like `tail -f`. A session that has
+/// gone can still be named by its full ID.
+pub fn handle_logs(session_id_or_name: &str, lines: usize, follow: bool) -> Result<()> {
+    let (id, label) = match log_path(session_id_or_name)? {
+        path if path.exists() => (
+            session_id_or_name.to_string(),
+            session_id_or_name.to_string(),
+        ),
+        _ => {
+            let session = find_session(session_id_or_name)?;
+            (session.id.clone(), session.display_name())
+        }
+    };
+    let path = log_path(&id)?;
+    if !path.exists() {
+        return Err(NdsError::ConfigError(format!(
+            "no log for {}: turn logging on with \"logging\": {{}} in config.json, then start the session",
+            label
+        )));
+    }
+
+    let mut file = File::open(&path)?;
+    let mut position = tail_offset(&mut file, lines)?;
+    let mut out = io::stdout().lock();
+    position += copy_from(&mut file, position, &mut out)?;
+    if !follow {
+        return Ok(());
+    }
+
+    loop {
+        thread::sleep(LOG_FOLLOW_INTERVAL);
+        let copied = copy_from(&mut file, position, &mut out)?;
+        position += copied;
+        // Rotated: finish the old log, then carry on at the start of the new one
+        let rotated =
+            fs::metadata(&path).is_ok_and(|m| m.ino() != file.metadata().map_or(0, |f| f.ino()));
+        if rotated {
+            copy_from(&mut file, position, &mut out)?;
+            file = File::open(&path)?;
+            position = 0;
+            continue;
+        }
+        // Sessions whose command has exited stay around for `nds wait`
+        let running = Session::load(&id)
+            .is_ok_and(|s| s.exit_status.is_none() && Session::is_process_alive(s.pid));
+        if copied == 0 && !running {
+            return Ok(());
+        }
+    }
+}
+
+/// How often `nds logs -f` looks for more output
+const LOG_FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Copy what `file` has from `position` on to `out`; returns how much
+fn copy_from(file: &mut File, position: u64, out: &mut impl Write) -> Result<u64> {
+    file.seek(SeekFrom::Start(position))?;
+    let copied = io::copy(file, out)?;
+    out.flush()?;
+    Ok(copied)
+}
@@ -0,0 +1,74 @@
+use detached_shell::{Result, Session, SessionManager};
+
+use super::session::find_session;
+
+/// Links a session to `others`, and them to each other; with no others,
+/// shows what it is linked to
+pub fn handle_link_sessions(session_id_or_name: &str, others: &[String]) -> Result<()> {
+    let session = find_session(session_id_or_name)?;
+
+    if others.is_empty() {
+        let linked = SessionManager::linked_sessions(&session.id)?;
+        if linked.is_empty() {
+            println!("Session {} isn't linked", session.display_name());
+            return Ok(());
+        }
+        println!("Session {} is linked to:", session.display_name());
+        for other in &linked {
+            println!("  {:<10} {}", &other.id, other.display_name());
+        }
+        return Ok(());
+    }
+
+    let mut sessions = vec![session];
+    for other in others {
+        let other = find_session(other)?;
+        if !sessions.iter().any(|session| session.id == other.id) {
+            sessions.push(other);
+        }
+    }
+    if sessions.len() < 2 {
+        println!("A session can't be linked to itself");
+        return Ok(());
+    }
+
+    let ids: Vec<&str> = sessions.iter().map(|session| session.id.as_str()).collect();
+    SessionManager::link_sessions(&ids)?;
+    let names: Vec<String> = sessions.iter().map(Session::display_name).collect();
+    println!("Linked sessions {}", names.join(", "));
+    println!("Type Enter ~b while attached to one to type into the others too");
+    Ok(())
+}
+
+/// Unlinks a session from `others`, or from every session it is linked to
+pub fn handle_unlink_sessions(session_id_or_name: &str, others: &[String]) -> Result<()> {
+    let session = find_session(session_id_or_name)?;
+
+    // Sessions that have gone away can still be unlinked by ID
+    let others: Vec<String> = others
+        .iter()
+        .map(|other| {
+            SessionManager::resolve(other).map_or_else(|_| other.clone(), |other| other.session.id)
+        })
+        .collect();
+    let others: Vec<&str> = others.iter().map(String::as_str).collect();
+    let unlinked = SessionManager::unlink_sessions(&session.id, &others)?;
+    if unlinked.is_empty() {
+        println!(
+            "Session {} isn't linked to {}",
+            session.display_name(),
+            if others.is_empty() {
+                "any session".to_string()
+            } else {
+                others.join(", ")
+            }
+        );
+        return Ok(());
+    }
+    println!(
+        "Unlinked session {} from {}",
+        session.display_name(),
+        unlinked.join(", ")
+    );
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use detached_shell::metrics::{render, write_textfile, SessionSample};
+use detached_shell::{Result, SessionManager};
+use std::path::Path;
+use std::thread;
+
+use super::session::find_session;
+
+/// Prints counters for one session or all of them in the Prometheus text
+/// format, or writes them to `textfile` every `interval` if one is given
+pub fn handle_metrics(
+    session: Option<&str>,
+    textfile: Option<&Path>,
+    interval: Option<chrono::Duration>,
+) -> Result<()> {
+    let Some(path) = textfile else {
+        print!("{}", render(&collect(session)?));
+        return Ok(());
+    };
+    loop {
+        write_textfile(path, &collect(session)?)?;
+        match interval.and_then(|interval| interval.to_std().ok()) {
+            Some(interval) => thread::sleep(interval),
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Ask each session's daemon for its counters; one that doesn't answer is
+/// still listed, as down
+fn collect(session: Option<&str>) -> Result<Vec<SessionSample>> {
+    let sessions = match session {
+        Some(id_or_name) => vec![find_session(id_or_name)?],
+        None => SessionManager::list_sessions()?,
+    };
+    Ok(sessions
+        .into_iter()
+        .map(|session| SessionSample {
+            metrics: SessionManager::metrics(&session.id).ok(),
+            session,
+        })
+        .collect())
+}
@@ -1,16 +1,51 @@
 // Module declarations
 pub mod clients;
+pub mod completions;
+pub mod env;
+pub mod group;
 pub mod info;
+pub mod link;
+pub mod metrics;
+pub mod remote;
+pub mod run;
+pub mod selftest;
+pub mod server;
 pub mod session;
+pub mod share;
+pub mod watch;
+pub mod window;
 
 #[cfg(test)]
 mod test;
 
 // Re-export commonly used items for convenience
 pub use session::{
-    handle_attach_session, handle_clean_sessions, handle_kill_sessions, handle_new_session,
-    handle_rename_session,
+    collect_env, handle_approve_attach, handle_attach_session, handle_capture_session,
+    handle_clean_sessions, handle_detach_session, handle_kill_sessions, handle_message_session,
+    handle_new_session, handle_note_session, handle_paste_session, handle_prune_sessions,
+    handle_record_session, handle_rename_session, handle_replay, handle_resize_session,
+    handle_restart_session, handle_signal_session, handle_tag_session, parse_bytes, parse_duration,
+    parse_signal, parse_size,
 };
 
 pub use clients::{handle_disconnect_client, handle_list_clients};
-pub use info::{handle_list_sessions, handle_session_history, handle_session_info};
+pub use completions::handle_completions;
+pub use env::{handle_env_hook, handle_set_env, handle_setenv, handle_shell_init, handle_show_env};
+pub use group::{handle_group_add, handle_group_remove, handle_list_groups};
+pub use info::{
+    clean_history_if_due, handle_debug_dump, handle_history_clean, handle_history_export,
+    handle_history_import, handle_history_stats, handle_list_sessions, handle_logs,
+    handle_session_history, handle_session_info, handle_status_line, ListFormat,
+};
+pub use link::{handle_link_sessions, handle_unlink_sessions};
+pub use metrics::handle_metrics;
+pub use remote::{handle_attach_remote, handle_ssh_attach, handle_ssh_list, prepare_listen};
+pub use run::{handle_run, handle_wait};
+pub use selftest::handle_selftest;
+pub use server::{
+    handle_new_session_id, handle_rpc, handle_server_run, handle_server_start,
+    handle_server_status, handle_server_stop,
+};
+pub use share::{handle_attach_shared, handle_share_session, parse_user};
+pub use watch::{handle_watch_session, WatchFor};
+pub use window::{handle_kill_window, handle_list_windows, handle_new_window};
@@ -0,0 +1,74 @@
+use detached_shell::remote::{self, ssh, RemoteEndpoint};
+use detached_shell::{AttachOptions, NdsError, Result, Session, SessionManager};
+
+use super::session::report_attach;
+
+/// Where the token for `nds attach --remote` comes from when `--token`
+/// isn't given, so it stays out of `ps` and shell history
+const TOKEN_VAR: &str = "NDS_REMOTE_TOKEN";
+
+/// Gets `nds new --listen ADDR` ready: a certificate, and a token for the
+/// session
+pub fn prepare_listen(address: Option<&str>) -> Result<Option<RemoteEndpoint>> {
+    address.map(remote::prepare).transpose()
+}
+
+/// Tells the user how to reach a session started with `--listen`, or that
+/// its daemon couldn't listen
+pub fn print_remote_hint(session: &Session, requested: Option<&str>) {
+    match (&session.remote, requested) {
+        (Some(remote), _) => {
+            println!("Listening on {} (TLS)", remote.address);
+            println!("From another machine, attach with:");
+            println!("  {}", remote.attach_command(&session.id));
+        }
+        (None, Some(address)) => {
+            eprintln!(
+                "Warning: the session could not listen on {}, so it is only reachable here",
+                address
+            );
+        }
+        (None, None) => {}
+    }
+}
+
+/// Attaches to a session on another machine through its daemon's TCP
+/// listener
+pub fn handle_attach_remote(
+    address: &str,
+    session: &str,
+    token: Option<String>,
+    fingerprint: &str,
+    options: AttachOptions,
+) -> Result<()> {
+    let token = token
+        .or_else(|| {
+            std::env::var(TOKEN_VAR)
+                .ok()
+                .filter(|token| !token.is_empty())
+        })
+        .ok_or_else(|| {
+            NdsError::RemoteError(format!(
+                "give the session's token with --token or {}",
+                TOKEN_VAR
+            ))
+        })?;
+    report_attach(SessionManager::attach_remote(
+        address,
+        session,
+        &token,
+        fingerprint,
+        options,
+    )?)
+}
+
+/// Lists the sessions on `host` with its own `nds list`, over ssh; returns
+/// the exit code to leave with
+pub fn handle_ssh_list(host: &str) -> Result<i32> {
+    ssh::list(host)
+}
+
+/// Attaches to a session on `host` over ssh
+pub fn handle_ssh_attach(host: &str, session: &str, options: AttachOptions) -> Result<()> {
+    report_attach(SessionManager::attach_ssh(host, session, options)?)
+}
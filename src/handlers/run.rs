@@ -0,0 +1,120 @@
+use detached_shell::pty::protocol::{Frame, Role};
+use detached_shell::{
+    ExitStatus, NdsError, PtyProcess, RedrawStrategy, RespawnPolicy, Result, Session,
+    SessionManager, SpawnOptions,
+};
+use std::io::{self, Read, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use super::session::find_session;
+
+/// How long to wait for output before checking whether the command has exited
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Runs a command in a new session and streams its output until it exits.
+///
+/// Returns the exit code to leave with: the command's own, or 0 right away
+/// with `detach`, in which case the session keeps running in the background.
+pub fn handle_run(
+    name: Option<String>,
+    command: Vec<String>,
+    detach: bool,
+    respawn: RespawnPolicy,
+) -> Result<i32> {
+    // Ctrl+L on attach would end up in the command's input, and replayed
+    // terminal modes would end up in the streamed (possibly piped) output
+    let redraw = if detach {
+        RedrawStrategy::VtSnapshot
+    } else {
+        RedrawStrategy::None
+    };
+    let options = SpawnOptions {
+        redraw: Some(redraw),
+        command: Some(command),
+        respawn,
+        ..SpawnOptions::default()
+    };
+    let session = SessionManager::create_session_with_options(name, options)?;
+
+    if detach {
+        println!("Created session: {}", session.id);
+        println!("\nTo attach to this session, run:");
+        println!("  nds attach {}", session.id);
+        return Ok(0);
+    }
+
+    let status = stream_until_exit(&session)?;
+
+    // The command has finished, so the session has served its purpose
+    let _ = PtyProcess::kill_session(&session.id);
+    Ok(status.code())
+}
+
+/// Copy session output to stdout until the daemon reports that the command exited
+fn stream_until_exit(session: &Session) -> Result<ExitStatus> {
+    let mut stream = PtyProcess::connect(session, Role::Attach)?;
+    stream.stream().set_read_timeout(Some(POLL_INTERVAL))?;
+
+    // Ctrl+C interrupts the command instead of abandoning it
+    let interrupt = stream.try_clone()?;
+    let _ = ctrlc::set_handler(move || {
+        let _ = interrupt.send(&Frame::Data(b"\x03".to_vec()));
+    });
+
+    // A second connection is told when the command exits
+    let (exit_tx, exit_rx) = mpsc::channel();
+    let waiting_on = session.clone();
+    thread::spawn(move || {
+        let _ = exit_tx.send(PtyProcess::wait_for_exit(&waiting_on));
+    });
+
+    let mut stdout = io::stdout();
+    let mut decoder = stream.decoder();
+    let mut buffer = [0u8; 16384];
+    let mut exit_status = None;
+    loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                decoder.push(&buffer[..n]);
+                stdout.write_all(&decoder.take_data()?)?;
+                stdout.flush()?;
+                continue;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        // The daemon passes on all output before reporting the exit, so once
+        // it is reported a quiet read means everything has been received
+        if let Some(status) = exit_status {
+            return Ok(status);
+        }
+        if let Ok(result) = exit_rx.try_recv() {
+            exit_status = Some(result?);
+        }
+    }
+
+    // The daemon closed the connection
+    match exit_status {
+        Some(status) => Ok(status),
+        None => exit_rx.recv().unwrap_or_else(|_| {
+            Err(NdsError::ProcessError(format!(
+                "session {} ended before the command finished",
+                session.id
+            )))
+        }),
+    }
+}
+
+/// Blocks until a session's shell or command exits and returns its exit code
+pub fn handle_wait(session_id_or_name: &str) -> Result<i32> {
+    let session = find_session(session_id_or_name)?;
+    let status = SessionManager::wait_session(&session.id)?;
+    Ok(status.code())
+}
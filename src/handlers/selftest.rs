@@ -0,0 +1,241 @@
+use detached_shell::pty::protocol::{Connection, Frame, Role};
+use detached_shell::{
+    NdsError, PtyProcess, Result, Session, SessionEvent, SessionHistory, SessionManager,
+    SpawnOptions,
+};
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for the shell to produce expected output
+const OUTPUT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs a throwaway session through its whole lifecycle in a temporary
+/// NDS_HOME and reports each step, so users and packagers can check that
+/// PTYs, sockets and permissions work in their environment
+pub fn handle_selftest(keep: bool) -> Result<()> {
+    let home = std::env::temp_dir().join(format!(
+        "nds-selftest-{}",
+        &uuid::Uuid::new_v4().to_string()[..8]
+    ));
+    fs::create_dir_all(&home).map_err(|e| NdsError::DirectoryCreationError(e.to_string()))?;
+    fs::set_permissions(&home, fs::Permissions::from_mode(0o700))?;
+
+    // Everything below, including the forked session daemon, uses the temp home
+    std::env::set_var("NDS_HOME", &home);
+    println!("Running nds self-test in {}", home.display());
+    println!();
+
+    let mut test = SelfTest::default();
+    let session = test.run_lifecycle();
+
+    // Make sure nothing is left running if a step failed half-way
+    if let Some(session) = session {
+        if Session::is_process_alive(session.pid) {
+            let _ = SessionManager::kill_session(&session.id);
+        }
+    }
+
+    if keep {
+        println!();
+        println!("Kept test directory: {}", home.display());
+    } else {
+        let _ = fs::remove_dir_all(&home);
+    }
+
+    println!();
+    if test.failures == 0 {
+        println!("All {} checks passed", test.passed);
+        Ok(())
+    } else {
+        println!(
+            "{} of {} checks failed",
+            test.failures,
+            test.passed + test.failures
+        );
+        Err(NdsError::SelfTestFailed(format!(
+            "{} check(s) failed",
+            test.failures
+        )))
+    }
+}
+
+#[derive(Default)]
+struct SelfTest {
+    passed: usize,
+    failures: usize,
+}
+
+impl SelfTest {
+    fn check<T>(&mut self, description: &str, result: Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                println!("  ✓ {}", description);
+                self.passed += 1;
+                Some(value)
+            }
+            Err(e) => {
+                println!("  ✗ {}: {}", description, e);
+                self.failures += 1;
+                None
+            }
+        }
+    }
+
+    /// Returns the session if it was created, so the caller can clean it up
+    fn run_lifecycle(&mut self) -> Option<Session> {
+        let session = self.check(
+            "create session",
+            SessionManager::create_session_with_options(
+                Some("selftest".to_string()),
+                SpawnOptions::default(),
+            ),
+        )?;
+
+        self.check("session metadata written", check_metadata(&session));
+        self.check(
+            "socket is owner-only (0600)",
+            check_socket_permissions(&session.socket_path),
+        );
+
+        if let Some(mut stream) = self.check("attach to socket", connect(&session)) {
+            let marker = format!("ok-{}", &session.id[..8]);
+            // Print the marker in two halves so the echoed command line
+            // doesn't already contain it
+            let command = format!("printf '%s-%s\\n' nds-selftest {}\r", marker);
+            self.check(
+                "shell echoes marker",
+                send_and_expect(
+                    &mut stream,
+                    &[Frame::Data(command.into_bytes())],
+                    &format!("nds-selftest-{}", marker),
+                ),
+            );
+
+            let resize = [
+                Frame::command("resize", &["100", "30"]),
+                Frame::Data(b"stty size\r".to_vec()),
+            ];
+            self.check(
+                "resize reaches the shell",
+                send_and_expect(&mut stream, &resize, "30 100"),
+            );
+
+            drop(stream);
+            thread::sleep(Duration::from_millis(200));
+            self.check("session survives detach", check_alive(&session));
+        }
+
+        self.check("kill session", SessionManager::kill_session(&session.id));
+        self.check("session files cleaned up", check_cleaned_up(&session));
+        self.check("history recorded", check_history(&session));
+
+        Some(session)
+    }
+}
+
+fn check_metadata(session: &Session) -> Result<()> {
+    let loaded = Session::load(&session.id)?;
+    if loaded.name.as_deref() != Some("selftest") {
+        return Err(NdsError::SelfTestFailed(format!(
+            "unexpected name {:?}",
+            loaded.name
+        )));
+    }
+    Ok(())
+}
+
+fn check_socket_permissions(path: &Path) -> Result<()> {
+    let mode = fs::metadata(path)?.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        return Err(NdsError::PermissionDenied(format!(
+            "socket mode is {:o}",
+            mode
+        )));
+    }
+    Ok(())
+}
+
+fn connect(session: &Session) -> Result<Connection> {
+    let stream = PtyProcess::connect(session, Role::Attach)?;
+    stream
+        .stream()
+        .set_read_timeout(Some(Duration::from_millis(100)))?;
+    Ok(stream)
+}
+
+/// Send `frames` and read session output until `expected` shows up
+fn send_and_expect(stream: &mut Connection, frames: &[Frame], expected: &str) -> Result<()> {
+    for frame in frames {
+        stream.send(frame)?;
+    }
+
+    let deadline = Instant::now() + OUTPUT_TIMEOUT;
+    let mut decoder = stream.decoder();
+    let mut output = Vec::new();
+    let mut buf = [0u8; 4096];
+    while Instant::now() < deadline {
+        match stream.read(&mut buf) {
+            Ok(0) => {
+                return Err(NdsError::SelfTestFailed(
+                    "session closed the connection".to_string(),
+                ))
+            }
+            Ok(n) => {
+                decoder.push(&buf[..n]);
+                output.extend_from_slice(&decoder.take_data()?);
+                if String::from_utf8_lossy(&output).contains(expected) {
+                    return Ok(());
+                }
+            }
+            Err(e)
+                if e.kind() == std::io::ErrorKind::WouldBlock
+                    || e.kind() == std::io::ErrorKind::TimedOut => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Err(NdsError::SelfTestFailed(format!(
+        "no '{}' in output after {}s",
+        expected,
+        OUTPUT_TIMEOUT.as_secs()
+    )))
+}
+
+fn check_alive(session: &Session) -> Result<()> {
+    if !Session::is_process_alive(session.pid) {
+        return Err(NdsError::SelfTestFailed(
+            "session daemon exited".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn check_cleaned_up(session: &Session) -> Result<()> {
+    let metadata = Session::session_dir()?.join(format!("{}.json", session.id));
+    for path in [&metadata, &session.socket_path] {
+        if path.exists() {
+            return Err(NdsError::SelfTestFailed(format!(
+                "{} still exists",
+                path.display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn check_history(session: &Session) -> Result<()> {
+    let history = SessionHistory::load_session_history(&session.id)?;
+    let has = |wanted: fn(&SessionEvent) -> bool| history.entries.iter().any(|e| wanted(&e.event));
+
+    if !has(|e| matches!(e, SessionEvent::Created)) {
+        return Err(NdsError::SelfTestFailed("no Created entry".to_string()));
+    }
+    if !has(|e| matches!(e, SessionEvent::Killed)) {
+        return Err(NdsError::SelfTestFailed("no Killed entry".to_string()));
+    }
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use detached_shell::server::{self, ServerClient};
+use detached_shell::{NdsError, Result, SessionManager, SpawnOptions};
+use std::io::{self, Write};
+
+use super::session::unresolved;
+
+/// Creates a session and prints only its ID, for `nds server` to read.
+/// Returns the exit code to leave with.
+pub fn handle_new_session_id(name: Option<String>, options: SpawnOptions) -> i32 {
+    match SessionManager::create_session_with_options(name, options) {
+        Ok(session) => {
+            println!("{}", session.id);
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+pub fn handle_server_start() -> Result<()> {
+    let pid = server::start()?;
+    println!("Server started (PID {})", pid);
+    Ok(())
+}
+
+pub fn handle_server_run() -> Result<()> {
+    server::run()
+}
+
+pub fn handle_server_stop() -> Result<()> {
+    let mut client = ServerClient::connect()
+        .ok_or_else(|| NdsError::ServerError("the server is not running".into()))?;
+    client.stop()?;
+    println!("Server stopped; sessions keep running");
+    Ok(())
+}
+
+/// Shows whether the server is running. Returns the exit code to leave
+/// with: 0 if it is, 1 if not.
+pub fn handle_server_status() -> Result<i32> {
+    let Some(mut client) = ServerClient::connect() else {
+        println!("Server is not running");
+        return Ok(1);
+    };
+    let status = client.status()?;
+    println!("Server is running (PID {})", status.pid);
+    println!(
+        "Started: {}",
+        status
+            .started_at
+            .with_timezone(&chrono::Local)
+            .format("%Y-%m-%d %H:%M:%S")
+    );
+    println!("Sessions: {}", status.sessions);
+    println!("Subscribers: {}", status.subscribers);
+    Ok(0)
+}
+
+/// Calls `method` on the server and prints the result as JSON; events from
+/// `subscribe-events` are printed one per line as they come
+pub fn handle_rpc(method: &str, args: &[String]) -> Result<()> {
+    let mut client = ServerClient::connect().ok_or_else(|| {
+        NdsError::ServerError("the server is not running; start it with `nds server start`".into())
+    })?;
+    if matches!(method, "subscribe-events" | "subscribe") {
+        let mut stdout = io::stdout().lock();
+        for event in client.subscribe()? {
+            writeln!(stdout, "{}", serde_json::to_string(&event?)?)?;
+            stdout.flush()?;
+        }
+        return Ok(());
+    }
+    let result = client
+        .call(method, server::params_from_args(args)?)
+        .map_err(|e| match &e {
+            NdsError::AmbiguousSession { query, .. } => {
+                let query = query.clone();
+                unresolved(&query, e)
+            }
+            _ => e,
+        })?;
+    println!("{}", serde_json::to_string_pretty(&result)?);
+    Ok(())
+}
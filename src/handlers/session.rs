@@ -1,26 +1,60 @@
-use detached_shell::{NdsError, Result, Session, SessionManager};
+use chrono::Utc;
+use detached_shell::replay::{Cast, CastEvent, Player};
+use detached_shell::session_env::parse_env_file;
+use detached_shell::text_input;
+use detached_shell::{
+    AttachOptions, AttachOutcome, NdsError, ResizePolicy, Result, Session, SessionHistory,
+    SessionManager, SessionSelector, Signal, SpawnOptions, DEFAULT_RECORDING_MAX_BYTES,
+};
+use std::collections::BTreeMap;
+use std::io::{self, IsTerminal, Write};
 use std::thread;
 use std::time::Duration;
 
+use super::remote::print_remote_hint;
+
 /// Creates a new detached shell session with optional name
-pub fn handle_new_session(name: Option<String>, attach: bool) -> Result<()> {
+///
+/// With `attach_if_exists`, an existing session with the same name is reused
+/// instead, which makes `nds new <name>` safe to run repeatedly.
+pub fn handle_new_session(
+    name: Option<String>,
+    attach: bool,
+    attach_if_exists: bool,
+    options: SpawnOptions,
+) -> Result<()> {
+    if attach_if_exists {
+        if let Some(ref session_name) = name {
+            if let Some(existing) = SessionManager::find_session_by_name(session_name)? {
+                if attach {
+                    println!("Attaching to existing session '{}'...", session_name);
+                    return attach_to(&existing, AttachOptions::default());
+                }
+                println!("Session '{}' already exists: {}", session_name, existing.id);
+                return Ok(());
+            }
+        }
+    }
+
     if let Some(ref session_name) = name {
         println!("Creating new session '{}'...", session_name);
     } else {
         println!("Creating new session...");
     }
 
-    match SessionManager::create_session_with_name(name) {
+    let listen = options.remote.as_ref().map(|remote| remote.address.clone());
+    match SessionManager::create_session_with_options(name, options) {
         Ok(session) => {
             println!("Created session: {}", session.id);
             println!("PID: {}", session.pid);
-            println!("Socket: {}", session.socket_path.display());
+            println!("Socket: {}", session.socket_label());
+            print_remote_hint(&session, listen.as_deref());
 
             if attach {
                 println!("\nAttaching to session...");
                 // Give the session a moment to fully initialize
                 thread::sleep(Duration::from_millis(100));
-                handle_attach_session(&session.id)?;
+                handle_attach_session(&session.id, AttachOptions::default())?;
             } else {
                 println!("\nTo attach to this session, run:");
                 println!("  nds attach {}", session.id);
@@ -34,206 +68,753 @@ pub fn handle_new_session(name: Option<String>, attach: bool) -> Result<()> {
     }
 }
 
-/// Attaches to an existing session by ID or name (supports partial matching)
-pub fn handle_attach_session(session_id_or_name: &str) -> Result<()> {
-    // Allow partial ID or name matching
-    let sessions = SessionManager::list_sessions()?;
-
-    // First try to match by ID
-    let mut matching_sessions: Vec<_> = sessions
-        .iter()
-        .filter(|s| s.id.starts_with(session_id_or_name))
-        .collect();
-
-    // If no ID matches, try matching by name
-    if matching_sessions.is_empty() {
-        matching_sessions = sessions
-            .iter()
-            .filter(|s| {
-                if let Some(ref name) = s.name {
-                    name == session_id_or_name
-                        || name.starts_with(session_id_or_name)
-                        || name
-                            .to_lowercase()
-                            .starts_with(&session_id_or_name.to_lowercase())
-                } else {
-                    false
-                }
-            })
-            .collect();
+/// Attaches to an existing session by index, ID or name (see `resolve`)
+pub fn handle_attach_session(session_id_or_name: &str, options: AttachOptions) -> Result<()> {
+    if let Some(group) = session_id_or_name.strip_prefix('@') {
+        return attach_group(group, options);
     }
+    let session = find_session(session_id_or_name)?;
+    attach_to(&session, options)
+}
+
+/// Picks one of the sessions in `group` and attaches to it, with `~n` and
+/// `~p` going on to the group's others
+fn attach_group(group: &str, options: AttachOptions) -> Result<()> {
+    use detached_shell::interactive::InteractivePicker;
 
-    match matching_sessions.len() {
-        0 => {
+    let mut picker = match InteractivePicker::with_group(group) {
+        Ok(picker) => picker,
+        Err(e @ NdsError::SessionNotFound(_)) => {
             eprintln!(
-                "No session found matching ID or name: {}",
-                session_id_or_name
+                "No active sessions in group {} (see 'nds group list')",
+                group
             );
-            Err(NdsError::SessionNotFound(session_id_or_name.to_string()))
+            return Err(e);
         }
-        1 => {
-            let session = matching_sessions[0];
-            SessionManager::attach_session(&session.id)?;
-            Ok(())
-        }
-        _ => {
-            eprintln!(
-                "Multiple sessions match '{}'. Please be more specific:",
-                session_id_or_name
-            );
-            for session in matching_sessions {
-                eprintln!("  - {}", session.display_name());
-            }
-            Err(NdsError::InvalidSessionId(session_id_or_name.to_string()))
+        Err(e) => return Err(e),
+    };
+    let Some(session_id) = picker.run()? else {
+        println!("No session selected.");
+        return Ok(());
+    };
+    let session = Session::load(&session_id)?;
+    attach_to(
+        &session,
+        AttachOptions {
+            group: Some(group.to_string()),
+            ..options
+        },
+    )
+}
+
+/// Attaches to `session` and says how it ended
+fn attach_to(session: &Session, options: AttachOptions) -> Result<()> {
+    if session.attached && !options.detach_others && !options.raw {
+        // The terminal may have closed without detaching
+        eprintln!(
+            "Warning: Session {} appears to be already attached.",
+            session.id
+        );
+        eprintln!("Attempting to attach anyway (previous connection may have been lost).");
+    }
+    report_attach(SessionManager::attach_session_with_options(
+        &session.id,
+        options,
+    )?)
+}
+
+/// Tells the user about a session that turned out to be dead, which is an
+/// error; detaching is not
+pub fn report_attach(outcome: AttachOutcome) -> Result<()> {
+    match outcome {
+        AttachOutcome::Detached { .. } => Ok(()),
+        AttachOutcome::Dead { session_id, pid } => {
+            eprintln!("Session {} appears to be dead.", session_id);
+            eprintln!("The process (PID {}) is no longer running.", pid);
+            eprintln!("Cleaned up the dead session.");
+            Err(NdsError::SessionNotFound(format!(
+                "Session {} was dead and has been cleaned up. Create a new session with 'nds new'.",
+                session_id
+            )))
         }
     }
 }
 
-/// Kills one or more sessions by ID or name
-pub fn handle_kill_sessions(session_ids: &[String]) -> Result<()> {
-    if session_ids.is_empty() {
+/// Kills one or more sessions by ID, name, or glob pattern
+///
+/// With `all` every active session is selected; with `detached_only` the
+/// selection is narrowed to sessions without attached clients (and, when no
+/// IDs are given, every detached session is selected). Unless `force` is set,
+/// killing several sessions or sessions with attached clients asks first.
+pub fn handle_kill_sessions(
+    session_ids: &[String],
+    all: bool,
+    detached_only: bool,
+    tags: &[String],
+    force: bool,
+) -> Result<()> {
+    if session_ids.is_empty() && !all && !detached_only && tags.is_empty() {
         eprintln!("No session IDs provided");
         return Err(NdsError::SessionNotFound(
             "No session IDs provided".to_string(),
         ));
     }
 
-    let sessions = SessionManager::list_sessions()?;
-    let mut killed_count = 0;
+    let selection = SessionManager::select_sessions(&SessionSelector {
+        queries: session_ids.to_vec(),
+        all,
+        detached_only,
+        tags: tags.to_vec(),
+    })?;
     let mut errors = Vec::new();
-
-    for session_id in session_ids {
-        match kill_single_session(session_id, &sessions) {
-            Ok(killed_id) => {
-                println!("Killed session: {}", killed_id);
-                killed_count += 1;
-            }
-            Err(e) => {
-                eprintln!("Error killing session '{}': {}", session_id, e);
-                errors.push(format!("{}: {}", session_id, e));
+    for (query, e) in &selection.unmatched {
+        eprintln!("Error killing session '{}': {}", query, e);
+        if let NdsError::AmbiguousSession { matches, .. } = e {
+            for session in matches {
+                eprintln!("  - {}", session.display_name());
             }
         }
+        errors.push(format!("{}: {}", query, e));
+    }
+
+    if selection.sessions.is_empty() && errors.is_empty() {
+        println!("No matching sessions to kill");
+        return Ok(());
+    }
+
+    if !force && selection.needs_confirmation() && !confirm_kill(&selection.sessions)? {
+        println!("Aborted, no sessions killed");
+        return Ok(());
+    }
+
+    let report = SessionManager::kill_sessions(&selection.sessions);
+    for session in &report.killed {
+        println!("Killed session: {}", session.id);
+    }
+    for (id, e) in &report.failed {
+        eprintln!("Error killing session '{}': {}", id, e);
+        errors.push(format!("{}: {}", id, e));
     }
 
-    if killed_count > 0 {
-        println!("Successfully killed {} session(s)", killed_count);
+    if !report.killed.is_empty() {
+        println!("Successfully killed {} session(s)", report.killed.len());
     }
 
-    if !errors.is_empty() && killed_count == 0 {
+    if !errors.is_empty() && report.killed.is_empty() {
         Err(NdsError::SessionNotFound(errors.join(", ")))
     } else {
         Ok(())
     }
 }
 
-/// Helper function to kill a single session with partial matching support
-fn kill_single_session(session_id_or_name: &str, sessions: &[Session]) -> Result<String> {
-    // Allow partial ID or name matching
-    let mut matching_sessions: Vec<_> = sessions
-        .iter()
-        .filter(|s| s.id.starts_with(session_id_or_name))
-        .collect();
-
-    // If no ID matches, try matching by name
-    if matching_sessions.is_empty() {
-        matching_sessions = sessions
-            .iter()
-            .filter(|s| {
-                if let Some(ref name) = s.name {
-                    name == session_id_or_name
-                        || name.starts_with(session_id_or_name)
-                        || name
-                            .to_lowercase()
-                            .starts_with(&session_id_or_name.to_lowercase())
-                } else {
-                    false
-                }
-            })
-            .collect();
+/// Show the sessions about to be killed and ask the user to confirm
+fn confirm_kill(targets: &[Session]) -> Result<bool> {
+    println!("The following session(s) will be killed:");
+    for session in targets {
+        let uptime = (Utc::now() - session.created_at).num_seconds().max(0);
+        let client_count = session.get_client_count();
+        println!(
+            "  {:<30} up {:<12} {} client{}",
+            session.display_name(),
+            SessionHistory::format_duration(uptime),
+            client_count,
+            if client_count == 1 { "" } else { "s" }
+        );
     }
 
-    match matching_sessions.len() {
-        0 => Err(NdsError::SessionNotFound(format!(
-            "No session found matching ID or name: {}",
-            session_id_or_name
-        ))),
-        1 => {
-            let session = matching_sessions[0];
-            SessionManager::kill_session(&session.id)?;
-            Ok(session.id.clone())
-        }
-        _ => {
-            let matches: Vec<String> = matching_sessions.iter().map(|s| s.display_name()).collect();
-            Err(NdsError::SessionNotFound(format!(
-                "Multiple sessions match '{}': {}. Please be more specific",
-                session_id_or_name,
-                matches.join(", ")
-            )))
-        }
+    if !io::stdin().is_terminal() {
+        eprintln!("Refusing to kill without confirmation; re-run with --force");
+        return Err(NdsError::PermissionDenied(
+            "kill requires confirmation (use --force)".to_string(),
+        ));
     }
+
+    print!("Kill {} session(s)? [y/N] ", targets.len());
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
 }
 
 /// Renames a session
-pub fn handle_rename_session(session_id_or_name: &str, new_name: &str) -> Result<()> {
-    // Allow partial ID or name matching
-    let sessions = SessionManager::list_sessions()?;
-
-    // First try to match by ID
-    let mut matching_sessions: Vec<_> = sessions
-        .iter()
-        .filter(|s| s.id.starts_with(session_id_or_name))
-        .collect();
-
-    // If no ID matches, try matching by name
-    if matching_sessions.is_empty() {
-        matching_sessions = sessions
-            .iter()
-            .filter(|s| {
-                if let Some(ref name) = s.name {
-                    name == session_id_or_name
-                        || name.starts_with(session_id_or_name)
-                        || name
-                            .to_lowercase()
-                            .starts_with(&session_id_or_name.to_lowercase())
-                } else {
-                    false
+pub fn handle_rename_session(session_id_or_name: &str, new_name: Option<&str>) -> Result<()> {
+    let session = find_session(session_id_or_name)?;
+    let old_display_name = session.display_name();
+
+    let new_name = match new_name {
+        Some(new_name) => {
+            if let Err(e) = SessionManager::rename_session(&session.id, new_name) {
+                eprintln!("Failed to rename session: {}", e);
+                return Err(e);
+            }
+            new_name.to_string()
+        }
+        None => {
+            if !io::stdin().is_terminal() {
+                eprintln!("No new name given and no terminal to prompt for one");
+                return Err(NdsError::InvalidSessionName(
+                    "new name required".to_string(),
+                ));
+            }
+            match prompt_rename(&session)? {
+                Some(new_name) => new_name,
+                None => {
+                    println!("Rename cancelled");
+                    return Ok(());
                 }
-            })
-            .collect();
+            }
+        }
+    };
+    println!("Renamed session {} to '{}'", old_display_name, new_name);
+    Ok(())
+}
+
+/// Adds or removes tags on a session, or lists them when none are given
+pub fn handle_tag_session(session_id_or_name: &str, tags: &[String], remove: bool) -> Result<()> {
+    let session = find_session(session_id_or_name)?;
+
+    if tags.is_empty() {
+        if session.tags.is_empty() {
+            println!("Session {} has no tags", session.display_name());
+        } else {
+            println!("{}", session.tags_label());
+        }
+        return Ok(());
     }
 
-    match matching_sessions.len() {
-        0 => {
-            eprintln!(
-                "No session found matching ID or name: {}",
-                session_id_or_name
+    let result = if remove {
+        SessionManager::tag_session(&session.id, &[], tags)
+    } else {
+        SessionManager::tag_session(&session.id, tags, &[])
+    };
+
+    match result {
+        Ok(tags) if tags.is_empty() => {
+            println!("Session {} has no tags", session.display_name());
+            Ok(())
+        }
+        Ok(tags) => {
+            let labels: Vec<_> = tags.iter().map(|tag| format!("#{}", tag)).collect();
+            println!(
+                "Session {} tags: {}",
+                session.display_name(),
+                labels.join(" ")
             );
-            Err(NdsError::SessionNotFound(session_id_or_name.to_string()))
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("Failed to tag session: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Detaches every client from a session, by default the one this command
+/// runs inside, so scripts can do what `~d` does. With `client_id` only that
+/// client is detached.
+pub fn handle_detach_session(
+    session_id_or_name: Option<&str>,
+    client_id: Option<&str>,
+) -> Result<()> {
+    if let (Some(session_id_or_name), Some(client_id)) = (session_id_or_name, client_id) {
+        return super::handle_disconnect_client(session_id_or_name, client_id);
+    }
+
+    let session = match session_id_or_name {
+        Some(id) => find_session(id)?,
+        None => {
+            let Ok(id) = std::env::var("NDS_SESSION_ID") else {
+                eprintln!("Not inside an nds session; pass a session ID or name");
+                return Err(NdsError::NotInSession);
+            };
+            Session::load(&id)?
+        }
+    };
+    SessionManager::detach_session(&session.id)?;
+    println!(
+        "Detached all clients from session {}",
+        session.display_name()
+    );
+    Ok(())
+}
+
+/// Shows or changes how a session's PTY is sized for its clients
+pub fn handle_resize_session(session_id_or_name: &str, policy: Option<ResizePolicy>) -> Result<()> {
+    let session = find_session(session_id_or_name)?;
+    let Some(policy) = policy else {
+        println!(
+            "Resize policy for session {}: {}",
+            session.display_name(),
+            session.resize
+        );
+        return Ok(());
+    };
+
+    match SessionManager::set_resize_policy(&session.id, policy)? {
+        Some((cols, rows)) => println!(
+            "Resize policy for session {} set to {} (PTY is now {}x{})",
+            session.display_name(),
+            policy,
+            cols,
+            rows
+        ),
+        None => println!(
+            "Resize policy for session {} set to {}",
+            session.display_name(),
+            policy
+        ),
+    }
+    Ok(())
+}
+
+/// Shows or turns on or off whether clients attaching to the session wait to
+/// be let in by one already attached
+pub fn handle_approve_attach(session_id_or_name: &str, enabled: Option<bool>) -> Result<()> {
+    let session = find_session(session_id_or_name)?;
+    let enabled = match enabled {
+        Some(enabled) => {
+            SessionManager::set_approve_attach(&session.id, enabled)?;
+            enabled
         }
-        1 => {
-            let session = matching_sessions[0];
-            let old_display_name = session.display_name();
-            SessionManager::rename_session(&session.id, new_name)?;
-            println!("Renamed session {} to '{}'", old_display_name, new_name);
+        None => session.approve_attach,
+    };
+    println!(
+        "Attach approval for session {} is {}",
+        session.display_name(),
+        if enabled { "on" } else { "off" }
+    );
+    Ok(())
+}
+
+/// Kills and re-execs a session's shell or command, keeping its ID, name,
+/// history and attached clients
+pub fn handle_restart_session(session_id_or_name: &str) -> Result<()> {
+    let session = find_session(session_id_or_name)?;
+    SessionManager::restart_session(&session.id)?;
+    println!("Restarted the shell of session {}", session.display_name());
+    Ok(())
+}
+
+/// Sends a signal to the foreground process group of a session's terminal
+pub fn handle_signal_session(session_id_or_name: &str, signal: Signal) -> Result<()> {
+    let session = find_session(session_id_or_name)?;
+    match SessionManager::signal_session(&session.id, signal) {
+        Ok(pgid) => {
+            println!(
+                "Sent {} to process group {} in session {}",
+                signal,
+                pgid,
+                session.display_name()
+            );
             Ok(())
         }
-        _ => {
+        Err(e) => {
+            eprintln!("Failed to signal session {}: {}", session.display_name(), e);
+            Err(e)
+        }
+    }
+}
+
+/// Shows a message on the bottom row of a session's attached clients
+pub fn handle_message_session(
+    session_id_or_name: &str,
+    text: &str,
+    duration: Option<chrono::Duration>,
+) -> Result<()> {
+    let session = find_session(session_id_or_name)?;
+    let duration = duration.and_then(|d| d.to_std().ok());
+    let shown = SessionManager::show_message(&session.id, text, duration)?;
+    if shown == 0 {
+        println!("No clients are attached to {}", session.display_name());
+    }
+    Ok(())
+}
+
+/// Writes a session's screen, or all of its scrollback, to a file or stdout
+pub fn handle_capture_session(
+    session_id_or_name: &str,
+    scrollback: bool,
+    output: Option<&std::path::Path>,
+    strip_ansi: bool,
+) -> Result<()> {
+    let session = find_session(session_id_or_name)?;
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => Box::new(io::BufWriter::new(std::fs::File::create(path)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+
+    let lines = if scrollback {
+        SessionManager::capture_scrollback(&session.id, &mut out, strip_ansi)?
+    } else {
+        let rows = SessionManager::capture_screen(&session.id)?;
+        for row in &rows {
+            writeln!(out, "{}", row)?;
+        }
+        out.flush()?;
+        rows.len() as u64
+    };
+
+    if let Some(path) = output {
+        let what = if scrollback { "scrollback" } else { "screen" };
+        println!(
+            "Saved {} lines of {} from {} to {}",
+            lines,
+            what,
+            session.display_name(),
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Starts recording a session's output to an asciicast file, or stops it
+pub fn handle_record_session(
+    session_id_or_name: &str,
+    output: Option<&std::path::Path>,
+    max_size: Option<u64>,
+    stop: bool,
+) -> Result<()> {
+    let session = find_session(session_id_or_name)?;
+    if stop {
+        match SessionManager::stop_recording(&session.id)? {
+            Some(summary) => {
+                let more = match summary.files {
+                    1 => String::new(),
+                    2 => " and 1 more file".to_string(),
+                    n => format!(" and {} more files", n - 1),
+                };
+                println!(
+                    "Stopped recording {} after {}: {}{}",
+                    session.display_name(),
+                    SessionHistory::format_duration(summary.seconds as i64),
+                    summary.path.display(),
+                    more
+                );
+            }
+            None => println!("{} isn't being recorded", session.display_name()),
+        }
+        return Ok(());
+    }
+
+    let path = match output {
+        Some(path) => path.to_path_buf(),
+        None => format!(
+            "{}-{}.cast",
+            session.name.as_deref().unwrap_or(&session.id),
+            chrono::Local::now().format("%Y%m%d-%H%M%S")
+        )
+        .into(),
+    };
+    // The daemon doesn't run in this directory
+    let path = std::path::absolute(path)?;
+    let max_bytes = max_size.unwrap_or(DEFAULT_RECORDING_MAX_BYTES);
+    SessionManager::start_recording(&session.id, &path, max_bytes)?;
+    println!("Recording {} to {}", session.display_name(), path.display());
+    println!(
+        "Stop with: nds record {} --stop",
+        session.name.as_deref().unwrap_or(&session.id)
+    );
+    Ok(())
+}
+
+/// Plays back a recording from `nds record`, `asciinema rec` or `script`.
+/// Without a terminal to play on, its output is written straight out.
+pub fn handle_replay(
+    path: &std::path::Path,
+    timing: Option<&std::path::Path>,
+    speed: f64,
+    idle_limit: Option<f64>,
+) -> Result<()> {
+    let mut cast = Cast::load(path, timing)?;
+    if let Some(limit) = idle_limit {
+        cast.limit_idle(limit);
+    }
+
+    if !io::stdout().is_terminal() {
+        let mut out = io::stdout().lock();
+        for (_, event) in &cast.events {
+            if let CastEvent::Output(data) = event {
+                out.write_all(data.as_bytes())?;
+            }
+        }
+        out.flush()?;
+        return Ok(());
+    }
+    Player::new(cast, speed).run()
+}
+
+/// Types the text last copied in a session's scrollback viewer into it
+pub fn handle_paste_session(session_id_or_name: &str) -> Result<()> {
+    let session = find_session(session_id_or_name)?;
+    if SessionManager::paste(&session.id)?.is_none() {
+        println!(
+            "Nothing to paste into {}: copy text in its scrollback viewer (~h, then v) first",
+            session.display_name()
+        );
+    }
+    Ok(())
+}
+
+/// Sets, clears or shows the note attached to a session
+pub fn handle_note_session(session_id_or_name: &str, note: Option<&str>) -> Result<()> {
+    let session = find_session(session_id_or_name)?;
+
+    let Some(note) = note else {
+        match &session.note {
+            Some(note) => println!("{}", note),
+            None => println!("Session {} has no note", session.display_name()),
+        }
+        return Ok(());
+    };
+
+    if let Err(e) = SessionManager::set_note(&session.id, note) {
+        eprintln!("Failed to set note: {}", e);
+        return Err(e);
+    }
+    if note.trim().is_empty() {
+        println!("Cleared note on session {}", session.display_name());
+    } else {
+        println!("Set note on session {}", session.display_name());
+    }
+    Ok(())
+}
+
+/// The one live session `query` means, saying why when there isn't one
+pub(crate) fn find_session(query: &str) -> Result<Session> {
+    SessionManager::resolve(query)
+        .map(|resolved| resolved.session)
+        .map_err(|e| unresolved(query, e))
+}
+
+/// Say why `query` didn't pick a session, listing the candidates when it
+/// picked several. The candidates are left out of the error returned, so
+/// leaving `main` with it prints one short line.
+pub(crate) fn unresolved(query: &str, e: NdsError) -> NdsError {
+    match e {
+        NdsError::SessionNotFound(_) => {
+            eprintln!("No session found matching ID or name: {}", query);
+            e
+        }
+        NdsError::AmbiguousSession { matches, .. } => {
             eprintln!(
                 "Multiple sessions match '{}'. Please be more specific:",
-                session_id_or_name
+                query
             );
-            for session in matching_sessions {
+            for session in matches {
                 eprintln!("  - {}", session.display_name());
             }
-            Err(NdsError::InvalidSessionId(session_id_or_name.to_string()))
+            NdsError::InvalidSessionId(query.to_string())
         }
+        e => e,
     }
 }
 
+/// Ask for a new name in an inline prompt pre-filled with the current one;
+/// the rename happens on submit so errors can be corrected in place
+fn prompt_rename(session: &Session) -> Result<Option<String>> {
+    text_input::prompt(
+        "RENAME SESSION",
+        &format!("New name for {}", session.display_name()),
+        session.name.as_deref().unwrap_or(""),
+        |name| SessionManager::rename_session(&session.id, name),
+    )
+}
+
 /// Cleans up dead sessions
 pub fn handle_clean_sessions() -> Result<()> {
     println!("Cleaning up dead sessions...");
-    SessionManager::cleanup_dead_sessions()?;
+    print_cleaned(&SessionManager::cleanup_dead_sessions()?);
     println!("Cleanup complete.");
     Ok(())
 }
+
+fn print_cleaned(cleaned: &[Session]) {
+    for session in cleaned {
+        println!("Cleaned up dead session: {}", session.display_name());
+    }
+    if cleaned.is_empty() {
+        println!("No dead sessions found");
+    } else {
+        println!("Cleaned up {} dead session(s)", cleaned.len());
+    }
+}
+
+/// Removes dead sessions and optionally kills sessions that have been idle
+/// longer than `idle`, or gone without input longer than `no_input`
+pub fn handle_prune_sessions(
+    idle: Option<chrono::Duration>,
+    no_input: Option<chrono::Duration>,
+    detached_only: bool,
+    dry_run: bool,
+    force: bool,
+) -> Result<()> {
+    if !dry_run {
+        print_cleaned(&SessionManager::cleanup_dead_sessions()?);
+    }
+
+    if idle.is_none() && no_input.is_none() {
+        return Ok(());
+    }
+
+    let targets = SessionManager::idle_sessions(idle, no_input, detached_only)?;
+
+    if targets.is_empty() {
+        let mut limits = Vec::new();
+        if let Some(idle) = idle {
+            limits.push(format!(
+                "idle for more than {}",
+                SessionHistory::format_duration(idle.num_seconds())
+            ));
+        }
+        if let Some(no_input) = no_input {
+            limits.push(format!(
+                "without input for more than {}",
+                SessionHistory::format_duration(no_input.num_seconds())
+            ));
+        }
+        println!("No sessions {}", limits.join(" and "));
+        return Ok(());
+    }
+
+    if dry_run {
+        println!("Would kill {} idle session(s):", targets.len());
+        for session in &targets {
+            println!(
+                "  {:<30} idle {}, no input for {}",
+                session.display_name(),
+                SessionHistory::format_duration(session.idle_duration().num_seconds()),
+                SessionHistory::format_duration(session.input_idle_duration().num_seconds())
+            );
+        }
+        return Ok(());
+    }
+
+    if !force && !confirm_kill(&targets)? {
+        println!("Aborted, no sessions pruned");
+        return Ok(());
+    }
+
+    let report = SessionManager::kill_sessions(&targets);
+    for session in &report.killed {
+        println!("Pruned idle session: {}", session.display_name());
+    }
+    for (id, e) in &report.failed {
+        eprintln!("Error killing session '{}': {}", id, e);
+    }
+    println!("Pruned {} idle session(s)", report.killed.len());
+    Ok(())
+}
+
+/// Combine `--env-file` contents with `--env` assignments; later values win
+pub fn collect_env(
+    assignments: &[(String, String)],
+    env_file: Option<&std::path::Path>,
+) -> Result<BTreeMap<String, String>> {
+    let mut env = BTreeMap::new();
+    if let Some(path) = env_file {
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            NdsError::InvalidEnvironment(format!("cannot read {}: {}", path.display(), e))
+        })?;
+        env.extend(parse_env_file(&content)?);
+    }
+    env.extend(assignments.iter().cloned());
+    Ok(env)
+}
+
+/// Parses terminal sizes like `200x50` (columns x rows)
+pub fn parse_size(input: &str) -> std::result::Result<(u16, u16), String> {
+    let (cols, rows) = input
+        .trim()
+        .split_once(['x', 'X'])
+        .ok_or_else(|| format!("invalid size '{}' (expected COLSxROWS, e.g. 200x50)", input))?;
+
+    let parse = |value: &str| {
+        value
+            .parse::<u16>()
+            .ok()
+            .filter(|v| (1..=9999).contains(v))
+            .ok_or_else(|| format!("invalid size '{}' (dimensions must be 1-9999)", input))
+    };
+
+    Ok((parse(cols)?, parse(rows)?))
+}
+
+/// Parses signals by name with or without the `SIG` prefix, in any case
+/// (`INT`, `sigterm`), or by number (`9`)
+pub fn parse_signal(input: &str) -> std::result::Result<Signal, String> {
+    let input = input.trim();
+    if let Ok(number) = input.parse::<i32>() {
+        return Signal::try_from(number).map_err(|_| format!("unknown signal number {}", number));
+    }
+    let name = input.to_ascii_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{}", name)
+    };
+    name.parse()
+        .map_err(|_| format!("unknown signal '{}' (e.g. INT, TERM, HUP or 9)", input))
+}
+
+/// Parses sizes in bytes like `512K`, `100M` or `2G` (bare numbers are bytes)
+pub fn parse_bytes(input: &str) -> std::result::Result<u64, String> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+
+    let value: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", input))?;
+
+    let shift = match unit.to_ascii_uppercase().trim_end_matches('B') {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        _ => return Err(format!("invalid size unit '{}' (use K, M or G)", unit)),
+    };
+
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size '{}' is too big", input))
+}
+
+/// Parses durations like `90s`, `30m`, `12h`, `7d` or `2w` (bare numbers are seconds)
+pub fn parse_duration(input: &str) -> std::result::Result<chrono::Duration, String> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+
+    let value: i64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", input))?;
+
+    let multiplier = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        _ => {
+            return Err(format!(
+                "invalid duration unit '{}' (use s, m, h, d or w)",
+                unit
+            ))
+        }
+    };
+
+    // Durations are taken from now, so they have to leave a valid date
+    value
+        .checked_mul(multiplier)
+        .and_then(chrono::Duration::try_seconds)
+        .filter(|duration| *duration <= MAX_DURATION)
+        .ok_or_else(|| format!("duration '{}' is too long", input))
+}
+
+/// The longest duration `parse_duration` accepts
+const MAX_DURATION: chrono::Duration = chrono::Duration::days(1000 * 365);
@@ -0,0 +1,90 @@
+use detached_shell::{AttachOptions, Result, Session, SessionManager, ShareRole};
+use nix::unistd::{Uid, User};
+use std::path::Path;
+
+use super::session::{find_session, report_attach};
+
+/// Shares a session with `with`, or stops sharing it with `remove`; with
+/// neither, shows who it is shared with
+pub fn handle_share_session(
+    session_id_or_name: &str,
+    with: Option<&str>,
+    read_only: bool,
+    remove: Option<&str>,
+) -> Result<()> {
+    let session = find_session(session_id_or_name)?;
+
+    if let Some(user) = remove {
+        if !session.shared_with.contains_key(user) {
+            println!(
+                "Session {} isn't shared with {}",
+                session.display_name(),
+                user
+            );
+            return Ok(());
+        }
+        SessionManager::share_session(&session.id, user, None)?;
+        println!(
+            "Stopped sharing session {} with {}",
+            session.display_name(),
+            user
+        );
+        return Ok(());
+    }
+
+    let Some(user) = with else {
+        if session.shared_with.is_empty() {
+            println!("Session {} isn't shared", session.display_name());
+            return Ok(());
+        }
+        println!("Session {} is shared with:", session.display_name());
+        for (user, role) in &session.shared_with {
+            println!("  {:<16} {}", user, role);
+        }
+        let path = Session::shared_socket_dir().join(format!("{}.sock", session.id));
+        print_attach_hint(&path);
+        return Ok(());
+    };
+
+    let role = if read_only {
+        ShareRole::ReadOnly
+    } else {
+        ShareRole::Interactive
+    };
+    let path = SessionManager::share_session(&session.id, user, Some(role))?;
+    println!(
+        "Shared session {} with {} ({})",
+        session.display_name(),
+        user,
+        role
+    );
+    if let Some(path) = path {
+        print_attach_hint(&path);
+    }
+    Ok(())
+}
+
+/// Attaches to a session another user shared, through its shared socket
+pub fn handle_attach_shared(socket_path: &Path, options: AttachOptions) -> Result<()> {
+    report_attach(SessionManager::attach_shared(socket_path, options)?)
+}
+
+fn print_attach_hint(path: &Path) {
+    println!("They attach with:");
+    println!("  nds attach --socket {}", path.display());
+}
+
+/// Parses a user on this machine by name or uid, to the user's name
+pub fn parse_user(input: &str) -> std::result::Result<String, String> {
+    let user = match input.parse::<u32>() {
+        Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+        Err(_) => User::from_name(input),
+    };
+    match user {
+        Ok(Some(user)) if user.uid == Uid::effective() => {
+            Err(format!("'{}' is you; the session is already yours", input))
+        }
+        Ok(Some(user)) => Ok(user.name),
+        _ => Err(format!("no such user '{}'", input)),
+    }
+}
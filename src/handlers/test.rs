@@ -12,9 +12,33 @@ mod tests {
             pid: 12345,
             created_at: chrono::Utc::now(),
             socket_path: temp_dir.path().join("test.sock"),
+            abstract_socket: None,
             shell: "/bin/bash".to_string(),
             working_dir: "/home/test".to_string(),
             attached: false,
+            last_activity: None,
+            last_output: None,
+            last_input: None,
+            bell: None,
+            redraw: None,
+            index: None,
+            env: Default::default(),
+            extra_env: Default::default(),
+            client_env: Default::default(),
+            size: None,
+            tags: Vec::new(),
+            note: None,
+            command: None,
+            exit_status: None,
+            respawn: detached_shell::RespawnPolicy::Never,
+            respawn_count: 0,
+            resize: Default::default(),
+            protocol: detached_shell::pty::protocol::PROTOCOL_VERSION,
+            approve_attach: false,
+            shared_with: Default::default(),
+            links: Vec::new(),
+            group: None,
+            remote: None,
         }
     }
 
@@ -97,6 +121,183 @@ mod tests {
         }
     }
 
+    mod kill_filters {
+        use detached_shell::manager::{glob_match, is_glob_pattern};
+
+        #[test]
+        fn test_glob_detection() {
+            assert!(is_glob_pattern("build-*"));
+            assert!(is_glob_pattern("web-?"));
+            assert!(!is_glob_pattern("build"));
+        }
+
+        #[test]
+        fn test_glob_star_matching() {
+            assert!(glob_match("build-*", "build-frontend"));
+            assert!(glob_match("build-*", "build-"));
+            assert!(glob_match("*-api", "staging-api"));
+            assert!(glob_match("*", "anything"));
+            assert!(!glob_match("build-*", "rebuild-frontend"));
+        }
+
+        #[test]
+        fn test_glob_question_and_backtracking() {
+            assert!(glob_match("web-?", "web-1"));
+            assert!(!glob_match("web-?", "web-12"));
+            assert!(glob_match("a*b*c", "axxbyybzc"));
+            assert!(!glob_match("a*b*c", "axxbyy"));
+        }
+    }
+
+    mod prune_filters {
+        use super::*;
+        use crate::handlers::session::parse_duration;
+        use detached_shell::manager::select_prune_targets;
+
+        #[test]
+        fn test_parse_duration_units() {
+            assert_eq!(parse_duration("45").unwrap().num_seconds(), 45);
+            assert_eq!(parse_duration("30m").unwrap().num_minutes(), 30);
+            assert_eq!(parse_duration("12h").unwrap().num_hours(), 12);
+            assert_eq!(parse_duration("7d").unwrap().num_days(), 7);
+            assert_eq!(parse_duration("2w").unwrap().num_days(), 14);
+        }
+
+        #[test]
+        fn test_parse_duration_rejects_garbage() {
+            assert!(parse_duration("").is_err());
+            assert!(parse_duration("d").is_err());
+            assert!(parse_duration("7y").is_err());
+            assert!(parse_duration("1h30m").is_err());
+        }
+
+        #[test]
+        fn test_parse_duration_rejects_overflow() {
+            assert!(parse_duration("9999999999999999w").is_err());
+            assert!(parse_duration("99999999999999999").is_err());
+            assert!(parse_duration("99999999999999999999").is_err());
+            let longest = parse_duration("52000w").unwrap();
+            assert!(chrono::Utc::now().checked_sub_signed(longest).is_some());
+        }
+
+        #[test]
+        fn test_prune_uses_last_activity() {
+            let now = chrono::Utc::now();
+            let mut old_but_busy = create_mock_session("busy", None);
+            old_but_busy.created_at = now - chrono::Duration::days(30);
+            old_but_busy.last_activity = Some(now - chrono::Duration::minutes(5));
+
+            let mut stale = create_mock_session("stale", None);
+            stale.created_at = now - chrono::Duration::days(30);
+            stale.last_activity = Some(now - chrono::Duration::days(10));
+
+            let mut never_used = create_mock_session("never", None);
+            never_used.created_at = now - chrono::Duration::days(8);
+
+            let sessions = vec![old_but_busy, stale, never_used];
+            let ids: Vec<_> =
+                select_prune_targets(&sessions, Some(chrono::Duration::days(7)), None, false)
+                    .into_iter()
+                    .map(|s| s.id.as_str())
+                    .collect();
+            assert_eq!(ids, vec!["stale", "never"]);
+        }
+
+        #[test]
+        fn test_prune_without_input_ignores_output() {
+            let now = chrono::Utc::now();
+            // Prints every few seconds, but nobody has typed into it for days
+            let mut chatty = create_mock_session("chatty", None);
+            chatty.created_at = now - chrono::Duration::days(30);
+            chatty.last_output = Some(now - chrono::Duration::seconds(5));
+            chatty.last_input = Some(now - chrono::Duration::days(3));
+            chatty.last_activity = chatty.last_output;
+
+            let mut in_use = create_mock_session("in-use", None);
+            in_use.created_at = now - chrono::Duration::days(30);
+            in_use.last_input = Some(now - chrono::Duration::minutes(1));
+            in_use.last_activity = in_use.last_input;
+
+            let sessions = vec![chatty, in_use];
+            let ids: Vec<_> =
+                select_prune_targets(&sessions, None, Some(chrono::Duration::days(1)), false)
+                    .into_iter()
+                    .map(|s| s.id.as_str())
+                    .collect();
+            assert_eq!(ids, vec!["chatty"]);
+
+            // Both limits have to be passed
+            let both = select_prune_targets(
+                &sessions,
+                Some(chrono::Duration::hours(1)),
+                Some(chrono::Duration::days(1)),
+                false,
+            );
+            assert!(both.is_empty());
+        }
+    }
+
+    mod size_parsing {
+        use crate::handlers::session::parse_size;
+
+        #[test]
+        fn test_parse_size() {
+            assert_eq!(parse_size("200x50").unwrap(), (200, 50));
+            assert_eq!(parse_size("80X24").unwrap(), (80, 24));
+        }
+
+        #[test]
+        fn test_parse_size_rejects_invalid() {
+            assert!(parse_size("200").is_err());
+            assert!(parse_size("0x50").is_err());
+            assert!(parse_size("200x").is_err());
+            assert!(parse_size("10000x50").is_err());
+            assert!(parse_size("wide x tall").is_err());
+        }
+    }
+
+    mod byte_parsing {
+        use crate::handlers::session::parse_bytes;
+
+        #[test]
+        fn test_parse_bytes_units() {
+            assert_eq!(parse_bytes("0").unwrap(), 0);
+            assert_eq!(parse_bytes("4096").unwrap(), 4096);
+            assert_eq!(parse_bytes("512K").unwrap(), 512 * 1024);
+            assert_eq!(parse_bytes("100m").unwrap(), 100 * 1024 * 1024);
+            assert_eq!(parse_bytes("2GB").unwrap(), 2 * 1024 * 1024 * 1024);
+        }
+
+        #[test]
+        fn test_parse_bytes_rejects_garbage() {
+            assert!(parse_bytes("").is_err());
+            assert!(parse_bytes("M").is_err());
+            assert!(parse_bytes("10T").is_err());
+            assert!(parse_bytes("1.5G").is_err());
+        }
+    }
+
+    mod signal_parsing {
+        use crate::handlers::session::parse_signal;
+        use detached_shell::Signal;
+
+        #[test]
+        fn test_parse_signal_names_and_numbers() {
+            assert_eq!(parse_signal("SIGINT").unwrap(), Signal::SIGINT);
+            assert_eq!(parse_signal("int").unwrap(), Signal::SIGINT);
+            assert_eq!(parse_signal("Term").unwrap(), Signal::SIGTERM);
+            assert_eq!(parse_signal("9").unwrap(), Signal::SIGKILL);
+        }
+
+        #[test]
+        fn test_parse_signal_rejects_unknown() {
+            assert!(parse_signal("").is_err());
+            assert!(parse_signal("SIGNOPE").is_err());
+            assert!(parse_signal("0").is_err());
+            assert!(parse_signal("999").is_err());
+        }
+    }
+
     mod info_handlers {
         use super::*;
 
@@ -151,6 +352,18 @@ mod tests {
                     from: Some("old".to_string()),
                     to: "new".to_string(),
                 },
+                SessionEvent::exited(detached_shell::ExitStatus::Signal(9)),
+                SessionEvent::Bell,
+                SessionEvent::ClientConnected {
+                    client_id: "c1".to_string(),
+                    uid: Some(1000),
+                    user: Some("alice".to_string()),
+                    pid: Some(4242),
+                    tty: Some("/dev/pts/3".to_string()),
+                },
+                SessionEvent::RemoteConnected {
+                    address: "192.0.2.7:51234".to_string(),
+                },
             ];
 
             // Ensure all variants can be created and matched
@@ -162,6 +375,16 @@ mod tests {
                     SessionEvent::Killed => assert!(true),
                     SessionEvent::Crashed => assert!(true),
                     SessionEvent::Renamed { from: _, to: _ } => assert!(true),
+                    SessionEvent::InputFlagged { .. } => assert!(true),
+                    SessionEvent::Exited { code, signal } => {
+                        assert_eq!(code, 137);
+                        assert_eq!(signal, Some(9));
+                    }
+                    SessionEvent::Bell => assert!(true),
+                    SessionEvent::ClientConnected { uid, .. } => assert_eq!(uid, Some(1000)),
+                    SessionEvent::RemoteConnected { address } => {
+                        assert_eq!(address, "192.0.2.7:51234")
+                    }
                 }
             }
         }
@@ -238,4 +461,49 @@ mod tests {
             assert_eq!(matching.len(), 2);
         }
     }
+
+    mod watch_parsing {
+        use crate::handlers::WatchFor;
+
+        #[test]
+        fn test_parse_watch_for() {
+            assert_eq!("activity".parse::<WatchFor>(), Ok(WatchFor::Activity));
+            assert_eq!("silence".parse::<WatchFor>(), Ok(WatchFor::Silence));
+            assert_eq!("bell".parse::<WatchFor>(), Ok(WatchFor::Bell));
+            assert!("beep".parse::<WatchFor>().is_err());
+            assert_eq!(WatchFor::Silence.to_string(), "silence");
+        }
+    }
+
+    mod completions {
+        use crate::handlers::completions::completion_script;
+        use clap::CommandFactory;
+
+        #[test]
+        fn test_completion_scripts_know_session_arguments() {
+            let bash = completion_script("bash", crate::Cli::command()).unwrap();
+            // clap_complete's completion, then ours taking over
+            assert!(bash.contains("_nds()"));
+            assert!(bash.ends_with("complete -F _nds_complete -o bashdefault -o default nds\n"));
+            for line in [
+                "'attach'|'a'|'at') _nds_first=0 _nds_last=0 ",
+                "'kill'|'k') _nds_first=0 _nds_last=-1 ",
+                "'group add'|'group a'|'groups add'|'groups a') _nds_first=1 _nds_last=-1 ",
+                "_nds_session_options='--session -s' ;;",
+            ] {
+                assert!(bash.contains(line), "{}", line);
+            }
+            // Sessions named on the remote host aren't ours to list
+            assert!(!bash.contains("'remote"));
+
+            let zsh = completion_script("zsh", crate::Cli::command()).unwrap();
+            let clap = zsh.find("functions[_nds_clap]=$functions[_nds]").unwrap();
+            assert!(zsh.find("compdef _nds nds").unwrap() > clap);
+
+            let fish = completion_script("fish", crate::Cli::command()).unwrap();
+            assert!(fish.contains("case 'kill' 'k'\n            set -g -- __nds_first 0\n"));
+
+            assert!(completion_script("tcsh", crate::Cli::command()).is_err());
+        }
+    }
 }
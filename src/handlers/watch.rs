@@ -0,0 +1,221 @@
+use chrono::Local;
+use detached_shell::{DaemonEvent, NdsError, Result, Session, SessionManager};
+use std::fmt;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use super::session::find_session;
+
+/// What `nds watch` waits for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchFor {
+    /// The session printing something
+    Activity,
+    /// The session printing nothing for a while
+    Silence,
+    /// The session ringing the bell
+    Bell,
+}
+
+impl WatchFor {
+    /// Quiet period when `--seconds` isn't given: how long a session must
+    /// be silent, or how long it must be quiet before activity counts again
+    fn default_seconds(self) -> u64 {
+        match self {
+            WatchFor::Activity => 10,
+            WatchFor::Silence => 30,
+            // Every bell counts
+            WatchFor::Bell => 0,
+        }
+    }
+}
+
+impl fmt::Display for WatchFor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchFor::Activity => f.write_str("activity"),
+            WatchFor::Silence => f.write_str("silence"),
+            WatchFor::Bell => f.write_str("bell"),
+        }
+    }
+}
+
+impl FromStr for WatchFor {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "activity" => Ok(WatchFor::Activity),
+            "silence" => Ok(WatchFor::Silence),
+            "bell" => Ok(WatchFor::Bell),
+            _ => Err(format!(
+                "unknown watch '{}' (expected activity, silence or bell)",
+                s
+            )),
+        }
+    }
+}
+
+/// Watches a session for output, a lack of it or the bell, and notifies the
+/// user through the desktop or by running `exec`. Stops after the first
+/// notification unless `repeat` is set, and when the session's shell exits.
+pub fn handle_watch_session(
+    session_id_or_name: &str,
+    watch_for: WatchFor,
+    seconds: Option<u64>,
+    exec: Option<&str>,
+    repeat: bool,
+) -> Result<()> {
+    let session = find_session(session_id_or_name)?;
+    let quiet = Duration::from_secs(seconds.unwrap_or_else(|| watch_for.default_seconds()));
+    let event = match watch_for {
+        WatchFor::Bell => "bell",
+        WatchFor::Activity | WatchFor::Silence => "output",
+    };
+    let mut events = SessionManager::subscribe(&session.id, &[event])?;
+
+    match watch_for {
+        WatchFor::Activity => println!("Watching {} for output", session.display_name()),
+        WatchFor::Silence => println!(
+            "Watching {} for {}s without output",
+            session.display_name(),
+            quiet.as_secs()
+        ),
+        WatchFor::Bell => println!("Watching {} for the bell", session.display_name()),
+    }
+
+    // Activity is armed from the start; after it fires it waits for the
+    // session to go quiet again. Silence re-arms on the next output.
+    let mut armed = true;
+    let mut last_output = Instant::now();
+    loop {
+        let until_quiet = quiet.saturating_sub(last_output.elapsed());
+        let timeout = match (watch_for, armed) {
+            (WatchFor::Silence, true) | (WatchFor::Activity, false) => Some(until_quiet),
+            _ => None,
+        };
+
+        let event = match events.next_event(timeout) {
+            Ok(event) => event,
+            // The session was killed
+            Err(NdsError::SessionNotFound(_)) => {
+                println!(
+                    "Session {} ended, no longer watching",
+                    session.display_name()
+                );
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        match event {
+            Some(DaemonEvent::Output) => {
+                last_output = Instant::now();
+                match watch_for {
+                    WatchFor::Activity if armed => {
+                        notify(&session, watch_for, exec);
+                        if !repeat {
+                            return Ok(());
+                        }
+                        armed = false;
+                    }
+                    WatchFor::Silence => armed = true,
+                    _ => {}
+                }
+            }
+            Some(DaemonEvent::Bell) => {
+                notify(&session, watch_for, exec);
+                if !repeat {
+                    return Ok(());
+                }
+            }
+            Some(DaemonEvent::Exited(status)) => {
+                println!(
+                    "Session {} exited ({}), no longer watching",
+                    session.display_name(),
+                    status
+                );
+                return Ok(());
+            }
+            None if last_output.elapsed() >= quiet => match watch_for {
+                WatchFor::Silence if armed => {
+                    notify(&session, watch_for, exec);
+                    if !repeat {
+                        return Ok(());
+                    }
+                    armed = false;
+                }
+                WatchFor::Activity => armed = true,
+                _ => {}
+            },
+            None => {}
+        }
+    }
+}
+
+/// Tell the user what was seen: print it, then run `exec` or fall back to
+/// a desktop notification, and the terminal bell if there is no desktop
+fn notify(session: &Session, watch_for: WatchFor, exec: Option<&str>) {
+    let message = match watch_for {
+        WatchFor::Activity => "new output",
+        WatchFor::Silence => "gone quiet",
+        WatchFor::Bell => "rang the bell",
+    };
+    println!(
+        "[{}] {}: {}",
+        Local::now().format("%H:%M:%S"),
+        session.display_name(),
+        message
+    );
+
+    if let Some(exec) = exec {
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(exec)
+            .env("NDS_SESSION_ID", &session.id)
+            .env("NDS_SESSION_NAME", session.name.as_deref().unwrap_or(""))
+            .env("NDS_WATCH_EVENT", watch_for.to_string())
+            .status();
+        match status {
+            Ok(status) if !status.success() => eprintln!("Warning: '{}' {}", exec, status),
+            Err(e) => eprintln!("Warning: failed to run '{}': {}", exec, e),
+            Ok(_) => {}
+        }
+        return;
+    }
+
+    let title = format!("nds: {}", session.display_name());
+    if !desktop_notification(&title, message) {
+        print!("\x07");
+        let _ = std::io::stdout().flush();
+    }
+}
+
+/// Show a desktop notification with notify-send or osascript. Returns
+/// whether one could be shown.
+fn desktop_notification(title: &str, body: &str) -> bool {
+    let mut command = if cfg!(target_os = "macos") {
+        let mut command = Command::new("osascript");
+        command.args([
+            "-e",
+            "on run argv",
+            "-e",
+            "display notification (item 2 of argv) with title (item 1 of argv)",
+            "-e",
+            "end run",
+            title,
+            body,
+        ]);
+        command
+    } else {
+        let mut command = Command::new("notify-send");
+        command.args([title, body]);
+        command
+    };
+    command
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
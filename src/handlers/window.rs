@@ -0,0 +1,84 @@
+use chrono::{Local, Utc};
+use detached_shell::{NdsError, Result, SessionHistory, SessionManager};
+
+use super::session::find_session;
+
+/// List the windows of a session
+pub fn handle_list_windows(session_id_or_name: &str) -> Result<()> {
+    let session = find_session(session_id_or_name)?;
+    let windows = SessionManager::list_windows(&session.id)?;
+
+    println!("Windows in session {}:", session.display_name());
+    println!();
+    println!(
+        "  {:<4} {:<14} {:<10} {:<8} {:<22} COMMAND",
+        "ID", "NAME", "SIZE", "CLIENTS", "CREATED"
+    );
+    for window in windows {
+        let age = (Utc::now() - window.created_at).num_seconds();
+        let created = format!(
+            "{} ({} ago)",
+            window.created_at.with_timezone(&Local).format("%H:%M:%S"),
+            SessionHistory::format_duration(age)
+        );
+        let command = if window.command.is_empty() {
+            "-".to_string()
+        } else {
+            window.command.join(" ")
+        };
+        println!(
+            "  {:<4} {:<14} {:<10} {:<8} {:<22} {}",
+            window.id,
+            window.name.as_deref().unwrap_or("-"),
+            format!("{}x{}", window.cols, window.rows),
+            window.clients,
+            created,
+            command
+        );
+    }
+    Ok(())
+}
+
+/// Open a window in a session, running `command` or a shell
+pub fn handle_new_window(
+    session_id_or_name: &str,
+    name: Option<&str>,
+    command: &[String],
+) -> Result<()> {
+    let session = find_session(session_id_or_name)?;
+    let window = SessionManager::new_window(&session.id, name, command)?;
+    println!(
+        "Opened window {} in session {}",
+        window,
+        session.display_name()
+    );
+    Ok(())
+}
+
+/// Close a window of a session, hanging up its shell
+pub fn handle_kill_window(session_id_or_name: &str, window: u32) -> Result<()> {
+    let session = find_session(session_id_or_name)?;
+
+    if window == 0 {
+        eprintln!(
+            "Window 0 is the shell of session {} itself (use 'nds kill' to end it)",
+            session.display_name()
+        );
+        return Err(NdsError::WindowNotFound(window.to_string()));
+    }
+    if !SessionManager::kill_window(&session.id, window)? {
+        eprintln!(
+            "No window {} in session {} (see 'nds window list')",
+            window,
+            session.display_name()
+        );
+        return Err(NdsError::WindowNotFound(window.to_string()));
+    }
+
+    println!(
+        "Closed window {} in session {}",
+        window,
+        session.display_name()
+    );
+    Ok(())
+}
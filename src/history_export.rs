@@ -0,0 +1,250 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::error::{NdsError, Result};
+use crate::history_v2::{HistoryEntry, HistoryQuery, SessionHistory};
+
+/// How `nds history export` writes history and `nds history import` reads it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HistoryFormat {
+    /// One JSON object per line
+    #[default]
+    Json,
+    /// A header row, then one row per entry
+    Csv,
+}
+
+impl HistoryFormat {
+    /// The format a file's extension suggests, if it suggests one
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "json" | "jsonl" | "ndjson" => Some(HistoryFormat::Json),
+            "csv" => Some(HistoryFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for HistoryFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(HistoryFormat::Json),
+            "csv" => Ok(HistoryFormat::Csv),
+            _ => Err(format!("unknown format '{}' (expected json or csv)", s)),
+        }
+    }
+}
+
+/// An entry as a CSV row. `event` is the kind, for spreadsheets and the
+/// like; `details` is the whole event as JSON, which is what import reads.
+#[derive(Debug, Serialize, Deserialize)]
+struct CsvEntry {
+    session_id: String,
+    session_name: Option<String>,
+    event: String,
+    timestamp: DateTime<Utc>,
+    pid: i32,
+    shell: String,
+    working_dir: String,
+    duration_seconds: Option<i64>,
+    details: String,
+}
+
+impl CsvEntry {
+    fn new(entry: HistoryEntry) -> Result<Self> {
+        Ok(CsvEntry {
+            event: entry.event.kind().to_string(),
+            details: serde_json::to_string(&entry.event)?,
+            session_id: entry.session_id,
+            session_name: entry.session_name,
+            timestamp: entry.timestamp,
+            pid: entry.pid,
+            shell: entry.shell,
+            working_dir: entry.working_dir,
+            duration_seconds: entry.duration_seconds,
+        })
+    }
+
+    fn into_entry(self) -> Result<HistoryEntry> {
+        Ok(HistoryEntry {
+            session_id: self.session_id,
+            session_name: self.session_name,
+            event: serde_json::from_str(&self.details)?,
+            timestamp: self.timestamp,
+            pid: self.pid,
+            shell: self.shell,
+            working_dir: self.working_dir,
+            duration_seconds: self.duration_seconds,
+        })
+    }
+}
+
+/// Write the entries matching `query` to `out` as they are read from the
+/// history, oldest first, returning how many there were
+pub fn export_history(
+    query: &HistoryQuery,
+    format: HistoryFormat,
+    out: impl Write,
+) -> Result<usize> {
+    let query = HistoryQuery {
+        oldest_first: true,
+        ..query.clone()
+    };
+    let mut writer = EntryWriter::new(format, out);
+    let mut count = 0;
+    SessionHistory::for_each(&query, |entry| {
+        count += 1;
+        writer.write(entry)
+    })?;
+    writer.finish()?;
+    Ok(count)
+}
+
+/// Writes entries out one at a time in either format
+enum EntryWriter<W: Write> {
+    Json(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> EntryWriter<W> {
+    fn new(format: HistoryFormat, out: W) -> Self {
+        match format {
+            HistoryFormat::Json => EntryWriter::Json(out),
+            HistoryFormat::Csv => EntryWriter::Csv(Box::new(csv::Writer::from_writer(out))),
+        }
+    }
+
+    fn write(&mut self, entry: HistoryEntry) -> Result<()> {
+        match self {
+            EntryWriter::Json(out) => {
+                serde_json::to_writer(&mut *out, &entry)?;
+                out.write_all(b"\n")?;
+            }
+            EntryWriter::Csv(writer) => {
+                writer.serialize(CsvEntry::new(entry)?).map_err(csv_error)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        match self {
+            EntryWriter::Json(out) => out.flush()?,
+            EntryWriter::Csv(writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+/// Read entries written by `export_history` from `input` one at a time and
+/// add those not already in the history. Returns how many were added and
+/// how many were already there.
+pub fn import_history(input: impl BufRead, format: HistoryFormat) -> Result<(usize, usize)> {
+    match format {
+        HistoryFormat::Json => SessionHistory::import(read_json(input)),
+        HistoryFormat::Csv => SessionHistory::import(read_csv(input)),
+    }
+}
+
+fn read_json(input: impl BufRead) -> impl Iterator<Item = Result<HistoryEntry>> {
+    input
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+        .map(|(n, line)| {
+            serde_json::from_str(&line?)
+                .map_err(|e| NdsError::HistoryError(format!("line {}: {}", n + 1, e)))
+        })
+}
+
+fn read_csv(input: impl BufRead) -> impl Iterator<Item = Result<HistoryEntry>> {
+    csv::Reader::from_reader(input)
+        .into_deserialize::<CsvEntry>()
+        .map(|row| row.map_err(csv_error)?.into_entry())
+}
+
+fn csv_error(e: csv::Error) -> NdsError {
+    NdsError::HistoryError(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history_v2::SessionEvent;
+
+    fn entries() -> Vec<HistoryEntry> {
+        let entry = |event| HistoryEntry {
+            session_id: "abc".to_string(),
+            session_name: Some("web, \"prod\"".to_string()),
+            event,
+            timestamp: DateTime::from_timestamp_micros(1_700_000_000_123_456).unwrap(),
+            pid: 42,
+            shell: "/bin/bash".to_string(),
+            working_dir: "/srv/my app".to_string(),
+            duration_seconds: None,
+        };
+        vec![
+            entry(SessionEvent::Created),
+            entry(SessionEvent::Renamed {
+                from: None,
+                to: "line\nbreak".to_string(),
+            }),
+            HistoryEntry {
+                duration_seconds: Some(90),
+                ..entry(SessionEvent::Killed)
+            },
+        ]
+    }
+
+    fn round_trip(format: HistoryFormat) {
+        let mut out = Vec::new();
+        let mut writer = EntryWriter::new(format, &mut out);
+        for entry in entries() {
+            writer.write(entry).unwrap();
+        }
+        writer.finish().unwrap();
+        drop(writer);
+
+        let read: Vec<HistoryEntry> = match format {
+            HistoryFormat::Json => read_json(&out[..]).collect::<Result<_>>().unwrap(),
+            HistoryFormat::Csv => read_csv(&out[..]).collect::<Result<_>>().unwrap(),
+        };
+        let json = |entries: &[HistoryEntry]| serde_json::to_string(entries).unwrap();
+        assert_eq!(json(&read), json(&entries()));
+    }
+
+    #[test]
+    fn json_reads_back_what_it_wrote() {
+        round_trip(HistoryFormat::Json);
+    }
+
+    #[test]
+    fn csv_reads_back_what_it_wrote() {
+        round_trip(HistoryFormat::Csv);
+    }
+
+    #[test]
+    fn bad_json_says_which_line() {
+        let input = b"\n{\"not\": \"an entry\"}\n";
+        let error = read_json(&input[..]).next().unwrap().unwrap_err();
+        assert!(error.to_string().contains("line 2"), "{}", error);
+    }
+
+    #[test]
+    fn format_from_extension() {
+        assert_eq!(
+            HistoryFormat::from_path(Path::new("h.csv")),
+            Some(HistoryFormat::Csv)
+        );
+        assert_eq!(
+            HistoryFormat::from_path(Path::new("h.jsonl")),
+            Some(HistoryFormat::Json)
+        );
+        assert_eq!(HistoryFormat::from_path(Path::new("h")), None);
+    }
+}
@@ -1,11 +1,14 @@
 use chrono::{DateTime, Duration, Utc};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, TransactionBehavior};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration as StdDuration;
 
 use crate::error::{NdsError, Result};
-use crate::session::Session;
+use crate::pty::PeerCredentials;
+use crate::session::{ExitStatus, Session};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum SessionEvent {
@@ -14,7 +17,42 @@ pub enum SessionEvent {
     Detached,
     Killed,
     Crashed,
-    Renamed { from: Option<String>, to: String },
+    Renamed {
+        from: Option<String>,
+        to: String,
+    },
+    InputFlagged {
+        client_id: String,
+        bytes_per_minute: u64,
+    },
+    /// The shell or command exited; `code` is shell-style (128 + signal)
+    Exited {
+        code: i32,
+        signal: Option<i32>,
+    },
+    /// The shell or command rang the bell
+    Bell,
+    /// A client attached, and who it was as far as the daemon could tell
+    ClientConnected {
+        client_id: String,
+        uid: Option<u32>,
+        user: Option<String>,
+        pid: Option<i32>,
+        tty: Option<String>,
+    },
+    /// A client came in over the network (`nds new --listen`), from `address`
+    RemoteConnected {
+        address: String,
+    },
+}
+
+impl SessionEvent {
+    pub fn exited(status: ExitStatus) -> Self {
+        SessionEvent::Exited {
+            code: status.code(),
+            signal: status.signal(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,7 +67,41 @@ pub struct HistoryEntry {
     pub duration_seconds: Option<i64>, // For Killed/Crashed events
 }
 
-// Individual session history stored in separate files
+/// Every kind of event, as stored and as given to `nds history --event`
+pub const EVENT_KINDS: [&str; 11] = [
+    "created",
+    "attached",
+    "detached",
+    "killed",
+    "crashed",
+    "renamed",
+    "input-flagged",
+    "exited",
+    "bell",
+    "client-connected",
+    "remote-connected",
+];
+
+impl SessionEvent {
+    /// The event's kind, one of `EVENT_KINDS`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SessionEvent::Created => "created",
+            SessionEvent::Attached => "attached",
+            SessionEvent::Detached => "detached",
+            SessionEvent::Killed => "killed",
+            SessionEvent::Crashed => "crashed",
+            SessionEvent::Renamed { .. } => "renamed",
+            SessionEvent::InputFlagged { .. } => "input-flagged",
+            SessionEvent::Exited { .. } => "exited",
+            SessionEvent::Bell => "bell",
+            SessionEvent::ClientConnected { .. } => "client-connected",
+            SessionEvent::RemoteConnected { .. } => "remote-connected",
+        }
+    }
+}
+
+// One session's history, as the JSON files before the database held it
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SessionHistoryFile {
     pub session_id: String,
@@ -37,11 +109,132 @@ pub struct SessionHistoryFile {
     pub entries: Vec<HistoryEntry>,
 }
 
+/// Which history entries to load. Sessions that were killed or crashed are
+/// archived and left out unless `include_archived` is set.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryQuery {
+    pub session_id: Option<String>,
+    /// Only events of this kind (see `EVENT_KINDS`)
+    pub event: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub include_archived: bool,
+    pub limit: Option<usize>,
+    /// Oldest first rather than newest first
+    pub oldest_first: bool,
+}
+
+/// How sessions were used over a stretch of time, for `nds history stats`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistoryStats {
+    /// Sessions with anything recorded in the window
+    pub sessions: usize,
+    /// Of those, the ones created in it
+    pub created: usize,
+    pub attaches: usize,
+    /// Sessions that exited, were killed or crashed in the window
+    pub ended: usize,
+    pub crashed: usize,
+    /// How long the sessions that ended ran for, in seconds, summed
+    pub total_duration: i64,
+    /// How many of the sessions that ended have a duration recorded
+    pub timed: usize,
+    /// Where the most sessions ran, busiest first
+    pub busiest_dirs: Vec<DirectoryStats>,
+}
+
+impl HistoryStats {
+    /// Seconds the sessions that ended ran for on average
+    pub fn average_duration(&self) -> Option<i64> {
+        (self.timed > 0).then(|| self.total_duration / self.timed as i64)
+    }
+
+    /// The share of sessions that ended by crashing
+    pub fn crash_rate(&self) -> Option<f64> {
+        (self.ended > 0).then(|| self.crashed as f64 / self.ended as f64)
+    }
+}
+
+/// Sessions in one working directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryStats {
+    pub working_dir: String,
+    pub sessions: usize,
+    pub attaches: usize,
+}
+
+/// How long archived history is kept when `keep_days` is not set
+pub const DEFAULT_HISTORY_KEEP_DAYS: i64 = 90;
+
+/// The `history` setting in config.json. Having it at all has nds remove old
+/// archived history once a day; `"history": {}` keeps 90 days of it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// Remove the history of sessions that ended longer ago than this
+    /// (default: 90)
+    pub keep_days: Option<i64>,
+    /// Keep the history of at most this many ended sessions, newest first
+    pub max_archived: Option<usize>,
+}
+
+/// Which archived history to remove. Live sessions' history always stays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Sessions that ended longer ago than this many days
+    pub keep_days: Option<i64>,
+    /// The oldest sessions beyond this many
+    pub max_archived: Option<usize>,
+}
+
+impl RetentionPolicy {
+    pub fn from_config(config: &HistoryConfig) -> Self {
+        RetentionPolicy {
+            keep_days: Some(config.keep_days.unwrap_or(DEFAULT_HISTORY_KEEP_DAYS)),
+            max_archived: config.max_archived,
+        }
+    }
+}
+
+/// What applying a retention policy removed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CleanReport {
+    /// Sessions whose history was removed
+    pub removed: Vec<String>,
+    /// Archived sessions whose history is still there
+    pub kept_archived: usize,
+}
+
+const DATABASE_FILE: &str = "history.db";
+const SCHEMA_VERSION: i32 = 2;
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS events (
+    id INTEGER PRIMARY KEY,
+    session_id TEXT NOT NULL,
+    session_name TEXT,
+    kind TEXT NOT NULL,
+    event TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    pid INTEGER NOT NULL,
+    shell TEXT NOT NULL,
+    working_dir TEXT NOT NULL,
+    duration_seconds INTEGER
+);
+CREATE INDEX IF NOT EXISTS events_by_session ON events (session_id, timestamp);
+CREATE INDEX IF NOT EXISTS events_by_kind ON events (kind, timestamp);
+CREATE INDEX IF NOT EXISTS events_by_time ON events (timestamp);
+CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value);
+";
+
+// Sessions whose history is archived: those that ended by being killed or
+// crashing
+const ARCHIVED_SESSIONS: &str = "SELECT session_id FROM events WHERE kind IN ('killed', 'crashed')";
+
 // Main history manager
 pub struct SessionHistory;
 
 impl SessionHistory {
-    // Directory structure: ~/.nds/history/active/ and ~/.nds/history/archived/
+    // Everything lives in ~/.nds/history/history.db
     pub fn history_dir() -> Result<PathBuf> {
         let dir = if let Ok(nds_home) = std::env::var("NDS_HOME") {
             PathBuf::from(nds_home).join("history")
@@ -63,91 +256,56 @@ impl SessionHistory {
         Ok(dir)
     }
 
-    pub fn active_history_dir() -> Result<PathBuf> {
-        let dir = Self::history_dir()?.join("active");
-        if !dir.exists() {
-            fs::create_dir_all(&dir)
-                .map_err(|e| NdsError::DirectoryCreationError(e.to_string()))?;
-        }
-        Ok(dir)
+    /// Open the history database, creating it and bringing in history from
+    /// the old per-session JSON files the first time
+    fn open() -> Result<Connection> {
+        let dir = Self::history_dir()?;
+        let mut conn = open_database(&dir.join(DATABASE_FILE))?;
+        import_json_history(&mut conn, &dir)?;
+        Ok(conn)
     }
 
-    pub fn archived_history_dir() -> Result<PathBuf> {
-        let dir = Self::history_dir()?.join("archived");
-        if !dir.exists() {
-            fs::create_dir_all(&dir)
-                .map_err(|e| NdsError::DirectoryCreationError(e.to_string()))?;
-        }
-        Ok(dir)
+    // Load history for a specific session
+    pub fn load_session_history(session_id: &str) -> Result<SessionHistoryFile> {
+        let entries = Self::query(&HistoryQuery {
+            session_id: Some(session_id.to_string()),
+            include_archived: true,
+            oldest_first: true,
+            ..Default::default()
+        })?;
+        Ok(SessionHistoryFile {
+            session_id: session_id.to_string(),
+            created_at: entries
+                .first()
+                .map(|e| e.timestamp)
+                .unwrap_or_else(Utc::now),
+            entries,
+        })
     }
 
-    // Get history file path for a session
-    fn session_history_path(session_id: &str, archived: bool) -> Result<PathBuf> {
-        let dir = if archived {
-            Self::archived_history_dir()?
-        } else {
-            Self::active_history_dir()?
-        };
-        Ok(dir.join(format!("{}.json", session_id)))
+    /// History entries matching `query`, using the database's indexes
+    pub fn query(query: &HistoryQuery) -> Result<Vec<HistoryEntry>> {
+        query_entries(&Self::open()?, query)
     }
 
-    // Load history for a specific session
-    pub fn load_session_history(session_id: &str) -> Result<SessionHistoryFile> {
-        // Try active first, then archived
-        let active_path = Self::session_history_path(session_id, false)?;
-        let archived_path = Self::session_history_path(session_id, true)?;
-
-        let path = if active_path.exists() {
-            active_path
-        } else if archived_path.exists() {
-            archived_path
-        } else {
-            // Create new history file for this session
-            let history = SessionHistoryFile {
-                session_id: session_id.to_string(),
-                created_at: Utc::now(),
-                entries: Vec::new(),
-            };
-            let json = serde_json::to_string_pretty(&history)?;
-            fs::write(&active_path, json)?;
-            return Ok(history);
-        };
-
-        let content = fs::read_to_string(&path)?;
-        let history: SessionHistoryFile = serde_json::from_str(&content)?;
-        Ok(history)
+    /// Hand the entries matching `query` to `f` as they are read, without
+    /// loading them all first
+    pub fn for_each(query: &HistoryQuery, f: impl FnMut(HistoryEntry) -> Result<()>) -> Result<()> {
+        each_entry(&Self::open()?, query, f)
     }
 
-    // Save history for a specific session
-    fn save_session_history(history: &SessionHistoryFile, archived: bool) -> Result<()> {
-        let path = Self::session_history_path(&history.session_id, archived)?;
-        let json = serde_json::to_string_pretty(history)?;
-        fs::write(path, json)?;
-        Ok(())
+    /// Add entries from elsewhere, such as another machine's export, leaving
+    /// out any already recorded. All or none are added; the counts of added
+    /// and left out entries are returned.
+    pub fn import(
+        entries: impl IntoIterator<Item = Result<HistoryEntry>>,
+    ) -> Result<(usize, usize)> {
+        import_entries(&mut Self::open()?, entries)
     }
 
     // Add an entry to a session's history
-    fn add_entry_to_session(session_id: &str, entry: HistoryEntry) -> Result<()> {
-        let mut history = Self::load_session_history(session_id)?;
-        history.entries.push(entry);
-
-        // Determine if this should be archived (session ended)
-        let should_archive = history
-            .entries
-            .iter()
-            .any(|e| matches!(e.event, SessionEvent::Killed | SessionEvent::Crashed));
-
-        Self::save_session_history(&history, should_archive)?;
-
-        // If archived, remove from active directory
-        if should_archive {
-            let active_path = Self::session_history_path(session_id, false)?;
-            if active_path.exists() {
-                let _ = fs::remove_file(active_path);
-            }
-        }
-
-        Ok(())
+    fn add_entry_to_session(entry: HistoryEntry) -> Result<()> {
+        insert_entry(&Self::open()?, &entry)
     }
 
     // Record session events
@@ -162,7 +320,7 @@ impl SessionHistory {
             working_dir: session.working_dir.clone(),
             duration_seconds: None,
         };
-        Self::add_entry_to_session(&session.id, entry)
+        Self::add_entry_to_session(entry)
     }
 
     pub fn record_session_attached(session: &Session) -> Result<()> {
@@ -176,7 +334,7 @@ impl SessionHistory {
             working_dir: session.working_dir.clone(),
             duration_seconds: None,
         };
-        Self::add_entry_to_session(&session.id, entry)
+        Self::add_entry_to_session(entry)
     }
 
     pub fn record_session_detached(session: &Session) -> Result<()> {
@@ -190,7 +348,7 @@ impl SessionHistory {
             working_dir: session.working_dir.clone(),
             duration_seconds: None,
         };
-        Self::add_entry_to_session(&session.id, entry)
+        Self::add_entry_to_session(entry)
     }
 
     pub fn record_session_killed(session: &Session) -> Result<()> {
@@ -211,7 +369,7 @@ impl SessionHistory {
             working_dir: session.working_dir.clone(),
             duration_seconds: Some(duration),
         };
-        Self::add_entry_to_session(&session.id, entry)
+        Self::add_entry_to_session(entry)
     }
 
     pub fn record_session_crashed(session: &Session) -> Result<()> {
@@ -232,7 +390,7 @@ impl SessionHistory {
             working_dir: session.working_dir.clone(),
             duration_seconds: Some(duration),
         };
-        Self::add_entry_to_session(&session.id, entry)
+        Self::add_entry_to_session(entry)
     }
 
     pub fn record_session_renamed(
@@ -253,61 +411,115 @@ impl SessionHistory {
             working_dir: session.working_dir.clone(),
             duration_seconds: None,
         };
-        Self::add_entry_to_session(&session.id, entry)
+        Self::add_entry_to_session(entry)
     }
 
-    // Get all history entries (from all sessions)
+    pub fn record_input_flagged(
+        session: &Session,
+        client_id: &str,
+        bytes_per_minute: u64,
+    ) -> Result<()> {
+        let entry = HistoryEntry {
+            session_id: session.id.clone(),
+            session_name: session.name.clone(),
+            event: SessionEvent::InputFlagged {
+                client_id: client_id.to_string(),
+                bytes_per_minute,
+            },
+            timestamp: Utc::now(),
+            pid: session.pid,
+            shell: session.shell.clone(),
+            working_dir: session.working_dir.clone(),
+            duration_seconds: None,
+        };
+        Self::add_entry_to_session(entry)
+    }
+
+    pub fn record_client_connected(
+        session: &Session,
+        client_id: &str,
+        peer: Option<PeerCredentials>,
+        tty: Option<String>,
+    ) -> Result<()> {
+        let entry = HistoryEntry {
+            session_id: session.id.clone(),
+            session_name: session.name.clone(),
+            event: SessionEvent::ClientConnected {
+                client_id: client_id.to_string(),
+                uid: peer.map(|peer| peer.uid),
+                user: peer.map(|peer| peer.user()),
+                pid: peer.and_then(|peer| peer.pid),
+                tty,
+            },
+            timestamp: Utc::now(),
+            pid: session.pid,
+            shell: session.shell.clone(),
+            working_dir: session.working_dir.clone(),
+            duration_seconds: None,
+        };
+        Self::add_entry_to_session(entry)
+    }
+
+    pub fn record_remote_connected(session: &Session, address: &str) -> Result<()> {
+        let entry = HistoryEntry {
+            session_id: session.id.clone(),
+            session_name: session.name.clone(),
+            event: SessionEvent::RemoteConnected {
+                address: address.to_string(),
+            },
+            timestamp: Utc::now(),
+            pid: session.pid,
+            shell: session.shell.clone(),
+            working_dir: session.working_dir.clone(),
+            duration_seconds: None,
+        };
+        Self::add_entry_to_session(entry)
+    }
+
+    pub fn record_bell(session: &Session) -> Result<()> {
+        let entry = HistoryEntry {
+            session_id: session.id.clone(),
+            session_name: session.name.clone(),
+            event: SessionEvent::Bell,
+            timestamp: Utc::now(),
+            pid: session.pid,
+            shell: session.shell.clone(),
+            working_dir: session.working_dir.clone(),
+            duration_seconds: None,
+        };
+        Self::add_entry_to_session(entry)
+    }
+
+    pub fn record_session_exited(session: &Session, status: ExitStatus) -> Result<()> {
+        let entry = HistoryEntry {
+            session_id: session.id.clone(),
+            session_name: session.name.clone(),
+            event: SessionEvent::exited(status),
+            timestamp: Utc::now(),
+            pid: session.pid,
+            shell: session.shell.clone(),
+            working_dir: session.working_dir.clone(),
+            duration_seconds: Some((Utc::now() - session.created_at).num_seconds()),
+        };
+        Self::add_entry_to_session(entry)
+    }
+
+    // Get all history entries (from all sessions), newest first
     pub fn load_all_history(
         include_archived: bool,
         limit: Option<usize>,
     ) -> Result<Vec<HistoryEntry>> {
-        let mut all_entries = Vec::new();
-
-        // Load from active sessions
-        let active_dir = Self::active_history_dir()?;
-        if active_dir.exists() {
-            for entry in fs::read_dir(active_dir)? {
-                let entry = entry?;
-                let path = entry.path();
-                if path.extension().and_then(|s| s.to_str()) == Some("json") {
-                    if let Ok(content) = fs::read_to_string(&path) {
-                        if let Ok(history) = serde_json::from_str::<SessionHistoryFile>(&content) {
-                            all_entries.extend(history.entries);
-                        }
-                    }
-                }
-            }
-        }
-
-        // Load from archived sessions if requested
-        if include_archived {
-            let archived_dir = Self::archived_history_dir()?;
-            if archived_dir.exists() {
-                for entry in fs::read_dir(archived_dir)? {
-                    let entry = entry?;
-                    let path = entry.path();
-                    if path.extension().and_then(|s| s.to_str()) == Some("json") {
-                        if let Ok(content) = fs::read_to_string(&path) {
-                            if let Ok(history) =
-                                serde_json::from_str::<SessionHistoryFile>(&content)
-                            {
-                                all_entries.extend(history.entries);
-                            }
-                        }
-                    }
-                }
-            }
-        }
-
-        // Sort by timestamp (newest first)
-        all_entries.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
-
-        // Apply limit if specified
-        if let Some(limit) = limit {
-            all_entries.truncate(limit);
-        }
+        Self::query(&HistoryQuery {
+            include_archived,
+            limit,
+            ..Default::default()
+        })
+    }
 
-        Ok(all_entries)
+    /// Totals over everything recorded since `since` (or ever), with the
+    /// `top` busiest working directories
+    pub fn stats(since: Option<DateTime<Utc>>, top: usize) -> Result<HistoryStats> {
+        history_stats(&Self::open()?, since, top)
     }
 
     // Get history for a specific session
@@ -316,31 +528,47 @@ impl SessionHistory {
         Ok(history.entries)
     }
 
-    // Clean up old archived history (older than specified days)
-    pub fn cleanup_old_history(days_to_keep: i64) -> Result<usize> {
-        let archived_dir = Self::archived_history_dir()?;
-        let cutoff = Utc::now() - Duration::days(days_to_keep);
-        let mut removed_count = 0;
-
-        if archived_dir.exists() {
-            for entry in fs::read_dir(archived_dir)? {
-                let entry = entry?;
-                let path = entry.path();
-                if path.extension().and_then(|s| s.to_str()) == Some("json") {
-                    if let Ok(content) = fs::read_to_string(&path) {
-                        if let Ok(history) = serde_json::from_str::<SessionHistoryFile>(&content) {
-                            // Check if all entries are older than cutoff
-                            if history.entries.iter().all(|e| e.timestamp < cutoff) {
-                                fs::remove_file(&path)?;
-                                removed_count += 1;
-                            }
-                        }
-                    }
-                }
-            }
+    /// Remove archived history as `policy` says, or with `dry_run` only
+    /// report what would go
+    pub fn clean(policy: &RetentionPolicy, dry_run: bool) -> Result<CleanReport> {
+        apply_retention(&mut Self::open()?, policy, dry_run)
+    }
+
+    /// Apply `policy` if it hasn't been for a day, so every nds command can
+    /// call this and only one a day does the work
+    pub fn clean_if_due(policy: &RetentionPolicy) -> Result<Option<CleanReport>> {
+        let conn = &mut Self::open()?;
+        if last_clean(conn)?.is_some_and(|last| Utc::now() - last < Duration::days(1)) {
+            return Ok(None);
         }
+        apply_retention(conn, policy, false).map(Some)
+    }
+
+    /// Size of the history database on disk, with its write-ahead log
+    pub fn database_size() -> Result<u64> {
+        let path = Self::history_dir()?.join(DATABASE_FILE);
+        let mut wal = path.clone().into_os_string();
+        wal.push("-wal");
+        Ok([path, PathBuf::from(wal)]
+            .iter()
+            .filter_map(|path| fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum())
+    }
+
+    // Clean up old archived history (older than specified days), returning
+    // how many sessions' history went
+    pub fn cleanup_old_history(days_to_keep: i64) -> Result<usize> {
+        let policy = RetentionPolicy {
+            keep_days: Some(days_to_keep),
+            max_archived: None,
+        };
+        Ok(Self::clean(&policy, false)?.removed.len())
+    }
 
-        Ok(removed_count)
+    /// Give the space left by removed history back to the filesystem
+    pub fn compact() -> Result<()> {
+        compact(&Self::open()?)
     }
 
     pub fn format_duration(seconds: i64) -> String {
@@ -357,7 +585,7 @@ impl SessionHistory {
         }
     }
 
-    // Migrate from old single-file format to new per-session format
+    // Migrate from the old single-file format to the database
     pub fn migrate_from_single_file() -> Result<()> {
         let old_file = directories::BaseDirs::new()
             .ok_or_else(|| {
@@ -374,12 +602,11 @@ impl SessionHistory {
         // Read old format
         let content = fs::read_to_string(&old_file)?;
         if let Ok(old_history) = serde_json::from_str::<crate::history::SessionHistory>(&content) {
-            // Group entries by session ID
-            let mut sessions: HashMap<String, Vec<HistoryEntry>> = HashMap::new();
-
+            let mut conn = Self::open()?;
+            let tx = conn.transaction()?;
             for old_entry in old_history.entries {
                 let entry = HistoryEntry {
-                    session_id: old_entry.session_id.clone(),
+                    session_id: old_entry.session_id,
                     session_name: old_entry.session_name,
                     event: match old_entry.event {
                         crate::history::SessionEvent::Created => SessionEvent::Created,
@@ -398,31 +625,9 @@ impl SessionHistory {
                     duration_seconds: old_entry.duration_seconds,
                 };
 
-                sessions
-                    .entry(old_entry.session_id.clone())
-                    .or_insert_with(Vec::new)
-                    .push(entry);
-            }
-
-            // Save each session to its own file
-            for (session_id, entries) in sessions {
-                let is_terminated = entries
-                    .iter()
-                    .any(|e| matches!(e.event, SessionEvent::Killed | SessionEvent::Crashed));
-
-                let created_at = entries
-                    .first()
-                    .map(|e| e.timestamp)
-                    .unwrap_or_else(Utc::now);
-
-                let history_file = SessionHistoryFile {
-                    session_id: session_id.clone(),
-                    created_at,
-                    entries,
-                };
-
-                Self::save_session_history(&history_file, is_terminated)?;
+                insert_entry(&tx, &entry)?;
             }
+            tx.commit()?;
 
             // Rename old file to .backup
             let backup_path = old_file.with_extension("json.backup");
@@ -432,3 +637,719 @@ impl SessionHistory {
         Ok(())
     }
 }
+
+/// Open the database at `path`, setting up its tables if it is new. Several
+/// nds processes write to it at once, so writers wait their turn. Each event
+/// is synced to disk as it is committed, so a power cut loses at most the one
+/// being written, never what was there before.
+fn open_database(path: &Path) -> Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(StdDuration::from_secs(5))?;
+    conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+    conn.pragma_update(None, "synchronous", "FULL")?;
+    create_schema(&conn)?;
+    Ok(conn)
+}
+
+fn create_schema(conn: &Connection) -> Result<()> {
+    let version: i32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version < SCHEMA_VERSION {
+        conn.execute_batch(SCHEMA)?;
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    }
+    Ok(())
+}
+
+fn insert_entry(conn: &Connection, entry: &HistoryEntry) -> Result<()> {
+    conn.execute(
+        "INSERT INTO events (session_id, session_name, kind, event, timestamp, pid, shell,
+                             working_dir, duration_seconds)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            entry.session_id,
+            entry.session_name,
+            entry.event.kind(),
+            serde_json::to_string(&entry.event)?,
+            entry.timestamp.timestamp_micros(),
+            entry.pid,
+            entry.shell,
+            entry.working_dir,
+            entry.duration_seconds,
+        ],
+    )?;
+    Ok(())
+}
+
+fn query_entries(conn: &Connection, query: &HistoryQuery) -> Result<Vec<HistoryEntry>> {
+    let mut entries = Vec::new();
+    each_entry(conn, query, |entry| {
+        entries.push(entry);
+        Ok(())
+    })?;
+    Ok(entries)
+}
+
+/// Hand the entries matching `query` to `f` one at a time, as they are read
+fn each_entry(
+    conn: &Connection,
+    query: &HistoryQuery,
+    mut f: impl FnMut(HistoryEntry) -> Result<()>,
+) -> Result<()> {
+    let mut sql = String::from(
+        "SELECT session_id, session_name, event, timestamp, pid, shell, working_dir,
+                duration_seconds
+         FROM events WHERE 1 = 1",
+    );
+    let mut values: Vec<Value> = Vec::new();
+    if let Some(session_id) = &query.session_id {
+        sql.push_str(" AND session_id = ?");
+        values.push(Value::Text(session_id.clone()));
+    }
+    if let Some(event) = &query.event {
+        sql.push_str(" AND kind = ?");
+        values.push(Value::Text(event.clone()));
+    }
+    if let Some(since) = query.since {
+        sql.push_str(" AND timestamp >= ?");
+        values.push(Value::Integer(since.timestamp_micros()));
+    }
+    if let Some(until) = query.until {
+        sql.push_str(" AND timestamp < ?");
+        values.push(Value::Integer(until.timestamp_micros()));
+    }
+    if !query.include_archived {
+        sql.push_str(&format!(" AND session_id NOT IN ({})", ARCHIVED_SESSIONS));
+    }
+    // Entries recorded in the same microsecond keep the order they came in
+    sql.push_str(if query.oldest_first {
+        " ORDER BY timestamp, id"
+    } else {
+        " ORDER BY timestamp DESC, id DESC"
+    });
+    if let Some(limit) = query.limit {
+        sql.push_str(" LIMIT ?");
+        values.push(Value::Integer(i64::try_from(limit).unwrap_or(i64::MAX)));
+    }
+
+    let mut statement = conn.prepare(&sql)?;
+    let rows = statement.query_map(params_from_iter(values), |row| {
+        Ok((
+            HistoryEntry {
+                session_id: row.get(0)?,
+                session_name: row.get(1)?,
+                event: SessionEvent::Created,
+                timestamp: DateTime::from_timestamp_micros(row.get(3)?).unwrap_or_default(),
+                pid: row.get(4)?,
+                shell: row.get(5)?,
+                working_dir: row.get(6)?,
+                duration_seconds: row.get(7)?,
+            },
+            row.get::<_, String>(2)?,
+        ))
+    })?;
+
+    for row in rows {
+        let (mut entry, event) = row?;
+        // An event this version doesn't know, written by a newer one
+        let Ok(event) = serde_json::from_str(&event) else {
+            continue;
+        };
+        entry.event = event;
+        f(entry)?;
+    }
+    Ok(())
+}
+
+/// Add `entries` in one transaction, leaving out those already there. Returns
+/// how many were added and how many left out.
+fn import_entries(
+    conn: &mut Connection,
+    entries: impl IntoIterator<Item = Result<HistoryEntry>>,
+) -> Result<(usize, usize)> {
+    let tx = conn.transaction()?;
+    let (mut added, mut skipped) = (0, 0);
+    for entry in entries {
+        let entry = entry?;
+        if has_entry(&tx, &entry)? {
+            skipped += 1;
+        } else {
+            insert_entry(&tx, &entry)?;
+            added += 1;
+        }
+    }
+    tx.commit()?;
+    Ok((added, skipped))
+}
+
+fn history_stats(
+    conn: &Connection,
+    since: Option<DateTime<Utc>>,
+    top: usize,
+) -> Result<HistoryStats> {
+    let since = since.map_or(i64::MIN, |since| since.timestamp_micros());
+    let count = |n: i64| usize::try_from(n).unwrap_or(0);
+
+    let (sessions, created, attaches) = conn.query_row(
+        "SELECT COUNT(DISTINCT session_id), TOTAL(kind = 'created'), TOTAL(kind = 'attached')
+         FROM events WHERE timestamp >= ?1",
+        params![since],
+        |row| {
+            Ok((
+                row.get(0)?,
+                row.get::<_, f64>(1)? as i64,
+                row.get::<_, f64>(2)? as i64,
+            ))
+        },
+    )?;
+    // A session that exited and was then killed ended once, and ran for the
+    // longer of the two durations recorded
+    let (ended, crashed, total_duration, timed) = conn.query_row(
+        "SELECT COUNT(*), TOTAL(crashed), TOTAL(duration), COUNT(duration)
+         FROM (SELECT MAX(kind = 'crashed') AS crashed, MAX(duration_seconds) AS duration
+               FROM events
+               WHERE timestamp >= ?1 AND kind IN ('exited', 'killed', 'crashed')
+               GROUP BY session_id)",
+        params![since],
+        |row| {
+            Ok((
+                row.get(0)?,
+                row.get::<_, f64>(1)? as i64,
+                row.get::<_, f64>(2)? as i64,
+                row.get(3)?,
+            ))
+        },
+    )?;
+
+    let busiest_dirs = conn
+        .prepare(
+            "SELECT working_dir, COUNT(DISTINCT session_id), TOTAL(kind = 'attached')
+             FROM events WHERE timestamp >= ?1
+             GROUP BY working_dir
+             ORDER BY 2 DESC, 3 DESC, working_dir
+             LIMIT ?2",
+        )?
+        .query_map(
+            params![since, i64::try_from(top).unwrap_or(i64::MAX)],
+            |row| {
+                Ok(DirectoryStats {
+                    working_dir: row.get(0)?,
+                    sessions: count(row.get(1)?),
+                    attaches: count(row.get::<_, f64>(2)? as i64),
+                })
+            },
+        )?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(HistoryStats {
+        sessions: count(sessions),
+        created: count(created),
+        attaches: count(attaches),
+        ended: count(ended),
+        crashed: count(crashed),
+        total_duration,
+        timed: count(timed),
+        busiest_dirs,
+    })
+}
+
+/// Archived sessions whose history `policy` lets go, as of `cutoff` for
+/// their last event: those with nothing since, then the oldest beyond
+/// `max_archived`
+fn expired_sessions(
+    conn: &Connection,
+    policy: &RetentionPolicy,
+    cutoff: DateTime<Utc>,
+) -> Result<Vec<String>> {
+    let mut statement = conn.prepare(&format!(
+        "SELECT session_id, MAX(timestamp) FROM events WHERE session_id IN ({})
+         GROUP BY session_id ORDER BY 2 DESC, session_id",
+        ARCHIVED_SESSIONS
+    ))?;
+    let archived = statement
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let cutoff = cutoff.timestamp_micros();
+    Ok(archived
+        .into_iter()
+        .enumerate()
+        .filter(|(n, (_, last))| *last < cutoff || policy.max_archived.is_some_and(|max| *n >= max))
+        .map(|(_, (session_id, _))| session_id)
+        .collect())
+}
+
+fn remove_sessions(conn: &Connection, session_ids: &[String]) -> Result<()> {
+    for session_id in session_ids {
+        conn.execute(
+            "DELETE FROM events WHERE session_id = ?1",
+            params![session_id],
+        )?;
+    }
+    Ok(())
+}
+
+/// Apply `policy`, or with `dry_run` only work out what it would remove
+fn apply_retention(
+    conn: &mut Connection,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+) -> Result<CleanReport> {
+    // A keep_days that is negative, or too long to count back from now,
+    // lets nothing expire
+    let cutoff = policy
+        .keep_days
+        .and_then(Duration::try_days)
+        .filter(|keep| *keep >= Duration::zero())
+        .and_then(|keep| Utc::now().checked_sub_signed(keep))
+        .unwrap_or(DateTime::<Utc>::MIN_UTC);
+    let tx = conn.transaction()?;
+    let removed = expired_sessions(&tx, policy, cutoff)?;
+    let archived: usize = tx.query_row(
+        &format!(
+            "SELECT COUNT(DISTINCT session_id) FROM ({})",
+            ARCHIVED_SESSIONS
+        ),
+        [],
+        |row| row.get(0),
+    )?;
+    if !dry_run {
+        remove_sessions(&tx, &removed)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES ('last_clean', ?1)",
+            params![Utc::now().timestamp_micros()],
+        )?;
+    }
+    tx.commit()?;
+    if !dry_run && !removed.is_empty() {
+        compact(conn)?;
+    }
+    Ok(CleanReport {
+        kept_archived: archived - removed.len(),
+        removed,
+    })
+}
+
+/// When the retention policy was last applied
+fn last_clean(conn: &Connection) -> Result<Option<DateTime<Utc>>> {
+    let last: Option<i64> = conn
+        .query_row(
+            "SELECT value FROM meta WHERE key = 'last_clean'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(last.and_then(DateTime::from_timestamp_micros))
+}
+
+/// Rebuild the database without the pages freed by removed entries. The
+/// rebuilt pages go to the write-ahead log first, so fold that back in too.
+fn compact(conn: &Connection) -> Result<()> {
+    conn.execute_batch("VACUUM")?;
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+    Ok(())
+}
+
+/// Bring the history kept as one JSON file per session in `history/active`
+/// and `history/archived` into the database, then move each file into
+/// `active.migrated` or `archived.migrated`. Files are only moved once their
+/// entries are committed, and entries already there are skipped, so an import
+/// cut short runs again without losing or repeating anything. A daemon from an
+/// older nds may still be writing its file, so one that changed while it was
+/// read stays for next time.
+fn import_json_history(conn: &mut Connection, dir: &Path) -> Result<()> {
+    let mut files = Vec::new();
+    for json_dir in ["active", "archived"].map(|name| dir.join(name)) {
+        let Ok(read_dir) = fs::read_dir(&json_dir) else {
+            continue;
+        };
+        for file in read_dir {
+            let path = file?.path();
+            if path.extension().and_then(|s| s.to_str()) == Some("json") {
+                files.push(path);
+            }
+        }
+    }
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let mut imported = Vec::new();
+    for path in files {
+        // Gone if another process imported it first
+        let Ok(before) = fs::metadata(&path).and_then(|m| m.modified()) else {
+            continue;
+        };
+        // Unreadable files were skipped before, and still are
+        let Ok(content) = fs::read_to_string(&path) else {
+            continue;
+        };
+        let Ok(history) = serde_json::from_str::<SessionHistoryFile>(&content) else {
+            continue;
+        };
+        for entry in &history.entries {
+            if !has_entry(&tx, entry)? {
+                insert_entry(&tx, entry)?;
+            }
+        }
+        imported.push((path, before));
+    }
+    tx.commit()?;
+
+    for (path, before) in imported {
+        if fs::metadata(&path).and_then(|m| m.modified()).ok() != Some(before) {
+            continue;
+        }
+        let (Some(json_dir), Some(name)) = (path.parent(), path.file_name()) else {
+            continue;
+        };
+        let mut migrated = json_dir.as_os_str().to_owned();
+        migrated.push(".migrated");
+        let migrated = PathBuf::from(migrated);
+        fs::create_dir_all(&migrated)?;
+        let mut target = migrated.join(name);
+        // An older daemon started the file again after an earlier import
+        let mut n = 1;
+        while target.exists() {
+            target = migrated.join(format!("{}.{}", name.to_string_lossy(), n));
+            n += 1;
+        }
+        match fs::rename(&path, &target) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        // Left for an older daemon to create again if it needs to
+        let _ = fs::remove_dir(json_dir);
+    }
+    Ok(())
+}
+
+/// Whether the database already holds `entry`, from an earlier import
+fn has_entry(conn: &Connection, entry: &HistoryEntry) -> Result<bool> {
+    Ok(conn
+        .query_row(
+            "SELECT 1 FROM events WHERE session_id = ?1 AND timestamp = ?2 AND event = ?3",
+            params![
+                entry.session_id,
+                entry.timestamp.timestamp_micros(),
+                serde_json::to_string(&entry.event)?,
+            ],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(session_id: &str, event: SessionEvent, minutes_ago: i64) -> HistoryEntry {
+        HistoryEntry {
+            session_id: session_id.to_string(),
+            session_name: None,
+            event,
+            timestamp: Utc::now() - Duration::minutes(minutes_ago),
+            pid: 1,
+            shell: "/bin/sh".to_string(),
+            working_dir: "/tmp".to_string(),
+            duration_seconds: None,
+        }
+    }
+
+    fn database(entries: &[HistoryEntry]) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        create_schema(&conn).unwrap();
+        for entry in entries {
+            insert_entry(&conn, entry).unwrap();
+        }
+        conn
+    }
+
+    fn kinds(entries: &[HistoryEntry]) -> Vec<String> {
+        entries
+            .iter()
+            .map(|e| format!("{} {}", e.session_id, e.event.kind()))
+            .collect()
+    }
+
+    #[test]
+    fn queries_by_session_kind_and_time() {
+        let conn = database(&[
+            entry("a", SessionEvent::Created, 60),
+            entry("a", SessionEvent::Attached, 30),
+            entry("b", SessionEvent::Created, 20),
+            entry("b", SessionEvent::Killed, 10),
+            entry("a", SessionEvent::Attached, 5),
+        ]);
+        let query = |query: HistoryQuery| kinds(&query_entries(&conn, &query).unwrap());
+
+        // Newest first, leaving out the killed session
+        assert_eq!(
+            query(HistoryQuery::default()),
+            ["a attached", "a attached", "a created"]
+        );
+        assert_eq!(
+            query(HistoryQuery {
+                include_archived: true,
+                limit: Some(2),
+                ..Default::default()
+            }),
+            ["a attached", "b killed"]
+        );
+        assert_eq!(
+            query(HistoryQuery {
+                session_id: Some("b".to_string()),
+                include_archived: true,
+                oldest_first: true,
+                ..Default::default()
+            }),
+            ["b created", "b killed"]
+        );
+        assert_eq!(
+            query(HistoryQuery {
+                event: Some("attached".to_string()),
+                since: Some(Utc::now() - Duration::minutes(15)),
+                ..Default::default()
+            }),
+            ["a attached"]
+        );
+        assert_eq!(
+            query(HistoryQuery {
+                include_archived: true,
+                until: Some(Utc::now() - Duration::minutes(25)),
+                ..Default::default()
+            }),
+            ["a attached", "a created"]
+        );
+    }
+
+    #[test]
+    fn events_read_back_the_same() {
+        let renamed = entry(
+            "a",
+            SessionEvent::Renamed {
+                from: None,
+                to: "web".to_string(),
+            },
+            0,
+        );
+        let conn = database(std::slice::from_ref(&renamed));
+        let entries = query_entries(&conn, &HistoryQuery::default()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(matches!(
+            &entries[0].event,
+            SessionEvent::Renamed { from: None, to } if to == "web"
+        ));
+        assert_eq!(
+            entries[0].timestamp.timestamp_micros(),
+            renamed.timestamp.timestamp_micros()
+        );
+    }
+
+    #[test]
+    fn sums_up_a_window() {
+        let ended = |session_id: &str, event, minutes_ago, duration| HistoryEntry {
+            duration_seconds: Some(duration),
+            ..entry(session_id, event, minutes_ago)
+        };
+        let mut elsewhere = entry("c", SessionEvent::Created, 30);
+        elsewhere.working_dir = "/srv".to_string();
+        let conn = database(&[
+            entry("old", SessionEvent::Created, 600),
+            ended("old", SessionEvent::Crashed, 500, 6000),
+            entry("a", SessionEvent::Created, 50),
+            entry("a", SessionEvent::Attached, 40),
+            entry("a", SessionEvent::Attached, 30),
+            ended("a", SessionEvent::exited(ExitStatus::Code(0)), 20, 1800),
+            ended("a", SessionEvent::Killed, 10, 2400),
+            entry("b", SessionEvent::Attached, 20),
+            ended("b", SessionEvent::Crashed, 5, 600),
+            elsewhere,
+        ]);
+
+        let stats = history_stats(&conn, Some(Utc::now() - Duration::minutes(60)), 5).unwrap();
+        assert_eq!(
+            stats,
+            HistoryStats {
+                sessions: 3,
+                created: 2,
+                attaches: 3,
+                ended: 2,
+                crashed: 1,
+                total_duration: 3000,
+                timed: 2,
+                busiest_dirs: vec![
+                    DirectoryStats {
+                        working_dir: "/tmp".to_string(),
+                        sessions: 2,
+                        attaches: 3,
+                    },
+                    DirectoryStats {
+                        working_dir: "/srv".to_string(),
+                        sessions: 1,
+                        attaches: 0,
+                    },
+                ],
+            }
+        );
+        assert_eq!(stats.average_duration(), Some(1500));
+        assert_eq!(stats.crash_rate(), Some(0.5));
+
+        let stats = history_stats(&conn, None, 1).unwrap();
+        assert_eq!((stats.sessions, stats.ended, stats.crashed), (4, 3, 2));
+        assert_eq!(stats.busiest_dirs.len(), 1);
+        assert_eq!(
+            history_stats(&database(&[]), None, 5).unwrap(),
+            HistoryStats::default()
+        );
+    }
+
+    #[test]
+    fn retention_removes_only_old_archived_sessions() {
+        const DAY: i64 = 24 * 60;
+        let mut conn = database(&[
+            entry("old", SessionEvent::Created, 9 * DAY),
+            entry("old", SessionEvent::Crashed, 5 * DAY),
+            entry("mid", SessionEvent::Created, 9 * DAY),
+            entry("mid", SessionEvent::Killed, 2 * DAY),
+            entry("recent", SessionEvent::Created, 9 * DAY),
+            entry("recent", SessionEvent::Killed, 5),
+            entry("live", SessionEvent::Created, 9 * DAY),
+        ]);
+        let left = |conn: &Connection| {
+            let all = HistoryQuery {
+                include_archived: true,
+                oldest_first: true,
+                ..Default::default()
+            };
+            kinds(&query_entries(conn, &all).unwrap())
+        };
+        assert_eq!(last_clean(&conn).unwrap(), None);
+
+        let by_age = RetentionPolicy {
+            keep_days: Some(3),
+            max_archived: None,
+        };
+        let report = apply_retention(&mut conn, &by_age, false).unwrap();
+        assert_eq!(report.removed, ["old"]);
+        assert_eq!(report.kept_archived, 2);
+        assert!(last_clean(&conn).unwrap().is_some());
+
+        // Only the newest archived session is kept, but not on a dry run
+        let by_count = RetentionPolicy {
+            keep_days: None,
+            max_archived: Some(1),
+        };
+        let report = apply_retention(&mut conn, &by_count, true).unwrap();
+        assert_eq!(report.removed, ["mid"]);
+        assert_eq!(left(&conn).len(), 5);
+        apply_retention(&mut conn, &by_count, false).unwrap();
+        assert_eq!(
+            left(&conn),
+            ["recent created", "live created", "recent killed"]
+        );
+
+        // Nonsense ages remove nothing rather than panic or remove everything
+        for keep_days in [i64::MAX, -1] {
+            let policy = RetentionPolicy {
+                keep_days: Some(keep_days),
+                max_archived: None,
+            };
+            let report = apply_retention(&mut conn, &policy, false).unwrap();
+            assert!(report.removed.is_empty());
+        }
+    }
+
+    #[test]
+    fn imports_json_history_again_without_repeats() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |sub: &str, history: &SessionHistoryFile| {
+            let sub = dir.path().join(sub);
+            fs::create_dir_all(&sub).unwrap();
+            let path = sub.join(format!("{}.json", history.session_id));
+            fs::write(path, serde_json::to_string(history).unwrap()).unwrap();
+        };
+        write(
+            "active",
+            &SessionHistoryFile {
+                session_id: "a".to_string(),
+                created_at: Utc::now(),
+                entries: vec![entry("a", SessionEvent::Created, 2)],
+            },
+        );
+        write(
+            "archived",
+            &SessionHistoryFile {
+                session_id: "b".to_string(),
+                created_at: Utc::now(),
+                entries: vec![
+                    entry("b", SessionEvent::Created, 3),
+                    entry("b", SessionEvent::Killed, 1),
+                ],
+            },
+        );
+        fs::write(dir.path().join("active").join("broken.json"), "{").unwrap();
+
+        let mut conn = open_database(&dir.path().join(DATABASE_FILE)).unwrap();
+        import_json_history(&mut conn, dir.path()).unwrap();
+        let all = HistoryQuery {
+            include_archived: true,
+            oldest_first: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            kinds(&query_entries(&conn, &all).unwrap()),
+            ["b created", "a created", "b killed"]
+        );
+        assert!(dir.path().join("active.migrated").join("a.json").exists());
+        assert!(dir.path().join("active").join("broken.json").exists());
+        assert!(!dir.path().join("archived").exists());
+
+        // As if the last import stopped before moving b's file, while an
+        // older daemon carried on writing to a's
+        fs::create_dir(dir.path().join("archived")).unwrap();
+        fs::copy(
+            dir.path().join("archived.migrated").join("b.json"),
+            dir.path().join("archived").join("b.json"),
+        )
+        .unwrap();
+        write(
+            "active",
+            &SessionHistoryFile {
+                session_id: "a".to_string(),
+                created_at: Utc::now(),
+                entries: vec![entry("a", SessionEvent::Attached, 0)],
+            },
+        );
+        import_json_history(&mut conn, dir.path()).unwrap();
+        assert_eq!(
+            kinds(&query_entries(&conn, &all).unwrap()),
+            ["b created", "a created", "b killed", "a attached"]
+        );
+        assert!(dir.path().join("active.migrated").join("a.json.1").exists());
+    }
+
+    #[test]
+    fn compacts_after_removing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(DATABASE_FILE);
+        let mut conn = open_database(&path).unwrap();
+        for i in 0..2000 {
+            insert_entry(&conn, &entry(&format!("s{}", i), SessionEvent::Created, 90)).unwrap();
+            insert_entry(&conn, &entry(&format!("s{}", i), SessionEvent::Killed, 60)).unwrap();
+        }
+        compact(&conn).unwrap();
+        let full = fs::metadata(&path).unwrap().len();
+
+        let everything = RetentionPolicy {
+            keep_days: Some(0),
+            max_archived: None,
+        };
+        let report = apply_retention(&mut conn, &everything, false).unwrap();
+        assert_eq!(report.removed.len(), 2000);
+        assert!(fs::metadata(&path).unwrap().len() < full / 4);
+    }
+}
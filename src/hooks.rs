@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::process::{Command, Stdio};
+
+use crate::config::Config;
+use crate::session::Session;
+
+/// A point in a session's life that a hook can be run at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    /// The session was created
+    Create,
+    /// A client attached
+    Attach,
+    /// A client detached
+    Detach,
+    /// The shell or command exited
+    Exit,
+    /// The session's daemon was found dead
+    Crash,
+    /// The shell or command rang the bell
+    Bell,
+}
+
+impl Hook {
+    /// Name of the hook in config.json, also passed to it as `NDS_HOOK`
+    pub fn name(self) -> &'static str {
+        match self {
+            Hook::Create => "on-create",
+            Hook::Attach => "on-attach",
+            Hook::Detach => "on-detach",
+            Hook::Exit => "on-exit",
+            Hook::Crash => "on-crash",
+            Hook::Bell => "on-bell",
+        }
+    }
+}
+
+impl fmt::Display for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Shell commands to run at points in a session's life, from `hooks` in
+/// config.json
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Hooks {
+    pub on_create: Option<String>,
+    pub on_attach: Option<String>,
+    pub on_detach: Option<String>,
+    pub on_exit: Option<String>,
+    pub on_crash: Option<String>,
+    pub on_bell: Option<String>,
+}
+
+impl Hooks {
+    /// The command configured for `hook`, if any
+    pub fn command(&self, hook: Hook) -> Option<&str> {
+        match hook {
+            Hook::Create => self.on_create.as_deref(),
+            Hook::Attach => self.on_attach.as_deref(),
+            Hook::Detach => self.on_detach.as_deref(),
+            Hook::Exit => self.on_exit.as_deref(),
+            Hook::Crash => self.on_crash.as_deref(),
+            Hook::Bell => self.on_bell.as_deref(),
+        }
+    }
+}
+
+/// What a hook is told about the session, as environment variables
+pub fn hook_env(hook: Hook, session: &Session) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("NDS_HOOK", hook.name().to_string()),
+        ("NDS_SESSION_ID", session.id.clone()),
+        ("NDS_SESSION_NAME", session.name.clone().unwrap_or_default()),
+        ("NDS_SESSION_PID", session.pid.to_string()),
+        ("NDS_SESSION_DIR", session.working_dir.clone()),
+        ("NDS_SESSION_TAGS", session.tags.join(",")),
+    ];
+    if let (Hook::Exit, Some(status)) = (hook, session.exit_status) {
+        env.push(("NDS_EXIT_CODE", status.code().to_string()));
+        if let Some(signal) = status.signal() {
+            env.push(("NDS_EXIT_SIGNAL", signal.to_string()));
+        }
+    }
+    env
+}
+
+/// Run the configured command for `hook` in the background, with the session
+/// described in `hook_env`. Hooks never hold up or fail what triggered them.
+pub fn run(hook: Hook, session: &Session) {
+    let Ok(config) = Config::load() else {
+        return;
+    };
+    let Some(command) = config.hooks.command(hook) else {
+        return;
+    };
+
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(hook_env(hook, session))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn();
+    match child {
+        // Reap it without waiting here
+        Ok(mut child) => {
+            std::thread::spawn(move || child.wait());
+        }
+        Err(e) => eprintln!("Warning: failed to run {} hook '{}': {}", hook, command, e),
+    }
+}
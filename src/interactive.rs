@@ -1,18 +1,20 @@
+use crate::keymap::{KeyMap, PickerAction};
+use crate::session_sort::ListConfig;
+use crate::text_input::{InputEvent, TextInput};
+use crate::ui::{help_line, picker_help, SessionList};
 use crate::{NdsError, Result, Session, SessionManager};
-use chrono::Timelike;
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind},
-    execute,
-    terminal::{
-        self, disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyEventKind,
+        MouseEvent,
     },
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{
     backend::{Backend, CrosstermBackend},
-    layout::{Alignment, Constraint, Direction, Layout, Rect},
-    style::{Color, Modifier, Style},
-    text::{Line, Span},
-    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    layout::{Alignment, Constraint, Direction, Layout},
+    widgets::{Block, Borders, Paragraph},
     Frame, Terminal,
 };
 use std::{
@@ -20,22 +22,79 @@ use std::{
     time::{Duration, Instant},
 };
 
+pub use crate::ui::{group_by_tag, PickerRow};
+
+/// Which sessions the picker lists, kept to list them again after a change
+enum Scope {
+    Tags(Vec<String>),
+    Group(String),
+}
+
+impl Scope {
+    fn sessions(&self) -> Result<Vec<Session>> {
+        match self {
+            Scope::Tags(tags) => Ok(SessionManager::list_sessions()?
+                .into_iter()
+                .filter(|s| s.matches_tags(tags))
+                .collect()),
+            Scope::Group(group) => SessionManager::group_sessions(group),
+        }
+    }
+}
+
+/// What the picker is waiting for
+enum Mode {
+    /// Moving around the list
+    Browse,
+    /// A new name for the selected session (`r`)
+    Rename(TextInput),
+    /// A name for a new session (`c`)
+    Create(TextInput),
+    /// A yes or no to killing these sessions (`x`)
+    ConfirmKill(Vec<Session>),
+    /// The filter being typed (`/`)
+    Filter(TextInput),
+}
+
 pub struct InteractivePicker {
-    sessions: Vec<Session>,
-    state: ListState,
-    current_session_id: Option<String>,
+    scope: Scope,
+    list: SessionList,
+    /// Digits typed so far, to jump to the session with that index
+    typed: String,
+    mode: Mode,
+    /// What the last action did, shown in the footer until the next key
+    status: Option<String>,
 }
 
 impl InteractivePicker {
     pub fn new() -> Result<Self> {
-        let sessions = SessionManager::list_sessions()?;
+        Self::with_tags(&[])
+    }
+
+    /// Picker limited to sessions carrying any of `tags`
+    pub fn with_tags(tags: &[String]) -> Result<Self> {
+        let scope = Scope::Tags(tags.to_vec());
+        let sessions = scope.sessions()?;
         if sessions.is_empty() {
             return Err(NdsError::SessionNotFound("No active sessions".to_string()));
         }
+        Ok(Self::with_sessions(scope, sessions))
+    }
 
-        let mut state = ListState::default();
-        state.select(Some(0));
+    /// Picker limited to the sessions in `group` (`nds attach @group`)
+    pub fn with_group(group: &str) -> Result<Self> {
+        let scope = Scope::Group(group.to_string());
+        let sessions = scope.sessions()?;
+        if sessions.is_empty() {
+            return Err(NdsError::SessionNotFound(format!(
+                "No active sessions in group {}",
+                group
+            )));
+        }
+        Ok(Self::with_sessions(scope, sessions))
+    }
 
+    fn with_sessions(scope: Scope, sessions: Vec<Session>) -> Self {
         // Check if we're currently attached to a session
         let mut current_session_id = std::env::var("NDS_SESSION_ID").ok();
 
@@ -44,11 +103,26 @@ impl InteractivePicker {
             current_session_id = Self::detect_current_session(&sessions);
         }
 
-        Ok(Self {
-            sessions,
-            state,
-            current_session_id,
-        })
+        Self {
+            scope,
+            list: SessionList::new(sessions, current_session_id),
+            typed: String::new(),
+            mode: Mode::Browse,
+            status: None,
+        }
+    }
+
+    /// Order the sessions by `order` instead of the configured order
+    pub fn sort_by(&mut self, order: ListConfig) {
+        self.list.sort_by(order);
+    }
+
+    /// List the sessions again after changing them
+    fn reload(&mut self) {
+        match self.scope.sessions() {
+            Ok(sessions) => self.list.show(sessions),
+            Err(e) => self.status = Some(format!("Couldn't list sessions: {}", e)),
+        }
     }
 
     fn detect_current_session(sessions: &[Session]) -> Option<String> {
@@ -140,6 +214,7 @@ impl InteractivePicker {
         let tick_rate = Duration::from_millis(250);
 
         loop {
+            self.list.refresh_preview();
             terminal.draw(|f| self.ui(f))?;
 
             let timeout = tick_rate
@@ -147,20 +222,13 @@ impl InteractivePicker {
                 .unwrap_or_else(|| Duration::from_secs(0));
 
             if crossterm::event::poll(timeout)? {
-                if let Event::Key(key) = event::read()? {
-                    if key.kind == KeyEventKind::Press {
-                        match key.code {
-                            KeyCode::Char('q') | KeyCode::Esc => return Ok(None),
-                            KeyCode::Down | KeyCode::Char('j') => self.next(),
-                            KeyCode::Up | KeyCode::Char('k') => self.previous(),
-                            KeyCode::Enter => {
-                                if let Some(selected) = self.state.selected() {
-                                    return Ok(Some(self.sessions[selected].id.clone()));
-                                }
-                            }
-                            _ => {}
-                        }
-                    }
+                let picked = match event::read()? {
+                    Event::Key(key) if key.kind == KeyEventKind::Press => self.handle_key(key),
+                    Event::Mouse(mouse) => self.handle_mouse(mouse),
+                    _ => None,
+                };
+                if let Some(picked) = picked {
+                    return Ok(picked);
                 }
             }
 
@@ -170,32 +238,133 @@ impl InteractivePicker {
         }
     }
 
-    fn next(&mut self) {
-        let i = match self.state.selected() {
-            Some(i) => {
-                if i >= self.sessions.len() - 1 {
-                    0
-                } else {
-                    i + 1
+    /// Act on a key press. `Some` ends the picker, with the session to
+    /// attach to if there is one.
+    fn handle_key(&mut self, key: KeyEvent) -> Option<Option<String>> {
+        self.status = None;
+        match std::mem::replace(&mut self.mode, Mode::Browse) {
+            Mode::Browse => return self.browse(key),
+            Mode::Rename(mut input) => match input.handle_key(key.code, key.modifiers) {
+                InputEvent::Continue => self.mode = Mode::Rename(input),
+                InputEvent::Cancel => {}
+                InputEvent::Submit(name) => {
+                    let id = self.list.selected().map(|session| session.id.clone())?;
+                    match SessionManager::rename_session(&id, name.trim()) {
+                        Ok(()) => self.reload(),
+                        Err(e) => {
+                            self.status = Some(e.to_string());
+                            self.mode = Mode::Rename(input);
+                        }
+                    }
+                }
+            },
+            Mode::Create(mut input) => match input.handle_key(key.code, key.modifiers) {
+                InputEvent::Continue => self.mode = Mode::Create(input),
+                InputEvent::Cancel => {}
+                InputEvent::Submit(name) => {
+                    let name = Some(name.trim().to_string()).filter(|name| !name.is_empty());
+                    match SessionManager::create_session_with_name(name) {
+                        Ok(session) => {
+                            // Made from a group's picker, it joins the group
+                            if let Scope::Group(group) = &self.scope {
+                                let _ = SessionManager::set_group(&session.id, Some(group));
+                            }
+                            return Some(Some(session.id));
+                        }
+                        Err(e) => {
+                            self.status = Some(e.to_string());
+                            self.mode = Mode::Create(input);
+                        }
+                    }
+                }
+            },
+            Mode::Filter(mut input) => {
+                if self.list.filter_key(&mut input, key) {
+                    self.mode = Mode::Filter(input);
                 }
             }
-            None => 0,
-        };
-        self.state.select(Some(i));
+            Mode::ConfirmKill(sessions) => {
+                if matches!(key.code, KeyCode::Char('y') | KeyCode::Char('Y')) {
+                    let report = SessionManager::kill_sessions(&sessions);
+                    self.status = Some(match report.failed.first() {
+                        Some((id, e)) => format!("Couldn't kill {}: {}", id, e),
+                        None if report.killed.len() == 1 => "Killed 1 session".to_string(),
+                        None => format!("Killed {} sessions", report.killed.len()),
+                    });
+                    self.reload();
+                }
+            }
+        }
+        None
     }
 
-    fn previous(&mut self) {
-        let i = match self.state.selected() {
-            Some(i) => {
-                if i == 0 {
-                    self.sessions.len() - 1
-                } else {
-                    i - 1
-                }
+    /// Clicking a session selects it and double-clicking attaches to it,
+    /// unless something else is being typed or answered
+    fn handle_mouse(&mut self, mouse: MouseEvent) -> Option<Option<String>> {
+        if !matches!(self.mode, Mode::Browse | Mode::Filter(_)) || !self.list.mouse(mouse) {
+            return None;
+        }
+        self.list.selected().map(|session| Some(session.id.clone()))
+    }
+
+    fn browse(&mut self, key: KeyEvent) -> Option<Option<String>> {
+        if !matches!(key.code, KeyCode::Char(c) if c.is_ascii_digit()) {
+            self.typed.clear();
+        }
+        // Esc clears a filter before it quits, whatever quits
+        if key.code == KeyCode::Esc && !self.list.query().is_empty() {
+            self.list.set_query("");
+            return None;
+        }
+        let Some(action) = KeyMap::current().picker.action(&key) else {
+            if let KeyCode::Char(c @ '0'..='9') = key.code {
+                self.jump(c);
             }
-            None => 0,
+            return None;
         };
-        self.state.select(Some(i));
+        match action {
+            PickerAction::Quit => return Some(None),
+            PickerAction::Filter => self.mode = Mode::Filter(TextInput::new(self.list.query())),
+            PickerAction::Down => self.list.next(),
+            PickerAction::Up => self.list.previous(),
+            PickerAction::Open => {
+                return self.list.selected().map(|session| Some(session.id.clone()));
+            }
+            PickerAction::Mark => self.list.toggle_mark(),
+            PickerAction::Kill => {
+                let sessions = self.list.marked_or_selected();
+                if !sessions.is_empty() {
+                    self.mode = Mode::ConfirmKill(sessions);
+                }
+            }
+            PickerAction::Rename => {
+                if let Some(session) = self.list.selected() {
+                    let name = session.name.clone().unwrap_or_default();
+                    self.mode = Mode::Rename(TextInput::new(&name));
+                }
+            }
+            PickerAction::New => self.mode = Mode::Create(TextInput::default()),
+            PickerAction::Sort => {
+                if let Err(e) = self.list.cycle_sort() {
+                    self.status = Some(format!("Couldn't save the sort: {}", e));
+                }
+            }
+        }
+        None
+    }
+
+    /// Select the session whose index is the digits typed so far, starting
+    /// over from `digit` when they no longer pick one
+    fn jump(&mut self, digit: char) {
+        self.typed.push(digit);
+        let mut found = self.list.select(&self.typed);
+        if !found {
+            self.typed = digit.to_string();
+            found = self.list.select(&self.typed);
+        }
+        if !found {
+            self.typed.clear();
+        }
     }
 
     fn ui(&mut self, f: &mut Frame) {
@@ -208,219 +377,61 @@ impl InteractivePicker {
                 Constraint::Length(3),
             ])
             .split(f.area());
+        let theme = self.list.theme().clone();
 
         // Header - more minimal
-        let header = Paragraph::new("SESSIONS")
-            .style(Style::default().fg(Color::DarkGray))
-            .alignment(Alignment::Left)
-            .block(
-                Block::default()
-                    .borders(Borders::BOTTOM)
-                    .border_style(Style::default().fg(Color::DarkGray)),
-            );
-        f.render_widget(header, chunks[0]);
-
-        // Sessions list
-        let items: Vec<ListItem> = self
-            .sessions
-            .iter()
-            .map(|session| {
-                let client_count = session.get_client_count();
-
-                let now = chrono::Utc::now().timestamp();
-                let created = session.created_at.timestamp();
-                let duration = now - created;
-                let uptime = format_duration(duration as u64);
-
-                // Check if this is the current attached session
-                let is_current = self.current_session_id.as_ref() == Some(&session.id);
-
-                // Status indicator - simplified
-                let (status_icon, status_color) = if is_current {
-                    ("★", Color::Cyan)
-                } else if client_count > 0 {
-                    ("●", Color::Green)
-                } else {
-                    ("○", Color::Gray)
-                };
-
-                // Session name styling
-                let name_style = if is_current {
-                    Style::default()
-                        .fg(Color::Cyan)
-                        .add_modifier(Modifier::BOLD)
-                } else {
-                    Style::default().fg(Color::White)
-                };
-
-                // Build the status text that appears on the right
-                let status_text = if is_current {
-                    if client_count > 0 {
-                        format!(
-                            "CURRENT SESSION · {} CLIENT{}",
-                            client_count,
-                            if client_count == 1 { "" } else { "S" }
-                        )
-                    } else {
-                        "CURRENT SESSION".to_string()
-                    }
-                } else if client_count > 0 {
-                    format!(
-                        "{} CLIENT{}",
-                        client_count,
-                        if client_count == 1 { "" } else { "S" }
-                    )
-                } else {
-                    "DETACHED".to_string()
-                };
-
-                // Format created time
-                let now = chrono::Local::now();
-                let local_time: chrono::DateTime<chrono::Local> = session.created_at.into();
-                let duration = now.signed_duration_since(local_time);
-
-                let created_time = if duration.num_days() > 0 {
-                    format!(
-                        "{}d, {:02}:{:02}",
-                        duration.num_days(),
-                        local_time.hour(),
-                        local_time.minute()
-                    )
-                } else {
-                    local_time.format("%H:%M:%S").to_string()
-                };
-
-                // Truncate working dir if too long
-                let mut working_dir = session.working_dir.clone();
-                if working_dir.len() > 30 {
-                    working_dir = format!(
-                        "...{}",
-                        &session.working_dir[session.working_dir.len() - 27..]
-                    );
-                }
+        self.list.render_header(f, chunks[0], "SESSIONS");
+
+        self.list.render(f, chunks[1]);
+
+        // Footer - what a key started, or else help
+        let footer_block = Block::default()
+            .borders(Borders::TOP)
+            .border_style(theme.dim);
+        let prompt = match &self.mode {
+            Mode::Browse => None,
+            Mode::Rename(input) => Some((input, "Rename session (empty to clear the name)")),
+            Mode::Create(input) => Some((input, "New session name (Enter for none)")),
+            Mode::Filter(input) => Some((input, "Filter by name, ID, directory or command")),
+            Mode::ConfirmKill(sessions) => {
+                let names: Vec<String> = sessions.iter().map(Session::display_name).collect();
+                let question = Paragraph::new(format!("Kill {}? [y/n]", names.join(", ")))
+                    .style(theme.warning)
+                    .alignment(Alignment::Center)
+                    .block(footer_block);
+                f.render_widget(question, chunks[2]);
+                return;
+            }
+        };
+        if let Some((input, title)) = prompt {
+            input.render(f, chunks[2], self.status.as_deref().unwrap_or(title));
+            return;
+        }
+        if let Some(status) = &self.status {
+            let status = Paragraph::new(status.as_str())
+                .style(theme.notice)
+                .alignment(Alignment::Center)
+                .block(footer_block);
+            f.render_widget(status, chunks[2]);
+            return;
+        }
 
-                // Build left side with fixed widths
-                let left_side = format!(
-                    " {} {:<25} │ PID {:<6} │ {:<8} │ {:<8} │ {:<30}",
-                    status_icon,
-                    session.display_name(),
-                    session.pid,
-                    uptime,
-                    created_time,
-                    working_dir
-                );
-
-                // Calculate padding for right alignment
-                let terminal_width = terminal::size().unwrap_or((80, 24)).0 as usize;
-                let left_len = left_side.chars().count();
-                let status_len = status_text.chars().count();
-                let padding = terminal_width.saturating_sub(left_len + status_len + 2);
-
-                let content = vec![Line::from(vec![
-                    Span::styled(
-                        format!(" {} ", status_icon),
-                        Style::default()
-                            .fg(status_color)
-                            .add_modifier(Modifier::BOLD),
-                    ),
-                    Span::styled(format!("{:<25}", session.display_name()), name_style),
-                    Span::styled(" │ ", Style::default().fg(Color::DarkGray)),
-                    Span::styled(
-                        format!("PID {:<6}", session.pid),
-                        Style::default().fg(Color::DarkGray),
-                    ),
-                    Span::styled(" │ ", Style::default().fg(Color::DarkGray)),
-                    Span::styled(
-                        format!("{:<8}", uptime),
-                        Style::default().fg(Color::DarkGray),
-                    ),
-                    Span::styled(" │ ", Style::default().fg(Color::DarkGray)),
-                    Span::styled(
-                        format!("{:<8}", created_time),
-                        Style::default().fg(Color::DarkGray),
-                    ),
-                    Span::styled(" │ ", Style::default().fg(Color::DarkGray)),
-                    Span::styled(
-                        format!("{:<30}", working_dir),
-                        Style::default().fg(Color::DarkGray),
-                    ),
-                    Span::styled(" ".repeat(padding), Style::default()),
-                    Span::styled(
-                        status_text.clone(),
-                        if is_current {
-                            Style::default()
-                                .fg(Color::Cyan)
-                                .add_modifier(Modifier::BOLD)
-                        } else if client_count > 0 {
-                            Style::default().fg(Color::Green)
-                        } else {
-                            Style::default()
-                                .fg(Color::DarkGray)
-                                .add_modifier(Modifier::DIM)
-                        },
-                    ),
-                ])];
-                ListItem::new(content)
-            })
-            .collect();
-
-        let sessions_list = List::new(items)
-            .block(Block::default().borders(Borders::NONE))
-            .highlight_style(
-                Style::default()
-                    .bg(Color::Rgb(40, 40, 40))
-                    .add_modifier(Modifier::BOLD),
-            )
-            .highlight_symbol("");
-
-        f.render_stateful_widget(sessions_list, chunks[1], &mut self.state);
-
-        // Footer - cleaner design
-        let help_text = vec![
-            Span::styled("↑↓/jk ", Style::default().fg(Color::DarkGray)),
-            Span::styled("navigate", Style::default().fg(Color::Gray)),
-            Span::styled("  ", Style::default()),
-            Span::styled("⏎ ", Style::default().fg(Color::DarkGray)),
-            Span::styled("attach", Style::default().fg(Color::Gray)),
-            Span::styled("  ", Style::default()),
-            Span::styled("q ", Style::default().fg(Color::DarkGray)),
-            Span::styled("quit", Style::default().fg(Color::Gray)),
-        ];
-
-        let session_info = format!("{} sessions", self.sessions.len());
-
-        let footer = Paragraph::new(Line::from(help_text))
-            .style(Style::default())
+        let mut keys = picker_help(&[
+            (&[PickerAction::Up, PickerAction::Down], "navigate"),
+            (&[PickerAction::Open], "attach"),
+            (&[PickerAction::Mark], "mark"),
+            (&[PickerAction::Kill], "kill"),
+            (&[PickerAction::Rename], "rename"),
+            (&[PickerAction::New], "new"),
+            (&[PickerAction::Filter], "filter"),
+            (&[PickerAction::Sort], "sort"),
+            (&[PickerAction::Quit], "quit"),
+        ]);
+        keys.insert(1.min(keys.len()), ("0-9".to_string(), "index"));
+        let help_text = help_line(&keys, &theme);
+        let footer = Paragraph::new(help_text)
             .alignment(Alignment::Center)
-            .block(
-                Block::default()
-                    .borders(Borders::TOP)
-                    .border_style(Style::default().fg(Color::DarkGray)),
-            );
+            .block(footer_block);
         f.render_widget(footer, chunks[2]);
-
-        // Session count on the right
-        let count_widget = Paragraph::new(session_info)
-            .style(Style::default().fg(Color::DarkGray))
-            .alignment(Alignment::Right);
-        let count_area = Rect {
-            x: chunks[2].x + 2,
-            y: chunks[2].y + 1,
-            width: chunks[2].width - 4,
-            height: 1,
-        };
-        f.render_widget(count_widget, count_area);
-    }
-}
-
-fn format_duration(seconds: u64) -> String {
-    if seconds < 60 {
-        format!("{}s", seconds)
-    } else if seconds < 3600 {
-        format!("{}m", seconds / 60)
-    } else if seconds < 86400 {
-        format!("{}h {}m", seconds / 3600, (seconds % 3600) / 60)
-    } else {
-        format!("{}d {}h", seconds / 86400, (seconds % 86400) / 3600)
     }
 }
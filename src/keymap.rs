@@ -0,0 +1,535 @@
+//! Which keys do what: the escapes typed after Enter while attached, and the
+//! keys of the picker, the `~s` switcher and the scrollback viewer.
+//!
+//! Each section of the `keymap` config maps actions to a key or a list of
+//! keys, written as `j`, `G`, `?`, `space`, `enter`, `esc`, `tab`, `up`,
+//! `pgdn`, `home`, `f5` and so on, optionally after `ctrl-`, `alt-` or
+//! `shift-`. Actions left out keep their default keys, less any the config
+//! gave to another action, and `[]` unbinds one.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use serde::de::{DeserializeOwned, Error as _};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::config::Config;
+
+/// One key, with the modifiers held down with it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    pub code: KeyCode,
+    pub modifiers: KeyModifiers,
+}
+
+const NAMED_KEYS: &[(&str, KeyCode)] = &[
+    ("space", KeyCode::Char(' ')),
+    ("enter", KeyCode::Enter),
+    ("esc", KeyCode::Esc),
+    ("tab", KeyCode::Tab),
+    ("backspace", KeyCode::Backspace),
+    ("delete", KeyCode::Delete),
+    ("insert", KeyCode::Insert),
+    ("up", KeyCode::Up),
+    ("down", KeyCode::Down),
+    ("left", KeyCode::Left),
+    ("right", KeyCode::Right),
+    ("home", KeyCode::Home),
+    ("end", KeyCode::End),
+    ("pgup", KeyCode::PageUp),
+    ("pgdn", KeyCode::PageDown),
+    ("pageup", KeyCode::PageUp),
+    ("pagedown", KeyCode::PageDown),
+];
+
+const MODIFIER_NAMES: &[(&str, KeyModifiers)] = &[
+    ("ctrl-", KeyModifiers::CONTROL),
+    ("alt-", KeyModifiers::ALT),
+    ("shift-", KeyModifiers::SHIFT),
+];
+
+impl Key {
+    /// Whether `event` is this key. Shift is part of the character typed,
+    /// so it only counts for keys that aren't characters.
+    pub fn matches(&self, event: &KeyEvent) -> bool {
+        let ignored = match event.code {
+            KeyCode::Char(_) => KeyModifiers::SHIFT,
+            _ => KeyModifiers::empty(),
+        };
+        self.code == event.code && self.modifiers == event.modifiers.difference(ignored)
+    }
+
+    /// The byte a terminal sends for this key, for keys that are one byte
+    pub fn byte(&self) -> Option<u8> {
+        match (self.code, self.modifiers) {
+            (KeyCode::Char(c), KeyModifiers::NONE) if c.is_ascii() => Some(c as u8),
+            (KeyCode::Char(c), KeyModifiers::CONTROL) if c.is_ascii_alphabetic() => {
+                Some(c.to_ascii_lowercase() as u8 & 0x1f)
+            }
+            (KeyCode::Tab, KeyModifiers::NONE) => Some(b'\t'),
+            _ => None,
+        }
+    }
+
+    /// The key as the config writes it
+    fn spec(&self) -> String {
+        let mut spec: String = MODIFIER_NAMES
+            .iter()
+            .filter(|(_, modifier)| self.modifiers.contains(*modifier))
+            .map(|(name, _)| *name)
+            .collect();
+        match NAMED_KEYS.iter().find(|(_, code)| *code == self.code) {
+            Some((name, _)) => spec.push_str(name),
+            None => match self.code {
+                KeyCode::Char(c) => spec.push(c),
+                KeyCode::F(n) => spec.push_str(&format!("f{}", n)),
+                code => spec.push_str(&format!("{:?}", code).to_lowercase()),
+            },
+        }
+        spec
+    }
+}
+
+impl FromStr for Key {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, String> {
+        let mut modifiers = KeyModifiers::NONE;
+        let mut rest = spec;
+        // `-` on its own, or after a modifier, is the key itself
+        while let Some((name, modifier)) = MODIFIER_NAMES.iter().find(|(name, _)| {
+            rest.len() > name.len()
+                && rest
+                    .get(..name.len())
+                    .is_some_and(|start| start.eq_ignore_ascii_case(name))
+        }) {
+            modifiers |= *modifier;
+            rest = &rest[name.len()..];
+        }
+
+        let mut chars = rest.chars();
+        let code = match (chars.next(), chars.next()) {
+            (Some(c), None) => KeyCode::Char(c),
+            (None, _) => return Err("a key can't be empty".to_string()),
+            _ => {
+                let name = rest.to_lowercase();
+                match NAMED_KEYS.iter().find(|(key, _)| *key == name) {
+                    Some((_, code)) => *code,
+                    None => match name.strip_prefix('f').and_then(|n| n.parse().ok()) {
+                        Some(n @ 1..=12) => KeyCode::F(n),
+                        _ => return Err(format!("'{}' is not a key", spec)),
+                    },
+                }
+            }
+        };
+
+        // Shifted characters are written as what they type
+        let code = match code {
+            KeyCode::Char(c) if modifiers.contains(KeyModifiers::SHIFT) => {
+                modifiers.remove(KeyModifiers::SHIFT);
+                KeyCode::Char(c.to_ascii_uppercase())
+            }
+            code => code,
+        };
+        Ok(Key { code, modifiers })
+    }
+}
+
+/// The key as help shows it: `↑`, `⏎`, `C-n`
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.modifiers.contains(KeyModifiers::CONTROL) {
+            write!(f, "C-")?;
+        }
+        if self.modifiers.contains(KeyModifiers::ALT) {
+            write!(f, "M-")?;
+        }
+        if self.modifiers.contains(KeyModifiers::SHIFT) {
+            write!(f, "S-")?;
+        }
+        match self.code {
+            KeyCode::Char(' ') => write!(f, "␣"),
+            KeyCode::Char(c) => write!(f, "{}", c),
+            KeyCode::Enter => write!(f, "⏎"),
+            KeyCode::Esc => write!(f, "Esc"),
+            KeyCode::Tab => write!(f, "Tab"),
+            KeyCode::Backspace => write!(f, "Bksp"),
+            KeyCode::Delete => write!(f, "Del"),
+            KeyCode::Insert => write!(f, "Ins"),
+            KeyCode::Up => write!(f, "↑"),
+            KeyCode::Down => write!(f, "↓"),
+            KeyCode::Left => write!(f, "←"),
+            KeyCode::Right => write!(f, "→"),
+            KeyCode::Home => write!(f, "Home"),
+            KeyCode::End => write!(f, "End"),
+            KeyCode::PageUp => write!(f, "PgUp"),
+            KeyCode::PageDown => write!(f, "PgDn"),
+            KeyCode::F(n) => write!(f, "F{}", n),
+            code => write!(f, "{:?}", code),
+        }
+    }
+}
+
+/// The actions of one section of the keymap
+pub trait Action: Copy + Ord + fmt::Debug + Serialize + DeserializeOwned + 'static {
+    /// Each action with the keys it has unless the config says otherwise
+    const DEFAULTS: &'static [(Self, &'static [&'static str])];
+
+    /// Why `key` can't be bound in this section
+    fn check(_key: &Key) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// The keys bound to each action of one section
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bindings<A: Action> {
+    keys: BTreeMap<A, Vec<Key>>,
+}
+
+impl<A: Action> Bindings<A> {
+    /// The action `event` is bound to
+    pub fn action(&self, event: &KeyEvent) -> Option<A> {
+        self.find(|key| key.matches(event))
+    }
+
+    /// The action of the first key `matches` accepts
+    pub fn find(&self, matches: impl Fn(&Key) -> bool) -> Option<A> {
+        self.keys
+            .iter()
+            .find(|(_, keys)| keys.iter().any(&matches))
+            .map(|(action, _)| *action)
+    }
+
+    /// The keys bound to `action`, in the order they were given
+    pub fn keys(&self, action: A) -> &[Key] {
+        self.keys.get(&action).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// The keys bound to `action` as help shows them: `↑/k`
+    pub fn label(&self, action: A) -> String {
+        let labels: Vec<String> = self.keys(action).iter().map(Key::to_string).collect();
+        labels.join("/")
+    }
+}
+
+impl<A: Action> Default for Bindings<A> {
+    fn default() -> Self {
+        let keys = A::DEFAULTS
+            .iter()
+            .map(|(action, specs)| {
+                let keys = specs
+                    .iter()
+                    .map(|spec| spec.parse().expect("default keys parse"))
+                    .collect();
+                (*action, keys)
+            })
+            .collect();
+        Self { keys }
+    }
+}
+
+impl<A: Action> Serialize for Bindings<A> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let specs: BTreeMap<A, Vec<String>> = self
+            .keys
+            .iter()
+            .map(|(action, keys)| (*action, keys.iter().map(Key::spec).collect()))
+            .collect();
+        specs.serialize(serializer)
+    }
+}
+
+/// One key or a list of them
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeySpecs {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl<'de, A: Action> Deserialize<'de> for Bindings<A> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let given = BTreeMap::<A, KeySpecs>::deserialize(deserializer)?;
+        let mut bindings = Self::default();
+        let mut rebound = BTreeMap::new();
+        for (action, specs) in given {
+            let specs = match specs {
+                KeySpecs::One(spec) => vec![spec],
+                KeySpecs::Many(specs) => specs,
+            };
+            let keys = specs
+                .iter()
+                .map(|spec| {
+                    let key = spec.parse()?;
+                    A::check(&key).map(|()| key)
+                })
+                .collect::<Result<Vec<Key>, String>>()
+                .map_err(D::Error::custom)?;
+            rebound.insert(action, keys);
+        }
+        // A key given to one action is taken from whichever had it by default
+        for (action, keys) in bindings.keys.iter_mut() {
+            if !rebound.contains_key(action) {
+                keys.retain(|key| !rebound.values().flatten().any(|taken| taken == key));
+            }
+        }
+        bindings.keys.extend(rebound);
+        Ok(bindings)
+    }
+}
+
+/// Escapes typed after Enter and the escape key while attached
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Escape {
+    Detach,
+    Switch,
+    LastSession,
+    QuickSwitch,
+    Scrollback,
+    Rename,
+    NewWindow,
+    NextWindow,
+    PreviousWindow,
+    SplitBeside,
+    SplitBelow,
+    NextPane,
+    Broadcast,
+    Suspend,
+    Help,
+}
+
+impl Action for Escape {
+    const DEFAULTS: &'static [(Self, &'static [&'static str])] = &[
+        (Escape::Detach, &["d"]),
+        (Escape::Switch, &["s"]),
+        (Escape::LastSession, &["l"]),
+        (Escape::QuickSwitch, &["tab"]),
+        (Escape::Scrollback, &["h"]),
+        (Escape::Rename, &["r"]),
+        (Escape::NewWindow, &["c"]),
+        (Escape::NextWindow, &["n"]),
+        (Escape::PreviousWindow, &["p"]),
+        (Escape::SplitBeside, &["|"]),
+        (Escape::SplitBelow, &["-"]),
+        (Escape::NextPane, &["o"]),
+        (Escape::Broadcast, &["b"]),
+        (Escape::Suspend, &["z"]),
+        (Escape::Help, &["?"]),
+    ];
+
+    fn check(key: &Key) -> Result<(), String> {
+        escape_byte(key).map(|_| ())
+    }
+}
+
+/// Keys of the picker (`nds`, `nds list -i`) and, where they apply, of the
+/// `~s` switcher
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PickerAction {
+    Up,
+    Down,
+    /// Attach to, or switch to, the selected session
+    Open,
+    Mark,
+    Kill,
+    Rename,
+    New,
+    Filter,
+    Sort,
+    Quit,
+}
+
+impl Action for PickerAction {
+    const DEFAULTS: &'static [(Self, &'static [&'static str])] = &[
+        (PickerAction::Up, &["up", "k"]),
+        (PickerAction::Down, &["down", "j"]),
+        (PickerAction::Open, &["enter"]),
+        (PickerAction::Mark, &["space"]),
+        (PickerAction::Kill, &["x"]),
+        (PickerAction::Rename, &["r"]),
+        (PickerAction::New, &["c"]),
+        (PickerAction::Filter, &["/"]),
+        (PickerAction::Sort, &["s"]),
+        (PickerAction::Quit, &["q", "esc"]),
+    ];
+}
+
+/// Keys of the scrollback viewer (`~h`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ViewerAction {
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Top,
+    Bottom,
+    Search,
+    SearchBackward,
+    NextMatch,
+    PreviousMatch,
+    NextPrompt,
+    PreviousPrompt,
+    CopyMode,
+    Save,
+    Plain,
+    Quit,
+}
+
+impl Action for ViewerAction {
+    const DEFAULTS: &'static [(Self, &'static [&'static str])] = &[
+        (ViewerAction::Up, &["up", "k"]),
+        (ViewerAction::Down, &["down", "j"]),
+        (ViewerAction::PageUp, &["pgup", "b"]),
+        (ViewerAction::PageDown, &["pgdn", "space", "f"]),
+        (ViewerAction::Top, &["home", "g"]),
+        (ViewerAction::Bottom, &["end", "G"]),
+        (ViewerAction::Search, &["/"]),
+        (ViewerAction::SearchBackward, &["?"]),
+        (ViewerAction::NextMatch, &["n"]),
+        (ViewerAction::PreviousMatch, &["N"]),
+        (ViewerAction::NextPrompt, &["]"]),
+        (ViewerAction::PreviousPrompt, &["["]),
+        (ViewerAction::CopyMode, &["v"]),
+        (ViewerAction::Save, &["s"]),
+        (ViewerAction::Plain, &["p"]),
+        (ViewerAction::Quit, &["q", "esc"]),
+    ];
+}
+
+/// Keys of the scrollback viewer's copy mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CopyAction {
+    Left,
+    Down,
+    Up,
+    Right,
+    NextWord,
+    PreviousWord,
+    LineStart,
+    LineEnd,
+    PageUp,
+    PageDown,
+    Top,
+    Bottom,
+    Select,
+    SelectLines,
+    Copy,
+    Leave,
+}
+
+impl Action for CopyAction {
+    const DEFAULTS: &'static [(Self, &'static [&'static str])] = &[
+        (CopyAction::Left, &["left", "h"]),
+        (CopyAction::Down, &["down", "j"]),
+        (CopyAction::Up, &["up", "k"]),
+        (CopyAction::Right, &["right", "l"]),
+        (CopyAction::NextWord, &["w"]),
+        (CopyAction::PreviousWord, &["b"]),
+        (CopyAction::LineStart, &["home", "0"]),
+        (CopyAction::LineEnd, &["end", "$"]),
+        (CopyAction::PageUp, &["pgup"]),
+        (CopyAction::PageDown, &["pgdn"]),
+        (CopyAction::Top, &["g"]),
+        (CopyAction::Bottom, &["G"]),
+        (CopyAction::Select, &["v"]),
+        (CopyAction::SelectLines, &["V"]),
+        (CopyAction::Copy, &["y", "enter"]),
+        (CopyAction::Leave, &["q", "esc"]),
+    ];
+}
+
+/// The byte a key sends, for the escapes, which are read as raw input
+fn escape_byte(key: &Key) -> Result<u8, String> {
+    match key.byte() {
+        Some(b'\r' | b'\n' | 0x1b) | None => Err(format!(
+            "'{}' can't be an escape: use a character, tab or a ctrl- letter",
+            key.spec()
+        )),
+        Some(byte) => Ok(byte),
+    }
+}
+
+/// Every section of the keymap
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyMap {
+    /// Typed at the start of a line to begin an escape; typed twice, it
+    /// sends itself
+    #[serde(with = "key_spec")]
+    pub prefix: Key,
+    pub escape: Bindings<Escape>,
+    pub picker: Bindings<PickerAction>,
+    pub viewer: Bindings<ViewerAction>,
+    pub copy: Bindings<CopyAction>,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        Self {
+            prefix: Key {
+                code: KeyCode::Char('~'),
+                modifiers: KeyModifiers::NONE,
+            },
+            escape: Bindings::default(),
+            picker: Bindings::default(),
+            viewer: Bindings::default(),
+            copy: Bindings::default(),
+        }
+    }
+}
+
+impl KeyMap {
+    /// The keymap from the config file, read once per process
+    pub fn current() -> &'static KeyMap {
+        static KEYMAP: OnceLock<KeyMap> = OnceLock::new();
+        KEYMAP.get_or_init(|| Config::load().unwrap_or_default().keymap)
+    }
+
+    /// The byte that begins an escape
+    pub fn prefix_byte(&self) -> u8 {
+        // Checked when the config was read
+        escape_byte(&self.prefix).unwrap_or(b'~')
+    }
+
+    /// The escape a byte typed after the prefix asks for
+    pub fn escape(&self, byte: u8) -> Option<Escape> {
+        self.escape.find(|key| key.byte() == Some(byte))
+    }
+
+    /// The keys typed for `escape`, as help shows them: `~d`
+    pub fn escape_label(&self, escape: Escape) -> String {
+        let labels: Vec<String> = self
+            .escape
+            .keys(escape)
+            .iter()
+            .map(|key| format!("{}{}", self.prefix, key))
+            .collect();
+        labels.join(" ")
+    }
+}
+
+mod key_spec {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use super::{escape_byte, Key};
+
+    pub fn serialize<S: Serializer>(key: &Key, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&key.spec())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Key, D::Error> {
+        let key: Key = String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)?;
+        escape_byte(&key).map_err(D::Error::custom)?;
+        Ok(key)
+    }
+}
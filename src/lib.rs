@@ -1,22 +1,57 @@
+pub mod compression;
+pub mod config;
 pub mod error;
+pub mod fuzzy;
 pub mod history;
+pub mod history_export;
 pub mod history_v2;
+pub mod hooks;
 pub mod interactive;
+pub mod keymap;
 pub mod manager;
+pub mod metrics;
 pub mod pty;
 pub mod pty_buffer;
 pub mod pty_handler;
+pub mod remote;
+pub mod replay;
+pub mod resolve;
 pub mod scrollback;
+pub mod scrollback_buffer;
+pub mod server;
 pub mod session;
+pub mod session_env;
+pub mod session_log;
+pub mod session_sort;
+pub mod shell_init;
+pub mod stats;
+pub mod status_line;
 pub mod terminal_state;
+pub mod text_input;
+pub mod transport;
+pub mod ui;
 
 #[cfg(test)]
 mod tests;
 
 pub use error::{NdsError, Result};
+// Signals for `SessionManager::signal_session`
+pub use nix::sys::signal::Signal;
 // Use v2 history as the main history module
 pub use history_v2::{HistoryEntry, SessionEvent, SessionHistory};
+pub use hooks::{Hook, Hooks};
 pub use interactive::InteractivePicker;
-pub use manager::{SessionManager, SessionTable};
-pub use pty::PtyProcess;
-pub use session::Session;
+pub use manager::{
+    AttachOutcome, KillReport, Selection, SessionManager, SessionSelector, SessionTable,
+};
+pub use pty::{
+    AttachOptions, ClientSummary, DaemonDump, DaemonEvent, EventSubscription, PtyProcess,
+    RecordingSummary, RedrawStrategy, ResizePolicy, RespawnPolicy, SessionMetrics, ShareRole,
+    SpawnOptions, WindowSummary, DEFAULT_RECORDING_MAX_BYTES,
+};
+pub use resolve::{MatchKind, ResolveOptions, ResolvedSession};
+pub use server::{SessionChange, SessionChanges};
+pub use session::{ExitStatus, ForegroundProcess, Session};
+pub use session_env::SessionEnv;
+pub use session_sort::{ListConfig, SessionSort};
+pub use stats::SessionStats;
@@ -1,5 +1,8 @@
-use clap::{Parser, Subcommand};
-use detached_shell::Result;
+use clap::{CommandFactory, Parser, Subcommand};
+use detached_shell::session_env::parse_assignment;
+use detached_shell::{
+    AttachOptions, RedrawStrategy, ResizePolicy, RespawnPolicy, Result, SessionSort, SpawnOptions,
+};
 
 // Import handler modules
 mod handlers;
@@ -22,6 +25,63 @@ enum Commands {
         /// Don't attach to the new session (default is to attach)
         #[arg(long = "no-attach")]
         no_attach: bool,
+        /// Attach to the session with this name if it already exists instead of creating another
+        #[arg(short = 'A', long = "attach-if-exists", requires = "name")]
+        attach_if_exists: bool,
+        /// How to redraw clients: ctrl-l, mode-replay, vt-snapshot or none (default: vt-snapshot)
+        #[arg(long)]
+        redraw: Option<RedrawStrategy>,
+        /// Initial terminal size as COLSxROWS, for sessions started without a terminal
+        #[arg(long, value_parser = handlers::parse_size)]
+        size: Option<(u16, u16)>,
+        /// Export KEY=VALUE in the session's shell (repeatable)
+        #[arg(short, long = "env", value_parser = parse_assignment)]
+        env: Vec<(String, String)>,
+        /// Export the variables from a dotenv-style file
+        #[arg(long = "env-file")]
+        env_file: Option<std::path::PathBuf>,
+        /// Shell to run (default: NDS_SHELL, default_shell in ~/.nds/config.json, then $SHELL)
+        #[arg(long)]
+        shell: Option<String>,
+        /// Start the shell again when it exits: never, on-failure or always
+        #[arg(long, default_value = "never")]
+        respawn: RespawnPolicy,
+        /// Size the PTY for several clients: smallest, largest, last-attached or COLSxROWS
+        /// (default: resize_policy in ~/.nds/config.json, then smallest)
+        #[arg(long)]
+        resize: Option<ResizePolicy>,
+        /// Ask an attached client before letting another one attach
+        #[arg(long)]
+        approve_attach: bool,
+        /// Also let clients attach over TLS on this address, e.g.
+        /// 127.0.0.1:7077, with the token printed (port 0 picks one)
+        #[arg(long, value_name = "ADDR")]
+        listen: Option<String>,
+        /// Only print the new session's ID, without attaching (for nds server)
+        #[arg(long, hide = true, conflicts_with_all = ["attach_if_exists", "listen"])]
+        print_id: bool,
+    },
+
+    /// Run a command in a new session, streaming its output and exiting with its status
+    Run {
+        /// Optional session name
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Return once the session has started instead of waiting for the command
+        #[arg(short, long)]
+        detach: bool,
+        /// Start the command again when it exits: never, on-failure or always
+        #[arg(long, default_value = "never", requires = "detach")]
+        respawn: RespawnPolicy,
+        /// Command and arguments, after `--`
+        #[arg(required = true, last = true)]
+        command: Vec<String>,
+    },
+
+    /// Wait for a session's shell or command to exit and exit with its status
+    Wait {
+        /// Session ID or name (supports partial matching)
+        id: String,
     },
 
     /// List all active sessions
@@ -30,20 +90,103 @@ enum Commands {
         /// Interactive mode - select session to attach
         #[arg(short, long)]
         interactive: bool,
+        /// Only show sessions with this tag (repeatable)
+        #[arg(short, long = "tag")]
+        tags: Vec<String>,
+        /// Order sessions by: created, name, idle (longest idle first) or
+        /// clients (most first); default from the config's list.sort, which
+        /// `s` in the picker sets
+        #[arg(long)]
+        sort: Option<SessionSort>,
+        /// Turn the order around
+        #[arg(short, long)]
+        reverse: bool,
+        /// Only show sessions with clients attached
+        #[arg(long, conflicts_with = "detached")]
+        attached: bool,
+        /// Only show sessions without clients attached
+        #[arg(long)]
+        detached: bool,
+        /// Also show each session's CPU, memory and process count
+        #[arg(short, long)]
+        stats: bool,
+        /// Keep the list up to date, redrawing it as sessions come, go or
+        /// change, and at least this often (default: 2s)
+        #[arg(
+            short,
+            long,
+            value_name = "INTERVAL",
+            num_args = 0..=1,
+            default_missing_value = "2s",
+            conflicts_with = "interactive",
+            value_parser = handlers::parse_duration
+        )]
+        watch: Option<chrono::Duration>,
+        /// Print `ID<TAB>NAME` lines instead of the table (for completions)
+        #[arg(
+            long,
+            hide = true,
+            value_parser = ["plain"],
+            conflicts_with_all = ["interactive", "stats", "watch"]
+        )]
+        format: Option<String>,
     },
 
+    /// Print a short line for a shell prompt or status bar: the session it
+    /// runs in, how many there are, bells (!) and detached sessions printing (+)
+    StatusLine,
+
     /// Attach to an existing session
     #[command(aliases = &["a", "at"])]
     Attach {
         /// Session ID or name to attach to (supports partial matching)
-        id: String,
+        #[arg(required_unless_present = "socket")]
+        id: Option<String>,
+        /// Attach to a session another user shared with you, through the
+        /// socket `nds share` printed
+        #[arg(long, conflicts_with_all = ["id", "detach_others", "remote"])]
+        socket: Option<std::path::PathBuf>,
+        /// Attach to a session on another machine whose daemon listens on
+        /// HOST:PORT (`nds new --listen`)
+        #[arg(long, value_name = "HOST:PORT", requires_all = ["id", "fingerprint"])]
+        remote: Option<String>,
+        /// The remote session's token (default: NDS_REMOTE_TOKEN)
+        #[arg(long, requires = "remote")]
+        token: Option<String>,
+        /// SHA-256 of the remote daemon's certificate, as `nds new --listen`
+        /// printed it
+        #[arg(long, requires = "remote")]
+        fingerprint: Option<String>,
+        /// Disconnect all other clients attached to the session
+        #[arg(short = 'd', long = "detach-others")]
+        detach_others: bool,
+        /// Connect stdin and stdout straight to the session, for scripts and
+        /// pipes: no escapes, resizing or messages
+        #[arg(long)]
+        raw: bool,
+        /// With --raw, pass the session's protocol through instead of its
+        /// output (used by `nds remote` on the far end)
+        #[arg(long, requires = "raw", hide = true)]
+        framed: bool,
     },
 
     /// Kill one or more sessions
     #[command(aliases = &["k"])]
     Kill {
-        /// Session IDs or names to kill (supports partial matching)
+        /// Session IDs or names to kill (supports partial matching and globs like 'build-*')
         ids: Vec<String>,
+        /// Kill all active sessions
+        #[arg(long, conflicts_with = "ids")]
+        all: bool,
+        /// Only kill sessions with no attached clients
+        #[arg(long)]
+        detached: bool,
+        /// Only kill sessions with this tag (repeatable)
+        #[arg(short, long = "tag")]
+        tags: Vec<String>,
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        force: bool,
     },
 
     /// Show information about a specific session
@@ -53,20 +196,265 @@ enum Commands {
         id: String,
     },
 
+    /// Show a session's output log, written while `logging` is on in
+    /// config.json
+    Logs {
+        /// Session ID or name (supports partial matching); the full ID of a
+        /// session that has gone
+        id: String,
+        /// Keep printing what the session logs until it ends
+        #[arg(short, long)]
+        follow: bool,
+        /// How many lines from the end to start with
+        #[arg(short = 'n', long, default_value = "20")]
+        lines: usize,
+    },
+
     /// Rename a session
     #[command(aliases = &["rn"])]
     Rename {
         /// Session ID or name to rename (supports partial matching)
         id: String,
-        /// New name for the session
-        new_name: String,
+        /// New name for the session; prompts with the current name when omitted
+        new_name: Option<String>,
+    },
+
+    /// Detach all clients from a session (the current one when run inside a session)
+    Detach {
+        /// Session ID or name (supports partial matching); defaults to $NDS_SESSION_ID
+        id: Option<String>,
+        /// Detach only this client (use 'clients list' to see IDs)
+        #[arg(long, requires = "id")]
+        client: Option<String>,
+    },
+
+    /// Show or change how a session's PTY is sized for its clients
+    Resize {
+        /// Session ID or name (supports partial matching)
+        id: String,
+        /// Pin the PTY at this many columns (with --rows)
+        #[arg(long, requires = "rows", value_parser = clap::value_parser!(u16).range(1..))]
+        cols: Option<u16>,
+        /// Pin the PTY at this many rows (with --cols)
+        #[arg(long, requires = "cols", value_parser = clap::value_parser!(u16).range(1..))]
+        rows: Option<u16>,
+        /// Size for several clients: smallest, largest, last-attached or COLSxROWS
+        #[arg(long, conflicts_with = "cols")]
+        policy: Option<ResizePolicy>,
+    },
+
+    /// Show or change whether a client attaching to a session that already
+    /// has clients waits for one of them to let it in
+    ApproveAttach {
+        /// Session ID or name (supports partial matching)
+        id: String,
+        /// on or off
+        #[arg(value_parser = clap::builder::BoolishValueParser::new())]
+        enabled: Option<bool>,
+    },
+
+    /// Kill and re-exec a session's shell or command, keeping the session and its clients
+    Restart {
+        /// Session ID or name (supports partial matching)
+        id: String,
+    },
+
+    /// Send a signal to whatever is running in the foreground of a session,
+    /// e.g. `nds signal build INT` to interrupt a stuck build without attaching
+    Signal {
+        /// Session ID or name (supports partial matching)
+        id: String,
+        /// Signal name (INT, SIGTERM, hup...) or number
+        #[arg(value_parser = handlers::parse_signal)]
+        signal: detached_shell::Signal,
+    },
+
+    /// Share a session with another user on this machine, or stop sharing
+    /// it; with neither --with nor --remove, show who it is shared with
+    Share {
+        /// Session ID or name (supports partial matching)
+        id: String,
+        /// User to share the session with, by name or uid
+        #[arg(long, value_name = "USER", value_parser = handlers::parse_user)]
+        with: Option<String>,
+        /// Let them watch the session but not type into it
+        #[arg(long, requires = "with")]
+        read_only: bool,
+        /// Stop sharing the session with this user, disconnecting them
+        #[arg(long, value_name = "USER", conflicts_with = "with")]
+        remove: Option<String>,
+    },
+
+    /// Link sessions, so input typed into one with broadcasting on (Enter
+    /// ~b) is typed into the others too; with no others, show its links
+    Link {
+        /// Session ID or name (supports partial matching)
+        id: String,
+        /// Sessions to link it to
+        others: Vec<String>,
+    },
+
+    /// Unlink a session from others, or from every session it is linked to
+    Unlink {
+        /// Session ID or name (supports partial matching)
+        id: String,
+        /// Sessions to unlink it from (default: all of them)
+        others: Vec<String>,
+    },
+
+    /// Show a message on the bottom row of a session's attached clients for
+    /// a few seconds, like tmux's display-message
+    Message {
+        /// Session ID or name (supports partial matching)
+        id: String,
+        /// Text to show
+        #[arg(required = true)]
+        text: Vec<String>,
+        /// How long to show it (e.g. 10s, 1m; default 3s)
+        #[arg(short, long, value_parser = handlers::parse_duration)]
+        duration: Option<chrono::Duration>,
+    },
+
+    /// Print shell integration that marks prompts, so the scrollback viewer
+    /// can jump between commands; add `eval "$(nds shell-init bash)"` to
+    /// your shell's rc file
+    ShellInit {
+        /// Shell to print the integration for
+        #[arg(value_parser = detached_shell::shell_init::SHELLS)]
+        shell: String,
+    },
+
+    /// Print a completion script for the shell, which completes session IDs
+    /// and names too: eval "$(nds completions bash)"
+    Completions {
+        /// Shell to print completions for
+        #[arg(value_parser = handlers::completions::SHELLS)]
+        shell: String,
+    },
+
+    /// Save the text on a session's screen, or all of its scrollback, like
+    /// tmux's capture-pane
+    Capture {
+        /// Session ID or name (supports partial matching)
+        id: String,
+        /// Capture all of the scrollback the session's daemon keeps, with
+        /// its escape sequences, rather than the screen's text
+        #[arg(long)]
+        scrollback: bool,
+        /// File to write to instead of stdout
+        #[arg(short, long)]
+        output: Option<std::path::PathBuf>,
+        /// Leave out colors and other escape sequences
+        #[arg(long, requires = "scrollback")]
+        strip_ansi: bool,
+    },
+
+    /// Record a session's output to an asciicast v2 file, for `asciinema
+    /// play` or sharing; recording goes on while nobody is attached
+    Record {
+        /// Session ID or name (supports partial matching)
+        id: String,
+        /// File to record to (default: <session>-<time>.cast here)
+        #[arg(short, long, conflicts_with = "stop")]
+        output: Option<std::path::PathBuf>,
+        /// Carry on in a new numbered file (name.1.cast, ...) once one
+        /// reaches this size, e.g. 50M; 0 never does (default: 100M)
+        #[arg(long, value_parser = handlers::parse_bytes, conflicts_with = "stop")]
+        max_size: Option<u64>,
+        /// Stop recording
+        #[arg(long)]
+        stop: bool,
+    },
+
+    /// Play back a recording from `nds record`, `asciinema rec` or `script`,
+    /// with pausing, seeking and speed control
+    Replay {
+        /// asciicast v2 file, or typescript
+        file: std::path::PathBuf,
+        /// Timing file for a typescript, from `script -t` or `script -T`
+        #[arg(short, long)]
+        timing: Option<std::path::PathBuf>,
+        /// Playback speed, e.g. 2 for twice as fast (`+` and `-` change it)
+        #[arg(short, long, default_value_t = 1.0)]
+        speed: f64,
+        /// Cut pauses longer than this many seconds down to it
+        #[arg(short, long)]
+        idle_limit: Option<f64>,
+    },
+
+    /// Type the text last copied in a session's scrollback viewer into the
+    /// session, like tmux's paste-buffer
+    Paste {
+        /// Session ID or name (supports partial matching)
+        id: String,
+    },
+
+    /// Notify when a session prints something, goes quiet or rings the
+    /// bell, like tmux's monitor-activity, monitor-silence and monitor-bell
+    Watch {
+        /// Session ID or name (supports partial matching)
+        id: String,
+        /// What to watch for: activity, silence or bell
+        #[arg(long = "for", default_value = "activity")]
+        watch_for: handlers::WatchFor,
+        /// Seconds without output that count as silence (default 30), or
+        /// that re-arm an activity watch with --repeat (default 10)
+        #[arg(long)]
+        seconds: Option<u64>,
+        /// Run this shell command instead of showing a desktop notification;
+        /// it gets NDS_SESSION_ID, NDS_SESSION_NAME and NDS_WATCH_EVENT
+        #[arg(long)]
+        exec: Option<String>,
+        /// Keep watching after notifying, instead of stopping
+        #[arg(long)]
+        repeat: bool,
+    },
+
+    /// Add, remove or show tags on a session
+    #[command(aliases = &["t"])]
+    Tag {
+        /// Session ID or name (supports partial matching)
+        id: String,
+        /// Tags to add (or remove with --remove); shows the tags when omitted
+        tags: Vec<String>,
+        /// Remove the given tags instead of adding them
+        #[arg(short, long)]
+        remove: bool,
+    },
+
+    /// Show or set a note describing what a session is for
+    Note {
+        /// Session ID or name (supports partial matching)
+        id: String,
+        /// Note text; shows the current note when omitted, "" clears it
+        text: Option<String>,
     },
 
     /// Clean up dead sessions
     Clean,
 
+    /// Remove dead sessions and kill sessions idle for too long
+    Prune {
+        /// Kill sessions with no input or output for longer than this (e.g. 30m, 12h, 7d)
+        #[arg(long, value_parser = handlers::parse_duration)]
+        idle: Option<chrono::Duration>,
+        /// Kill sessions no client has typed into for longer than this, even
+        /// if they still print output
+        #[arg(long = "no-input", value_parser = handlers::parse_duration)]
+        no_input: Option<chrono::Duration>,
+        /// Only prune sessions with no attached clients
+        #[arg(long)]
+        detached: bool,
+        /// Show what would be pruned without killing anything
+        #[arg(long = "dry-run")]
+        dry_run: bool,
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        force: bool,
+    },
+
     /// Show session history
-    #[command(aliases = &["h", "hist"])]
+    #[command(aliases = &["h", "hist"], args_conflicts_with_subcommands = true)]
     History {
         /// Show history for a specific session ID or name (supports partial matching)
         #[arg(short, long)]
@@ -79,6 +467,17 @@ enum Commands {
         /// Limit number of entries to show
         #[arg(short, long, default_value = "50")]
         limit: usize,
+
+        /// Only show events of this kind
+        #[arg(short, long, value_parser = detached_shell::history_v2::EVENT_KINDS)]
+        event: Option<String>,
+
+        /// Only show events from this long ago on, e.g. 30m, 12h or 7d
+        #[arg(long, value_parser = handlers::parse_duration)]
+        since: Option<chrono::Duration>,
+
+        #[command(subcommand)]
+        command: Option<HistoryCommands>,
     },
 
     /// Manage clients connected to sessions
@@ -87,6 +486,198 @@ enum Commands {
         #[command(subcommand)]
         command: ClientCommands,
     },
+
+    /// List, open or close windows: other shells inside a session
+    #[command(alias = "windows")]
+    Window {
+        #[command(subcommand)]
+        command: WindowCommands,
+    },
+
+    /// Put sessions in groups, attached to with `nds attach @group`
+    #[command(alias = "groups")]
+    Group {
+        #[command(subcommand)]
+        command: GroupCommands,
+    },
+
+    /// Set TERM, COLORTERM, locale or other variables for the session's shell
+    /// when it is restarted or respawns; attaching sets the terminal's own
+    Setenv {
+        /// Session ID or name (supports partial matching)
+        session: String,
+        /// Variables as KEY=VALUE (an empty value unsets one); this terminal's
+        /// TERM, COLORTERM and locale if none are given
+        assignments: Vec<String>,
+    },
+
+    /// Check that sessions work in this environment (PTYs, sockets, permissions)
+    Selftest {
+        /// Keep the temporary NDS_HOME for inspection
+        #[arg(long)]
+        keep: bool,
+    },
+
+    /// Show or update a session's environment
+    #[command(args_conflicts_with_subcommands = true)]
+    Env {
+        /// Session ID or name (supports partial matching)
+        session: Option<String>,
+        /// Only show the live environment reported by the shell hook
+        #[arg(long)]
+        live: bool,
+        #[command(subcommand)]
+        command: Option<EnvCommands>,
+    },
+
+    /// Print counters for every session (or one) in the Prometheus text format
+    Metrics {
+        /// Session ID or name (supports partial matching); all sessions if omitted
+        session: Option<String>,
+        /// Write to this file for node_exporter's textfile collector instead of stdout
+        #[arg(long)]
+        textfile: Option<std::path::PathBuf>,
+        /// Keep rewriting the textfile this often, e.g. 15s or 1m
+        #[arg(long, requires = "textfile", value_parser = handlers::parse_duration)]
+        interval: Option<chrono::Duration>,
+    },
+
+    /// Inspect a session's daemon when something goes wrong
+    Debug {
+        #[command(subcommand)]
+        command: DebugCommands,
+    },
+
+    /// Work with sessions on another machine over ssh (nds must be on its PATH)
+    Remote {
+        /// Where to ssh to, as you would give it to ssh (user@host or an alias)
+        host: String,
+        #[command(subcommand)]
+        command: RemoteCommands,
+    },
+
+    /// Run or control the optional server that keeps track of every session
+    Server {
+        #[command(subcommand)]
+        command: ServerCommands,
+    },
+
+    /// Call the server's JSON-RPC API and print the result as JSON
+    Rpc {
+        /// list, info, create, kill, rename, send-input, capture, subscribe-events or status
+        method: String,
+        /// Params as one JSON object, or as KEY=VALUE pairs (values that parse as JSON are
+        /// taken as JSON, the rest as strings)
+        params: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum RemoteCommands {
+    /// List the sessions on the remote host
+    #[command(aliases = &["ls"])]
+    List,
+
+    /// Attach to a session on the remote host
+    #[command(aliases = &["a"])]
+    Attach {
+        /// Session ID or name there (supports partial matching)
+        id: String,
+        /// Disconnect all other clients attached to the session
+        #[arg(short = 'd', long = "detach-others")]
+        detach_others: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ServerCommands {
+    /// Start the server in the background
+    Start,
+
+    /// Run the server in the foreground, e.g. under systemd
+    Run,
+
+    /// Stop the server; sessions keep running
+    Stop,
+
+    /// Show whether the server is running
+    Status,
+}
+
+#[derive(Subcommand)]
+enum HistoryCommands {
+    /// Summarize how sessions were used: attaches, durations, crashes and busiest directories
+    Stats {
+        /// Only count what happened this long ago or later, e.g. 12h, 7d or 4w
+        #[arg(long, value_parser = handlers::parse_duration)]
+        since: Option<chrono::Duration>,
+        /// How many of the busiest working directories to show
+        #[arg(long, default_value = "5")]
+        top: usize,
+    },
+
+    /// Remove the history of sessions that ended long ago, as the `history` config says
+    Clean {
+        /// Keep the history of sessions that ended this many days ago or less
+        #[arg(long, value_parser = clap::value_parser!(i64).range(0..))]
+        keep_days: Option<i64>,
+        /// Keep the history of at most this many ended sessions
+        #[arg(long)]
+        max_archived: Option<usize>,
+        /// Only report what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Write history out as JSON lines or CSV, oldest first, for archiving or analysis
+    Export {
+        /// json (one object per line) or csv
+        #[arg(short, long, default_value = "json")]
+        format: detached_shell::history_export::HistoryFormat,
+        /// Only export what happened this long ago or later, e.g. 12h, 7d or 4w
+        #[arg(long, value_parser = handlers::parse_duration)]
+        since: Option<chrono::Duration>,
+        /// Write to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<std::path::PathBuf>,
+    },
+
+    /// Add history exported by `nds history export`, here or on another machine
+    Import {
+        /// File to read, or - for stdin
+        file: std::path::PathBuf,
+        /// json or csv (default: from the file's extension, else json)
+        #[arg(short, long)]
+        format: Option<detached_shell::history_export::HistoryFormat>,
+    },
+}
+
+#[derive(Subcommand)]
+enum EnvCommands {
+    /// Queue variables to be exported at the session's next prompt
+    Set {
+        /// Session ID or name (supports partial matching)
+        session: String,
+        /// Variables to export, as KEY=VALUE
+        #[arg(required = true)]
+        assignments: Vec<String>,
+    },
+
+    /// Print the shell hook that reports the live environment and applies queued exports
+    Hook {
+        /// Shell to generate the hook for (bash or zsh)
+        #[arg(default_value = "bash")]
+        shell: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum DebugCommands {
+    /// Print the daemon's clients, queues, buffers, terminal modes and last PTY error as JSON
+    Dump {
+        /// Session ID or name (supports partial matching)
+        session: String,
+    },
 }
 
 #[derive(Subcommand)]
@@ -108,37 +699,361 @@ enum ClientCommands {
     },
 }
 
+#[derive(Subcommand)]
+enum WindowCommands {
+    /// List the windows of a session
+    #[command(aliases = &["ls", "l"])]
+    List {
+        /// Session ID or name (supports partial matching)
+        session: String,
+    },
+
+    /// Open a window running a shell, or the command after --
+    #[command(alias = "n")]
+    New {
+        /// Session ID or name (supports partial matching)
+        session: String,
+        /// Name to show for the window
+        #[arg(long)]
+        name: Option<String>,
+        /// Command to run instead of the session's shell
+        #[arg(last = true)]
+        command: Vec<String>,
+    },
+
+    /// Close a window, hanging up its shell
+    #[command(aliases = &["k", "close"])]
+    Kill {
+        /// Session ID or name (supports partial matching)
+        session: String,
+        /// Window number (use 'window list' to see them)
+        window: u32,
+    },
+}
+
+#[derive(Subcommand)]
+enum GroupCommands {
+    /// List the groups and the sessions in them
+    #[command(aliases = &["ls", "l"])]
+    List,
+
+    /// Put sessions in a group, taking them out of any other
+    #[command(alias = "a")]
+    Add {
+        /// Group name
+        group: String,
+        /// Session IDs or names (supports partial matching)
+        #[arg(required = true)]
+        sessions: Vec<String>,
+    },
+
+    /// Take sessions out of their group
+    #[command(aliases = &["rm", "r"])]
+    Remove {
+        /// Session IDs or names (supports partial matching)
+        #[arg(required = true)]
+        sessions: Vec<String>,
+    },
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
+    // Shell prompts and completion run these over and over, so they stay
+    // clear of the history database
+    let runs_often = matches!(
+        cli.command,
+        Some(Commands::StatusLine)
+            | Some(Commands::List {
+                format: Some(_),
+                ..
+            })
+    );
+    if !runs_often {
+        handlers::clean_history_if_due();
+    }
+
     match cli.command {
-        Some(Commands::New { name, no_attach }) => {
-            handlers::handle_new_session(name, !no_attach)?;
+        Some(Commands::New {
+            name,
+            no_attach,
+            attach_if_exists,
+            redraw,
+            size,
+            env,
+            env_file,
+            shell,
+            respawn,
+            resize,
+            approve_attach,
+            listen,
+            print_id,
+        }) => {
+            let env = handlers::collect_env(&env, env_file.as_deref())?;
+            let remote = handlers::prepare_listen(listen.as_deref())?;
+            let options = SpawnOptions {
+                redraw,
+                size,
+                env,
+                client_env: Default::default(),
+                shell,
+                command: None,
+                respawn,
+                resize,
+                approve_attach,
+                remote,
+            };
+            if print_id {
+                let code = handlers::handle_new_session_id(name, options);
+                std::process::exit(code);
+            }
+            handlers::handle_new_session(name, !no_attach, attach_if_exists, options)?;
         }
-        Some(Commands::List { interactive }) => {
-            handlers::handle_list_sessions(interactive)?;
+        Some(Commands::Run {
+            name,
+            detach,
+            respawn,
+            command,
+        }) => {
+            let code = handlers::handle_run(name, command, detach, respawn)?;
+            std::process::exit(code);
+        }
+        Some(Commands::Wait { id }) => {
+            let code = handlers::handle_wait(&id)?;
+            std::process::exit(code);
         }
-        Some(Commands::Attach { id }) => {
-            handlers::handle_attach_session(&id)?;
+        Some(Commands::List {
+            interactive,
+            tags,
+            sort,
+            reverse,
+            attached,
+            detached,
+            stats,
+            watch,
+            format,
+        }) => {
+            // Some(true) for --attached, Some(false) for --detached
+            let attached = (attached || detached).then_some(attached);
+            let format = match (format, stats) {
+                (Some(_), _) => handlers::ListFormat::Plain,
+                (None, true) => handlers::ListFormat::Stats,
+                (None, false) => handlers::ListFormat::Table,
+            };
+            handlers::handle_list_sessions(
+                interactive,
+                &tags,
+                sort,
+                reverse,
+                attached,
+                format,
+                watch,
+            )?;
         }
-        Some(Commands::Kill { ids }) => {
-            handlers::handle_kill_sessions(&ids)?;
+        Some(Commands::StatusLine) => {
+            handlers::handle_status_line()?;
+        }
+        Some(Commands::Attach {
+            id,
+            socket,
+            remote,
+            token,
+            fingerprint,
+            detach_others,
+            raw,
+            framed,
+        }) => {
+            let options = AttachOptions {
+                detach_others,
+                raw,
+                framed,
+                group: None,
+            };
+            match (id, socket, remote) {
+                (_, Some(socket), _) => handlers::handle_attach_shared(&socket, options)?,
+                (Some(id), None, Some(remote)) => handlers::handle_attach_remote(
+                    &remote,
+                    &id,
+                    token,
+                    fingerprint.as_deref().unwrap_or_default(),
+                    options,
+                )?,
+                (Some(id), None, None) => handlers::handle_attach_session(&id, options)?,
+                (None, None, _) => unreachable!("clap requires an ID or --socket"),
+            }
+        }
+        Some(Commands::Kill {
+            ids,
+            all,
+            detached,
+            tags,
+            force,
+        }) => {
+            handlers::handle_kill_sessions(&ids, all, detached, &tags, force)?;
         }
         Some(Commands::Info { id }) => {
             handlers::handle_session_info(&id)?;
         }
+        Some(Commands::Logs { id, follow, lines }) => {
+            handlers::handle_logs(&id, lines, follow)?;
+        }
         Some(Commands::Rename { id, new_name }) => {
-            handlers::handle_rename_session(&id, &new_name)?;
+            handlers::handle_rename_session(&id, new_name.as_deref())?;
+        }
+        Some(Commands::Detach { id, client }) => {
+            handlers::handle_detach_session(id.as_deref(), client.as_deref())?;
+        }
+        Some(Commands::Resize {
+            id,
+            cols,
+            rows,
+            policy,
+        }) => {
+            let fixed = cols
+                .zip(rows)
+                .map(|(cols, rows)| ResizePolicy::Fixed { cols, rows });
+            handlers::handle_resize_session(&id, policy.or(fixed))?;
+        }
+        Some(Commands::Restart { id }) => {
+            handlers::handle_restart_session(&id)?;
+        }
+        Some(Commands::Signal { id, signal }) => {
+            handlers::handle_signal_session(&id, signal)?;
+        }
+        Some(Commands::ApproveAttach { id, enabled }) => {
+            handlers::handle_approve_attach(&id, enabled)?;
+        }
+        Some(Commands::Share {
+            id,
+            with,
+            read_only,
+            remove,
+        }) => {
+            handlers::handle_share_session(&id, with.as_deref(), read_only, remove.as_deref())?;
+        }
+        Some(Commands::Link { id, others }) => {
+            handlers::handle_link_sessions(&id, &others)?;
+        }
+        Some(Commands::Unlink { id, others }) => {
+            handlers::handle_unlink_sessions(&id, &others)?;
+        }
+        Some(Commands::Message { id, text, duration }) => {
+            handlers::handle_message_session(&id, &text.join(" "), duration)?;
+        }
+        Some(Commands::ShellInit { shell }) => {
+            handlers::handle_shell_init(&shell)?;
+        }
+        Some(Commands::Completions { shell }) => {
+            handlers::handle_completions(&shell, Cli::command())?;
+        }
+        Some(Commands::Capture {
+            id,
+            scrollback,
+            output,
+            strip_ansi,
+        }) => {
+            handlers::handle_capture_session(&id, scrollback, output.as_deref(), strip_ansi)?;
+        }
+        Some(Commands::Record {
+            id,
+            output,
+            max_size,
+            stop,
+        }) => {
+            handlers::handle_record_session(&id, output.as_deref(), max_size, stop)?;
+        }
+        Some(Commands::Replay {
+            file,
+            timing,
+            speed,
+            idle_limit,
+        }) => {
+            handlers::handle_replay(&file, timing.as_deref(), speed, idle_limit)?;
+        }
+        Some(Commands::Paste { id }) => {
+            handlers::handle_paste_session(&id)?;
+        }
+        Some(Commands::Watch {
+            id,
+            watch_for,
+            seconds,
+            exec,
+            repeat,
+        }) => {
+            handlers::handle_watch_session(&id, watch_for, seconds, exec.as_deref(), repeat)?;
+        }
+        Some(Commands::Note { id, text }) => {
+            handlers::handle_note_session(&id, text.as_deref())?;
+        }
+        Some(Commands::Tag { id, tags, remove }) => {
+            handlers::handle_tag_session(&id, &tags, remove)?;
         }
         Some(Commands::Clean) => {
             handlers::handle_clean_sessions()?;
         }
+        Some(Commands::Prune {
+            idle,
+            no_input,
+            detached,
+            dry_run,
+            force,
+        }) => {
+            handlers::handle_prune_sessions(idle, no_input, detached, dry_run, force)?;
+        }
         Some(Commands::History {
             session,
             all,
             limit,
-        }) => {
-            handlers::handle_session_history(session, all, limit)?;
+            event,
+            since,
+            command,
+        }) => match command {
+            Some(HistoryCommands::Stats { since, top }) => {
+                handlers::handle_history_stats(since, top)?;
+            }
+            Some(HistoryCommands::Clean {
+                keep_days,
+                max_archived,
+                dry_run,
+            }) => {
+                handlers::handle_history_clean(keep_days, max_archived, dry_run)?;
+            }
+            Some(HistoryCommands::Export {
+                format,
+                since,
+                output,
+            }) => {
+                handlers::handle_history_export(format, since, output.as_deref())?;
+            }
+            Some(HistoryCommands::Import { file, format }) => {
+                handlers::handle_history_import(&file, format)?;
+            }
+            None => handlers::handle_session_history(session, all, limit, event, since)?,
+        },
+        Some(Commands::Remote { host, command }) => match command {
+            RemoteCommands::List => {
+                let code = handlers::handle_ssh_list(&host)?;
+                std::process::exit(code);
+            }
+            RemoteCommands::Attach { id, detach_others } => {
+                let options = AttachOptions {
+                    detach_others,
+                    ..AttachOptions::default()
+                };
+                handlers::handle_ssh_attach(&host, &id, options)?;
+            }
+        },
+        Some(Commands::Server { command }) => match command {
+            ServerCommands::Start => handlers::handle_server_start()?,
+            ServerCommands::Run => handlers::handle_server_run()?,
+            ServerCommands::Stop => handlers::handle_server_stop()?,
+            ServerCommands::Status => {
+                let code = handlers::handle_server_status()?;
+                std::process::exit(code);
+            }
+        },
+        Some(Commands::Rpc { method, params }) => {
+            handlers::handle_rpc(&method, &params)?;
         }
         Some(Commands::Clients { command }) => match command {
             ClientCommands::List { session } => {
@@ -148,9 +1063,89 @@ fn main() -> Result<()> {
                 handlers::handle_disconnect_client(&session, &client_id)?;
             }
         },
+        Some(Commands::Group { command }) => match command {
+            GroupCommands::List => {
+                handlers::handle_list_groups()?;
+            }
+            GroupCommands::Add { group, sessions } => {
+                handlers::handle_group_add(&group, &sessions)?;
+            }
+            GroupCommands::Remove { sessions } => {
+                handlers::handle_group_remove(&sessions)?;
+            }
+        },
+        Some(Commands::Window { command }) => match command {
+            WindowCommands::List { session } => {
+                handlers::handle_list_windows(&session)?;
+            }
+            WindowCommands::New {
+                session,
+                name,
+                command,
+            } => {
+                handlers::handle_new_window(&session, name.as_deref(), &command)?;
+            }
+            WindowCommands::Kill { session, window } => {
+                handlers::handle_kill_window(&session, window)?;
+            }
+        },
+        Some(Commands::Setenv {
+            session,
+            assignments,
+        }) => {
+            handlers::handle_setenv(&session, &assignments)?;
+        }
+        Some(Commands::Selftest { keep }) => {
+            handlers::handle_selftest(keep)?;
+        }
+        Some(Commands::Env {
+            session,
+            live,
+            command,
+        }) => match (command, session) {
+            (
+                Some(EnvCommands::Set {
+                    session,
+                    assignments,
+                }),
+                _,
+            ) => {
+                handlers::handle_set_env(&session, &assignments)?;
+            }
+            (Some(EnvCommands::Hook { shell }), _) => {
+                handlers::handle_env_hook(&shell)?;
+            }
+            (None, Some(session)) => {
+                handlers::handle_show_env(&session, live)?;
+            }
+            (None, None) => {
+                eprintln!("Usage: nds env <SESSION> | nds env set <SESSION> KEY=VALUE... | nds env hook [SHELL]");
+                std::process::exit(2);
+            }
+        },
+        Some(Commands::Metrics {
+            session,
+            textfile,
+            interval,
+        }) => {
+            handlers::handle_metrics(session.as_deref(), textfile.as_deref(), interval)?;
+        }
+        Some(Commands::Debug { command }) => match command {
+            DebugCommands::Dump { session } => {
+                handlers::handle_debug_dump(&session)?;
+            }
+        },
         None => {
             // Default action: interactive session picker
-            handlers::handle_list_sessions(true)?;
+            handlers::handle_list_sessions(
+                true,
+                &[],
+                None,
+                false,
+                None,
+                handlers::ListFormat::Table,
+                None,
+            )?;
         }
     }
 
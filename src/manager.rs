@@ -1,10 +1,24 @@
 use chrono::{DateTime, Local, Timelike, Utc};
+use nix::sys::signal::Signal;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use crate::config::{is_executable, validate_shell, Config};
 use crate::error::{NdsError, Result};
 use crate::history_v2::SessionHistory;
-use crate::pty::PtyProcess;
-use crate::session::Session;
+use crate::hooks::{self, Hook};
+use crate::pty::{
+    AttachOptions, ClientSummary, DaemonDump, EventSubscription, PtyProcess, RecordingSummary,
+    ResizePolicy, SessionMetrics, ShareRole, SpawnOptions, WindowSummary,
+};
+use crate::remote::ssh::SshTunnel;
+use crate::remote::Tunnel;
+use crate::resolve::{self, ResolveOptions, ResolvedSession};
+use crate::server::{ServerClient, SessionChanges};
+use crate::session::{ExitStatus, ForegroundProcess, Session};
+use crate::stats::{self, format_bytes, SessionStats};
 
 pub struct SessionManager;
 
@@ -14,20 +28,56 @@ impl SessionManager {
     }
 
     pub fn create_session_with_name(name: Option<String>) -> Result<Session> {
+        Self::create_session_with_options(name, SpawnOptions::default())
+    }
+
+    pub fn create_session_with_options(
+        name: Option<String>,
+        mut options: SpawnOptions,
+    ) -> Result<Session> {
+        if let Some(ref name) = name {
+            Self::validate_session_name(name, None)?;
+        }
+
+        // Settle on the shell up front so a typo fails here, not in the daemon
+        let config = Config::load()?;
+        let shell = config.resolve_shell(options.shell.as_deref());
+        validate_shell(&shell)?;
+        options.shell = Some(shell);
+        options.resize = options.resize.or(config.resize_policy);
+
+        if let Some(program) = options.command.as_ref().and_then(|c| c.first()) {
+            if !is_executable(program) {
+                return Err(NdsError::CommandNotFound(program.clone()));
+            }
+        }
+
         // Generate session ID
         let session_id = uuid::Uuid::new_v4().to_string()[..8].to_string();
 
         // Spawn new PTY process with optional name
-        let session = PtyProcess::spawn_new_detached_with_name(&session_id, name)?;
+        let session = PtyProcess::spawn_new_detached_with_options(&session_id, name, &options)?;
 
         // Record session creation in history
         let _ = SessionHistory::record_session_created(&session);
+        hooks::run(Hook::Create, &session);
 
         Ok(session)
     }
 
-    pub fn attach_session(session_id: &str) -> Result<()> {
+    pub fn attach_session(session_id: &str) -> Result<AttachOutcome> {
+        Self::attach_session_with_options(session_id, AttachOptions::default())
+    }
+
+    /// Attach the terminal to the session until the user detaches, following
+    /// switches to other sessions. A session whose daemon is gone is cleaned
+    /// up instead, which the outcome says.
+    pub fn attach_session_with_options(
+        session_id: &str,
+        options: AttachOptions,
+    ) -> Result<AttachOutcome> {
         let mut current_session_id = session_id.to_string();
+        let mut options = options;
 
         loop {
             // Load session metadata
@@ -35,47 +85,23 @@ impl SessionManager {
 
             // Validate session is still alive before attempting to attach
             if !Self::validate_session_health(&session) {
-                eprintln!("Session {} appears to be dead.", session.id);
-                eprintln!("The process (PID {}) is no longer running.", session.pid);
-                eprintln!("");
-                eprintln!("Would you like to:");
-                eprintln!("  1. Clean up the dead session");
-                eprintln!("  2. Try to attach anyway (will likely fail)");
-                eprintln!("");
-
-                // For now, attempt cleanup and return error
-                eprintln!("Cleaning up dead session...");
-                let _ = Session::cleanup(&session.id);
-                let _ = SessionHistory::record_session_crashed(&session);
-
-                return Err(NdsError::SessionNotFound(format!(
-                    "Session {} was dead and has been cleaned up. Create a new session with 'nds new'.",
-                    session.id
-                )));
-            }
-
-            if session.attached {
-                // Session appears to be attached, but allow override
-                // This handles cases where terminal closed without proper detach
-                eprintln!(
-                    "Warning: Session {} appears to be already attached.",
-                    current_session_id
-                );
-                eprintln!("Attempting to attach anyway (previous connection may have been lost).");
-
-                // Force mark as detached first to clean up stale state
-                session.attached = false;
-                session.save()?;
+                Self::clean_up_dead(&session);
+                return Ok(AttachOutcome::Dead {
+                    session_id: session.id,
+                    pid: session.pid,
+                });
             }
 
-            // Mark as attached
+            // Marked attached or not, the terminal may have closed without
+            // detaching; attach anyway
             session.mark_attached()?;
 
             // Record attach event in history
             let _ = SessionHistory::record_session_attached(&session);
+            hooks::run(Hook::Attach, &session);
 
             // Attach to the session with better error handling
-            let switch_to = match PtyProcess::attach_to_session(&session) {
+            let switch_to = match PtyProcess::attach_to_session_with_options(&session, &options) {
                 Ok(result) => result,
                 Err(e) => {
                     // If we get a broken pipe or connection refused, the session is dead
@@ -83,21 +109,12 @@ impl SessionManager {
                         io_err.kind() == std::io::ErrorKind::BrokenPipe ||
                         io_err.kind() == std::io::ErrorKind::ConnectionRefused)
                     {
-                        eprintln!(
-                            "\nSession {} is dead (broken pipe/connection refused).",
-                            session.id
-                        );
-                        eprintln!("Cleaning up dead session...");
-
-                        // Mark as detached and cleanup
                         let _ = session.mark_detached();
-                        let _ = Session::cleanup(&session.id);
-                        let _ = SessionHistory::record_session_crashed(&session);
-
-                        return Err(NdsError::SessionNotFound(format!(
-                            "Session {} was dead and has been cleaned up. Create a new session with 'nds new'.",
-                            session.id
-                        )));
+                        Self::clean_up_dead(&session);
+                        return Ok(AttachOutcome::Dead {
+                            session_id: session.id,
+                            pid: session.pid,
+                        });
                     }
                     return Err(e);
                 }
@@ -108,23 +125,164 @@ impl SessionManager {
 
             // Record detach event in history
             let _ = SessionHistory::record_session_detached(&session);
+            hooks::run(Hook::Detach, &session);
 
             // If switching to another session, continue the loop
             if let Some(new_session_id) = switch_to {
                 // Update current session ID and continue
                 current_session_id = new_session_id;
+                // Exclusive attach only applies to the session originally
+                // requested; a group keeps being cycled through
+                options = AttachOptions {
+                    group: options.group.take(),
+                    ..AttachOptions::default()
+                };
             } else {
-                // Normal detach
-                return Ok(());
+                return Ok(AttachOutcome::Detached {
+                    session_id: session.id,
+                });
             }
         }
     }
 
+    /// Remove what a session whose daemon died left behind, recording the
+    /// crash
+    fn clean_up_dead(session: &Session) {
+        let _ = Session::cleanup(&session.id);
+        let _ = SessionHistory::record_session_crashed(session);
+        hooks::run(Hook::Crash, session);
+    }
+
+    /// Attach to a session another user shared (`nds share`) through its
+    /// shared socket. Its metadata belongs to them, so none is read or
+    /// updated; switching sessions from there goes to one of our own.
+    pub fn attach_shared(socket_path: &Path, options: AttachOptions) -> Result<AttachOutcome> {
+        let id = socket_path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let switch_to = Self::attach_through(&id, socket_path, &options)?;
+        Self::attach_switched(id, switch_to)
+    }
+
+    /// Attach to a session on another machine whose daemon listens on
+    /// `address` (`nds new --listen`), checking its certificate against
+    /// `fingerprint`. As with a shared session, nothing local is read or
+    /// updated, and switching sessions goes to one of our own.
+    pub fn attach_remote(
+        address: &str,
+        session: &str,
+        token: &str,
+        fingerprint: &str,
+        options: AttachOptions,
+    ) -> Result<AttachOutcome> {
+        let tunnel = Tunnel::open(address, session, token, fingerprint)?;
+        let switch_to = Self::attach_through(&tunnel.session_id, &tunnel.socket_path, &options)?;
+        let session_id = tunnel.session_id.clone();
+        drop(tunnel);
+        Self::attach_switched(session_id, switch_to)
+    }
+
+    /// Attach to a session on `host` through ssh (`nds remote HOST attach`),
+    /// as `attach_remote` does through a daemon's listener
+    pub fn attach_ssh(host: &str, session: &str, options: AttachOptions) -> Result<AttachOutcome> {
+        let tunnel = SshTunnel::open(host, session)?;
+        let switch_to = Self::attach_through(&tunnel.session_id, &tunnel.socket_path, &options)?;
+        let session_id = tunnel.session_id.clone();
+        drop(tunnel);
+        Self::attach_switched(session_id, switch_to)
+    }
+
+    /// Attach to the session behind a tunnel's socket; returns the local
+    /// session the user switched to, if any
+    fn attach_through(
+        session_id: &str,
+        socket_path: &Path,
+        options: &AttachOptions,
+    ) -> Result<Option<String>> {
+        let session = Session::new(session_id.to_string(), 0, socket_path.to_path_buf());
+        PtyProcess::attach_to_session_with_options(&session, options)
+    }
+
+    /// Follow a switch out of `session_id`, a session that isn't ours, to
+    /// one that is
+    fn attach_switched(session_id: String, switch_to: Option<String>) -> Result<AttachOutcome> {
+        match switch_to {
+            Some(switch_to) => {
+                Self::attach_session_with_options(&switch_to, AttachOptions::default())
+            }
+            None => Ok(AttachOutcome::Detached { session_id }),
+        }
+    }
+
+    /// The live sessions, oldest first. Asks `nds server` if it is running,
+    /// which saves checking every session's socket.
     pub fn list_sessions() -> Result<Vec<Session>> {
+        if let Some(mut server) = ServerClient::connect() {
+            if let Ok(sessions) = server.list() {
+                return Ok(sessions);
+            }
+        }
         Session::list_all()
     }
 
+    /// The live session `query` means: an index, an ID or the start of one,
+    /// or a name or the start of one, matched as config.json's `matching`
+    /// says. `AmbiguousSession` lists every session it could mean.
+    pub fn resolve(query: &str) -> Result<ResolvedSession> {
+        let options = Config::load().unwrap_or_default().matching;
+        Self::resolve_with(query, &options)
+    }
+
+    /// `resolve` with `options` instead of the configured ones
+    pub fn resolve_with(query: &str, options: &ResolveOptions) -> Result<ResolvedSession> {
+        resolve::resolve(query, &Self::list_sessions()?, options)
+    }
+
+    /// Check that a name is usable and not taken by another live session.
+    ///
+    /// Names are matched case-insensitively elsewhere, so duplicates are too.
+    /// `exclude_id` lets a session keep (or re-case) its own name on rename.
+    pub fn validate_session_name(name: &str, exclude_id: Option<&str>) -> Result<()> {
+        Session::validate_name(name)?;
+
+        let taken = Self::list_sessions()?.into_iter().find(|s| {
+            Some(s.id.as_str()) != exclude_id
+                && s.name
+                    .as_deref()
+                    .is_some_and(|existing| Self::same_name(existing, name))
+        });
+
+        match taken {
+            Some(other) => Err(NdsError::InvalidSessionName(format!(
+                "'{}' is already used by session {}",
+                name, other.id
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Find the live session with this name, if any, by the same rule as
+    /// `validate_session_name` so a name it refuses is one found here
+    pub fn find_session_by_name(name: &str) -> Result<Option<Session>> {
+        Ok(Self::list_sessions()?.into_iter().find(|s| {
+            s.name
+                .as_deref()
+                .is_some_and(|existing| Self::same_name(existing, name))
+        }))
+    }
+
+    /// Whether two session names are the same one, ignoring case
+    fn same_name(a: &str, b: &str) -> bool {
+        a.eq_ignore_ascii_case(b)
+    }
+
     pub fn kill_session(session_id: &str) -> Result<()> {
+        // The server kills sessions one at a time and tells subscribers
+        if let Some(mut server) = ServerClient::connect() {
+            return server.kill(session_id);
+        }
+
         // Load session for history recording
         if let Ok(session) = Session::load(session_id) {
             // Record kill event in history
@@ -134,49 +292,393 @@ impl SessionManager {
         PtyProcess::kill_session(session_id)
     }
 
+    /// Kill and re-exec the session's shell or command, keeping its ID,
+    /// socket and attached clients
+    pub fn restart_session(session_id: &str) -> Result<()> {
+        let session = Session::load(session_id)?;
+        PtyProcess::restart_shell(&session)
+    }
+
+    /// Change how the session's PTY is sized for its clients. Returns the
+    /// PTY's new size, if the policy changed it.
+    pub fn set_resize_policy(session_id: &str, policy: ResizePolicy) -> Result<Option<(u16, u16)>> {
+        let session = Session::load(session_id)?;
+        PtyProcess::set_resize_policy(&session, policy)
+    }
+
+    /// Turn on or off asking an attached client before letting another attach.
+    /// Applies from the next client to attach.
+    pub fn set_approve_attach(session_id: &str, enabled: bool) -> Result<()> {
+        let session = Session::load(session_id)?;
+        Session::set_approve_attach(&session.id, enabled)
+    }
+
+    /// Share the session with `user` in `role`, or stop sharing it with them
+    /// (disconnecting them) with `None`. Returns the socket the users it is
+    /// shared with connect to, or `None` if it is no longer shared.
+    pub fn share_session(
+        session_id: &str,
+        user: &str,
+        role: Option<ShareRole>,
+    ) -> Result<Option<PathBuf>> {
+        let session = Session::load(session_id)?;
+        Session::set_share(&session.id, user, role)?;
+        PtyProcess::share(&session)
+    }
+
+    /// Link the sessions to one another, so input typed into any of them
+    /// with broadcasting on (`~b`) is typed into the others too
+    pub fn link_sessions(session_ids: &[&str]) -> Result<()> {
+        let sessions = session_ids
+            .iter()
+            .map(|id| Session::load(id))
+            .collect::<Result<Vec<_>>>()?;
+        for session in &sessions {
+            for other in sessions.iter().filter(|other| other.id != session.id) {
+                Session::set_link(&session.id, &other.id, true)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Unlink the session from `others`, or from every session it is linked
+    /// to if that is empty, both ways. Returns the sessions it was linked to.
+    pub fn unlink_sessions(session_id: &str, others: &[&str]) -> Result<Vec<String>> {
+        let session = Session::load(session_id)?;
+        let unlinked: Vec<String> = if others.is_empty() {
+            session.links.clone()
+        } else {
+            others
+                .iter()
+                .filter(|other| session.links.iter().any(|link| link == *other))
+                .map(|other| other.to_string())
+                .collect()
+        };
+        for other in &unlinked {
+            Session::set_link(&session.id, other, false)?;
+            // The other side may be gone already
+            if Session::load(other).is_ok() {
+                Session::set_link(other, &session.id, false)?;
+            }
+        }
+        Ok(unlinked)
+    }
+
+    /// The live sessions linked to this one
+    pub fn linked_sessions(session_id: &str) -> Result<Vec<Session>> {
+        let session = Session::load(session_id)?;
+        Ok(session
+            .links
+            .iter()
+            .filter_map(|id| Session::load(id).ok())
+            .collect())
+    }
+
+    /// Set variables the session's shell gets if it is restarted or
+    /// respawns; an empty value unsets one
+    pub fn set_session_client_env(session_id: &str, vars: &BTreeMap<String, String>) -> Result<()> {
+        let session = Session::load(session_id)?;
+        PtyProcess::set_client_env(&session, vars)
+    }
+
+    /// Detach every client attached to the session, leaving it running
+    pub fn detach_session(session_id: &str) -> Result<()> {
+        let session = Session::load(session_id)?;
+        PtyProcess::detach_clients(&session)
+    }
+
+    /// Clients currently attached to the session, as reported by its daemon
+    pub fn session_clients(session_id: &str) -> Result<Vec<ClientSummary>> {
+        Session::load(session_id)?.clients()
+    }
+
+    /// What is running in the foreground of the session's terminal, as
+    /// reported by its daemon
+    pub fn session_foreground(session_id: &str) -> Result<Option<ForegroundProcess>> {
+        Session::load(session_id)?.foreground_process()
+    }
+
+    /// Disconnect one client from the session. Returns whether it was attached.
+    pub fn disconnect_client(session_id: &str, client_id: &str) -> Result<bool> {
+        let session = Session::load(session_id)?;
+        PtyProcess::disconnect_client(&session, client_id)
+    }
+
+    /// The session's windows, window 0 (its own shell) first
+    pub fn list_windows(session_id: &str) -> Result<Vec<WindowSummary>> {
+        let session = Session::load(session_id)?;
+        PtyProcess::list_windows(&session)
+    }
+
+    /// Open a window in the session running `command`, or a shell if it is
+    /// empty. Returns the window's number.
+    pub fn new_window(session_id: &str, name: Option<&str>, command: &[String]) -> Result<u32> {
+        let session = Session::load(session_id)?;
+        PtyProcess::open_window(&session, name, command)
+    }
+
+    /// Close one of the session's windows, hanging up its shell. Returns
+    /// whether there was such a window; window 0 is the session itself and
+    /// is never closed this way.
+    pub fn kill_window(session_id: &str, window: u32) -> Result<bool> {
+        if window == 0 {
+            return Ok(false);
+        }
+        let session = Session::load(session_id)?;
+        PtyProcess::close_window(&session, window)
+    }
+
+    /// Send a signal to whatever is in the foreground of the session's
+    /// terminal, not its daemon. Returns the process group signalled.
+    pub fn signal_session(session_id: &str, signal: Signal) -> Result<i32> {
+        let session = Session::load(session_id)?;
+        PtyProcess::signal_foreground_process(&session, signal)
+    }
+
+    /// Show a message on the bottom row of the session's attached clients for
+    /// `duration` (a few seconds if `None`), like tmux's display-message.
+    /// Returns how many clients were attached to see it.
+    pub fn show_message(session_id: &str, text: &str, duration: Option<Duration>) -> Result<usize> {
+        let session = Session::load(session_id)?;
+        PtyProcess::show_message(&session, text, duration)
+    }
+
+    /// Write the session's scrollback to `out`, without escape sequences if
+    /// `strip_ansi`; returns how many lines were written
+    pub fn capture_scrollback(
+        session_id: &str,
+        out: &mut dyn std::io::Write,
+        strip_ansi: bool,
+    ) -> Result<u64> {
+        let session = Session::load(session_id)?;
+        PtyProcess::capture_scrollback(&session, out, strip_ansi)
+    }
+
+    /// The text on the session's screen, a row at a time
+    pub fn capture_screen(session_id: &str) -> Result<Vec<String>> {
+        let session = Session::load(session_id)?;
+        PtyProcess::capture_screen(&session)
+    }
+
+    /// A snapshot of the session daemon's internal state, for debugging
+    pub fn debug_dump(session_id: &str) -> Result<DaemonDump> {
+        let session = Session::load(session_id)?;
+        PtyProcess::debug_dump(&session)
+    }
+
+    /// The session daemon's counters and gauges, for `nds metrics`
+    pub fn metrics(session_id: &str) -> Result<SessionMetrics> {
+        let session = Session::load(session_id)?;
+        PtyProcess::metrics(&session)
+    }
+
+    /// Type the session's paste buffer into its shell; `None` if nothing
+    /// has been copied in the scrollback viewer yet
+    pub fn paste(session_id: &str) -> Result<Option<usize>> {
+        let session = Session::load(session_id)?;
+        PtyProcess::paste(&session)
+    }
+
+    /// Type `text` into the session's shell as if from the keyboard; `None`
+    /// if the shell has exited
+    pub fn send_input(session_id: &str, text: &str) -> Result<Option<usize>> {
+        let session = Session::load(session_id)?;
+        PtyProcess::send_input(&session, text.as_bytes())
+    }
+
+    /// Record the session's output to `path` (absolute) as asciicast,
+    /// rotating to a new file every `max_bytes` (0 for never)
+    pub fn start_recording(session_id: &str, path: &std::path::Path, max_bytes: u64) -> Result<()> {
+        let session = Session::load(session_id)?;
+        PtyProcess::start_recording(&session, path, max_bytes)
+    }
+
+    /// Stop recording the session; `None` if it wasn't being recorded
+    pub fn stop_recording(session_id: &str) -> Result<Option<RecordingSummary>> {
+        let session = Session::load(session_id)?;
+        PtyProcess::stop_recording(&session)
+    }
+
+    /// Hear about `events` (e.g. `output`) in the session as they happen,
+    /// until its shell or command exits
+    pub fn subscribe(session_id: &str, events: &[&str]) -> Result<EventSubscription> {
+        let session = Session::load(session_id)?;
+        PtyProcess::subscribe(&session, events)
+    }
+
+    /// Sessions being created, attached, detached, changed, killed and
+    /// exiting, from now on, across all sessions. Unlike `subscribe`, this
+    /// needs no daemon to connect to: changes come from `nds server` if it
+    /// runs, and from watching the sessions directory if not.
+    pub fn subscribe_sessions() -> Result<SessionChanges> {
+        SessionChanges::start()
+    }
+
+    /// Block until the session's shell or command exits
+    pub fn wait_session(session_id: &str) -> Result<ExitStatus> {
+        let session = Session::load(session_id)?;
+        PtyProcess::wait_for_exit(&session)
+    }
+
     pub fn get_session(session_id: &str) -> Result<Session> {
         Session::load(session_id)
     }
 
     pub fn rename_session(session_id: &str, new_name: &str) -> Result<()> {
-        let mut session = Session::load(session_id)?;
-        let old_name = session.name.clone();
+        Session::load(session_id)?;
 
-        session.name = if new_name.trim().is_empty() {
-            None
-        } else {
-            Some(new_name.to_string())
-        };
+        if !new_name.trim().is_empty() {
+            Self::validate_session_name(new_name, Some(session_id))?;
+        }
+
+        let name = Some(new_name.to_string()).filter(|name| !name.trim().is_empty());
+        let (session, old_name) = Session::update(session_id, |session| {
+            let old_name = std::mem::replace(&mut session.name, name);
+            (session.clone(), old_name)
+        })?;
 
         // Record rename event in history
         if let Some(ref name) = session.name {
             let _ = SessionHistory::record_session_renamed(&session, old_name, name.clone());
         }
+        Ok(())
+    }
 
-        session.save()
+    /// Set the session's note; empty text clears it
+    pub fn set_note(session_id: &str, note: &str) -> Result<()> {
+        Session::load(session_id)?;
+        let note = Some(note.trim().to_string()).filter(|note| !note.is_empty());
+        Session::update(session_id, |session| session.note = note)
     }
 
-    pub fn cleanup_dead_sessions() -> Result<()> {
-        let sessions = Session::list_all()?;
-        let mut cleaned = 0;
+    /// Put a session in `group`, or take it out of the one it is in with
+    /// `None`
+    pub fn set_group(session_id: &str, group: Option<&str>) -> Result<()> {
+        if let Some(group) = group {
+            Session::validate_group(group)?;
+        }
+        Session::load(session_id)?;
+        Session::update(session_id, |session| {
+            session.group = group.map(str::to_string)
+        })
+    }
 
-        for session in sessions {
+    /// The live sessions in `group`, in index order
+    pub fn group_sessions(group: &str) -> Result<Vec<Session>> {
+        Ok(Self::list_sessions()?
+            .into_iter()
+            .filter(|session| session.group.as_deref() == Some(group))
+            .collect())
+    }
+
+    /// The session after `session_id` in `group`, or before it unless
+    /// `forward`, going round; the group's first if the session isn't in it
+    pub fn step_in_group(group: &str, session_id: &str, forward: bool) -> Result<Option<Session>> {
+        let mut sessions = Self::group_sessions(group)?;
+        let Some(at) = sessions.iter().position(|session| session.id == session_id) else {
+            return Ok((!sessions.is_empty()).then(|| sessions.remove(0)));
+        };
+        let len = sessions.len();
+        let next = if forward {
+            (at + 1) % len
+        } else {
+            (at + len - 1) % len
+        };
+        Ok(Some(sessions.swap_remove(next)))
+    }
+
+    /// Add and remove tags on a session, returning the resulting tag list
+    pub fn tag_session(session_id: &str, add: &[String], remove: &[String]) -> Result<Vec<String>> {
+        for tag in add {
+            Session::validate_tag(tag)?;
+        }
+
+        Session::load(session_id)?;
+        Session::update(session_id, |session| {
+            session.tags.retain(|tag| !remove.contains(tag));
+            for tag in add {
+                if !session.tags.contains(tag) {
+                    session.tags.push(tag.clone());
+                }
+            }
+            session.tags.sort();
+            session.tags.clone()
+        })
+    }
+
+    /// Remove the leftovers of sessions whose daemon is gone, recording each
+    /// as crashed; returns the sessions cleaned up
+    pub fn cleanup_dead_sessions() -> Result<Vec<Session>> {
+        let mut cleaned = Vec::new();
+        for session in Session::list_all()? {
             if !Self::validate_session_health(&session) {
                 // Record crash event in history before cleanup
                 let _ = SessionHistory::record_session_crashed(&session);
+                hooks::run(Hook::Crash, &session);
                 Session::cleanup(&session.id)?;
-                cleaned += 1;
-                println!("Cleaned up dead session: {}", session.display_name());
+                cleaned.push(session);
             }
         }
+        Ok(cleaned)
+    }
+
+    /// The sessions `selector` picks, and the arguments that picked none
+    pub fn select_sessions(selector: &SessionSelector) -> Result<Selection> {
+        let sessions = Self::list_sessions()?;
+        let options = Config::load().unwrap_or_default().matching;
+        let mut selection = Selection::default();
 
-        if cleaned > 0 {
-            println!("Cleaned up {} dead session(s)", cleaned);
+        if selector.all || selector.queries.is_empty() {
+            selection.sessions = sessions.clone();
         } else {
-            println!("No dead sessions found");
+            for query in &selector.queries {
+                match select_targets(query, &sessions, &options) {
+                    Ok(matched) => {
+                        for session in matched {
+                            if !selection.sessions.iter().any(|s| s.id == session.id) {
+                                selection.sessions.push(session);
+                            }
+                        }
+                    }
+                    Err(e) => selection.unmatched.push((query.clone(), e)),
+                }
+            }
         }
 
-        Ok(())
+        if selector.detached_only {
+            selection.sessions.retain(|s| s.get_client_count() == 0);
+        }
+        selection
+            .sessions
+            .retain(|s| s.matches_tags(&selector.tags));
+        Ok(selection)
+    }
+
+    /// Kill each of `sessions`, carrying on past the ones that fail
+    pub fn kill_sessions(sessions: &[Session]) -> KillReport {
+        let mut report = KillReport::default();
+        for session in sessions {
+            match Self::kill_session(&session.id) {
+                Ok(()) => report.killed.push(session.clone()),
+                Err(e) => report.failed.push((session.id.clone(), e)),
+            }
+        }
+        report
+    }
+
+    /// The live sessions `select_prune_targets` picks
+    pub fn idle_sessions(
+        idle: Option<chrono::Duration>,
+        no_input: Option<chrono::Duration>,
+        detached_only: bool,
+    ) -> Result<Vec<Session>> {
+        let sessions = Self::list_sessions()?;
+        Ok(
+            select_prune_targets(&sessions, idle, no_input, detached_only)
+                .into_iter()
+                .cloned()
+                .collect(),
+        )
     }
 
     /// Validate that a session is healthy and can be attached to
@@ -186,38 +688,174 @@ impl SessionManager {
             return false;
         }
 
-        // Check if the socket file exists
-        if !session.socket_path.exists() {
-            return false;
+        // Try to connect to the socket, file or abstract, to verify it's
+        // responsive
+        session.is_socket_healthy()
+    }
+}
+
+/// The sessions one argument refers to.
+///
+/// Glob patterns (`*`, `?`) may select several sessions by name or ID; plain
+/// arguments go through `resolve` and must mean one session.
+fn select_targets(
+    session_id_or_name: &str,
+    sessions: &[Session],
+    options: &ResolveOptions,
+) -> Result<Vec<Session>> {
+    if is_glob_pattern(session_id_or_name) {
+        let matching_sessions: Vec<_> = sessions
+            .iter()
+            .filter(|s| {
+                glob_match(session_id_or_name, &s.id)
+                    || s.name
+                        .as_ref()
+                        .is_some_and(|name| glob_match(session_id_or_name, name))
+            })
+            .cloned()
+            .collect();
+
+        if matching_sessions.is_empty() {
+            return Err(NdsError::SessionNotFound(format!(
+                "No session found matching pattern: {}",
+                session_id_or_name
+            )));
         }
+        return Ok(matching_sessions);
+    }
 
-        // Try to connect to the socket to verify it's responsive
-        // We use a very short timeout to avoid hanging
-        use std::os::unix::net::UnixStream;
-        use std::time::Duration;
+    Ok(vec![
+        resolve::resolve(session_id_or_name, sessions, options)?.session,
+    ])
+}
 
-        match UnixStream::connect(&session.socket_path) {
-            Ok(socket) => {
-                // Set a short timeout for the test
-                let _ = socket.set_read_timeout(Some(Duration::from_millis(100)));
-                let _ = socket.set_write_timeout(Some(Duration::from_millis(100)));
+/// Returns true if the argument contains shell-style glob wildcards
+pub fn is_glob_pattern(pattern: &str) -> bool {
+    pattern.contains('*') || pattern.contains('?')
+}
 
-                // Socket is connectable, session is likely healthy
-                drop(socket);
-                true
-            }
-            Err(_) => {
-                // Can't connect to socket, session is likely dead
-                false
-            }
+/// Minimal shell-style glob matching supporting `*` and `?`
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<usize> = None;
+    let mut star_text = 0;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some(p);
+            star_text = t;
+            p += 1;
+        } else if let Some(star_pos) = star {
+            // Backtrack: let the last star absorb one more character
+            p = star_pos + 1;
+            star_text += 1;
+            t = star_text;
+        } else {
+            return false;
         }
     }
+
+    while p < pattern.len() && pattern[p] == '*' {
+        p += 1;
+    }
+
+    p == pattern.len()
+}
+
+/// Sessions that have been idle for longer than `idle` and gone without
+/// client input for longer than `no_input` (either limit may be left out),
+/// optionally only those without attached clients
+pub fn select_prune_targets(
+    sessions: &[Session],
+    idle: Option<chrono::Duration>,
+    no_input: Option<chrono::Duration>,
+    detached_only: bool,
+) -> Vec<&Session> {
+    sessions
+        .iter()
+        .filter(|s| idle.is_none_or(|idle| s.idle_duration() > idle))
+        .filter(|s| no_input.is_none_or(|limit| s.input_idle_duration() > limit))
+        .filter(|s| !detached_only || s.get_client_count() == 0)
+        .collect()
+}
+
+/// A duration in its largest whole unit, e.g. `3d` or `12m`
+fn short_duration(duration: chrono::Duration) -> String {
+    if duration.num_days() > 0 {
+        format!("{}d", duration.num_days())
+    } else if duration.num_hours() > 0 {
+        format!("{}h", duration.num_hours())
+    } else if duration.num_minutes() > 0 {
+        format!("{}m", duration.num_minutes())
+    } else {
+        format!("{}s", duration.num_seconds())
+    }
+}
+
+/// How an attach ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttachOutcome {
+    /// The user detached from `session_id`, the last session attached to
+    /// after any switches, or its connection ended
+    Detached { session_id: String },
+    /// The daemon of `session_id` was gone, so what it left behind was
+    /// cleaned up instead
+    Dead { session_id: String, pid: i32 },
+}
+
+/// Which sessions a command like `nds kill` acts on
+#[derive(Debug, Clone, Default)]
+pub struct SessionSelector {
+    /// IDs, ID prefixes, names, indexes or glob patterns; each has to pick
+    /// a session, and every session is picked if there are none
+    pub queries: Vec<String>,
+    /// Every session, whatever the queries
+    pub all: bool,
+    /// Only sessions without attached clients
+    pub detached_only: bool,
+    /// Only sessions with all of these tags
+    pub tags: Vec<String>,
+}
+
+/// The sessions a `SessionSelector` picked
+#[derive(Debug, Default)]
+pub struct Selection {
+    pub sessions: Vec<Session>,
+    /// Queries that picked no session, or more than one without a glob
+    pub unmatched: Vec<(String, NdsError)>,
+}
+
+impl Selection {
+    /// Whether acting on these sessions deserves asking first: there is
+    /// more than one, or someone is attached
+    pub fn needs_confirmation(&self) -> bool {
+        self.sessions.len() > 1 || self.sessions.iter().any(|s| s.get_client_count() > 0)
+    }
+}
+
+/// What killing several sessions did
+#[derive(Debug, Default)]
+pub struct KillReport {
+    pub killed: Vec<Session>,
+    /// Session IDs that couldn't be killed, and why
+    pub failed: Vec<(String, NdsError)>,
 }
 
+/// Widest the foreground command is shown in session listings
+pub const FOREGROUND_WIDTH: usize = 20;
+
 // Helper for pretty-printing sessions
 pub struct SessionDisplay<'a> {
     pub session: &'a Session,
     pub is_current: bool,
+    /// Resource use, shown as extra columns when present
+    pub stats: Option<SessionStats>,
 }
 
 impl<'a> SessionDisplay<'a> {
@@ -225,6 +863,7 @@ impl<'a> SessionDisplay<'a> {
         SessionDisplay {
             session,
             is_current: false,
+            stats: None,
         }
     }
 
@@ -232,22 +871,21 @@ impl<'a> SessionDisplay<'a> {
         SessionDisplay {
             session,
             is_current,
+            stats: None,
         }
     }
 
+    pub fn with_stats(mut self, stats: Option<SessionStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
     fn format_duration(&self) -> String {
-        let now = Utc::now();
-        let duration = now - self.session.created_at;
+        short_duration(Utc::now() - self.session.created_at)
+    }
 
-        if duration.num_days() > 0 {
-            format!("{}d", duration.num_days())
-        } else if duration.num_hours() > 0 {
-            format!("{}h", duration.num_hours())
-        } else if duration.num_minutes() > 0 {
-            format!("{}m", duration.num_minutes())
-        } else {
-            format!("{}s", duration.num_seconds())
-        }
+    fn format_idle(&self) -> String {
+        short_duration(self.session.idle_duration())
     }
 
     fn format_time(&self) -> String {
@@ -280,18 +918,32 @@ impl<'a> fmt::Display for SessionDisplay<'a> {
                 "CURRENT"
             } else if client_count > 0 {
                 "attached"
+            } else if self.session.bell.is_some() {
+                "detached, bell"
             } else {
                 "detached"
             };
 
             write!(
                 f,
-                "{} [{}] - PID {} - {}",
+                "{}: {} [{}] - PID {} - {} - idle {}",
+                self.session.index_label(),
                 self.session.display_name(),
                 &self.session.id[..8],
                 self.session.pid,
-                status
-            )
+                status,
+                self.format_idle()
+            )?;
+            if let Some(running) = self.session.foreground_label(FOREGROUND_WIDTH) {
+                write!(f, " - running {}", running)?;
+            }
+            if let Some(stats) = self.stats {
+                write!(f, " - {}", stats)?;
+            }
+            if !self.session.tags.is_empty() {
+                write!(f, " {}", self.session.tags_label())?;
+            }
+            Ok(())
         } else {
             // Full formatted output for normal terminal
             // Get client count
@@ -316,6 +968,8 @@ impl<'a> fmt::Display for SessionDisplay<'a> {
                         if client_count == 1 { "" } else { "s" }
                     ),
                 )
+            } else if self.session.bell.is_some() {
+                ("!", "detached · bell".to_string())
             } else {
                 ("○", "detached".to_string())
             };
@@ -330,18 +984,40 @@ impl<'a> fmt::Display for SessionDisplay<'a> {
                 );
             }
 
+            let running = self
+                .session
+                .foreground_label(FOREGROUND_WIDTH)
+                .unwrap_or_default();
+
             // Format with sleek layout including all info
             write!(
                 f,
-                " {} {:<25} │ PID {:<6} │ {:<8} │ {:<8} │ {:<30} │ {}",
+                " {} {:>2} {:<25} │ PID {:<6} │ {:<8} │ {:<8} │ idle {:<4} │ {:<30} │ {:<width$} │ {}",
                 icon,
+                self.session.index_label(),
                 self.session.display_name(),
                 self.session.pid,
                 self.format_duration(),
                 self.format_time(),
+                self.format_idle(),
                 working_dir,
-                status_text
-            )
+                running,
+                status_text,
+                width = FOREGROUND_WIDTH
+            )?;
+            if let Some(stats) = self.stats {
+                write!(
+                    f,
+                    " │ {:>5.1}% CPU {:>7} {:>3} proc",
+                    stats.cpu_percent,
+                    format_bytes(stats.rss_bytes),
+                    stats.processes
+                )?;
+            }
+            if !self.session.tags.is_empty() {
+                write!(f, " │ {}", self.session.tags_label())?;
+            }
+            Ok(())
         }
     }
 }
@@ -349,6 +1025,8 @@ impl<'a> fmt::Display for SessionDisplay<'a> {
 pub struct SessionTable {
     sessions: Vec<Session>,
     current_session_id: Option<String>,
+    /// Resource use per daemon PID, when asked for
+    stats: Option<HashMap<i32, SessionStats>>,
 }
 
 impl SessionTable {
@@ -358,9 +1036,21 @@ impl SessionTable {
         SessionTable {
             sessions,
             current_session_id,
+            stats: None,
         }
     }
 
+    /// Measure and show each session's CPU, memory and process count
+    pub fn with_stats(mut self) -> Self {
+        let pids: Vec<i32> = self.sessions.iter().map(|s| s.pid).collect();
+        self.stats = Some(stats::collect(&pids));
+        self
+    }
+
+    fn stats_of(&self, session: &Session) -> Option<SessionStats> {
+        self.stats.as_ref()?.get(&session.pid).copied()
+    }
+
     pub fn print(&self) {
         if self.sessions.is_empty() {
             println!("No active sessions");
@@ -393,16 +1083,37 @@ impl SessionTable {
                 "[CURRENT]"
             } else if client_count > 0 {
                 &format!("[{} clients]", client_count)
+            } else if session.bell.is_some() {
+                "[detached, bell]"
             } else {
                 "[detached]"
             };
 
+            let tags = if session.tags.is_empty() {
+                String::new()
+            } else {
+                format!(" {}", session.tags_label())
+            };
+            let running = session
+                .foreground_label(FOREGROUND_WIDTH)
+                .map(|label| format!(" running {}", label))
+                .unwrap_or_default();
+            let stats = self
+                .stats_of(session)
+                .map(|stats| format!(" ({})", stats))
+                .unwrap_or_default();
+
             println!(
-                "  {} {} - PID {} {}",
+                "  {:>2}: {} {} - PID {} {} idle {}{}{}{}",
+                session.index_label(),
                 session.display_name(),
                 &session.id[..8],
                 session.pid,
-                status
+                status,
+                short_duration(session.idle_duration()),
+                running,
+                stats,
+                tags
             );
         }
 
@@ -417,7 +1128,11 @@ impl SessionTable {
         // Print sessions with full formatting
         for session in &self.sessions {
             let is_current = self.current_session_id.as_ref() == Some(&session.id);
-            println!("{}", SessionDisplay::with_current(session, is_current));
+            println!(
+                "{}",
+                SessionDisplay::with_current(session, is_current)
+                    .with_stats(self.stats_of(session))
+            );
         }
 
         // Footer
@@ -0,0 +1,284 @@
+use chrono::{DateTime, Utc};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use crate::error::Result;
+use crate::pty::SessionMetrics;
+use crate::session::Session;
+
+/// A session and what its daemon reported to `nds metrics`, or `None` if the
+/// daemon didn't answer
+#[derive(Debug, Clone)]
+pub struct SessionSample {
+    pub session: Session,
+    pub metrics: Option<SessionMetrics>,
+}
+
+/// Per-session series taken from what the daemon reports: name, type, help
+/// and value
+type Series = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&SessionMetrics) -> u64,
+);
+
+const SERIES: &[Series] = &[
+    (
+        "nds_session_uptime_seconds",
+        "gauge",
+        "Seconds since the session's daemon started",
+        |m| m.uptime_seconds,
+    ),
+    (
+        "nds_session_shell_running",
+        "gauge",
+        "Whether the session's shell or command is running",
+        |m| m.shell_running as u64,
+    ),
+    (
+        "nds_session_clients",
+        "gauge",
+        "Clients attached to the session",
+        |m| m.clients as u64,
+    ),
+    (
+        "nds_session_client_connects_total",
+        "counter",
+        "Clients that have attached to the session",
+        |m| m.client_connects,
+    ),
+    (
+        "nds_session_pty_read_bytes_total",
+        "counter",
+        "Output read from the session's PTY",
+        |m| m.pty_bytes_read,
+    ),
+    (
+        "nds_session_pty_written_bytes_total",
+        "counter",
+        "Input written to the session's PTY",
+        |m| m.pty_bytes_written,
+    ),
+    (
+        "nds_session_pty_errors_total",
+        "counter",
+        "Failed reads from the session's PTY",
+        |m| m.pty_errors,
+    ),
+    (
+        "nds_session_pty_recovery_attempts_total",
+        "counter",
+        "Attempts to recover the session's PTY after errors",
+        |m| m.recovery_attempts,
+    ),
+    (
+        "nds_session_output_buffer_bytes",
+        "gauge",
+        "Output kept for the next client to attach",
+        |m| m.output_buffer_bytes,
+    ),
+    (
+        "nds_session_scrollback_bytes",
+        "gauge",
+        "Output kept for the scrollback viewer",
+        |m| m.scrollback_bytes as u64,
+    ),
+    (
+        "nds_session_pending_input_bytes",
+        "gauge",
+        "Input waiting for room in the PTY",
+        |m| m.pending_input_bytes as u64,
+    ),
+    (
+        "nds_session_client_queued_bytes",
+        "gauge",
+        "Output queued for clients that haven't read it yet",
+        |m| m.client_queued_bytes as u64,
+    ),
+    (
+        "nds_session_input_flagged_clients",
+        "gauge",
+        "Clients sending more input than NDS_INPUT_RATE_LIMIT allows",
+        |m| m.input_flagged_clients as u64,
+    ),
+];
+
+/// Per-session timestamps from the session's file, there even if the daemon
+/// is stuck: name, help and value
+type Activity = (
+    &'static str,
+    &'static str,
+    fn(&Session) -> Option<DateTime<Utc>>,
+);
+
+const ACTIVITY: &[Activity] = &[
+    (
+        "nds_session_last_output_timestamp_seconds",
+        "When the session last wrote output",
+        |s| s.last_output,
+    ),
+    (
+        "nds_session_last_input_timestamp_seconds",
+        "When input was last sent to the session",
+        |s| s.last_input,
+    ),
+];
+
+/// `samples` in the Prometheus text exposition format
+pub fn render(samples: &[SessionSample]) -> String {
+    let mut out = String::new();
+    let up = samples.iter().filter(|s| s.metrics.is_some()).count();
+    let clients: usize = samples
+        .iter()
+        .filter_map(|s| s.metrics.as_ref())
+        .map(|m| m.clients)
+        .sum();
+    header(
+        &mut out,
+        "nds_sessions",
+        "gauge",
+        "Sessions nds knows about",
+    );
+    let _ = writeln!(out, "nds_sessions {}", samples.len());
+    header(
+        &mut out,
+        "nds_sessions_up",
+        "gauge",
+        "Sessions whose daemon answered",
+    );
+    let _ = writeln!(out, "nds_sessions_up {}", up);
+    header(
+        &mut out,
+        "nds_clients",
+        "gauge",
+        "Clients attached to any session",
+    );
+    let _ = writeln!(out, "nds_clients {}", clients);
+
+    header(
+        &mut out,
+        "nds_session_up",
+        "gauge",
+        "Whether the session's daemon answered",
+    );
+    for sample in samples {
+        let up = sample.metrics.is_some() as u8;
+        let _ = writeln!(out, "nds_session_up{} {}", labels(&sample.session), up);
+    }
+
+    for (name, kind, help, value) in SERIES {
+        header(&mut out, name, kind, help);
+        for sample in samples {
+            if let Some(metrics) = &sample.metrics {
+                let labels = labels(&sample.session);
+                let _ = writeln!(out, "{}{} {}", name, labels, value(metrics));
+            }
+        }
+    }
+
+    for (name, help, at) in ACTIVITY {
+        header(&mut out, name, "gauge", help);
+        for sample in samples {
+            if let Some(at) = at(&sample.session) {
+                let labels = labels(&sample.session);
+                let _ = writeln!(out, "{}{} {}", name, labels, at.timestamp());
+            }
+        }
+    }
+    out
+}
+
+/// Write `samples` to `path` for node_exporter's textfile collector, through
+/// a temporary file so it never reads one half written
+pub fn write_textfile(path: &Path, samples: &[SessionSample]) -> Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!(".{}.tmp", name));
+    fs::write(&temp, render(samples))?;
+    fs::rename(&temp, path)?;
+    Ok(())
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn labels(session: &Session) -> String {
+    format!(
+        "{{session=\"{}\",name=\"{}\"}}",
+        escape(&session.id),
+        escape(session.name.as_deref().unwrap_or(""))
+    )
+}
+
+/// A label value with backslashes, quotes and newlines escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(id: &str, name: Option<&str>) -> Session {
+        let mut session = Session::new(id.to_string(), 4242, "/tmp/nds.sock".into());
+        session.name = name.map(str::to_string);
+        session
+    }
+
+    #[test]
+    fn renders_sessions_that_answered_and_those_that_did_not() {
+        let mut up = session("abc", Some("web \"prod\"\n"));
+        up.last_output = DateTime::from_timestamp(1_700_000_000, 0);
+        let samples = [
+            SessionSample {
+                session: up,
+                metrics: Some(SessionMetrics {
+                    clients: 2,
+                    pty_bytes_read: 1234,
+                    ..Default::default()
+                }),
+            },
+            SessionSample {
+                session: session("def", None),
+                metrics: None,
+            },
+        ];
+        let text = render(&samples);
+        let labels = r#"{session="abc",name="web \"prod\"\n"}"#;
+
+        assert!(text.contains("nds_sessions 2\n"), "{}", text);
+        assert!(text.contains("nds_sessions_up 1\n"));
+        assert!(text.contains("nds_clients 2\n"));
+        assert!(text.contains(&format!("nds_session_up{} 1\n", labels)));
+        assert!(text.contains("nds_session_up{session=\"def\",name=\"\"} 0\n"));
+        assert!(text.contains("# TYPE nds_session_pty_read_bytes_total counter\n"));
+        assert!(text.contains(&format!(
+            "nds_session_pty_read_bytes_total{} 1234\n",
+            labels
+        )));
+        assert!(!text.contains("nds_session_clients{session=\"def\""));
+        assert!(text.contains(&format!(
+            "nds_session_last_output_timestamp_seconds{} 1700000000\n",
+            labels
+        )));
+    }
+
+    #[test]
+    fn textfile_is_replaced_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nds.prom");
+        fs::write(&path, "stale").unwrap();
+        write_textfile(&path, &[]).unwrap();
+
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .contains("nds_sessions 0\n"));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
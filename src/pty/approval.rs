@@ -0,0 +1,181 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::os::unix::io::RawFd;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+
+use super::client::ClientInfo;
+use super::status_message::StatusMessage;
+use super::terminal::get_terminal_size;
+
+/// What attaching clients that can let others in offer in their hello
+pub const APPROVE_ATTACH: &str = "approve_attach";
+
+/// Sent to the clients that can answer when another one asks to attach
+pub const ATTACH_REQUEST_EVENT: &str = "attach_request";
+
+/// Sent to the same clients once the request is answered or given up on
+pub const ATTACH_SETTLED_EVENT: &str = "attach_settled";
+
+/// How long a client waits to be let in before it is turned away
+pub const APPROVAL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A client asking to attach to a session with attach approval on, as the
+/// attached clients are shown it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttachRequest {
+    pub client_id: String,
+    pub user: Option<String>,
+    pub pid: Option<i32>,
+    pub tty: Option<String>,
+}
+
+impl AttachRequest {
+    pub(super) fn of(client: &ClientInfo) -> Self {
+        AttachRequest {
+            client_id: client.id.clone(),
+            user: client.peer.map(|peer| peer.user()),
+            pid: client.peer.and_then(|peer| peer.pid),
+            tty: client.tty.clone(),
+        }
+    }
+
+    /// The question put to attached clients, e.g. `Client 1a2b3c4d (alice,
+    /// pid 4242, /dev/pts/3) wants to attach. Let it in? [y/n]`
+    pub fn prompt(&self) -> String {
+        let mut who: Vec<String> = self.user.iter().cloned().collect();
+        who.extend(self.pid.map(|pid| format!("pid {}", pid)));
+        who.extend(self.tty.iter().cloned());
+        let who = if who.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", who.join(", "))
+        };
+        format!(
+            " Client {}{} wants to attach. Let it in? [y/n] ",
+            self.client_id, who
+        )
+    }
+}
+
+/// What the daemon tells an attached client about clients waiting to attach
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApprovalEvent {
+    /// A client is waiting to be let in
+    Asked(AttachRequest),
+    /// The client with this ID was let in or turned away, here or elsewhere
+    Settled(String),
+}
+
+impl ApprovalEvent {
+    /// The approval event in a daemon event, if it is one
+    pub fn from_event(name: &str, payload: &str) -> Option<Self> {
+        match name {
+            ATTACH_REQUEST_EVENT => serde_json::from_str(payload).ok().map(Self::Asked),
+            ATTACH_SETTLED_EVENT => Some(Self::Settled(payload.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// A client kept out until an attached one lets it in
+pub(super) struct PendingAttach {
+    pub client: ClientInfo,
+    pub request: AttachRequest,
+    /// Turned away if nobody has answered by then
+    pub until: Instant,
+}
+
+/// Take the waiting clients that are settled: those answered in `answers`
+/// (client ID and whether to let it in), those nobody answered in time, who
+/// are turned away, and, once no attached client `can_ask`, everyone left,
+/// who are let in as if approval were off
+pub(super) fn settle(
+    pending: &mut Vec<PendingAttach>,
+    answers: &[(String, bool)],
+    can_ask: bool,
+    now: Instant,
+) -> Vec<(PendingAttach, bool)> {
+    let mut settled = Vec::new();
+    let mut i = 0;
+    while i < pending.len() {
+        let id = &pending[i].request.client_id;
+        let verdict = match answers.iter().find(|(answered, _)| answered == id) {
+            Some((_, allow)) => Some(*allow),
+            None if !can_ask => Some(true),
+            None if now >= pending[i].until => Some(false),
+            None => None,
+        };
+        match verdict {
+            Some(allow) => settled.push((pending.remove(i), allow)),
+            None => i += 1,
+        }
+    }
+    settled
+}
+
+/// Put `request` to the user over the bottom row of the attached terminal
+/// and wait for y or n. Returns `None` if it was settled elsewhere or timed
+/// out first; requests that arrive meanwhile are added to `queued`.
+pub fn ask(
+    stdin_fd: RawFd,
+    request: &AttachRequest,
+    events: &Receiver<ApprovalEvent>,
+    queued: &mut VecDeque<AttachRequest>,
+) -> io::Result<Option<bool>> {
+    let (cols, rows) = get_terminal_size().unwrap_or((80, 24));
+    let prompt = StatusMessage::new(&request.prompt(), APPROVAL_TIMEOUT);
+    let mut stdout = io::stdout();
+    // Save cursor, draw the prompt, restore cursor
+    stdout.write_all(b"\x1b7")?;
+    stdout.write_all(&prompt.render(cols, rows))?;
+    stdout.write_all(b"\x1b8")?;
+    stdout.flush()?;
+
+    while !prompt.expired(Instant::now()) {
+        if let Some(key) = read_key(stdin_fd, Duration::from_millis(50))? {
+            match key {
+                b'y' | b'Y' => return Ok(Some(true)),
+                b'n' | b'N' | b'\x1b' => return Ok(Some(false)),
+                _ => {}
+            }
+        }
+        loop {
+            match events.try_recv() {
+                Ok(ApprovalEvent::Asked(next)) => queued.push_back(next),
+                Ok(ApprovalEvent::Settled(id)) if id == request.client_id => return Ok(None),
+                Ok(ApprovalEvent::Settled(id)) => queued.retain(|r| r.client_id != id),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(None),
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The first byte of the next key press, or `None` after `timeout`
+fn read_key(stdin_fd: RawFd, timeout: Duration) -> io::Result<Option<u8>> {
+    let mut fds = [libc::pollfd {
+        fd: stdin_fd,
+        events: libc::POLLIN,
+        revents: 0,
+    }];
+    let ret = unsafe { libc::poll(fds.as_mut_ptr(), 1, timeout.as_millis() as libc::c_int) };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::Interrupted {
+            return Ok(None);
+        }
+        return Err(err);
+    }
+    if ret == 0 {
+        return Ok(None);
+    }
+    let mut buf = [0u8; 16];
+    let n = unsafe { libc::read(stdin_fd, buf.as_mut_ptr() as *mut _, buf.len()) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((n > 0).then_some(buf[0]))
+}
@@ -1,9 +1,27 @@
 use chrono::{DateTime, Utc};
 use nix::libc;
+use serde::{Deserialize, Serialize};
 use std::io::{self, Write};
 use std::os::unix::net::UnixStream;
+use std::sync::Arc;
 use uuid::Uuid;
 
+use super::debug_dump::ClientDump;
+use super::input_rate::InputRateMeter;
+use super::output_queue::{OutputQueue, OutputQueuePolicy, Overflow, SharedOutput};
+use super::peer::{peer_credentials, PeerCredentials, ShareRole};
+use super::process_info::tty_of;
+use super::protocol::{Frame, FrameDecoder};
+use super::screen::ScreenView;
+use super::socket::{control_reply, exit_notice, get_command_end, parse_nds_command};
+use crate::pty_buffer::BufferReplay;
+use crate::session::ExitStatus;
+
+/// Most buffered output replayed to a client per pass of the daemon loop,
+/// so one replaying a large spill doesn't hold up everyone else
+const REPLAY_BYTES_PER_PASS: usize = 1024 * 1024;
+const REPLAY_CHUNK_BYTES: usize = 64 * 1024;
+
 // Structure to track client information
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -17,7 +35,59 @@ pub struct ClientInfo {
     pub remote_addr: Option<String>, // For future use with network connections
     #[allow(dead_code)]
     pub user_agent: Option<String>, // Client type/version info
-    pub pending_output: Vec<u8>, // Bytes we still owe the client
+    pub pending_output: OutputQueue, // Bytes we still owe the client
+    pub output_policy: OutputQueuePolicy, // How much may queue up, and what then
+    pub resync: bool,                // Output was dropped; repaint from the screen once caught up
+    pub input_rate: InputRateMeter,  // Input volume, for flood detection
+    pub tty: Option<String>,         // Terminal the client attached from
+    pub peer: Option<PeerCredentials>, // Who connected, as the kernel reports it
+    pub guest: Option<ShareRole>,    // Connected as a user the session is shared with
+    pub approves_attach: bool,       // Can be asked to let other clients in
+    pub size_reported: bool,         // Sent its terminal size with a resize command
+    pub view: Option<ScreenView>,    // Drawn from the screen when it doesn't fit the PTY
+    pub framed: bool,                // Speaks the framed protocol rather than in-band commands
+    pub replay: Option<BufferReplay>, // Output buffered while detached, still being sent
+    pub shows_windows: bool,         // Can be moved to windows other than window 0
+    pub window: u32,                 // Window being shown, input goes to
+    pub panes: Vec<(u32, (u16, u16))>, // Windows shown in split panes, and the panes' sizes
+    pub unpainted: Vec<u32>,         // Windows put in a pane that it hasn't been drawn from yet
+    pub broadcast: bool,             // Input is typed into the linked sessions too (`~b`)
+    decoder: FrameDecoder,           // Partial frames received from a framed client
+}
+
+/// Something an attached client sent, in the order it was sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientEvent {
+    /// Keyboard input for the PTY
+    Input(Vec<u8>),
+    /// A command and its arguments
+    Command(String, Vec<String>),
+}
+
+/// What the daemon reports about an attached client to `nds clients`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientSummary {
+    pub id: String,
+    pub cols: u16,
+    pub rows: u16,
+    pub connected_at: DateTime<Utc>,
+    pub input_bytes_per_minute: u64,
+    /// Sent more input than `NDS_INPUT_RATE_LIMIT` allows
+    pub input_flagged: bool,
+    /// Terminal the client attached from, if it reported one
+    pub tty: Option<String>,
+    /// User and process that connected, if the platform says
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub pid: Option<i32>,
+    /// What the client may do, if it connected as a user the session is
+    /// shared with
+    #[serde(default)]
+    pub guest: Option<ShareRole>,
+    /// Typing into the session's linked sessions too (`~b`)
+    #[serde(default)]
+    pub broadcast: bool,
 }
 
 impl ClientInfo {
@@ -25,6 +95,7 @@ impl ClientInfo {
     pub fn new(stream: UnixStream) -> Self {
         // Get initial terminal size
         let (rows, cols) = get_terminal_size().unwrap_or((24, 80));
+        let peer = peer_credentials(&stream).ok();
 
         Self {
             id: Uuid::new_v4().to_string()[..8].to_string(),
@@ -34,69 +105,228 @@ impl ClientInfo {
             connected_at: Utc::now(),
             remote_addr: None,
             user_agent: None,
-            pending_output: Vec::new(),
+            pending_output: OutputQueue::default(),
+            output_policy: OutputQueuePolicy::from_env(),
+            resync: false,
+            input_rate: InputRateMeter::default(),
+            // Until the client says otherwise, that of the process that connected
+            tty: peer.and_then(|peer| peer.pid).and_then(tty_of),
+            peer,
+            guest: None,
+            approves_attach: false,
+            size_reported: false,
+            view: None,
+            framed: false,
+            replay: None,
+            shows_windows: false,
+            window: 0,
+            panes: Vec::new(),
+            unpainted: Vec::new(),
+            broadcast: false,
+            decoder: FrameDecoder::default(),
+        }
+    }
+
+    /// A client that opened with a framed hello from `software`
+    pub fn framed(stream: UnixStream, software: String) -> Self {
+        Self {
+            framed: true,
+            user_agent: Some(software),
+            ..Self::new(stream)
+        }
+    }
+
+    /// Turn what the client sent into input and commands. Old clients embed
+    /// commands in their input; only those at the front of a read count.
+    pub fn input_events(&mut self, data: &[u8]) -> io::Result<Vec<ClientEvent>> {
+        let mut events = Vec::new();
+        if self.framed {
+            self.decoder.push(data);
+            while let Some(frame) = self.decoder.next_frame()? {
+                match frame {
+                    Frame::Data(input) => events.push(ClientEvent::Input(input)),
+                    Frame::Command { name, args } => events.push(ClientEvent::Command(name, args)),
+                    _ => {}
+                }
+            }
+            return Ok(events);
+        }
+
+        // Several commands (e.g. detach_others followed by resize) can arrive
+        // in a single read, so keep parsing until regular input remains
+        let mut data = data;
+        while let Some((cmd, args)) = parse_nds_command(data) {
+            events.push(ClientEvent::Command(cmd, args));
+            data = &data[get_command_end(data).unwrap_or(data.len())..];
+        }
+        if !data.is_empty() {
+            events.push(ClientEvent::Input(data.to_vec()));
+        }
+        Ok(events)
+    }
+
+    /// Redraw the client's view in full next time, after something else
+    /// was written over it
+    pub fn invalidate_view(&mut self) {
+        if let Some(view) = self.view.as_mut() {
+            view.invalidate();
         }
     }
 
     /// Try to drain any queued output for this client. We tolerate WouldBlock
     /// by leaving remaining bytes in the queue for the next loop iteration.
     pub fn flush_pending(&mut self) -> io::Result<()> {
-        while !self.pending_output.is_empty() {
-            match self.stream.write(&self.pending_output) {
-                Ok(0) => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::WriteZero,
-                        "socket closed while flushing pending output",
-                    ));
-                }
-                Ok(n) => {
-                    self.pending_output.drain(..n);
+        self.pending_output.write_to(&mut self.stream)
+    }
+
+    /// Send more of the output buffered while detached, a chunk at a time
+    /// as the client takes it. Live output waits behind it in the replay.
+    pub fn continue_replay(&mut self) -> io::Result<()> {
+        let mut sent = 0;
+        while sent < REPLAY_BYTES_PER_PASS && self.pending_output.is_empty() {
+            let Some(replay) = self.replay.as_mut() else {
+                break;
+            };
+            match replay.next_chunk(REPLAY_CHUNK_BYTES) {
+                Ok(Some(chunk)) => {
+                    sent += chunk.len();
+                    self.send_data(&chunk)?;
                 }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    break;
+                Ok(None) => self.replay = None,
+                Err(e) => {
+                    eprintln!("Warning: failed to read spilled output: {}", e);
+                    self.replay = None;
                 }
-                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
-                Err(e) => return Err(e),
             }
         }
         Ok(())
     }
 
-    /// Attempt to send fresh data to the client, queueing any tail bytes that
-    /// cannot be delivered immediately.
+    /// Whether the PTY should be left unread for this client to catch up.
+    /// Once paused, reading resumes at half the limit so it doesn't flap.
+    pub fn holds_pty(&self, paused: bool) -> bool {
+        let limit = if paused {
+            self.output_policy.limit / 2
+        } else {
+            self.output_policy.limit
+        };
+        self.output_policy.overflow == Overflow::Pause && self.pending_output.len() > limit
+    }
+
+    /// Apply the overflow policy once too much output has queued up
+    fn check_backlog(&mut self) -> io::Result<()> {
+        if self.pending_output.len() <= self.output_policy.limit {
+            return Ok(());
+        }
+        match self.output_policy.overflow {
+            Overflow::Disconnect => Err(io::Error::other(format!(
+                "more than {} bytes of output queued",
+                self.output_policy.limit
+            ))),
+            Overflow::DropOldest => {
+                self.pending_output.drop_unsent();
+                if self.view.is_some() {
+                    self.invalidate_view();
+                } else {
+                    self.resync = true;
+                }
+                Ok(())
+            }
+            Overflow::Pause => Ok(()),
+        }
+    }
+
+    /// Attempt to send session output to the client, queueing any tail bytes
+    /// that cannot be delivered immediately.
     pub fn send_data(&mut self, data: &[u8]) -> io::Result<()> {
-        if data.is_empty() {
+        self.send_output(&SharedOutput::new(data))
+    }
+
+    /// Send output that other clients get too, without copying it
+    pub fn send_output(&mut self, output: &SharedOutput) -> io::Result<()> {
+        if output.is_empty() {
             return Ok(());
         }
+        // Queued output goes out first, in the same write as the new output
+        self.pending_output.push_message(output.parts(self.framed));
+        self.flush_pending()?;
+        self.check_backlog()
+    }
 
-        // If we already have queued data, append and try to flush once.
-        if !self.pending_output.is_empty() {
-            self.pending_output.extend_from_slice(data);
-            return self.flush_pending();
+    /// Send output from window `window`; window 0's is session output as usual
+    pub fn send_window_output(&mut self, window: u32, data: &[u8]) -> io::Result<()> {
+        if window == 0 {
+            return self.send_data(data);
         }
+        let frame = Frame::WindowData {
+            window,
+            data: data.to_vec(),
+        };
+        self.pending_output
+            .push_message([Arc::<[u8]>::from(frame.encode())]);
+        self.flush_pending()?;
+        self.check_backlog()
+    }
 
-        let mut offset = 0;
-        while offset < data.len() {
-            match self.stream.write(&data[offset..]) {
-                Ok(0) => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::WriteZero,
-                        "socket closed while sending",
-                    ));
-                }
-                Ok(n) => {
-                    offset += n;
-                }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    self.pending_output.extend_from_slice(&data[offset..]);
-                    break;
-                }
-                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
-                Err(e) => return Err(e),
-            }
+    /// Whether the client is shown window `window`'s output, as the window
+    /// it shows or in one of its panes
+    pub fn shows(&self, window: u32) -> bool {
+        self.window == window || self.panes.iter().any(|(id, _)| *id == window)
+    }
+
+    /// Whether the client can be asked to let others attach: one that said
+    /// it can, and not a guest
+    pub fn can_approve(&self) -> bool {
+        self.approves_attach && self.guest.is_none()
+    }
+
+    /// Send a framed client an event, after the output queued before it.
+    /// Clients from before framing can't be told.
+    pub fn send_event(&mut self, name: &str, payload: &str) -> io::Result<()> {
+        if !self.framed {
+            return Ok(());
         }
+        let frame: Arc<[u8]> = Arc::from(Frame::event(name, payload).encode());
+        self.pending_output.push_message([frame]);
+        self.flush_pending()?;
+        self.check_backlog()
+    }
 
-        Ok(())
+    pub fn summary(&self) -> ClientSummary {
+        ClientSummary {
+            id: self.id.clone(),
+            cols: self.cols,
+            rows: self.rows,
+            connected_at: self.connected_at,
+            input_bytes_per_minute: self.input_rate.bytes_per_minute(),
+            input_flagged: self.input_rate.is_flagged(),
+            tty: self.tty.clone(),
+            uid: self.peer.map(|peer| peer.uid),
+            pid: self.peer.and_then(|peer| peer.pid),
+            guest: self.guest,
+            broadcast: self.broadcast,
+        }
+    }
+
+    pub fn dump(&self) -> ClientDump {
+        ClientDump {
+            id: self.id.clone(),
+            framed: self.framed,
+            tty: self.tty.clone(),
+            uid: self.peer.map(|peer| peer.uid),
+            pid: self.peer.and_then(|peer| peer.pid),
+            guest: self.guest,
+            cols: self.cols,
+            rows: self.rows,
+            size_reported: self.size_reported,
+            connected_at: self.connected_at,
+            queued_bytes: self.pending_output.len(),
+            replay_bytes: self.replay.as_ref().map_or(0, BufferReplay::len),
+            resync: self.resync,
+            drawn_from_screen: self.view.is_some(),
+            input_bytes_per_minute: self.input_rate.bytes_per_minute(),
+            input_flagged: self.input_rate.is_flagged(),
+        }
     }
 
     /// Get a display string for this client
@@ -116,12 +346,18 @@ impl ClientInfo {
         };
 
         format!(
-            "Client {} | Size: {}x{} | Connected: {} | Duration: {}",
+            "Client {} | Size: {}x{} | Connected: {} | Duration: {} | Input: {} B/min{}",
             self.id,
             self.cols,
             self.rows,
             self.connected_at.format("%H:%M:%S"),
-            duration_str
+            duration_str,
+            self.input_rate.bytes_per_minute(),
+            if self.input_rate.is_flagged() {
+                " [HIGH INPUT]"
+            } else {
+                ""
+            }
         )
     }
 
@@ -132,6 +368,60 @@ impl ClientInfo {
     }
 }
 
+/// Notices and refresh sequences written straight to a client go out as
+/// session output
+impl Write for ClientInfo {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send_data(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_pending()
+    }
+}
+
+/// A one-shot control connection (`nds wait`, `nds clients`...), answered in
+/// whichever protocol it spoke
+#[derive(Debug)]
+pub struct ControlConn {
+    pub stream: UnixStream,
+    pub framed: bool,
+}
+
+impl ControlConn {
+    pub fn new(stream: UnixStream, framed: bool) -> Self {
+        Self { stream, framed }
+    }
+
+    /// `args` as the one free-text argument they were sent as. The command
+    /// was split wherever its separator turned up, a NUL in a frame or a ':'
+    /// from an old client (see `parse_nds_command`), so this joins the
+    /// pieces back with the same one.
+    pub fn rest_of_args(&self, args: &[String]) -> String {
+        args.join(if self.framed { "\0" } else { ":" })
+    }
+
+    pub fn reply(&mut self, cmd: &str, payload: &str) -> io::Result<()> {
+        if self.framed {
+            self.stream.write_all(&Frame::reply(cmd, payload).encode())
+        } else {
+            self.stream
+                .write_all(control_reply(cmd, payload).as_bytes())
+        }
+    }
+
+    /// Tell a waiter how the shell or command ended
+    pub fn notify_exit(&mut self, status: ExitStatus) -> io::Result<()> {
+        if self.framed {
+            self.stream.write_all(&Frame::Exited(status).encode())?;
+        } else {
+            self.stream.write_all(exit_notice(status).as_bytes())?;
+        }
+        self.stream.flush()
+    }
+}
+
 #[allow(dead_code)]
 pub fn get_terminal_size() -> Result<(u16, u16), std::io::Error> {
     unsafe {
@@ -145,7 +435,8 @@ pub fn get_terminal_size() -> Result<(u16, u16), std::io::Error> {
 
 #[cfg(test)]
 mod tests {
-    use super::ClientInfo;
+    use super::{ClientInfo, ControlConn};
+    use crate::pty::output_queue::{OutputQueuePolicy, Overflow};
     use std::io::{Read, Result};
     use std::os::unix::net::UnixStream;
 
@@ -194,4 +485,66 @@ mod tests {
 
         Ok(())
     }
+
+    /// A client whose socket is full, with `limit` bytes of queue allowed
+    fn stalled_client(overflow: Overflow, limit: usize) -> Result<(ClientInfo, UnixStream)> {
+        let (writer, reader) = UnixStream::pair()?;
+        writer.set_nonblocking(true)?;
+        let mut client = ClientInfo::new(writer);
+        client.output_policy = OutputQueuePolicy { limit, overflow };
+        while client.pending_output.is_empty() {
+            client.send_data(&[b'x'; 4096])?;
+        }
+        Ok((client, reader))
+    }
+
+    #[test]
+    fn drop_oldest_discards_queue_and_asks_for_repaint() -> Result<()> {
+        let (mut client, _reader) = stalled_client(Overflow::DropOldest, 64 * 1024)?;
+        for _ in 0..64 {
+            client.send_data(&[b'y'; 4096])?;
+        }
+        assert!(client.pending_output.len() <= 64 * 1024);
+        assert!(client.resync);
+        Ok(())
+    }
+
+    #[test]
+    fn disconnect_policy_fails_the_write() -> Result<()> {
+        let (mut client, _reader) = stalled_client(Overflow::Disconnect, 64 * 1024)?;
+        let failed = (0..64).any(|_| client.send_data(&[b'y'; 4096]).is_err());
+        assert!(failed);
+        Ok(())
+    }
+
+    #[test]
+    fn pause_policy_holds_pty_until_half_drained() -> Result<()> {
+        let (mut client, mut reader) = stalled_client(Overflow::Pause, 64 * 1024)?;
+        assert!(!client.holds_pty(false));
+        for _ in 0..32 {
+            client.send_data(&[b'y'; 4096])?;
+        }
+        // Nothing is dropped; the daemon stops reading instead
+        assert!(client.pending_output.len() > 64 * 1024);
+        assert!(client.holds_pty(false));
+        assert!(!client.resync);
+
+        let mut buf = [0u8; 64 * 1024];
+        while client.holds_pty(true) {
+            let _ = reader.read(&mut buf)?;
+            client.flush_pending()?;
+        }
+        assert!(client.pending_output.len() <= 32 * 1024);
+        Ok(())
+    }
+
+    #[test]
+    fn rest_of_args_joins_with_the_separator_it_was_split_on() -> Result<()> {
+        let args = vec!["a".to_string(), "b".to_string()];
+        let (framed, _) = UnixStream::pair()?;
+        assert_eq!(ControlConn::new(framed, true).rest_of_args(&args), "a\0b");
+        let (legacy, _) = UnixStream::pair()?;
+        assert_eq!(ControlConn::new(legacy, false).rest_of_args(&args), "a:b");
+        Ok(())
+    }
 }
@@ -0,0 +1,175 @@
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+/// Largest OSC 52 clipboard write passed on when `max_bytes` is not set
+pub const DEFAULT_CLIPBOARD_MAX_BYTES: usize = 1024 * 1024;
+
+const OSC52: &[u8] = b"\x1b]52;";
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+/// Whether programs in a session may set the clipboard of attached clients
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClipboardPolicy {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// The `clipboard` setting in config.json
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClipboardConfig {
+    /// Pass OSC 52 clipboard writes on to clients (default: allow)
+    pub osc52: ClipboardPolicy,
+    /// Drop writes bigger than this many bytes, escape sequence and all
+    /// (default: 1 MiB)
+    pub max_bytes: Option<usize>,
+    /// Keep text a program copies while nobody is attached as the session's
+    /// paste buffer, for `nds paste`
+    pub store_detached: bool,
+}
+
+/// An OSC 52 clipboard write that was let through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClipboardWrite {
+    /// The whole escape sequence, ended with BEL, to send on
+    pub sequence: Vec<u8>,
+    /// The text, if it decoded
+    pub text: Option<String>,
+}
+
+/// Takes OSC 52 sequences out of PTY output, letting through the writes the
+/// policy allows. A sequence can span reads if it is too long to be held back
+/// whole, so one in progress is carried over to the next.
+#[derive(Debug)]
+pub struct ClipboardFilter {
+    policy: ClipboardPolicy,
+    max_bytes: usize,
+    /// An OSC 52 sequence started but not yet ended
+    pending: Option<Vec<u8>>,
+    /// The sequence in progress got too long and is being thrown away
+    dropping: bool,
+    /// The last read ended with the ESC of a string terminator
+    after_esc: bool,
+    store_detached: bool,
+}
+
+impl ClipboardFilter {
+    pub fn new(config: &ClipboardConfig) -> Self {
+        Self {
+            policy: config.osc52,
+            max_bytes: config.max_bytes.unwrap_or(DEFAULT_CLIPBOARD_MAX_BYTES),
+            pending: None,
+            dropping: false,
+            after_esc: false,
+            store_detached: config.store_detached,
+        }
+    }
+
+    /// Whether text copied with nobody attached becomes the paste buffer
+    pub fn stores_detached(&self) -> bool {
+        self.store_detached
+    }
+
+    /// `data` with OSC 52 sequences the policy doesn't allow taken out, and
+    /// the writes that were let through
+    pub fn filter(&mut self, data: &[u8]) -> (Vec<u8>, Vec<ClipboardWrite>) {
+        let mut output = Vec::with_capacity(data.len());
+        let mut writes = Vec::new();
+        let mut rest = data;
+
+        // The `\` of an ESC \ split across reads
+        if std::mem::take(&mut self.after_esc) && self.pending.is_some() {
+            let backslash = rest.first() == Some(&b'\\');
+            if backslash {
+                rest = &rest[1..];
+            }
+            self.finish(backslash, &mut output, &mut writes);
+        }
+
+        while !rest.is_empty() {
+            let Some(sequence) = self.pending.as_mut() else {
+                match find(rest, OSC52) {
+                    Some(start) => {
+                        output.extend_from_slice(&rest[..start]);
+                        self.pending = Some(OSC52.to_vec());
+                        rest = &rest[start + OSC52.len()..];
+                    }
+                    None => {
+                        output.extend_from_slice(rest);
+                        rest = &[];
+                    }
+                }
+                continue;
+            };
+
+            let end = rest.iter().position(|&b| b == BEL || b == ESC);
+            let taken = end.unwrap_or(rest.len());
+            if !self.dropping {
+                sequence.extend_from_slice(&rest[..taken]);
+                if sequence.len() > self.max_bytes {
+                    self.dropping = true;
+                    sequence.clear();
+                }
+            }
+            let Some(end) = end else {
+                rest = &[];
+                continue;
+            };
+            match (rest[end], rest.get(end + 1)) {
+                (BEL, _) => {
+                    rest = &rest[end + 1..];
+                    self.finish(true, &mut output, &mut writes);
+                }
+                (_, Some(b'\\')) => {
+                    rest = &rest[end + 2..];
+                    self.finish(true, &mut output, &mut writes);
+                }
+                (_, None) => {
+                    self.after_esc = true;
+                    rest = &[];
+                }
+                // ESC starting something else cuts the string short
+                (_, Some(_)) => {
+                    rest = &rest[end..];
+                    self.finish(false, &mut output, &mut writes);
+                }
+            }
+        }
+        (output, writes)
+    }
+
+    /// Decide on the sequence in progress now that it has ended, `complete`
+    /// if with a proper terminator
+    fn finish(&mut self, complete: bool, output: &mut Vec<u8>, writes: &mut Vec<ClipboardWrite>) {
+        let sequence = self.pending.take().unwrap_or_default();
+        let dropped = std::mem::take(&mut self.dropping);
+        if !complete || dropped || self.policy == ClipboardPolicy::Deny {
+            return;
+        }
+        // `targets;data`, where data `?` asks for the clipboard, which is
+        // never answered from here
+        let body = &sequence[OSC52.len()..];
+        let Some(split) = body.iter().position(|&b| b == b';') else {
+            return;
+        };
+        let payload = &body[split + 1..];
+        if payload == b"?" {
+            return;
+        }
+        let text = base64::engine::general_purpose::STANDARD
+            .decode(payload)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok());
+        let mut sequence = sequence;
+        sequence.push(BEL);
+        output.extend_from_slice(&sequence);
+        writes.push(ClipboardWrite { sequence, text });
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
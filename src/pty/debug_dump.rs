@@ -0,0 +1,104 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::peer::ShareRole;
+
+/// A snapshot of a session daemon's internal state, reported by
+/// `nds debug dump` to help work out why a session has stopped responding
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DaemonDump {
+    pub session_id: String,
+    pub daemon_pid: u32,
+    pub shell_pid: i32,
+    pub started_at: DateTime<Utc>,
+    pub uptime_seconds: u64,
+    /// Since the shell was last started, respawned or restarted
+    pub shell_uptime_seconds: u64,
+    /// How the shell exited, while it isn't running
+    pub exit_status: Option<String>,
+    pub respawn_in_seconds: Option<f64>,
+    pub clients: Vec<ClientDump>,
+    /// `nds wait` connections
+    pub waiters: usize,
+    /// `nds watch` and other event subscribers
+    pub subscribers: usize,
+    pub pty: PtyDump,
+    pub buffers: BufferDump,
+    pub terminal: TerminalDump,
+    /// File output is being recorded to (`nds record`)
+    pub recording: Option<String>,
+    /// Output is going to the session's log
+    pub logging: bool,
+}
+
+/// An attached client as the daemon sees it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientDump {
+    pub id: String,
+    pub framed: bool,
+    pub tty: Option<String>,
+    pub uid: Option<u32>,
+    pub pid: Option<i32>,
+    /// Connected as a user the session is shared with, with this role
+    pub guest: Option<ShareRole>,
+    pub cols: u16,
+    pub rows: u16,
+    pub size_reported: bool,
+    pub connected_at: DateTime<Utc>,
+    /// Output queued for the client that it hasn't read yet
+    pub queued_bytes: usize,
+    /// Output buffered while detached that is still being replayed
+    pub replay_bytes: usize,
+    /// Output was dropped and the client will be repainted once caught up
+    pub resync: bool,
+    /// Drawn from the screen because it doesn't fit the PTY
+    pub drawn_from_screen: bool,
+    pub input_bytes_per_minute: u64,
+    pub input_flagged: bool,
+}
+
+/// The PTY master and what has gone wrong with it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PtyDump {
+    pub master_fd: i32,
+    pub cols: u16,
+    pub rows: u16,
+    /// Client input waiting for room in the PTY
+    pub pending_input_bytes: usize,
+    /// Not reading output while a client catches up
+    pub output_paused: bool,
+    pub consecutive_errors: u32,
+    pub last_error: Option<PtyErrorDump>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PtyErrorDump {
+    pub at: DateTime<Utc>,
+    pub message: String,
+}
+
+/// How much output the daemon is holding on to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BufferDump {
+    /// Output kept for the next client to attach, in memory and on disk
+    pub detached_output_bytes: u64,
+    pub detached_output_spilled_bytes: u64,
+    pub scrollback_bytes: usize,
+    pub scrollback_lines: u64,
+    pub paste_buffer_bytes: Option<usize>,
+}
+
+/// What the daemon's copy of the screen says the application has switched on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TerminalDump {
+    pub cols: u16,
+    pub rows: u16,
+    pub cursor_row: u16,
+    pub cursor_col: u16,
+    pub cursor_visible: bool,
+    pub alternate_screen: bool,
+    pub application_keypad: bool,
+    /// Tracked private modes that are on, e.g. 2004 for bracketed paste
+    pub modes: Vec<u16>,
+    pub bells: u64,
+}
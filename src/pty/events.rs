@@ -0,0 +1,181 @@
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use super::client::ControlConn;
+use super::protocol::{Connection, Frame, FrameDecoder};
+use crate::error::{NdsError, Result};
+use crate::session::ExitStatus;
+
+/// Output and bell events go to each subscriber at most this often, however
+/// much the session prints or rings
+pub const OUTPUT_EVENT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Something that happened in a session, as heard by a subscriber
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DaemonEvent {
+    /// The shell or command wrote output
+    Output,
+    /// The shell or command rang the bell
+    Bell,
+    /// The shell or command exited; nothing more follows
+    Exited(ExitStatus),
+}
+
+impl DaemonEvent {
+    /// Name subscribers ask for the event by
+    pub fn name(&self) -> &'static str {
+        match self {
+            DaemonEvent::Output => "output",
+            DaemonEvent::Bell => "bell",
+            DaemonEvent::Exited(_) => "exited",
+        }
+    }
+
+    /// The event a frame from the daemon carries, if any
+    pub fn from_frame(frame: &Frame) -> Option<Self> {
+        match frame {
+            Frame::Event { name, .. } if name == "output" => Some(DaemonEvent::Output),
+            Frame::Event { name, .. } if name == "bell" => Some(DaemonEvent::Bell),
+            Frame::Exited(status) => Some(DaemonEvent::Exited(*status)),
+            _ => None,
+        }
+    }
+}
+
+/// A control connection that asked to hear about events
+#[derive(Debug)]
+struct Subscriber {
+    conn: ControlConn,
+    events: Vec<String>,
+    last_output: Option<Instant>,
+    last_bell: Option<Instant>,
+}
+
+impl Subscriber {
+    fn wants(&self, event: &str) -> bool {
+        self.events.iter().any(|e| e == event)
+    }
+
+    /// Send an event without blocking the daemon. Events are a few bytes, so
+    /// a subscriber whose socket can't take one has stopped reading.
+    fn send(&mut self, name: &str) -> io::Result<()> {
+        self.conn.stream.write_all(&Frame::event(name, "").encode())
+    }
+}
+
+/// Control connections kept open after their command: `nds wait` callers,
+/// who only hear how the shell or command ended, and subscribers (`nds
+/// watch`), who also hear about the events they asked for
+#[derive(Debug, Default)]
+pub struct Watchers {
+    waiters: Vec<ControlConn>,
+    subscribers: Vec<Subscriber>,
+}
+
+impl Watchers {
+    pub fn add_waiter(&mut self, conn: ControlConn) {
+        self.waiters.push(conn);
+    }
+
+    /// How many `nds wait` connections and event subscribers there are
+    pub fn counts(&self) -> (usize, usize) {
+        (self.waiters.len(), self.subscribers.len())
+    }
+
+    /// Keep `conn` open and send it `events` as they happen. Only framed
+    /// connections can be sent events.
+    pub fn subscribe(&mut self, conn: ControlConn, events: &[String]) {
+        if conn.framed {
+            self.subscribers.push(Subscriber {
+                conn,
+                events: events.to_vec(),
+                last_output: None,
+                last_bell: None,
+            });
+        }
+    }
+
+    /// The session wrote output. Subscribers that can't be told are dropped.
+    pub fn output(&mut self) {
+        self.throttled("output", |subscriber| &mut subscriber.last_output);
+    }
+
+    /// The session rang the bell. Subscribers that can't be told are dropped.
+    pub fn bell(&mut self) {
+        self.throttled("bell", |subscriber| &mut subscriber.last_bell);
+    }
+
+    /// Send `event` to subscribers that want it and haven't been sent it in
+    /// the last `OUTPUT_EVENT_INTERVAL`, going by the time `last` keeps
+    fn throttled(&mut self, event: &str, last: fn(&mut Subscriber) -> &mut Option<Instant>) {
+        let now = Instant::now();
+        self.subscribers.retain_mut(|subscriber| {
+            if !subscriber.wants(event)
+                || last(subscriber).is_some_and(|at| now.duration_since(at) < OUTPUT_EVENT_INTERVAL)
+            {
+                return true;
+            }
+            *last(subscriber) = Some(now);
+            subscriber.send(event).is_ok()
+        });
+    }
+
+    /// Number of subscribers still listening
+    #[cfg(test)]
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.len()
+    }
+
+    /// Tell everyone how the shell or command ended and hang up on them
+    pub fn exited(&mut self, status: ExitStatus) {
+        for mut waiter in self.waiters.drain(..) {
+            let _ = waiter.notify_exit(status);
+        }
+        for mut subscriber in self.subscribers.drain(..) {
+            let _ = subscriber.conn.notify_exit(status);
+        }
+    }
+}
+
+/// Events from a session's daemon, from `PtyProcess::subscribe`
+pub struct EventSubscription {
+    socket: Connection,
+    decoder: FrameDecoder,
+}
+
+impl EventSubscription {
+    pub(super) fn new(socket: Connection) -> Self {
+        let decoder = socket.decoder();
+        Self { socket, decoder }
+    }
+
+    /// The next event, or `None` if nothing happens within `timeout`
+    /// (`None` waits for as long as it takes). After `Exited` the daemon
+    /// hangs up, and so does one that is killed; both end in an error here.
+    pub fn next_event(&mut self, timeout: Option<Duration>) -> Result<Option<DaemonEvent>> {
+        // A zero timeout would mean none at all
+        let timeout = timeout.map(|t| t.max(Duration::from_millis(1)));
+        self.socket.stream().set_read_timeout(timeout)?;
+        loop {
+            match self.socket.read_frame(&mut self.decoder) {
+                Ok(Some(frame)) => {
+                    if let Some(event) = DaemonEvent::from_frame(&frame) {
+                        return Ok(Some(event));
+                    }
+                }
+                Ok(None) => {
+                    return Err(NdsError::SessionNotFound(
+                        "the session's daemon hung up".to_string(),
+                    ))
+                }
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
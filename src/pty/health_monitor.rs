@@ -1,3 +1,4 @@
+use std::io;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::thread;
@@ -72,6 +73,7 @@ impl HealthMonitor {
 
 /// Session recovery strategies
 #[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RecoveryStrategy {
     /// Send terminal refresh sequences
     RefreshTerminal,
@@ -80,6 +82,21 @@ pub enum RecoveryStrategy {
     /// Restart PTY process
     #[allow(dead_code)]
     RestartProcess,
+    /// Open a new PTY pair and start the shell on it (master fd is unusable)
+    ReopenPty,
+}
+
+/// Pick a recovery strategy for a PTY read error.
+///
+/// EIO on the master while the shell is still running means the PTY layer
+/// itself has failed; the only way forward is a new PTY pair. Anything else
+/// is treated as transient.
+pub fn select_recovery_strategy(error: &io::Error, shell_alive: bool) -> RecoveryStrategy {
+    if shell_alive && error.raw_os_error() == Some(libc::EIO) {
+        RecoveryStrategy::ReopenPty
+    } else {
+        RecoveryStrategy::RefreshTerminal
+    }
 }
 
 /// Attempt to recover a session
@@ -112,5 +129,9 @@ pub fn attempt_recovery(strategy: RecoveryStrategy, master_fd: i32) -> Result<()
             // For now, just return an error
             Err("Process restart not implemented".to_string())
         }
+        RecoveryStrategy::ReopenPty => {
+            // Needs ownership of the PTY and shell, see PtyProcess::recover
+            Err("PTY re-open must be performed by the PTY owner".to_string())
+        }
     }
 }
@@ -0,0 +1,142 @@
+use std::io::{self, Write};
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use super::terminal::get_terminal_size;
+use crate::keymap::{Escape, KeyMap};
+
+/// Escapes available while attached, each typed after Enter, as `~?` lists
+/// them
+const ESCAPE_HELP: &[(&[Escape], &str)] = &[
+    (&[Escape::Detach], "detach (or Ctrl+D)"),
+    (&[Escape::Switch], "switch sessions"),
+    (&[Escape::LastSession], "switch to the last session"),
+    (
+        &[Escape::QuickSwitch],
+        "quick-switch between recent sessions",
+    ),
+    (&[Escape::Scrollback], "scrollback history"),
+    (&[Escape::Rename], "rename the session"),
+    (&[Escape::NewWindow], "open a new window"),
+    (
+        &[Escape::NextWindow, Escape::PreviousWindow],
+        "next or previous window",
+    ),
+    (
+        &[Escape::SplitBeside, Escape::SplitBelow],
+        "split side by side or one above the other",
+    ),
+    (&[Escape::NextPane], "next pane"),
+    (&[Escape::Broadcast], "type into linked sessions too"),
+    (&[Escape::Suspend], "suspend nds (fg to resume)"),
+];
+
+/// The escapes and what they do, under `keymap`'s keys; those bound to no
+/// key are left out
+pub fn escape_help(keymap: &KeyMap) -> Vec<(String, String)> {
+    let mut rows: Vec<(String, String)> = ESCAPE_HELP
+        .iter()
+        .filter_map(|(escapes, action)| {
+            let keys: Vec<String> = escapes
+                .iter()
+                .map(|escape| keymap.escape_label(*escape))
+                .filter(|label| !label.is_empty())
+                .collect();
+            (!keys.is_empty()).then(|| (keys.join(" "), action.to_string()))
+        })
+        .collect();
+    let prefix = keymap.prefix.to_string();
+    rows.push((
+        format!("{}{}", prefix, prefix),
+        format!("send a literal {}", prefix),
+    ));
+    let help = keymap.escape_label(Escape::Help);
+    if !help.is_empty() {
+        rows.push((help, "show this help".to_string()));
+    }
+    rows
+}
+
+/// How long the help stays up if no key is pressed
+const HELP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Lines of the help box, padded to the same width and cut to `cols`
+pub fn render_help(cols: usize, keymap: &KeyMap) -> Vec<String> {
+    let rows = escape_help(keymap);
+    let key_width = rows
+        .iter()
+        .map(|(keys, _)| keys.chars().count() + 1)
+        .max()
+        .unwrap_or(0)
+        .max(6);
+    let mut lines = vec![" Escapes (after Enter) ".to_string()];
+    for (keys, action) in rows {
+        lines.push(format!("  {:<key_width$}{} ", keys, action));
+    }
+    lines.push(" Press any key to close ".to_string());
+
+    let width = lines
+        .iter()
+        .map(|l| l.chars().count())
+        .max()
+        .unwrap_or(0)
+        .min(cols);
+    lines
+        .into_iter()
+        .map(|l| format!("{:<width$}", l.chars().take(width).collect::<String>()))
+        .collect()
+}
+
+/// Draw the help over the bottom of the attached terminal until a key is
+/// pressed or it times out. The key is swallowed, and the rows it covered
+/// are left blank for the caller to have redrawn.
+pub fn show_help(stdin_fd: RawFd, keymap: &KeyMap) -> io::Result<()> {
+    let (cols, rows) = get_terminal_size().unwrap_or((80, 24));
+    let lines = render_help(cols as usize, keymap);
+    let lines = &lines[lines.len().saturating_sub(rows as usize)..];
+    let top = rows as usize - lines.len() + 1;
+
+    let mut stdout = io::stdout();
+    // Save cursor, draw from the bottom up, restore cursor
+    write!(stdout, "\x1b7")?;
+    for (i, line) in lines.iter().enumerate() {
+        write!(stdout, "\x1b[{};1H\x1b[2K\x1b[7m{}\x1b[0m", top + i, line)?;
+    }
+    write!(stdout, "\x1b8")?;
+    stdout.flush()?;
+
+    let result = wait_for_key(stdin_fd, HELP_TIMEOUT);
+
+    write!(stdout, "\x1b7")?;
+    for i in 0..lines.len() {
+        write!(stdout, "\x1b[{};1H\x1b[2K", top + i)?;
+    }
+    write!(stdout, "\x1b8")?;
+    stdout.flush()?;
+    result
+}
+
+/// Consume one key press, or give up after `timeout`
+fn wait_for_key(stdin_fd: RawFd, timeout: Duration) -> io::Result<()> {
+    let mut fds = [libc::pollfd {
+        fd: stdin_fd,
+        events: libc::POLLIN,
+        revents: 0,
+    }];
+    let ret = unsafe { libc::poll(fds.as_mut_ptr(), 1, timeout.as_millis() as libc::c_int) };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::Interrupted {
+            return Ok(());
+        }
+        return Err(err);
+    }
+    if ret > 0 {
+        let mut buf = [0u8; 16];
+        let n = unsafe { libc::read(stdin_fd, buf.as_mut_ptr() as *mut _, buf.len()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
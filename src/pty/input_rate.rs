@@ -0,0 +1,122 @@
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// Length of the window input volume is measured over
+pub const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Default number of input bytes per minute above which a client is flagged
+pub const DEFAULT_INPUT_RATE_LIMIT: u64 = 1024 * 1024;
+
+/// Limits applied to client input, read once from the daemon's environment:
+///
+/// - `NDS_INPUT_RATE_LIMIT`: bytes per minute before a client is flagged
+/// - `NDS_INPUT_THROTTLE`: when set, stop reading from a flagged client until
+///   its window rolls over instead of only flagging it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputRatePolicy {
+    pub bytes_per_minute: u64,
+    pub throttle: bool,
+}
+
+impl Default for InputRatePolicy {
+    fn default() -> Self {
+        Self {
+            bytes_per_minute: DEFAULT_INPUT_RATE_LIMIT,
+            throttle: false,
+        }
+    }
+}
+
+impl InputRatePolicy {
+    pub fn from_env() -> Self {
+        static POLICY: OnceLock<InputRatePolicy> = OnceLock::new();
+        *POLICY.get_or_init(|| {
+            let mut policy = Self::default();
+            if let Some(limit) = std::env::var("NDS_INPUT_RATE_LIMIT")
+                .ok()
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .filter(|v| *v > 0)
+            {
+                policy.bytes_per_minute = limit;
+            }
+            policy.throttle = std::env::var("NDS_INPUT_THROTTLE")
+                .map(|v| !v.is_empty() && v != "0")
+                .unwrap_or(false);
+            policy
+        })
+    }
+}
+
+/// Per-client input volume over fixed one-minute windows
+#[derive(Debug, Clone)]
+pub struct InputRateMeter {
+    window_start: Instant,
+    window_bytes: u64,
+    last_window_bytes: u64,
+    flagged: bool,
+}
+
+impl Default for InputRateMeter {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
+}
+
+impl InputRateMeter {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            window_bytes: 0,
+            last_window_bytes: 0,
+            flagged: false,
+        }
+    }
+
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= RATE_WINDOW {
+            // A gap of more than one window means the last full minute was quiet
+            self.last_window_bytes = if elapsed >= RATE_WINDOW * 2 {
+                0
+            } else {
+                self.window_bytes
+            };
+            self.window_bytes = 0;
+            self.window_start = now;
+        }
+    }
+
+    /// Account for `bytes` of input. Returns true the first time the client
+    /// crosses the limit, so the caller can raise a single alert for it.
+    pub fn record(&mut self, bytes: usize, now: Instant, policy: &InputRatePolicy) -> bool {
+        self.roll(now);
+        self.window_bytes += bytes as u64;
+
+        if !self.flagged && self.window_bytes > policy.bytes_per_minute {
+            self.flagged = true;
+            return true;
+        }
+        false
+    }
+
+    /// Whether input from this client should be left unread for now
+    pub fn is_throttled(&mut self, now: Instant, policy: &InputRatePolicy) -> bool {
+        self.roll(now);
+        policy.throttle && self.window_bytes >= policy.bytes_per_minute
+    }
+
+    /// When the current window rolls over, letting a throttled client in again
+    pub fn window_end(&self) -> Instant {
+        self.window_start + RATE_WINDOW
+    }
+
+    /// Bytes received in the current window, or the last full one if busier
+    pub fn bytes_per_minute(&self) -> u64 {
+        self.window_bytes.max(self.last_window_bytes)
+    }
+
+    /// True once the client has exceeded the limit during its connection
+    pub fn is_flagged(&self) -> bool {
+        self.flagged
+    }
+}
@@ -1,11 +1,19 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
 use std::io::{self, Read, Write};
 use std::os::unix::io::RawFd;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 use std::thread;
-use std::time::Duration;
-
+use std::time::{Duration, Instant};
+
+use super::approval::ApprovalEvent;
+use super::panes::Panes;
+use super::protocol::Connection;
+use super::socket::{
+    send_panes_command, send_refresh_command, send_resize_command, send_window_select_command,
+};
 use crate::pty_buffer::PtyBuffer;
 
 /// Buffer size constants for improved performance
@@ -13,11 +21,153 @@ pub const DEFAULT_BUFFER_SIZE: usize = 16384; // 16KB for better throughput
 #[allow(dead_code)]
 pub const SMALL_BUFFER_SIZE: usize = 4096; // 4KB for control messages
 
+/// Longest sequence held back waiting for its end; anything longer (say a
+/// large OSC 52 clipboard write) is passed on in pieces
+const MAX_HELD: usize = 64 * 1024;
+
+/// How long an incomplete sequence is held before it is passed on anyway,
+/// in case the program never finishes it
+pub const MAX_HOLD: Duration = Duration::from_millis(20);
+
+/// Most bytes handed to the PTY in one write, so a large paste goes in as
+/// the line discipline makes room rather than all at once
+const PTY_WRITE_CHUNK: usize = 4096;
+
+/// Input queued for the PTY above which the daemon stops reading clients'
+/// input, leaving the rest of a paste in their sockets
+pub const MAX_QUEUED_INPUT: usize = 256 * 1024;
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1a;
+
+/// Where the scan of PTY output is: between sequences, or partway through one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scan {
+    Ground,
+    Utf8(u8), // Continuation bytes still to come
+    Esc,
+    EscIntermediate,
+    Csi,
+    Str,    // OSC, DCS, APC, PM or SOS payload
+    StrEsc, // ESC inside a string, maybe the start of ST
+}
+
+/// Holds back the end of a PTY read that stops partway through a UTF-8
+/// character or an escape sequence, so every chunk passed on to clients and
+/// the replay buffer can be written out on its own
+#[derive(Debug, Default)]
+pub struct BoundarySplitter {
+    held: Vec<u8>,
+    held_since: Option<Instant>,
+}
+
+impl BoundarySplitter {
+    /// `data` after anything held back, minus an incomplete UTF-8 character
+    /// or escape sequence at the end, which is held until the next read
+    pub fn split(&mut self, data: &[u8], now: Instant) -> Vec<u8> {
+        let mut out = std::mem::take(&mut self.held);
+        out.extend_from_slice(data);
+        let tail = incomplete_tail(&out);
+        if tail > 0 && tail <= MAX_HELD {
+            self.held = out.split_off(out.len() - tail);
+            self.held_since = Some(now);
+        } else {
+            self.held_since = None;
+        }
+        out
+    }
+
+    /// When held output is passed on even if the rest never comes
+    pub fn deadline(&self) -> Option<Instant> {
+        self.held_since.map(|since| since + MAX_HOLD)
+    }
+
+    /// What is held, once it has waited `MAX_HOLD` for the rest
+    pub fn take_stale(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if self.deadline().is_some_and(|deadline| now >= deadline) {
+            self.take()
+        } else {
+            None
+        }
+    }
+
+    /// Whatever is held, e.g. once the PTY has closed
+    pub fn take(&mut self) -> Option<Vec<u8>> {
+        self.held_since = None;
+        Some(std::mem::take(&mut self.held)).filter(|held| !held.is_empty())
+    }
+}
+
+/// Length of the incomplete UTF-8 character or escape sequence `data` ends
+/// with, if any. `data` is taken to start between sequences.
+fn incomplete_tail(data: &[u8]) -> usize {
+    let mut scan = Scan::Ground;
+    let mut start = 0;
+
+    for (i, &b) in data.iter().enumerate() {
+        // A string's ESC not followed by `\` aborts it and starts a new escape
+        if scan == Scan::StrEsc && b != b'\\' {
+            start = i - 1;
+            scan = Scan::Esc;
+        }
+        // A character cut short by something else is left as it is
+        if matches!(scan, Scan::Utf8(_)) && b & 0xc0 != 0x80 {
+            scan = Scan::Ground;
+        }
+
+        scan = match (scan, b) {
+            (Scan::Str, ESC) => Scan::StrEsc,
+            (_, ESC) => {
+                start = i;
+                Scan::Esc
+            }
+            (_, CAN | SUB) => Scan::Ground,
+            (Scan::Ground, 0xc2..=0xdf) => {
+                start = i;
+                Scan::Utf8(1)
+            }
+            (Scan::Ground, 0xe0..=0xef) => {
+                start = i;
+                Scan::Utf8(2)
+            }
+            (Scan::Ground, 0xf0..=0xf4) => {
+                start = i;
+                Scan::Utf8(3)
+            }
+            (Scan::Ground, _) => Scan::Ground,
+            (Scan::Utf8(1), _) => Scan::Ground,
+            (Scan::Utf8(n), _) => Scan::Utf8(n - 1),
+            (Scan::Esc, b'[') => Scan::Csi,
+            (Scan::Esc, b']' | b'P' | b'_' | b'^' | b'X') => Scan::Str,
+            (Scan::Esc | Scan::EscIntermediate, 0x20..=0x2f) => Scan::EscIntermediate,
+            (Scan::Esc, _) => Scan::Ground,
+            (Scan::EscIntermediate, 0x30..=0x7e) => Scan::Ground,
+            (Scan::EscIntermediate, _) => Scan::EscIntermediate,
+            (Scan::Csi, 0x40..=0x7e) => Scan::Ground,
+            (Scan::Csi, _) => Scan::Csi,
+            (Scan::Str, BEL) => Scan::Ground,
+            (Scan::Str, _) => Scan::Str,
+            (Scan::StrEsc, _) => Scan::Ground, // ST
+        };
+    }
+
+    if scan == Scan::Ground {
+        0
+    } else {
+        data.len() - start
+    }
+}
+
 /// Handle reading from PTY master and broadcasting to clients
 pub struct PtyIoHandler {
     master_fd: RawFd,
     #[allow(dead_code)]
     buffer_size: usize,
+    pending_input: RefCell<VecDeque<u8>>, // Input the PTY hasn't taken yet
+    bytes_read: Cell<u64>,                // Output read from the PTY, for `nds metrics`
+    bytes_written: Cell<u64>,             // Input written to the PTY
 }
 
 impl PtyIoHandler {
@@ -25,9 +175,24 @@ impl PtyIoHandler {
         Self {
             master_fd,
             buffer_size: DEFAULT_BUFFER_SIZE, // Use 16KB buffer for better performance
+            pending_input: RefCell::new(VecDeque::new()),
+            bytes_read: Cell::new(0),
+            bytes_written: Cell::new(0),
         }
     }
 
+    /// Move over to a re-opened PTY master, dropping input queued for the
+    /// old one but keeping the byte counts
+    pub fn reopen(&mut self, master_fd: RawFd) {
+        self.master_fd = master_fd;
+        self.discard_input();
+    }
+
+    /// Bytes read from and written to the PTY since the daemon started
+    pub fn byte_counts(&self) -> (u64, u64) {
+        (self.bytes_read.get(), self.bytes_written.get())
+    }
+
     /// Read from PTY master file descriptor
     pub fn read_from_pty(&self, buffer: &mut [u8]) -> io::Result<usize> {
         // Use direct syscall to avoid file descriptor issues
@@ -50,38 +215,71 @@ impl PtyIoHandler {
                 return Err(err);
             }
 
+            self.bytes_read.set(self.bytes_read.get() + result as u64);
             Ok(result as usize)
         }
     }
 
-    /// Write to PTY master file descriptor
+    /// Queue input for the PTY and write as much of it as the PTY takes
+    /// without blocking; the rest goes in on later calls to `flush_input`
     pub fn write_to_pty(&self, data: &[u8]) -> io::Result<()> {
-        // Use direct syscall to avoid file descriptor issues
-        let mut written = 0;
-        while written < data.len() {
-            unsafe {
-                let result = libc::write(
+        self.pending_input.borrow_mut().extend(data);
+        self.flush_input()
+    }
+
+    /// Write queued input to the PTY master in chunks until it is all
+    /// written or the PTY's input buffer is full. On an error the queued
+    /// input is dropped, as the PTY won't be taking it.
+    pub fn flush_input(&self) -> io::Result<()> {
+        let mut pending = self.pending_input.borrow_mut();
+        while !pending.is_empty() {
+            let (front, _) = pending.as_slices();
+            let chunk = &front[..front.len().min(PTY_WRITE_CHUNK)];
+            // Use direct syscall to avoid file descriptor issues
+            let result = unsafe {
+                libc::write(
                     self.master_fd,
-                    data[written..].as_ptr() as *const libc::c_void,
-                    data.len() - written,
-                );
+                    chunk.as_ptr() as *const libc::c_void,
+                    chunk.len(),
+                )
+            };
 
-                if result < 0 {
-                    let err = io::Error::last_os_error();
+            if result < 0 {
+                let err = io::Error::last_os_error();
+                match err.kind() {
                     // Retry on interrupted system call
-                    if err.kind() == io::ErrorKind::Interrupted {
-                        continue;
+                    io::ErrorKind::Interrupted => continue,
+                    // The PTY is full; wait until it can take more
+                    io::ErrorKind::WouldBlock => break,
+                    _ => {
+                        pending.clear();
+                        return Err(err);
                     }
-                    // Return error for non-recoverable errors
-                    return Err(err);
                 }
-
-                written += result as usize;
             }
+
+            pending.drain(..result as usize);
+            self.bytes_written
+                .set(self.bytes_written.get() + result as u64);
         }
         Ok(())
     }
 
+    /// Bytes of input waiting for the PTY to take them
+    pub fn pending_input(&self) -> usize {
+        self.pending_input.borrow().len()
+    }
+
+    /// Whether enough input is queued that no more should be read from clients
+    pub fn input_backlogged(&self) -> bool {
+        self.pending_input() >= MAX_QUEUED_INPUT
+    }
+
+    /// Forget queued input, e.g. once the program it was for has exited
+    pub fn discard_input(&self) {
+        self.pending_input.borrow_mut().clear();
+    }
+
     /// Send a control character to the PTY
     pub fn send_control_char(&self, ch: u8) -> io::Result<()> {
         self.write_to_pty(&[ch])
@@ -93,116 +291,131 @@ impl PtyIoHandler {
     }
 }
 
-/// Handle scrollback buffer management
-pub struct ScrollbackHandler {
-    buffer: Arc<Mutex<Vec<u8>>>,
-    #[allow(dead_code)]
-    max_size: usize,
+/// The client's panes, shared by the threads of an attached client
+pub fn lock_panes(panes: &Mutex<Panes>) -> MutexGuard<'_, Panes> {
+    panes.lock().unwrap_or_else(PoisonError::into_inner)
 }
 
-impl ScrollbackHandler {
-    pub fn new(max_size: usize) -> Self {
-        Self {
-            buffer: Arc::new(Mutex::new(Vec::new())),
-            max_size,
-        }
+/// Tell the daemon where the panes are and how big they are, or, once back
+/// to one window, the terminal's size and to repaint it
+pub fn send_layout(socket: &Connection, panes: &Panes) -> io::Result<()> {
+    let (cols, rows) = panes.main_size();
+    send_resize_command(socket, cols, rows)?;
+    if socket.supports("panes") {
+        send_panes_command(socket, &panes.window_sizes())?;
     }
-
-    /// Add data to the scrollback buffer
-    #[allow(dead_code)]
-    pub fn add_data(&self, data: &[u8]) {
-        let mut buffer = self.buffer.lock().unwrap();
-        buffer.extend_from_slice(data);
-
-        // Trim if too large
-        if buffer.len() > self.max_size {
-            let remove = buffer.len() - self.max_size;
-            buffer.drain(..remove);
-        }
-    }
-
-    /// Get a clone of the scrollback buffer
-    pub fn get_buffer(&self) -> Vec<u8> {
-        self.buffer.lock().unwrap().clone()
-    }
-
-    /// Get a reference to the shared buffer
-    pub fn get_shared_buffer(&self) -> Arc<Mutex<Vec<u8>>> {
-        Arc::clone(&self.buffer)
+    if !panes.is_split() {
+        send_refresh_command(socket)?;
     }
+    Ok(())
 }
 
-/// Thread that reads from socket and writes to stdout
+/// Thread that reads from socket and writes to stdout, through `panes`
 pub fn spawn_socket_to_stdout_thread(
-    mut socket: std::os::unix::net::UnixStream,
+    mut socket: Connection,
     running: Arc<AtomicBool>,
-    scrollback: Arc<Mutex<Vec<u8>>>,
     paused: Arc<AtomicBool>,
+    approvals: Sender<ApprovalEvent>,
+    panes: Arc<Mutex<Panes>>,
 ) -> thread::JoinHandle<()> {
     thread::spawn(move || {
         let mut stdout = io::stdout();
         let mut buffer = [0u8; DEFAULT_BUFFER_SIZE]; // Use 16KB buffer
         let mut held_buffer = Vec::new(); // Buffer to hold data while paused
+        let mut was_paused = false; // Split panes are drawn again after a pause
+        let mut decoder = socket.decoder();
+        // Passed to the input loop, which asks the user
+        let on_event = |name: &str, payload: &str| {
+            if let Some(event) = ApprovalEvent::from_event(name, payload) {
+                let _ = approvals.send(event);
+            }
+        };
 
         while running.load(Ordering::SeqCst) {
+            // A change of layout goes to the daemon as soon as it is made,
+            // after the pane input goes to if the focused one closed
+            {
+                let mut panes = lock_panes(&panes);
+                if let Some(window) = panes.take_reselect() {
+                    let _ = send_window_select_command(&socket, &window.to_string());
+                }
+                if panes.take_relayout() && send_layout(&socket, &panes).is_err() {
+                    break;
+                }
+            }
+
             // If paused, just sleep and continue
             if paused.load(Ordering::SeqCst) {
+                was_paused = true;
                 // Still read from socket to prevent blocking, but buffer it
                 match socket.read(&mut buffer) {
                     Ok(0) => break, // Socket closed
                     Ok(n) => {
-                        // Hold the data while paused
-                        held_buffer.extend_from_slice(&buffer[..n]);
+                        // Hold the data while paused; split panes are drawn
+                        // again in full instead
+                        decoder.push(&buffer[..n]);
+                        let mut panes = lock_panes(&panes);
+                        match panes.take_output(&mut decoder, on_event) {
+                            Ok(_) if panes.is_split() => panes.invalidate(),
+                            Ok(output) => held_buffer.extend_from_slice(&output),
+                            Err(_) => break,
+                        }
                     }
                     Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                         thread::sleep(Duration::from_millis(10));
                     }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
                     Err(_) => break,
                 }
                 continue;
             }
 
+            // Whatever covered the panes is drawn over, and panes a resize
+            // changed are drawn without waiting for output
+            {
+                let mut panes = lock_panes(&panes);
+                if std::mem::take(&mut was_paused) {
+                    panes.invalidate();
+                }
+                if panes.needs_redraw() {
+                    let _ = stdout.write_all(&panes.render());
+                    let _ = stdout.flush();
+                }
+            }
+
             // If we have held data and we're no longer paused, flush it
             if !held_buffer.is_empty() {
                 if stdout.write_all(&held_buffer).is_err() {
                     break;
                 }
                 let _ = stdout.flush();
-
-                // Add to scrollback
-                let mut scrollback = scrollback.lock().unwrap();
-                scrollback.extend_from_slice(&held_buffer);
                 held_buffer.clear();
             }
 
             match socket.read(&mut buffer) {
                 Ok(0) => break, // Socket closed
                 Ok(n) => {
+                    decoder.push(&buffer[..n]);
+                    let Ok(output) = lock_panes(&panes).take_output(&mut decoder, on_event) else {
+                        break; // Not speaking the protocol
+                    };
+
                     // Write to stdout only if not paused
                     if !paused.load(Ordering::SeqCst) {
-                        if stdout.write_all(&buffer[..n]).is_err() {
+                        if stdout.write_all(&output).is_err() {
                             break;
                         }
                         let _ = stdout.flush();
                     } else {
                         // If paused mid-read, buffer it
-                        held_buffer.extend_from_slice(&buffer[..n]);
-                    }
-
-                    // Always add to scrollback buffer
-                    let mut scrollback = scrollback.lock().unwrap();
-                    scrollback.extend_from_slice(&buffer[..n]);
-
-                    // Trim if too large
-                    let scrollback_max = 10 * 1024 * 1024; // 10MB
-                    if scrollback.len() > scrollback_max {
-                        let remove = scrollback.len() - scrollback_max;
-                        scrollback.drain(..remove);
+                        held_buffer.extend_from_slice(&output);
                     }
                 }
                 Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                     thread::sleep(Duration::from_millis(10));
                 }
+                // Being stopped and continued (`~z`, then `fg`) interrupts the read
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
                 Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {
                     // Expected when socket is closed, just exit cleanly
                     break;
@@ -210,16 +423,21 @@ pub fn spawn_socket_to_stdout_thread(
                 Err(_) => break,
             }
         }
+
+        // The daemon closed our connection (e.g. another client attached with
+        // --detach-others); let the input loop know so it can detach cleanly.
+        running.store(false, Ordering::SeqCst);
     })
 }
 
-/// Thread that monitors terminal size changes
+/// Thread that monitors terminal size changes. Split panes are laid out
+/// again instead, and the daemon told by the output thread.
 pub fn spawn_resize_monitor_thread(
-    mut socket: std::os::unix::net::UnixStream,
+    socket: Connection,
     running: Arc<AtomicBool>,
     initial_size: (u16, u16),
+    panes: Arc<Mutex<Panes>>,
 ) -> thread::JoinHandle<()> {
-    use crate::pty::socket::send_resize_command;
     use crate::pty::terminal::get_terminal_size;
 
     thread::spawn(move || {
@@ -229,7 +447,11 @@ pub fn spawn_resize_monitor_thread(
             if let Ok((new_cols, new_rows)) = get_terminal_size() {
                 if (new_cols, new_rows) != last_size {
                     // Terminal size changed, send resize command
-                    let _ = send_resize_command(&mut socket, new_cols, new_rows);
+                    let mut panes = lock_panes(&panes);
+                    panes.resize(new_cols, new_rows);
+                    if !panes.is_split() {
+                        let _ = send_resize_command(&socket, new_cols, new_rows);
+                    }
                     last_size = (new_cols, new_rows);
                 }
             }
@@ -0,0 +1,57 @@
+//! Typing into linked sessions (`nds link`, `~b`).
+//!
+//! A client that turns broadcasting on has its input typed into every live
+//! session linked to its own as well. The daemon hands that input to a
+//! thread of its own, which passes the bytes on as they are with each linked
+//! daemon's `input` control command, so a slow or stuck daemon never holds
+//! up this one.
+//! Input that comes in that way is written to the PTY and goes no further:
+//! only keystrokes from attached clients are mirrored, so sessions linked to
+//! each other both ways can't echo input back and forth.
+
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use crate::session::Session;
+
+use super::spawn::PtyProcess;
+
+/// Passes input on to the sessions linked to this one
+#[derive(Default)]
+pub(super) struct Mirror {
+    queue: Option<Sender<Vec<u8>>>,
+}
+
+impl Mirror {
+    /// Type `data` into the sessions linked to `session_id`, without waiting
+    /// for it to get there
+    pub(super) fn send(&mut self, session_id: &str, data: Vec<u8>) {
+        if let Some(queue) = &self.queue {
+            if queue.send(data.clone()).is_ok() {
+                return;
+            }
+        }
+        let (queue, input) = mpsc::channel::<Vec<u8>>();
+        let session_id = session_id.to_string();
+        let spawned = thread::Builder::new()
+            .name("nds-mirror".to_string())
+            .spawn(move || {
+                while let Ok(mut data) = input.recv() {
+                    // Whatever queued up meanwhile goes in one command
+                    while let Ok(more) = input.try_recv() {
+                        data.extend(more);
+                    }
+                    // Read again each time, as links come and go
+                    let Ok(session) = Session::load(&session_id) else {
+                        continue;
+                    };
+                    for linked in session.links.iter().filter_map(|id| Session::load(id).ok()) {
+                        let _ = PtyProcess::send_input(&linked, &data);
+                    }
+                }
+            });
+        if spawned.is_ok() && queue.send(data).is_ok() {
+            self.queue = Some(queue);
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+
+/// A session daemon's counters and gauges, reported to `nds metrics`.
+/// Counters run from when the daemon started.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionMetrics {
+    pub uptime_seconds: u64,
+    pub shell_running: bool,
+    pub clients: usize,
+    pub client_connects: u64,
+    /// Output read from the PTY
+    pub pty_bytes_read: u64,
+    /// Input written to the PTY
+    pub pty_bytes_written: u64,
+    pub pty_errors: u64,
+    pub recovery_attempts: u64,
+    /// Output kept for the next client to attach, in memory and on disk
+    pub output_buffer_bytes: u64,
+    pub scrollback_bytes: usize,
+    /// Input waiting for room in the PTY
+    pub pending_input_bytes: usize,
+    /// Output queued for clients that haven't read it yet
+    pub client_queued_bytes: usize,
+    /// Clients sending more input than `NDS_INPUT_RATE_LIMIT` allows
+    pub input_flagged_clients: usize,
+}
+
+/// Events the daemon loop counts for `SessionMetrics`; the PTY's byte
+/// counts are kept by its `PtyIoHandler`
+#[derive(Debug, Default)]
+pub struct DaemonCounters {
+    pub client_connects: u64,
+    pub pty_errors: u64,
+    pub recovery_attempts: u64,
+}
@@ -1,24 +1,61 @@
 // PTY process management module
+mod approval;
 mod client;
+mod clipboard;
+mod debug_dump;
+mod events;
 mod health_monitor;
+mod help_overlay;
+mod input_rate;
 mod io_handler;
+mod links;
+mod metrics;
+mod output_queue;
+mod panes;
+mod peer;
+mod process_info;
+pub mod protocol;
+mod quick_switch;
+mod recording;
+mod redraw;
+mod resize;
+mod respawn;
+mod screen;
 mod session_switcher;
+mod session_switcher_tui;
 mod socket;
 mod spawn;
+mod status_message;
 mod terminal;
+mod wakeup;
+mod windows;
 
 // Async versions for tokio runtime
 #[cfg(feature = "async")]
-mod io_handler_async;
-#[cfg(feature = "async")]
-#[cfg(feature = "async")]
 pub mod socket_async;
+#[cfg(feature = "async")]
+mod spawn_async;
 
 #[cfg(test)]
 mod tests;
 
 // Re-export main types for backward compatibility
-pub use spawn::PtyProcess;
+pub use approval::{ApprovalEvent, AttachRequest};
+pub use client::ClientSummary;
+pub use clipboard::{ClipboardConfig, ClipboardPolicy, DEFAULT_CLIPBOARD_MAX_BYTES};
+pub use debug_dump::{BufferDump, ClientDump, DaemonDump, PtyDump, PtyErrorDump, TerminalDump};
+pub use events::{DaemonEvent, EventSubscription};
+pub use metrics::SessionMetrics;
+pub use peer::{peer_credentials, user_name, PeerAccess, PeerCredentials, ShareRole};
+pub use recording::{RecordingSummary, DEFAULT_RECORDING_MAX_BYTES};
+pub use redraw::RedrawStrategy;
+pub use resize::ResizePolicy;
+pub use respawn::RespawnPolicy;
+pub(crate) use screen::Screen;
+pub use spawn::{AttachOptions, PtyProcess, SpawnOptions};
+#[cfg(feature = "async")]
+pub use spawn_async::AsyncPtyProcess;
+pub use windows::WindowSummary;
 
 // Note: ClientInfo is now internal to the module
 // If it needs to be public, uncomment the line below:
@@ -0,0 +1,239 @@
+use std::cell::OnceCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, IoSlice, Write};
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+
+use super::protocol::Frame;
+
+/// Default number of queued output bytes above which a client is behind
+pub const DEFAULT_OUTPUT_QUEUE_LIMIT: usize = 1024 * 1024;
+
+/// What the daemon does about a client whose queued output passes the limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Disconnect the client
+    Disconnect,
+    /// Throw away the output the client hasn't been sent yet and repaint it
+    /// from the daemon's screen once it has caught up
+    #[default]
+    DropOldest,
+    /// Stop reading from the PTY until the client catches up, which holds up
+    /// the program instead
+    Pause,
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Overflow::Disconnect => f.write_str("disconnect"),
+            Overflow::DropOldest => f.write_str("drop-oldest"),
+            Overflow::Pause => f.write_str("pause"),
+        }
+    }
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disconnect" => Ok(Overflow::Disconnect),
+            "drop-oldest" => Ok(Overflow::DropOldest),
+            "pause" => Ok(Overflow::Pause),
+            _ => Err(format!(
+                "unknown output queue policy '{}' (expected disconnect, drop-oldest or pause)",
+                s
+            )),
+        }
+    }
+}
+
+/// Limits on output queued for slow clients, read once from the daemon's
+/// environment:
+///
+/// - `NDS_OUTPUT_QUEUE_LIMIT`: bytes queued for a client before it is behind
+/// - `NDS_OUTPUT_QUEUE_POLICY`: `disconnect`, `drop-oldest` or `pause`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputQueuePolicy {
+    pub limit: usize,
+    pub overflow: Overflow,
+}
+
+impl Default for OutputQueuePolicy {
+    fn default() -> Self {
+        Self {
+            limit: DEFAULT_OUTPUT_QUEUE_LIMIT,
+            overflow: Overflow::default(),
+        }
+    }
+}
+
+impl OutputQueuePolicy {
+    pub fn from_env() -> Self {
+        static POLICY: OnceLock<OutputQueuePolicy> = OnceLock::new();
+        *POLICY.get_or_init(|| {
+            let mut policy = Self::default();
+            if let Some(limit) = std::env::var("NDS_OUTPUT_QUEUE_LIMIT")
+                .ok()
+                .and_then(|v| v.trim().parse::<usize>().ok())
+                .filter(|v| *v > 0)
+            {
+                policy.limit = limit;
+            }
+            if let Some(overflow) = std::env::var("NDS_OUTPUT_QUEUE_POLICY")
+                .ok()
+                .and_then(|v| v.trim().parse().ok())
+            {
+                policy.overflow = overflow;
+            }
+            policy
+        })
+    }
+}
+
+/// Most slices handed to one `writev`
+const MAX_IOVECS: usize = 64;
+
+/// One chunk of session output, shared by every client it goes to instead
+/// of being copied for each
+#[derive(Debug)]
+pub struct SharedOutput {
+    data: Arc<[u8]>,
+    header: OnceCell<Arc<[u8]>>, // Frame header for framed clients, made once
+}
+
+impl SharedOutput {
+    pub fn new(data: &[u8]) -> Self {
+        Self {
+            data: Arc::from(data),
+            header: OnceCell::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// What to queue for a client: the data, behind a frame header for
+    /// framed clients
+    pub fn parts(&self, framed: bool) -> Vec<Arc<[u8]>> {
+        if !framed {
+            return vec![Arc::clone(&self.data)];
+        }
+        let header = self
+            .header
+            .get_or_init(|| Arc::from(Frame::data_header(self.data.len())));
+        vec![Arc::clone(header), Arc::clone(&self.data)]
+    }
+}
+
+/// Part of a message queued for a client
+#[derive(Debug)]
+struct Part {
+    bytes: Arc<[u8]>,
+    starts_message: bool,
+}
+
+/// Output owed to a client, kept as the messages it was sent in so that
+/// dropping some never cuts a frame or escape sequence in half
+#[derive(Debug, Default)]
+pub struct OutputQueue {
+    parts: VecDeque<Part>,
+    written: usize, // Bytes of the front part already sent
+    len: usize,
+}
+
+impl OutputQueue {
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bytes still to be sent
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Queue parts that go out together, and are only ever dropped together
+    pub fn push_message(&mut self, parts: impl IntoIterator<Item = Arc<[u8]>>) {
+        let mut starts_message = true;
+        for bytes in parts {
+            if bytes.is_empty() {
+                continue;
+            }
+            self.len += bytes.len();
+            self.parts.push_back(Part {
+                bytes,
+                starts_message,
+            });
+            starts_message = false;
+        }
+    }
+
+    /// Write as much as `writer` takes without blocking, several parts per
+    /// call
+    pub fn write_to(&mut self, writer: &mut impl Write) -> io::Result<()> {
+        while !self.parts.is_empty() {
+            let slices: Vec<IoSlice> = self
+                .parts
+                .iter()
+                .take(MAX_IOVECS)
+                .enumerate()
+                .map(|(i, part)| {
+                    let skip = if i == 0 { self.written } else { 0 };
+                    IoSlice::new(&part.bytes[skip..])
+                })
+                .collect();
+            match writer.write_vectored(&slices) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "socket closed while flushing pending output",
+                    ));
+                }
+                Ok(n) => self.advance(n),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn advance(&mut self, mut n: usize) {
+        self.len -= n;
+        while let Some(front) = self.parts.front() {
+            let left = front.bytes.len() - self.written;
+            if n < left {
+                self.written += n;
+                return;
+            }
+            n -= left;
+            self.parts.pop_front();
+            self.written = 0;
+        }
+    }
+
+    /// Drop every message nothing has been sent of yet, keeping the rest of
+    /// a message that is partly sent. Returns the number of bytes dropped.
+    pub fn drop_unsent(&mut self) -> usize {
+        let started = self
+            .parts
+            .front()
+            .is_some_and(|p| self.written > 0 || !p.starts_message);
+        let keep = if started {
+            1 + self
+                .parts
+                .iter()
+                .skip(1)
+                .take_while(|p| !p.starts_message)
+                .count()
+        } else {
+            0
+        };
+        let dropped: usize = self.parts.drain(keep..).map(|p| p.bytes.len()).sum();
+        self.len -= dropped;
+        dropped
+    }
+}
@@ -0,0 +1,553 @@
+//! Split panes: several of a session's windows on one terminal at once.
+//!
+//! `~|` splits the focused pane into two side by side and `~-` into two one
+//! above the other, opening a new window (see `windows`) in the new half.
+//! `~o` moves the focus, and with it the keyboard, to the next pane; `~n` and
+//! `~p` change the window in the focused pane.
+//!
+//! The daemon sizes each window to its pane and sends the client the output
+//! of every window it shows. The client feeds each window's output into a
+//! `Screen` of the pane's size and draws the panes, and the lines between
+//! them, from those screens. A pane closes when its window does; once one is
+//! left, the client goes back to passing that window's output straight
+//! through.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+
+use super::protocol::{Frame, FrameDecoder};
+use super::screen::Screen;
+use super::windows::{WINDOW_CLOSED_EVENT, WINDOW_EVENT};
+
+/// Takes the terminal over for the split screen: the alternate screen,
+/// cleared, with mouse reporting off as its positions wouldn't be a pane's
+const ENTER: &str = "\x1b[?1049h\x1b[?1000l\x1b[?1002l\x1b[?1003l\x1b[?1006l\x1b[2J";
+
+/// Handing the terminal back to a single window, which the daemon repaints
+const LEAVE: &str = "\x1b[0m\x1b[2J\x1b[H\x1b[?1049l";
+
+/// How a pane is split in two
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Split {
+    /// Side by side, with a line between them (`~|`)
+    Beside,
+    /// One above the other (`~-`)
+    Below,
+}
+
+/// Where something is on the terminal, counted from 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub top: u16,
+    pub left: u16,
+    pub cols: u16,
+    pub rows: u16,
+}
+
+impl Rect {
+    /// The two halves of the rect split `split` way, and the line between them
+    fn split(self, split: Split) -> (Rect, Rect, Rect) {
+        match split {
+            Split::Beside => {
+                let first = self.cols / 2;
+                let second = self.cols.saturating_sub(first + 1);
+                (
+                    Rect {
+                        cols: first,
+                        ..self
+                    },
+                    Rect {
+                        left: self.left + first + 1,
+                        cols: second,
+                        ..self
+                    },
+                    Rect {
+                        left: self.left + first,
+                        cols: 1,
+                        ..self
+                    },
+                )
+            }
+            Split::Below => {
+                let first = self.rows / 2;
+                let second = self.rows.saturating_sub(first + 1);
+                (
+                    Rect {
+                        rows: first,
+                        ..self
+                    },
+                    Rect {
+                        top: self.top + first + 1,
+                        rows: second,
+                        ..self
+                    },
+                    Rect {
+                        top: self.top + first,
+                        rows: 1,
+                        ..self
+                    },
+                )
+            }
+        }
+    }
+}
+
+/// The layout: a window's pane, or a pane split in two
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Pane(u32),
+    Split {
+        split: Split,
+        first: Box<Node>,
+        second: Box<Node>,
+    },
+}
+
+impl Node {
+    /// Where each pane and each line between panes goes in `rect`, panes
+    /// in order from the top left
+    fn place(&self, rect: Rect, panes: &mut Vec<(u32, Rect)>, lines: &mut Vec<(Split, Rect)>) {
+        match self {
+            Node::Pane(window) => panes.push((*window, rect)),
+            Node::Split {
+                split,
+                first,
+                second,
+            } => {
+                let (a, b, line) = rect.split(*split);
+                first.place(a, panes, lines);
+                lines.push((*split, line));
+                second.place(b, panes, lines);
+            }
+        }
+    }
+
+    /// Put `node` in place of the pane showing `window`
+    fn replace(&mut self, window: u32, node: &mut Option<Node>) {
+        match self {
+            Node::Pane(id) if *id == window => {
+                if let Some(node) = node.take() {
+                    *self = node;
+                }
+            }
+            Node::Pane(_) => {}
+            Node::Split { first, second, .. } => {
+                first.replace(window, node);
+                second.replace(window, node);
+            }
+        }
+    }
+
+    /// The pane that takes the place of `window`'s if it closes: the nearest
+    /// in the other half of its split
+    fn heir(&self, window: u32) -> Option<u32> {
+        let Node::Split { first, second, .. } = self else {
+            return None;
+        };
+        if **first == Node::Pane(window) {
+            Some(second.edge(true))
+        } else if **second == Node::Pane(window) {
+            Some(first.edge(false))
+        } else {
+            first.heir(window).or_else(|| second.heir(window))
+        }
+    }
+
+    /// The window in the first pane, or the last
+    fn edge(&self, first_pane: bool) -> u32 {
+        match self {
+            Node::Pane(window) => *window,
+            Node::Split { first, .. } if first_pane => first.edge(true),
+            Node::Split { second, .. } => second.edge(false),
+        }
+    }
+
+    /// The layout without the pane showing `window`, its other half taking
+    /// its place; `None` if that was the only pane
+    fn remove(self, window: u32) -> Option<Node> {
+        match self {
+            Node::Pane(id) if id == window => None,
+            Node::Pane(_) => Some(self),
+            Node::Split {
+                split,
+                first,
+                second,
+            } => match (first.remove(window), second.remove(window)) {
+                (Some(first), Some(second)) => Some(Node::Split {
+                    split,
+                    first: Box::new(first),
+                    second: Box::new(second),
+                }),
+                (rest, None) | (None, rest) => rest,
+            },
+        }
+    }
+}
+
+/// A pane's screen and the rows last drawn from it
+struct Pane {
+    screen: Screen,
+    drawn: Vec<String>,
+}
+
+/// What an attached client draws: one window, passed straight through, or
+/// several in split panes
+pub(super) struct Panes {
+    size: (u16, u16),
+    /// The window shown, or the one in the focused pane; input goes to it
+    focus: u32,
+    /// `None` while one window fills the terminal
+    root: Option<Node>,
+    panes: HashMap<u32, Pane>,
+    /// Split asked for, waiting for the daemon to open its window
+    pending: Option<Split>,
+    /// Everything has to be drawn again, lines between panes included
+    redraw: bool,
+    /// Where the cursor was left, and whether it was shown
+    cursor: Option<(u16, u16, bool)>,
+    /// Keyboard modes last set for the focused pane's application
+    keys: Option<(bool, bool, bool)>,
+    /// The daemon has yet to hear about a change of layout
+    relayout: bool,
+    /// The focused pane closed; the daemon has to be told which took over
+    reselect: bool,
+}
+
+impl Panes {
+    pub fn new(size: (u16, u16)) -> Self {
+        Panes {
+            size,
+            focus: 0,
+            root: None,
+            panes: HashMap::new(),
+            pending: None,
+            redraw: false,
+            cursor: None,
+            keys: None,
+            relayout: false,
+            reselect: false,
+        }
+    }
+
+    /// The window shown, or the one in the focused pane
+    pub fn window(&self) -> u32 {
+        self.focus
+    }
+
+    pub fn is_split(&self) -> bool {
+        self.root.is_some()
+    }
+
+    /// Split the focused pane `split` way once the daemon has opened a
+    /// window for the new half. Returns false if the pane is too small.
+    pub fn request_split(&mut self, split: Split) -> bool {
+        let rect = self
+            .layout()
+            .into_iter()
+            .find(|(window, _)| *window == self.focus)
+            .map_or(self.screen_rect(), |(_, rect)| rect);
+        let room = match split {
+            Split::Beside => rect.cols,
+            Split::Below => rect.rows,
+        };
+        if room < 3 {
+            return false;
+        }
+        self.pending = Some(split);
+        true
+    }
+
+    /// The window in the pane after the focused one, going round
+    pub fn next_pane(&self) -> Option<u32> {
+        let layout = self.layout();
+        let at = layout
+            .iter()
+            .position(|(window, _)| *window == self.focus)?;
+        layout
+            .get((at + 1) % layout.len())
+            .map(|(window, _)| *window)
+            .filter(|window| *window != self.focus)
+    }
+
+    /// Follow the terminal to a new size. Split, the layout goes to the
+    /// daemon with the next output; otherwise the caller sends the size.
+    pub fn resize(&mut self, cols: u16, rows: u16) {
+        if self.size == (cols, rows) {
+            return;
+        }
+        self.size = (cols, rows);
+        self.fit();
+        self.redraw = self.is_split();
+        self.relayout |= self.is_split();
+    }
+
+    /// Draw everything again, e.g. after an overlay covered the panes
+    pub fn invalidate(&mut self) {
+        self.redraw = self.is_split();
+    }
+
+    /// Whether the panes have to be drawn again without new output
+    pub fn needs_redraw(&self) -> bool {
+        self.redraw
+    }
+
+    /// Whether the daemon has to be told the layout, once
+    pub fn take_relayout(&mut self) -> bool {
+        std::mem::take(&mut self.relayout)
+    }
+
+    /// The window input has to go to now that the focused pane has closed,
+    /// once
+    pub fn take_reselect(&mut self) -> Option<u32> {
+        std::mem::take(&mut self.reselect).then_some(self.focus)
+    }
+
+    /// The size to give the daemon for window 0: its pane's, or the
+    /// terminal's
+    pub fn main_size(&self) -> (u16, u16) {
+        self.layout()
+            .into_iter()
+            .find(|(window, _)| *window == 0)
+            .map_or(self.size, |(_, rect)| (rect.cols.max(1), rect.rows.max(1)))
+    }
+
+    /// Each pane's window and size, for the `panes` command; empty when not
+    /// split
+    pub fn window_sizes(&self) -> Vec<(u32, u16, u16)> {
+        self.layout()
+            .into_iter()
+            .map(|(window, rect)| (window, rect.cols.max(1), rect.rows.max(1)))
+            .collect()
+    }
+
+    /// Take the complete frames from `decoder` and return what to write to
+    /// the terminal: the shown window's output as it is, or the panes that
+    /// changed drawn from their screens. Events other than window changes
+    /// go to `on_event`.
+    pub fn take_output(
+        &mut self,
+        decoder: &mut FrameDecoder,
+        mut on_event: impl FnMut(&str, &str),
+    ) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        while let Some(frame) = decoder.next_frame()? {
+            match frame {
+                Frame::Data(data) => self.output(0, &data, &mut out),
+                Frame::WindowData { window, data } => self.output(window, &data, &mut out),
+                Frame::Event { name, payload } if name == WINDOW_EVENT => {
+                    if let Ok(window) = payload.parse() {
+                        self.show(window);
+                    }
+                }
+                Frame::Event { name, payload } if name == WINDOW_CLOSED_EVENT => {
+                    if let Ok(window) = payload.parse() {
+                        self.close(window, &mut out);
+                    }
+                }
+                Frame::Event { name, payload } => on_event(&name, &payload),
+                _ => {}
+            }
+        }
+        if self.is_split() {
+            out.extend(self.render());
+        }
+        Ok(out)
+    }
+
+    /// Bytes that bring the terminal up to date with the panes; empty if it
+    /// already shows them
+    pub fn render(&mut self) -> Vec<u8> {
+        let mut panes = Vec::new();
+        let mut lines = Vec::new();
+        if let Some(root) = &self.root {
+            root.place(self.screen_rect(), &mut panes, &mut lines);
+        }
+
+        let mut out = String::new();
+        if std::mem::take(&mut self.redraw) {
+            out.push_str(ENTER);
+            out.push_str("\x1b[0;2m");
+            for (split, line) in &lines {
+                match split {
+                    Split::Beside => {
+                        for row in line.top..line.top + line.rows {
+                            let _ = write!(out, "\x1b[{};{}H\u{2502}", row + 1, line.left + 1);
+                        }
+                    }
+                    Split::Below => {
+                        let _ = write!(out, "\x1b[{};{}H", line.top + 1, line.left + 1);
+                        out.extend(std::iter::repeat_n('\u{2500}', line.cols as usize));
+                    }
+                }
+            }
+            for pane in self.panes.values_mut() {
+                pane.drawn.iter_mut().for_each(String::clear);
+            }
+            self.cursor = None;
+            self.keys = None;
+        }
+
+        let mut row_text = String::new();
+        for (window, rect) in &panes {
+            let Some(pane) = self.panes.get_mut(window) else {
+                continue;
+            };
+            for row in 0..rect.rows {
+                row_text.clear();
+                pane.screen.render_row_padded(&mut row_text, row, rect.cols);
+                let drawn = &mut pane.drawn[row as usize];
+                if *drawn != row_text {
+                    let _ = write!(
+                        out,
+                        "\x1b[{};{}H{}",
+                        rect.top + row + 1,
+                        rect.left + 1,
+                        row_text
+                    );
+                    drawn.clone_from(&row_text);
+                }
+            }
+        }
+
+        // The cursor and keyboard are the focused application's
+        let focused = panes.iter().find(|(window, _)| *window == self.focus);
+        if let Some((pane, rect)) = focused.and_then(|(w, r)| Some((self.panes.get(w)?, r))) {
+            let screen = &pane.screen;
+            let keys = (
+                screen.mode(1),
+                screen.application_keypad(),
+                screen.mode(2004),
+            );
+            if self.keys != Some(keys) {
+                let _ = write!(
+                    out,
+                    "\x1b[?1{}{}\x1b[?2004{}",
+                    if keys.0 { 'h' } else { 'l' },
+                    if keys.1 { "\x1b=" } else { "\x1b>" },
+                    if keys.2 { 'h' } else { 'l' },
+                );
+                self.keys = Some(keys);
+            }
+
+            let (row, col) = screen.cursor();
+            let cursor = (
+                rect.top + row.min(rect.rows.saturating_sub(1)),
+                rect.left + col.min(rect.cols.saturating_sub(1)),
+                screen.cursor_visible(),
+            );
+            if !out.is_empty() || self.cursor != Some(cursor) {
+                let _ = write!(out, "\x1b[0m\x1b[{};{}H", cursor.0 + 1, cursor.1 + 1);
+                out.push_str(if cursor.2 { "\x1b[?25h" } else { "\x1b[?25l" });
+                self.cursor = Some(cursor);
+            }
+        }
+
+        if out.is_empty() {
+            return Vec::new();
+        }
+        let mut bytes = b"\x1b[?25l".to_vec();
+        bytes.extend(out.into_bytes());
+        bytes
+    }
+
+    fn screen_rect(&self) -> Rect {
+        Rect {
+            top: 0,
+            left: 0,
+            cols: self.size.0,
+            rows: self.size.1,
+        }
+    }
+
+    /// Each pane's window and where it goes, in order from the top left
+    fn layout(&self) -> Vec<(u32, Rect)> {
+        let mut panes = Vec::new();
+        if let Some(root) = &self.root {
+            root.place(self.screen_rect(), &mut panes, &mut Vec::new());
+        }
+        panes
+    }
+
+    /// Output from `window`: straight to the terminal if it is the one
+    /// shown, or into its pane's screen
+    fn output(&mut self, window: u32, data: &[u8], out: &mut Vec<u8>) {
+        if !self.is_split() {
+            if window == self.focus {
+                out.extend_from_slice(data);
+            }
+        } else if let Some(pane) = self.panes.get_mut(&window) {
+            pane.screen.process(data);
+        }
+    }
+
+    /// The daemon moved input to `window`: a split waiting for its window, a
+    /// pane to focus, or a window for the focused pane
+    fn show(&mut self, window: u32) {
+        let shown = self.panes.contains_key(&window);
+        match self.pending.take() {
+            Some(split) if !shown => {
+                let halves = Node::Split {
+                    split,
+                    first: Box::new(Node::Pane(self.focus)),
+                    second: Box::new(Node::Pane(window)),
+                };
+                match self.root.as_mut() {
+                    Some(root) => root.replace(self.focus, &mut Some(halves)),
+                    None => self.root = Some(halves),
+                }
+                self.redraw = true;
+                self.relayout = true;
+            }
+            _ if shown => {}
+            _ => {
+                if let Some(root) = self.root.as_mut() {
+                    root.replace(self.focus, &mut Some(Node::Pane(window)));
+                    self.redraw = true;
+                    self.relayout = true;
+                }
+            }
+        }
+        self.focus = window;
+        self.fit();
+    }
+
+    /// `window` has closed; its pane goes, and the split screen with it if
+    /// only one pane is left
+    fn close(&mut self, window: u32, out: &mut Vec<u8>) {
+        let Some(root) = self.root.take() else {
+            return;
+        };
+        if window == self.focus {
+            if let Some(heir) = root.heir(window) {
+                self.focus = heir;
+                self.reselect = true;
+            }
+        }
+        self.root = root.remove(window);
+        if let Some(Node::Pane(last)) = self.root {
+            self.root = None;
+            self.reselect |= self.focus != last;
+            self.focus = last;
+            out.extend_from_slice(LEAVE.as_bytes());
+        }
+        self.redraw = self.is_split();
+        self.relayout = true;
+        self.fit();
+    }
+
+    /// Keep a screen of the right size for each pane, and only for them
+    fn fit(&mut self) {
+        let layout = self.layout();
+        self.panes
+            .retain(|window, _| layout.iter().any(|(shown, _)| shown == window));
+        for (window, rect) in layout {
+            let pane = self.panes.entry(window).or_insert_with(|| Pane {
+                screen: Screen::new(rect.cols, rect.rows),
+                drawn: Vec::new(),
+            });
+            pane.screen.resize(rect.cols, rect.rows);
+            pane.drawn.resize(rect.rows as usize, String::new());
+        }
+    }
+}
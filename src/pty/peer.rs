@@ -0,0 +1,209 @@
+use nix::unistd::{Uid, User};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::os::unix::net::UnixStream;
+
+/// Who is on the other end of a session socket, as the kernel reports it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// The connecting process; not every platform says
+    pub pid: Option<i32>,
+}
+
+impl PeerCredentials {
+    /// The peer's user name, or its uid if it has none
+    pub fn user(&self) -> String {
+        user_name(self.uid)
+    }
+}
+
+/// The name of the user with `uid`, or the uid itself if there isn't one
+pub fn user_name(uid: u32) -> String {
+    User::from_uid(Uid::from_raw(uid))
+        .ok()
+        .flatten()
+        .map(|user| user.name)
+        .unwrap_or_else(|| uid.to_string())
+}
+
+/// The credentials of the process that connected `stream` (SO_PEERCRED)
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn peer_credentials(stream: &UnixStream) -> io::Result<PeerCredentials> {
+    use nix::sys::socket::{getsockopt, sockopt};
+
+    let creds = getsockopt(stream, sockopt::PeerCredentials)?;
+    Ok(PeerCredentials {
+        uid: creds.uid(),
+        gid: creds.gid(),
+        pid: Some(creds.pid()),
+    })
+}
+
+/// The credentials of the process that connected `stream` (LOCAL_PEERCRED,
+/// and LOCAL_PEERPID where there is one)
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly"
+))]
+pub fn peer_credentials(stream: &UnixStream) -> io::Result<PeerCredentials> {
+    use nix::sys::socket::{getsockopt, sockopt};
+
+    let creds = getsockopt(stream, sockopt::LocalPeerCred)?;
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    let pid = getsockopt(stream, sockopt::LocalPeerPid).ok();
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    let pid = None;
+    Ok(PeerCredentials {
+        uid: creds.uid(),
+        gid: creds.groups().first().copied().unwrap_or_default(),
+        pid,
+    })
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly"
+)))]
+pub fn peer_credentials(_stream: &UnixStream) -> io::Result<PeerCredentials> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "peer credentials aren't available on this platform",
+    ))
+}
+
+/// What a user a session is shared with (`nds share`) may do once connected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShareRole {
+    /// Watch the session: no input, nothing that changes it
+    ReadOnly,
+    /// Type into the session as well, and paste and signal it
+    Interactive,
+}
+
+impl ShareRole {
+    /// Whether the role lets keyboard input through to the PTY
+    pub fn allows_input(self) -> bool {
+        self == ShareRole::Interactive
+    }
+
+    /// Whether the role may send the control command `cmd`. Managing the
+    /// session (restarting it, detaching or kicking clients, recording,
+    /// changing its environment or sharing) is left to its owner.
+    pub fn allows(self, cmd: &str) -> bool {
+        match cmd {
+            "resize" | "refresh" | "tty" | "wait" | "subscribe" | "clients" | "scrollback"
+            | "scrollback_search" | "scrollback_prompt" | "capture_screen" | "foreground"
+            | "windows" | "window_select" | "panes" => true,
+            "paste_buffer" | "paste" | "input" | "signal" | "message" | "window_new"
+            | "window_kill" | "broadcast" => self.allows_input(),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ShareRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShareRole::ReadOnly => write!(f, "read-only"),
+            ShareRole::Interactive => write!(f, "interactive"),
+        }
+    }
+}
+
+/// Which users a session's daemon takes connections from: the user it runs
+/// as, root, and those in the `allow_users` config
+#[derive(Debug, Clone)]
+pub struct PeerAccess {
+    owner: u32,
+    allowed: Vec<u32>,
+}
+
+impl PeerAccess {
+    /// Access for the current user plus `allow_users`, given by name or uid.
+    /// Names that don't resolve are reported and left out.
+    pub fn new(allow_users: &[String]) -> Self {
+        let allowed = allow_users
+            .iter()
+            .filter_map(|user| {
+                let uid = user.parse().ok().or_else(|| {
+                    User::from_name(user)
+                        .ok()
+                        .flatten()
+                        .map(|user| user.uid.as_raw())
+                });
+                if uid.is_none() {
+                    eprintln!("Warning: allow_users: no such user '{}'", user);
+                }
+                uid
+            })
+            .collect();
+        PeerAccess {
+            owner: Uid::effective().as_raw(),
+            allowed,
+        }
+    }
+
+    /// Only the current user (and root)
+    pub fn owner_only() -> Self {
+        Self::new(&[])
+    }
+
+    /// Why a connection from `peer` isn't accepted, if it isn't. A peer
+    /// whose credentials can't be read is turned away rather than left to
+    /// the socket's permissions.
+    pub fn check(&self, peer: Option<&PeerCredentials>) -> Result<(), String> {
+        let Some(peer) = peer else {
+            return Err("can't tell who is connecting on this platform".to_string());
+        };
+        if peer.uid == self.owner || peer.uid == 0 || self.allowed.contains(&peer.uid) {
+            return Ok(());
+        }
+        Err(format!(
+            "connections from {} (uid {}) are refused: the session belongs to {}, \
+             who can add {} to \"allow_users\" in config.json to share it",
+            peer.user(),
+            peer.uid,
+            user_name(self.owner),
+            peer.user()
+        ))
+    }
+
+    /// Like `check`, for the socket a session is shared on: the users it is
+    /// shared with get in too, with their role.
+    pub fn check_guest(
+        &self,
+        peer: Option<&PeerCredentials>,
+        shared_with: &BTreeMap<String, ShareRole>,
+    ) -> Result<Option<ShareRole>, String> {
+        let Some(peer) = peer else {
+            return Err("can't tell who is connecting on this platform".to_string());
+        };
+        if self.check(Some(peer)).is_ok() {
+            return Ok(None);
+        }
+        shared_with
+            .get(&peer.user())
+            .copied()
+            .map(Some)
+            .ok_or_else(|| {
+                format!(
+                "connections from {} (uid {}) are refused: {} hasn't shared the session with {}",
+                peer.user(),
+                peer.uid,
+                user_name(self.owner),
+                peer.user()
+            )
+            })
+    }
+}
@@ -0,0 +1,74 @@
+/// Command line of a running process, read from /proc on Linux or `ps` on
+/// macOS. `None` if the process is gone or can't be looked at.
+pub fn command_line(pid: i32) -> Option<Vec<String>> {
+    #[cfg(target_os = "linux")]
+    {
+        let raw = std::fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+        let args: Vec<String> = raw
+            .split(|b| *b == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect();
+        if !args.is_empty() {
+            return Some(args);
+        }
+        // Zombies and kernel threads have no command line, only a name
+        let comm = std::fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+        Some(vec![comm.trim_end().to_string()])
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        use std::process::Command;
+        let output = Command::new("ps")
+            .args(["-p", &pid.to_string(), "-o", "command="])
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        // ps joins the arguments with spaces, so that is all we can split on
+        let args: Vec<String> = String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .map(str::to_string)
+            .collect();
+        (!args.is_empty()).then_some(args)
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "linux")))]
+    {
+        let _ = pid;
+        None
+    }
+}
+
+/// The terminal a running process reads from, e.g. `/dev/pts/3`, found
+/// through /proc on Linux or `ps` on macOS. `None` if it has no terminal or
+/// can't be looked at.
+pub fn tty_of(pid: i32) -> Option<String> {
+    #[cfg(target_os = "linux")]
+    {
+        let path = std::fs::read_link(format!("/proc/{}/fd/0", pid)).ok()?;
+        let path = path.to_string_lossy();
+        let is_tty = path.starts_with("/dev/pts/") || path.starts_with("/dev/tty");
+        is_tty.then(|| path.into_owned())
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        use std::process::Command;
+        let output = Command::new("ps")
+            .args(["-p", &pid.to_string(), "-o", "tty="])
+            .output()
+            .ok()?;
+        let tty = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        // ps shows `??` for a process without a terminal
+        (output.status.success() && !tty.is_empty() && tty != "??").then(|| format!("/dev/{}", tty))
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "linux")))]
+    {
+        let _ = pid;
+        None
+    }
+}
@@ -0,0 +1,580 @@
+//! Framed protocol spoken over a session's Unix socket.
+//!
+//! A client opens with a magic string, what it connects for and a `Hello`
+//! naming its protocol version and capabilities; the daemon answers with its
+//! own `Hello` and both sides speak the lower of the two versions. After that
+//! both exchange frames: a kind byte, a big-endian `u32` payload length and
+//! the payload. Keyboard input and session output travel in `Data` frames, so
+//! nothing a user types or pastes can be mistaken for a command. Output from
+//! a session's other windows travels in `WindowData` frames, which start
+//! with the window's number (see `windows`).
+//!
+//! Clients and daemons from before framing (protocol 0) send raw input and
+//! output with commands and replies embedded as `\x1b]nds:...\x07`. The
+//! daemon still understands such clients (see `parse_nds_command`), and a
+//! `Connection` to such a daemon speaks its language.
+
+use std::io::{self, Read};
+
+use super::windows::WINDOW_EVENT;
+use crate::session::ExitStatus;
+use crate::transport::Transport;
+
+/// Opening bytes of a framed client's hello. Old clients always open with a
+/// command or terminal input, never a NUL, so the daemon can tell them apart.
+pub const HELLO_MAGIC: &[u8; 4] = b"\0NDS";
+
+/// Version of the framed protocol spoken by this build. Version 0 is the
+/// in-band protocol from before framing.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Length of what a framed client sends before its `Hello`: magic and role
+pub const OPENING_LEN: usize = HELLO_MAGIC.len() + 1;
+
+/// Frame header: kind byte and payload length
+const HEADER_LEN: usize = 5;
+
+/// Frames larger than this are refused rather than buffered
+const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+const KIND_HELLO: u8 = b'H';
+const KIND_DATA: u8 = b'D';
+const KIND_COMMAND: u8 = b'C';
+const KIND_REPLY: u8 = b'R';
+const KIND_EXITED: u8 = b'X';
+const KIND_EVENT: u8 = b'E';
+const KIND_WINDOW_DATA: u8 = b'W';
+
+/// Separates a command from its arguments and a reply's command from its payload
+const FIELD_SEPARATOR: u8 = 0;
+
+/// What a framed client connects for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Exchange input and output with the session (`nds attach`, `nds run`)
+    Attach,
+    /// Send one command and read the answer (`nds wait`, `nds clients`...)
+    Control,
+}
+
+impl Role {
+    fn as_byte(self) -> u8 {
+        match self {
+            Role::Attach => b'a',
+            Role::Control => b'c',
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            b'a' => Some(Role::Attach),
+            b'c' => Some(Role::Control),
+            _ => None,
+        }
+    }
+}
+
+/// What each side says about itself when a framed connection opens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    /// Highest protocol version spoken
+    pub version: u8,
+    /// Program and version, e.g. `nds 0.1.0`
+    pub software: String,
+    /// Optional features understood, named after the commands that use them
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    /// This build's hello, offering `capabilities`
+    pub fn ours(capabilities: &[&str]) -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            software: format!("nds {}", env!("CARGO_PKG_VERSION")),
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+}
+
+/// Bytes a client opens a framed connection with, up to its `Hello`
+pub fn client_opening(role: Role) -> [u8; OPENING_LEN] {
+    let [m0, m1, m2, m3] = *HELLO_MAGIC;
+    [m0, m1, m2, m3, role.as_byte()]
+}
+
+/// Parse the role out of a client's opening bytes
+pub fn parse_client_opening(data: &[u8]) -> Option<Role> {
+    if data.len() < OPENING_LEN || !data.starts_with(HELLO_MAGIC) {
+        return None;
+    }
+    Role::from_byte(data[HELLO_MAGIC.len()])
+}
+
+/// One message on a framed connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// Sent by each side when a connection opens
+    Hello(Hello),
+    /// Keyboard input for the session, or output from it
+    Data(Vec<u8>),
+    /// Output from one of the session's windows other than window 0, sent
+    /// to clients that offered `windows`
+    WindowData { window: u32, data: Vec<u8> },
+    /// A request to the daemon, e.g. `resize` with `["80", "24"]`
+    Command { name: String, args: Vec<String> },
+    /// The daemon's answer to a command
+    Reply { name: String, payload: String },
+    /// The session's shell or command exited (sent to `nds wait`)
+    Exited(ExitStatus),
+    /// Something happened in the session, e.g. `output` (sent to subscribers)
+    Event { name: String, payload: String },
+}
+
+impl Frame {
+    pub fn command(name: &str, args: &[&str]) -> Self {
+        Frame::Command {
+            name: name.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        }
+    }
+
+    pub fn reply(name: &str, payload: &str) -> Self {
+        Frame::Reply {
+            name: name.to_string(),
+            payload: payload.to_string(),
+        }
+    }
+
+    pub fn event(name: &str, payload: &str) -> Self {
+        Frame::Event {
+            name: name.to_string(),
+            payload: payload.to_string(),
+        }
+    }
+
+    /// Header of a data frame carrying `len` bytes, for sending the
+    /// payload from a buffer of its own
+    pub fn data_header(len: usize) -> [u8; HEADER_LEN] {
+        let mut header = [KIND_DATA, 0, 0, 0, 0];
+        header[1..].copy_from_slice(&(len as u32).to_be_bytes());
+        header
+    }
+
+    /// Encode as header and payload, ready to be written in one go
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, payload) = match self {
+            Frame::Hello(hello) => {
+                let mut payload = vec![hello.version];
+                payload.extend(join_fields(
+                    std::iter::once(&hello.software).chain(&hello.capabilities),
+                ));
+                (KIND_HELLO, payload)
+            }
+            Frame::Data(data) => (KIND_DATA, data.clone()),
+            Frame::WindowData { window, data } => {
+                let mut payload = window.to_be_bytes().to_vec();
+                payload.extend_from_slice(data);
+                (KIND_WINDOW_DATA, payload)
+            }
+            Frame::Command { name, args } => {
+                (KIND_COMMAND, join_fields(std::iter::once(name).chain(args)))
+            }
+            Frame::Reply { name, payload } => (KIND_REPLY, join_fields([name, payload])),
+            Frame::Event { name, payload } => (KIND_EVENT, join_fields([name, payload])),
+            Frame::Exited(ExitStatus::Code(code)) => {
+                (KIND_EXITED, format!("code:{}", code).into_bytes())
+            }
+            Frame::Exited(ExitStatus::Signal(signal)) => {
+                (KIND_EXITED, format!("signal:{}", signal).into_bytes())
+            }
+        };
+
+        let mut encoded = Vec::with_capacity(HEADER_LEN + payload.len());
+        encoded.push(kind);
+        encoded.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        encoded.extend_from_slice(&payload);
+        encoded
+    }
+
+    fn decode(kind: u8, payload: &[u8]) -> io::Result<Self> {
+        let frame = match kind {
+            KIND_HELLO => {
+                let (&version, rest) = payload
+                    .split_first()
+                    .ok_or_else(|| invalid("empty hello"))?;
+                let mut fields = split_fields(rest)?;
+                let software = fields.remove(0);
+                Frame::Hello(Hello {
+                    version,
+                    software,
+                    capabilities: fields.into_iter().filter(|c| !c.is_empty()).collect(),
+                })
+            }
+            KIND_DATA => Frame::Data(payload.to_vec()),
+            KIND_WINDOW_DATA => match payload.split_first_chunk::<4>() {
+                Some((window, data)) => Frame::WindowData {
+                    window: u32::from_be_bytes(*window),
+                    data: data.to_vec(),
+                },
+                None => return Err(invalid("malformed window data")),
+            },
+            KIND_COMMAND => {
+                let mut fields = split_fields(payload)?;
+                let name = fields.remove(0);
+                Frame::Command { name, args: fields }
+            }
+            KIND_REPLY => match split_fields(payload)?.as_slice() {
+                [name, payload] => Frame::reply(name, payload),
+                _ => return Err(invalid("malformed reply")),
+            },
+            KIND_EVENT => match split_fields(payload)?.as_slice() {
+                [name, payload] => Frame::event(name, payload),
+                _ => return Err(invalid("malformed event")),
+            },
+            KIND_EXITED => {
+                let text = std::str::from_utf8(payload).map_err(|_| invalid("malformed exit"))?;
+                let status = match text.split_once(':') {
+                    Some(("code", code)) => code.parse().ok().map(ExitStatus::Code),
+                    Some(("signal", signal)) => signal.parse().ok().map(ExitStatus::Signal),
+                    _ => None,
+                };
+                Frame::Exited(status.ok_or_else(|| invalid("malformed exit"))?)
+            }
+            _ => return Err(invalid(&format!("unknown frame kind {:#04x}", kind))),
+        };
+        Ok(frame)
+    }
+}
+
+fn join_fields<'a>(fields: impl IntoIterator<Item = &'a String>) -> Vec<u8> {
+    let mut joined = Vec::new();
+    for (i, field) in fields.into_iter().enumerate() {
+        if i > 0 {
+            joined.push(FIELD_SEPARATOR);
+        }
+        joined.extend_from_slice(field.as_bytes());
+    }
+    joined
+}
+
+fn split_fields(payload: &[u8]) -> io::Result<Vec<String>> {
+    payload
+        .split(|&b| b == FIELD_SEPARATOR)
+        .map(|field| {
+            String::from_utf8(field.to_vec()).map_err(|_| invalid("field is not valid UTF-8"))
+        })
+        .collect()
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reassembles frames from whatever chunks the socket hands over
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    /// Reading from a daemon from before framing
+    legacy: bool,
+}
+
+impl FrameDecoder {
+    /// Decoder for a daemon from before framing: its output is turned into
+    /// `Data` frames, and embedded replies and exit notices into their own
+    pub fn legacy() -> Self {
+        FrameDecoder {
+            buf: Vec::new(),
+            legacy: true,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// The next complete frame, if one has arrived. An error means the peer
+    /// isn't speaking the protocol and the connection should be dropped.
+    pub fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        if self.legacy {
+            return Ok(self.next_legacy_frame());
+        }
+        if self.buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([self.buf[1], self.buf[2], self.buf[3], self.buf[4]]) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(invalid(&format!("frame of {} bytes is too large", len)));
+        }
+        if self.buf.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        let frame = Frame::decode(self.buf[0], &self.buf[HEADER_LEN..HEADER_LEN + len]);
+        self.buf.drain(..HEADER_LEN + len);
+        frame.map(Some)
+    }
+
+    fn next_legacy_frame(&mut self) -> Option<Frame> {
+        if self.buf.is_empty() {
+            return None;
+        }
+
+        let Some(start) = find(&self.buf, LEGACY_MARKER) else {
+            // Hold back what could be the start of a reply split across reads
+            let keep = (2..LEGACY_MARKER.len())
+                .rev()
+                .find(|&n| self.buf.ends_with(&LEGACY_MARKER[..n]))
+                .unwrap_or(0);
+            let data: Vec<u8> = self.buf.drain(..self.buf.len() - keep).collect();
+            return (!data.is_empty()).then_some(Frame::Data(data));
+        };
+        if start > 0 {
+            return Some(Frame::Data(self.buf.drain(..start).collect()));
+        }
+
+        let Some(end) = self.buf.iter().position(|&b| b == b'\x07') else {
+            if self.buf.len() > MAX_LEGACY_MESSAGE {
+                return Some(Frame::Data(std::mem::take(&mut self.buf)));
+            }
+            return None;
+        };
+        let message: Vec<u8> = self.buf.drain(..=end).collect();
+        let text = String::from_utf8_lossy(&message[LEGACY_MARKER.len()..end]);
+        let frame = match text.split_once(':') {
+            Some(("reply", reply)) => reply
+                .split_once(':')
+                .map(|(name, payload)| Frame::reply(name, payload)),
+            Some(("exited", status)) => match status.split_once(':') {
+                Some(("code", code)) => code.parse().ok().map(ExitStatus::Code),
+                Some(("signal", signal)) => signal.parse().ok().map(ExitStatus::Signal),
+                _ => None,
+            }
+            .map(Frame::Exited),
+            _ => None,
+        };
+        Some(frame.unwrap_or(Frame::Data(message)))
+    }
+
+    /// Session output from every complete frame, skipping all other frames
+    pub fn take_data(&mut self) -> io::Result<Vec<u8>> {
+        self.take_data_with_events(|_, _| {})
+    }
+
+    /// Session output from every complete frame, passing events to
+    /// `on_event` as they come and skipping all other frames
+    pub fn take_data_with_events(
+        &mut self,
+        on_event: impl FnMut(&str, &str),
+    ) -> io::Result<Vec<u8>> {
+        self.take_window_data(&mut 0, on_event)
+    }
+
+    /// Output of the window being shown from every complete frame, like
+    /// `take_data_with_events`. A `window` event changes which one that is;
+    /// output from the others is left out.
+    pub fn take_window_data(
+        &mut self,
+        shown: &mut u32,
+        mut on_event: impl FnMut(&str, &str),
+    ) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        while let Some(frame) = self.next_frame()? {
+            match frame {
+                Frame::Data(chunk) if *shown == 0 => data.extend_from_slice(&chunk),
+                Frame::WindowData {
+                    window,
+                    data: chunk,
+                } if window == *shown => data.extend_from_slice(&chunk),
+                Frame::Event { name, payload } if name == WINDOW_EVENT => {
+                    if let Ok(window) = payload.parse() {
+                        *shown = window;
+                    }
+                }
+                Frame::Event { name, payload } => on_event(&name, &payload),
+                _ => {}
+            }
+        }
+        Ok(data)
+    }
+}
+
+/// Read until a whole frame has arrived. Returns `None` if the peer hung up
+/// first.
+pub fn read_frame(reader: &mut impl Read, decoder: &mut FrameDecoder) -> io::Result<Option<Frame>> {
+    let mut buf = [0u8; 4096];
+    loop {
+        if let Some(frame) = decoder.next_frame()? {
+            return Ok(Some(frame));
+        }
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(None),
+            Ok(n) => decoder.push(&buf[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Read exactly one frame, leaving whatever follows it in the socket
+pub fn read_single_frame(reader: &mut impl Read) -> io::Result<Frame> {
+    let mut header = [0u8; HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(invalid(&format!("frame of {} bytes is too large", len)));
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Frame::decode(header[0], &payload)
+}
+
+/// Start of commands, replies and exit notices embedded in the byte stream
+/// by clients and daemons from before framing
+const LEGACY_MARKER: &[u8] = b"\x1b]nds:";
+
+/// Embedded messages longer than this are taken to be plain output
+const MAX_LEGACY_MESSAGE: usize = 8192;
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Encode a client's frame the way clients did before framing
+fn encode_legacy(frame: &Frame) -> Vec<u8> {
+    match frame {
+        Frame::Data(data) => data.clone(),
+        Frame::Command { name, args } => {
+            let mut fields = vec![name.as_str()];
+            fields.extend(args.iter().map(String::as_str));
+            format!("\x1b]nds:{}\x07", fields.join(":")).into_bytes()
+        }
+        // Daemons from before framing never hear these from a client
+        Frame::Hello(_)
+        | Frame::WindowData { .. }
+        | Frame::Reply { .. }
+        | Frame::Exited(_)
+        | Frame::Event { .. } => Vec::new(),
+    }
+}
+
+/// Client end of a connection to a session's daemon, speaking whichever
+/// protocol the two agreed on
+#[derive(Debug)]
+pub struct Connection {
+    stream: Box<dyn Transport>,
+    protocol: u8,
+    daemon: Option<Hello>,
+}
+
+impl Connection {
+    /// Open a framed connection: send our hello and wait for the daemon's
+    pub fn handshake(stream: impl Transport + 'static, role: Role) -> io::Result<Self> {
+        Self::handshake_offering(stream, role, &[])
+    }
+
+    /// Like `handshake`, telling the daemon the client understands
+    /// `capabilities`
+    pub fn handshake_offering(
+        stream: impl Transport + 'static,
+        role: Role,
+        capabilities: &[&str],
+    ) -> io::Result<Self> {
+        let stream: Box<dyn Transport> = Box::new(stream);
+        let mut opening = client_opening(role).to_vec();
+        opening.extend(Frame::Hello(Hello::ours(capabilities)).encode());
+        // A daemon that won't talk to this user says why and hangs up, maybe
+        // before the hello is all written, so read its answer either way
+        let sent = stream.send(&opening);
+        let answer = read_single_frame(&mut &*stream);
+        if sent.is_err() && !matches!(&answer, Ok(Frame::Reply { name, .. }) if name == "refused") {
+            sent?;
+        }
+
+        match answer? {
+            Frame::Hello(hello) if hello.version > 0 => Ok(Connection {
+                stream,
+                protocol: hello.version.min(PROTOCOL_VERSION),
+                daemon: Some(hello),
+            }),
+            // The daemon won't talk to this user
+            Frame::Reply { name, payload } if name == "refused" => {
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, payload))
+            }
+            _ => Err(invalid("session did not answer with a hello")),
+        }
+    }
+
+    /// Connection to a daemon from before framing, which gets commands
+    /// embedded in the input
+    pub fn legacy(stream: impl Transport + 'static) -> Self {
+        Connection {
+            stream: Box::new(stream),
+            protocol: 0,
+            daemon: None,
+        }
+    }
+
+    /// Protocol version agreed with the daemon; 0 before framing
+    pub fn protocol(&self) -> u8 {
+        self.protocol
+    }
+
+    /// The daemon's hello, unless it predates framing
+    pub fn daemon(&self) -> Option<&Hello> {
+        self.daemon.as_ref()
+    }
+
+    /// Whether the daemon understands `capability`. Daemons from before
+    /// framing don't say, so everything is worth a try with them.
+    pub fn supports(&self, capability: &str) -> bool {
+        self.daemon
+            .as_ref()
+            .is_none_or(|hello| hello.capabilities.iter().any(|c| c == capability))
+    }
+
+    /// Send a frame in a single write, so frames from different threads
+    /// never interleave
+    pub fn send(&self, frame: &Frame) -> io::Result<()> {
+        let encoded = if self.protocol == 0 {
+            encode_legacy(frame)
+        } else {
+            frame.encode()
+        };
+        self.stream.send(&encoded)
+    }
+
+    /// A decoder for what the daemon sends on this connection
+    pub fn decoder(&self) -> FrameDecoder {
+        if self.protocol == 0 {
+            FrameDecoder::legacy()
+        } else {
+            FrameDecoder::default()
+        }
+    }
+
+    /// Read until a whole frame has arrived; `None` if the daemon hung up
+    pub fn read_frame(&self, decoder: &mut FrameDecoder) -> io::Result<Option<Frame>> {
+        read_frame(&mut &*self.stream, decoder)
+    }
+
+    /// What the connection runs over, for timeouts and shutting it down
+    pub fn stream(&self) -> &dyn Transport {
+        &*self.stream
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Connection {
+            stream: self.stream.try_clone()?,
+            protocol: self.protocol,
+            daemon: self.daemon.clone(),
+        })
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.recv(buf)
+    }
+}
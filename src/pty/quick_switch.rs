@@ -0,0 +1,228 @@
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+use crate::error::Result;
+use crate::history_v2::{HistoryEntry, HistoryQuery, SessionEvent, SessionHistory};
+use crate::manager::SessionManager;
+use crate::session::Session;
+
+use super::session_switcher::SwitchResult;
+use super::terminal::get_terminal_size;
+
+/// Number of sessions offered by the quick switcher
+pub const QUICK_SWITCH_LIMIT: usize = 5;
+
+/// How long after the last key press the current selection is confirmed
+const CONFIRM_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Alt-tab style overlay drawn on the bottom row of the attached terminal
+pub struct QuickSwitcher<'a> {
+    current_session: &'a Session,
+    stdin_fd: RawFd,
+}
+
+impl<'a> QuickSwitcher<'a> {
+    pub fn new(current_session: &'a Session, stdin_fd: RawFd) -> Self {
+        Self {
+            current_session,
+            stdin_fd,
+        }
+    }
+
+    /// Show the overlay and cycle through recent sessions until the
+    /// selection is confirmed (Enter or timeout) or cancelled (Esc)
+    pub fn run(&self) -> Result<SwitchResult> {
+        let sessions = SessionManager::list_sessions()?;
+        let history = SessionHistory::query(&attaches()).unwrap_or_default();
+        let candidates = recent_sessions(&self.current_session.id, &sessions, &history);
+
+        let (cols, rows) = get_terminal_size().unwrap_or((80, 24));
+
+        if candidates.is_empty() {
+            draw_line(rows, "\x1b[7m No other sessions \x1b[0m")?;
+            std::thread::sleep(Duration::from_millis(700));
+            clear_line(rows)?;
+            return Ok(SwitchResult::Continue);
+        }
+
+        // The ~Tab that opened the overlay already selects the first entry
+        let mut selected = 0;
+        let mut last_press = Instant::now();
+
+        loop {
+            draw_line(rows, &render_overlay(&candidates, selected, cols as usize))?;
+
+            let remaining = CONFIRM_TIMEOUT.saturating_sub(last_press.elapsed());
+            if remaining.is_zero() {
+                break;
+            }
+
+            let mut buf = [0u8; 16];
+            let n = match self.read_with_timeout(&mut buf, remaining)? {
+                Some(n) => n,
+                None => break, // Timed out: confirm current selection
+            };
+            last_press = Instant::now();
+
+            match &buf[..n] {
+                b"\t" => selected = (selected + 1) % candidates.len(),
+                b"\x1b[Z" => selected = (selected + candidates.len() - 1) % candidates.len(),
+                b"\r" | b"\n" => break,
+                b"\x1b" | b"\x03" | b"q" => {
+                    clear_line(rows)?;
+                    return Ok(SwitchResult::Continue);
+                }
+                _ => {}
+            }
+        }
+
+        clear_line(rows)?;
+        Ok(SwitchResult::SwitchTo(candidates[selected].id.clone()))
+    }
+
+    /// Switch straight to the session attached before this one, or say
+    /// briefly that there isn't one
+    pub fn last(&self) -> Result<SwitchResult> {
+        let sessions = SessionManager::list_sessions()?;
+        let history = SessionHistory::query(&attaches()).unwrap_or_default();
+        if let Some(session) = previous_session(&self.current_session.id, &sessions, &history) {
+            return Ok(SwitchResult::SwitchTo(session.id));
+        }
+
+        let (_, rows) = get_terminal_size().unwrap_or((80, 24));
+        draw_line(rows, "\x1b[7m No previous session \x1b[0m")?;
+        std::thread::sleep(Duration::from_millis(700));
+        clear_line(rows)?;
+        Ok(SwitchResult::Continue)
+    }
+
+    fn read_with_timeout(&self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        let mut fds = [libc::pollfd {
+            fd: self.stdin_fd,
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), 1, timeout.as_millis() as libc::c_int) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(Some(0));
+            }
+            return Err(err);
+        }
+        if ret == 0 {
+            return Ok(None);
+        }
+
+        let n = unsafe { libc::read(self.stdin_fd, buf.as_mut_ptr() as *mut _, buf.len()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Some(n as usize))
+    }
+}
+
+/// Pick the sessions offered by the quick switcher: most recently attached
+/// first, then the remaining live sessions from newest to oldest
+pub fn recent_sessions(
+    current_id: &str,
+    sessions: &[Session],
+    history: &[HistoryEntry],
+) -> Vec<Session> {
+    let mut seen = HashSet::new();
+    seen.insert(current_id.to_string());
+
+    let mut ordered: Vec<Session> = Vec::new();
+
+    let mut attached: Vec<&HistoryEntry> = history
+        .iter()
+        .filter(|e| matches!(e.event, SessionEvent::Attached))
+        .collect();
+    attached.sort_by_key(|e| Reverse(e.timestamp));
+
+    for entry in attached {
+        if seen.contains(&entry.session_id) {
+            continue;
+        }
+        if let Some(session) = sessions.iter().find(|s| s.id == entry.session_id) {
+            seen.insert(session.id.clone());
+            ordered.push(session.clone());
+        }
+    }
+
+    let mut rest: Vec<&Session> = sessions.iter().filter(|s| !seen.contains(&s.id)).collect();
+    rest.sort_by_key(|s| Reverse(s.created_at));
+    ordered.extend(rest.into_iter().cloned());
+
+    ordered.truncate(QUICK_SWITCH_LIMIT);
+    ordered
+}
+
+/// The live session other than the current one that was attached most
+/// recently, for `~l`
+pub fn previous_session(
+    current_id: &str,
+    sessions: &[Session],
+    history: &[HistoryEntry],
+) -> Option<Session> {
+    history
+        .iter()
+        .filter(|e| matches!(e.event, SessionEvent::Attached) && e.session_id != current_id)
+        .filter_map(|e| {
+            let session = sessions.iter().find(|s| s.id == e.session_id)?;
+            Some((e.timestamp, session))
+        })
+        .max_by_key(|(at, _)| *at)
+        .map(|(_, session)| session.clone())
+}
+
+/// Render the overlay line, highlighting the selected entry and keeping the
+/// visible text within the terminal width
+pub fn render_overlay(sessions: &[Session], selected: usize, cols: usize) -> String {
+    let mut out = String::from("\x1b[7m switch \x1b[0m");
+    let mut width = " switch ".len();
+
+    for (i, session) in sessions.iter().enumerate() {
+        let label = match &session.name {
+            Some(name) => name.clone(),
+            None => session.id.chars().take(8).collect(),
+        };
+        let item = format!(" {}:{} ", i + 1, label);
+        let item_width = item.chars().count() + 1;
+        if width + item_width > cols {
+            break;
+        }
+        width += item_width;
+
+        out.push(' ');
+        if i == selected {
+            out.push_str(&format!("\x1b[1;7m{}\x1b[0m", item));
+        } else {
+            out.push_str(&item);
+        }
+    }
+
+    out
+}
+
+/// The attaches to live sessions, which is all the switcher looks at
+fn attaches() -> HistoryQuery {
+    HistoryQuery {
+        event: Some(SessionEvent::Attached.kind().to_string()),
+        ..Default::default()
+    }
+}
+
+fn draw_line(row: u16, content: &str) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    // Save cursor, draw on the bottom row, restore cursor
+    write!(stdout, "\x1b7\x1b[{};1H\x1b[2K{}\x1b8", row, content)?;
+    stdout.flush()
+}
+
+fn clear_line(row: u16) -> io::Result<()> {
+    draw_line(row, "")
+}
@@ -0,0 +1,172 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use super::screen::Screen;
+
+/// How big a recording file gets before `nds record` starts the next one
+/// when `--max-size` is not given
+pub const DEFAULT_RECORDING_MAX_BYTES: u64 = 100 * 1024 * 1024;
+
+/// What a stopped recording left behind, for `nds record --stop`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordingSummary {
+    /// The first file; later ones are numbered after it
+    pub path: PathBuf,
+    /// How many files the recording was rotated across
+    pub files: u32,
+    /// How long it ran for
+    pub seconds: f64,
+}
+
+/// A session's output being written to an asciicast v2 file, like
+/// `asciinema rec`. Each file starts with the screen as it was, so every one
+/// plays on its own once the recording rotates.
+pub(super) struct Recording {
+    path: PathBuf,
+    title: Option<String>,
+    max_bytes: u64,
+    started: Instant,
+    file: File,
+    part: u32,
+    /// When the current file was started; event times count from here
+    file_started: Instant,
+    written: u64,
+    size: (u16, u16),
+}
+
+impl Recording {
+    /// Start recording to `path`, truncating it, and rotate to a new file
+    /// whenever one reaches `max_bytes` (0 never does)
+    pub fn start(
+        path: &Path,
+        title: Option<String>,
+        max_bytes: u64,
+        screen: &Screen,
+    ) -> io::Result<Self> {
+        let file = create(path)?;
+        let now = Instant::now();
+        let mut recording = Self {
+            path: path.to_path_buf(),
+            title,
+            max_bytes,
+            started: now,
+            file,
+            part: 0,
+            file_started: now,
+            written: 0,
+            size: screen.size(),
+        };
+        recording.write_header(screen)?;
+        Ok(recording)
+    }
+
+    /// The file being written to now
+    pub fn current_path(&self) -> PathBuf {
+        part_path(&self.path, self.part)
+    }
+
+    /// Record output the screen has already taken in
+    pub fn output(&mut self, data: &[u8], screen: &Screen) -> io::Result<()> {
+        self.follow(screen)?;
+        self.event("o", &String::from_utf8_lossy(data))?;
+        if self.max_bytes > 0 && self.written >= self.max_bytes {
+            self.rotate(screen)?;
+        }
+        Ok(())
+    }
+
+    /// Record the PTY changing size, if it has
+    pub fn follow(&mut self, screen: &Screen) -> io::Result<()> {
+        let size = screen.size();
+        if size == self.size {
+            return Ok(());
+        }
+        self.size = size;
+        self.event("r", &format!("{}x{}", size.0, size.1))
+    }
+
+    pub fn summary(&self) -> RecordingSummary {
+        RecordingSummary {
+            path: self.path.clone(),
+            files: self.part + 1,
+            seconds: self.started.elapsed().as_secs_f64(),
+        }
+    }
+
+    /// Carry on in the next numbered file
+    fn rotate(&mut self, screen: &Screen) -> io::Result<()> {
+        let next = part_path(&self.path, self.part + 1);
+        self.file = create(&next)?;
+        self.part += 1;
+        self.file_started = Instant::now();
+        self.written = 0;
+        self.size = screen.size();
+        self.write_header(screen)
+    }
+
+    /// The asciicast header, then the screen so far as the first output
+    fn write_header(&mut self, screen: &Screen) -> io::Result<()> {
+        let (width, height) = self.size;
+        let mut header = serde_json::json!({
+            "version": 2,
+            "width": width,
+            "height": height,
+            "timestamp": chrono::Utc::now().timestamp(),
+            "env": {
+                "TERM": std::env::var("TERM").unwrap_or_else(|_| "xterm-256color".to_string()),
+                "SHELL": std::env::var("SHELL").unwrap_or_default(),
+            },
+        });
+        if let Some(title) = &self.title {
+            header["title"] = title.clone().into();
+        }
+        self.write_line(&header.to_string())?;
+        self.event("o", &String::from_utf8_lossy(&screen.repaint()))
+    }
+
+    fn event(&mut self, kind: &str, data: &str) -> io::Result<()> {
+        // Microseconds are as fine as asciinema goes
+        let time = (self.file_started.elapsed().as_secs_f64() * 1e6).round() / 1e6;
+        let line = serde_json::to_string(&(time, kind, data))?;
+        self.write_line(&line)
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        // One write per event, so a reader never sees half of one
+        let mut bytes = Vec::with_capacity(line.len() + 1);
+        bytes.extend_from_slice(line.as_bytes());
+        bytes.push(b'\n');
+        self.file.write_all(&bytes)?;
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+/// Recordings hold whatever went past on screen, so only the owner reads them
+fn create(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+}
+
+/// The file for part `part` of a recording at `path`: `path` itself, then
+/// `deploy.1.cast`, `deploy.2.cast` and so on
+pub fn part_path(path: &Path, part: u32) -> PathBuf {
+    if part == 0 {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, part, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, part),
+    };
+    path.with_file_name(name)
+}
@@ -0,0 +1,87 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// How a client's screen is brought up to date when it attaches or returns
+/// from the switcher/scrollback viewer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RedrawStrategy {
+    /// Send Ctrl+L to the application and let it repaint
+    CtrlL,
+    /// Re-apply the application's terminal modes (alternate screen, cursor
+    /// keys, mouse, bracketed paste, cursor visibility), then send Ctrl+L
+    ModeReplay,
+    /// Repaint the client from the daemon's copy of the screen, modes
+    /// included, without involving the application
+    VtSnapshot,
+    /// Don't touch the client or the application
+    None,
+}
+
+impl RedrawStrategy {
+    pub const ALL: [RedrawStrategy; 4] = [
+        RedrawStrategy::CtrlL,
+        RedrawStrategy::ModeReplay,
+        RedrawStrategy::VtSnapshot,
+        RedrawStrategy::None,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RedrawStrategy::CtrlL => "ctrl-l",
+            RedrawStrategy::ModeReplay => "mode-replay",
+            RedrawStrategy::VtSnapshot => "vt-snapshot",
+            RedrawStrategy::None => "none",
+        }
+    }
+
+    /// Whether the client is repainted from the daemon's screen
+    pub fn repaints_screen(&self) -> bool {
+        matches!(self, RedrawStrategy::VtSnapshot)
+    }
+
+    /// Whether the application's terminal modes are re-sent to the client
+    pub fn replays_modes(&self) -> bool {
+        matches!(
+            self,
+            RedrawStrategy::ModeReplay | RedrawStrategy::VtSnapshot
+        )
+    }
+
+    /// Whether the application is asked to repaint with Ctrl+L
+    pub fn nudges_application(&self) -> bool {
+        matches!(self, RedrawStrategy::CtrlL | RedrawStrategy::ModeReplay)
+    }
+
+    /// The strategy for a session: its own setting, else a repaint from
+    /// the daemon's screen, which works whatever the application is
+    pub fn resolve(configured: Option<RedrawStrategy>) -> Self {
+        configured.unwrap_or(RedrawStrategy::VtSnapshot)
+    }
+}
+
+impl fmt::Display for RedrawStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for RedrawStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|strategy| strategy.as_str() == s)
+            .ok_or_else(|| {
+                let names: Vec<_> = Self::ALL.iter().map(|s| s.as_str()).collect();
+                format!(
+                    "unknown redraw strategy '{}' (expected one of: {})",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
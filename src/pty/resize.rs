@@ -0,0 +1,97 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// How the PTY is sized when clients with different terminal sizes attach
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum ResizePolicy {
+    /// Fit the smallest client, so every client sees the whole screen
+    #[default]
+    Smallest,
+    /// Fit the largest client; smaller clients see a clipped screen
+    Largest,
+    /// Follow the client that attached most recently
+    LastAttached,
+    /// Keep the PTY at a fixed size whatever the clients are
+    Fixed { cols: u16, rows: u16 },
+}
+
+impl ResizePolicy {
+    /// Size for the PTY given the attached clients' (cols, rows) in the order
+    /// they attached. `None` leaves the PTY as it is.
+    pub fn size_for(&self, clients: &[(u16, u16)]) -> Option<(u16, u16)> {
+        match self {
+            ResizePolicy::Smallest => clients
+                .iter()
+                .copied()
+                .reduce(|(c1, r1), (c2, r2)| (c1.min(c2), r1.min(r2))),
+            ResizePolicy::Largest => clients
+                .iter()
+                .copied()
+                .reduce(|(c1, r1), (c2, r2)| (c1.max(c2), r1.max(r2))),
+            ResizePolicy::LastAttached => clients.last().copied(),
+            ResizePolicy::Fixed { cols, rows } => Some((*cols, *rows)),
+        }
+    }
+
+    /// The size a fixed policy pins the PTY at
+    pub fn fixed_size(&self) -> Option<(u16, u16)> {
+        match self {
+            ResizePolicy::Fixed { cols, rows } => Some((*cols, *rows)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ResizePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResizePolicy::Smallest => f.write_str("smallest"),
+            ResizePolicy::Largest => f.write_str("largest"),
+            ResizePolicy::LastAttached => f.write_str("last-attached"),
+            ResizePolicy::Fixed { cols, rows } => write!(f, "{}x{}", cols, rows),
+        }
+    }
+}
+
+impl FromStr for ResizePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "smallest" => return Ok(ResizePolicy::Smallest),
+            "largest" => return Ok(ResizePolicy::Largest),
+            "last-attached" => return Ok(ResizePolicy::LastAttached),
+            _ => {}
+        }
+
+        let fixed = s.strip_prefix("fixed ").unwrap_or(s);
+        fixed
+            .split_once('x')
+            .and_then(|(cols, rows)| Some((cols.parse().ok()?, rows.parse().ok()?)))
+            .filter(|&(cols, rows): &(u16, u16)| cols > 0 && rows > 0)
+            .map(|(cols, rows)| ResizePolicy::Fixed { cols, rows })
+            .ok_or_else(|| {
+                format!(
+                    "unknown resize policy '{}' (expected smallest, largest, last-attached or COLSxROWS)",
+                    s
+                )
+            })
+    }
+}
+
+impl From<ResizePolicy> for String {
+    fn from(policy: ResizePolicy) -> Self {
+        policy.to_string()
+    }
+}
+
+impl TryFrom<String> for ResizePolicy {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
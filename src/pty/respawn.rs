@@ -0,0 +1,95 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::session::ExitStatus;
+
+/// What the daemon does when the session's shell or command exits
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RespawnPolicy {
+    /// Leave the session with its exit status until it is killed
+    #[default]
+    Never,
+    /// Start it again unless it exited with code 0
+    OnFailure,
+    /// Start it again whenever it exits
+    Always,
+}
+
+impl RespawnPolicy {
+    pub const ALL: [RespawnPolicy; 3] = [
+        RespawnPolicy::Never,
+        RespawnPolicy::OnFailure,
+        RespawnPolicy::Always,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RespawnPolicy::Never => "never",
+            RespawnPolicy::OnFailure => "on-failure",
+            RespawnPolicy::Always => "always",
+        }
+    }
+
+    pub fn should_respawn(&self, status: ExitStatus) -> bool {
+        match self {
+            RespawnPolicy::Never => false,
+            RespawnPolicy::OnFailure => status != ExitStatus::Code(0),
+            RespawnPolicy::Always => true,
+        }
+    }
+}
+
+impl fmt::Display for RespawnPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for RespawnPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|policy| policy.as_str() == s)
+            .ok_or_else(|| {
+                let names: Vec<_> = Self::ALL.iter().map(|p| p.as_str()).collect();
+                format!(
+                    "unknown respawn policy '{}' (expected one of: {})",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// Delay before the first respawn; doubled for each quick failure after it
+const INITIAL_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+/// A child that ran at least this long is considered to have started fine
+const STABLE_UPTIME: Duration = Duration::from_secs(60);
+
+/// Exponential backoff between respawns so a command that fails straight
+/// away doesn't spin the daemon
+#[derive(Debug, Default)]
+pub struct RespawnBackoff {
+    quick_failures: u32,
+}
+
+impl RespawnBackoff {
+    /// Delay before respawning a child that ran for `uptime`
+    pub fn next_delay(&mut self, uptime: Duration) -> Duration {
+        if uptime >= STABLE_UPTIME {
+            self.quick_failures = 0;
+        }
+        let delay = INITIAL_DELAY
+            .saturating_mul(1 << self.quick_failures.min(16))
+            .min(MAX_DELAY);
+        self.quick_failures += 1;
+        delay
+    }
+}
@@ -0,0 +1,1057 @@
+use std::fmt::Write;
+
+use unicode_width::UnicodeWidthChar;
+
+/// Colour of a cell's text or background
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Color {
+    #[default]
+    Default,
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+/// How a cell is drawn: colours plus SGR attributes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Style {
+    pub fg: Color,
+    pub bg: Color,
+    pub attrs: u8,
+}
+
+impl Style {
+    pub const BOLD: u8 = 1 << 0;
+    pub const DIM: u8 = 1 << 1;
+    pub const ITALIC: u8 = 1 << 2;
+    pub const UNDERLINE: u8 = 1 << 3;
+    pub const BLINK: u8 = 1 << 4;
+    pub const REVERSE: u8 = 1 << 5;
+    pub const HIDDEN: u8 = 1 << 6;
+    pub const STRIKE: u8 = 1 << 7;
+
+    const SGR_CODES: [(u8, u8); 8] = [
+        (Style::BOLD, 1),
+        (Style::DIM, 2),
+        (Style::ITALIC, 3),
+        (Style::UNDERLINE, 4),
+        (Style::BLINK, 5),
+        (Style::REVERSE, 7),
+        (Style::HIDDEN, 8),
+        (Style::STRIKE, 9),
+    ];
+
+    /// Append the SGR sequence that sets this style from scratch
+    fn write_sgr(&self, out: &mut String) {
+        out.push_str("\x1b[0");
+        for (attr, code) in Style::SGR_CODES {
+            if self.attrs & attr != 0 {
+                let _ = write!(out, ";{}", code);
+            }
+        }
+        write_color(out, self.fg, 30);
+        write_color(out, self.bg, 40);
+        out.push('m');
+    }
+
+    /// A blank cell's style after an erase: only the background is kept
+    fn erased(&self) -> Style {
+        Style {
+            bg: self.bg,
+            ..Style::default()
+        }
+    }
+}
+
+fn write_color(out: &mut String, color: Color, base: u8) {
+    let _ = match color {
+        Color::Default => Ok(()),
+        Color::Indexed(n) if n < 8 => write!(out, ";{}", base + n),
+        Color::Indexed(n) if n < 16 => write!(out, ";{}", base + 60 + n - 8),
+        Color::Indexed(n) => write!(out, ";{};5;{}", base + 8, n),
+        Color::Rgb(r, g, b) => write!(out, ";{};2;{};{};{}", base + 8, r, g, b),
+    };
+}
+
+/// One character cell. A wide character takes two cells; the right one is a
+/// spacer with width 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub ch: char,
+    pub width: u8,
+    pub style: Style,
+}
+
+impl Cell {
+    fn blank(style: Style) -> Self {
+        Self {
+            ch: ' ',
+            width: 1,
+            style,
+        }
+    }
+}
+
+/// Where the parser is inside an escape sequence or UTF-8 character
+#[derive(Debug, Default)]
+enum State {
+    #[default]
+    Ground,
+    Utf8 {
+        bytes: [u8; 4],
+        len: usize,
+        need: usize,
+    },
+    Escape,
+    Charset,
+    Csi(Csi),
+    Osc,
+    OscEscape,
+    Str,
+    StrEscape,
+}
+
+#[derive(Debug, Default)]
+struct Csi {
+    params: Vec<u16>,
+    current: Option<u16>,
+    private: Option<u8>,
+    intermediate: Option<u8>,
+}
+
+impl Csi {
+    /// Parameter `i`, with 0 or a missing value meaning `default`
+    fn param(&self, i: usize, default: u16) -> u16 {
+        match self.params.get(i) {
+            Some(&n) if n > 0 => n,
+            _ => default,
+        }
+    }
+}
+
+/// The daemon's copy of what the PTY's screen looks like, kept up to date
+/// with everything the shell writes. Clients whose terminal doesn't match the
+/// PTY size are drawn from it instead of getting the raw output.
+#[derive(Debug)]
+pub struct Screen {
+    cols: u16,
+    rows: u16,
+    grid: Vec<Vec<Cell>>,
+    /// The primary screen while the alternate one is shown
+    primary: Option<Vec<Vec<Cell>>>,
+    row: u16,
+    col: u16,
+    /// The last column was written; the next character wraps first
+    wrap_pending: bool,
+    pen: Style,
+    saved_cursor: Option<(u16, u16, Style)>,
+    scroll_top: u16,
+    scroll_bottom: u16,
+    autowrap: bool,
+    cursor_visible: bool,
+    state: State,
+    /// Tracked private modes (see `TRACKED_MODES`) that are switched on
+    modes: Vec<u16>,
+    application_keypad: bool,
+    /// Bumped on every change, so views know when to redraw
+    generation: u64,
+    /// BEL characters seen outside escape sequences
+    bells: u64,
+}
+
+/// Private modes a client's terminal has to share with the application:
+/// cursor keys, mouse reporting, focus events and bracketed paste
+const TRACKED_MODES: [u16; 9] = [1, 1000, 1002, 1003, 1004, 1005, 1006, 1015, 2004];
+
+/// The largest screen modelled. The PTY itself can be bigger (sizes come
+/// from clients, up to 9999x9999), but every cell is allocated up front, so
+/// the model stops here and keeps the top left of such a screen.
+const MAX_COLS: u16 = 1000;
+const MAX_ROWS: u16 = 1000;
+
+impl Screen {
+    pub fn new(cols: u16, rows: u16) -> Self {
+        let cols = cols.clamp(1, MAX_COLS);
+        let rows = rows.clamp(1, MAX_ROWS);
+        Self {
+            cols,
+            rows,
+            grid: blank_grid(cols, rows, Style::default()),
+            primary: None,
+            row: 0,
+            col: 0,
+            wrap_pending: false,
+            pen: Style::default(),
+            saved_cursor: None,
+            scroll_top: 0,
+            scroll_bottom: rows - 1,
+            autowrap: true,
+            cursor_visible: true,
+            state: State::Ground,
+            modes: Vec::new(),
+            application_keypad: false,
+            generation: 0,
+            bells: 0,
+        }
+    }
+
+    /// Size as (cols, rows)
+    pub fn size(&self) -> (u16, u16) {
+        (self.cols, self.rows)
+    }
+
+    /// Cursor position as (row, col), both from 0
+    pub fn cursor(&self) -> (u16, u16) {
+        (self.row, self.col)
+    }
+
+    /// Whether the application wants the cursor shown
+    pub fn cursor_visible(&self) -> bool {
+        self.cursor_visible
+    }
+
+    pub fn alternate_screen(&self) -> bool {
+        self.primary.is_some()
+    }
+
+    /// How many times the application has rung the bell. A BEL ending an
+    /// OSC sequence (e.g. a window title) doesn't count.
+    pub fn bells(&self) -> u64 {
+        self.bells
+    }
+
+    /// Whether a tracked private mode (e.g. 2004 for bracketed paste) is on
+    pub fn mode(&self, mode: u16) -> bool {
+        self.modes.contains(&mode)
+    }
+
+    /// The tracked private modes that are on
+    pub fn modes(&self) -> &[u16] {
+        &self.modes
+    }
+
+    pub fn application_keypad(&self) -> bool {
+        self.application_keypad
+    }
+
+    /// Bytes that put a terminal into the application's modes: alternate
+    /// screen, keypad, cursor keys, mouse, bracketed paste and cursor visibility
+    pub fn mode_sequence(&self) -> Vec<u8> {
+        let mut out = String::new();
+        self.write_modes(&mut out);
+        out.push_str(if self.cursor_visible {
+            "\x1b[?25h"
+        } else {
+            "\x1b[?25l"
+        });
+        out.into_bytes()
+    }
+
+    fn write_modes(&self, out: &mut String) {
+        out.push_str(if self.alternate_screen() {
+            "\x1b[?1049h"
+        } else {
+            "\x1b[?1049l"
+        });
+        out.push_str(if self.application_keypad {
+            "\x1b="
+        } else {
+            "\x1b>"
+        });
+        for mode in TRACKED_MODES {
+            let _ = write!(
+                out,
+                "\x1b[?{}{}",
+                mode,
+                if self.mode(mode) { 'h' } else { 'l' }
+            );
+        }
+    }
+
+    /// The cell at (row, col), if it is on the screen
+    pub fn cell(&self, row: u16, col: u16) -> Option<&Cell> {
+        self.grid.get(row as usize)?.get(col as usize)
+    }
+
+    /// Text of a row with trailing blanks trimmed
+    pub fn row_text(&self, row: u16) -> String {
+        self.grid
+            .get(row as usize)
+            .map(|line| {
+                line.iter()
+                    .filter(|cell| cell.width > 0)
+                    .map(|cell| cell.ch)
+                    .collect::<String>()
+                    .trim_end()
+                    .to_string()
+            })
+            .unwrap_or_default()
+    }
+
+    /// A row's text with the SGR sequences that style it, for drawing it
+    /// somewhere other than a terminal of the same size
+    pub fn row_ansi(&self, row: u16) -> String {
+        let mut out = String::new();
+        if row < self.rows {
+            self.render_row(&mut out, row, 0, self.cols);
+        }
+        out
+    }
+
+    /// Feed output from the PTY
+    pub fn process(&mut self, data: &[u8]) {
+        for &byte in data {
+            let state = std::mem::take(&mut self.state);
+            self.state = self.advance(state, byte);
+        }
+        self.generation += 1;
+    }
+
+    /// Follow the PTY to a new size. Rows drop off the top when the cursor
+    /// would otherwise fall off the bottom, like a terminal window shrinking.
+    pub fn resize(&mut self, cols: u16, rows: u16) {
+        let cols = cols.clamp(1, MAX_COLS);
+        let rows = rows.clamp(1, MAX_ROWS);
+        if (cols, rows) == (self.cols, self.rows) {
+            return;
+        }
+
+        let overflow = (self.row + 1).saturating_sub(rows);
+        resize_grid(&mut self.grid, cols, rows, overflow);
+        if let Some(primary) = self.primary.as_mut() {
+            resize_grid(primary, cols, rows, overflow);
+        }
+
+        self.cols = cols;
+        self.rows = rows;
+        self.row = (self.row - overflow).min(rows - 1);
+        self.col = self.col.min(cols - 1);
+        self.wrap_pending = false;
+        self.scroll_top = 0;
+        self.scroll_bottom = rows - 1;
+        self.generation += 1;
+    }
+
+    /// Bytes that make a terminal of the same size show exactly this screen,
+    /// modes, cursor and pen included, so raw output can carry on from there
+    pub fn repaint(&self) -> Vec<u8> {
+        let mut out = String::new();
+        self.write_modes(&mut out);
+        out.push_str("\x1b[?25l\x1b[r");
+        for row in 0..self.rows {
+            let _ = write!(out, "\x1b[{};1H", row + 1);
+            self.render_row(&mut out, row, 0, self.cols);
+        }
+        if (self.scroll_top, self.scroll_bottom) != (0, self.rows - 1) {
+            let _ = write!(
+                out,
+                "\x1b[{};{}r",
+                self.scroll_top + 1,
+                self.scroll_bottom + 1
+            );
+        }
+        self.write_cursor(&mut out);
+        out.into_bytes()
+    }
+
+    /// Bytes that put the cursor and pen back where the application left
+    /// them, after something else drew on a terminal showing this screen
+    pub fn cursor_sequence(&self) -> Vec<u8> {
+        let mut out = String::new();
+        self.write_cursor(&mut out);
+        out.into_bytes()
+    }
+
+    fn write_cursor(&self, out: &mut String) {
+        let _ = write!(out, "\x1b[{};{}H", self.row + 1, self.col + 1);
+        if self.wrap_pending {
+            // Rewrite the last character so the terminal also wraps next
+            if let Some(cell) = self.cell(self.row, self.col).filter(|c| c.width == 1) {
+                cell.style.write_sgr(out);
+                out.push(cell.ch);
+            }
+        }
+        self.pen.write_sgr(out);
+        out.push_str(if self.autowrap {
+            "\x1b[?7h"
+        } else {
+            "\x1b[?7l"
+        });
+        if self.cursor_visible {
+            out.push_str("\x1b[?25h");
+        }
+    }
+
+    /// Append row `row` as seen through a window `width` cells wide whose
+    /// left edge is at column `left`, blanking whatever lies past the screen
+    fn render_row(&self, out: &mut String, row: u16, left: u16, width: u16) {
+        self.render_cells(out, row, left, width, true);
+    }
+
+    /// A row cut or padded to `width` columns, ending in blanks rather than
+    /// an erase to the end of the line, for drawing it beside other things
+    pub(super) fn render_row_padded(&self, out: &mut String, row: u16, width: u16) {
+        if row < self.rows {
+            self.render_cells(out, row, 0, width, false);
+        } else {
+            out.push_str("\x1b[0m");
+            out.extend(std::iter::repeat_n(' ', width as usize));
+        }
+    }
+
+    fn render_cells(&self, out: &mut String, row: u16, left: u16, width: u16, erase: bool) {
+        let line = &self.grid[row as usize];
+        let mut style = Style::default();
+        let mut written = 0;
+        let mut col = left;
+
+        // Trailing blanks are left to the erase at the end
+        let right = self.cols.min(left.saturating_add(width));
+        let end = (left..right)
+            .rev()
+            .find(|&c| line[c as usize] != Cell::blank(Style::default()))
+            .map_or(left, |c| c + 1);
+
+        out.push_str("\x1b[0m");
+        while written < width && col < end {
+            let cell = line[col as usize];
+            let (ch, cell_width) = match cell.width {
+                // A wide character cut in half by either edge of the window
+                0 => (' ', 1),
+                2 if written + 1 >= width || col + 1 >= self.cols => (' ', 1),
+                w => (cell.ch, w as u16),
+            };
+            if cell.style != style {
+                cell.style.write_sgr(out);
+                style = cell.style;
+            }
+            out.push(ch);
+            written += cell_width;
+            col += cell_width;
+        }
+
+        if written < width {
+            if style != Style::default() {
+                out.push_str("\x1b[0m");
+            }
+            if erase {
+                out.push_str("\x1b[K");
+            } else {
+                out.extend(std::iter::repeat_n(' ', (width - written) as usize));
+            }
+        }
+    }
+
+    fn advance(&mut self, state: State, byte: u8) -> State {
+        match state {
+            State::Ground => self.ground(byte),
+            State::Utf8 {
+                mut bytes,
+                len,
+                need,
+            } => {
+                if byte & 0xc0 != 0x80 {
+                    // Truncated character: show a replacement and start over
+                    self.print('\u{fffd}');
+                    return self.ground(byte);
+                }
+                bytes[len] = byte;
+                if len + 1 < need {
+                    return State::Utf8 {
+                        bytes,
+                        len: len + 1,
+                        need,
+                    };
+                }
+                let ch = std::str::from_utf8(&bytes[..need])
+                    .ok()
+                    .and_then(|s| s.chars().next())
+                    .unwrap_or('\u{fffd}');
+                self.print(ch);
+                State::Ground
+            }
+            State::Escape => self.escape(byte),
+            State::Charset => State::Ground,
+            State::Csi(csi) => self.csi(csi, byte),
+            State::Osc => match byte {
+                0x07 => State::Ground,
+                0x1b => State::OscEscape,
+                _ => State::Osc,
+            },
+            State::OscEscape | State::StrEscape if byte != b'\\' => self.escape(byte),
+            State::OscEscape | State::StrEscape => State::Ground,
+            State::Str => match byte {
+                0x1b => State::StrEscape,
+                _ => State::Str,
+            },
+        }
+    }
+
+    fn ground(&mut self, byte: u8) -> State {
+        let need = match byte {
+            0x1b => return State::Escape,
+            0x00..=0x1f | 0x7f => {
+                self.control(byte);
+                return State::Ground;
+            }
+            0x20..=0x7e => {
+                self.print(byte as char);
+                return State::Ground;
+            }
+            0xc2..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf4 => 4,
+            _ => {
+                self.print('\u{fffd}');
+                return State::Ground;
+            }
+        };
+        State::Utf8 {
+            bytes: [byte, 0, 0, 0],
+            len: 1,
+            need,
+        }
+    }
+
+    fn escape(&mut self, byte: u8) -> State {
+        match byte {
+            b'[' => return State::Csi(Csi::default()),
+            b']' => return State::Osc,
+            b'P' | b'X' | b'^' | b'_' => return State::Str,
+            b'(' | b')' | b'*' | b'+' | b'#' | b'%' => return State::Charset,
+            0x1b => return State::Escape,
+            0x00..=0x1f => {
+                self.control(byte);
+                return State::Escape;
+            }
+            b'7' => self.save_cursor(),
+            b'8' => self.restore_cursor(),
+            b'D' => self.linefeed(),
+            b'E' => {
+                self.col = 0;
+                self.linefeed();
+            }
+            b'M' => self.reverse_index(),
+            b'=' => self.application_keypad = true,
+            b'>' => self.application_keypad = false,
+            b'c' => {
+                let (generation, bells) = (self.generation, self.bells);
+                *self = Screen::new(self.cols, self.rows);
+                self.generation = generation;
+                self.bells = bells;
+            }
+            _ => {}
+        }
+        State::Ground
+    }
+
+    fn csi(&mut self, mut csi: Csi, byte: u8) -> State {
+        match byte {
+            b'0'..=b'9' => {
+                let digit = (byte - b'0') as u16;
+                csi.current = Some(
+                    csi.current
+                        .unwrap_or(0)
+                        .saturating_mul(10)
+                        .saturating_add(digit),
+                );
+                State::Csi(csi)
+            }
+            b';' | b':' => {
+                csi.params.push(csi.current.take().unwrap_or(0));
+                State::Csi(csi)
+            }
+            b'<' | b'=' | b'>' | b'?' => {
+                csi.private = Some(byte);
+                State::Csi(csi)
+            }
+            0x20..=0x2f => {
+                csi.intermediate = Some(byte);
+                State::Csi(csi)
+            }
+            0x40..=0x7e => {
+                if let Some(current) = csi.current.take() {
+                    csi.params.push(current);
+                }
+                self.dispatch_csi(&csi, byte);
+                State::Ground
+            }
+            0x1b => State::Escape,
+            0x00..=0x1f => {
+                self.control(byte);
+                State::Csi(csi)
+            }
+            _ => State::Ground,
+        }
+    }
+
+    fn dispatch_csi(&mut self, csi: &Csi, action: u8) {
+        if csi.intermediate.is_some() {
+            return;
+        }
+        if csi.private == Some(b'?') {
+            if let b'h' | b'l' = action {
+                for &mode in &csi.params {
+                    self.set_private_mode(mode, action == b'h');
+                }
+            }
+            return;
+        }
+        if csi.private.is_some() {
+            return;
+        }
+
+        let n = csi.param(0, 1);
+        match action {
+            b'A' => self.goto(self.row.saturating_sub(n), self.col),
+            b'B' | b'e' => self.goto(self.row.saturating_add(n), self.col),
+            b'C' | b'a' => self.goto(self.row, self.col.saturating_add(n)),
+            b'D' => self.goto(self.row, self.col.saturating_sub(n)),
+            b'E' => self.goto(self.row.saturating_add(n), 0),
+            b'F' => self.goto(self.row.saturating_sub(n), 0),
+            b'G' | b'`' => self.goto(self.row, n - 1),
+            b'H' | b'f' => self.goto(n - 1, csi.param(1, 1) - 1),
+            b'd' => self.goto(n - 1, self.col),
+            b'J' => self.erase_display(csi.param(0, 0)),
+            b'K' => self.erase_line(csi.param(0, 0)),
+            b'L' => self.insert_lines(n),
+            b'M' => self.delete_lines(n),
+            b'@' => self.insert_chars(n),
+            b'P' => self.delete_chars(n),
+            b'X' => self.erase_chars(n),
+            b'S' => self.scroll_up(n),
+            b'T' => self.scroll_down(n),
+            b'm' => self.set_graphics(&csi.params),
+            b'r' => self.set_scroll_region(n, csi.param(1, self.rows)),
+            b's' => self.save_cursor(),
+            b'u' => self.restore_cursor(),
+            _ => {}
+        }
+    }
+
+    fn set_private_mode(&mut self, mode: u16, enable: bool) {
+        match mode {
+            7 => self.autowrap = enable,
+            25 => self.cursor_visible = enable,
+            47 | 1047 | 1049 => {
+                if mode == 1049 && enable {
+                    self.save_cursor();
+                }
+                if enable && self.primary.is_none() {
+                    let alternate = blank_grid(self.cols, self.rows, Style::default());
+                    self.primary = Some(std::mem::replace(&mut self.grid, alternate));
+                } else if !enable {
+                    if let Some(primary) = self.primary.take() {
+                        self.grid = primary;
+                    }
+                }
+                if mode == 1049 && !enable {
+                    self.restore_cursor();
+                }
+            }
+            mode if TRACKED_MODES.contains(&mode) => {
+                self.modes.retain(|&m| m != mode);
+                if enable {
+                    self.modes.push(mode);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn control(&mut self, byte: u8) {
+        match byte {
+            0x07 => self.bells += 1,
+            0x08 => {
+                self.col = self.col.saturating_sub(1);
+                self.wrap_pending = false;
+            }
+            0x09 => {
+                self.col = ((self.col / 8 + 1) * 8).min(self.cols - 1);
+                self.wrap_pending = false;
+            }
+            0x0a..=0x0c => self.linefeed(),
+            0x0d => {
+                self.col = 0;
+                self.wrap_pending = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn print(&mut self, ch: char) {
+        let width = match ch.width() {
+            Some(w @ 1..=2) => w as u16,
+            _ => return, // Combining marks and other zero-width characters
+        };
+
+        if self.wrap_pending && self.autowrap {
+            self.col = 0;
+            self.linefeed();
+        }
+        self.wrap_pending = false;
+        if width == 2 && self.col + 1 >= self.cols {
+            if !self.autowrap || self.cols < 2 {
+                return;
+            }
+            self.col = 0;
+            self.linefeed();
+        }
+
+        let (row, col) = (self.row as usize, self.col as usize);
+        self.split_wide(row, col);
+        if width == 2 {
+            self.split_wide(row, col + 1);
+        }
+        let line = &mut self.grid[row];
+        line[col] = Cell {
+            ch,
+            width: width as u8,
+            style: self.pen,
+        };
+        if width == 2 {
+            line[col + 1] = Cell {
+                ch: ' ',
+                width: 0,
+                style: self.pen,
+            };
+        }
+
+        if self.col + width >= self.cols {
+            self.col = self.cols - 1;
+            self.wrap_pending = self.autowrap;
+        } else {
+            self.col += width;
+        }
+    }
+
+    /// Blank the other half of a wide character about to be overwritten
+    fn split_wide(&mut self, row: usize, col: usize) {
+        let line = &mut self.grid[row];
+        match line[col].width {
+            0 if col > 0 => line[col - 1] = Cell::blank(line[col - 1].style),
+            2 if col + 1 < line.len() => line[col + 1] = Cell::blank(line[col + 1].style),
+            _ => {}
+        }
+    }
+
+    fn goto(&mut self, row: u16, col: u16) {
+        self.row = row.min(self.rows - 1);
+        self.col = col.min(self.cols - 1);
+        self.wrap_pending = false;
+    }
+
+    fn linefeed(&mut self) {
+        self.wrap_pending = false;
+        if self.row == self.scroll_bottom {
+            self.scroll_up(1);
+        } else if self.row + 1 < self.rows {
+            self.row += 1;
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        self.wrap_pending = false;
+        if self.row == self.scroll_top {
+            self.scroll_down(1);
+        } else {
+            self.row = self.row.saturating_sub(1);
+        }
+    }
+
+    fn blank_row(&self) -> Vec<Cell> {
+        vec![Cell::blank(self.pen.erased()); self.cols as usize]
+    }
+
+    fn scroll_up(&mut self, n: u16) {
+        let (top, bottom) = (self.scroll_top as usize, self.scroll_bottom as usize);
+        for _ in 0..n.min(self.scroll_bottom - self.scroll_top + 1) {
+            self.grid.remove(top);
+            self.grid.insert(bottom, self.blank_row());
+        }
+    }
+
+    fn scroll_down(&mut self, n: u16) {
+        let (top, bottom) = (self.scroll_top as usize, self.scroll_bottom as usize);
+        for _ in 0..n.min(self.scroll_bottom - self.scroll_top + 1) {
+            self.grid.remove(bottom);
+            self.grid.insert(top, self.blank_row());
+        }
+    }
+
+    fn insert_lines(&mut self, n: u16) {
+        if self.row < self.scroll_top || self.row > self.scroll_bottom {
+            return;
+        }
+        let (row, bottom) = (self.row as usize, self.scroll_bottom as usize);
+        for _ in 0..n.min(self.scroll_bottom - self.row + 1) {
+            self.grid.remove(bottom);
+            self.grid.insert(row, self.blank_row());
+        }
+        self.goto(self.row, 0);
+    }
+
+    fn delete_lines(&mut self, n: u16) {
+        if self.row < self.scroll_top || self.row > self.scroll_bottom {
+            return;
+        }
+        let (row, bottom) = (self.row as usize, self.scroll_bottom as usize);
+        for _ in 0..n.min(self.scroll_bottom - self.row + 1) {
+            self.grid.remove(row);
+            self.grid.insert(bottom, self.blank_row());
+        }
+        self.goto(self.row, 0);
+    }
+
+    fn insert_chars(&mut self, n: u16) {
+        let blank = Cell::blank(self.pen.erased());
+        let (row, col) = (self.row as usize, self.col as usize);
+        self.split_wide(row, col);
+        let line = &mut self.grid[row];
+        for _ in 0..n.min(self.cols - self.col) {
+            line.pop();
+            line.insert(col, blank);
+        }
+        self.wrap_pending = false;
+    }
+
+    fn delete_chars(&mut self, n: u16) {
+        let blank = Cell::blank(self.pen.erased());
+        let (row, col) = (self.row as usize, self.col as usize);
+        self.split_wide(row, col);
+        let line = &mut self.grid[row];
+        for _ in 0..n.min(self.cols - self.col) {
+            line.remove(col);
+            line.push(blank);
+        }
+        self.wrap_pending = false;
+    }
+
+    fn erase_chars(&mut self, n: u16) {
+        let end = self.col.saturating_add(n).min(self.cols);
+        self.erase_cells(self.row, self.col, end);
+        self.wrap_pending = false;
+    }
+
+    /// Blank columns `start..end` of a row
+    fn erase_cells(&mut self, row: u16, start: u16, end: u16) {
+        let blank = Cell::blank(self.pen.erased());
+        let row = row as usize;
+        if start < end {
+            self.split_wide(row, start as usize);
+            self.split_wide(row, end as usize - 1);
+        }
+        for cell in &mut self.grid[row][start as usize..end as usize] {
+            *cell = blank;
+        }
+    }
+
+    fn erase_line(&mut self, mode: u16) {
+        let (start, end) = match mode {
+            0 => (self.col, self.cols),
+            1 => (0, self.col + 1),
+            _ => (0, self.cols),
+        };
+        self.erase_cells(self.row, start, end);
+        self.wrap_pending = false;
+    }
+
+    fn erase_display(&mut self, mode: u16) {
+        let rows = match mode {
+            0 => {
+                self.erase_line(0);
+                self.row + 1..self.rows
+            }
+            1 => {
+                self.erase_line(1);
+                0..self.row
+            }
+            _ => 0..self.rows,
+        };
+        for row in rows {
+            self.erase_cells(row, 0, self.cols);
+        }
+        self.wrap_pending = false;
+    }
+
+    fn set_scroll_region(&mut self, top: u16, bottom: u16) {
+        let bottom = bottom.min(self.rows);
+        if top < bottom {
+            self.scroll_top = top - 1;
+            self.scroll_bottom = bottom - 1;
+            self.goto(0, 0);
+        }
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved_cursor = Some((self.row, self.col, self.pen));
+    }
+
+    fn restore_cursor(&mut self) {
+        let (row, col, pen) = self.saved_cursor.unwrap_or((0, 0, Style::default()));
+        self.goto(row, col);
+        self.pen = pen;
+    }
+
+    fn set_graphics(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            self.pen = Style::default();
+            return;
+        }
+
+        let mut i = 0;
+        while i < params.len() {
+            let pen = &mut self.pen;
+            match params[i] {
+                0 => *pen = Style::default(),
+                1 => pen.attrs |= Style::BOLD,
+                2 => pen.attrs |= Style::DIM,
+                3 => pen.attrs |= Style::ITALIC,
+                4 => pen.attrs |= Style::UNDERLINE,
+                5 | 6 => pen.attrs |= Style::BLINK,
+                7 => pen.attrs |= Style::REVERSE,
+                8 => pen.attrs |= Style::HIDDEN,
+                9 => pen.attrs |= Style::STRIKE,
+                22 => pen.attrs &= !(Style::BOLD | Style::DIM),
+                23 => pen.attrs &= !Style::ITALIC,
+                24 => pen.attrs &= !Style::UNDERLINE,
+                25 => pen.attrs &= !Style::BLINK,
+                27 => pen.attrs &= !Style::REVERSE,
+                28 => pen.attrs &= !Style::HIDDEN,
+                29 => pen.attrs &= !Style::STRIKE,
+                n @ 30..=37 => pen.fg = Color::Indexed((n - 30) as u8),
+                39 => pen.fg = Color::Default,
+                n @ 40..=47 => pen.bg = Color::Indexed((n - 40) as u8),
+                49 => pen.bg = Color::Default,
+                n @ 90..=97 => pen.fg = Color::Indexed((n - 90 + 8) as u8),
+                n @ 100..=107 => pen.bg = Color::Indexed((n - 100 + 8) as u8),
+                n @ (38 | 48) => {
+                    let (color, used) = extended_color(&params[i + 1..]);
+                    if let Some(color) = color {
+                        if n == 38 {
+                            pen.fg = color;
+                        } else {
+                            pen.bg = color;
+                        }
+                    }
+                    i += used;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+}
+
+/// Parse the colour after SGR 38 or 48 (`5;N` or `2;R;G;B`), returning it
+/// and how many parameters it used
+fn extended_color(params: &[u16]) -> (Option<Color>, usize) {
+    let byte = |i: usize| params.get(i).map(|&n| n.min(255) as u8);
+    match params.first() {
+        Some(5) => (byte(1).map(Color::Indexed), 2),
+        Some(2) => match (byte(1), byte(2), byte(3)) {
+            (Some(r), Some(g), Some(b)) => (Some(Color::Rgb(r, g, b)), 4),
+            _ => (None, params.len()),
+        },
+        _ => (None, params.len()),
+    }
+}
+
+fn blank_grid(cols: u16, rows: u16, style: Style) -> Vec<Vec<Cell>> {
+    vec![vec![Cell::blank(style); cols as usize]; rows as usize]
+}
+
+fn resize_grid(grid: &mut Vec<Vec<Cell>>, cols: u16, rows: u16, drop_top: u16) {
+    grid.drain(..drop_top as usize);
+    grid.resize(
+        rows as usize,
+        vec![Cell::blank(Style::default()); cols as usize],
+    );
+    for line in grid.iter_mut() {
+        line.resize(cols as usize, Cell::blank(Style::default()));
+        // Don't leave half of a wide character at the new right edge
+        if let Some(last) = line.last_mut().filter(|cell| cell.width == 2) {
+            *last = Cell::blank(last.style);
+        }
+    }
+}
+
+/// What a client whose terminal doesn't match the PTY is shown: a window onto
+/// the screen that follows the cursor, plus the rows it was last sent so only
+/// changed rows are redrawn
+#[derive(Debug, Default)]
+pub struct ScreenView {
+    top: u16,
+    left: u16,
+    size: (u16, u16),
+    drawn: Vec<String>,
+    generation: Option<u64>,
+}
+
+impl ScreenView {
+    /// Forget what the client was shown, e.g. after something else drew over it
+    pub fn invalidate(&mut self) {
+        self.drawn.clear();
+        self.generation = None;
+    }
+
+    /// Bytes that bring a `cols`x`rows` client up to date with the screen;
+    /// empty if it already shows the latest state
+    pub fn render(&mut self, screen: &Screen, cols: u16, rows: u16) -> Vec<u8> {
+        if self.generation == Some(screen.generation) && self.size == (cols, rows) {
+            return Vec::new();
+        }
+        if self.generation.is_none() || self.size != (cols, rows) {
+            self.drawn = vec![String::new(); rows as usize];
+        }
+        self.generation = Some(screen.generation);
+        self.size = (cols, rows);
+        self.follow_cursor(screen, cols, rows);
+
+        let mut out = String::from("\x1b[?25l");
+        if self.drawn.iter().all(|line| line.is_empty()) {
+            out.push_str("\x1b[r");
+        }
+        let mut line = String::new();
+        for row in 0..rows {
+            line.clear();
+            match self.top.checked_add(row).filter(|&r| r < screen.rows) {
+                Some(screen_row) => screen.render_row(&mut line, screen_row, self.left, cols),
+                None => line.push_str("\x1b[0m\x1b[K"),
+            }
+            if self.drawn[row as usize] != line {
+                let _ = write!(out, "\x1b[{};1H{}", row + 1, line);
+                self.drawn[row as usize].clone_from(&line);
+            }
+        }
+
+        let (row, col) = screen.cursor();
+        let (row, col) = (row.saturating_sub(self.top), col.saturating_sub(self.left));
+        let _ = write!(out, "\x1b[{};{}H", row + 1, col + 1);
+        if screen.cursor_visible && row < rows && col < cols {
+            out.push_str("\x1b[?25h");
+        }
+        out.into_bytes()
+    }
+
+    /// Scroll the window just enough to keep the cursor in it
+    fn follow_cursor(&mut self, screen: &Screen, cols: u16, rows: u16) {
+        let (row, col) = screen.cursor();
+        self.top = follow(self.top, row, rows, screen.rows);
+        self.left = follow(self.left, col, cols, screen.cols);
+    }
+}
+
+/// New start of a window `len` long over `total` positions so it includes `pos`.
+/// A window of nothing is treated as one position long, so it still never
+/// starts past `pos`.
+fn follow(start: u16, pos: u16, len: u16, total: u16) -> u16 {
+    let len = len.max(1);
+    let start = if pos < start {
+        pos
+    } else if pos - start >= len {
+        pos + 1 - len
+    } else {
+        start
+    };
+    start.min(total.saturating_sub(len))
+}
@@ -4,9 +4,12 @@ use std::os::unix::io::{BorrowedFd, RawFd};
 use nix::sys::termios::{tcgetattr, tcsetattr, SetArg, Termios};
 
 use crate::error::{NdsError, Result};
-use crate::manager::SessionManager;
+use crate::fuzzy::match_session;
+use crate::manager::{SessionManager, FOREGROUND_WIDTH};
 use crate::session::Session;
 
+use super::session_switcher_tui::pick_session;
+
 /// Result of a session switch operation
 pub enum SwitchResult {
     /// Switch to an existing session with the given ID
@@ -37,6 +40,16 @@ impl<'a> SessionSwitcher<'a> {
 
     /// Show the session switcher interface and handle user selection
     pub fn show_switcher(&self) -> Result<SwitchResult> {
+        match pick_session(self.current_session) {
+            Ok(result) => Ok(result),
+            // A terminal the full-screen switcher can't run in still gets
+            // the numbered list
+            Err(_) => self.prompt_switcher(),
+        }
+    }
+
+    /// The switcher as a numbered list and a prompt
+    fn prompt_switcher(&self) -> Result<SwitchResult> {
         // Clear screen and show simple picker
         print!("\x1b[2J\x1b[H"); // Clear screen
         println!("\r\n╔══════════════════════════════════════╗\r");
@@ -79,7 +92,7 @@ impl<'a> SessionSwitcher<'a> {
         println!("\r  [{}] ➕ Create New Session\r", new_option);
         println!("\r  [0] Cancel\r");
         println!("\r\n─────────────────────────────────────────\r");
-        print!("\r\nSelect [0-{}]: ", new_option);
+        print!("\r\nSelect [0-{}] or type to filter: ", new_option);
         let _ = io::stdout().flush();
 
         // Read selection
@@ -92,6 +105,9 @@ impl<'a> SessionSwitcher<'a> {
             } else if num == new_option {
                 return self.handle_new_session();
             }
+        } else if let Some(target) = best_match(selection.trim(), &other_sessions) {
+            println!("\r\n✓ Switching to: {}\r", target.display_name());
+            return Ok(SwitchResult::SwitchTo(target.id.clone()));
         }
 
         println!("\r\n[Continuing current session]\r");
@@ -161,3 +177,29 @@ impl<'a> SessionSwitcher<'a> {
         Ok(buffer)
     }
 }
+
+/// The session that best matches a fuzzy `query` (a leading `/` is allowed)
+/// by name, ID, directory or foreground command
+pub(super) fn best_match<'a>(query: &str, sessions: &[&'a Session]) -> Option<&'a Session> {
+    let query = query.strip_prefix('/').unwrap_or(query);
+    if query.is_empty() {
+        return None;
+    }
+    sessions
+        .iter()
+        .filter_map(|session| {
+            let running = session
+                .foreground_label(FOREGROUND_WIDTH)
+                .unwrap_or_default();
+            match_session(query, session, &running).map(|found| (*session, found.score))
+        })
+        // The first of equally good matches
+        .fold(
+            None,
+            |best: Option<(&Session, i32)>, (session, score)| match best {
+                Some((_, best_score)) if best_score >= score => best,
+                _ => Some((session, score)),
+            },
+        )
+        .map(|(session, _)| session)
+}
@@ -1,147 +1,159 @@
-use std::io::{self, BufRead, Write};
-use std::os::unix::io::{BorrowedFd, RawFd};
-use std::time::{Duration, Instant};
+use std::io;
+use std::time::Duration;
 
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind},
+    cursor,
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyEventKind,
+        MouseEvent,
+    },
     execute,
     terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use nix::sys::termios::{tcflush, tcgetattr, tcsetattr, SetArg, Termios};
 use ratatui::{
     backend::{Backend, CrosstermBackend},
-    layout::{Alignment, Constraint, Direction, Layout, Rect},
-    style::{Color, Modifier, Style},
-    text::{Line, Span},
-    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    layout::{Alignment, Constraint, Direction, Layout},
+    widgets::{Block, Borders, Paragraph},
     Frame, Terminal,
 };
 
-use super::terminal::{restore_terminal, set_raw_mode};
-use crate::error::{NdsError, Result};
+use super::session_switcher::SwitchResult;
+use crate::error::Result;
+use crate::keymap::{KeyMap, PickerAction};
 use crate::manager::SessionManager;
 use crate::session::Session;
-
-/// Result of a session switch operation
-pub enum SwitchResult {
-    /// Switch to an existing session with the given ID
-    SwitchTo(String),
-    /// Continue with the current session
-    Continue,
+use crate::text_input::{InputEvent, TextInput};
+use crate::ui::{help_line, picker_help, SessionList};
+
+/// How long to wait for a key before redrawing, so the preview keeps up
+const TICK: Duration = Duration::from_millis(250);
+
+/// What the switcher is waiting for
+enum Mode {
+    /// Moving around the list
+    Browse,
+    /// The filter being typed (`/`)
+    Filter(TextInput),
+    /// A name for a new session (`c`)
+    Create(TextInput),
 }
 
-/// TUI-based session picker
+/// The switcher opened with `~s`: the picker's list, with keys to switch to
+/// a session or start a new one
 struct TuiSessionPicker {
-    sessions: Vec<Session>,
-    current_session: Session,
-    state: ListState,
-    show_new_session_input: bool,
-    new_session_name: String,
+    list: SessionList,
+    current_session_id: String,
+    mode: Mode,
+    /// Why the last action failed, shown in the footer until the next key
+    status: Option<String>,
 }
 
 impl TuiSessionPicker {
-    fn run<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<SwitchResult> {
-        let tick_rate = Duration::from_millis(100);
-        let mut last_tick = Instant::now();
+    fn new(current_session: &Session, sessions: Vec<Session>) -> Self {
+        let current_session_id = current_session.id.clone();
+        let mut list = SessionList::new(sessions, Some(current_session_id.clone()));
+        // Start on another session, as that is what a switch is for
+        if list
+            .selected()
+            .is_some_and(|session| session.id == current_session_id)
+        {
+            list.next();
+        }
+        Self {
+            list,
+            current_session_id,
+            mode: Mode::Browse,
+            status: None,
+        }
+    }
 
+    fn run<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<SwitchResult> {
         loop {
+            self.list.refresh_preview();
             terminal.draw(|f| self.ui(f))?;
 
-            let timeout = tick_rate
-                .checked_sub(last_tick.elapsed())
-                .unwrap_or_else(|| Duration::from_secs(0));
-
-            if crossterm::event::poll(timeout)? {
-                if let Event::Key(key) = event::read()? {
-                    if key.kind == KeyEventKind::Press {
-                        if self.show_new_session_input {
-                            // Handle input for new session name
-                            match key.code {
-                                KeyCode::Esc => {
-                                    self.show_new_session_input = false;
-                                    self.new_session_name.clear();
-                                }
-                                KeyCode::Enter => {
-                                    let name = if self.new_session_name.is_empty() {
-                                        None
-                                    } else {
-                                        Some(self.new_session_name.clone())
-                                    };
-                                    return self.create_new_session(name);
-                                }
-                                KeyCode::Backspace => {
-                                    self.new_session_name.pop();
-                                }
-                                KeyCode::Char(c) => {
-                                    self.new_session_name.push(c);
-                                }
-                                _ => {}
-                            }
-                        } else {
-                            // Normal navigation
-                            match key.code {
-                                KeyCode::Char('q') | KeyCode::Esc => {
-                                    return Ok(SwitchResult::Continue);
-                                }
-                                KeyCode::Down | KeyCode::Char('j') => self.next(),
-                                KeyCode::Up | KeyCode::Char('k') => self.previous(),
-                                KeyCode::Enter => {
-                                    if let Some(selected) = self.state.selected() {
-                                        if selected < self.sessions.len() {
-                                            // Selected an existing session
-                                            let session = &self.sessions[selected];
-                                            if session.id != self.current_session.id {
-                                                return Ok(SwitchResult::SwitchTo(
-                                                    session.id.clone(),
-                                                ));
-                                            }
-                                        } else {
-                                            // Selected "New Session"
-                                            self.show_new_session_input = true;
-                                        }
-                                    }
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
+            if event::poll(TICK)? {
+                let result = match event::read()? {
+                    Event::Key(key) if key.kind == KeyEventKind::Press => self.handle_key(key),
+                    Event::Mouse(mouse) => self.handle_mouse(mouse),
+                    _ => None,
+                };
+                if let Some(result) = result {
+                    return Ok(result);
                 }
             }
-
-            if last_tick.elapsed() >= tick_rate {
-                last_tick = Instant::now();
-            }
         }
     }
 
-    fn next(&mut self) {
-        let total_items = self.sessions.len() + 1; // +1 for "New Session"
-        let i = match self.state.selected() {
-            Some(i) => {
-                if i >= total_items - 1 {
-                    0
-                } else {
-                    i + 1
+    /// Act on a key press; `Some` closes the switcher
+    fn handle_key(&mut self, key: KeyEvent) -> Option<SwitchResult> {
+        self.status = None;
+        match std::mem::replace(&mut self.mode, Mode::Browse) {
+            Mode::Browse => return self.browse(key),
+            Mode::Filter(mut input) => {
+                if self.list.filter_key(&mut input, key) {
+                    self.mode = Mode::Filter(input);
                 }
             }
-            None => 0,
-        };
-        self.state.select(Some(i));
+            Mode::Create(mut input) => match input.handle_key(key.code, key.modifiers) {
+                InputEvent::Continue => self.mode = Mode::Create(input),
+                InputEvent::Cancel => {}
+                InputEvent::Submit(name) => {
+                    let name = Some(name.trim().to_string()).filter(|name| !name.is_empty());
+                    match SessionManager::create_session_with_name(name) {
+                        Ok(session) => return Some(SwitchResult::SwitchTo(session.id)),
+                        Err(e) => {
+                            self.status = Some(e.to_string());
+                            self.mode = Mode::Create(input);
+                        }
+                    }
+                }
+            },
+        }
+        None
     }
 
-    fn previous(&mut self) {
-        let total_items = self.sessions.len() + 1; // +1 for "New Session"
-        let i = match self.state.selected() {
-            Some(i) => {
-                if i == 0 {
-                    total_items - 1
-                } else {
-                    i - 1
+    fn browse(&mut self, key: KeyEvent) -> Option<SwitchResult> {
+        // Esc clears a filter before it goes back, whatever goes back
+        if key.code == KeyCode::Esc && !self.list.query().is_empty() {
+            self.list.set_query("");
+            return None;
+        }
+        match KeyMap::current().picker.action(&key)? {
+            PickerAction::Quit => return Some(SwitchResult::Continue),
+            PickerAction::Filter => self.mode = Mode::Filter(TextInput::new(self.list.query())),
+            PickerAction::Down => self.list.next(),
+            PickerAction::Up => self.list.previous(),
+            PickerAction::Open => return self.switch(),
+            PickerAction::New => self.mode = Mode::Create(TextInput::default()),
+            PickerAction::Sort => {
+                if let Err(e) = self.list.cycle_sort() {
+                    self.status = Some(format!("Couldn't save the sort: {}", e));
                 }
             }
-            None => 0,
-        };
-        self.state.select(Some(i));
+            // Sessions are killed and renamed from the picker
+            PickerAction::Mark | PickerAction::Kill | PickerAction::Rename => {}
+        }
+        None
+    }
+
+    /// Clicking a session selects it and double-clicking switches to it,
+    /// unless a name is being typed
+    fn handle_mouse(&mut self, mouse: MouseEvent) -> Option<SwitchResult> {
+        if matches!(self.mode, Mode::Create(_)) || !self.list.mouse(mouse) {
+            return None;
+        }
+        self.switch()
+    }
+
+    /// Switch to the selected session
+    fn switch(&self) -> Option<SwitchResult> {
+        let session = self.list.selected()?;
+        // Picking the session we are in goes back to it
+        if session.id == self.current_session_id {
+            return Some(SwitchResult::Continue);
+        }
+        Some(SwitchResult::SwitchTo(session.id.clone()))
     }
 
     fn ui(&mut self, f: &mut Frame) {
@@ -154,288 +166,72 @@ impl TuiSessionPicker {
                 Constraint::Length(3),
             ])
             .split(f.area());
+        let theme = self.list.theme().clone();
 
-        // Header
-        let header_text = if self.show_new_session_input {
-            "NEW SESSION"
-        } else {
-            "SESSION SWITCHER"
-        };
-        let header = Paragraph::new(header_text)
-            .style(
-                Style::default()
-                    .fg(Color::Cyan)
-                    .add_modifier(Modifier::BOLD),
-            )
-            .alignment(Alignment::Center)
-            .block(
-                Block::default()
-                    .borders(Borders::BOTTOM)
-                    .border_style(Style::default().fg(Color::DarkGray)),
-            );
-        f.render_widget(header, chunks[0]);
-
-        if self.show_new_session_input {
-            // Show input for new session name
-            let input_block = Block::default()
-                .title("Enter session name (or press Enter for no name)")
-                .borders(Borders::ALL)
-                .border_style(Style::default().fg(Color::Yellow));
-
-            let input = Paragraph::new(self.new_session_name.as_str())
-                .style(Style::default().fg(Color::White))
-                .block(input_block);
-
-            f.render_widget(input, chunks[1]);
-        } else {
-            // Show session list
-            let items: Vec<ListItem> = self
-                .sessions
-                .iter()
-                .map(|session| {
-                    let is_current = session.id == self.current_session.id;
-                    let client_count = session.get_client_count();
-
-                    // Status indicator
-                    let (status_icon, status_color) = if is_current {
-                        ("★", Color::Cyan)
-                    } else if client_count > 0 {
-                        ("●", Color::Green)
-                    } else {
-                        ("○", Color::Gray)
-                    };
-
-                    let content = vec![Line::from(vec![
-                        Span::styled(status_icon, Style::default().fg(status_color)),
-                        Span::raw(" "),
-                        Span::styled(
-                            session.display_name(),
-                            if is_current {
-                                Style::default()
-                                    .fg(Color::Cyan)
-                                    .add_modifier(Modifier::ITALIC)
-                            } else {
-                                Style::default().fg(Color::White)
-                            },
-                        ),
-                        Span::raw(" "),
-                        Span::styled(
-                            format!("[{}]", &session.id[..8]),
-                            Style::default().fg(Color::DarkGray),
-                        ),
-                        if is_current {
-                            Span::styled(" (current)", Style::default().fg(Color::DarkGray))
-                        } else {
-                            Span::raw("")
-                        },
-                    ])];
-
-                    ListItem::new(content)
-                })
-                .chain(std::iter::once(ListItem::new(vec![Line::from(vec![
-                    Span::styled("➕", Style::default().fg(Color::Yellow)),
-                    Span::raw(" "),
-                    Span::styled("Create New Session", Style::default().fg(Color::Yellow)),
-                ])])))
-                .collect();
-
-            let sessions = List::new(items)
-                .block(Block::default().borders(Borders::NONE))
-                .highlight_style(
-                    Style::default()
-                        .bg(Color::DarkGray)
-                        .add_modifier(Modifier::BOLD),
-                )
-                .highlight_symbol("> ");
-
-            f.render_stateful_widget(sessions, chunks[1], &mut self.state);
-        }
-
-        // Footer
-        let footer_text = if self.show_new_session_input {
-            "[Enter] Create  [Esc] Cancel"
-        } else {
-            "[↑/↓/j/k] Navigate  [Enter] Select  [q/Esc] Cancel"
-        };
-        let footer = Paragraph::new(footer_text)
-            .style(Style::default().fg(Color::DarkGray))
-            .alignment(Alignment::Center)
-            .block(
-                Block::default()
-                    .borders(Borders::TOP)
-                    .border_style(Style::default().fg(Color::DarkGray)),
-            );
-        f.render_widget(footer, chunks[2]);
-    }
-
-    fn create_new_session(&self, name: Option<String>) -> Result<SwitchResult> {
-        match SessionManager::create_session_with_name(name.clone()) {
-            Ok(new_session) => Ok(SwitchResult::SwitchTo(new_session.id)),
-            Err(e) => {
-                eprintln!("Error creating session: {}", e);
-                Ok(SwitchResult::Continue)
-            }
-        }
-    }
-}
-
-/// Handle the session switcher interface
-pub struct SessionSwitcher<'a> {
-    current_session: &'a Session,
-    stdin_fd: RawFd,
-    original_termios: &'a Termios,
-}
-
-impl<'a> SessionSwitcher<'a> {
-    pub fn new(
-        current_session: &'a Session,
-        stdin_fd: RawFd,
-        original_termios: &'a Termios,
-    ) -> Self {
-        Self {
-            current_session,
-            stdin_fd,
-            original_termios,
-        }
-    }
-
-    /// Show the session switcher interface and handle user selection
-    pub fn show_switcher(&self) -> Result<SwitchResult> {
-        // Enter alternate screen and setup terminal
-        let mut stdout = io::stdout();
-        execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-
-        // Create terminal backend
-        let backend = CrosstermBackend::new(stdout);
-        let mut terminal = Terminal::new(backend)?;
-        terminal.clear()?;
-
-        // Get all sessions
-        let sessions = SessionManager::list_sessions()?;
-
-        // Create picker state
-        let mut picker = TuiSessionPicker {
-            sessions: sessions.clone(),
-            current_session: self.current_session.clone(),
-            state: ListState::default(),
-            show_new_session_input: false,
-            new_session_name: String::new(),
-        };
-
-        // Set initial selection to first non-current session or "New Session"
-        let initial_selection = if sessions.len() > 1 {
-            0
-        } else {
-            sessions.len() // Points to "New Session" option
-        };
-        picker.state.select(Some(initial_selection));
-
-        // Run the TUI event loop
-        let result = picker.run(&mut terminal)?;
-
-        // Restore terminal
-        // Restore terminal completely
-        execute!(
-            terminal.backend_mut(),
-            LeaveAlternateScreen,
-            DisableMouseCapture
-        )?;
-        terminal.show_cursor()?;
-        crossterm::terminal::disable_raw_mode()?;
-
-        // Set back to raw mode for session
-        set_raw_mode(self.stdin_fd, self.original_termios)?;
+        self.list.render_header(f, chunks[0], "SWITCH SESSION");
 
-        Ok(result)
-    }
-
-    /// Handle creating a new session
-    fn handle_new_session(&self) -> Result<SwitchResult> {
-        println!("\r\nEnter name for new session (or press Enter for no name): ");
-        let _ = io::stdout().flush();
-
-        let session_name = self.read_user_input()?;
-        let session_name = session_name.trim();
+        self.list.render(f, chunks[1]);
 
-        let name = if session_name.is_empty() {
-            None
-        } else {
-            Some(session_name.to_string())
+        let footer_block = Block::default()
+            .borders(Borders::TOP)
+            .border_style(theme.dim);
+        let prompt = match &self.mode {
+            Mode::Browse => None,
+            Mode::Filter(input) => Some((input, "Filter by name, ID, directory or command")),
+            Mode::Create(input) => Some((input, "New session name (Enter for none)")),
         };
-
-        // Create new session
-        match SessionManager::create_session_with_name(name.clone()) {
-            Ok(new_session) => {
-                if let Some(ref n) = name {
-                    println!(
-                        "\r\n[Created and switching to new session '{}' ({})]",
-                        n, new_session.id
-                    );
-                } else {
-                    println!(
-                        "\r\n[Created and switching to new session {}]",
-                        new_session.id
-                    );
-                }
-                Ok(SwitchResult::SwitchTo(new_session.id))
-            }
-            Err(e) => {
-                eprintln!("\r\nError creating session: {}\r", e);
-                Ok(SwitchResult::Continue)
-            }
+        if let Some((input, title)) = prompt {
+            input.render(f, chunks[2], self.status.as_deref().unwrap_or(title));
+            return;
         }
-    }
-
-    /// Read user input with temporary cooked mode
-    fn read_user_input(&self) -> Result<String> {
-        let stdin_borrowed = unsafe { BorrowedFd::borrow_raw(self.stdin_fd) };
-
-        // Save current raw mode settings
-        let current_termios = tcgetattr(&stdin_borrowed)?;
-
-        // Restore to original (cooked) mode for line input
-        tcsetattr(&stdin_borrowed, SetArg::TCSAFLUSH, self.original_termios)?;
-
-        // Ensure stdin is in blocking mode for reading
-        unsafe {
-            let flags = libc::fcntl(self.stdin_fd, libc::F_GETFL);
-            if flags >= 0 {
-                let _ = libc::fcntl(self.stdin_fd, libc::F_SETFL, flags & !libc::O_NONBLOCK);
-            }
-        }
-
-        // Flush any pending input
-        tcflush(&stdin_borrowed, nix::sys::termios::FlushArg::TCIFLUSH)?;
-
-        // Read user input
-        let stdin = io::stdin();
-        let mut buffer = String::new();
-        let mut stdin_lock = stdin.lock();
-        let read_result = stdin_lock.read_line(&mut buffer);
-
-        // Restore non-blocking mode if it was set
-        unsafe {
-            let flags = libc::fcntl(self.stdin_fd, libc::F_GETFL);
-            if flags >= 0 {
-                let _ = libc::fcntl(self.stdin_fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
-            }
+        if let Some(status) = &self.status {
+            let status = Paragraph::new(status.as_str())
+                .style(theme.notice)
+                .alignment(Alignment::Center)
+                .block(footer_block);
+            f.render_widget(status, chunks[2]);
+            return;
         }
 
-        // Restore raw mode
-        tcsetattr(&stdin_borrowed, SetArg::TCSANOW, &current_termios)?;
-
-        read_result.map_err(|e| NdsError::Io(e))?;
-        Ok(buffer)
+        let keys = picker_help(&[
+            (&[PickerAction::Up, PickerAction::Down], "navigate"),
+            (&[PickerAction::Open], "switch"),
+            (&[PickerAction::New], "new"),
+            (&[PickerAction::Filter], "filter"),
+            (&[PickerAction::Sort], "sort"),
+            (&[PickerAction::Quit], "back"),
+        ]);
+        let help_text = help_line(&keys, &theme);
+        let footer = Paragraph::new(help_text)
+            .alignment(Alignment::Center)
+            .block(footer_block);
+        f.render_widget(footer, chunks[2]);
     }
 }
 
-/// Show the session help message
-#[allow(dead_code)]
-pub fn show_session_help() {
-    println!("\r\n[Session Commands]\r");
-    println!("\r  ~d - Detach from current session\r");
-    println!("\r  ~s - Switch sessions\r");
-    println!("\r  ~h - Show scrollback history\r");
-    println!("\r  ~~ - Send literal tilde\r");
-    println!("\r\n[Press any key to continue]\r");
+/// Let the user pick a session to switch to from `current_session`, full
+/// screen. The terminal is expected in its normal mode, and is left that way.
+pub(super) fn pick_session(current_session: &Session) -> Result<SwitchResult> {
+    let sessions = SessionManager::list_sessions()?;
+    let mut picker = TuiSessionPicker::new(current_session, sessions);
+
+    terminal::enable_raw_mode()?;
+    let result = execute!(io::stdout(), EnterAlternateScreen, EnableMouseCapture)
+        .map_err(Into::into)
+        .and_then(|()| Ok(Terminal::new(CrosstermBackend::new(io::stdout()))?))
+        .and_then(|mut terminal| {
+            // Don't leave the session's screen showing through
+            terminal.clear()?;
+            picker.run(&mut terminal)
+        });
+
+    // Put the terminal back however far that got
+    let _ = execute!(
+        io::stdout(),
+        LeaveAlternateScreen,
+        DisableMouseCapture,
+        cursor::Show
+    );
+    let _ = terminal::disable_raw_mode();
+    result
 }
@@ -1,10 +1,45 @@
-use std::io::{self, Write};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
 use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use nix::sys::signal::Signal;
+
+use super::protocol::{
+    parse_client_opening, read_single_frame, Connection, Frame, Hello, Role, HELLO_MAGIC,
+    OPENING_LEN,
+};
 use crate::error::{NdsError, Result};
-use crate::session::Session;
+use crate::pty::ResizePolicy;
+use crate::session::{private_dir, ExitStatus, Session};
+
+/// Commands that old clients send on one-shot control connections (`nds wait`,
+/// `nds restart`, `nds detach`, `nds clients`, `nds resize`) rather than when
+/// attaching
+const CONTROL_COMMANDS: &[&str] = &[
+    "wait",
+    "restart",
+    "detach",
+    "clients",
+    "disconnect_client",
+    "resize_policy",
+    "message",
+    "scrollback",
+    "scrollback_search",
+    "scrollback_prompt",
+    "paste_buffer",
+    "paste",
+    "capture_screen",
+    "record",
+    "record_stop",
+];
+
+/// How long the daemon waits for a new connection's first bytes, and for a
+/// framed control connection's command after its hello
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(100);
 
 /// Creates a Unix socket listener for a session with secure permissions
 pub fn create_listener(session_id: &str) -> Result<(UnixListener, PathBuf)> {
@@ -27,19 +62,453 @@ pub fn create_listener(session_id: &str) -> Result<(UnixListener, PathBuf)> {
     Ok((listener, socket_path))
 }
 
+/// Creates a listener in Linux's abstract socket namespace, which has no
+/// file to go stale; returns the name it is bound to. Abstract sockets have
+/// no permissions, so the daemon's check of who connects is all that keeps
+/// others out.
+#[cfg(target_os = "linux")]
+pub fn create_abstract_listener(session_id: &str) -> Result<(UnixListener, String)> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    let name = Session::abstract_socket_name(session_id);
+    let listener = SocketAddr::from_abstract_name(&name)
+        .and_then(|addr| UnixListener::bind_addr(&addr))
+        .map_err(|e| {
+            NdsError::SocketError(format!("Failed to bind abstract socket @{}: {}", name, e))
+        })?;
+    Ok((listener, name))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn create_abstract_listener(_session_id: &str) -> Result<(UnixListener, String)> {
+    Err(NdsError::SocketError(
+        "abstract_sockets is set, but abstract sockets are only available on Linux".to_string(),
+    ))
+}
+
+/// Creates the second listener a shared session takes other users'
+/// connections on. Anyone may connect to it; the daemon checks who did.
+pub fn create_shared_listener(session_id: &str) -> Result<(UnixListener, PathBuf)> {
+    let dir = Session::shared_socket_dir();
+    private_dir(&dir, 0o711)?;
+
+    let socket_path = dir.join(format!("{}.sock", session_id));
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+    let listener = UnixListener::bind(&socket_path)
+        .map_err(|e| NdsError::SocketError(format!("Failed to bind shared socket: {}", e)))?;
+    std::fs::set_permissions(&socket_path, std::fs::Permissions::from_mode(0o666))?;
+    Ok((listener, socket_path))
+}
+
+/// What the daemon tells framed clients it understands: the commands beyond
+/// plain input and output
+pub const CAPABILITIES: &[&str] = &[
+    "resize",
+    "refresh",
+    "detach_others",
+    "tty",
+    "wait",
+    "restart",
+    "detach",
+    "clients",
+    "disconnect_client",
+    "resize_policy",
+    "signal",
+    "foreground",
+    "subscribe",
+    "message",
+    "scrollback",
+    "scrollback_search",
+    "scrollback_prompt",
+    "paste_buffer",
+    "paste",
+    "capture_screen",
+    "record",
+    "record_stop",
+    "setenv",
+    "debug_dump",
+    "metrics",
+    "share",
+    "approve_attach",
+    "input",
+    "windows",
+    "window_new",
+    "window_select",
+    "window_kill",
+    "panes",
+    "broadcast",
+];
+
+/// Send keyboard input for the session
+pub fn send_input(socket: &Connection, data: &[u8]) -> io::Result<()> {
+    socket.send(&Frame::Data(data.to_vec()))
+}
+
 /// Send a resize command to the daemon through the socket
-pub fn send_resize_command(socket: &mut UnixStream, cols: u16, rows: u16) -> io::Result<()> {
+pub fn send_resize_command(socket: &Connection, cols: u16, rows: u16) -> io::Result<()> {
     // Sanitize input to prevent overflow
-    let cols = cols.min(9999).max(1);
-    let rows = rows.min(9999).max(1);
+    let cols = cols.clamp(1, 9999).to_string();
+    let rows = rows.clamp(1, 9999).to_string();
+    socket.send(&Frame::command("resize", &[&cols, &rows]))
+}
+
+/// Ask the daemon to disconnect every other client (exclusive attach)
+pub fn send_detach_others_command(socket: &Connection) -> io::Result<()> {
+    socket.send(&Frame::command("detach_others", &[]))
+}
+
+/// Ask the daemon to redraw this client using the session's redraw strategy
+pub fn send_refresh_command(socket: &Connection) -> io::Result<()> {
+    socket.send(&Frame::command("refresh", &[]))
+}
+
+/// Ask the daemon to report when the session's shell or command exits
+pub fn send_wait_command(socket: &Connection) -> io::Result<()> {
+    socket.send(&Frame::command("wait", &[]))
+}
+
+/// Ask the daemon to kill and re-exec the session's shell or command
+pub fn send_restart_command(socket: &Connection) -> io::Result<()> {
+    socket.send(&Frame::command("restart", &[]))
+}
+
+/// Ask the daemon to detach every attached client
+pub fn send_detach_command(socket: &Connection) -> io::Result<()> {
+    socket.send(&Frame::command("detach", &[]))
+}
+
+/// Ask the daemon for its attached clients; it answers with a JSON array
+pub fn send_clients_command(socket: &Connection) -> io::Result<()> {
+    socket.send(&Frame::command("clients", &[]))
+}
+
+/// Ask the daemon to disconnect one client; it answers `true` if the client
+/// was attached
+pub fn send_disconnect_client_command(socket: &Connection, client_id: &str) -> io::Result<()> {
+    socket.send(&Frame::command("disconnect_client", &[client_id]))
+}
+
+/// Ask the daemon to size the PTY by `policy` from now on; it answers with
+/// the new size, or nothing if the PTY was left alone
+pub fn send_resize_policy_command(socket: &Connection, policy: ResizePolicy) -> io::Result<()> {
+    let policy = policy.to_string();
+    socket.send(&Frame::command("resize_policy", &[&policy]))
+}
+
+/// Ask the daemon to signal the process group in the foreground of the PTY;
+/// it answers with the group's ID or why it couldn't
+pub fn send_signal_command(socket: &Connection, signal: Signal) -> io::Result<()> {
+    socket.send(&Frame::command("signal", &[signal.as_str()]))
+}
+
+/// Ask the daemon what is in the foreground of the PTY; it answers with a
+/// JSON process group and command line, or `null`
+pub fn send_foreground_command(socket: &Connection) -> io::Result<()> {
+    socket.send(&Frame::command("foreground", &[]))
+}
+
+/// Ask the daemon to show `text` on the bottom row of its attached clients
+/// for `duration` (its default if `None`); it answers with how many clients
+/// it showed it to
+pub fn send_message_command(
+    socket: &Connection,
+    text: &str,
+    duration: Option<Duration>,
+) -> io::Result<()> {
+    let seconds = duration
+        .map(|d| d.as_secs_f64().to_string())
+        .unwrap_or_default();
+    socket.send(&Frame::command("message", &[&seconds, text]))
+}
+
+/// Ask the daemon for up to `count` lines of the session's scrollback from
+/// line `from` on; it answers with a JSON `ScrollbackPage`
+pub fn send_scrollback_command(socket: &Connection, from: u64, count: usize) -> io::Result<()> {
+    socket.send(&Frame::command(
+        "scrollback",
+        &[&from.to_string(), &count.to_string()],
+    ))
+}
+
+/// Ask the daemon for the first line of scrollback from `from` on (or back,
+/// unless `forward`) matching the regex `pattern`; it answers with the JSON
+/// line number or `null`
+pub fn send_scrollback_search_command(
+    socket: &Connection,
+    pattern: &str,
+    from: u64,
+    forward: bool,
+) -> io::Result<()> {
+    let direction = if forward { "forward" } else { "backward" };
+    socket.send(&Frame::command(
+        "scrollback_search",
+        &[&from.to_string(), direction, pattern],
+    ))
+}
+
+/// Ask the daemon for the first line of scrollback from `from` on (or back,
+/// unless `forward`) that a prompt started on; it answers with the JSON
+/// line number or `null`
+pub fn send_scrollback_prompt_command(
+    socket: &Connection,
+    from: u64,
+    forward: bool,
+) -> io::Result<()> {
+    let direction = if forward { "forward" } else { "backward" };
+    socket.send(&Frame::command(
+        "scrollback_prompt",
+        &[&from.to_string(), direction],
+    ))
+}
+
+/// Keep `text` in the session's paste buffer, replacing what was there
+pub fn send_paste_buffer_command(socket: &Connection, text: &str) -> io::Result<()> {
+    socket.send(&Frame::command("paste_buffer", &[]))?;
+    socket.send(&Frame::Data(text.as_bytes().to_vec()))
+}
+
+/// Type `data` into the session's shell without attaching; the daemon
+/// answers with the JSON number of bytes, or `null` if the shell has exited
+pub fn send_input_command(socket: &Connection, data: &[u8]) -> io::Result<()> {
+    socket.send(&Frame::command("input", &[]))?;
+    socket.send(&Frame::Data(data.to_vec()))
+}
+
+/// Ask the daemon to type the paste buffer into the shell; it answers with
+/// the JSON number of bytes or `null` if the buffer is empty
+pub fn send_paste_command(socket: &Connection) -> io::Result<()> {
+    socket.send(&Frame::command("paste", &[]))
+}
+
+/// Ask the daemon about its windows; it answers with a JSON list of
+/// `WindowSummary`, window 0 first
+pub fn send_windows_command(socket: &Connection) -> io::Result<()> {
+    socket.send(&Frame::command("windows", &[]))
+}
+
+/// Open a window running `command`, or a shell if it is empty. An attached
+/// client is switched to it; a control connection is answered with the JSON
+/// `Result` of its number.
+pub fn send_window_new_command(
+    socket: &Connection,
+    name: Option<&str>,
+    command: &[String],
+) -> io::Result<()> {
+    let mut args = vec![name.unwrap_or_default()];
+    args.extend(command.iter().map(String::as_str));
+    socket.send(&Frame::command("window_new", &args))
+}
+
+/// Show another window on this client: `next`, `prev` or a window's number
+pub fn send_window_select_command(socket: &Connection, target: &str) -> io::Result<()> {
+    socket.send(&Frame::command("window_select", &[target]))
+}
+
+/// Show these windows in split panes of these sizes; an empty list goes back
+/// to showing one window
+pub fn send_panes_command(socket: &Connection, panes: &[(u32, u16, u16)]) -> io::Result<()> {
+    let args: Vec<String> = panes
+        .iter()
+        .map(|(window, cols, rows)| format!("{}:{}x{}", window, cols, rows))
+        .collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    socket.send(&Frame::command("panes", &args))
+}
+
+/// Turn on or off typing this client's input into the linked sessions too
+pub fn send_broadcast_command(socket: &Connection) -> io::Result<()> {
+    socket.send(&Frame::command("broadcast", &[]))
+}
+
+/// Close a window, hanging up its shell; the daemon answers with JSON `true`
+/// if there was such a window
+pub fn send_window_kill_command(socket: &Connection, window: u32) -> io::Result<()> {
+    socket.send(&Frame::command("window_kill", &[&window.to_string()]))
+}
+
+/// Ask the daemon for the text on the screen; it answers with a JSON list of
+/// rows
+pub fn send_capture_screen_command(socket: &Connection) -> io::Result<()> {
+    socket.send(&Frame::command("capture_screen", &[]))
+}
+
+/// Ask the daemon for its internal state; it answers with a JSON `DaemonDump`
+pub fn send_debug_dump_command(socket: &Connection) -> io::Result<()> {
+    socket.send(&Frame::command("debug_dump", &[]))
+}
+
+/// Ask the daemon for its counters; it answers with JSON `SessionMetrics`
+pub fn send_metrics_command(socket: &Connection) -> io::Result<()> {
+    socket.send(&Frame::command("metrics", &[]))
+}
+
+/// Let a client waiting to attach in, or turn it away (attach approval)
+pub fn send_approve_attach_command(
+    socket: &Connection,
+    client_id: &str,
+    allow: bool,
+) -> io::Result<()> {
+    let answer = if allow { "allow" } else { "deny" };
+    socket.send(&Frame::command("approve_attach", &[client_id, answer]))
+}
+
+/// Tell the daemon the users its session is shared with have changed; it
+/// answers with the path of the socket they connect to, empty if there are
+/// none left
+pub fn send_share_command(socket: &Connection) -> io::Result<()> {
+    socket.send(&Frame::command("share", &[]))
+}
+
+/// Ask the daemon to record its output to `path`, an absolute path, in a new
+/// file each time one reaches `max_bytes` (0 for never); it answers with a
+/// JSON result
+pub fn send_record_command(socket: &Connection, path: &Path, max_bytes: u64) -> io::Result<()> {
+    socket.send(&Frame::command(
+        "record",
+        &[&max_bytes.to_string(), &path.to_string_lossy()],
+    ))
+}
+
+/// Ask the daemon to stop recording; it answers with the JSON summary of the
+/// recording, or `null` if there was none
+pub fn send_record_stop_command(socket: &Connection) -> io::Result<()> {
+    socket.send(&Frame::command("record_stop", &[]))
+}
+
+/// Ask the daemon to send `events` (e.g. `output`) as they happen, and how
+/// the shell or command exits, until one side hangs up
+pub fn send_subscribe_command(socket: &Connection, events: &[&str]) -> io::Result<()> {
+    socket.send(&Frame::command("subscribe", events))
+}
+
+/// Update the environment the daemon starts the shell with next time; an
+/// empty value unsets a variable. Attaching clients send their terminal's.
+pub fn send_setenv_command(socket: &Connection, vars: &BTreeMap<String, String>) -> io::Result<()> {
+    let assignments: Vec<String> = vars.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    let args: Vec<&str> = assignments.iter().map(String::as_str).collect();
+    socket.send(&Frame::command("setenv", &args))
+}
+
+/// Tell the daemon which terminal an attaching client is on
+pub fn send_client_tty_command(socket: &Connection, tty: &str) -> io::Result<()> {
+    socket.send(&Frame::command("tty", &[tty]))
+}
+
+/// How a just-accepted connection opened
+#[derive(Debug, PartialEq, Eq)]
+pub enum Opening {
+    /// A framed client's hello, already answered with the daemon's
+    Framed(Role, Hello),
+    /// A control command from a client that predates framing
+    LegacyControl(String, Vec<String>),
+    /// Input or attach-time commands from a client that predates framing,
+    /// or nothing yet (`nds run` from before framing sends nothing)
+    Legacy,
+    /// The peer hung up without sending anything, or sent a bad hello
+    Closed,
+}
+
+/// Work out how a just-accepted connection opened, consuming its hello or
+/// control command. Clients send these straight after connecting, so this
+/// waits briefly for the first bytes rather than guessing from an empty socket.
+pub fn take_opening(stream: &UnixStream) -> Opening {
+    let _ = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT));
+    let mut buf = [0u8; 64];
+    let peeked = loop {
+        let n = unsafe {
+            libc::recv(
+                stream.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                libc::MSG_PEEK,
+            )
+        };
+        // A signal (the daemon's SIGCHLD) is no reason to give up on the client
+        if n >= 0 || io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+            break n;
+        }
+    };
+    let peeked = match peeked {
+        0 => return Opening::Closed,
+        n if n < 0 => return Opening::Legacy,
+        n => &buf[..n as usize],
+    };
+
+    if peeked[0] == HELLO_MAGIC[0] {
+        let mut opening = [0u8; OPENING_LEN];
+        if (&*stream).read_exact(&mut opening).is_err() {
+            return Opening::Closed;
+        }
+        let Some(role) = parse_client_opening(&opening) else {
+            return Opening::Closed;
+        };
+        let hello = match read_single_frame(&mut &*stream) {
+            Ok(Frame::Hello(hello)) if hello.version > 0 => hello,
+            _ => return Opening::Closed,
+        };
+        // Clients newer than us read our version and speak it too
+        let ours = Frame::Hello(Hello::ours(CAPABILITIES));
+        if (&*stream).write_all(&ours.encode()).is_err() {
+            return Opening::Closed;
+        }
+        return Opening::Framed(role, hello);
+    }
+
+    match take_control_command(stream, peeked) {
+        Some((cmd, args)) => Opening::LegacyControl(cmd, args),
+        None => Opening::Legacy,
+    }
+}
+
+/// Commands whose text a framed client sends in a `Data` frame of its own
+/// right after the command, so that it arrives byte for byte
+const DATA_COMMANDS: &[&str] = &["paste_buffer", "input"];
+
+/// Read the command a framed control connection sends after its hello, with
+/// the data that follows one of `DATA_COMMANDS`
+pub fn read_control_command(stream: &UnixStream) -> Option<(String, Vec<String>, Option<Vec<u8>>)> {
+    let _ = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT));
+    let Ok(Frame::Command { name, args }) = read_single_frame(&mut &*stream) else {
+        return None;
+    };
+    if !DATA_COMMANDS.contains(&name.as_str()) {
+        return Some((name, args, None));
+    }
+    match read_single_frame(&mut &*stream) {
+        Ok(Frame::Data(data)) => Some((name, args, Some(data))),
+        _ => None,
+    }
+}
+
+/// Consume an old client's control command from the front of `peeked`
+fn take_control_command(stream: &UnixStream, peeked: &[u8]) -> Option<(String, Vec<String>)> {
+    let (cmd, args) = parse_nds_command(peeked)?;
+    if !CONTROL_COMMANDS.contains(&cmd.as_str()) {
+        return None;
+    }
+    let mut consumed = vec![0u8; get_command_end(peeked)?];
+    (&*stream).read_exact(&mut consumed).ok()?;
+    Some((cmd, args))
+}
+
+/// Message sent to old clients' waiters once the shell or command has exited
+pub fn exit_notice(status: ExitStatus) -> String {
+    match status {
+        ExitStatus::Code(code) => format!("\x1b]nds:exited:code:{}\x07", code),
+        ExitStatus::Signal(signal) => format!("\x1b]nds:exited:signal:{}\x07", signal),
+    }
+}
 
-    // Format: \x1b]nds:resize:<cols>:<rows>\x07
-    let resize_cmd = format!("\x1b]nds:resize:{}:{}\x07", cols, rows);
-    socket.write_all(resize_cmd.as_bytes())?;
-    socket.flush()
+/// Daemon's answer to an old client's control command, marked so the caller
+/// can find it even after session output. `payload` must not contain BEL.
+pub fn control_reply(cmd: &str, payload: &str) -> String {
+    format!("\x1b]nds:reply:{}:{}\x07", cmd, payload)
 }
 
-/// Parse NDS commands from socket data with input validation
+/// Parse NDS commands embedded in an old client's input, with validation
 /// Returns Some((command, args)) if a valid command is found, None otherwise
 pub fn parse_nds_command(data: &[u8]) -> Option<(String, Vec<String>)> {
     // Check for special NDS commands with size limit for security
@@ -94,6 +563,13 @@ fn is_valid_command(cmd: &str) -> bool {
         "scrollback",
         "clear",
         "refresh",
+        "detach_others",
+        "wait",
+        "restart",
+        "clients",
+        "disconnect_client",
+        "tty",
+        "resize_policy",
     ];
 
     if let Some(command) = cmd.split(':').next() {
@@ -1,224 +1,1024 @@
+use std::collections::{BTreeMap, VecDeque};
 use std::io::{self, Read, Write};
-use std::os::unix::io::RawFd;
+use std::os::fd::{AsFd, BorrowedFd};
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, OnceLock};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::thread;
 use std::time::{Duration, Instant};
 
-use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use chrono::{DateTime, Utc};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
 use nix::sys::signal::{kill, Signal};
 use nix::sys::termios::Termios;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::{close, dup2, execvp, fork, setsid, ForkResult, Pid};
+use regex::Regex;
 
-use super::client::ClientInfo;
-use super::health_monitor::{attempt_recovery, HealthMonitor, RecoveryStrategy};
+use super::approval::{
+    self, settle, ApprovalEvent, AttachRequest, PendingAttach, APPROVAL_TIMEOUT, APPROVE_ATTACH,
+    ATTACH_REQUEST_EVENT, ATTACH_SETTLED_EVENT,
+};
+use super::client::{ClientEvent, ClientInfo, ClientSummary, ControlConn};
+use super::clipboard::ClipboardFilter;
+use super::debug_dump::{BufferDump, DaemonDump, PtyDump, PtyErrorDump, TerminalDump};
+use super::events::{EventSubscription, Watchers};
+use super::health_monitor::{
+    attempt_recovery, select_recovery_strategy, HealthMonitor, RecoveryStrategy,
+};
+use super::help_overlay::show_help;
+use super::input_rate::InputRatePolicy;
 use super::io_handler::{
-    spawn_resize_monitor_thread, spawn_socket_to_stdout_thread, PtyIoHandler, ScrollbackHandler,
-    DEFAULT_BUFFER_SIZE,
+    lock_panes, spawn_resize_monitor_thread, spawn_socket_to_stdout_thread, BoundarySplitter,
+    PtyIoHandler, DEFAULT_BUFFER_SIZE,
 };
+use super::links::Mirror;
+use super::metrics::{DaemonCounters, SessionMetrics};
+use super::output_queue::SharedOutput;
+use super::panes::{Panes, Split};
+use super::peer::{peer_credentials, PeerAccess, PeerCredentials, ShareRole};
+use super::process_info::command_line;
+use super::protocol::{Connection, Frame, Role};
+use super::quick_switch::QuickSwitcher;
+use super::recording::{Recording, RecordingSummary};
+use super::redraw::RedrawStrategy;
+use super::resize::ResizePolicy;
+use super::respawn::{RespawnBackoff, RespawnPolicy};
+use super::screen::{Screen, ScreenView};
 use super::session_switcher::{SessionSwitcher, SwitchResult};
-use super::socket::{create_listener, get_command_end, parse_nds_command, send_resize_command};
+use super::socket::{
+    create_abstract_listener, create_listener, create_shared_listener, read_control_command,
+    send_approve_attach_command, send_broadcast_command, send_capture_screen_command,
+    send_client_tty_command, send_clients_command, send_debug_dump_command, send_detach_command,
+    send_detach_others_command, send_disconnect_client_command, send_foreground_command,
+    send_input, send_input_command, send_message_command, send_metrics_command,
+    send_paste_buffer_command, send_paste_command, send_record_command, send_record_stop_command,
+    send_refresh_command, send_resize_command, send_resize_policy_command, send_restart_command,
+    send_scrollback_command, send_scrollback_prompt_command, send_scrollback_search_command,
+    send_setenv_command, send_share_command, send_signal_command, send_subscribe_command,
+    send_wait_command, send_window_kill_command, send_window_new_command,
+    send_window_select_command, send_windows_command, take_opening, Opening,
+};
+use super::status_message::{StatusMessage, DEFAULT_MESSAGE_DURATION};
 use super::terminal::{
-    capture_terminal_state, get_terminal_size, restore_terminal, save_terminal_state, send_refresh,
-    send_terminal_refresh_sequences, set_raw_mode, set_stdin_blocking, set_terminal_size,
+    capture_terminal_state, get_terminal_size, get_terminal_size_of, restore_terminal,
+    save_terminal_state, send_terminal_refresh_sequences, set_raw_mode, set_stdin_blocking,
+    set_terminal_size, suspend_self, take_suspend_request, SuspendGuard,
 };
+use super::wakeup::Wakeup;
+use super::windows::{WindowSummary, Windows, WINDOWS};
+use crate::compression::Compressor;
+use crate::config::Config;
 use crate::error::{NdsError, Result};
-use crate::pty_buffer::PtyBuffer;
-use crate::scrollback::ScrollbackViewer;
-use crate::session::Session;
-
-#[derive(Debug, Clone)]
-struct TerminalModeTracker {
-    cursor_visible: bool,
-    application_cursor_keys: bool,
-    alternate_screen: bool,
-    bracketed_paste: bool,
-    tail: Vec<u8>,
-}
+use crate::history_v2::SessionHistory;
+use crate::hooks::{self, Hook};
+use crate::keymap::{Escape, KeyMap};
+use crate::manager::SessionManager;
+use crate::pty_buffer::{PtyBuffer, DEFAULT_BUFFER_MEMORY};
+use crate::remote::{self, RemoteEndpoint, RemoteListener};
+use crate::scrollback::{write_scrollback, ScrollbackSource, ScrollbackViewer};
+use crate::scrollback_buffer::{ScrollbackBuffer, ScrollbackPage, DEFAULT_SCROLLBACK_LIMIT};
+use crate::session::{ExitStatus, ForegroundProcess, Session};
+use crate::session_env::{client_env, SessionEnv};
+use crate::session_log::{log_path, SessionLog};
+use crate::text_input;
+
+/// Start replaying what was buffered while detached to a new client; what
+/// doesn't go straight away follows as the client keeps up
+fn send_buffered_output_to_client(
+    client: &mut ClientInfo,
+    output_buffer: &PtyBuffer,
+) -> io::Result<()> {
+    if !output_buffer.is_empty() {
+        let replay = output_buffer.take_replay();
 
-impl Default for TerminalModeTracker {
-    fn default() -> Self {
-        TerminalModeTracker {
-            cursor_visible: true,
-            application_cursor_keys: false,
-            alternate_screen: false,
-            bracketed_paste: false,
-            tail: Vec::with_capacity(16),
+        if !replay.is_empty() {
+            if trace_enabled() {
+                trace(|| {
+                    format!(
+                        "replaying {} bytes of scrollback to client {}",
+                        replay.len(),
+                        client.id
+                    )
+                });
+            }
+            client.replay = Some(replay);
+            client.continue_replay()?;
         }
     }
+
+    Ok(())
 }
 
-impl TerminalModeTracker {
-    fn observe(&mut self, chunk: &[u8]) {
-        if chunk.is_empty() {
-            return;
-        }
+/// Bring a client's screen up to date according to the redraw strategy
+fn apply_redraw(
+    strategy: RedrawStrategy,
+    client: &mut ClientInfo,
+    screen: &Screen,
+    io_handler: &PtyIoHandler,
+) -> io::Result<()> {
+    trace(|| format!("redrawing client {} with {}", client.id, strategy));
+
+    if strategy.repaints_screen() && client.view.is_some() {
+        // Its view is drawn from the screen anyway
+        client.invalidate_view();
+    } else if strategy.repaints_screen() {
+        client.send_data(&screen.repaint())?;
+    } else if strategy.replays_modes() {
+        client.send_data(&screen.mode_sequence())?;
+    }
+    if strategy.nudges_application() {
+        io_handler.send_refresh()?;
+    }
+    Ok(())
+}
 
-        let mut changes = Vec::new();
-        self.scan(chunk, &mut changes);
+/// How often the daemon writes its last-activity timestamp to the session file
+const ACTIVITY_PERSIST_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often a ringing session is written to its history and runs the
+/// on-bell hook, however often it rings
+const BELL_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the daemon drops clients whose sockets have gone bad
+const CLIENT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long turning broadcasting (`~b`) on or off is shown for
+const BROADCAST_MESSAGE_DURATION: Duration = Duration::from_secs(3);
+
+/// Requests from clients that the main loop acts on
+#[derive(Debug, Default)]
+struct ClientRequests {
+    /// Kill and re-exec the shell or command (`nds restart`)
+    restart: bool,
+    /// Detach every attached client (`nds detach`)
+    detach: bool,
+    /// Show a message over every attached client (`nds message`)
+    message: Option<StatusMessage>,
+    /// Scrollback asked for by the viewer (`~h`), answered once the output
+    /// read this pass is in the buffer
+    scrollback: Vec<(ControlConn, ScrollbackRequest)>,
+    /// Text yanked in the scrollback viewer's copy mode
+    paste_buffer: Option<String>,
+    /// `nds paste` connections, answered once the buffer is in the PTY
+    paste: Vec<ControlConn>,
+    /// Connections typing text into the shell (the server's `send-input`),
+    /// answered once it is in the PTY
+    input: Vec<(ControlConn, Vec<u8>)>,
+    /// `nds capture` connections, answered with the screen's text
+    capture_screen: Vec<ControlConn>,
+    /// `nds record` connections, answered once the recording has started or
+    /// stopped
+    record: Vec<(ControlConn, RecordRequest)>,
+    /// `nds debug dump` connections, answered with the daemon's state
+    debug_dump: Vec<ControlConn>,
+    /// `nds metrics` connections, answered with the daemon's counters
+    metrics: Vec<ControlConn>,
+    /// `nds share` connections, answered once the shared socket is open or
+    /// closed
+    share: Vec<ControlConn>,
+    /// `nds window` connections, answered once the window is listed, opened
+    /// or closed
+    windows: Vec<(ControlConn, WindowRequest)>,
+    /// A client to let in once an attached one agrees (attach approval)
+    awaiting_approval: Option<ClientInfo>,
+    /// Attached clients' answers about waiting ones: client ID and whether
+    /// to let it in
+    approvals: Vec<(String, bool)>,
+    /// Input from clients broadcasting it, for the linked sessions (`~b`)
+    broadcast: Vec<u8>,
+}
 
-        if !self.tail.is_empty() {
-            let mut combined = Vec::with_capacity(self.tail.len() + chunk.len());
-            combined.extend_from_slice(&self.tail);
-            combined.extend_from_slice(chunk);
-            self.scan(&combined, &mut changes);
-        }
+/// What `nds record` asks the daemon to do
+#[derive(Debug)]
+enum RecordRequest {
+    Start { path: PathBuf, max_bytes: u64 },
+    Stop,
+}
 
-        const MAX_TAIL: usize = 7; // longest tracked sequence length minus one
-        self.tail.clear();
-        let take = chunk.len().min(MAX_TAIL);
-        self.tail.extend_from_slice(&chunk[chunk.len() - take..]);
+/// What `nds window` asks the daemon to do
+#[derive(Debug)]
+enum WindowRequest {
+    List,
+    New {
+        name: Option<String>,
+        command: Option<Vec<String>>,
+    },
+    Close(u32),
+}
 
-        if trace_enabled() && !changes.is_empty() {
-            trace(|| format!("observed sequences: {}", changes.join(", ")));
-        }
-    }
+/// What the scrollback viewer asks the daemon for
+#[derive(Debug)]
+enum ScrollbackRequest {
+    /// Up to `count` lines from line `from` on
+    Page { from: u64, count: usize },
+    /// The next line from `from` that matches `pattern`
+    Search {
+        pattern: String,
+        from: u64,
+        forward: bool,
+    },
+    /// The next line from `from` that a prompt started on
+    Prompt { from: u64, forward: bool },
+}
 
-    fn scan(&mut self, data: &[u8], changes: &mut Vec<&'static str>) {
-        if contains_sequence(data, b"\x1b[?25l") {
-            if self.cursor_visible {
-                self.cursor_visible = false;
-                changes.push("?25l");
+/// How long `nds clients` and client counts wait for a daemon to answer
+const CONTROL_REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long the scrollback viewer waits for the daemon to search
+const SCROLLBACK_SEARCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Read from a control connection until the daemon's reply to `cmd` arrives.
+/// A daemon from before framing may send session output first.
+fn read_control_reply(socket: &Connection, cmd: &str) -> Result<String> {
+    let mut decoder = socket.decoder();
+    loop {
+        match socket.read_frame(&mut decoder)? {
+            Some(Frame::Reply { name, payload }) if name == cmd => return Ok(payload),
+            Some(Frame::Reply { name, payload }) if name == "refused" => {
+                return Err(NdsError::PermissionDenied(payload))
             }
-        }
-        if contains_sequence(data, b"\x1b[?25h") {
-            if !self.cursor_visible {
-                self.cursor_visible = true;
-                changes.push("?25h");
+            Some(_) => continue,
+            None => {
+                return Err(NdsError::SocketError(format!(
+                    "session closed the connection without answering '{}'",
+                    cmd
+                )))
             }
         }
-        if contains_sequence(data, b"\x1b[?1h") {
-            if !self.application_cursor_keys {
-                self.application_cursor_keys = true;
-                changes.push("?1h");
-            }
+    }
+}
+
+/// Fail with a clear error if the daemon is known not to understand `cmd`,
+/// rather than sending something it would misread
+fn require_capability(socket: &Connection, session: &Session, cmd: &str) -> Result<()> {
+    if socket.supports(cmd) {
+        return Ok(());
+    }
+    let daemon = socket.daemon().map(|hello| hello.software.as_str());
+    eprintln!(
+        "Session {} is run by {}, which doesn't support '{}'. Restart the session to use it.",
+        session.id,
+        daemon.unwrap_or("an older nds"),
+        cmd
+    );
+    Err(NdsError::SocketError(format!(
+        "session {} doesn't support '{}'",
+        session.id, cmd
+    )))
+}
+
+/// Give up on the PTY after this many failed reads in a row, unless the
+/// health monitor still thinks the session is fine
+const MAX_CONSECUTIVE_PTY_ERRORS: u32 = 10;
+
+/// What the daemon's main loop waits on for the PTY master
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct MasterWatch {
+    pub fd: RawFd,
+    /// False while a client is holding up output
+    pub read: bool,
+    /// True while input is queued for the PTY
+    pub write: bool,
+}
+
+/// What the daemon's main loop waits on for one attached client
+pub(super) struct ClientWatch {
+    #[cfg_attr(not(feature = "async"), allow(dead_code))]
+    pub id: String,
+    pub fd: RawFd,
+    /// False while the client's input is throttled
+    pub read: bool,
+    /// True while output is queued for the client
+    pub write: bool,
+}
+
+/// State the daemon's main loop carries between iterations, shared by the
+/// blocking loop in `run_detached` and the tokio one
+pub(super) struct DaemonLoop {
+    listener: UnixListener,
+    wakeup: Wakeup,
+    running: Arc<AtomicBool>,
+    output_buffer: PtyBuffer,
+    active_clients: Vec<ClientInfo>, // Support multiple concurrent clients
+    watchers: Watchers,              // `nds wait` and `nds watch` connections; they get no output
+    buffer: Vec<u8>,
+    session_id: String,
+    io_handler: PtyIoHandler,   // Replaced if the PTY has to be re-opened
+    splitter: BoundarySplitter, // Holds reads that end partway through a sequence
+    screen: Screen,             // What the PTY's screen looks like, for repainting clients
+    health_monitor: HealthMonitor,
+    _monitor_thread: thread::JoinHandle<()>,
+    // Track consecutive errors for recovery
+    consecutive_pty_errors: u32,
+    last_pty_error: Option<PtyErrorDump>, // For `nds debug dump`
+    counters: DaemonCounters,             // For `nds metrics`
+    last_recovery_attempt: Instant,
+    last_client_health_check: Instant,
+    activity: ActivityTracker,
+    bell: BellTracker,
+    // Exit handling and respawning of the shell or command
+    exit_handled: bool,
+    child_started: Instant,
+    respawn_at: Option<Instant>,
+    backoff: RespawnBackoff,
+    // Not reading the PTY while a client catches up (`NDS_OUTPUT_QUEUE_POLICY=pause`)
+    output_paused: bool,
+    message: Option<StatusMessage>, // Drawn over the bottom row of clients until it expires
+    scrollback: ScrollbackBuffer,   // The session's output by line, for the scrollback viewer
+    paste_buffer: Option<String>,   // Last text yanked in copy mode, for `nds paste`
+    recording: Option<Recording>,   // Output going to an asciicast file (`nds record`)
+    log: Option<SessionLog>,        // Output going to the session's log (`nds logs`)
+    clipboard: ClipboardFilter,     // OSC 52 clipboard writes let through or not
+    access: PeerAccess,             // Users whose connections are accepted
+    shared: Option<SharedSocket>,   // Where users the session is shared with connect
+    pending_attach: Vec<PendingAttach>, // Clients waiting to be let in
+    windows: Windows,               // Shells in the session's other windows
+    mirror: Mirror,                 // Passes input on to linked sessions
+    started_at: DateTime<Utc>,
+}
+
+impl DaemonLoop {
+    pub(super) fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// What to wait for on the master while there is a child: output unless
+    /// a client is holding it up, and room for queued input
+    pub(super) fn watched_master(&self, process: &PtyProcess) -> Option<MasterWatch> {
+        let watch = MasterWatch {
+            fd: process.master_fd,
+            read: !self.output_paused,
+            write: self.io_handler.pending_input() > 0,
+        };
+        (process.exit_status.is_none() && (watch.read || watch.write)).then_some(watch)
+    }
+
+    /// What to wait for on the other windows' masters, with the shell on
+    /// each
+    #[cfg_attr(not(feature = "async"), allow(dead_code))]
+    pub(super) fn window_watches(&self) -> Vec<(Pid, MasterWatch)> {
+        self.windows.watches()
+    }
+
+    /// What `nds debug dump` reports
+    fn dump(&self, process: &PtyProcess) -> DaemonDump {
+        let (waiters, subscribers) = self.watchers.counts();
+        let (cols, rows) = get_terminal_size_of(process.master_fd).unwrap_or((0, 0));
+        let (cursor_row, cursor_col) = self.screen.cursor();
+        let (screen_cols, screen_rows) = self.screen.size();
+        DaemonDump {
+            session_id: self.session_id.clone(),
+            daemon_pid: std::process::id(),
+            shell_pid: process.pid.as_raw(),
+            started_at: self.started_at,
+            uptime_seconds: (Utc::now() - self.started_at).num_seconds().max(0) as u64,
+            shell_uptime_seconds: self.child_started.elapsed().as_secs(),
+            exit_status: process.exit_status.map(|status| status.to_string()),
+            respawn_in_seconds: self
+                .respawn_at
+                .map(|at| at.saturating_duration_since(Instant::now()).as_secs_f64()),
+            clients: self.active_clients.iter().map(ClientInfo::dump).collect(),
+            waiters,
+            subscribers,
+            pty: PtyDump {
+                master_fd: process.master_fd,
+                cols,
+                rows,
+                pending_input_bytes: self.io_handler.pending_input(),
+                output_paused: self.output_paused,
+                consecutive_errors: self.consecutive_pty_errors,
+                last_error: self.last_pty_error.clone(),
+            },
+            buffers: BufferDump {
+                detached_output_bytes: self.output_buffer.len(),
+                detached_output_spilled_bytes: self.output_buffer.spilled_len(),
+                scrollback_bytes: self.scrollback.stored_bytes(),
+                scrollback_lines: self.scrollback.end() - self.scrollback.oldest(),
+                paste_buffer_bytes: self.paste_buffer.as_ref().map(String::len),
+            },
+            terminal: TerminalDump {
+                cols: screen_cols,
+                rows: screen_rows,
+                cursor_row,
+                cursor_col,
+                cursor_visible: self.screen.cursor_visible(),
+                alternate_screen: self.screen.alternate_screen(),
+                application_keypad: self.screen.application_keypad(),
+                modes: self.screen.modes().to_vec(),
+                bells: self.screen.bells(),
+            },
+            recording: self
+                .recording
+                .as_ref()
+                .map(|r| r.current_path().display().to_string()),
+            logging: self.log.is_some(),
         }
-        if contains_sequence(data, b"\x1b[?1l") {
-            if self.application_cursor_keys {
-                self.application_cursor_keys = false;
-                changes.push("?1l");
-            }
+    }
+
+    /// What `nds metrics` reports
+    fn metrics(&self, process: &PtyProcess) -> SessionMetrics {
+        let (pty_bytes_read, pty_bytes_written) = self.io_handler.byte_counts();
+        let clients = &self.active_clients;
+        SessionMetrics {
+            uptime_seconds: (Utc::now() - self.started_at).num_seconds().max(0) as u64,
+            shell_running: process.exit_status.is_none(),
+            clients: clients.len(),
+            client_connects: self.counters.client_connects,
+            pty_bytes_read,
+            pty_bytes_written,
+            pty_errors: self.counters.pty_errors,
+            recovery_attempts: self.counters.recovery_attempts,
+            output_buffer_bytes: self.output_buffer.len(),
+            scrollback_bytes: self.scrollback.stored_bytes(),
+            pending_input_bytes: self.io_handler.pending_input(),
+            client_queued_bytes: clients.iter().map(|c| c.pending_output.len()).sum(),
+            input_flagged_clients: clients.iter().filter(|c| c.input_rate.is_flagged()).count(),
         }
-        if contains_sequence(data, b"\x1b[?1049h") || contains_sequence(data, b"\x1b[?47h") {
-            if !self.alternate_screen {
-                self.alternate_screen = true;
-                changes.push("?1049h");
-            }
+    }
+
+    #[cfg_attr(not(feature = "async"), allow(dead_code))]
+    pub(super) fn listener_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    #[cfg_attr(not(feature = "async"), allow(dead_code))]
+    pub(super) fn shared_listener_fd(&self) -> Option<RawFd> {
+        self.shared
+            .as_ref()
+            .map(|shared| shared.listener.as_raw_fd())
+    }
+
+    #[cfg_attr(not(feature = "async"), allow(dead_code))]
+    pub(super) fn wakeup_fd(&self) -> RawFd {
+        self.wakeup.fd().as_raw_fd()
+    }
+
+    /// When the loop has to run again even if nothing happens: a pending
+    /// respawn, the client health check, writing out the last activity,
+    /// passing on output held back for the rest of a sequence or taking
+    /// down a status message
+    pub(super) fn deadline(&self) -> Instant {
+        let mut deadline = self.last_client_health_check + CLIENT_HEALTH_CHECK_INTERVAL;
+        for at in [
+            self.respawn_at,
+            self.activity.next_persist(),
+            self.splitter.deadline(),
+            self.windows.deadline(),
+            self.message.as_ref().map(StatusMessage::until),
+            self.pending_attach
+                .iter()
+                .map(|pending| pending.until)
+                .min(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            deadline = deadline.min(at);
         }
-        if contains_sequence(data, b"\x1b[?1049l") || contains_sequence(data, b"\x1b[?47l") {
-            if self.alternate_screen {
-                self.alternate_screen = false;
-                changes.push("?1049l");
-            }
+        deadline
+    }
+
+    /// What to wait on for each client. Throttled clients are left out
+    /// until their window rolls over, which the returned deadline covers,
+    /// and no client's input is read while the PTY is behind on input.
+    pub(super) fn client_watches(&mut self) -> (Vec<ClientWatch>, Instant) {
+        let rate_policy = InputRatePolicy::from_env();
+        let now = Instant::now();
+        let backlogged = self.io_handler.input_backlogged();
+        let mut deadline = self.deadline();
+        let watches = self
+            .active_clients
+            .iter_mut()
+            .map(|client| {
+                let throttled = client.input_rate.is_throttled(now, &rate_policy);
+                if throttled {
+                    deadline = deadline.min(client.input_rate.window_end());
+                }
+                ClientWatch {
+                    id: client.id.clone(),
+                    fd: client.stream.as_raw_fd(),
+                    read: !throttled && !backlogged,
+                    write: !client.pending_output.is_empty(),
+                }
+            })
+            .collect();
+        (watches, deadline)
+    }
+
+    /// Block until the listener, the PTY (`None` once the child is gone),
+    /// another window's PTY, a client or the wakeup pipe needs attention, or until `deadline`.
+    /// Returns whether anything did.
+    pub(super) fn wait_for_activity(
+        &mut self,
+        master: Option<MasterWatch>,
+        deadline: Instant,
+    ) -> bool {
+        let (watches, watch_deadline) = self.client_watches();
+        let deadline = deadline.min(watch_deadline);
+
+        let mut fds = vec![
+            PollFd::new(self.listener.as_fd(), PollFlags::POLLIN),
+            PollFd::new(self.wakeup.fd(), PollFlags::POLLIN),
+        ];
+        if let Some(shared) = &self.shared {
+            fds.push(PollFd::new(shared.listener.as_fd(), PollFlags::POLLIN));
         }
-        if contains_sequence(data, b"\x1b[?2004h") {
-            if !self.bracketed_paste {
-                self.bracketed_paste = true;
-                changes.push("?2004h");
-            }
+        // These all stay open for the whole loop iteration
+        let borrow = |fd| unsafe { BorrowedFd::borrow_raw(fd) };
+        if let Some(master) = master {
+            let mut events = PollFlags::empty();
+            events.set(PollFlags::POLLIN, master.read);
+            events.set(PollFlags::POLLOUT, master.write);
+            fds.push(PollFd::new(borrow(master.fd), events));
         }
-        if contains_sequence(data, b"\x1b[?2004l") {
-            if self.bracketed_paste {
-                self.bracketed_paste = false;
-                changes.push("?2004l");
-            }
+        for (_, window) in self.windows.watches() {
+            let mut events = PollFlags::POLLIN;
+            events.set(PollFlags::POLLOUT, window.write);
+            fds.push(PollFd::new(borrow(window.fd), events));
+        }
+        for watch in &watches {
+            let mut events = PollFlags::empty();
+            events.set(PollFlags::POLLIN, watch.read);
+            events.set(PollFlags::POLLOUT, watch.write);
+            fds.push(PollFd::new(borrow(watch.fd), events));
         }
+
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        // Round up so the deadline has passed when poll returns
+        let millis = timeout.as_nanos().div_ceil(1_000_000).min(u16::MAX as u128) as u16;
+        // EINTR (e.g. SIGCHLD) just means go round the loop again
+        let ready = poll(&mut fds, PollTimeout::from(millis)).is_ok_and(|n| n > 0);
+        self.wakeup.drain();
+        ready
+    }
+
+    /// Stop monitoring and write out what is left once the loop ends
+    pub(super) fn finish(mut self) {
+        self.health_monitor.stop_monitoring();
+        self.activity.flush(&self.session_id);
+    }
+}
+
+/// Run a session's daemon: on tokio in builds with the `async` feature,
+/// unless `NDS_ASYNC_DAEMON=0` asks for the blocking loop
+fn run_daemon(process: PtyProcess) -> Result<()> {
+    #[cfg(feature = "async")]
+    if super::spawn_async::async_daemon_enabled() {
+        return super::spawn_async::AsyncPtyProcess::new(process).run_detached();
     }
+    process.run_detached()
+}
 
-    fn apply_to_client(&self, client: &mut ClientInfo) -> io::Result<()> {
-        let mut seq = Vec::new();
-        let mut applied = Vec::new();
+/// Upper bound on reads when draining the PTY after the child exits, in case
+/// a background job keeps the PTY open and writing
+const MAX_DRAIN_READS: usize = 256;
+
+/// Tracks shell output and client input so idle sessions can be found later.
+/// Updates are batched to avoid rewriting the metadata file on every keystroke.
+struct ActivityTracker {
+    last_output: Option<chrono::DateTime<chrono::Utc>>,
+    last_input: Option<chrono::DateTime<chrono::Utc>>,
+    dirty: bool,
+    last_persist: Instant,
+}
 
-        if self.alternate_screen {
-            push_sequence(&mut seq, &mut applied, b"\x1b[?1049h", "?1049h");
-        } else {
-            push_sequence(&mut seq, &mut applied, b"\x1b[?1049l", "?1049l");
+impl ActivityTracker {
+    fn new() -> Self {
+        Self {
+            last_output: None,
+            last_input: None,
+            dirty: false,
+            last_persist: Instant::now(),
         }
+    }
 
-        if self.bracketed_paste {
-            push_sequence(&mut seq, &mut applied, b"\x1b[?2004h", "?2004h");
-        } else {
-            push_sequence(&mut seq, &mut applied, b"\x1b[?2004l", "?2004l");
+    fn touch_output(&mut self) {
+        self.last_output = Some(chrono::Utc::now());
+        self.dirty = true;
+    }
+
+    fn touch_input(&mut self) {
+        self.last_input = Some(chrono::Utc::now());
+        self.dirty = true;
+    }
+
+    fn persist_if_due(&mut self, session_id: &str) {
+        if !self.dirty || self.last_persist.elapsed() < ACTIVITY_PERSIST_INTERVAL {
+            return;
         }
+        self.flush(session_id);
+    }
 
-        if self.application_cursor_keys {
-            push_sequence(&mut seq, &mut applied, b"\x1b[?1h", "?1h");
-        } else {
-            push_sequence(&mut seq, &mut applied, b"\x1b[?1l", "?1l");
+    /// When `persist_if_due` next has something to write
+    fn next_persist(&self) -> Option<Instant> {
+        self.dirty
+            .then(|| self.last_persist + ACTIVITY_PERSIST_INTERVAL)
+    }
+
+    fn flush(&mut self, session_id: &str) {
+        if self.dirty {
+            let _ = Session::update_activity(session_id, self.last_output, self.last_input);
         }
+        self.dirty = false;
+        self.last_persist = Instant::now();
+    }
+}
 
-        if self.cursor_visible {
-            push_sequence(&mut seq, &mut applied, b"\x1b[?25h", "?25h");
-        } else {
-            push_sequence(&mut seq, &mut applied, b"\x1b[?25l", "?25l");
+/// Follows the bell: a session that rings with nobody attached is marked
+/// until a client attaches, and bells are logged to the session's history
+/// and run the on-bell hook
+#[derive(Default)]
+struct BellTracker {
+    marked: bool,
+    last_logged: Option<Instant>,
+}
+
+impl BellTracker {
+    /// The application rang the bell; `seen` if a client was attached to hear it
+    fn ring(&mut self, session_id: &str, seen: bool) {
+        if !seen && !self.marked {
+            self.marked = Session::set_bell(session_id, Some(chrono::Utc::now())).is_ok();
+        }
+        if self
+            .last_logged
+            .is_some_and(|at| at.elapsed() < BELL_LOG_INTERVAL)
+        {
+            return;
         }
+        self.last_logged = Some(Instant::now());
+        if let Ok(session) = Session::load(session_id) {
+            let _ = SessionHistory::record_bell(&session);
+            hooks::run(Hook::Bell, &session);
+        }
+    }
 
-        if !seq.is_empty() {
-            client.send_data(&seq)?;
-            client.flush_pending()?;
+    /// A client is attached, so any bell has been seen
+    fn seen(&mut self, session_id: &str) {
+        if self.marked {
+            let _ = Session::set_bell(session_id, None);
+            self.marked = false;
         }
+    }
+}
+
+/// Ring the bell on clients drawn from the screen, who don't get the raw
+/// output it came in
+fn forward_bell(active_clients: &mut [ClientInfo]) {
+    for client in active_clients.iter_mut().filter(|c| c.view.is_some()) {
+        let _ = client.send_data(b"\x07");
+    }
+}
+
+/// `KEY=VALUE` arguments of a `setenv` command; anything else is ignored
+fn parse_setenv(args: &[String]) -> BTreeMap<String, String> {
+    args.iter()
+        .filter_map(|arg| arg.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Name and command of a `window_new` command; empty ones mean none
+fn parse_window_new(args: &[String]) -> (Option<String>, Option<Vec<String>>) {
+    match args.split_first() {
+        Some((name, command)) => (
+            Some(name.clone()).filter(|name| !name.is_empty()),
+            Some(command.to_vec()).filter(|command| !command.is_empty()),
+        ),
+        None => (None, None),
+    }
+}
+
+/// A pane from a `panes` command: `1:80x24` is window 1 in an 80x24 pane
+fn parse_pane(arg: &str) -> Option<(u32, (u16, u16))> {
+    let (window, size) = arg.split_once(':')?;
+    let (cols, rows) = size.split_once('x')?;
+    let size = (cols.parse().ok()?, rows.parse().ok()?);
+    Some((window.parse().ok()?, size)).filter(|(_, (cols, rows))| *cols > 0 && *rows > 0)
+}
 
-        if trace_enabled() && !applied.is_empty() {
-            trace(|| format!("reapplied to client {}: {}", client.id, applied.join(", ")));
+/// Why a guest with `role` can't send `cmd`
+fn guest_refusal(role: ShareRole, cmd: &str) -> String {
+    format!("{} guests can't use '{}'", role, cmd)
+}
+
+/// A connection let in by `accept_connection`
+struct Accepted {
+    stream: UnixStream,
+    /// The role of a user the session is shared with; `None` for those with
+    /// full access
+    guest: Option<ShareRole>,
+}
+
+/// Take a waiting connection off the listener, turning away users `check`
+/// doesn't allow before anything is read from them
+fn accept_connection(
+    listener: &UnixListener,
+    check: impl FnOnce(Option<&PeerCredentials>) -> std::result::Result<Option<ShareRole>, String>,
+) -> Option<Accepted> {
+    // Nothing waiting, or an error accepting; either way, try again next pass
+    let (stream, _) = listener.accept().ok()?;
+    let peer = peer_credentials(&stream).ok();
+    match check(peer.as_ref()) {
+        Ok(guest) => Some(Accepted { stream, guest }),
+        Err(reason) => {
+            eprintln!(
+                "Refused connection from pid {}: {}",
+                peer.and_then(|p| p.pid).unwrap_or_default(),
+                reason
+            );
+            let _ = (&stream).write_all(&Frame::reply("refused", &reason).encode());
+            None
         }
+    }
+}
 
-        Ok(())
+/// The second socket of a shared session (`nds share`), which other users
+/// can reach. Removed when the session stops being shared or ends.
+struct SharedSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl SharedSocket {
+    fn open(session_id: &str) -> Result<Self> {
+        let (listener, path) = create_shared_listener(session_id)?;
+        listener.set_nonblocking(true)?;
+        Ok(SharedSocket { listener, path })
     }
 }
 
-fn contains_sequence(haystack: &[u8], needle: &[u8]) -> bool {
-    if needle.is_empty() || needle.len() > haystack.len() {
-        return false;
+impl Drop for SharedSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
     }
+}
 
-    haystack
-        .windows(needle.len())
-        .any(|window| window == needle)
+/// Open the shared socket if the session is shared with anyone and close it
+/// if not, returning its path while open
+fn update_shared_socket(
+    shared: &mut Option<SharedSocket>,
+    session_id: &str,
+    shared_with: &BTreeMap<String, ShareRole>,
+) -> Result<Option<PathBuf>> {
+    if shared_with.is_empty() {
+        *shared = None;
+    } else if shared.is_none() {
+        *shared = Some(SharedSocket::open(session_id)?);
+    }
+    Ok(shared.as_ref().map(|shared| shared.path.clone()))
 }
 
-fn send_buffered_output_to_client(
-    client: &mut ClientInfo,
-    output_buffer: &PtyBuffer,
-    io_handler: &PtyIoHandler,
-) -> io::Result<()> {
-    if !output_buffer.is_empty() {
-        let mut buffered_data = Vec::new();
-        output_buffer.drain_to(&mut buffered_data);
+/// The users the session is shared with, re-read so `nds share` applies at once
+fn shared_with(session_id: &str) -> BTreeMap<String, ShareRole> {
+    Session::load(session_id)
+        .map(|session| session.shared_with)
+        .unwrap_or_default()
+}
 
-        if !buffered_data.is_empty() {
-            client.send_data(&buffered_data)?;
-            client.flush_pending()?;
+/// Record a client's environment for the next shell to start, and queue
+/// variables in `update_environment` that changed for the running shell's
+/// prompt hook
+fn apply_setenv(session_id: &str, args: &[String]) -> Result<()> {
+    let changed = Session::update_client_env(session_id, &parse_setenv(args))?;
+    let update = Config::load().unwrap_or_default().update_environment();
+    let live: BTreeMap<String, String> = changed
+        .into_iter()
+        .filter(|(key, _)| update.contains(key))
+        .collect();
+    if !live.is_empty() {
+        SessionEnv::queue_updates(session_id, &live)?;
+    }
+    Ok(())
+}
 
-            if trace_enabled() {
-                trace(|| {
-                    format!(
-                        "replayed {} bytes of scrollback to client {}",
-                        buffered_data.len(),
-                        client.id
-                    )
-                });
+/// Apply the clipboard policy to output from the PTY. Writes it lets through
+/// go to clients drawn from the screen, who don't get the raw output they
+/// came in, or with nobody attached may become the paste buffer.
+fn filter_clipboard(
+    clipboard: &mut ClipboardFilter,
+    data: &[u8],
+    active_clients: &mut [ClientInfo],
+    paste_buffer: &mut Option<String>,
+) -> Vec<u8> {
+    let (output, writes) = clipboard.filter(data);
+    for write in writes {
+        for client in active_clients.iter_mut().filter(|c| c.view.is_some()) {
+            let _ = client.send_data(&write.sequence);
+        }
+        if active_clients.is_empty() && clipboard.stores_detached() {
+            if let Some(text) = write.text {
+                trace(|| format!("kept {} bytes copied while detached", text.len()));
+                *paste_buffer = Some(text);
             }
         }
+    }
+    output
+}
 
-        // Nudges the PTY to ensure the client sees the latest frame.
-        io_handler.send_refresh()?;
+/// Answer the scrollback viewer. A page can be more than the socket takes at
+/// once, so wait a little for the viewer to read it rather than cut it short.
+fn answer_scrollback(
+    mut conn: ControlConn,
+    scrollback: &ScrollbackBuffer,
+    request: ScrollbackRequest,
+) -> Result<()> {
+    conn.stream.set_nonblocking(false)?;
+    conn.stream.set_write_timeout(Some(CONTROL_REPLY_TIMEOUT))?;
+    match request {
+        ScrollbackRequest::Page { from, count } => {
+            let page = scrollback.page(from, count);
+            conn.reply("scrollback", &serde_json::to_string(&page)?)?;
+        }
+        ScrollbackRequest::Search {
+            pattern,
+            from,
+            forward,
+        } => {
+            // The viewer checked the pattern; a bad one matches nothing
+            let found = Regex::new(&pattern)
+                .ok()
+                .and_then(|pattern| scrollback.search(&pattern, from, forward));
+            conn.reply("scrollback_search", &serde_json::to_string(&found)?)?;
+        }
+        ScrollbackRequest::Prompt { from, forward } => {
+            let found = scrollback.prompt(from, forward);
+            conn.reply("scrollback_prompt", &serde_json::to_string(&found)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// Scrollback paged in from the session's daemon for `~h`
+struct DaemonScrollback<'a> {
+    session: &'a Session,
+}
+
+impl ScrollbackSource for DaemonScrollback<'_> {
+    fn page(&mut self, from: u64, count: usize) -> Result<ScrollbackPage> {
+        PtyProcess::scrollback_page(self.session, from, count)
+    }
+
+    fn search(&mut self, pattern: &Regex, from: u64, forward: bool) -> Result<Option<u64>> {
+        PtyProcess::scrollback_search(self.session, pattern.as_str(), from, forward)
+    }
+
+    fn prompt(&mut self, from: u64, forward: bool) -> Result<Option<u64>> {
+        PtyProcess::scrollback_prompt(self.session, from, forward)
+    }
+
+    fn set_paste_buffer(&mut self, text: &str) -> Result<()> {
+        PtyProcess::set_paste_buffer(self.session, text)
+    }
+}
+
+/// Write output to the recording, if there is one. A recording that can't
+/// be written to, e.g. on a full disk, is stopped rather than retried.
+fn record_output(recording: &mut Option<Recording>, data: &[u8], screen: &Screen) {
+    if let Some(current) = recording.as_mut() {
+        if let Err(e) = current.output(data, screen) {
+            eprintln!(
+                "Stopped recording to {}: {}",
+                current.current_path().display(),
+                e
+            );
+            *recording = None;
+        }
+    }
+}
+
+/// Append output to the session's log, if it has one. A log that can't be
+/// written to is given up on, like a recording.
+fn log_output(log: &mut Option<SessionLog>, data: &[u8]) {
+    if let Some(current) = log.as_mut() {
+        if let Err(e) = current.write(data) {
+            eprintln!("Stopped logging to {}: {}", current.path().display(), e);
+            *log = None;
+        }
+    }
+}
+
+/// Draw `message` over the bottom row of every client showing window 0. Clients
+/// that get raw output have the cursor put back from the screen; the others
+/// never see the application's own cursor saves, so theirs can be used.
+fn show_message(message: &StatusMessage, screen: &Screen, active_clients: &mut [ClientInfo]) {
+    for client in active_clients
+        .iter_mut()
+        .filter(|c| !c.resync && c.shows(0))
+    {
+        let data = if client.view.is_some() {
+            let mut data = b"\x1b7".to_vec();
+            data.extend(message.render(client.cols, client.rows));
+            data.extend(b"\x1b8");
+            data
+        } else {
+            let (cols, rows) = screen.size();
+            let mut data = message.render(cols, rows);
+            data.extend(screen.cursor_sequence());
+            data
+        };
+        let _ = client.send_data(&data);
+    }
+}
+
+/// With the client attached through `group`, the session `~n` or `~p` moves
+/// on to from `window`: the group's next or previous one once the session
+/// has no more windows that way
+fn step_out_of_session(
+    session: &Session,
+    group: &str,
+    window: u32,
+    forward: bool,
+) -> Option<String> {
+    // Daemons without windows only have window 0
+    let windows: Vec<u32> = PtyProcess::list_windows(session)
+        .map(|windows| windows.iter().map(|window| window.id).collect())
+        .unwrap_or_else(|_| vec![0]);
+    let last = if forward {
+        windows.last()
     } else {
-        io_handler.send_refresh()?;
+        windows.first()
+    };
+    if last != Some(&window) {
+        return None;
     }
+    SessionManager::step_in_group(group, &session.id, forward)
+        .ok()
+        .flatten()
+        .map(|next| next.id)
+        .filter(|id| *id != session.id)
+}
 
-    Ok(())
+/// Take a status message down by drawing the clients from the screen again
+fn clear_message(screen: &Screen, active_clients: &mut [ClientInfo]) {
+    for client in active_clients
+        .iter_mut()
+        .filter(|c| !c.resync && c.shows(0))
+    {
+        if client.view.is_some() {
+            // Redrawn in full with the other views
+            client.invalidate_view();
+        } else {
+            let _ = client.send_data(&screen.repaint());
+        }
+    }
 }
 
-fn push_sequence(
-    seq: &mut Vec<u8>,
-    applied: &mut Vec<&'static str>,
-    bytes: &[u8],
-    label: &'static str,
-) {
-    seq.extend_from_slice(bytes);
-    applied.push(label);
+/// Terminal sizes of the attached clients, in the order they attached
+fn client_sizes(active_clients: &[ClientInfo]) -> Vec<(u16, u16)> {
+    active_clients.iter().map(|c| (c.cols, c.rows)).collect()
+}
+
+/// Have Ctrl+C stop the attach loop that `running` belongs to. The handler
+/// can only be set once per process, and switching sessions attaches again.
+fn stop_on_ctrl_c(running: Arc<AtomicBool>) -> Result<()> {
+    static CURRENT: Mutex<Option<Arc<AtomicBool>>> = Mutex::new(None);
+    static INSTALLED: OnceLock<std::result::Result<(), String>> = OnceLock::new();
+
+    *CURRENT.lock().unwrap() = Some(running);
+    INSTALLED
+        .get_or_init(|| {
+            ctrlc::set_handler(|| {
+                if let Some(running) = CURRENT.lock().unwrap().as_ref() {
+                    running.store(false, Ordering::SeqCst);
+                }
+            })
+            .map_err(|e| e.to_string())
+        })
+        .clone()
+        .map_err(|e| NdsError::SignalError(format!("Failed to set signal handler: {}", e)))
+}
+
+/// Read whatever else is already waiting on `fd` into `buf`, without
+/// blocking. Returns the number of bytes read.
+fn read_waiting_input(fd: RawFd, buf: &mut [u8]) -> usize {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let mut fds = [PollFd::new(
+            unsafe { BorrowedFd::borrow_raw(fd) },
+            PollFlags::POLLIN,
+        )];
+        if !poll(&mut fds, PollTimeout::ZERO).is_ok_and(|n| n > 0) {
+            break;
+        }
+        let n = unsafe {
+            libc::read(
+                fd,
+                buf[filled..].as_mut_ptr() as *mut libc::c_void,
+                buf.len() - filled,
+            )
+        };
+        // EOF and errors are seen by the next blocking read
+        if n <= 0 {
+            break;
+        }
+        filled += n as usize;
+    }
+    filled
 }
 
 fn trace_enabled() -> bool {
@@ -239,16 +1039,139 @@ where
     }
 }
 
+/// Actions triggered by the SSH-style `~` escapes (or Ctrl+D) while
+/// attached, under their default keys (see `KeyMap`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapeAction {
+    /// `~d` or Ctrl+D: detach this client
+    Detach,
+    /// `~s`: open the session switcher
+    Switch,
+    /// `~h`: open the scrollback viewer
+    Scrollback,
+    /// `~Tab`: cycle through recent sessions in an inline overlay
+    QuickSwitch,
+    /// `~r`: rename the session
+    Rename,
+    /// `~l`: switch to the previously attached session
+    LastSession,
+    /// `~?`: show the escapes in an overlay
+    Help,
+    /// `~z`: suspend this client back to the shell it was started from
+    Suspend,
+    /// `~c`: open a new window in the session and show it
+    NewWindow,
+    /// `~n`: show the session's next window
+    NextWindow,
+    /// `~p`: show the session's previous window
+    PreviousWindow,
+    /// `~|` or `~-`: split the pane side by side or one above the other,
+    /// with a new window in the new half
+    SplitPane(Split),
+    /// `~o`: move to the next pane
+    NextPane,
+    /// `~b`: start or stop typing into the linked sessions too
+    Broadcast,
+}
+
+impl From<Escape> for EscapeAction {
+    fn from(escape: Escape) -> Self {
+        match escape {
+            Escape::Detach => EscapeAction::Detach,
+            Escape::Switch => EscapeAction::Switch,
+            Escape::LastSession => EscapeAction::LastSession,
+            Escape::QuickSwitch => EscapeAction::QuickSwitch,
+            Escape::Scrollback => EscapeAction::Scrollback,
+            Escape::Rename => EscapeAction::Rename,
+            Escape::NewWindow => EscapeAction::NewWindow,
+            Escape::NextWindow => EscapeAction::NextWindow,
+            Escape::PreviousWindow => EscapeAction::PreviousWindow,
+            Escape::SplitBeside => EscapeAction::SplitPane(Split::Beside),
+            Escape::SplitBelow => EscapeAction::SplitPane(Split::Below),
+            Escape::NextPane => EscapeAction::NextPane,
+            Escape::Broadcast => EscapeAction::Broadcast,
+            Escape::Suspend => EscapeAction::Suspend,
+            Escape::Help => EscapeAction::Help,
+        }
+    }
+}
+
+/// Options applied when spawning a new session
+#[derive(Debug, Clone, Default)]
+pub struct SpawnOptions {
+    /// Redraw strategy instead of the default repaint from the screen
+    pub redraw: Option<RedrawStrategy>,
+    /// Initial terminal size (cols, rows) instead of the creating terminal's
+    pub size: Option<(u16, u16)>,
+    /// Extra variables exported to the shell, on top of the inherited environment
+    pub env: BTreeMap<String, String>,
+    /// Terminal variables from the client that last attached, set before
+    /// `env`; an empty value unsets one
+    pub client_env: BTreeMap<String, String>,
+    /// Shell to exec instead of the configured default
+    pub shell: Option<String>,
+    /// Program and arguments to run instead of an interactive shell
+    pub command: Option<Vec<String>>,
+    /// Whether to start the shell or command again when it exits
+    pub respawn: RespawnPolicy,
+    /// How to size the PTY for several clients instead of the configured default
+    pub resize: Option<ResizePolicy>,
+    /// Ask an attached client before letting another one attach
+    pub approve_attach: bool,
+    /// Also listen for remote clients here (`--listen`)
+    pub remote: Option<RemoteEndpoint>,
+}
+
+impl SpawnOptions {
+    /// Options that re-create the shell of an existing session
+    pub fn from_session(session: &Session) -> Self {
+        Self {
+            redraw: session.redraw,
+            size: session.size,
+            env: session.extra_env.clone(),
+            client_env: session.client_env.clone(),
+            shell: Some(session.shell.clone()),
+            command: session.command.clone(),
+            respawn: session.respawn,
+            resize: Some(session.resize),
+            approve_attach: session.approve_attach,
+            // The daemon listens already; a new shell doesn't change that
+            remote: None,
+        }
+    }
+
+    /// The shell that will be exec'd for these options
+    pub fn resolved_shell(&self) -> String {
+        Config::load()
+            .unwrap_or_default()
+            .resolve_shell(self.shell.as_deref())
+    }
+}
+
+/// Options controlling how a client attaches to a session
+#[derive(Debug, Clone, Default)]
+pub struct AttachOptions {
+    /// Disconnect all other clients before streaming to this one (like `tmux attach -d`)
+    pub detach_others: bool,
+    /// Plain byte stream for scripts: no escapes, resize monitor or messages
+    pub raw: bool,
+    /// With `raw`, pass the session's framed protocol through as it is
+    /// rather than just its output; what `nds remote` runs on the far end
+    pub framed: bool,
+    /// Attached through a group (`nds attach @group`): `~n` and `~p` go on
+    /// to its next or previous session from the last or first window
+    pub group: Option<String>,
+}
+
 pub struct PtyProcess {
     pub master_fd: RawFd,
     pub pid: Pid,
     pub socket_path: PathBuf,
     listener: Option<UnixListener>,
     output_buffer: Option<PtyBuffer>,
-    #[allow(dead_code)]
     shell_pid: Option<Pid>, // Track the actual shell process
-    #[allow(dead_code)]
-    session_id: String, // Store session ID for restart
+    session_id: String,     // Store session ID for restart
+    exit_status: Option<ExitStatus>,
 }
 
 impl PtyProcess {
@@ -301,8 +1224,23 @@ impl PtyProcess {
 
     /// Spawn a new detached session with a custom name
     pub fn spawn_new_detached_with_name(session_id: &str, name: Option<String>) -> Result<Session> {
-        // Capture terminal size BEFORE detaching using proper ioctl
-        let (cols, rows) = get_terminal_size().unwrap_or((80, 24));
+        Self::spawn_new_detached_with_options(session_id, name, &SpawnOptions::default())
+    }
+
+    /// Spawn a new detached session with a custom name and spawn options
+    pub fn spawn_new_detached_with_options(
+        session_id: &str,
+        name: Option<String>,
+        options: &SpawnOptions,
+    ) -> Result<Session> {
+        // Capture terminal size BEFORE detaching using proper ioctl, unless
+        // one was requested (headless starts have no terminal to measure)
+        let (cols, rows) = options
+            .resize
+            .and_then(|policy| policy.fixed_size())
+            .or(options.size)
+            .or_else(|| get_terminal_size().ok())
+            .unwrap_or((80, 24));
 
         // First fork to create intermediate process
         match unsafe { fork() }
@@ -356,11 +1294,11 @@ impl PtyProcess {
 
                         // Continue with PTY setup, passing the captured terminal size and daemon PID
                         let (pty_process, _session) = Self::spawn_new_internal_with_size(
-                            session_id, name, cols, rows, daemon_pid,
+                            session_id, name, options, cols, rows, daemon_pid,
                         )?;
 
                         // Run the PTY handler
-                        if let Err(_e) = pty_process.run_detached() {
+                        if let Err(_e) = run_daemon(pty_process) {
                             // Can't print errors anymore since stdout is closed
                         }
 
@@ -376,10 +1314,98 @@ impl PtyProcess {
     fn spawn_new_internal_with_size(
         session_id: &str,
         name: Option<String>,
+        options: &SpawnOptions,
         cols: u16,
         rows: u16,
         daemon_pid: i32,
     ) -> Result<(Self, Session)> {
+        // Create socket for IPC; an abstract one leaves no file behind
+        let (listener, socket_path, abstract_socket) =
+            if Config::load().unwrap_or_default().abstract_sockets {
+                let (listener, name) = create_abstract_listener(session_id)?;
+                // Not created, but still how the daemon knows its session
+                let socket_path = Session::socket_dir()?.join(format!("{}.sock", session_id));
+                (listener, socket_path, Some(name))
+            } else {
+                let (listener, socket_path) = create_listener(session_id)?;
+                (listener, socket_path, None)
+            };
+
+        // Open a PTY and start the shell on it
+        let (master_fd, child) =
+            Self::spawn_shell(session_id, name.as_deref(), options, cols, rows)?;
+
+        // Create session metadata with daemon PID (not child shell PID)
+        // This ensures we track the PTY manager process, not the shell
+        let mut session = Session::with_name(
+            session_id.to_string(),
+            name,
+            daemon_pid, // Use daemon PID instead of child PID
+            socket_path.clone(),
+        );
+        session.abstract_socket = abstract_socket;
+        session.redraw = options.redraw;
+        session.size = options.size;
+        session.extra_env = options.env.clone();
+        session.client_env = client_env(&Config::load().unwrap_or_default().update_environment());
+        session.shell = options.resolved_shell();
+        session.command = options.command.clone();
+        session.respawn = options.respawn;
+        session.resize = options.resize.unwrap_or_default();
+        session.approve_attach = options.approve_attach;
+        // Remote clients are optional; if the address can't be had, the
+        // session is only reachable locally, which `nds new` points out
+        let remote = options
+            .remote
+            .as_ref()
+            .and_then(|requested| RemoteListener::bind(requested).ok());
+        session.remote = remote.as_ref().map(|remote| remote.endpoint().clone());
+        session.index = Session::allocate_index(session_id, daemon_pid).ok();
+        session.env = std::env::vars().collect();
+        session
+            .env
+            .insert("NDS_SESSION_ID".to_string(), session_id.to_string());
+        let session_name = session
+            .name
+            .clone()
+            .unwrap_or_else(|| session_id.to_string());
+        session
+            .env
+            .insert("NDS_SESSION_NAME".to_string(), session_name);
+        session.env.extend(options.env.clone());
+        session.save().map_err(|e| {
+            eprintln!("Failed to save session: {}", e);
+            e
+        })?;
+        if let Some(remote) = remote {
+            remote::serve(remote, session.clone());
+        }
+
+        let pty_process = PtyProcess {
+            master_fd,
+            pid: child,
+            socket_path,
+            listener: Some(listener),
+            output_buffer: Some(PtyBuffer::new(DEFAULT_BUFFER_MEMORY)),
+            shell_pid: Some(child), // Initially the shell PID
+            session_id: session_id.to_string(),
+            exit_status: None,
+        };
+
+        Ok((pty_process, session))
+    }
+
+    /// Open a fresh PTY pair and exec the session shell on its slave side.
+    /// Returns the non-blocking master fd and the shell PID.
+    pub(super) fn spawn_shell(
+        session_id: &str,
+        name: Option<&str>,
+        options: &SpawnOptions,
+        cols: u16,
+        rows: u16,
+    ) -> Result<(RawFd, Pid)> {
+        let shell = options.resolved_shell();
+
         // Open PTY
         let (master_fd, slave_fd) = Self::open_pty()?;
 
@@ -395,39 +1421,17 @@ impl PtyProcess {
         )
         .map_err(|e| NdsError::PtyError(format!("Failed to set non-blocking: {}", e)))?;
 
-        // Create socket for IPC
-        let (listener, socket_path) = create_listener(session_id)?;
+        // Shells started later, in other windows or on respawn, mustn't
+        // hold this PTY open
+        fcntl(master_fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+            .map_err(|e| NdsError::PtyError(format!("Failed to set close-on-exec: {}", e)))?;
 
         // Fork process
         match unsafe { fork() }.map_err(|e| NdsError::ForkError(e.to_string()))? {
             ForkResult::Parent { child } => {
                 // Close slave in parent
                 let _ = close(slave_fd);
-
-                // Create session metadata with daemon PID (not child shell PID)
-                // This ensures we track the PTY manager process, not the shell
-                let session = Session::with_name(
-                    session_id.to_string(),
-                    name,
-                    daemon_pid, // Use daemon PID instead of child PID
-                    socket_path.clone(),
-                );
-                session.save().map_err(|e| {
-                    eprintln!("Failed to save session: {}", e);
-                    e
-                })?;
-
-                let pty_process = PtyProcess {
-                    master_fd,
-                    pid: child,
-                    socket_path,
-                    listener: Some(listener),
-                    output_buffer: Some(PtyBuffer::new(2 * 1024 * 1024)), // 2MB buffer for better performance
-                    shell_pid: Some(child),                               // Initially the shell PID
-                    session_id: session_id.to_string(),
-                };
-
-                Ok((pty_process, session))
+                Ok((master_fd, child))
             }
             ForkResult::Child => {
                 // Close master in child
@@ -459,10 +1463,16 @@ impl PtyProcess {
 
                 // Set environment variables for session tracking and isolation
                 std::env::set_var("NDS_SESSION_ID", session_id);
-                if let Some(ref session_name) = name {
-                    std::env::set_var("NDS_SESSION_NAME", session_name);
-                } else {
-                    std::env::set_var("NDS_SESSION_NAME", session_id);
+                std::env::set_var("NDS_SESSION_NAME", name.unwrap_or(session_id));
+                for (key, value) in &options.client_env {
+                    if value.is_empty() {
+                        std::env::remove_var(key);
+                    } else {
+                        std::env::set_var(key, value);
+                    }
+                }
+                for (key, value) in &options.env {
+                    std::env::set_var(key, value);
                 }
 
                 // Set restrictive umask for session isolation
@@ -470,24 +1480,91 @@ impl PtyProcess {
                     libc::umask(0o077); // Only owner can read/write/execute new files
                 }
 
-                // Get shell
-                let shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
+                // Programs started from the session should see the shell it runs
+                std::env::set_var("SHELL", &shell);
+
+                // Execute the command, or the shell when there is none
+                let argv = options
+                    .command
+                    .clone()
+                    .unwrap_or_else(|| vec![shell.clone()]);
+                let args: Vec<std::ffi::CString> = argv
+                    .iter()
+                    .map(|arg| std::ffi::CString::new(arg.as_str()).unwrap_or_default())
+                    .collect();
+
+                // execvp only returns on failure; stderr is the PTY, so
+                // whoever attaches sees why
+                let e = execvp(&args[0], &args).unwrap_err();
+                eprintln!("nds: failed to execute {}: {}", argv[0], e);
+                std::process::exit(127);
+            }
+        }
+    }
 
-                // Execute shell
-                let shell_cstr = std::ffi::CString::new(shell.as_str()).unwrap();
-                let args = vec![shell_cstr.clone()];
+    /// Replace a dead PTY master with a fresh PTY pair.
+    ///
+    /// A process can only acquire a controlling terminal itself, so the old
+    /// shell cannot be migrated onto the new PTY; it is hung up and a new shell
+    /// is started in its place. The listener, clients, and session metadata
+    /// are untouched.
+    fn reopen_pty(&mut self, cols: u16, rows: u16) -> Result<()> {
+        let session = Session::load(&self.session_id).ok();
+        let name = session.as_ref().and_then(|s| s.name.clone());
+        let options = session
+            .as_ref()
+            .map(SpawnOptions::from_session)
+            .unwrap_or_default();
+        let (master_fd, child) =
+            Self::spawn_shell(&self.session_id, name.as_deref(), &options, cols, rows)?;
+
+        // Hang up the orphaned shell and reap it so it doesn't linger as a
+        // zombie. A shell that exited was reaped already and its PID may
+        // belong to another process by now.
+        if let Some(old_shell) = self.shell_pid.filter(|_| self.exit_status.is_none()) {
+            let _ = kill(old_shell, Signal::SIGHUP);
+            thread::sleep(Duration::from_millis(100));
+            if Session::is_process_alive(old_shell.as_raw()) {
+                let _ = kill(old_shell, Signal::SIGKILL);
+            }
+            // Nothing survives SIGKILL, so this doesn't block for long
+            let _ = waitpid(old_shell, None);
+        }
 
-                execvp(&shell_cstr, &args)
-                    .map_err(|e| NdsError::ProcessError(format!("execvp failed: {}", e)))?;
+        let _ = close(self.master_fd);
+        self.master_fd = master_fd;
+        self.pid = child;
+        self.shell_pid = Some(child);
+        self.exit_status = None;
 
-                // Should never reach here
-                unreachable!()
-            }
+        Ok(())
+    }
+
+    /// Run a recovery strategy against this PTY
+    fn recover(&mut self, strategy: RecoveryStrategy, cols: u16, rows: u16) -> Result<()> {
+        match strategy {
+            RecoveryStrategy::ReopenPty => self.reopen_pty(cols, rows),
+            other => attempt_recovery(other, self.master_fd).map_err(NdsError::PtyError),
         }
     }
 
     /// Attach to an existing session
     pub fn attach_to_session(session: &Session) -> Result<Option<String>> {
+        Self::attach_to_session_with_options(session, &AttachOptions::default())
+    }
+
+    /// Attach to an existing session with custom attach options
+    pub fn attach_to_session_with_options(
+        session: &Session,
+        options: &AttachOptions,
+    ) -> Result<Option<String>> {
+        if options.raw && options.framed {
+            return crate::remote::ssh::relay(session).map(|()| None);
+        }
+        if options.raw {
+            return Self::attach_raw(session, options).map(|()| None);
+        }
+
         // Set environment variables
         std::env::set_var("NDS_SESSION_ID", &session.id);
         std::env::set_var(
@@ -502,12 +1579,34 @@ impl PtyProcess {
         // Capture current terminal state for restoration
         let _terminal_state = capture_terminal_state(stdin_fd)?;
 
-        // Connect to session socket
-        let mut socket = session.connect_socket()?;
+        // Connect to session socket, offering to answer attach requests and
+        // show the session's other windows
+        let socket = Self::connect_offering(session, Role::Attach, &[APPROVE_ATTACH, WINDOWS])?;
+
+        // Kick other clients before anything else so they stop resizing the PTY
+        if options.detach_others {
+            require_capability(&socket, session, "detach_others")?;
+            send_detach_others_command(&socket)?;
+        }
+
+        // Let `nds clients` show which terminal this client is on
+        if let Ok(tty) = nix::unistd::ttyname(io::stdin()) {
+            if socket.supports("tty") {
+                send_client_tty_command(&socket, &tty.to_string_lossy())?;
+            }
+        }
+
+        // A shell started later should suit this terminal, not the one the
+        // session was created from, and the running one should find this
+        // login's ssh agent and display
+        if socket.supports("setenv") {
+            let update = Config::load().unwrap_or_default().update_environment();
+            send_setenv_command(&socket, &client_env(&update))?;
+        }
 
         // Get current terminal size and send resize command
         let (cols, rows) = get_terminal_size()?;
-        send_resize_command(&mut socket, cols, rows)?;
+        send_resize_command(&socket, cols, rows)?;
         thread::sleep(Duration::from_millis(50));
 
         // Don't send refresh - it disrupts running applications like htop
@@ -524,10 +1623,7 @@ impl PtyProcess {
         let paused_clone = paused.clone();
 
         // Handle Ctrl+C
-        ctrlc::set_handler(move || {
-            r1.store(false, Ordering::SeqCst);
-        })
-        .map_err(|e| NdsError::SignalError(format!("Failed to set signal handler: {}", e)))?;
+        stop_on_ctrl_c(r1)?;
 
         // Set terminal to raw mode AFTER setting up signal handler
         set_raw_mode(stdin_fd, &original_termios)?;
@@ -537,44 +1633,53 @@ impl PtyProcess {
         // println!("\r\n[Attached to session {}]\r", session.id);
         // println!("[Press Enter then ~d to detach, ~s to switch, ~h for history]\r");
 
-        // Create scrollback handler
-        let scrollback = ScrollbackHandler::new(10 * 1024 * 1024); // 10MB
-
         // Spawn resize monitor thread
         let socket_for_resize = socket
             .try_clone()
             .map_err(|e| NdsError::SocketError(format!("Failed to clone socket: {}", e)))?;
         let resize_running = running.clone();
-        let _resize_monitor =
-            spawn_resize_monitor_thread(socket_for_resize, resize_running, (cols, rows));
+        // What is drawn, one window or several in split panes
+        let panes = Arc::new(Mutex::new(Panes::new((cols, rows))));
+        let _resize_monitor = spawn_resize_monitor_thread(
+            socket_for_resize,
+            resize_running,
+            (cols, rows),
+            panes.clone(),
+        );
 
         // Spawn socket to stdout thread
         let socket_clone = socket
             .try_clone()
             .map_err(|e| NdsError::SocketError(format!("Failed to clone socket: {}", e)))?;
+        let (approvals_tx, approvals) = mpsc::channel();
         let socket_to_stdout = spawn_socket_to_stdout_thread(
             socket_clone,
             r2,
-            scrollback.get_shared_buffer(),
             paused_clone,
+            approvals_tx,
+            panes.clone(),
         );
 
         // Don't set stdin to non-blocking - keep it blocking
         // We'll handle the non-blocking behavior in the read loop
 
-        // Main input loop
+        // Main input loop; a SIGTSTP from outside suspends like `~z`
+        let suspend_guard = SuspendGuard::new()?;
         let result = Self::handle_input_loop(
-            &mut socket,
+            &socket,
             session,
+            options.group.as_deref(),
             &original_termios,
             &running,
-            &scrollback,
             &paused,
+            &approvals,
+            &panes,
         );
 
         // Clean up
+        drop(suspend_guard);
         running.store(false, Ordering::SeqCst);
-        let _ = socket.shutdown(std::net::Shutdown::Both);
+        let _ = socket.stream().shutdown(std::net::Shutdown::Both);
         drop(socket);
         thread::sleep(Duration::from_millis(50));
         let _ = socket_to_stdout.join();
@@ -595,21 +1700,161 @@ impl PtyProcess {
         result
     }
 
+    /// Attach as a plain byte stream: stdin goes to the session as it is
+    /// and the session's output to stdout, for `expect`, CI drivers and
+    /// pipes. The terminal's modes are only touched when stdin is one. Runs
+    /// until the daemon hangs up; the end of stdin just stops the input.
+    fn attach_raw(session: &Session, options: &AttachOptions) -> Result<()> {
+        let stdin_fd = 0;
+        let socket = Self::connect(session, Role::Attach)?;
+        // Input may wait on a program that reads slowly, and output on one
+        // that writes rarely
+        let _ = socket.stream().set_read_timeout(None);
+        let _ = socket.stream().set_write_timeout(None);
+
+        if options.detach_others {
+            require_capability(&socket, session, "detach_others")?;
+            send_detach_others_command(&socket)?;
+        }
+
+        // Size the PTY from a terminal if there is one; otherwise leave it
+        // to the other clients
+        let size = get_terminal_size_of(1).or_else(|_| get_terminal_size_of(stdin_fd));
+        if let Ok((cols, rows)) = size {
+            if cols > 0 && rows > 0 {
+                send_resize_command(&socket, cols, rows)?;
+            }
+        }
+
+        let original_termios = if nix::unistd::isatty(stdin_fd).unwrap_or(false) {
+            let termios = save_terminal_state(stdin_fd)?;
+            set_raw_mode(stdin_fd, &termios)?;
+            Some(termios)
+        } else {
+            None
+        };
+
+        // Left blocked in read when the daemon hangs up; exiting ends it
+        let input = socket
+            .try_clone()
+            .map_err(|e| NdsError::SocketError(format!("Failed to clone socket: {}", e)))?;
+        thread::spawn(move || {
+            let mut stdin = io::stdin().lock();
+            let mut buffer = [0u8; DEFAULT_BUFFER_SIZE];
+            loop {
+                match stdin.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if send_input(&input, &buffer[..n]).is_err() {
+                            break;
+                        }
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => break,
+                }
+            }
+        });
+
+        let result = Self::copy_output(&socket);
+
+        if let Some(termios) = original_termios {
+            let stdin = unsafe { BorrowedFd::borrow_raw(stdin_fd) };
+            let _ =
+                nix::sys::termios::tcsetattr(stdin, nix::sys::termios::SetArg::TCSANOW, &termios);
+        }
+        result
+    }
+
+    /// Write the session's output to stdout until the daemon hangs up
+    fn copy_output(socket: &Connection) -> Result<()> {
+        let mut reader = socket
+            .try_clone()
+            .map_err(|e| NdsError::SocketError(format!("Failed to clone socket: {}", e)))?;
+        let mut decoder = socket.decoder();
+        let mut stdout = io::stdout().lock();
+        let mut buffer = [0u8; DEFAULT_BUFFER_SIZE];
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(0) => return Ok(()),
+                Ok(n) => {
+                    decoder.push(&buffer[..n]);
+                    stdout.write_all(&decoder.take_data()?)?;
+                    stdout.flush()?;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Put clients waiting to attach to the user one at a time, over the
+    /// bottom row, and send the daemon each answer
+    fn answer_attach_requests(
+        socket: &Connection,
+        stdin_fd: RawFd,
+        paused: &Arc<AtomicBool>,
+        approvals: &Receiver<ApprovalEvent>,
+    ) -> Result<()> {
+        let mut queued = VecDeque::new();
+        for event in approvals.try_iter() {
+            match event {
+                ApprovalEvent::Asked(request) => queued.push_back(request),
+                ApprovalEvent::Settled(id) => {
+                    queued.retain(|request: &AttachRequest| request.client_id != id)
+                }
+            }
+        }
+        if queued.is_empty() {
+            return Ok(());
+        }
+
+        // Hold output so it doesn't draw over the prompt
+        paused.store(true, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        let mut result = Ok(());
+        while let Some(request) = queued.pop_front() {
+            match approval::ask(stdin_fd, &request, approvals, &mut queued) {
+                Ok(Some(allow)) => {
+                    if let Err(e) = send_approve_attach_command(socket, &request.client_id, allow) {
+                        result = Err(e);
+                        break;
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        paused.store(false, Ordering::SeqCst);
+        result?;
+
+        // Have the row the prompt covered redrawn
+        send_refresh_command(socket)?;
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn handle_input_loop(
-        socket: &mut UnixStream,
+        socket: &Connection,
         session: &Session,
+        group: Option<&str>,
         original_termios: &Termios,
         running: &Arc<AtomicBool>,
-        scrollback: &ScrollbackHandler,
         paused: &Arc<AtomicBool>,
+        approvals: &Receiver<ApprovalEvent>,
+        panes: &Mutex<Panes>,
     ) -> Result<Option<String>> {
         let stdin_fd = 0i32;
-        let mut buffer = [0u8; 1024]; // Use smaller buffer for more responsive input
+        let mut buffer = [0u8; DEFAULT_BUFFER_SIZE];
 
         // SSH-style escape sequence tracking
         let mut at_line_start = true;
-        let mut escape_state = 0; // 0=normal, 1=saw tilde at line start
+        let mut escape_state = 0; // 0=normal, 1=saw the prefix at line start
         let mut escape_time = Instant::now();
+        let keymap = KeyMap::current();
 
         // Use poll to check for input availability
         use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
@@ -619,6 +1864,14 @@ impl PtyProcess {
                 break;
             }
 
+            if take_suspend_request() {
+                Self::suspend_client(socket, original_termios, paused, panes)?;
+                escape_state = 0;
+                at_line_start = true;
+            }
+
+            Self::answer_attach_requests(socket, stdin_fd, paused, approvals)?;
+
             // Poll stdin with a short timeout
             use std::os::unix::io::BorrowedFd;
             let stdin_borrowed = unsafe { BorrowedFd::borrow_raw(stdin_fd) };
@@ -648,65 +1901,217 @@ impl PtyProcess {
                             break;
                         }
                         n if n > 0 => {
+                            // A paste arrives in many small reads; take what
+                            // else is already waiting so it goes to the
+                            // daemon in fewer, larger frames
                             let n = n as usize;
-                            let (should_detach, should_switch, should_scroll, data_to_forward) =
-                                Self::process_input(
-                                    &buffer[..n],
-                                    &mut at_line_start,
-                                    &mut escape_state,
-                                    &mut escape_time,
-                                );
-
-                            if should_detach {
-                                // Don't print anything that could corrupt the display
-                                running.store(false, Ordering::SeqCst);
-                                break;
-                            }
-
-                            if should_switch {
-                                // Pause socket-to-stdout thread to prevent overwriting
-                                paused.store(true, Ordering::SeqCst);
-
-                                // Wait a bit for current output to finish
-                                thread::sleep(Duration::from_millis(50));
-
-                                // Stop forwarding PTY output to prevent display corruption
-                                let switcher =
-                                    SessionSwitcher::new(session, stdin_fd, original_termios);
-
-                                // Temporarily restore terminal for switcher UI
-                                restore_terminal(stdin_fd, original_termios)?;
-
-                                let switch_result = switcher.show_switcher()?;
-
-                                // Re-enter raw mode after switcher
-                                set_raw_mode(stdin_fd, original_termios)?;
-
-                                // Resume socket-to-stdout thread
-                                paused.store(false, Ordering::SeqCst);
+                            let n = n + read_waiting_input(stdin_fd, &mut buffer[n..]);
+                            let (action, data_to_forward) = Self::process_input(
+                                &buffer[..n],
+                                &mut at_line_start,
+                                &mut escape_state,
+                                &mut escape_time,
+                                keymap,
+                            );
 
-                                match switch_result {
-                                    SwitchResult::SwitchTo(target_id) => {
+                            match action {
+                                Some(EscapeAction::Detach) => {
+                                    // Don't print anything that could corrupt the display
+                                    running.store(false, Ordering::SeqCst);
+                                    break;
+                                }
+                                Some(EscapeAction::Switch) => {
+                                    // Pause socket-to-stdout thread to prevent overwriting
+                                    paused.store(true, Ordering::SeqCst);
+
+                                    // Wait a bit for current output to finish
+                                    thread::sleep(Duration::from_millis(50));
+
+                                    // Stop forwarding PTY output to prevent display corruption
+                                    let switcher =
+                                        SessionSwitcher::new(session, stdin_fd, original_termios);
+
+                                    // Temporarily restore terminal for switcher UI
+                                    restore_terminal(stdin_fd, original_termios)?;
+
+                                    let switch_result = switcher.show_switcher()?;
+
+                                    // Re-enter raw mode after switcher
+                                    set_raw_mode(stdin_fd, original_termios)?;
+
+                                    // Resume socket-to-stdout thread
+                                    paused.store(false, Ordering::SeqCst);
+
+                                    match switch_result {
+                                        SwitchResult::SwitchTo(target_id) => {
+                                            return Ok(Some(target_id));
+                                        }
+                                        SwitchResult::Continue => {
+                                            escape_state = 0;
+                                            at_line_start = true;
+                                            // Let the daemon redraw with the session's strategy
+                                            send_refresh_command(socket)?;
+                                        }
+                                    }
+                                }
+                                Some(EscapeAction::QuickSwitch) => {
+                                    // Overlay is drawn in place, so only pause output
+                                    paused.store(true, Ordering::SeqCst);
+                                    thread::sleep(Duration::from_millis(50));
+
+                                    let switch_result = QuickSwitcher::new(session, stdin_fd).run();
+
+                                    paused.store(false, Ordering::SeqCst);
+                                    escape_state = 0;
+                                    at_line_start = true;
+
+                                    match switch_result? {
+                                        SwitchResult::SwitchTo(target_id) => {
+                                            return Ok(Some(target_id));
+                                        }
+                                        SwitchResult::Continue => {
+                                            // Redraw whatever the overlay covered
+                                            send_refresh_command(socket)?;
+                                        }
+                                    }
+                                }
+                                Some(EscapeAction::LastSession) => {
+                                    // A missing session is reported in place,
+                                    // so only pause output
+                                    paused.store(true, Ordering::SeqCst);
+                                    thread::sleep(Duration::from_millis(50));
+
+                                    let switch_result =
+                                        QuickSwitcher::new(session, stdin_fd).last();
+
+                                    paused.store(false, Ordering::SeqCst);
+                                    escape_state = 0;
+                                    at_line_start = true;
+
+                                    match switch_result? {
+                                        SwitchResult::SwitchTo(target_id) => {
+                                            return Ok(Some(target_id));
+                                        }
+                                        SwitchResult::Continue => {
+                                            send_refresh_command(socket)?;
+                                        }
+                                    }
+                                }
+                                Some(EscapeAction::Help) => {
+                                    // Drawn over the application, so only pause output
+                                    paused.store(true, Ordering::SeqCst);
+                                    thread::sleep(Duration::from_millis(50));
+
+                                    let help_result = show_help(stdin_fd, keymap);
+
+                                    paused.store(false, Ordering::SeqCst);
+                                    escape_state = 0;
+                                    at_line_start = true;
+                                    help_result?;
+                                    // Redraw whatever the overlay covered
+                                    send_refresh_command(socket)?;
+                                }
+                                Some(EscapeAction::Suspend) => {
+                                    Self::suspend_client(socket, original_termios, paused, panes)?;
+                                    escape_state = 0;
+                                    at_line_start = true;
+                                }
+                                Some(EscapeAction::NewWindow) => {
+                                    // The daemon switches this client to it
+                                    if socket.supports("window_new") {
+                                        send_window_new_command(socket, None, &[])?;
+                                    }
+                                    escape_state = 0;
+                                    at_line_start = true;
+                                }
+                                Some(
+                                    action @ (EscapeAction::NextWindow
+                                    | EscapeAction::PreviousWindow),
+                                ) => {
+                                    let forward = action == EscapeAction::NextWindow;
+                                    let window = lock_panes(panes).window();
+                                    let next_session = group.and_then(|group| {
+                                        step_out_of_session(session, group, window, forward)
+                                    });
+                                    if let Some(target_id) = next_session {
                                         return Ok(Some(target_id));
                                     }
-                                    SwitchResult::Continue => {
-                                        escape_state = 0;
-                                        at_line_start = true;
-                                        // Send refresh to redraw the terminal
-                                        send_terminal_refresh_sequences(socket)?;
+                                    if socket.supports("window_select") {
+                                        let target = if action == EscapeAction::NextWindow {
+                                            "next"
+                                        } else {
+                                            "prev"
+                                        };
+                                        send_window_select_command(socket, target)?;
                                     }
+                                    escape_state = 0;
+                                    at_line_start = true;
                                 }
-                            }
-
-                            if should_scroll {
-                                Self::show_scrollback_viewer(original_termios, socket, scrollback)?;
-                                escape_state = 0;
-                                at_line_start = true;
+                                Some(EscapeAction::SplitPane(split)) => {
+                                    // The new half shows once the daemon has
+                                    // opened its window
+                                    if socket.supports("panes")
+                                        && lock_panes(panes).request_split(split)
+                                    {
+                                        send_window_new_command(socket, None, &[])?;
+                                    }
+                                    escape_state = 0;
+                                    at_line_start = true;
+                                }
+                                Some(EscapeAction::NextPane) => {
+                                    let next = lock_panes(panes).next_pane();
+                                    if let Some(window) = next {
+                                        send_window_select_command(socket, &window.to_string())?;
+                                    }
+                                    escape_state = 0;
+                                    at_line_start = true;
+                                }
+                                Some(EscapeAction::Broadcast) => {
+                                    if socket.supports("broadcast") {
+                                        send_broadcast_command(socket)?;
+                                    }
+                                    escape_state = 0;
+                                    at_line_start = true;
+                                }
+                                Some(EscapeAction::Rename) => {
+                                    paused.store(true, Ordering::SeqCst);
+                                    thread::sleep(Duration::from_millis(50));
+
+                                    // The prompt manages raw mode itself
+                                    restore_terminal(stdin_fd, original_termios)?;
+                                    let current = Session::load(&session.id)
+                                        .ok()
+                                        .and_then(|s| s.name)
+                                        .unwrap_or_default();
+                                    let rename_result = text_input::prompt(
+                                        "RENAME SESSION",
+                                        "New name (empty to clear)",
+                                        &current,
+                                        |name| SessionManager::rename_session(&session.id, name),
+                                    );
+                                    set_raw_mode(stdin_fd, original_termios)?;
+
+                                    paused.store(false, Ordering::SeqCst);
+                                    escape_state = 0;
+                                    at_line_start = true;
+                                    rename_result?;
+                                    send_refresh_command(socket)?;
+                                }
+                                Some(EscapeAction::Scrollback) => {
+                                    Self::show_scrollback_viewer(
+                                        original_termios,
+                                        socket,
+                                        session,
+                                    )?;
+                                    escape_state = 0;
+                                    at_line_start = true;
+                                }
+                                None => {}
                             }
 
                             // Forward the processed data
                             if !data_to_forward.is_empty() {
-                                if let Err(e) = socket.write_all(&data_to_forward) {
+                                if let Err(e) = send_input(socket, &data_to_forward) {
                                     if e.kind() == io::ErrorKind::BrokenPipe {
                                         break;
                                     } else {
@@ -725,6 +2130,10 @@ impl PtyProcess {
                         }
                     }
                 }
+                Err(nix::errno::Errno::EINTR) => {
+                    // A signal such as SIGTSTP; handled at the top of the loop
+                    continue;
+                }
                 Err(e) => {
                     // Poll error
                     eprintln!("Poll error: {:?}", e);
@@ -739,21 +2148,55 @@ impl PtyProcess {
         Ok(None)
     }
 
+    /// Give the terminal back to the shell `nds` was started from and stop
+    /// until `fg`, then take it over again and bring the session up to date
+    fn suspend_client(
+        socket: &Connection,
+        original_termios: &Termios,
+        paused: &Arc<AtomicBool>,
+        panes: &Mutex<Panes>,
+    ) -> Result<()> {
+        let stdin_fd = 0i32;
+
+        // Keep session output off the shell's screen while stopped
+        paused.store(true, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        restore_terminal(stdin_fd, original_termios)?;
+        println!();
+        let suspended = suspend_self();
+
+        set_raw_mode(stdin_fd, original_termios)?;
+        paused.store(false, Ordering::SeqCst);
+        suspended?;
+
+        // The terminal may have been resized while we were stopped; split
+        // panes are laid out again and drawn from their screens
+        if let Ok((cols, rows)) = get_terminal_size() {
+            let mut panes = lock_panes(panes);
+            panes.resize(cols, rows);
+            if !panes.is_split() {
+                send_resize_command(socket, cols, rows)?;
+            }
+        }
+        send_refresh_command(socket)?;
+        Ok(())
+    }
+
     fn process_input(
         buffer: &[u8],
         at_line_start: &mut bool,
         escape_state: &mut u8,
         escape_time: &mut Instant,
-    ) -> (bool, bool, bool, Vec<u8>) {
-        let mut should_detach = false;
-        let mut should_switch = false;
-        let mut should_scroll = false;
+        keymap: &KeyMap,
+    ) -> (Option<EscapeAction>, Vec<u8>) {
+        let mut action = None;
         let mut data_to_forward = Vec::new();
+        let prefix = keymap.prefix_byte();
 
         // Check for escape timeout (reset after 1 second)
         if *escape_state == 1 && escape_time.elapsed() > Duration::from_secs(1) {
-            // Timeout - forward the held tilde and reset
-            data_to_forward.push(b'~');
+            // Timeout - forward the held prefix and reset
+            data_to_forward.push(prefix);
             *escape_state = 0;
         }
 
@@ -761,18 +2204,18 @@ impl PtyProcess {
         for &byte in buffer {
             // Check for Ctrl+D (ASCII 4) - detach this client only
             if byte == 0x04 {
-                should_detach = true;
+                action = Some(EscapeAction::Detach);
                 break;
             }
 
             match *escape_state {
                 0 => {
                     // Normal state
-                    if *at_line_start && byte == b'~' {
+                    if *at_line_start && byte == prefix {
                         // Start of potential escape sequence
                         *escape_state = 1;
                         *escape_time = Instant::now();
-                        // Don't forward the tilde yet
+                        // Don't forward the prefix yet
                     } else {
                         // Regular character
                         data_to_forward.push(byte);
@@ -781,34 +2224,21 @@ impl PtyProcess {
                     }
                 }
                 1 => {
-                    // We saw ~ at the beginning of a line
-                    match byte {
-                        b'd' => {
-                            should_detach = true;
-                            break;
-                        }
-                        b's' => {
-                            should_switch = true;
-                            break;
-                        }
-                        b'h' => {
-                            should_scroll = true;
-                            break;
-                        }
-                        b'~' => {
-                            // ~~ means literal tilde
-                            data_to_forward.push(b'~');
-                            *escape_state = 0;
-                            *at_line_start = false;
-                        }
-                        _ => {
-                            // Not an escape sequence, forward tilde and this char
-                            data_to_forward.push(b'~');
-                            data_to_forward.push(byte);
-                            *escape_state = 0;
-                            *at_line_start =
-                                byte == b'\r' || byte == b'\n' || byte == 10 || byte == 13;
-                        }
+                    // We saw the prefix at the beginning of a line
+                    if byte == prefix {
+                        // Typed twice, it sends itself
+                        data_to_forward.push(prefix);
+                        *escape_state = 0;
+                        *at_line_start = false;
+                    } else if let Some(escape) = keymap.escape(byte) {
+                        action = Some(EscapeAction::from(escape));
+                        break;
+                    } else {
+                        // Not an escape sequence, forward the prefix and this char
+                        data_to_forward.push(prefix);
+                        data_to_forward.push(byte);
+                        *escape_state = 0;
+                        *at_line_start = byte == b'\r' || byte == b'\n';
                     }
                 }
                 _ => {
@@ -817,22 +2247,19 @@ impl PtyProcess {
             }
         }
 
-        (should_detach, should_switch, should_scroll, data_to_forward)
+        (action, data_to_forward)
     }
 
     fn show_scrollback_viewer(
         original_termios: &Termios,
-        socket: &mut UnixStream,
-        scrollback: &ScrollbackHandler,
+        socket: &Connection,
+        session: &Session,
     ) -> Result<()> {
         use nix::sys::termios::{tcsetattr, SetArg};
         use std::os::unix::io::BorrowedFd;
 
         println!("\r\n[Opening scrollback viewer...]\r");
 
-        // Get scrollback content
-        let content = scrollback.get_buffer();
-
         // Temporarily restore terminal for viewer
         let stdin_fd = 0;
         let stdin = unsafe { BorrowedFd::borrow_raw(stdin_fd) };
@@ -843,22 +2270,39 @@ impl PtyProcess {
         // Restore to original mode for viewer
         tcsetattr(&stdin, SetArg::TCSANOW, original_termios)?;
 
-        // Show scrollback viewer
-        let mut viewer = ScrollbackViewer::new(&content);
-        let _ = viewer.run(); // Ignore errors, just return to session
+        // Show scrollback viewer, paging through what the daemon kept
+        let mut viewer = ScrollbackViewer::new(DaemonScrollback { session });
+        if let Err(e) = viewer.run() {
+            println!("\r\n[Scrollback unavailable: {}]\r", e);
+        }
 
         // Re-enter raw mode
         tcsetattr(&stdin, SetArg::TCSANOW, &raw_termios)?;
 
         // Refresh display
-        send_refresh(socket)?;
         println!("\r\n[Returned to session]\r");
+        send_refresh_command(socket)?;
 
         Ok(())
     }
 
     /// Run the detached PTY handler
     pub fn run_detached(mut self) -> Result<()> {
+        let mut daemon = self.start_daemon()?;
+        while daemon.is_running() {
+            self.daemon_iteration(&mut daemon)?;
+
+            // Sleep until there is something to do
+            let deadline = daemon.deadline();
+            daemon.wait_for_activity(daemon.watched_master(&self), deadline);
+        }
+        daemon.finish();
+        Ok(())
+    }
+
+    /// Set up what the daemon's main loop needs, taking the listener and
+    /// output buffer out of the process
+    pub(super) fn start_daemon(&mut self) -> Result<DaemonLoop> {
         let listener = self
             .listener
             .take()
@@ -866,13 +2310,21 @@ impl PtyProcess {
 
         // Set listener to non-blocking
         listener.set_nonblocking(true)?;
+        Session::clear_legacy_socket_dir();
 
         let running = Arc::new(AtomicBool::new(true));
         let r = running.clone();
 
+        // Woken when the child exits or on shutdown, so the loop can sleep
+        // until something happens instead of polling
+        let wakeup = Wakeup::new()?;
+        wakeup.wake_on_child_exit()?;
+        let waker = wakeup.waker();
+
         // Handle cleanup on exit
         ctrlc::set_handler(move || {
             r.store(false, Ordering::SeqCst);
+            waker.wake();
         })
         .map_err(|e| NdsError::SignalError(format!("Failed to set signal handler: {}", e)))?;
 
@@ -880,11 +2332,12 @@ impl PtyProcess {
             .output_buffer
             .take()
             .ok_or_else(|| NdsError::PtyError("No output buffer available".to_string()))?;
-
-        // Support multiple concurrent clients
-        let mut active_clients: Vec<ClientInfo> = Vec::new();
-        let mut buffer = [0u8; DEFAULT_BUFFER_SIZE]; // Use 16KB buffer
-        let mut terminal_modes = TerminalModeTracker::default();
+        let config = Config::load().unwrap_or_default();
+        // Nothing is buffered yet, so a configured buffer can take its place
+        let output_buffer = match &config.output_buffer {
+            Some(buffer) => PtyBuffer::from_config(buffer),
+            None => output_buffer,
+        };
 
         // Get session ID from socket path
         let session_id = self
@@ -894,167 +2347,884 @@ impl PtyProcess {
             .unwrap_or("unknown")
             .to_string();
 
-        // Create IO handler
-        let io_handler = PtyIoHandler::new(self.master_fd);
+        // What the PTY's screen looks like, for repainting clients
+        let (cols, rows) = get_terminal_size_of(self.master_fd).unwrap_or((80, 24));
+
+        let mut scrollback =
+            ScrollbackBuffer::new(config.scrollback_limit.unwrap_or(DEFAULT_SCROLLBACK_LIMIT));
+        if let Some(compressor) = config
+            .compression
+            .as_ref()
+            .and_then(Compressor::from_config)
+        {
+            output_buffer.set_compression(compressor);
+            scrollback.set_compression(compressor);
+        }
+        let log = config.logging.as_ref().and_then(|logging| {
+            let opened =
+                log_path(&session_id).and_then(|path| Ok(SessionLog::open(&path, logging)?));
+            opened
+                .map_err(|e| eprintln!("Not logging output: {}", e))
+                .ok()
+        });
+
+        // Create health monitor
+        let health_monitor = HealthMonitor::new();
+        let monitor_thread = health_monitor.start_monitoring(300); // 5 minutes timeout
+
+        Ok(DaemonLoop {
+            listener,
+            wakeup,
+            running,
+            output_buffer,
+            active_clients: Vec::new(),
+            watchers: Watchers::default(),
+            buffer: vec![0u8; DEFAULT_BUFFER_SIZE], // Use 16KB buffer
+            session_id,
+            io_handler: PtyIoHandler::new(self.master_fd),
+            splitter: BoundarySplitter::default(),
+            screen: Screen::new(cols, rows),
+            health_monitor,
+            _monitor_thread: monitor_thread,
+            consecutive_pty_errors: 0,
+            last_pty_error: None,
+            counters: DaemonCounters::default(),
+            last_recovery_attempt: Instant::now(),
+            last_client_health_check: Instant::now(),
+            activity: ActivityTracker::new(),
+            bell: BellTracker::default(),
+            exit_handled: false,
+            child_started: Instant::now(),
+            respawn_at: None,
+            backoff: RespawnBackoff::default(),
+            output_paused: false,
+            message: None,
+            paste_buffer: None,
+            recording: None,
+            log,
+            clipboard: ClipboardFilter::new(&config.clipboard.unwrap_or_default()),
+            access: PeerAccess::new(&config.allow_users.unwrap_or_default()),
+            shared: None,
+            pending_attach: Vec::new(),
+            windows: Windows::default(),
+            mirror: Mirror::default(),
+            scrollback,
+            started_at: Utc::now(),
+        })
+    }
+
+    /// One pass of the daemon's main loop: handle whatever is ready without
+    /// blocking. Fails only if the PTY is beyond recovery.
+    pub(super) fn daemon_iteration(&mut self, daemon: &mut DaemonLoop) -> Result<()> {
+        let DaemonLoop {
+            listener,
+            output_buffer,
+            active_clients,
+            watchers,
+            buffer,
+            session_id,
+            io_handler,
+            splitter,
+            screen,
+            health_monitor,
+            consecutive_pty_errors,
+            last_pty_error,
+            counters,
+            last_recovery_attempt,
+            last_client_health_check,
+            activity,
+            bell,
+            exit_handled,
+            child_started,
+            respawn_at,
+            backoff,
+            output_paused,
+            message,
+            scrollback,
+            paste_buffer,
+            recording,
+            log,
+            clipboard,
+            access,
+            shared,
+            pending_attach,
+            windows,
+            mirror,
+            started_at,
+            ..
+        } = daemon;
+        let session_id = session_id.as_str();
+
+        if !*exit_handled {
+            if let Some(status) = self.poll_child() {
+                *exit_handled = true;
+                // Pass on what the child wrote before exiting
+                for _ in 0..MAX_DRAIN_READS {
+                    match self.read_from_pty(io_handler, splitter, buffer) {
+                        Ok(Some(data)) => {
+                            let data =
+                                filter_clipboard(clipboard, &data, active_clients, paste_buffer);
+                            screen.process(&data);
+                            scrollback.push(&data);
+                            record_output(recording, &data, screen);
+                            log_output(log, &data);
+                            let _ = self.broadcast_to_clients(active_clients, &data, output_buffer);
+                        }
+                        _ => break,
+                    }
+                }
+                eprintln!("Shell process exited ({})", status);
+                let _ = Session::record_exit(session_id, status);
+                if let Ok(session) = Session::load(session_id) {
+                    let _ = SessionHistory::record_session_exited(&session, status);
+                    hooks::run(Hook::Exit, &session);
+                    if session.respawn.should_respawn(status) {
+                        let delay = backoff.next_delay(child_started.elapsed());
+                        eprintln!("Respawning in {}s", delay.as_secs());
+                        *respawn_at = Some(Instant::now() + delay);
+                    }
+                }
+            }
+        }
+
+        // Check for new connections (non-critical, ignore errors)
+        let clients_before = active_clients.len();
+        let accepted = accept_connection(listener, |peer| access.check(peer).map(|()| None))
+            .or_else(|| {
+                let shared = shared.as_ref()?;
+                accept_connection(&shared.listener, |peer| {
+                    access.check_guest(peer, &shared_with(session_id))
+                })
+            });
+        let mut connection_requests = match accepted {
+            Some(accepted) => self
+                .handle_new_connection(
+                    accepted,
+                    active_clients,
+                    watchers,
+                    output_buffer,
+                    io_handler,
+                    screen,
+                )
+                .unwrap_or_default(),
+            None => ClientRequests::default(),
+        };
+        counters.client_connects += active_clients.len().saturating_sub(clients_before) as u64;
+        if let Some(client) = connection_requests.awaiting_approval.take() {
+            let request = AttachRequest::of(&client);
+            let payload = serde_json::to_string(&request)?;
+            for approver in active_clients.iter_mut().filter(|c| c.can_approve()) {
+                let _ = approver.send_event(ATTACH_REQUEST_EVENT, &payload);
+            }
+            eprintln!("Client {} is waiting to be let in", request.client_id);
+            pending_attach.push(PendingAttach {
+                client,
+                request,
+                until: Instant::now() + APPROVAL_TIMEOUT,
+            });
+        }
+        let mut redraw_message = false;
+        if let Some(new) = connection_requests.message.take() {
+            *message = Some(new);
+            redraw_message = true;
+        }
+
+        // Read from PTY master and broadcast; once the child is gone
+        // there is nothing left to read, and while a client catches up the
+        // output waits in the PTY
+        let read = if self.exit_status.is_some() || *output_paused {
+            Ok(None)
+        } else {
+            self.read_from_pty(io_handler, splitter, buffer)
+        };
+        match read {
+            Ok(Some(data)) => {
+                *consecutive_pty_errors = 0; // Reset error counter on success
+                health_monitor.update_activity(); // Update health status
+                activity.touch_output();
+                watchers.output();
+                let data = filter_clipboard(clipboard, &data, active_clients, paste_buffer);
+                let bells = screen.bells();
+                screen.process(&data);
+                scrollback.push(&data);
+                record_output(recording, &data, screen);
+                log_output(log, &data);
+                let _ = self.broadcast_to_clients(active_clients, &data, output_buffer);
+                redraw_message = true;
+                if screen.bells() > bells {
+                    watchers.bell();
+                    forward_bell(active_clients);
+                    bell.ring(session_id, !active_clients.is_empty());
+                }
+            }
+            Ok(None) => {
+                // No data available, this is normal
+            }
+            Err(e) => {
+                // Handle PTY errors gracefully
+                *consecutive_pty_errors += 1;
+                counters.pty_errors += 1;
+                *last_pty_error = Some(PtyErrorDump {
+                    at: Utc::now(),
+                    message: e.to_string(),
+                });
+
+                // Attempt recovery every 5 seconds
+                if last_recovery_attempt.elapsed() > Duration::from_secs(5) {
+                    // Try different recovery strategies
+                    let _ = attempt_recovery(RecoveryStrategy::RefreshTerminal, self.master_fd);
+                    let _ = attempt_recovery(RecoveryStrategy::ResetBuffers, self.master_fd);
+                    counters.recovery_attempts += 1;
+                    *last_recovery_attempt = Instant::now();
+                }
+
+                if *consecutive_pty_errors >= MAX_CONSECUTIVE_PTY_ERRORS {
+                    // Too many consecutive errors, PTY might be dead
+                    eprintln!(
+                        "PTY appears to be dead after {} errors: {}",
+                        consecutive_pty_errors, e
+                    );
+
+                    // A dead master with a live shell can be survived by
+                    // moving the session onto a brand new PTY pair
+                    let strategy = match &e {
+                        NdsError::Io(io_err) => {
+                            select_recovery_strategy(io_err, self.shell_is_running())
+                        }
+                        _ => RecoveryStrategy::RefreshTerminal,
+                    };
+                    if matches!(strategy, RecoveryStrategy::ReopenPty) {
+                        let (cols, rows) = self.pty_size(active_clients);
+                        counters.recovery_attempts += 1;
+                        match self.recover(strategy, cols, rows) {
+                            Ok(()) => {
+                                eprintln!("Re-opened PTY, shell restarted as {}", self.pid);
+                                io_handler.reopen(self.master_fd);
+                                *splitter = BoundarySplitter::default();
+                                *screen = Screen::new(cols, rows);
+                                for client in active_clients.iter_mut() {
+                                    let _ = send_terminal_refresh_sequences(client);
+                                    client.invalidate_view();
+                                }
+                                *consecutive_pty_errors = 0;
+                                return Ok(());
+                            }
+                            Err(reopen_err) => {
+                                eprintln!("Failed to re-open PTY: {}", reopen_err);
+                            }
+                        }
+                    }
+
+                    // Check if session is healthy according to monitor
+                    if !health_monitor.is_healthy() {
+                        eprintln!("Health monitor confirms session is unhealthy, terminating");
+                        return Err(e);
+                    }
+
+                    // Give it one more chance if health monitor thinks it's okay
+                    *consecutive_pty_errors = MAX_CONSECUTIVE_PTY_ERRORS - 1;
+                }
+
+                // Try to recover by sleeping a bit longer
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+
+        // The other windows' output only goes to the clients showing them
+        windows.pump(buffer, active_clients);
+
+        // Pass on input queued while the PTY was full; there is nobody to
+        // take it once the child is gone
+        if self.exit_status.is_some() {
+            io_handler.discard_input();
+        } else if let Err(e) = io_handler.flush_input() {
+            eprintln!("Warning: Failed to write to PTY: {}", e);
+            *last_pty_error = Some(PtyErrorDump {
+                at: Utc::now(),
+                message: format!("writing input: {}", e),
+            });
+        }
+
+        // Read from clients and handle input (non-critical, ignore errors)
+        let mut input_requests = self
+            .handle_client_input(
+                active_clients,
+                watchers,
+                io_handler,
+                windows,
+                activity,
+                screen,
+            )
+            .unwrap_or_default();
+        if !input_requests.broadcast.is_empty() {
+            mirror.send(session_id, std::mem::take(&mut input_requests.broadcast));
+        }
+        if let Some(new) = input_requests.message.take() {
+            *message = Some(new);
+            redraw_message = true;
+        }
+
+        windows.reap();
+        windows.rehome(active_clients, screen);
+
+        if let Some(status) = self.exit_status {
+            watchers.exited(status);
+        }
+
+        let can_ask = active_clients.iter().any(ClientInfo::can_approve);
+        let answers = &input_requests.approvals;
+        for (mut pending, allow) in settle(pending_attach, answers, can_ask, Instant::now()) {
+            let id = pending.request.client_id.clone();
+            for approver in active_clients.iter_mut().filter(|c| c.can_approve()) {
+                let _ = approver.send_event(ATTACH_SETTLED_EVENT, &id);
+            }
+            if allow {
+                eprintln!("Client {} was let in", id);
+                let before = active_clients.len();
+                self.admit_client(
+                    pending.client,
+                    active_clients,
+                    output_buffer,
+                    io_handler,
+                    screen,
+                );
+                counters.client_connects += active_clients.len().saturating_sub(before) as u64;
+            } else {
+                eprintln!("Client {} was turned away", id);
+                let client = &mut pending.client;
+                let _ = client.write_all(b"\r\n[Not let in to the session]\r\n");
+                let _ = client.flush();
+                let _ = client.stream.shutdown(std::net::Shutdown::Both);
+            }
+        }
+
+        let pages = connection_requests.scrollback.drain(..);
+        for (conn, request) in pages.chain(input_requests.scrollback.drain(..)) {
+            let _ = answer_scrollback(conn, scrollback, request);
+        }
+
+        let captures = connection_requests.capture_screen.drain(..);
+        for mut conn in captures.chain(input_requests.capture_screen.drain(..)) {
+            let (_, rows) = screen.size();
+            let mut text: Vec<String> = (0..rows).map(|row| screen.row_text(row)).collect();
+            while text.last().is_some_and(String::is_empty) {
+                text.pop();
+            }
+            let _ = conn.reply("capture_screen", &serde_json::to_string(&text)?);
+        }
+
+        // Answered once this pass is done, from the state it leaves behind
+        let dumps: Vec<ControlConn> = connection_requests
+            .debug_dump
+            .drain(..)
+            .chain(input_requests.debug_dump.drain(..))
+            .collect();
+        let metrics: Vec<ControlConn> = connection_requests
+            .metrics
+            .drain(..)
+            .chain(input_requests.metrics.drain(..))
+            .collect();
+
+        if let Some(text) = connection_requests.paste_buffer.take() {
+            *paste_buffer = Some(text);
+        }
+        let pastes = connection_requests.paste.drain(..);
+        for mut conn in pastes.chain(input_requests.paste.drain(..)) {
+            let pasted = match paste_buffer {
+                Some(text) if self.exit_status.is_none() => {
+                    // Wrapped so the shell takes it as a paste, not typing
+                    let bracketed = screen.mode(2004);
+                    let mut data = Vec::with_capacity(text.len() + 12);
+                    if bracketed {
+                        data.extend_from_slice(b"\x1b[200~");
+                    }
+                    data.extend_from_slice(text.as_bytes());
+                    if bracketed {
+                        data.extend_from_slice(b"\x1b[201~");
+                    }
+                    let _ = io_handler.write_to_pty(&data);
+                    activity.touch_input();
+                    Some(text.len())
+                }
+                _ => None,
+            };
+            let _ = conn.reply("paste", &serde_json::to_string(&pasted)?);
+        }
+
+        let inputs = connection_requests.input.drain(..);
+        for (mut conn, data) in inputs.chain(input_requests.input.drain(..)) {
+            let sent = if self.exit_status.is_none() {
+                let _ = io_handler.write_to_pty(&data);
+                activity.touch_input();
+                Some(data.len())
+            } else {
+                None
+            };
+            let _ = conn.reply("input", &serde_json::to_string(&sent)?);
+        }
+
+        let records = connection_requests.record.drain(..);
+        for (mut conn, request) in records.chain(input_requests.record.drain(..)) {
+            match request {
+                RecordRequest::Start { path, max_bytes } => {
+                    let started = match recording {
+                        Some(current) => Err(format!(
+                            "already recording to {}",
+                            current.current_path().display()
+                        )),
+                        None => {
+                            let title = Session::load(session_id)
+                                .ok()
+                                .map(|s| s.name.unwrap_or(s.id));
+                            Recording::start(&path, title, max_bytes, screen)
+                                .map(|started| *recording = Some(started))
+                                .map_err(|e| format!("cannot write {}: {}", path.display(), e))
+                        }
+                    };
+                    let _ = conn.reply("record", &serde_json::to_string(&started)?);
+                }
+                RecordRequest::Stop => {
+                    let summary = recording.take().map(|r| r.summary());
+                    let _ = conn.reply("record_stop", &serde_json::to_string(&summary)?);
+                }
+            }
+        }
+
+        let window_requests = connection_requests.windows.drain(..);
+        for (mut conn, request) in window_requests.chain(input_requests.windows.drain(..)) {
+            match request {
+                WindowRequest::List => {
+                    let first = WindowSummary::of(
+                        0,
+                        None,
+                        self.pid,
+                        self.master_fd,
+                        *started_at,
+                        active_clients,
+                    );
+                    let mut summaries = vec![first];
+                    summaries.extend(windows.summaries(active_clients));
+                    let _ = conn.reply("windows", &serde_json::to_string(&summaries)?);
+                }
+                WindowRequest::New { name, command } => {
+                    let size = get_terminal_size_of(self.master_fd).unwrap_or((80, 24));
+                    let opened = windows
+                        .open(session_id, name, command, size)
+                        .map_err(|e| e.to_string());
+                    let _ = conn.reply("window_new", &serde_json::to_string(&opened)?);
+                }
+                WindowRequest::Close(id) => {
+                    let closed = windows.close(id);
+                    windows.rehome(active_clients, screen);
+                    let _ = conn.reply("window_kill", &closed.to_string());
+                }
+            }
+        }
+
+        let shares = connection_requests.share.drain(..);
+        let shares: Vec<ControlConn> = shares.chain(input_requests.share.drain(..)).collect();
+        if !shares.is_empty() {
+            let shared_with = shared_with(session_id);
+            let path = update_shared_socket(shared, session_id, &shared_with)
+                .map_err(|e| format!("cannot open the shared socket: {}", e));
+            if let Err(e) = &path {
+                eprintln!("Not sharing the session: {}", e);
+            }
+            self.update_guests(active_clients, &shared_with)?;
+            for mut conn in shares {
+                let _ = conn.reply("share", &serde_json::to_string(&path)?);
+            }
+        }
+
+        if connection_requests.detach || input_requests.detach {
+            let _ = self.detach_all_clients(active_clients);
+        }
+
+        // A restart on request replaces the shell whether or not it is
+        // still running, and takes the place of any pending respawn
+        let restart = connection_requests.restart || input_requests.restart;
+        if restart || respawn_at.is_some_and(|at| Instant::now() >= at) {
+            *respawn_at = None;
+            let (cols, rows) = self.pty_size(active_clients);
+            match self.reopen_pty(cols, rows) {
+                Ok(()) => {
+                    if restart {
+                        eprintln!("Restarted shell as {}", self.pid);
+                        let _ = Session::record_restart(session_id);
+                    } else {
+                        eprintln!("Respawned shell as {}", self.pid);
+                        let _ = Session::record_respawn(session_id);
+                    }
+                    io_handler.reopen(self.master_fd);
+                    *splitter = BoundarySplitter::default();
+                    *screen = Screen::new(cols, rows);
+                    for client in active_clients.iter_mut() {
+                        let _ = send_terminal_refresh_sequences(client);
+                        client.invalidate_view();
+                    }
+                    *exit_handled = false;
+                    *child_started = Instant::now();
+                }
+                Err(e) if restart => {
+                    eprintln!("Failed to restart shell: {}", e);
+                }
+                Err(e) => {
+                    eprintln!("Failed to respawn shell: {}", e);
+                    *respawn_at = Some(Instant::now() + backoff.next_delay(Duration::ZERO));
+                }
+            }
+        }
+
+        // Opportunistically flush any queued output so slow terminals catch up
+        let _ = self.flush_pending_clients(active_clients);
+
+        // Put the screen back once the status message expires
+        if message.as_ref().is_some_and(|m| m.expired(Instant::now())) {
+            *message = None;
+            clear_message(screen, active_clients);
+        }
+
+        // Draw clients that don't fit the PTY from the screen, and size the
+        // other windows to theirs or to the panes they are shown in
+        self.update_views(screen, active_clients);
+        if let Ok(size) = get_terminal_size_of(self.master_fd) {
+            windows.fit(size, active_clients, screen);
+        }
+        if let Some(current) = recording.as_mut() {
+            if let Err(e) = current.follow(screen) {
+                eprintln!("Stopped recording: {}", e);
+                *recording = None;
+            }
+        }
+
+        // Keep the status message on top of whatever was drawn under it
+        if let Some(message) = message.as_ref().filter(|_| redraw_message) {
+            show_message(message, screen, active_clients);
+        }
+
+        let paused = *output_paused;
+        *output_paused = active_clients.iter().any(|c| c.holds_pty(paused));
+        if *output_paused != paused {
+            trace(|| format!("PTY reads {}", if paused { "resumed" } else { "paused" }));
+        }
+
+        // Periodic client health check every 10 seconds
+        if last_client_health_check.elapsed() >= CLIENT_HEALTH_CHECK_INTERVAL {
+            self.check_client_health(active_clients);
+            *last_client_health_check = Instant::now();
+        }
+
+        if !active_clients.is_empty() {
+            bell.seen(session_id);
+        }
+        activity.persist_if_due(session_id);
+
+        for mut conn in dumps {
+            let dump = daemon.dump(self);
+            let _ = conn.reply("debug_dump", &serde_json::to_string(&dump)?);
+        }
+        for mut conn in metrics {
+            let metrics = daemon.metrics(self);
+            let _ = conn.reply("metrics", &serde_json::to_string(&metrics)?);
+        }
+        Ok(())
+    }
 
-        // Create health monitor
-        let health_monitor = HealthMonitor::new();
-        let _monitor_thread = health_monitor.start_monitoring(300); // 5 minutes timeout
-
-        // Track consecutive errors for recovery
-        let mut consecutive_pty_errors = 0;
-        let max_consecutive_errors = 10;
-        let mut last_recovery_attempt = Instant::now();
-        let mut last_client_health_check = Instant::now();
-
-        while running.load(Ordering::SeqCst) {
-            // Check for new connections (non-critical, ignore errors)
-            let _ = self.handle_new_connections(
-                &listener,
-                &mut active_clients,
-                &output_buffer,
-                &io_handler,
-                &session_id,
-                &terminal_modes,
-            );
+    /// Size for a new PTY: the smallest attached client, else the last known size
+    fn pty_size(&self, active_clients: &[ClientInfo]) -> (u16, u16) {
+        let session = Session::load(&self.session_id).ok();
+        let policy = session.as_ref().map(|s| s.resize).unwrap_or_default();
+        policy
+            .size_for(&client_sizes(active_clients))
+            .or_else(|| session.and_then(|s| s.size))
+            .unwrap_or((80, 24))
+    }
 
-            // Read from PTY master and broadcast
-            match self.read_from_pty(&io_handler, &mut buffer) {
-                Ok(Some(data)) => {
-                    consecutive_pty_errors = 0; // Reset error counter on success
-                    health_monitor.update_activity(); // Update health status
-                    terminal_modes.observe(&data);
-                    let _ = self.broadcast_to_clients(
-                        &mut active_clients,
-                        &data,
-                        &output_buffer,
-                        &session_id,
-                    );
-                }
-                Ok(None) => {
-                    // No data available, this is normal
-                }
-                Err(e) => {
-                    // Handle PTY errors gracefully
-                    consecutive_pty_errors += 1;
-
-                    // Attempt recovery every 5 seconds
-                    if last_recovery_attempt.elapsed() > Duration::from_secs(5) {
-                        // Try different recovery strategies
-                        let _ = attempt_recovery(RecoveryStrategy::RefreshTerminal, self.master_fd);
-                        let _ = attempt_recovery(RecoveryStrategy::ResetBuffers, self.master_fd);
-                        last_recovery_attempt = Instant::now();
-                    }
+    fn resize_policy(&self) -> ResizePolicy {
+        Session::load(&self.session_id)
+            .map(|s| s.resize)
+            .unwrap_or_default()
+    }
 
-                    if consecutive_pty_errors >= max_consecutive_errors {
-                        // Too many consecutive errors, PTY might be dead
-                        eprintln!(
-                            "PTY appears to be dead after {} errors: {}",
-                            consecutive_pty_errors, e
-                        );
-
-                        // Check if session is healthy according to monitor
-                        if !health_monitor.is_healthy() {
-                            eprintln!("Health monitor confirms session is unhealthy, terminating");
-                            return Err(e);
-                        }
+    /// Size the PTY for the attached clients according to the session's
+    /// resize policy. Returns the new size, or `None` if it was left alone.
+    fn apply_resize_policy(&self, active_clients: &[ClientInfo]) -> Result<Option<(u16, u16)>> {
+        let size = self.resize_policy().size_for(&client_sizes(active_clients));
+        self.resize_pty(size)?;
+        Ok(size)
+    }
 
-                        // Give it one more chance if health monitor thinks it's okay
-                        consecutive_pty_errors = max_consecutive_errors - 1;
-                    }
+    /// Set the PTY size and tell the shell; `None` leaves it alone
+    fn resize_pty(&self, size: Option<(u16, u16)>) -> Result<()> {
+        if let Some((cols, rows)) = size {
+            set_terminal_size(self.master_fd, cols, rows)?;
+            let _ = kill(self.pid, Signal::SIGWINCH);
+        }
+        Ok(())
+    }
 
-                    // Try to recover by sleeping a bit longer
-                    thread::sleep(Duration::from_millis(100));
+    /// Keep the screen at the PTY's size and bring clients that don't fit the
+    /// PTY up to date from it. A client that fits again is repainted once and
+    /// goes back to getting the raw output.
+    fn update_views(&self, screen: &mut Screen, active_clients: &mut [ClientInfo]) {
+        let Ok((cols, rows)) = get_terminal_size_of(self.master_fd) else {
+            return;
+        };
+        screen.resize(cols, rows);
+
+        for client in active_clients.iter_mut().filter(|c| c.shows(0)) {
+            // Until a client reports its size there is nothing to fit
+            if !client.size_reported || (client.cols, client.rows) == (cols, rows) {
+                if client.view.take().is_some() {
+                    trace(|| format!("client {} fits the PTY again", client.id));
+                    client.resync = false;
+                    let _ = client.send_data(&screen.repaint());
+                } else if client.resync && client.pending_output.is_empty() {
+                    trace(|| format!("client {} caught up after dropping output", client.id));
+                    client.resync = false;
+                    let _ = client.send_data(&screen.repaint());
                 }
+                continue;
+            }
+
+            if client.view.is_none() {
+                trace(|| {
+                    format!(
+                        "client {} ({}x{}) gets its own view of the {}x{} PTY",
+                        client.id, client.cols, client.rows, cols, rows
+                    )
+                });
             }
+            let (client_cols, client_rows) = (client.cols, client.rows);
+            let frame = client.view.get_or_insert_with(ScreenView::default).render(
+                screen,
+                client_cols,
+                client_rows,
+            );
+            let _ = client.send_data(&frame);
+        }
+    }
 
-            // Read from clients and handle input (non-critical, ignore errors)
-            let _ = self.handle_client_input(&mut active_clients, &io_handler, &session_id);
+    /// Check whether the shell child is still running (zombies count as exited)
+    fn shell_is_running(&mut self) -> bool {
+        self.poll_child().is_none()
+    }
 
-            // Opportunistically flush any queued output so slow terminals catch up
-            let _ = self.flush_pending_clients(&mut active_clients, &session_id);
+    /// The process group in the PTY's foreground: the shell, or the job it
+    /// is running
+    fn foreground_pgid(&self) -> Option<Pid> {
+        let master = unsafe { BorrowedFd::borrow_raw(self.master_fd) };
+        nix::unistd::tcgetpgrp(master).ok()
+    }
 
-            // Periodic client health check every 10 seconds
-            if last_client_health_check.elapsed() > Duration::from_secs(10) {
-                self.check_client_health(&mut active_clients, &session_id);
-                last_client_health_check = Instant::now();
-            }
+    /// What is in the PTY's foreground, unless the shell has exited
+    fn foreground(&self) -> Option<ForegroundProcess> {
+        if self.exit_status.is_some() {
+            return None;
+        }
+        let pgid = self.foreground_pgid()?.as_raw();
+        Some(ForegroundProcess {
+            pgid,
+            // The group's leader may have exited while the rest of a
+            // pipeline runs on
+            command: command_line(pgid).unwrap_or_default(),
+        })
+    }
 
-            // Small sleep to prevent busy loop
-            thread::sleep(Duration::from_millis(10));
+    /// Send `signal` to the PTY's foreground process group, returning the
+    /// group's ID
+    fn signal_foreground(&self, signal: Signal) -> std::result::Result<i32, String> {
+        if self.exit_status.is_some() {
+            return Err("the session's shell has exited".to_string());
         }
+        let pgid = self
+            .foreground_pgid()
+            .ok_or_else(|| "no process is in the foreground".to_string())?;
+        nix::sys::signal::killpg(pgid, signal)
+            .map_err(|e| format!("failed to send {} to process group {}: {}", signal, pgid, e))?;
+        Ok(pgid.as_raw())
+    }
 
-        // Stop health monitoring
-        health_monitor.stop_monitoring();
+    /// Reap the shell child if it has exited, remembering how it ended
+    fn poll_child(&mut self) -> Option<ExitStatus> {
+        if self.exit_status.is_none() {
+            self.exit_status = match waitpid(self.pid, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::Exited(_, code)) => Some(ExitStatus::Code(code)),
+                Ok(WaitStatus::Signaled(_, signal, _)) => Some(ExitStatus::Signal(signal as i32)),
+                _ => None,
+            };
+        }
+        self.exit_status
+    }
 
-        Ok(())
+    /// Redraw strategy for the session, re-read so changes apply to running sessions
+    fn redraw_strategy(&self, session_id: &str) -> RedrawStrategy {
+        let configured = Session::load(session_id).ok().and_then(|s| s.redraw);
+        RedrawStrategy::resolve(configured)
     }
 
-    fn handle_new_connections(
+    fn handle_new_connection(
         &self,
-        listener: &UnixListener,
+        accepted: Accepted,
         active_clients: &mut Vec<ClientInfo>,
+        watchers: &mut Watchers,
         output_buffer: &PtyBuffer,
         io_handler: &PtyIoHandler,
-        session_id: &str,
-        terminal_modes: &TerminalModeTracker,
-    ) -> Result<()> {
-        match listener.accept() {
-            Ok((stream, _)) => {
-                // Switch to non-blocking immediately so we never block the daemon.
-                stream.set_nonblocking(true)?;
+        screen: &Screen,
+    ) -> Result<ClientRequests> {
+        let mut requests = ClientRequests::default();
+        let Accepted { stream, guest } = accepted;
+        // Control connections (`nds wait`, `nds restart`, `nds clients`...)
+        // aren't attaching, so skip the replay and redraw
+        let (control, attach_framed) = match take_opening(&stream) {
+            Opening::Framed(Role::Control, _) => {
+                (read_control_command(&stream).map(|cmd| (cmd, true)), None)
+            }
+            Opening::LegacyControl(cmd, args) => (Some(((cmd, args, None), false)), None),
+            Opening::Framed(Role::Attach, hello) => (None, Some(Some(hello))),
+            Opening::Legacy => (None, Some(None)),
+            Opening::Closed => (None, None),
+        };
+
+        // Switch to non-blocking so we never block the daemon
+        stream.set_nonblocking(true)?;
+
+        if let Some(((cmd, args, data), framed)) = control {
+            let mut conn = ControlConn::new(stream, framed);
+            if let Some(role) = guest.filter(|role| !role.allows(&cmd)) {
+                let _ = conn.reply("refused", &guest_refusal(role, &cmd));
+                return Ok(requests);
+            }
+            self.answer_control(
+                conn,
+                &cmd,
+                &args,
+                data,
+                active_clients,
+                watchers,
+                &mut requests,
+            )?;
+            return Ok(requests);
+        }
+
+        // Hung up, sent a bad hello or a control command we couldn't read
+        let Some(hello) = attach_framed else {
+            return Ok(requests);
+        };
+        let mut client = match hello {
+            Some(hello) => {
+                let approves = hello.capabilities.iter().any(|c| c == APPROVE_ATTACH);
+                let shows_windows = hello.capabilities.iter().any(|c| c == WINDOWS);
+                let mut client = ClientInfo::framed(stream, hello.software);
+                client.approves_attach = approves;
+                client.shows_windows = shows_windows;
+                client
+            }
+            None => ClientInfo::new(stream),
+        };
+        client.guest = guest;
+
+        // With attach approval on, nothing is shown until an attached
+        // client lets this one in
+        if self.needs_approval(active_clients) {
+            let _ = client
+                .send_data(b"[Waiting for a client attached to the session to let you in]\r\n");
+            requests.awaiting_approval = Some(client);
+            return Ok(requests);
+        }
+        self.admit_client(client, active_clients, output_buffer, io_handler, screen);
+        Ok(requests)
+    }
 
-                let mut client = ClientInfo::new(stream);
+    /// Whether a client attaching now waits to be let in: attach approval is
+    /// on for the session and an attached client can be asked
+    fn needs_approval(&self, active_clients: &[ClientInfo]) -> bool {
+        active_clients.iter().any(ClientInfo::can_approve)
+            && Session::load(&self.session_id).is_ok_and(|session| session.approve_attach)
+    }
 
-                if let Err(e) = terminal_modes.apply_to_client(&mut client) {
+    /// Bring a new client up to date with the session and start streaming
+    /// its output to it
+    fn admit_client(
+        &self,
+        mut client: ClientInfo,
+        active_clients: &mut Vec<ClientInfo>,
+        output_buffer: &PtyBuffer,
+        io_handler: &PtyIoHandler,
+        screen: &Screen,
+    ) {
+        let strategy = self.redraw_strategy(&self.session_id);
+
+        if strategy.repaints_screen() {
+            // The screen already shows what the buffered output drew
+            output_buffer.clear();
+            if let Err(e) = client.send_data(&screen.repaint()) {
+                eprintln!("Warning: failed to repaint new client {}: {}", client.id, e);
+            }
+            trace(|| format!("repainted client {} from the screen", client.id));
+        } else {
+            if strategy.replays_modes() {
+                if let Err(e) = client.send_data(&screen.mode_sequence()) {
                     eprintln!(
                         "Warning: failed to reapply terminal modes for client {}: {}",
                         client.id, e
                     );
                 }
+            }
 
-                // Don't send notifications - they corrupt the display
-                if let Err(e) =
-                    send_buffered_output_to_client(&mut client, output_buffer, io_handler)
-                {
-                    eprintln!(
-                        "Warning: failed to send buffered output to new client {}: {}",
-                        client.id, e
-                    );
-                }
+            // Don't send notifications - they corrupt the display
+            if let Err(e) = send_buffered_output_to_client(&mut client, output_buffer) {
+                eprintln!(
+                    "Warning: failed to send buffered output to new client {}: {}",
+                    client.id, e
+                );
+            }
+        }
 
-                let _ = client.flush_pending();
+        // Nudge the application so the client sees the latest frame
+        if strategy.nudges_application() {
+            let _ = io_handler.send_refresh();
+        }
 
-                active_clients.push(client);
+        let _ = client.flush_pending();
 
-                // Update client count in status file
-                let _ = Session::update_client_count(session_id, active_clients.len());
-            }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                // No new connections
-            }
-            Err(_) => {
-                // Error accepting connection, continue
-            }
+        if let Ok(session) = Session::load(&self.session_id) {
+            let _ = SessionHistory::record_client_connected(
+                &session,
+                &client.id,
+                client.peer,
+                client.tty.clone(),
+            );
         }
-        Ok(())
+        active_clients.push(client);
     }
 
+    /// Read output from the PTY, in chunks that don't end partway through a
+    /// UTF-8 character or escape sequence
     fn read_from_pty(
         &self,
         io_handler: &PtyIoHandler,
+        splitter: &mut BoundarySplitter,
         buffer: &mut [u8],
     ) -> Result<Option<Vec<u8>>> {
         match io_handler.read_from_pty(buffer) {
             Ok(0) => {
-                // Shell exited, but don't kill the daemon!
-                // Mark that shell needs restart when client connects
-                eprintln!("Shell process exited, session remains alive for restart");
-                Ok(None)
+                // Shell exited, but don't kill the daemon; the exit is picked
+                // up by poll_child and the shell can be respawned or restarted
+                Ok(splitter.take())
+            }
+            Ok(n) => {
+                let data = splitter.split(&buffer[..n], Instant::now());
+                Ok(Some(data).filter(|d| !d.is_empty()))
             }
-            Ok(n) => Ok(Some(buffer[..n].to_vec())),
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
-            Err(e) => Err(NdsError::Io(e)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                Ok(splitter.take_stale(Instant::now()))
+            }
+            // Whatever was held back goes out before the error is reported
+            Err(e) => match splitter.take() {
+                Some(held) => Ok(Some(held)),
+                None => Err(NdsError::Io(e)),
+            },
         }
     }
 
@@ -1063,13 +3233,25 @@ impl PtyProcess {
         active_clients: &mut Vec<ClientInfo>,
         data: &[u8],
         output_buffer: &PtyBuffer,
-        session_id: &str,
     ) -> Result<()> {
         if !active_clients.is_empty() {
             let mut disconnected_indices = Vec::new();
+            // Every client's queue holds the same copy of the output
+            let output = SharedOutput::new(data);
 
             for (i, client) in active_clients.iter_mut().enumerate() {
-                let write_result = client.flush_pending().and_then(|_| client.send_data(data));
+                // Clients with their own view are drawn from the screen
+                // instead, as are clients that fell behind once caught up;
+                // clients showing only other windows don't see this one
+                if client.view.is_some() || client.resync || !client.shows(0) {
+                    continue;
+                }
+                // and clients still being replayed to get it after the rest
+                if let Some(replay) = client.replay.as_mut() {
+                    replay.push(data);
+                    continue;
+                }
+                let write_result = client.send_output(&output);
 
                 if let Err(e) = write_result {
                     match e.kind() {
@@ -1089,11 +3271,7 @@ impl PtyProcess {
 
             // Remove disconnected clients
             if !disconnected_indices.is_empty() {
-                self.handle_client_disconnections(
-                    active_clients,
-                    disconnected_indices,
-                    session_id,
-                )?;
+                self.handle_client_disconnections(active_clients, disconnected_indices)?;
             }
 
             // Buffer if no clients
@@ -1111,34 +3289,82 @@ impl PtyProcess {
         &self,
         active_clients: &mut Vec<ClientInfo>,
         disconnected_indices: Vec<usize>,
-        session_id: &str,
     ) -> Result<()> {
         for i in disconnected_indices.iter().rev() {
             active_clients.remove(*i);
         }
 
-        // Update client count
-        let _ = Session::update_client_count(session_id, active_clients.len());
-
         // Don't send disconnect notifications - just refresh and resize
         if !active_clients.is_empty() {
             // Send refresh sequence to remaining clients
             for client in active_clients.iter_mut() {
-                let _ = send_terminal_refresh_sequences(&mut client.stream);
-                let _ = client.stream.flush();
+                let _ = send_terminal_refresh_sequences(client);
+                let _ = client.flush();
+                client.invalidate_view();
             }
 
-            // Resize to smallest terminal
-            self.resize_to_smallest(active_clients)?;
+            // Re-fit the PTY to the clients that are left
+            if self.apply_resize_policy(active_clients)?.is_some() {
+                let _ = PtyIoHandler::new(self.master_fd).send_refresh();
+            }
         }
         Ok(())
     }
 
-    fn flush_pending_clients(
+    /// Disconnect one attached client by ID, telling it why first. Returns
+    /// whether the client was attached.
+    fn kick_client(&self, active_clients: &mut Vec<ClientInfo>, target_id: &str) -> Result<bool> {
+        let Some(idx) = active_clients.iter().position(|c| c.id == target_id) else {
+            return Ok(false);
+        };
+        let client = &mut active_clients[idx];
+        let _ = client.write_all(b"\r\n[You have been disconnected by another client]\r\n");
+        let _ = client.flush();
+        let _ = client.stream.shutdown(std::net::Shutdown::Both);
+        self.handle_client_disconnections(active_clients, vec![idx])?;
+        Ok(true)
+    }
+
+    /// Give attached guests the role they now have, and hang up on those the
+    /// session is no longer shared with
+    fn update_guests(
         &self,
         active_clients: &mut Vec<ClientInfo>,
-        session_id: &str,
+        shared_with: &BTreeMap<String, ShareRole>,
     ) -> Result<()> {
+        let mut unshared = Vec::new();
+        for (idx, client) in active_clients.iter_mut().enumerate() {
+            if client.guest.is_none() {
+                continue;
+            }
+            let user = client.peer.map(|peer| peer.user()).unwrap_or_default();
+            client.guest = shared_with.get(&user).copied();
+            if client.guest.is_none() {
+                let _ = client
+                    .write_all(b"\r\n[Detached: the session is no longer shared with you]\r\n");
+                let _ = client.flush();
+                let _ = client.stream.shutdown(std::net::Shutdown::Both);
+                unshared.push(idx);
+            }
+        }
+        if unshared.is_empty() {
+            return Ok(());
+        }
+        self.handle_client_disconnections(active_clients, unshared)
+    }
+
+    /// Hang up on every attached client; their attach loops see the closed
+    /// socket and detach as if the user had pressed `~d`
+    fn detach_all_clients(&self, active_clients: &mut Vec<ClientInfo>) -> Result<()> {
+        for client in active_clients.iter_mut() {
+            let _ = client.flush_pending();
+            let _ = client.stream.shutdown(std::net::Shutdown::Both);
+        }
+        let indices = (0..active_clients.len()).collect();
+        self.handle_client_disconnections(active_clients, indices)
+    }
+
+    fn flush_pending_clients(&self, active_clients: &mut Vec<ClientInfo>) -> Result<()> {
         if active_clients.is_empty() {
             return Ok(());
         }
@@ -1146,7 +3372,10 @@ impl PtyProcess {
         let mut disconnected_indices = Vec::new();
 
         for (i, client) in active_clients.iter_mut().enumerate() {
-            if let Err(e) = client.flush_pending() {
+            let flushed = client
+                .flush_pending()
+                .and_then(|_| client.continue_replay());
+            if let Err(e) = flushed {
                 match e.kind() {
                     io::ErrorKind::BrokenPipe
                     | io::ErrorKind::ConnectionAborted
@@ -1161,34 +3390,14 @@ impl PtyProcess {
         }
 
         if !disconnected_indices.is_empty() {
-            self.handle_client_disconnections(active_clients, disconnected_indices, session_id)?;
+            self.handle_client_disconnections(active_clients, disconnected_indices)?;
         }
 
         Ok(())
     }
 
-    fn resize_to_smallest(&self, active_clients: &[ClientInfo]) -> Result<()> {
-        let mut min_cols = u16::MAX;
-        let mut min_rows = u16::MAX;
-
-        for client in active_clients {
-            min_cols = min_cols.min(client.cols);
-            min_rows = min_rows.min(client.rows);
-        }
-
-        if min_cols != u16::MAX && min_rows != u16::MAX {
-            set_terminal_size(self.master_fd, min_cols, min_rows)?;
-            let _ = kill(self.pid, Signal::SIGWINCH);
-
-            // Send refresh
-            let io_handler = PtyIoHandler::new(self.master_fd);
-            let _ = io_handler.send_refresh();
-        }
-        Ok(())
-    }
-
     /// Check if clients are still healthy and remove dead ones
-    fn check_client_health(&self, active_clients: &mut Vec<ClientInfo>, session_id: &str) {
+    fn check_client_health(&self, active_clients: &mut Vec<ClientInfo>) {
         let mut dead_clients = Vec::new();
 
         for (i, client) in active_clients.iter_mut().enumerate() {
@@ -1204,9 +3413,6 @@ impl PtyProcess {
                 active_clients.remove(*i);
             }
 
-            // Update client count
-            let _ = Session::update_client_count(session_id, active_clients.len());
-
             // Notify remaining clients
             if !active_clients.is_empty() {
                 let notification = format!(
@@ -1215,8 +3421,9 @@ impl PtyProcess {
                     active_clients.len()
                 );
                 for client in active_clients.iter_mut() {
-                    let _ = client.stream.write_all(notification.as_bytes());
-                    let _ = client.stream.flush();
+                    let _ = client.write_all(notification.as_bytes());
+                    let _ = client.flush();
+                    client.invalidate_view();
                 }
             }
         }
@@ -1225,75 +3432,214 @@ impl PtyProcess {
     fn handle_client_input(
         &self,
         active_clients: &mut Vec<ClientInfo>,
+        watchers: &mut Watchers,
         io_handler: &PtyIoHandler,
-        session_id: &str,
-    ) -> Result<()> {
+        windows: &mut Windows,
+        activity: &mut ActivityTracker,
+        screen: &Screen,
+    ) -> Result<ClientRequests> {
+        let mut requests = ClientRequests::default();
         let mut disconnected_indices = Vec::new();
         let mut client_buffer = [0u8; DEFAULT_BUFFER_SIZE]; // Use 16KB buffer
-        let mut pending_disconnects = Vec::new(); // Track clients to disconnect
+        let mut late_control = Vec::new(); // Control connections accepted as clients
+        let mut exclusive_requesters: Vec<String> = Vec::new(); // Clients asking to detach others
+        let mut sizes = client_sizes(active_clients); // Kept current as clients resize
+        let mut policy = None; // Loaded on the first resize
 
-        // Store the count before the loop
-        let client_count = active_clients.len();
+        let mut flagged_clients: Vec<(String, u64)> = Vec::new(); // Newly flagged floods
+        let rate_policy = InputRatePolicy::from_env();
+        let now = Instant::now();
 
         for (i, client) in active_clients.iter_mut().enumerate() {
+            // Leave a flooding client's input in its socket until the window
+            // rolls over, and everyone's while the PTY catches up on input
+            if client.input_rate.is_throttled(now, &rate_policy) || io_handler.input_backlogged() {
+                continue;
+            }
+
             match client.stream.read(&mut client_buffer) {
                 Ok(0) => {
                     disconnected_indices.push(i);
                 }
                 Ok(n) => {
-                    let data = &client_buffer[..n];
+                    if client.input_rate.record(n, now, &rate_policy) {
+                        flagged_clients
+                            .push((client.id.clone(), client.input_rate.bytes_per_minute()));
+                    }
+
+                    let events = match client.input_events(&client_buffer[..n]) {
+                        Ok(events) => events,
+                        Err(e) => {
+                            eprintln!("Warning: dropping client {}: {}", client.id, e);
+                            disconnected_indices.push(i);
+                            continue;
+                        }
+                    };
+
+                    for event in events {
+                        let (cmd, args) = match event {
+                            ClientEvent::Command(cmd, args) => (cmd, args),
+                            ClientEvent::Input(_)
+                                if client.guest.is_some_and(|role| !role.allows_input()) =>
+                            {
+                                continue;
+                            }
+                            ClientEvent::Input(data) => {
+                                activity.touch_input();
+
+                                // Ignore write errors to prevent session death
+                                // from transient issues
+                                let written = match client.window {
+                                    0 => io_handler.write_to_pty(&data),
+                                    window => windows.write(window, &data),
+                                };
+                                if let Err(e) = written {
+                                    eprintln!("Warning: Failed to write to PTY: {}", e);
+                                }
+                                if client.broadcast {
+                                    requests.broadcast.extend(data);
+                                }
+                                continue;
+                            }
+                        };
+                        if let Some(role) = client.guest.filter(|role| !role.allows(&cmd)) {
+                            trace(|| {
+                                format!("client {}: {}", client.id, guest_refusal(role, &cmd))
+                            });
+                            continue;
+                        }
 
-                    // Check for NDS commands
-                    if let Some((cmd, args)) = parse_nds_command(data) {
                         if cmd == "resize" && args.len() == 2 {
                             if let (Ok(cols), Ok(rows)) =
                                 (args[0].parse::<u16>(), args[1].parse::<u16>())
                             {
+                                // Any peer can send this, so hold it to what
+                                // clients send (see `send_resize_command`)
+                                let (cols, rows) = (cols.clamp(1, 9999), rows.clamp(1, 9999));
+                                if (client.cols, client.rows) != (cols, rows) {
+                                    let _ = Session::update_size(&self.session_id, cols, rows);
+                                }
                                 client.cols = cols;
                                 client.rows = rows;
-                                set_terminal_size(self.master_fd, cols, rows)?;
-                                let _ = kill(self.pid, Signal::SIGWINCH);
-
-                                // Forward any remaining data after command
-                                if let Some(end_idx) = get_command_end(data) {
-                                    if end_idx < n {
-                                        io_handler.write_to_pty(&data[end_idx..])?;
-                                    }
-                                }
-                                continue;
+                                client.size_reported = true;
+                                sizes[i] = (cols, rows);
+                                // Resize before forwarding the input that follows,
+                                // which may already depend on the new size
+                                let policy = *policy.get_or_insert_with(|| self.resize_policy());
+                                self.resize_pty(policy.size_for(&sizes))?;
+                            }
+                        } else if cmd == "tty" {
+                            client.tty = args.first().cloned();
+                        } else if cmd == "setenv" {
+                            let _ = apply_setenv(&self.session_id, &args);
+                        } else if cmd == "approve_attach" && args.len() == 2 {
+                            if client.can_approve() {
+                                requests
+                                    .approvals
+                                    .push((args[0].clone(), args[1] == "allow"));
+                            }
+                        } else if cmd == "window_new" && client.shows_windows {
+                            let (name, command) = parse_window_new(&args);
+                            let size = get_terminal_size_of(self.master_fd).unwrap_or((80, 24));
+                            match windows.open(&self.session_id, name, command, size) {
+                                Ok(id) => windows.show(client, id, screen),
+                                Err(e) => eprintln!("Failed to open a window: {}", e),
                             }
-                        } else if cmd == "list_clients" {
-                            // Handle list clients command
-                            // Just send a basic count for now due to borrow checker limitations
-                            let response = format!("Connected clients: {}\r\n", client_count);
-                            let _ = client.stream.write_all(response.as_bytes());
-                            let _ = client.stream.flush();
-                            continue; // Don't forward to PTY
-                        } else if cmd == "disconnect_client" && !args.is_empty() {
-                            // Handle disconnect client command
-                            let target_id = args[0].to_string();
-                            let current_id = client.id.clone();
-
-                            let response = if current_id == target_id {
-                                "Cannot disconnect yourself. Use ~d to detach.\r\n".to_string()
-                            } else {
-                                // Mark for disconnection after loop completes
-                                pending_disconnects.push(target_id.clone());
-                                format!("Client {} will be disconnected\r\n", target_id)
+                        } else if cmd == "window_select" && client.shows_windows {
+                            let id = match args.first().map(String::as_str) {
+                                Some("next") => windows.step(client.window, true),
+                                Some("prev") => windows.step(client.window, false),
+                                Some(id) => id
+                                    .parse()
+                                    .ok()
+                                    .filter(|id| *id == 0 || windows.contains(*id))
+                                    .unwrap_or(client.window),
+                                None => client.window,
                             };
-
-                            let _ = client.stream.write_all(response.as_bytes());
-                            let _ = client.stream.flush();
-                            continue; // Don't forward to PTY
+                            if id != client.window {
+                                windows.show(client, id, screen);
+                            }
+                        } else if cmd == "panes" && client.shows_windows {
+                            let panes: Vec<_> = args
+                                .iter()
+                                .filter_map(|arg| parse_pane(arg))
+                                .filter(|(id, _)| *id == 0 || windows.contains(*id))
+                                .collect();
+                            // Drawn once the windows are sized to their panes
+                            client.unpainted = panes
+                                .iter()
+                                .map(|(id, _)| *id)
+                                .filter(|id| !client.panes.iter().any(|(shown, _)| shown == id))
+                                .collect();
+                            client.panes = panes;
+                        } else if cmd == "broadcast" {
+                            client.broadcast = !client.broadcast;
+                            let linked = Session::load(&self.session_id)
+                                .map(|session| {
+                                    session
+                                        .links
+                                        .iter()
+                                        .filter(|id| Session::load(id).is_ok())
+                                        .count()
+                                })
+                                .unwrap_or(0);
+                            let text = match (client.broadcast, linked) {
+                                (false, _) => "Broadcast off".to_string(),
+                                (true, 0) => {
+                                    "Broadcast on: no linked sessions yet (nds link)".to_string()
+                                }
+                                (true, 1) => "Broadcasting input to 1 linked session".to_string(),
+                                (true, n) => format!("Broadcasting input to {} linked sessions", n),
+                            };
+                            requests.message =
+                                Some(StatusMessage::new(&text, BROADCAST_MESSAGE_DURATION));
+                        } else if cmd == "detach_others" {
+                            // Exclusive attach: drop everyone except this client
+                            exclusive_requesters.push(client.id.clone());
+                        } else if matches!(
+                            cmd.as_str(),
+                            "wait"
+                                | "clients"
+                                | "disconnect_client"
+                                | "resize_policy"
+                                | "scrollback"
+                                | "scrollback_search"
+                                | "scrollback_prompt"
+                                | "paste_buffer"
+                                | "paste"
+                                | "input"
+                                | "capture_screen"
+                                | "record"
+                                | "record_stop"
+                                | "debug_dump"
+                                | "metrics"
+                                | "share"
+                                | "windows"
+                                | "window_kill"
+                        ) {
+                            // Normally caught on accept; the connection isn't
+                            // a client after all, so answer it once it's dropped
+                            if let Ok(stream) = client.stream.try_clone() {
+                                late_control.push((
+                                    ControlConn::new(stream, client.framed),
+                                    cmd,
+                                    args,
+                                ));
+                                disconnected_indices.push(i);
+                            }
+                        } else if cmd == "restart" {
+                            requests.restart = true;
+                        } else if cmd == "detach" {
+                            requests.detach = true;
+                        } else if cmd == "refresh" && windows.contains(client.window) {
+                            windows.show(client, client.window, screen);
+                        } else if cmd == "refresh" {
+                            // Client is back from a local overlay (switcher, scrollback)
+                            let strategy = self.redraw_strategy(&self.session_id);
+                            let _ = apply_redraw(strategy, client, screen, io_handler);
+                            client.invalidate_view();
                         }
                     }
-
-                    // Normal data - forward to PTY
-                    // Ignore write errors to prevent session death from transient issues
-                    if let Err(e) = io_handler.write_to_pty(data) {
-                        eprintln!("Warning: Failed to write to PTY: {}", e);
-                        // Don't propagate the error, just log it
-                    }
                 }
                 Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                     // No data available
@@ -1304,26 +3650,192 @@ impl PtyProcess {
             }
         }
 
-        // Handle pending disconnects from disconnect_client commands
-        for target_id in pending_disconnects {
-            if let Some(idx) = active_clients.iter().position(|c| c.id == target_id) {
-                // Notify the target client
-                let _ = active_clients[idx]
-                    .stream
-                    .write_all(b"\r\n[You have been disconnected by another client]\r\n");
-                let _ = active_clients[idx].stream.flush();
-                let _ = active_clients[idx]
-                    .stream
-                    .shutdown(std::net::Shutdown::Both);
+        // Raise a warning and an audit entry once per flooding client
+        if !flagged_clients.is_empty() {
+            let session = Session::load(&self.session_id).ok();
+            for (client_id, bytes_per_minute) in flagged_clients {
+                eprintln!(
+                    "Warning: client {} is sending {} bytes/min of input{}",
+                    client_id,
+                    bytes_per_minute,
+                    if rate_policy.throttle {
+                        ", throttling"
+                    } else {
+                        ""
+                    }
+                );
+                if let Some(ref session) = session {
+                    let _ =
+                        SessionHistory::record_input_flagged(session, &client_id, bytes_per_minute);
+                }
+            }
+        }
+
+        // Handle detach_others requests (exclusive attach). The most recent
+        // requester wins if several clients race for exclusivity.
+        if let Some(keep_id) = exclusive_requesters.last() {
+            for (idx, other) in active_clients.iter_mut().enumerate() {
+                if &other.id == keep_id || disconnected_indices.contains(&idx) {
+                    continue;
+                }
+                let _ = other.write_all(b"\r\n[Detached: session was attached elsewhere]\r\n");
+                let _ = other.flush();
+                let _ = other.stream.shutdown(std::net::Shutdown::Both);
                 disconnected_indices.push(idx);
             }
         }
 
         // Handle disconnections
         if !disconnected_indices.is_empty() {
-            self.handle_client_disconnections(active_clients, disconnected_indices, session_id)?;
+            disconnected_indices.sort_unstable();
+            disconnected_indices.dedup();
+            self.handle_client_disconnections(active_clients, disconnected_indices)?;
         }
 
+        for (stream, cmd, args) in late_control {
+            self.answer_control(
+                stream,
+                &cmd,
+                &args,
+                None,
+                active_clients,
+                watchers,
+                &mut requests,
+            )?;
+        }
+
+        Ok(requests)
+    }
+
+    /// Act on a one-shot control connection (`nds wait`, `nds restart`,
+    /// `nds detach`, `nds clients`). Dropping the stream once answered tells
+    /// the caller the request was taken. `data` is what a framed client sent
+    /// after `input` or `paste_buffer`; old clients put it in the arguments.
+    #[allow(clippy::too_many_arguments)]
+    fn answer_control(
+        &self,
+        mut conn: ControlConn,
+        cmd: &str,
+        args: &[String],
+        data: Option<Vec<u8>>,
+        active_clients: &mut Vec<ClientInfo>,
+        watchers: &mut Watchers,
+        requests: &mut ClientRequests,
+    ) -> Result<()> {
+        match (cmd, args) {
+            ("wait", _) => watchers.add_waiter(conn),
+            ("subscribe", events) => watchers.subscribe(conn, events),
+            ("restart", _) => requests.restart = true,
+            ("detach", _) => requests.detach = true,
+            ("message", [seconds, text @ ..]) => {
+                let duration = seconds
+                    .parse::<f64>()
+                    .ok()
+                    .and_then(|s| Duration::try_from_secs_f64(s).ok())
+                    .unwrap_or(DEFAULT_MESSAGE_DURATION);
+                requests.message = Some(StatusMessage::new(&conn.rest_of_args(text), duration));
+                let _ = conn.reply("message", &active_clients.len().to_string());
+            }
+            ("scrollback", [from, count]) => {
+                let (Ok(from), Ok(count)) = (from.parse(), count.parse()) else {
+                    return Ok(());
+                };
+                let request = ScrollbackRequest::Page { from, count };
+                requests.scrollback.push((conn, request));
+            }
+            ("scrollback_search", [from, direction, pattern @ ..]) => {
+                let Ok(from) = from.parse() else {
+                    return Ok(());
+                };
+                let request = ScrollbackRequest::Search {
+                    pattern: conn.rest_of_args(pattern),
+                    from,
+                    forward: direction != "backward",
+                };
+                requests.scrollback.push((conn, request));
+            }
+            ("scrollback_prompt", [from, direction]) => {
+                let Ok(from) = from.parse() else {
+                    return Ok(());
+                };
+                let forward = direction != "backward";
+                let request = ScrollbackRequest::Prompt { from, forward };
+                requests.scrollback.push((conn, request));
+            }
+            ("paste_buffer", text) => {
+                requests.paste_buffer = Some(match data {
+                    Some(data) => String::from_utf8_lossy(&data).into_owned(),
+                    None => conn.rest_of_args(text),
+                });
+            }
+            ("paste", _) => requests.paste.push(conn),
+            ("input", text) => {
+                let data = data.unwrap_or_else(|| conn.rest_of_args(text).into_bytes());
+                requests.input.push((conn, data));
+            }
+            ("capture_screen", _) => requests.capture_screen.push(conn),
+            ("record", [max_bytes, path @ ..]) => {
+                let Ok(max_bytes) = max_bytes.parse() else {
+                    return Ok(());
+                };
+                let path = PathBuf::from(conn.rest_of_args(path));
+                requests
+                    .record
+                    .push((conn, RecordRequest::Start { path, max_bytes }));
+            }
+            ("record_stop", _) => requests.record.push((conn, RecordRequest::Stop)),
+            ("debug_dump", _) => requests.debug_dump.push(conn),
+            ("metrics", _) => requests.metrics.push(conn),
+            ("share", _) => requests.share.push(conn),
+            ("windows", _) => requests.windows.push((conn, WindowRequest::List)),
+            ("window_new", args) => {
+                let (name, command) = parse_window_new(args);
+                let request = WindowRequest::New { name, command };
+                requests.windows.push((conn, request));
+            }
+            ("window_kill", [id]) => {
+                let Ok(id) = id.parse() else {
+                    return Ok(());
+                };
+                requests.windows.push((conn, WindowRequest::Close(id)));
+            }
+            ("setenv", assignments) => {
+                apply_setenv(&self.session_id, assignments)?;
+                let _ = conn.reply("setenv", "");
+            }
+            ("clients", _) => {
+                let summaries: Vec<_> = active_clients.iter().map(|c| c.summary()).collect();
+                let _ = conn.reply("clients", &serde_json::to_string(&summaries)?);
+            }
+            ("resize_policy", [policy]) => {
+                // The CLI validated the policy; an unparsable one changes nothing
+                let mut size = None;
+                if let Ok(policy) = policy.parse::<ResizePolicy>() {
+                    Session::set_resize_policy(&self.session_id, policy)?;
+                    size = self.apply_resize_policy(active_clients)?;
+                }
+                let size = size
+                    .map(|(cols, rows)| format!("{}x{}", cols, rows))
+                    .unwrap_or_default();
+                let _ = conn.reply("resize_policy", &size);
+            }
+            ("disconnect_client", [target_id]) => {
+                let found = self.kick_client(active_clients, target_id)?;
+                let _ = conn.reply("disconnect_client", &found.to_string());
+            }
+            ("signal", [name]) => {
+                let sent = name
+                    .parse::<Signal>()
+                    .map_err(|_| format!("unknown signal '{}'", name))
+                    .and_then(|signal| self.signal_foreground(signal));
+                let _ = conn.reply("signal", &serde_json::to_string(&sent)?);
+            }
+            ("foreground", _) => {
+                let process = self.foreground();
+                let _ = conn.reply("foreground", &serde_json::to_string(&process)?);
+            }
+            _ => {}
+        }
         Ok(())
     }
 
@@ -1380,9 +3892,8 @@ impl PtyProcess {
 
             // Notify the target client before disconnecting
             let _ = clients[target_index]
-                .stream
                 .write_all(b"\r\n[You have been disconnected by another client]\r\n");
-            let _ = clients[target_index].stream.flush();
+            let _ = clients[target_index].flush();
             let _ = clients[target_index]
                 .stream
                 .shutdown(std::net::Shutdown::Both);
@@ -1393,6 +3904,33 @@ impl PtyProcess {
         }
     }
 
+    /// Connect to a session's daemon and exchange hellos. `Attach` connections
+    /// then carry input and output in `Frame::Data`; `Control` connections
+    /// send one command. Daemons from before framing, which would take a
+    /// hello for keyboard input, get the in-band protocol instead.
+    pub fn connect(session: &Session, role: Role) -> Result<Connection> {
+        Self::connect_offering(session, role, &[])
+    }
+
+    /// Connect like `connect`, telling the daemon which optional
+    /// capabilities this client has (e.g. answering attach requests)
+    pub fn connect_offering(
+        session: &Session,
+        role: Role,
+        capabilities: &[&str],
+    ) -> Result<Connection> {
+        let socket = session.connect_socket()?;
+        if session.protocol == 0 {
+            return Ok(Connection::legacy(socket));
+        }
+        Connection::handshake_offering(socket, role, capabilities).map_err(|e| {
+            NdsError::SocketError(format!(
+                "Handshake with session {} failed: {}",
+                session.id, e
+            ))
+        })
+    }
+
     /// Kill a session by its ID
     pub fn kill_session(session_id: &str) -> Result<()> {
         let session = Session::load(session_id)?;
@@ -1416,6 +3954,369 @@ impl PtyProcess {
 
         Ok(())
     }
+
+    /// Ask the session's daemon to kill and re-exec its shell or command
+    pub fn restart_shell(session: &Session) -> Result<()> {
+        let mut socket = Self::connect(session, Role::Control)?;
+        require_capability(&socket, session, "restart")?;
+        send_restart_command(&socket)?;
+
+        // The daemon hangs up once it has taken the request
+        let mut buf = [0u8; 64];
+        while socket.read(&mut buf)? > 0 {}
+        Ok(())
+    }
+
+    /// Ask the session's daemon which clients are attached
+    pub fn list_clients(session: &Session) -> Result<Vec<ClientSummary>> {
+        let socket = Self::connect(session, Role::Control)?;
+        socket
+            .stream()
+            .set_read_timeout(Some(CONTROL_REPLY_TIMEOUT))?;
+        require_capability(&socket, session, "clients")?;
+        send_clients_command(&socket)?;
+        let reply = read_control_reply(&socket, "clients")?;
+        Ok(serde_json::from_str(&reply)?)
+    }
+
+    /// Ask the session's daemon to disconnect one client. Returns whether
+    /// the client was attached.
+    pub fn disconnect_client(session: &Session, client_id: &str) -> Result<bool> {
+        let socket = Self::connect(session, Role::Control)?;
+        socket
+            .stream()
+            .set_read_timeout(Some(CONTROL_REPLY_TIMEOUT))?;
+        require_capability(&socket, session, "disconnect_client")?;
+        send_disconnect_client_command(&socket, client_id)?;
+        let reply = read_control_reply(&socket, "disconnect_client")?;
+        Ok(serde_json::from_str(&reply)?)
+    }
+
+    /// Set variables for the session's daemon to start the shell with from
+    /// now on, if it is restarted or respawns; an empty value unsets one
+    pub fn set_client_env(session: &Session, vars: &BTreeMap<String, String>) -> Result<()> {
+        let socket = Self::connect(session, Role::Control)?;
+        socket
+            .stream()
+            .set_read_timeout(Some(CONTROL_REPLY_TIMEOUT))?;
+        require_capability(&socket, session, "setenv")?;
+        send_setenv_command(&socket, vars)?;
+        read_control_reply(&socket, "setenv")?;
+        Ok(())
+    }
+
+    /// Ask the session's daemon to size the PTY by `policy` from now on.
+    /// Returns the PTY's new size, if the policy changed it.
+    pub fn set_resize_policy(
+        session: &Session,
+        policy: ResizePolicy,
+    ) -> Result<Option<(u16, u16)>> {
+        let socket = Self::connect(session, Role::Control)?;
+        socket
+            .stream()
+            .set_read_timeout(Some(CONTROL_REPLY_TIMEOUT))?;
+        require_capability(&socket, session, "resize_policy")?;
+        send_resize_policy_command(&socket, policy)?;
+        let reply = read_control_reply(&socket, "resize_policy")?;
+        Ok(reply
+            .split_once('x')
+            .and_then(|(cols, rows)| Some((cols.parse().ok()?, rows.parse().ok()?))))
+    }
+
+    /// Ask the session's daemon to send `signal` to the process group in the
+    /// foreground of its PTY. Returns the group's ID.
+    pub fn signal_foreground_process(session: &Session, signal: Signal) -> Result<i32> {
+        let socket = Self::connect(session, Role::Control)?;
+        socket
+            .stream()
+            .set_read_timeout(Some(CONTROL_REPLY_TIMEOUT))?;
+        require_capability(&socket, session, "signal")?;
+        send_signal_command(&socket, signal)?;
+        let reply = read_control_reply(&socket, "signal")?;
+        let sent: std::result::Result<i32, String> = serde_json::from_str(&reply)?;
+        sent.map_err(NdsError::SignalError)
+    }
+
+    /// Show `text` over the bottom row of the session's attached clients for
+    /// `duration`, or a few seconds. Returns how many clients it was shown to.
+    pub fn show_message(
+        session: &Session,
+        text: &str,
+        duration: Option<Duration>,
+    ) -> Result<usize> {
+        let socket = Self::connect(session, Role::Control)?;
+        socket
+            .stream()
+            .set_read_timeout(Some(CONTROL_REPLY_TIMEOUT))?;
+        require_capability(&socket, session, "message")?;
+        send_message_command(&socket, text, duration)?;
+        let reply = read_control_reply(&socket, "message")?;
+        reply
+            .parse()
+            .map_err(|_| NdsError::SocketError(format!("unexpected reply '{}'", reply)))
+    }
+
+    /// Ask the session's daemon for up to `count` lines of scrollback from
+    /// line `from` on
+    pub fn scrollback_page(session: &Session, from: u64, count: usize) -> Result<ScrollbackPage> {
+        let socket = Self::connect(session, Role::Control)?;
+        socket
+            .stream()
+            .set_read_timeout(Some(CONTROL_REPLY_TIMEOUT))?;
+        require_capability(&socket, session, "scrollback")?;
+        send_scrollback_command(&socket, from, count)?;
+        let reply = read_control_reply(&socket, "scrollback")?;
+        Ok(serde_json::from_str(&reply)?)
+    }
+
+    /// Ask the session's daemon for the first line from `from` on (or back,
+    /// unless `forward`) that matches the regex `pattern`
+    pub fn scrollback_search(
+        session: &Session,
+        pattern: &str,
+        from: u64,
+        forward: bool,
+    ) -> Result<Option<u64>> {
+        let socket = Self::connect(session, Role::Control)?;
+        // Searching a lot of scrollback takes a while
+        socket
+            .stream()
+            .set_read_timeout(Some(SCROLLBACK_SEARCH_TIMEOUT))?;
+        require_capability(&socket, session, "scrollback_search")?;
+        send_scrollback_search_command(&socket, pattern, from, forward)?;
+        let reply = read_control_reply(&socket, "scrollback_search")?;
+        Ok(serde_json::from_str(&reply)?)
+    }
+
+    /// Ask the session's daemon for the first line from `from` on (or back,
+    /// unless `forward`) that a prompt started on
+    pub fn scrollback_prompt(session: &Session, from: u64, forward: bool) -> Result<Option<u64>> {
+        let socket = Self::connect(session, Role::Control)?;
+        socket
+            .stream()
+            .set_read_timeout(Some(CONTROL_REPLY_TIMEOUT))?;
+        require_capability(&socket, session, "scrollback_prompt")?;
+        send_scrollback_prompt_command(&socket, from, forward)?;
+        let reply = read_control_reply(&socket, "scrollback_prompt")?;
+        Ok(serde_json::from_str(&reply)?)
+    }
+
+    /// Write the session's scrollback to `out`, a line at a time, without
+    /// escape sequences if `strip_ansi`. Returns how many lines were written.
+    pub fn capture_scrollback(
+        session: &Session,
+        out: &mut dyn Write,
+        strip_ansi: bool,
+    ) -> Result<u64> {
+        write_scrollback(&mut DaemonScrollback { session }, out, strip_ansi)
+    }
+
+    /// The text on the session's screen, a row at a time, without the blank
+    /// rows at the bottom
+    pub fn capture_screen(session: &Session) -> Result<Vec<String>> {
+        let socket = Self::connect(session, Role::Control)?;
+        socket
+            .stream()
+            .set_read_timeout(Some(CONTROL_REPLY_TIMEOUT))?;
+        require_capability(&socket, session, "capture_screen")?;
+        send_capture_screen_command(&socket)?;
+        let reply = read_control_reply(&socket, "capture_screen")?;
+        Ok(serde_json::from_str(&reply)?)
+    }
+
+    /// Ask the session's daemon for a snapshot of its internal state
+    pub fn debug_dump(session: &Session) -> Result<DaemonDump> {
+        let socket = Self::connect(session, Role::Control)?;
+        socket
+            .stream()
+            .set_read_timeout(Some(CONTROL_REPLY_TIMEOUT))?;
+        require_capability(&socket, session, "debug_dump")?;
+        send_debug_dump_command(&socket)?;
+        let reply = read_control_reply(&socket, "debug_dump")?;
+        Ok(serde_json::from_str(&reply)?)
+    }
+
+    /// Ask the session's daemon for its counters and gauges
+    pub fn metrics(session: &Session) -> Result<SessionMetrics> {
+        let socket = Self::connect(session, Role::Control)?;
+        socket
+            .stream()
+            .set_read_timeout(Some(CONTROL_REPLY_TIMEOUT))?;
+        require_capability(&socket, session, "metrics")?;
+        send_metrics_command(&socket)?;
+        let reply = read_control_reply(&socket, "metrics")?;
+        Ok(serde_json::from_str(&reply)?)
+    }
+
+    /// Tell the session's daemon who it is shared with has changed. Returns
+    /// the socket those users connect to, or `None` once nobody is left.
+    pub fn share(session: &Session) -> Result<Option<PathBuf>> {
+        let socket = Self::connect(session, Role::Control)?;
+        socket
+            .stream()
+            .set_read_timeout(Some(CONTROL_REPLY_TIMEOUT))?;
+        require_capability(&socket, session, "share")?;
+        send_share_command(&socket)?;
+        let reply = read_control_reply(&socket, "share")?;
+        let path: std::result::Result<Option<PathBuf>, String> = serde_json::from_str(&reply)?;
+        path.map_err(NdsError::SocketError)
+    }
+
+    /// Keep `text` in the session's paste buffer for `nds paste`
+    pub fn set_paste_buffer(session: &Session, text: &str) -> Result<()> {
+        let socket = Self::connect(session, Role::Control)?;
+        require_capability(&socket, session, "paste_buffer")?;
+        send_paste_buffer_command(&socket, text)?;
+        Ok(())
+    }
+
+    /// Type `data` into the session's shell as if from the keyboard. Returns
+    /// how many bytes went in, or `None` if the shell has exited.
+    pub fn send_input(session: &Session, data: &[u8]) -> Result<Option<usize>> {
+        let socket = Self::connect(session, Role::Control)?;
+        socket
+            .stream()
+            .set_read_timeout(Some(CONTROL_REPLY_TIMEOUT))?;
+        require_capability(&socket, session, "input")?;
+        send_input_command(&socket, data)?;
+        let reply = read_control_reply(&socket, "input")?;
+        Ok(serde_json::from_str(&reply)?)
+    }
+
+    /// Ask the session's daemon to type its paste buffer into the shell.
+    /// Returns how many bytes went in, or `None` if the buffer is empty.
+    pub fn paste(session: &Session) -> Result<Option<usize>> {
+        let socket = Self::connect(session, Role::Control)?;
+        socket
+            .stream()
+            .set_read_timeout(Some(CONTROL_REPLY_TIMEOUT))?;
+        require_capability(&socket, session, "paste")?;
+        send_paste_command(&socket)?;
+        let reply = read_control_reply(&socket, "paste")?;
+        Ok(serde_json::from_str(&reply)?)
+    }
+
+    /// Have the session's daemon record its output to `path`, an absolute
+    /// path, in asciicast v2 format, starting a new file each time one
+    /// reaches `max_bytes` (0 for never)
+    pub fn start_recording(session: &Session, path: &Path, max_bytes: u64) -> Result<()> {
+        let socket = Self::connect(session, Role::Control)?;
+        socket
+            .stream()
+            .set_read_timeout(Some(CONTROL_REPLY_TIMEOUT))?;
+        require_capability(&socket, session, "record")?;
+        send_record_command(&socket, path, max_bytes)?;
+        let reply = read_control_reply(&socket, "record")?;
+        let started: std::result::Result<(), String> = serde_json::from_str(&reply)?;
+        started.map_err(NdsError::RecordingError)
+    }
+
+    /// Have the session's daemon stop recording. Returns what it recorded,
+    /// or `None` if it wasn't recording.
+    pub fn stop_recording(session: &Session) -> Result<Option<RecordingSummary>> {
+        let socket = Self::connect(session, Role::Control)?;
+        socket
+            .stream()
+            .set_read_timeout(Some(CONTROL_REPLY_TIMEOUT))?;
+        require_capability(&socket, session, "record_stop")?;
+        send_record_stop_command(&socket)?;
+        let reply = read_control_reply(&socket, "record_stop")?;
+        Ok(serde_json::from_str(&reply)?)
+    }
+
+    /// Ask the session's daemon what is in the foreground of its PTY
+    pub fn foreground_process(session: &Session) -> Result<Option<ForegroundProcess>> {
+        let socket = Self::connect(session, Role::Control)?;
+        socket
+            .stream()
+            .set_read_timeout(Some(CONTROL_REPLY_TIMEOUT))?;
+        require_capability(&socket, session, "foreground")?;
+        send_foreground_command(&socket)?;
+        let reply = read_control_reply(&socket, "foreground")?;
+        Ok(serde_json::from_str(&reply)?)
+    }
+
+    /// Ask the session's daemon about its windows, window 0 first
+    pub fn list_windows(session: &Session) -> Result<Vec<WindowSummary>> {
+        let socket = Self::connect(session, Role::Control)?;
+        socket
+            .stream()
+            .set_read_timeout(Some(CONTROL_REPLY_TIMEOUT))?;
+        require_capability(&socket, session, "windows")?;
+        send_windows_command(&socket)?;
+        let reply = read_control_reply(&socket, "windows")?;
+        Ok(serde_json::from_str(&reply)?)
+    }
+
+    /// Ask the session's daemon to open a window running `command`, or a
+    /// shell. Returns the window's number.
+    pub fn open_window(session: &Session, name: Option<&str>, command: &[String]) -> Result<u32> {
+        let socket = Self::connect(session, Role::Control)?;
+        socket
+            .stream()
+            .set_read_timeout(Some(CONTROL_REPLY_TIMEOUT))?;
+        require_capability(&socket, session, "window_new")?;
+        send_window_new_command(&socket, name, command)?;
+        let reply = read_control_reply(&socket, "window_new")?;
+        let opened: std::result::Result<u32, String> = serde_json::from_str(&reply)?;
+        opened.map_err(NdsError::PtyError)
+    }
+
+    /// Ask the session's daemon to close a window, hanging up its shell.
+    /// Returns whether there was such a window.
+    pub fn close_window(session: &Session, window: u32) -> Result<bool> {
+        let socket = Self::connect(session, Role::Control)?;
+        socket
+            .stream()
+            .set_read_timeout(Some(CONTROL_REPLY_TIMEOUT))?;
+        require_capability(&socket, session, "window_kill")?;
+        send_window_kill_command(&socket, window)?;
+        let reply = read_control_reply(&socket, "window_kill")?;
+        Ok(serde_json::from_str(&reply)?)
+    }
+
+    /// Ask the session's daemon to detach all of its clients
+    pub fn detach_clients(session: &Session) -> Result<()> {
+        let mut socket = Self::connect(session, Role::Control)?;
+        require_capability(&socket, session, "detach")?;
+        send_detach_command(&socket)?;
+
+        // The daemon hangs up once it has taken the request
+        let mut buf = [0u8; 64];
+        while socket.read(&mut buf)? > 0 {}
+        Ok(())
+    }
+
+    /// Hear about `events` (e.g. `output`) in the session as they happen,
+    /// and how its shell or command exits
+    pub fn subscribe(session: &Session, events: &[&str]) -> Result<EventSubscription> {
+        let socket = Self::connect(session, Role::Control)?;
+        require_capability(&socket, session, "subscribe")?;
+        send_subscribe_command(&socket, events)?;
+        Ok(EventSubscription::new(socket))
+    }
+
+    /// Block until the session's shell or command exits and return how it ended
+    pub fn wait_for_exit(session: &Session) -> Result<ExitStatus> {
+        let socket = Self::connect(session, Role::Control)?;
+        socket.stream().set_read_timeout(None)?;
+        require_capability(&socket, session, "wait")?;
+        send_wait_command(&socket)?;
+
+        let mut decoder = socket.decoder();
+        loop {
+            match socket.read_frame(&mut decoder)? {
+                Some(Frame::Exited(status)) => return Ok(status),
+                Some(_) => continue,
+                None => {
+                    return Err(NdsError::ProcessError(format!(
+                        "session {} ended without reporting an exit status",
+                        session.id
+                    )))
+                }
+            }
+        }
+    }
 }
 
 /// Static function to format client list without borrowing
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use nix::unistd::Pid;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+use super::spawn::{ClientWatch, MasterWatch, PtyProcess};
+use crate::error::Result;
+
+/// Set to `0` to run the blocking daemon loop in builds with the `async` feature
+pub const ASYNC_DAEMON_ENV: &str = "NDS_ASYNC_DAEMON";
+
+pub fn async_daemon_enabled() -> bool {
+    std::env::var(ASYNC_DAEMON_ENV)
+        .map(|v| v != "0")
+        .unwrap_or(true)
+}
+
+/// A session daemon driven by tokio: the same work as
+/// `PtyProcess::run_detached`, but waiting on the PTY, listener and clients
+/// with `tokio::select!` instead of `poll`
+pub struct AsyncPtyProcess {
+    inner: PtyProcess,
+}
+
+impl AsyncPtyProcess {
+    pub fn new(inner: PtyProcess) -> Self {
+        Self { inner }
+    }
+
+    /// Run the daemon to completion on a single-threaded runtime
+    pub fn run_detached(self) -> Result<()> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(self.run())
+    }
+
+    async fn run(mut self) -> Result<()> {
+        let process = &mut self.inner;
+        let mut daemon = process.start_daemon()?;
+
+        let listener = AsyncFd::with_interest(daemon.listener_fd(), Interest::READABLE)?;
+        let wakeup = AsyncFd::with_interest(daemon.wakeup_fd(), Interest::READABLE)?;
+        // Keyed by the child too: a re-opened PTY can reuse the old fd number
+        let mut master: Option<((RawFd, Pid), AsyncFd<RawFd>)> = None;
+        let mut windows: HashMap<(RawFd, Pid), AsyncFd<RawFd>> = HashMap::new();
+        let mut clients: HashMap<String, AsyncFd<RawFd>> = HashMap::new();
+        // Opened and closed by `nds share`
+        let mut shared: Option<AsyncFd<RawFd>> = None;
+
+        while daemon.is_running() {
+            process.daemon_iteration(&mut daemon)?;
+
+            let watched = daemon.watched_master(process);
+            let key = watched.map(|watch| (watch.fd, process.pid));
+            if master.as_ref().map(|(k, _)| *k) != key {
+                // Deregister the old master before registering the new one
+                master = None;
+                if let Some(key) = key {
+                    let fd =
+                        AsyncFd::with_interest(key.0, Interest::READABLE | Interest::WRITABLE)?;
+                    master = Some((key, fd));
+                }
+            }
+
+            let window_watches = daemon.window_watches();
+            let window_keys: Vec<(RawFd, Pid)> = window_watches
+                .iter()
+                .map(|(pid, watch)| (watch.fd, *pid))
+                .collect();
+            windows.retain(|key, _| window_keys.contains(key));
+            for key in &window_keys {
+                if !windows.contains_key(key) {
+                    let fd =
+                        AsyncFd::with_interest(key.0, Interest::READABLE | Interest::WRITABLE)?;
+                    windows.insert(*key, fd);
+                }
+            }
+
+            let shared_fd = daemon.shared_listener_fd();
+            if shared.as_ref().map(|fd| *fd.get_ref()) != shared_fd {
+                shared = None;
+                if let Some(fd) = shared_fd {
+                    shared = Some(AsyncFd::with_interest(fd, Interest::READABLE)?);
+                }
+            }
+
+            let (watches, deadline) = daemon.client_watches();
+            clients.retain(|id, _| watches.iter().any(|w| &w.id == id));
+            for watch in &watches {
+                if !clients.contains_key(&watch.id) {
+                    let fd =
+                        AsyncFd::with_interest(watch.fd, Interest::READABLE | Interest::WRITABLE)?;
+                    clients.insert(watch.id.clone(), fd);
+                }
+            }
+
+            // Readiness is edge-triggered and the iteration may have left
+            // data unread, so check what is ready right now first
+            if daemon.wait_for_activity(watched, Instant::now()) {
+                continue;
+            }
+
+            let master_ready = async {
+                match (&master, watched) {
+                    (Some((_, fd)), Some(watch)) => master_ready(fd, watch).await,
+                    _ => std::future::pending().await,
+                }
+            };
+            let shared_ready = async {
+                match &shared {
+                    Some(fd) => fd.readable().await.map(|mut guard| guard.clear_ready()),
+                    None => std::future::pending().await,
+                }
+            };
+            let sleep = tokio::time::sleep_until(deadline.into());
+            tokio::select! {
+                guard = listener.readable() => guard?.clear_ready(),
+                guard = wakeup.readable() => guard?.clear_ready(),
+                ready = shared_ready => ready?,
+                ready = master_ready => ready?,
+                ready = windows_ready(&windows, &window_watches) => ready?,
+                ready = client_ready(&clients, &watches) => ready?,
+                _ = sleep => {}
+            }
+        }
+
+        daemon.finish();
+        Ok(())
+    }
+}
+
+/// Resolves once the PTY has output waiting, or room for queued input
+async fn master_ready(fd: &AsyncFd<RawFd>, watch: MasterWatch) -> io::Result<()> {
+    std::future::poll_fn(|cx| poll_master(fd, watch, cx)).await
+}
+
+/// Resolves once another window's PTY has output waiting, or room for
+/// queued input
+async fn windows_ready(
+    windows: &HashMap<(RawFd, Pid), AsyncFd<RawFd>>,
+    watches: &[(Pid, MasterWatch)],
+) -> io::Result<()> {
+    std::future::poll_fn(|cx| {
+        for (pid, watch) in watches {
+            let Some(fd) = windows.get(&(watch.fd, *pid)) else {
+                continue;
+            };
+            if let Poll::Ready(ready) = poll_master(fd, *watch, cx) {
+                return Poll::Ready(ready);
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+fn poll_master(
+    fd: &AsyncFd<RawFd>,
+    watch: MasterWatch,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<()>> {
+    if watch.read {
+        if let Poll::Ready(guard) = fd.poll_read_ready(cx) {
+            guard?.clear_ready();
+            return Poll::Ready(Ok(()));
+        }
+    }
+    if watch.write {
+        if let Poll::Ready(guard) = fd.poll_write_ready(cx) {
+            guard?.clear_ready();
+            return Poll::Ready(Ok(()));
+        }
+    }
+    Poll::Pending
+}
+
+/// Resolves once a client has input waiting, or room for its queued output
+async fn client_ready(
+    clients: &HashMap<String, AsyncFd<RawFd>>,
+    watches: &[ClientWatch],
+) -> io::Result<()> {
+    std::future::poll_fn(|cx| {
+        for watch in watches {
+            let Some(fd) = clients.get(&watch.id) else {
+                continue;
+            };
+            if watch.read {
+                if let Poll::Ready(guard) = fd.poll_read_ready(cx) {
+                    guard?.clear_ready();
+                    return Poll::Ready(Ok(()));
+                }
+            }
+            if watch.write {
+                if let Poll::Ready(guard) = fd.poll_write_ready(cx) {
+                    guard?.clear_ready();
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
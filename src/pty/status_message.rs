@@ -0,0 +1,61 @@
+use std::time::{Duration, Instant};
+
+use unicode_width::UnicodeWidthChar;
+
+/// How long `nds message` shows a message for when no duration is given
+pub const DEFAULT_MESSAGE_DURATION: Duration = Duration::from_secs(3);
+
+/// A short-lived message drawn over the bottom row of attached clients, like
+/// tmux's display-message. The daemon puts the row back from the screen once
+/// the message expires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusMessage {
+    text: String,
+    until: Instant,
+}
+
+impl StatusMessage {
+    /// A message shown for `duration` from now. Control characters become
+    /// spaces so the message can't move the cursor or change modes.
+    pub fn new(text: &str, duration: Duration) -> Self {
+        let text = text
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect();
+        Self {
+            text,
+            until: Instant::now() + duration,
+        }
+    }
+
+    /// When the message goes away
+    pub fn until(&self) -> Instant {
+        self.until
+    }
+
+    pub fn expired(&self, now: Instant) -> bool {
+        now >= self.until
+    }
+
+    /// Bytes that draw the message in reverse video across the bottom row of
+    /// a `cols`x`rows` terminal, cut to fit. The cursor is left on that row
+    /// for the caller to put back.
+    pub fn render(&self, cols: u16, rows: u16) -> Vec<u8> {
+        let mut out = format!("\x1b[{};1H\x1b[0;7m", rows.max(1));
+        let mut width = 0;
+        for ch in self.text.chars() {
+            let w = ch.width().unwrap_or(0);
+            if width + w > cols as usize {
+                break;
+            }
+            out.push(ch);
+            width += w;
+        }
+        out.extend(std::iter::repeat_n(
+            ' ',
+            (cols as usize).saturating_sub(width),
+        ));
+        out.push_str("\x1b[0m");
+        out.into_bytes()
+    }
+}
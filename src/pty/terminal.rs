@@ -1,13 +1,16 @@
 use std::io::{self, Write};
 use std::os::unix::io::{BorrowedFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::time::Duration;
 
 use crossterm::terminal;
+use nix::sys::signal::{kill, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
 use nix::sys::termios::{tcflush, tcgetattr, tcsetattr, FlushArg, SetArg, Termios};
 use nix::sys::termios::{
     ControlFlags, InputFlags, LocalFlags, OutputFlags, SpecialCharacterIndices,
 };
+use nix::unistd::Pid;
 
 use crate::error::{NdsError, Result};
 use crate::terminal_state::TerminalState;
@@ -92,6 +95,19 @@ pub fn get_terminal_size() -> Result<(u16, u16)> {
     terminal::size().map_err(|e| NdsError::TerminalError(e.to_string()))
 }
 
+/// Get the size of the terminal on a file descriptor as (cols, rows)
+pub fn get_terminal_size_of(fd: RawFd) -> Result<(u16, u16)> {
+    unsafe {
+        let mut winsize: libc::winsize = std::mem::zeroed();
+        if libc::ioctl(fd, libc::TIOCGWINSZ, &mut winsize) < 0 {
+            return Err(NdsError::PtyError(
+                "Failed to get terminal size".to_string(),
+            ));
+        }
+        Ok((winsize.ws_col, winsize.ws_row))
+    }
+}
+
 /// Set terminal size on a file descriptor
 pub fn set_terminal_size(fd: RawFd, cols: u16, rows: u16) -> Result<()> {
     unsafe {
@@ -145,6 +161,7 @@ pub fn set_stdin_blocking(stdin_fd: RawFd) -> Result<()> {
 }
 
 /// Send a refresh command to the terminal
+#[allow(dead_code)]
 pub fn send_refresh(stream: &mut impl Write) -> io::Result<()> {
     // Send Ctrl+L to refresh the display
     stream.write_all(b"\x0c")?;
@@ -175,3 +192,53 @@ pub fn send_terminal_refresh_sequences(stream: &mut impl Write) -> io::Result<()
 pub fn capture_terminal_state(stdin_fd: RawFd) -> Result<TerminalState> {
     TerminalState::capture(stdin_fd)
 }
+
+/// Set by the SIGTSTP handler while attached; the input loop suspends the
+/// client properly instead of stopping with the terminal still raw
+static SUSPEND_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_suspend(_: libc::c_int) {
+    SUSPEND_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+fn set_suspend_handler(handler: SigHandler) -> Result<()> {
+    let action = SigAction::new(handler, SaFlags::SA_RESTART, SigSet::empty());
+    unsafe { sigaction(Signal::SIGTSTP, &action) }
+        .map(|_| ())
+        .map_err(|e| NdsError::SignalError(format!("Failed to handle SIGTSTP: {}", e)))
+}
+
+/// Catches SIGTSTP (say `kill -TSTP` from another shell) while a client is
+/// attached, and puts the default action back when dropped
+pub struct SuspendGuard(());
+
+impl SuspendGuard {
+    pub fn new() -> Result<Self> {
+        SUSPEND_REQUESTED.store(false, Ordering::SeqCst);
+        set_suspend_handler(SigHandler::Handler(on_suspend))?;
+        Ok(Self(()))
+    }
+}
+
+impl Drop for SuspendGuard {
+    fn drop(&mut self) {
+        let _ = set_suspend_handler(SigHandler::SigDfl);
+    }
+}
+
+/// Whether a SIGTSTP has arrived since the last call
+pub fn take_suspend_request() -> bool {
+    SUSPEND_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// Stop the process group the way Ctrl+Z in a shell would, returning once
+/// it is continued (`fg`). The caller restores the terminal first.
+pub fn suspend_self() -> Result<()> {
+    set_suspend_handler(SigHandler::SigDfl)?;
+    let stopped = kill(Pid::from_raw(0), Signal::SIGTSTP)
+        .map_err(|e| NdsError::SignalError(format!("Failed to suspend: {}", e)));
+    set_suspend_handler(SigHandler::Handler(on_suspend))?;
+    // The stop request that got us here has been acted on
+    SUSPEND_REQUESTED.store(false, Ordering::SeqCst);
+    stopped
+}
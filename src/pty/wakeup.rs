@@ -0,0 +1,117 @@
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+/// Write end of the pipe the SIGCHLD handler wakes the daemon through
+static CHILD_EXIT_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_child_exit(_: libc::c_int) {
+    let fd = CHILD_EXIT_FD.load(Ordering::Relaxed);
+    if fd >= 0 {
+        // Only async-signal-safe calls here; keep errno for the interrupted code
+        let saved = nix::errno::Errno::last_raw();
+        unsafe {
+            libc::write(fd, [1u8].as_ptr() as *const libc::c_void, 1);
+        }
+        nix::errno::Errno::set_raw(saved);
+    }
+}
+
+/// Self-pipe that interrupts the daemon's wait for socket and PTY activity
+/// when something other than a file descriptor needs attention: the child
+/// exiting, or a shutdown signal handled on another thread
+pub struct Wakeup {
+    read_fd: OwnedFd,
+    write_fd: Arc<OwnedFd>,
+}
+
+/// Handle for waking the daemon from another thread
+#[derive(Clone)]
+pub struct Waker {
+    write_fd: Arc<OwnedFd>,
+}
+
+impl Waker {
+    pub fn wake(&self) {
+        wake_fd(self.write_fd.as_raw_fd());
+    }
+}
+
+fn wake_fd(fd: RawFd) {
+    // A full pipe already has a wakeup pending, so errors don't matter
+    unsafe {
+        libc::write(fd, [1u8].as_ptr() as *const libc::c_void, 1);
+    }
+}
+
+impl Wakeup {
+    pub fn new() -> io::Result<Self> {
+        let (read_fd, write_fd) = nix::unistd::pipe()?;
+        for fd in [&read_fd, &write_fd] {
+            // Never block the daemon, and keep the pipe out of the shell
+            fcntl(fd.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+            fcntl(fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+        }
+        Ok(Self {
+            read_fd,
+            write_fd: Arc::new(write_fd),
+        })
+    }
+
+    pub fn waker(&self) -> Waker {
+        Waker {
+            write_fd: Arc::clone(&self.write_fd),
+        }
+    }
+
+    /// Wake up whenever a child process exits. Only one `Wakeup` per
+    /// process can do this; the last one to ask wins.
+    pub fn wake_on_child_exit(&self) -> io::Result<()> {
+        CHILD_EXIT_FD.store(self.write_fd.as_raw_fd(), Ordering::Relaxed);
+        let action = SigAction::new(
+            SigHandler::Handler(on_child_exit),
+            SaFlags::SA_RESTART | SaFlags::SA_NOCLDSTOP,
+            SigSet::empty(),
+        );
+        unsafe { sigaction(Signal::SIGCHLD, &action) }?;
+        Ok(())
+    }
+
+    /// What to poll for readability
+    pub fn fd(&self) -> BorrowedFd<'_> {
+        self.read_fd.as_fd()
+    }
+
+    /// Consume pending wakeups once they have been acted on
+    pub fn drain(&self) {
+        let mut buf = [0u8; 64];
+        loop {
+            let n = unsafe {
+                libc::read(
+                    self.read_fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            if n <= 0 {
+                break;
+            }
+        }
+    }
+}
+
+impl Drop for Wakeup {
+    fn drop(&mut self) {
+        // The handler must not write to a closed (or reused) descriptor
+        let _ = CHILD_EXIT_FD.compare_exchange(
+            self.write_fd.as_raw_fd(),
+            -1,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+}
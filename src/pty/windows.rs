@@ -0,0 +1,372 @@
+//! Several shells in one session, tmux-style.
+//!
+//! Window 0 is the session's own shell or command, with everything else the
+//! daemon keeps for it: scrollback, logs, recordings and respawning. `~c` and
+//! `nds window new` start another shell on a PTY of its own in the same
+//! daemon, sized like window 0's. Each attached client shows one window at a
+//! time (`~n`/`~p` cycle through them) and its input goes to that window.
+//! Output from a window goes only to the clients showing it, in
+//! `Frame::WindowData` frames that name the window; window 0's output stays
+//! in `Data` frames. A window goes away when its shell exits, and the clients
+//! showing it go back to window 0.
+//!
+//! Only clients that offer the `windows` capability are moved off window 0;
+//! older ones never see the others.
+//!
+//! A client can also show windows side by side in split panes (see
+//! `panes`). It tells the daemon each pane's window and size with the
+//! `panes` command, gets the output of all of them, and a window shown in
+//! panes is sized to the smallest of them rather than like window 0.
+
+use std::os::fd::BorrowedFd;
+use std::os::unix::io::RawFd;
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{close, Pid};
+use serde::{Deserialize, Serialize};
+
+use super::client::ClientInfo;
+use super::io_handler::{BoundarySplitter, PtyIoHandler};
+use super::process_info::command_line;
+use super::screen::Screen;
+use super::spawn::{MasterWatch, PtyProcess, SpawnOptions};
+use super::terminal::{get_terminal_size_of, set_terminal_size};
+use crate::error::Result;
+use crate::session::Session;
+
+/// What attaching clients that can show other windows offer in their hello
+pub const WINDOWS: &str = "windows";
+
+/// Sent to a client when it starts showing another window, with the
+/// window's number
+pub const WINDOW_EVENT: &str = "window";
+
+/// Sent to the clients showing a window, in a pane or not, when it closes
+pub const WINDOW_CLOSED_EVENT: &str = "window_closed";
+
+/// A window as `nds window list` shows it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WindowSummary {
+    /// 0 for the session's own shell
+    pub id: u32,
+    pub name: Option<String>,
+    /// The window's shell or command
+    pub pid: i32,
+    /// What is in the window's foreground
+    pub command: Vec<String>,
+    pub cols: u16,
+    pub rows: u16,
+    /// Attached clients showing the window
+    pub clients: usize,
+    pub created_at: DateTime<Utc>,
+}
+
+impl WindowSummary {
+    /// Summary of the window on `master_fd`, shown by the clients in
+    /// `active_clients` whose window is `id`
+    pub(super) fn of(
+        id: u32,
+        name: Option<String>,
+        pid: Pid,
+        master_fd: RawFd,
+        created_at: DateTime<Utc>,
+        active_clients: &[ClientInfo],
+    ) -> Self {
+        let (cols, rows) = get_terminal_size_of(master_fd).unwrap_or((0, 0));
+        let master = unsafe { BorrowedFd::borrow_raw(master_fd) };
+        let command = nix::unistd::tcgetpgrp(master)
+            .ok()
+            .and_then(|pgid| command_line(pgid.as_raw()))
+            .unwrap_or_default();
+        WindowSummary {
+            id,
+            name,
+            pid: pid.as_raw(),
+            command,
+            cols,
+            rows,
+            clients: active_clients.iter().filter(|c| c.shows(id)).count(),
+            created_at,
+        }
+    }
+}
+
+/// A shell besides the session's own, on a PTY of its own
+struct Window {
+    id: u32,
+    name: Option<String>,
+    pid: Pid,
+    master_fd: RawFd,
+    io_handler: PtyIoHandler,
+    splitter: BoundarySplitter, // Holds reads that end partway through a sequence
+    screen: Screen,             // For repainting clients that switch to it
+    created_at: DateTime<Utc>,
+    reaped: bool, // Its PID may belong to another process by now
+}
+
+/// Closing the master hangs up the shell and whatever it runs
+impl Drop for Window {
+    fn drop(&mut self) {
+        if !self.reaped {
+            let _ = kill(self.pid, Signal::SIGHUP);
+        }
+        let _ = close(self.master_fd);
+    }
+}
+
+/// The windows a daemon runs besides window 0, in order
+#[derive(Default)]
+pub(super) struct Windows {
+    list: Vec<Window>,
+    /// Shells of closed windows, reaped once they exit
+    hung_up: Vec<Pid>,
+}
+
+impl Windows {
+    /// Start `command`, or the session's shell, in a new window of `size`.
+    /// Returns its number, the lowest one free.
+    pub fn open(
+        &mut self,
+        session_id: &str,
+        name: Option<String>,
+        command: Option<Vec<String>>,
+        (cols, rows): (u16, u16),
+    ) -> Result<u32> {
+        let id = (1..).find(|id| !self.contains(*id)).unwrap_or_default();
+        let session = Session::load(session_id).ok();
+        let mut options = session
+            .as_ref()
+            .map(SpawnOptions::from_session)
+            .unwrap_or_default();
+        options.command = command;
+        options.env.insert("NDS_WINDOW".to_string(), id.to_string());
+        let session_name = session.and_then(|s| s.name);
+        let (master_fd, pid) =
+            PtyProcess::spawn_shell(session_id, session_name.as_deref(), &options, cols, rows)?;
+
+        let at = self.list.partition_point(|window| window.id < id);
+        self.list.insert(
+            at,
+            Window {
+                id,
+                name,
+                pid,
+                master_fd,
+                io_handler: PtyIoHandler::new(master_fd),
+                splitter: BoundarySplitter::default(),
+                screen: Screen::new(cols, rows),
+                created_at: Utc::now(),
+                reaped: false,
+            },
+        );
+        Ok(id)
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        self.list.iter().any(|window| window.id == id)
+    }
+
+    /// The window after `current`, or before it, counting window 0 and
+    /// going round
+    pub fn step(&self, current: u32, forward: bool) -> u32 {
+        let ids: Vec<u32> = std::iter::once(0)
+            .chain(self.list.iter().map(|window| window.id))
+            .collect();
+        let at = ids.iter().position(|&id| id == current).unwrap_or(0);
+        if forward {
+            ids[(at + 1) % ids.len()]
+        } else {
+            ids[(at + ids.len() - 1) % ids.len()]
+        }
+    }
+
+    /// Close window `id`, hanging up its shell. Returns whether there was
+    /// such a window.
+    pub fn close(&mut self, id: u32) -> bool {
+        let Some(at) = self.list.iter().position(|window| window.id == id) else {
+            return false;
+        };
+        let window = self.list.remove(at);
+        self.hung_up.push(window.pid);
+        true
+    }
+
+    /// Forget windows whose shell has exited, and reap closed ones
+    pub fn reap(&mut self) {
+        let exited = |pid: Pid| {
+            !matches!(
+                waitpid(pid, Some(WaitPidFlag::WNOHANG)),
+                Ok(WaitStatus::StillAlive | WaitStatus::Stopped(..) | WaitStatus::Continued(_))
+            )
+        };
+        self.hung_up.retain(|pid| !exited(*pid));
+        self.list.retain_mut(|window| {
+            window.reaped = exited(window.pid);
+            if window.reaped {
+                eprintln!("Window {} exited", window.id);
+            }
+            !window.reaped
+        });
+    }
+
+    /// Type `data` into window `id`; dropped if the window is gone
+    pub fn write(&self, id: u32, data: &[u8]) -> std::io::Result<()> {
+        match self.list.iter().find(|window| window.id == id) {
+            Some(window) => window.io_handler.write_to_pty(data),
+            None => Ok(()),
+        }
+    }
+
+    /// Size every window to the smallest pane it is shown in, or else like
+    /// window 0's PTY, then draw clients the windows just put in their panes
+    pub fn fit(&mut self, main: (u16, u16), active_clients: &mut [ClientInfo], screen: &Screen) {
+        for window in &mut self.list {
+            let (cols, rows) = active_clients
+                .iter()
+                .flat_map(|client| &client.panes)
+                .filter(|(id, _)| *id == window.id)
+                .map(|(_, size)| *size)
+                .reduce(|a, b| (a.0.min(b.0), a.1.min(b.1)))
+                .unwrap_or(main);
+            if get_terminal_size_of(window.master_fd).ok() != Some((cols, rows))
+                && set_terminal_size(window.master_fd, cols, rows).is_ok()
+            {
+                let _ = kill(window.pid, Signal::SIGWINCH);
+            }
+            window.screen.resize(cols, rows);
+        }
+
+        for client in active_clients.iter_mut() {
+            for id in std::mem::take(&mut client.unpainted) {
+                self.repaint(client, id, screen);
+            }
+        }
+    }
+
+    /// What to wait for on each window's PTY, with its shell to tell a
+    /// re-used fd number apart
+    pub fn watches(&self) -> Vec<(Pid, MasterWatch)> {
+        self.list
+            .iter()
+            .map(|window| {
+                let watch = MasterWatch {
+                    fd: window.master_fd,
+                    read: true,
+                    write: window.io_handler.pending_input() > 0,
+                };
+                (window.pid, watch)
+            })
+            .collect()
+    }
+
+    /// When output held back for the rest of a sequence has to go out
+    pub fn deadline(&self) -> Option<Instant> {
+        self.list
+            .iter()
+            .filter_map(|window| window.splitter.deadline())
+            .min()
+    }
+
+    /// Pass on what each window's shell wrote to the clients showing it,
+    /// and the input queued for it to the shell
+    pub fn pump(&mut self, buffer: &mut [u8], active_clients: &mut [ClientInfo]) {
+        for window in &mut self.list {
+            let now = Instant::now();
+            let output = match window.io_handler.read_from_pty(buffer) {
+                Ok(0) => window.splitter.take(),
+                Ok(n) => Some(window.splitter.split(&buffer[..n], now)).filter(|d| !d.is_empty()),
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    window.splitter.take_stale(now)
+                }
+                // The shell has gone; it is reaped and the window closed
+                Err(_) => window.splitter.take(),
+            };
+            if let Some(data) = output {
+                window.screen.process(&data);
+                for client in active_clients.iter_mut().filter(|c| c.shows(window.id)) {
+                    if !client.resync {
+                        let _ = client.send_window_output(window.id, &data);
+                    }
+                }
+            }
+
+            // Clients that dropped output are repainted once caught up
+            for client in active_clients.iter_mut().filter(|c| c.shows(window.id)) {
+                if client.resync && client.pending_output.is_empty() {
+                    client.resync = false;
+                    let _ = client.send_window_output(window.id, &window.screen.repaint());
+                }
+            }
+
+            if let Err(e) = window.io_handler.flush_input() {
+                eprintln!("Warning: Failed to write to window {}: {}", window.id, e);
+            }
+        }
+    }
+
+    /// Show window `id` on `client` from now on, drawn from that window's
+    /// screen, or from `screen` for window 0
+    pub fn show(&self, client: &mut ClientInfo, id: u32, screen: &Screen) {
+        client.window = id;
+        let _ = client.send_event(WINDOW_EVENT, &id.to_string());
+        self.repaint(client, id, screen);
+    }
+
+    /// Draw window `id` on `client` from its screen, or from `screen` for
+    /// window 0
+    fn repaint(&self, client: &mut ClientInfo, id: u32, screen: &Screen) {
+        match self.list.iter().find(|window| window.id == id) {
+            Some(window) => {
+                let _ = client.send_window_output(id, &window.screen.repaint());
+            }
+            // Its view is drawn from the screen anyway
+            None if client.view.is_some() => client.invalidate_view(),
+            None => {
+                let _ = client.send_data(&screen.repaint());
+            }
+        }
+    }
+
+    /// Tell clients which of the windows they show have closed, and move
+    /// those whose own window has back to window 0. A client with panes left
+    /// picks one of them itself, knowing where they are.
+    pub fn rehome(&self, active_clients: &mut [ClientInfo], screen: &Screen) {
+        for client in active_clients.iter_mut() {
+            let mut closed: Vec<u32> = client.panes.iter().map(|(id, _)| *id).collect();
+            closed.push(client.window);
+            closed.retain(|&id| id != 0 && !self.contains(id));
+            closed.sort_unstable();
+            closed.dedup();
+            if closed.is_empty() {
+                continue;
+            }
+            client.panes.retain(|(id, _)| !closed.contains(id));
+            for id in &closed {
+                let _ = client.send_event(WINDOW_CLOSED_EVENT, &id.to_string());
+            }
+            if closed.contains(&client.window) && client.panes.is_empty() {
+                self.show(client, 0, screen);
+            }
+        }
+    }
+
+    /// What `nds window list` shows for each window
+    pub fn summaries(&self, active_clients: &[ClientInfo]) -> Vec<WindowSummary> {
+        self.list
+            .iter()
+            .map(|window| {
+                WindowSummary::of(
+                    window.id,
+                    window.name.clone(),
+                    window.pid,
+                    window.master_fd,
+                    window.created_at,
+                    active_clients,
+                )
+            })
+            .collect()
+    }
+}
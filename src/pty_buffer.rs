@@ -1,58 +1,531 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
 
-/// Circular buffer for PTY output
+use crate::compression::{Compressor, Packed};
+
+/// Bytes of output a session's daemon holds in memory while nobody is
+/// attached, when `max_memory` is not set
+pub const DEFAULT_BUFFER_MEMORY: usize = 2 * 1024 * 1024;
+
+/// The `output_buffer` setting in config.json
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BufferConfig {
+    /// Most memory output printed while detached takes up, compressed or
+    /// not (default: 2 MiB)
+    pub max_memory: Option<usize>,
+    /// Once memory is full, move older output to temporary files of up to
+    /// this many bytes in all instead of dropping it (default: 0, off)
+    pub max_disk: Option<u64>,
+    /// Where those files go (default: the system's temporary directory)
+    pub spill_dir: Option<PathBuf>,
+}
+
+/// Output is kept in segments of up to this size, or an eighth of the
+/// buffer if that is smaller; the oldest whole segment goes first when the
+/// buffer is full
+const SEGMENT_BYTES: usize = 64 * 1024;
+
+/// Part of the buffer: whole chunks of output, never split, so every
+/// segment starts between escape sequences and UTF-8 characters. Full
+/// segments are compressed if the buffer has a compressor.
+#[derive(Debug, Default)]
+struct Segment {
+    data: Packed,
+    newlines: usize,
+    ends_line: bool,
+}
+
+#[derive(Debug, Default)]
+struct Segments {
+    segments: VecDeque<Segment>,
+    /// Output held in memory
+    bytes: usize,
+    /// Memory it takes up, which is what `max_size` caps
+    stored: usize,
+    newlines: usize,
+    spill: Option<Spill>,
+    compressor: Option<Compressor>,
+}
+
+/// Output moved out of memory, oldest first, across a ring of files that
+/// are deleted as soon as they are created so nothing is left behind
+#[derive(Debug)]
+struct Spill {
+    dir: PathBuf,
+    max_bytes: u64,
+    file_bytes: u64,
+    files: VecDeque<SpillFile>,
+    bytes: u64,
+    newlines: usize,
+}
+
+#[derive(Debug)]
+struct SpillFile {
+    file: File,
+    /// Where the output still held starts; a file that now comes first
+    /// after an older one was dropped starts at its first full line
+    start: u64,
+    end: u64,
+    newlines: usize,
+}
+
+/// Everything a buffer held, taken out of it to be sent to a client a piece
+/// at a time, so a large spill is never read into memory all at once
+#[derive(Debug, Default)]
+pub struct BufferReplay {
+    files: VecDeque<SpillFile>,
+    chunks: VecDeque<Packed>,
+    compressor: Option<Compressor>,
+}
+
+/// Segmented ring buffer for PTY output
 /// Stores output generated while no client is attached
+///
+/// Old output is dropped a segment at a time and then up to the next line,
+/// so what is left starts at the beginning of a line rather than partway
+/// through one, or through an escape sequence. With a spill set, segments
+/// that no longer fit in memory go to disk first and are only dropped once
+/// that is full too.
 pub struct PtyBuffer {
-    buffer: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    inner: Arc<Mutex<Segments>>,
     max_size: usize,
-    total_bytes: Arc<Mutex<usize>>,
+    segment_bytes: usize,
 }
 
 impl PtyBuffer {
     pub fn new(max_size: usize) -> Self {
         PtyBuffer {
-            buffer: Arc::new(Mutex::new(VecDeque::new())),
+            inner: Arc::new(Mutex::new(Segments::default())),
             max_size,
-            total_bytes: Arc::new(Mutex::new(0)),
+            segment_bytes: (max_size / 8).clamp(1, SEGMENT_BYTES),
+        }
+    }
+
+    /// A buffer holding as much as `config` allows in memory and on disk
+    pub fn from_config(config: &BufferConfig) -> Self {
+        let buffer = Self::new(config.max_memory.unwrap_or(DEFAULT_BUFFER_MEMORY));
+        if let Some(max_disk) = config.max_disk.filter(|&max| max > 0) {
+            let dir = config.spill_dir.clone().unwrap_or_else(std::env::temp_dir);
+            buffer.set_spill(dir, max_disk);
         }
+        buffer
+    }
+
+    /// Compress segments with `compressor` once they are full, so more
+    /// output fits in the same memory
+    pub fn set_compression(&self, compressor: Compressor) {
+        self.inner.lock().unwrap().compressor = Some(compressor);
+    }
+
+    /// Move output that doesn't fit in memory to files in `dir`, keeping up
+    /// to `max_bytes` of it there before dropping the oldest
+    pub fn set_spill(&self, dir: PathBuf, max_bytes: u64) {
+        self.inner.lock().unwrap().spill = Some(Spill {
+            dir,
+            max_bytes,
+            // A handful of files, so dropping one loses a little at a time
+            file_bytes: (max_bytes / 4).max(1),
+            files: VecDeque::new(),
+            bytes: 0,
+            newlines: 0,
+        });
     }
 
+    /// Add a chunk of output, which should end between escape sequences
     pub fn push(&self, data: &[u8]) {
-        let mut buffer = self.buffer.lock().unwrap();
-        let mut total = self.total_bytes.lock().unwrap();
+        if data.is_empty() {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let newlines = count_newlines(data);
 
-        buffer.push_back(data.to_vec());
-        *total += data.len();
+        let fits = inner.segments.back().is_some_and(|last| {
+            matches!(last.data, Packed::Plain(_))
+                && last.data.size() + data.len() <= self.segment_bytes
+        });
+        if !fits {
+            inner.seal_last();
+            inner.segments.push_back(Segment::default());
+        }
+        let last = inner.segments.back_mut().expect("a segment was just added");
+        if let Packed::Plain(held) = &mut last.data {
+            held.extend_from_slice(data);
+        }
+        last.newlines += newlines;
+        last.ends_line = data.last() == Some(&b'\n');
+        inner.bytes += data.len();
+        inner.stored += data.len();
+        inner.newlines += newlines;
 
-        // Remove old data if we exceed max size
-        while *total > self.max_size && !buffer.is_empty() {
-            if let Some(old_data) = buffer.pop_front() {
-                *total -= old_data.len();
+        // Remove old data if we exceed max size; the chunk just added stays
+        // even if it is bigger than the whole buffer
+        let mut cut_line = false;
+        while inner.stored > self.max_size && inner.segments.len() > 1 {
+            let old = inner.segments.pop_front().expect("checked above");
+            inner.bytes -= old.data.size();
+            inner.stored -= old.data.stored();
+            inner.newlines -= old.newlines;
+            cut_line = !inner.spill_segment(&old) && !old.ends_line;
+        }
+        if cut_line {
+            inner.skip_to_line_start();
+        }
+    }
+
+    /// Take everything held, from disk and memory, leaving the buffer empty
+    pub fn take_replay(&self) -> BufferReplay {
+        let mut inner = self.inner.lock().unwrap();
+        let files = match inner.spill.as_mut() {
+            Some(spill) => {
+                spill.bytes = 0;
+                spill.newlines = 0;
+                std::mem::take(&mut spill.files)
             }
+            None => VecDeque::new(),
+        };
+        let chunks = inner.segments.drain(..).map(|s| s.data).collect();
+        inner.bytes = 0;
+        inner.stored = 0;
+        inner.newlines = 0;
+        BufferReplay {
+            files,
+            chunks,
+            compressor: inner.compressor,
         }
     }
 
     pub fn drain_to(&self, output: &mut Vec<u8>) {
-        let mut buffer = self.buffer.lock().unwrap();
-        let mut total = self.total_bytes.lock().unwrap();
+        let mut replay = self.take_replay();
+        output.reserve(replay.len());
+        while let Ok(Some(chunk)) = replay.next_chunk(SEGMENT_BYTES) {
+            output.extend_from_slice(&chunk);
+        }
+    }
 
-        while let Some(data) = buffer.pop_front() {
-            output.extend_from_slice(&data);
+    /// The last `lines` lines held, without taking them out. Whole segments
+    /// and spill files are skipped by their line counts, so this is cheap
+    /// however much is buffered.
+    pub fn tail(&self, lines: usize) -> Vec<u8> {
+        if lines == 0 {
+            return Vec::new();
+        }
+        let inner = self.inner.lock().unwrap();
+        // A final newline ends the last line rather than starting another
+        let ends_line = inner.segments.back().is_some_and(|last| last.ends_line);
+        let mut wanted = lines + usize::from(ends_line);
+        let mut first = inner.segments.len();
+        let mut offset = 0;
+        for (index, segment) in inner.segments.iter().enumerate().rev() {
+            first = index;
+            if segment.newlines < wanted {
+                wanted -= segment.newlines;
+                continue;
+            }
+            // The line start is inside this segment
+            offset = line_start(&inner.raw(segment), wanted);
+            wanted = 0;
+            break;
+        }
+
+        // Not enough in memory, so the rest comes from disk
+        let mut output = Vec::new();
+        if let Some(spill) = inner.spill.as_ref().filter(|_| wanted > 0) {
+            if let Err(e) = spill.tail(wanted, &mut output) {
+                eprintln!("Warning: failed to read spilled output: {}", e);
+                output.clear();
+            }
+        }
+        for (index, segment) in inner.segments.iter().enumerate().skip(first) {
+            let start = if index == first { offset } else { 0 };
+            output.extend_from_slice(&inner.raw(segment)[start..]);
         }
+        output
+    }
 
-        *total = 0;
+    /// Drop everything buffered, e.g. once a client was repainted without it
+    pub fn clear(&self) {
+        self.take_replay();
     }
 
     pub fn is_empty(&self) -> bool {
-        self.buffer.lock().unwrap().is_empty()
+        // Nothing is spilled while memory has room
+        self.inner.lock().unwrap().bytes == 0
+    }
+
+    /// Bytes held, in memory and on disk
+    pub fn len(&self) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner.bytes as u64 + inner.spill.as_ref().map_or(0, |spill| spill.bytes)
+    }
+
+    /// Bytes held on disk
+    pub fn spilled_len(&self) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner.spill.as_ref().map_or(0, |spill| spill.bytes)
+    }
+
+    /// Complete lines held, i.e. newlines
+    pub fn line_count(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        inner.newlines + inner.spill.as_ref().map_or(0, |spill| spill.newlines)
     }
 
     pub fn clone_handle(&self) -> Self {
         PtyBuffer {
-            buffer: Arc::clone(&self.buffer),
+            inner: Arc::clone(&self.inner),
             max_size: self.max_size,
-            total_bytes: Arc::clone(&self.total_bytes),
+            segment_bytes: self.segment_bytes,
+        }
+    }
+}
+
+impl Segments {
+    /// A segment's output, decompressed if need be
+    fn raw<'a>(&self, segment: &'a Segment) -> Cow<'a, [u8]> {
+        match &segment.data {
+            Packed::Plain(data) => Cow::Borrowed(data),
+            packed => match packed.to_vec(self.compressor.as_ref()) {
+                Ok(data) => Cow::Owned(data),
+                Err(e) => {
+                    eprintln!("Warning: failed to decompress buffered output: {}", e);
+                    Cow::Owned(Vec::new())
+                }
+            },
+        }
+    }
+
+    /// Compress the newest segment, as nothing more is going in it
+    fn seal_last(&mut self) {
+        let Some(compressor) = self.compressor else {
+            return;
+        };
+        let Some(last) = self.segments.back_mut() else {
+            return;
+        };
+        if let Packed::Plain(data) = &mut last.data {
+            let packed = Packed::pack(std::mem::take(data), &compressor);
+            self.stored -= packed.size();
+            self.stored += packed.stored();
+            last.data = packed;
+        }
+    }
+
+    /// Move a segment leaving memory to disk, if there is a spill. Whether
+    /// it went; if writing fails, spilling stops and the output is dropped.
+    fn spill_segment(&mut self, segment: &Segment) -> bool {
+        if self.spill.is_none() {
+            return false;
+        }
+        let data = self.raw(segment);
+        let spill = self.spill.as_mut().expect("checked above");
+        match spill.write(&data, segment.newlines) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Warning: not spilling output to disk any more: {}", e);
+                self.spill = None;
+                false
+            }
         }
     }
+
+    /// Drop the rest of a line whose start was evicted, up to the start of
+    /// the oldest segment's first full line. A segment that is all one line
+    /// has nowhere to cut and is kept whole.
+    fn skip_to_line_start(&mut self) {
+        let only = self.segments.len() == 1;
+        let Some(first) = self.segments.front() else {
+            return;
+        };
+        // The rest of the segment stays, so it is decompressed for good
+        let mut data = self.raw(first).into_owned();
+        let Some(newline) = data.iter().position(|&b| b == b'\n') else {
+            return;
+        };
+        if only && newline + 1 == data.len() {
+            // Keep the only line there is
+            return;
+        }
+        data.drain(..=newline);
+        let first = self.segments.front_mut().expect("checked above");
+        self.stored -= first.data.stored();
+        self.stored += data.len();
+        first.data = Packed::Plain(data);
+        first.newlines -= 1;
+        self.bytes -= newline + 1;
+        self.newlines -= 1;
+        if first.data.size() == 0 {
+            self.segments.pop_front();
+        }
+    }
+}
+
+impl Spill {
+    /// Append output to the newest file, then drop the oldest files while
+    /// over the limit
+    fn write(&mut self, data: &[u8], newlines: usize) -> io::Result<()> {
+        let full = self
+            .files
+            .back()
+            .is_none_or(|last| last.end - last.start >= self.file_bytes);
+        if full {
+            self.files.push_back(SpillFile::create(&self.dir)?);
+        }
+        let last = self.files.back_mut().expect("a file was just added");
+        last.file.write_all_at(data, last.end)?;
+        last.end += data.len() as u64;
+        last.newlines += newlines;
+        self.bytes += data.len() as u64;
+        self.newlines += newlines;
+
+        let mut cut_line = false;
+        while self.bytes > self.max_bytes && self.files.len() > 1 {
+            let old = self.files.pop_front().expect("checked above");
+            self.bytes -= old.end - old.start;
+            self.newlines -= old.newlines;
+            cut_line = true;
+        }
+        if cut_line {
+            self.skip_to_line_start()?;
+        }
+        Ok(())
+    }
+
+    /// Start the oldest file after its first newline, as the line it ends
+    /// began in a file that is gone
+    fn skip_to_line_start(&mut self) -> io::Result<()> {
+        let Some(first) = self.files.front_mut() else {
+            return Ok(());
+        };
+        let mut block = vec![0u8; 8 * 1024];
+        let mut at = first.start;
+        while at < first.end {
+            let len = block.len().min((first.end - at) as usize);
+            first.file.read_exact_at(&mut block[..len], at)?;
+            if let Some(newline) = block[..len].iter().position(|&b| b == b'\n') {
+                let start = at + newline as u64 + 1;
+                self.bytes -= start - first.start;
+                self.newlines -= 1;
+                first.start = start;
+                first.newlines -= 1;
+                break;
+            }
+            at += len as u64;
+        }
+        if first.start == first.end {
+            self.files.pop_front();
+        }
+        Ok(())
+    }
+
+    /// Add the last `lines` lines on disk to `output`
+    fn tail(&self, lines: usize, output: &mut Vec<u8>) -> io::Result<()> {
+        let mut wanted = lines;
+        // Everything on disk, unless the line start is found
+        let mut from = (0, self.files.front().map_or(0, |file| file.start));
+        for (index, file) in self.files.iter().enumerate().rev() {
+            if file.newlines < wanted {
+                wanted -= file.newlines;
+                continue;
+            }
+            let data = file.read(file.start, file.end)?;
+            from = (index, file.start + line_start(&data, wanted) as u64);
+            break;
+        }
+        let (first, offset) = from;
+        for (index, file) in self.files.iter().enumerate().skip(first) {
+            let start = if index == first { offset } else { file.start };
+            output.extend_from_slice(&file.read(start, file.end)?);
+        }
+        Ok(())
+    }
+}
+
+impl SpillFile {
+    /// A new file for spilled output, deleted straight away; it lives on as
+    /// long as it is open, readable only by the daemon
+    fn create(dir: &Path) -> io::Result<Self> {
+        static COUNT: AtomicU32 = AtomicU32::new(0);
+        let name = format!(
+            ".nds-spill-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = dir.join(name);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        fs::remove_file(&path)?;
+        Ok(Self {
+            file,
+            start: 0,
+            end: 0,
+            newlines: 0,
+        })
+    }
+
+    fn read(&self, from: u64, to: u64) -> io::Result<Vec<u8>> {
+        let mut data = vec![0u8; (to - from) as usize];
+        self.file.read_exact_at(&mut data, from)?;
+        Ok(data)
+    }
+}
+
+impl BufferReplay {
+    /// Output that arrived since, to be sent after the rest
+    pub fn push(&mut self, data: &[u8]) {
+        self.chunks.push_back(Packed::Plain(data.to_vec()));
+    }
+
+    /// The next piece to send, of up to `max` bytes when read from disk
+    pub fn next_chunk(&mut self, max: usize) -> io::Result<Option<Vec<u8>>> {
+        if let Some(file) = self.files.front_mut() {
+            let to = file.end.min(file.start + max as u64);
+            let data = file.read(file.start, to)?;
+            file.start = to;
+            if file.start == file.end {
+                self.files.pop_front();
+            }
+            return Ok(Some(data));
+        }
+        self.chunks
+            .pop_front()
+            .map(|chunk| chunk.unpack(self.compressor.as_ref()))
+            .transpose()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty() && self.chunks.is_empty()
+    }
+
+    /// Bytes still to send
+    pub fn len(&self) -> usize {
+        let on_disk: u64 = self.files.iter().map(|f| f.end - f.start).sum();
+        on_disk as usize + self.chunks.iter().map(Packed::size).sum::<usize>()
+    }
+}
+
+/// Where the last `lines` lines of `data` start, which ends with the line
+/// start before `lines` newlines from its end or is all of it
+fn line_start(data: &[u8], lines: usize) -> usize {
+    data.iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, &b)| b == b'\n')
+        .nth(lines - 1)
+        .map_or(0, |(i, _)| i + 1)
+}
+
+fn count_newlines(data: &[u8]) -> usize {
+    data.iter().filter(|&&b| b == b'\n').count()
 }
@@ -0,0 +1,298 @@
+//! Attaching to sessions over the network without SSH.
+//!
+//! A session started with `nds new --listen ADDR` has its daemon also listen
+//! on TCP. Connections are wrapped in TLS, and a client has to present the
+//! session's token before anything else. The daemon then connects to its own
+//! Unix socket on the client's behalf and copies bytes both ways, so remote
+//! clients speak the usual framed protocol and go through the same checks as
+//! local ones. On the other end, `nds attach --remote` opens a private Unix
+//! socket that leads to the remote daemon, and the normal attach code
+//! connects to that.
+//!
+//! `nds remote HOST` gets the same effect through ssh instead (see `ssh`),
+//! with no listener on the far end.
+//!
+//! TLS needs the `remote` cargo feature; without it, only the types here
+//! are built and asking for a remote session is an error.
+
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read};
+use std::os::fd::BorrowedFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+
+use crate::error::{NdsError, Result};
+use crate::resolve::{resolve, ResolveOptions};
+use crate::session::Session;
+use crate::transport::Transport;
+
+pub mod ssh;
+#[cfg(feature = "remote")]
+mod tls;
+
+#[cfg(feature = "remote")]
+pub use tls::{prepare, serve, RemoteListener, Tunnel};
+
+/// Where the daemon's TLS certificate and key come from, in config.json as
+/// `"remote": { "cert": ..., "key": ... }`. Without them, a self-signed
+/// pair is made with `openssl` in `~/.nds/remote/` the first time one is
+/// needed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RemoteConfig {
+    /// PEM certificate chain, the daemon's own certificate first
+    pub cert: Option<PathBuf>,
+    /// PEM private key for `cert`
+    pub key: Option<PathBuf>,
+}
+
+/// How to reach a session's daemon over the network, as recorded in its
+/// metadata
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteEndpoint {
+    /// Address the daemon listens on, e.g. `127.0.0.1:7077`
+    pub address: String,
+    /// What clients have to present before they get anywhere
+    pub token: String,
+    /// SHA-256 of the daemon's certificate, hex, for clients to check it by
+    pub fingerprint: String,
+}
+
+impl RemoteEndpoint {
+    /// The command that attaches to `session_id` through this endpoint,
+    /// from another machine
+    pub fn attach_command(&self, session_id: &str) -> String {
+        format!(
+            "NDS_REMOTE_TOKEN={} nds attach --remote {} {} --fingerprint {}",
+            self.token, self.address, session_id, self.fingerprint
+        )
+    }
+}
+
+/// How long a new connection has to finish TLS and say who it is
+pub const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest first line a client may send
+const MAX_HELLO: usize = 1024;
+
+/// What a client sends first: the token, then the session it wants (an ID,
+/// an ID prefix or a name, which may contain spaces)
+pub fn hello_line(token: &str, session: &str) -> String {
+    format!("nds-remote {} {}\n", token, session)
+}
+
+/// The token and session in a client's first line
+pub fn parse_hello(line: &str) -> Option<(&str, &str)> {
+    let rest = line.strip_suffix('\n')?.strip_prefix("nds-remote ")?;
+    let (token, session) = rest.split_once(' ')?;
+    (!token.is_empty() && !session.is_empty()).then_some((token, session))
+}
+
+/// What a client is told once it is let in: the full ID of its session
+pub fn ok_line(session_id: &str) -> String {
+    format!("ok {}\n", session_id)
+}
+
+/// The daemon's answer to a client's first line: the full session ID, or
+/// why it was turned away
+pub fn parse_reply(line: &str) -> Result<String> {
+    let line = line.trim_end_matches('\n');
+    if let Some(id) = line.strip_prefix("ok ") {
+        return Ok(id.to_string());
+    }
+    Err(NdsError::PermissionDenied(
+        line.strip_prefix("error ").unwrap_or(line).to_string(),
+    ))
+}
+
+/// A fresh random token, 256 bits as hex
+pub fn new_token() -> String {
+    format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+/// Compare tokens in time that doesn't depend on where they differ
+pub fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Whether `query` picks the session with `id` out of `sessions`, the way
+/// session arguments are resolved on the command line
+pub fn session_matches(
+    query: &str,
+    id: &str,
+    sessions: &[Session],
+    options: &ResolveOptions,
+) -> bool {
+    resolve(query, sessions, options).is_ok_and(|resolved| resolved.session.id == id)
+}
+
+/// Read one `\n`-terminated line, a byte at a time so nothing after it is
+/// taken, up to `MAX_HELLO` bytes
+pub fn read_line(stream: &mut impl Read) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    while line.last() != Some(&b'\n') {
+        if line.len() >= MAX_HELLO {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+        }
+        if stream.read(&mut byte)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        line.push(byte[0]);
+    }
+    String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Copy bytes between `remote` and `local` until either side closes. Both
+/// have to have an fd to poll.
+pub fn bridge(remote: impl Transport, local: impl Transport) -> io::Result<()> {
+    let (Some(remote_fd), Some(local_fd)) = (remote.raw_fd(), local.raw_fd()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "only transports with an fd can be bridged",
+        ));
+    };
+    let mut buf = [0u8; 16384];
+    let result = loop {
+        // Safety: both fds stay open for the whole call
+        let (remote_borrowed, local_borrowed) = unsafe {
+            (
+                BorrowedFd::borrow_raw(remote_fd),
+                BorrowedFd::borrow_raw(local_fd),
+            )
+        };
+        let mut fds = [
+            PollFd::new(remote_borrowed, PollFlags::POLLIN),
+            PollFd::new(local_borrowed, PollFlags::POLLIN),
+        ];
+        let timeout = if remote.buffered() {
+            PollTimeout::ZERO
+        } else {
+            PollTimeout::NONE
+        };
+        match poll(&mut fds, timeout) {
+            Ok(_) => {}
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(e) => break Err(e.into()),
+        }
+        let ready = |fd: &PollFd| fd.revents().is_some_and(|revents| !revents.is_empty());
+        let (remote_ready, local_ready) = (ready(&fds[0]), ready(&fds[1]));
+
+        if remote_ready || remote.buffered() {
+            match remote.recv(&mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(n) => {
+                    if let Err(e) = local.send(&buf[..n]) {
+                        break Err(e);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => break Err(e),
+            }
+        }
+        if local_ready {
+            match local.recv(&mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(n) => {
+                    if let Err(e) = remote.send(&buf[..n]) {
+                        break Err(e);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        }
+    };
+    remote.close();
+    local.close();
+    result
+}
+
+/// A private socket in the socket directory for a tunnel to listen on,
+/// named after this process
+fn tunnel_socket() -> Result<(UnixListener, PathBuf)> {
+    let dir = Session::socket_dir()?;
+    remove_stale_tunnels(&dir);
+    let socket_path = dir.join(format!("remote-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&socket_path);
+    let listener = UnixListener::bind(&socket_path)?;
+    std::fs::set_permissions(&socket_path, std::fs::Permissions::from_mode(0o600))?;
+    Ok((listener, socket_path))
+}
+
+/// Remove the sockets of tunnels whose `nds` was killed before it could
+fn remove_stale_tunnels(dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let pid = entry.file_name().to_str().and_then(|name| {
+            name.strip_prefix("remote-")?
+                .strip_suffix(".sock")?
+                .parse()
+                .ok()
+        });
+        if pid.is_some_and(|pid| !Session::is_process_alive(pid)) {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
+
+#[cfg(not(feature = "remote"))]
+fn not_built() -> NdsError {
+    NdsError::RemoteError("this nds was built without the `remote` feature".to_string())
+}
+
+/// Without TLS there is nothing to listen with
+#[cfg(not(feature = "remote"))]
+pub fn prepare(_address: &str) -> Result<RemoteEndpoint> {
+    Err(not_built())
+}
+
+/// A daemon's TCP listener; only with the `remote` feature
+#[cfg(not(feature = "remote"))]
+pub struct RemoteListener {
+    endpoint: RemoteEndpoint,
+}
+
+#[cfg(not(feature = "remote"))]
+impl RemoteListener {
+    pub fn bind(_requested: &RemoteEndpoint) -> Result<Self> {
+        Err(not_built())
+    }
+
+    pub fn endpoint(&self) -> &RemoteEndpoint {
+        &self.endpoint
+    }
+}
+
+#[cfg(not(feature = "remote"))]
+pub fn serve(_remote: RemoteListener, _session: crate::session::Session) {}
+
+/// A local socket leading to a session on another machine; only with the
+/// `remote` feature
+#[cfg(not(feature = "remote"))]
+pub struct Tunnel {
+    pub session_id: String,
+    pub socket_path: PathBuf,
+}
+
+#[cfg(not(feature = "remote"))]
+impl Tunnel {
+    pub fn open(_address: &str, _session: &str, _token: &str, _fingerprint: &str) -> Result<Self> {
+        Err(not_built())
+    }
+}
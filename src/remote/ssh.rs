@@ -0,0 +1,237 @@
+//! Sessions on another machine reached over ssh, for `nds remote HOST`.
+//!
+//! Nothing listens on the far end: each connection runs
+//! `nds attach --raw --framed` there over its own ssh, which passes the
+//! session's socket through stdin and stdout untouched. Locally, a private
+//! Unix socket leads to those, so the normal attach client (escapes,
+//! resizing, the switcher) works as it does for a local session.
+
+use std::borrow::Cow;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+
+use super::{ok_line, parse_reply, read_line, tunnel_socket};
+use crate::error::{NdsError, Result};
+use crate::session::Session;
+
+/// The ssh command to use instead of `ssh`, split on whitespace, e.g.
+/// `ssh -p 2222` or `ssh -F ~/.ssh/work_config`
+pub const SSH_VAR: &str = "NDS_SSH";
+
+/// Quote `arg` for the POSIX shell ssh runs the far end's command with
+pub fn shell_quote(arg: &str) -> Cow<'_, str> {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./@:+=,%".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        Cow::Borrowed(arg)
+    } else {
+        Cow::Owned(format!("'{}'", arg.replace('\'', r"'\''")))
+    }
+}
+
+/// `nds` with `args`, as a command line for the far end's shell
+pub fn remote_command(args: &[&str]) -> String {
+    std::iter::once("nds")
+        .chain(args.iter().copied())
+        .map(shell_quote)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// ssh to `host` running `nds` with `args` there
+fn ssh(host: &str, ssh_options: &[&str], args: &[&str]) -> Command {
+    let program = std::env::var(SSH_VAR)
+        .ok()
+        .filter(|ssh| !ssh.trim().is_empty())
+        .unwrap_or_else(|| "ssh".to_string());
+    let mut words = program.split_whitespace();
+    let mut command = Command::new(words.next().unwrap_or("ssh"));
+    command
+        .args(words)
+        .args(ssh_options)
+        .arg("--")
+        .arg(host)
+        .arg(remote_command(args));
+    command
+}
+
+/// Run `nds list` on `host`, with its output shown here. Returns the exit
+/// code to leave with.
+pub fn list(host: &str) -> Result<i32> {
+    let status = ssh(host, &[], &["list"])
+        .status()
+        .map_err(|e| NdsError::RemoteError(format!("failed to run ssh: {}", e)))?;
+    Ok(status.code().unwrap_or(1))
+}
+
+/// Runs one far-end `nds attach --raw --framed` for each connection
+struct SshDialer {
+    host: String,
+    session: String,
+}
+
+impl SshDialer {
+    /// Start ssh and wait for the far end to say it has connected to the
+    /// session; returns the child and the session's full ID
+    fn dial(&self) -> Result<(Child, String)> {
+        let mut child = ssh(
+            &self.host,
+            &["-T", "-e", "none"],
+            &["attach", "--raw", "--framed", &self.session],
+        )
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| NdsError::RemoteError(format!("failed to run ssh: {}", e)))?;
+
+        let reply = child
+            .stdout
+            .as_mut()
+            .map(read_line)
+            .unwrap_or_else(|| Err(io::ErrorKind::BrokenPipe.into()));
+        match reply {
+            Ok(line) => match parse_reply(&line) {
+                Ok(session_id) => Ok((child, session_id)),
+                Err(e) => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    Err(e)
+                }
+            },
+            Err(_) => {
+                let _ = child.kill();
+                let status = child.wait()?;
+                Err(NdsError::RemoteError(format!(
+                    "could not reach session '{}' on {} through ssh ({})",
+                    self.session, self.host, status
+                )))
+            }
+        }
+    }
+}
+
+/// A local socket leading to a session on another machine through ssh
+pub struct SshTunnel {
+    pub session_id: String,
+    pub socket_path: PathBuf,
+}
+
+impl SshTunnel {
+    /// ssh to `host` and find `session` (an ID, an ID prefix or a name)
+    /// there. The first ssh runs before this returns, so it can still ask
+    /// for a password; later connections need to get in without one.
+    pub fn open(host: &str, session: &str) -> Result<Self> {
+        let dialer = SshDialer {
+            host: host.to_string(),
+            session: session.to_string(),
+        };
+        let (first, session_id) = dialer.dial()?;
+        let (listener, socket_path) = tunnel_socket()?;
+
+        thread::spawn(move || {
+            let mut first = Some(first);
+            for local in listener.incoming().flatten() {
+                let child = match first.take() {
+                    Some(child) => child,
+                    None => match dialer.dial() {
+                        Ok((child, _)) => child,
+                        Err(_) => continue,
+                    },
+                };
+                thread::spawn(move || bridge_child(child, local));
+            }
+        });
+
+        Ok(Self {
+            session_id,
+            socket_path,
+        })
+    }
+}
+
+impl Drop for SshTunnel {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.socket_path);
+    }
+}
+
+/// Copy bytes between `local` and ssh's stdin and stdout until either side
+/// closes, then make sure ssh is gone
+fn bridge_child(mut child: Child, local: UnixStream) {
+    let (Some(mut stdin), Some(mut stdout)) = (child.stdin.take(), child.stdout.take()) else {
+        let _ = child.kill();
+        let _ = child.wait();
+        return;
+    };
+    if let Ok(mut from_local) = local.try_clone() {
+        // Dropping stdin at the end tells the far end this client is gone
+        thread::spawn(move || {
+            let _ = pump(&mut from_local, &mut stdin);
+        });
+    }
+    let mut to_local = &local;
+    let _ = pump(&mut stdout, &mut to_local);
+    let _ = local.shutdown(std::net::Shutdown::Both);
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Copy from `from` to `to` until `from` ends, passing on each read as it
+/// comes. `io::copy` would be simpler, but between a socket and a pipe it
+/// can hold on to a hello that needs answering, and stdout only flushes
+/// whole lines.
+fn pump(from: &mut impl Read, to: &mut impl Write) -> io::Result<()> {
+    let mut buffer = [0u8; 16384];
+    loop {
+        match from.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(n) => {
+                to.write_all(&buffer[..n])?;
+                to.flush()?;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The far end of a tunnel: say `session` was found on stdout, then copy
+/// bytes between its socket and stdin and stdout until either side closes
+pub fn relay(session: &Session) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(ok_line(&session.id).as_bytes())?;
+    stdout.flush()?;
+
+    // The daemon gives a new connection only a moment to say hello before
+    // taking it for a client that predates framing, so don't connect until
+    // the client on the other end has started talking
+    let mut stdin = io::stdin().lock();
+    let mut buffer = [0u8; 16384];
+    let n = loop {
+        match stdin.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(n) => break n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    };
+    drop(stdin);
+    let mut socket = session.connect_socket()?;
+    socket.set_read_timeout(None)?;
+    socket.set_write_timeout(None)?;
+    socket.write_all(&buffer[..n])?;
+
+    // Left blocked in read when the daemon hangs up; exiting ends it
+    let mut to_session = socket.try_clone()?;
+    thread::spawn(move || {
+        let _ = pump(&mut io::stdin().lock(), &mut to_session);
+        let _ = to_session.shutdown(std::net::Shutdown::Write);
+    });
+
+    match pump(&mut &socket, &mut stdout) {
+        Err(e) if e.kind() == io::ErrorKind::ConnectionReset => Ok(()),
+        result => Ok(result?),
+    }
+}
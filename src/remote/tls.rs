@@ -0,0 +1,603 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring as provider, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, ServerConfig};
+
+use super::{
+    bridge, hello_line, new_token, ok_line, parse_hello, parse_reply, read_line, session_matches,
+    tokens_match, tunnel_socket, RemoteEndpoint, AUTH_TIMEOUT,
+};
+use crate::config::Config;
+use crate::error::{NdsError, Result};
+use crate::history_v2::SessionHistory;
+use crate::session::{private_dir, Session};
+use crate::transport::Transport;
+
+fn tls_error(e: impl std::fmt::Display) -> NdsError {
+    NdsError::RemoteError(e.to_string())
+}
+
+/// A TLS connection over TCP. `recv` takes one record's worth off the
+/// socket at a time, so the bridge can poll it between reads.
+#[derive(Debug)]
+struct TlsStream {
+    sock: TcpStream,
+    tls: Mutex<Tls>,
+    /// When the handshake and first line have to be done by
+    deadline: Instant,
+}
+
+#[derive(Debug)]
+struct Tls {
+    conn: rustls::Connection,
+    /// Decrypted data may be left over from the last read
+    more: bool,
+}
+
+impl Tls {
+    fn flush(&mut self, mut sock: &TcpStream) -> io::Result<()> {
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut sock)?;
+        }
+        Ok(())
+    }
+}
+
+/// A socket read and written only until `deadline`, so a peer can't drag
+/// out the handshake and first line by sending a byte at a time
+struct Until<'a> {
+    sock: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Until<'_> {
+    /// Have the next read or write give up at the deadline
+    fn arm(&self) -> io::Result<()> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "took too long to authenticate",
+            ));
+        }
+        self.sock.set_read_timeout(Some(left))?;
+        self.sock.set_write_timeout(Some(left))
+    }
+}
+
+impl Read for Until<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.arm()?;
+        (&*self.sock).read(buf)
+    }
+}
+
+impl Write for Until<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.arm()?;
+        (&*self.sock).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.sock).flush()
+    }
+}
+
+impl TlsStream {
+    /// Finish the handshake on `sock`, leaving `AUTH_TIMEOUT` from now for
+    /// it and the first line
+    fn handshake(conn: impl Into<rustls::Connection>, sock: TcpStream) -> io::Result<Self> {
+        let deadline = Instant::now() + AUTH_TIMEOUT;
+        let mut conn = conn.into();
+        while conn.is_handshaking() {
+            conn.complete_io(&mut Until {
+                sock: &sock,
+                deadline,
+            })?;
+        }
+        Ok(Self {
+            sock,
+            tls: Mutex::new(Tls { conn, more: false }),
+            deadline,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Tls> {
+        self.tls.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Blocking reads that wait out TLS bookkeeping, for the first line before
+/// the bridge takes over
+impl Read for &TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut sock = Until {
+            sock: &self.sock,
+            deadline: self.deadline,
+        };
+        match &mut self.lock().conn {
+            rustls::Connection::Client(conn) => rustls::Stream::new(conn, &mut sock).read(buf),
+            rustls::Connection::Server(conn) => rustls::Stream::new(conn, &mut sock).read(buf),
+        }
+    }
+}
+
+impl Transport for TlsStream {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut tls = self.lock();
+        if !tls.more {
+            // Take what arrived and decrypt it
+            if tls.conn.read_tls(&mut &self.sock)? == 0 {
+                return Ok(0);
+            }
+            tls.conn
+                .process_new_packets()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            // Alerts and key updates it may have to answer
+            tls.flush(&self.sock)?;
+        }
+        match tls.conn.reader().read(buf) {
+            Ok(n) => {
+                // A full buffer may have left more behind
+                tls.more = n == buf.len();
+                Ok(n)
+            }
+            Err(e) => {
+                tls.more = false;
+                Err(e)
+            }
+        }
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<()> {
+        let mut tls = self.lock();
+        tls.conn.writer().write_all(buf)?;
+        tls.flush(&self.sock)
+    }
+
+    fn buffered(&self) -> bool {
+        self.lock().more
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.sock.as_raw_fd())
+    }
+
+    /// Both handles would need the one TLS session, and reads hold it
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "a TLS stream can't be cloned",
+        ))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_write_timeout(timeout)
+    }
+
+    fn shutdown(&self, how: std::net::Shutdown) -> io::Result<()> {
+        self.sock.shutdown(how)
+    }
+
+    fn close(&self) {
+        let mut tls = self.lock();
+        tls.conn.send_close_notify();
+        let _ = tls.flush(&self.sock);
+        let _ = self.sock.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(provider::default_provider())
+}
+
+/// `~/.nds/remote`, or `$NDS_HOME/remote`, where a generated certificate
+/// is kept
+fn remote_dir() -> Result<PathBuf> {
+    let dir = if let Ok(nds_home) = std::env::var("NDS_HOME") {
+        PathBuf::from(nds_home).join("remote")
+    } else {
+        directories::BaseDirs::new()
+            .ok_or_else(|| {
+                NdsError::DirectoryCreationError("Could not find home directory".to_string())
+            })?
+            .home_dir()
+            .join(".nds")
+            .join("remote")
+    };
+    Ok(dir)
+}
+
+/// The configured certificate and key, or the generated pair, made with
+/// `openssl` the first time
+fn cert_paths() -> Result<(PathBuf, PathBuf)> {
+    let config = Config::load()
+        .unwrap_or_default()
+        .remote
+        .unwrap_or_default();
+    if let (Some(cert), Some(key)) = (config.cert, config.key) {
+        return Ok((cert, key));
+    }
+
+    let dir = remote_dir()?;
+    let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+    if cert.exists() && key.exists() {
+        return Ok((cert, key));
+    }
+    if let Some(parent) = dir.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    private_dir(&dir, 0o700)?;
+    let output = Command::new("openssl")
+        .args(["req", "-x509", "-newkey", "ec", "-pkeyopt"])
+        .arg("ec_paramgen_curve:prime256v1")
+        .args(["-nodes", "-days", "3650", "-subj", "/CN=nds"])
+        .arg("-keyout")
+        .arg(&key)
+        .arg("-out")
+        .arg(&cert)
+        .output()
+        .map_err(|e| {
+            NdsError::RemoteError(format!(
+                "could not run openssl to make a certificate ({}); set remote.cert and \
+                 remote.key in config.json",
+                e
+            ))
+        })?;
+    if !output.status.success() {
+        return Err(NdsError::RemoteError(format!(
+            "openssl could not make a certificate: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    std::fs::set_permissions(&key, std::fs::Permissions::from_mode(0o600))?;
+    Ok((cert, key))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| NdsError::RemoteError(format!("{}: {}", path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(NdsError::RemoteError(format!(
+            "{}: no certificate",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+/// SHA-256 of a certificate, as lowercase hex
+fn fingerprint(cert: &CertificateDer) -> String {
+    ring::digest::digest(&ring::digest::SHA256, cert.as_ref())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Get ready to start a session that listens on `address`: make sure there
+/// is a certificate, and pick a token
+pub fn prepare(address: &str) -> Result<RemoteEndpoint> {
+    address
+        .to_socket_addrs()
+        .map_err(|e| NdsError::RemoteError(format!("{}: {}", address, e)))?;
+    let (cert, _) = cert_paths()?;
+    Ok(RemoteEndpoint {
+        address: address.to_string(),
+        token: new_token(),
+        fingerprint: fingerprint(&load_certs(&cert)?[0]),
+    })
+}
+
+/// A daemon's TCP listener, bound but not yet taking connections
+pub struct RemoteListener {
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+    endpoint: RemoteEndpoint,
+}
+
+impl RemoteListener {
+    /// Bind what `nds new --listen` asked for. The endpoint it reports
+    /// has the port actually bound and the certificate actually loaded.
+    pub fn bind(requested: &RemoteEndpoint) -> Result<Self> {
+        let (cert, key) = cert_paths()?;
+        let certs = load_certs(&cert)?;
+        let key = PrivateKeyDer::from_pem_file(&key)
+            .map_err(|e| NdsError::RemoteError(format!("{}: {}", key.display(), e)))?;
+        let endpoint = RemoteEndpoint {
+            fingerprint: fingerprint(&certs[0]),
+            ..requested.clone()
+        };
+        let config = ServerConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(tls_error)?;
+
+        let listener = TcpListener::bind(&requested.address).map_err(|e| {
+            NdsError::RemoteError(format!("cannot listen on {}: {}", requested.address, e))
+        })?;
+        let endpoint = RemoteEndpoint {
+            address: listener.local_addr()?.to_string(),
+            ..endpoint
+        };
+        Ok(Self {
+            listener,
+            config: Arc::new(config),
+            endpoint,
+        })
+    }
+
+    pub fn endpoint(&self) -> &RemoteEndpoint {
+        &self.endpoint
+    }
+}
+
+/// Connections that may be still authenticating at once; more are hung up
+/// on straight away
+const MAX_PENDING_CLIENTS: usize = 16;
+
+/// A connection that hasn't authenticated yet, counted until dropped
+struct Pending(Arc<AtomicUsize>);
+
+impl Pending {
+    /// Count one more, unless there are `MAX_PENDING_CLIENTS` already
+    fn start(count: &Arc<AtomicUsize>) -> Option<Self> {
+        if count.fetch_add(1, Ordering::SeqCst) >= MAX_PENDING_CLIENTS {
+            count.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Pending(count.clone()))
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Take remote clients for `session` in the background, each on its own
+/// thread, for as long as the daemon runs
+pub fn serve(remote: RemoteListener, session: Session) {
+    let session = Arc::new(session);
+    let pending = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for stream in remote.listener.incoming().flatten() {
+            let Some(pending) = Pending::start(&pending) else {
+                continue;
+            };
+            let config = remote.config.clone();
+            let token = remote.endpoint.token.clone();
+            let session = session.clone();
+            thread::spawn(move || {
+                let _ = serve_client(stream, config, &token, &session, pending);
+            });
+        }
+    });
+}
+
+/// Check a remote client's token and session, then join it to the
+/// session's socket
+fn serve_client(
+    sock: TcpStream,
+    config: Arc<ServerConfig>,
+    token: &str,
+    session: &Session,
+    pending: Pending,
+) -> Result<()> {
+    let address = sock.peer_addr()?.to_string();
+    let conn = rustls::ServerConnection::new(config).map_err(tls_error)?;
+    let stream = TlsStream::handshake(conn, sock)?;
+
+    let line = read_line(&mut &stream)?;
+    let is_this_session = |query| {
+        let options = Config::load().unwrap_or_default().matching;
+        Session::list_all()
+            .is_ok_and(|sessions| session_matches(query, &session.id, &sessions, &options))
+    };
+    let verdict = match parse_hello(&line) {
+        Some((given, _)) if !tokens_match(given, token) => Err("authentication failed"),
+        Some((_, query)) if !is_this_session(query) => Err("no such session here"),
+        Some(_) => Ok(()),
+        None => Err("not an nds client"),
+    };
+    if let Err(reason) = verdict {
+        let _ = stream.send(format!("error {}\n", reason).as_bytes());
+        stream.close();
+        return Err(NdsError::PermissionDenied(format!(
+            "{}: {}",
+            address, reason
+        )));
+    }
+    // In, so no longer held against MAX_PENDING_CLIENTS
+    drop(pending);
+
+    let local = session.connect_socket()?;
+    local.set_read_timeout(None)?;
+    local.set_write_timeout(None)?;
+    stream.send(ok_line(&session.id).as_bytes())?;
+    stream.sock.set_read_timeout(None)?;
+    stream.sock.set_write_timeout(None)?;
+    let _ = SessionHistory::record_remote_connected(session, &address);
+    bridge(stream, local)?;
+    Ok(())
+}
+
+/// Accepts the one certificate whose SHA-256 it was given, whatever name
+/// it is for: the fingerprint is what `nds new --listen` printed, so there
+/// is no CA to ask
+#[derive(Debug)]
+struct PinnedCert {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity).eq_ignore_ascii_case(&self.fingerprint) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "the server's certificate does not have the expected fingerprint".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Where to reach a remote session and how to prove we may
+struct Dialer {
+    address: String,
+    session: String,
+    token: String,
+    config: Arc<ClientConfig>,
+}
+
+impl Dialer {
+    /// Connect, check the certificate, present the token and ask for the
+    /// session; returns the stream and the session's full ID
+    fn dial(&self) -> Result<(TlsStream, String)> {
+        let sock = TcpStream::connect(&self.address)
+            .map_err(|e| NdsError::RemoteError(format!("{}: {}", self.address, e)))?;
+        // The certificate is checked by fingerprint, so any name will do
+        let host = self
+            .address
+            .rsplit_once(':')
+            .map_or(self.address.as_str(), |(host, _)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let name = ServerName::try_from(host.to_string())
+            .unwrap_or_else(|_| ServerName::try_from("nds").expect("valid name"));
+        let conn = ClientConnection::new(self.config.clone(), name).map_err(tls_error)?;
+        let stream = TlsStream::handshake(conn, sock)
+            .map_err(|e| NdsError::RemoteError(format!("{}: {}", self.address, e)))?;
+
+        stream.send(hello_line(&self.token, &self.session).as_bytes())?;
+        let id = parse_reply(&read_line(&mut &stream)?)?;
+        stream.sock.set_read_timeout(None)?;
+        stream.sock.set_write_timeout(None)?;
+        Ok((stream, id))
+    }
+}
+
+/// A private Unix socket leading to a session on another machine. Each
+/// connection to it becomes a connection to the remote daemon, so the
+/// normal attach code can be pointed at it like at a local session.
+pub struct Tunnel {
+    pub session_id: String,
+    pub socket_path: PathBuf,
+}
+
+impl Tunnel {
+    /// Connect to the daemon at `address` (checking its certificate against
+    /// `fingerprint`) and ask for `session` with `token`
+    pub fn open(address: &str, session: &str, token: &str, fingerprint: &str) -> Result<Self> {
+        let provider = crypto_provider();
+        let verifier = PinnedCert {
+            fingerprint: fingerprint.to_string(),
+            provider: provider.clone(),
+        };
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        let dialer = Dialer {
+            address: address.to_string(),
+            session: session.to_string(),
+            token: token.to_string(),
+            config: Arc::new(config),
+        };
+        // Fail here, not on attach, if we can't get in
+        let (first, session_id) = dialer.dial()?;
+
+        let (listener, socket_path) = tunnel_socket()?;
+
+        thread::spawn(move || {
+            let mut first = Some(first);
+            for local in listener.incoming().flatten() {
+                let remote = match first.take() {
+                    Some(stream) => stream,
+                    None => match dialer.dial() {
+                        Ok((stream, _)) => stream,
+                        Err(_) => continue,
+                    },
+                };
+                thread::spawn(move || {
+                    let _ = bridge(remote, local);
+                });
+            }
+        });
+
+        Ok(Self {
+            session_id,
+            socket_path,
+        })
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.socket_path);
+    }
+}
@@ -0,0 +1,492 @@
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEvent},
+    execute,
+    terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Direction, Layout},
+    text::Line,
+    widgets::Paragraph,
+    Frame, Terminal,
+};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::error::{NdsError, Result};
+use crate::pty::Screen;
+use crate::scrollback::ansi_to_line;
+use crate::ui::Theme;
+
+/// How far the arrow keys seek, and page up and down
+const SEEK_STEP: f64 = 5.0;
+const SEEK_PAGE: f64 = 60.0;
+
+/// Fastest and slowest playback `+` and `-` go to
+const MAX_SPEED: f64 = 64.0;
+const MIN_SPEED: f64 = 1.0 / 16.0;
+
+/// Longest the player waits for a key before updating the clock
+const FRAME_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Something that happened in a recording
+#[derive(Debug, Clone, PartialEq)]
+pub enum CastEvent {
+    Output(String),
+    /// The terminal changed size, as (cols, rows)
+    Resize(u16, u16),
+}
+
+/// A recording to play back: an asciicast v2 file like `nds record` and
+/// `asciinema rec` write, or a typescript from `script`
+#[derive(Debug, Clone)]
+pub struct Cast {
+    pub width: u16,
+    pub height: u16,
+    pub title: Option<String>,
+    /// Events in order, with their time in seconds from the start
+    pub events: Vec<(f64, CastEvent)>,
+}
+
+impl Cast {
+    /// Read a recording, guessing its format from the first line. A
+    /// typescript plays all at once unless `timing` (from `script -t`) says
+    /// when each part was written.
+    pub fn load(path: &Path, timing: Option<&Path>) -> Result<Self> {
+        let data = std::fs::read(path)?;
+        let timing = timing.map(std::fs::read_to_string).transpose()?;
+        if data.first() == Some(&b'{') && timing.is_none() {
+            Self::parse_asciicast(&String::from_utf8_lossy(&data))
+        } else {
+            Self::parse_typescript(&data, timing.as_deref())
+        }
+    }
+
+    /// Parse an asciicast v2 recording: a JSON header line, then one
+    /// `[time, code, data]` array per line. Events other than output and
+    /// resizes (input, markers) are skipped.
+    pub fn parse_asciicast(text: &str) -> Result<Self> {
+        let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+        let header: serde_json::Value = lines
+            .next()
+            .and_then(|line| serde_json::from_str(line).ok())
+            .ok_or_else(|| NdsError::RecordingError("missing asciicast header".to_string()))?;
+        if header["version"] != 2 {
+            return Err(NdsError::RecordingError(format!(
+                "unsupported asciicast version {}",
+                header["version"]
+            )));
+        }
+        let dimension = |key: &str| {
+            header[key]
+                .as_u64()
+                .and_then(|v| u16::try_from(v).ok())
+                .ok_or_else(|| NdsError::RecordingError(format!("header has no {}", key)))
+        };
+
+        let mut events = Vec::new();
+        for (number, line) in lines.enumerate() {
+            let (time, code, data): (f64, String, String) = serde_json::from_str(line)
+                .map_err(|e| NdsError::RecordingError(format!("event {}: {}", number + 1, e)))?;
+            let event = match code.as_str() {
+                "o" => CastEvent::Output(data),
+                "r" => match data
+                    .split_once('x')
+                    .and_then(|(cols, rows)| Some((cols.parse().ok()?, rows.parse().ok()?)))
+                {
+                    Some((cols, rows)) => CastEvent::Resize(cols, rows),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            events.push((time, event));
+        }
+
+        Ok(Self {
+            width: dimension("width")?,
+            height: dimension("height")?,
+            title: header["title"].as_str().map(str::to_string),
+            events,
+        })
+    }
+
+    /// Parse a typescript from `script`, dropping its "Script started" and
+    /// "Script done" lines. `timing` has a `delay bytes` line per write, as
+    /// `scriptreplay` reads, or `O delay bytes` in the advanced format; the
+    /// size comes from the start line if it has it.
+    pub fn parse_typescript(data: &[u8], timing: Option<&str>) -> Result<Self> {
+        let mut body = data;
+        let mut size = None;
+        if body.starts_with(b"Script started on ") {
+            let end = body
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(body.len(), |i| i + 1);
+            size = script_size(&String::from_utf8_lossy(&body[..end]));
+            body = &body[end..];
+        }
+        if let Some(done) = find(body, b"\nScript done on ") {
+            body = &body[..done + 1];
+        }
+        let (width, height) = size.unwrap_or_else(|| terminal::size().unwrap_or((80, 24)));
+
+        let mut events = Vec::new();
+        match timing {
+            Some(timing) => {
+                let mut time = 0.0;
+                let mut rest = body;
+                for line in timing.lines().filter(|line| !line.trim().is_empty()) {
+                    let mut fields: Vec<&str> = line.split_whitespace().collect();
+                    // Advanced format: only output is in the typescript
+                    match fields.first() {
+                        Some(&"O") => {
+                            fields.remove(0);
+                        }
+                        Some(&("I" | "S" | "H")) => continue,
+                        _ => {}
+                    }
+                    let parsed = match fields[..] {
+                        [delay, bytes] => {
+                            delay.parse::<f64>().ok().zip(bytes.parse::<usize>().ok())
+                        }
+                        _ => None,
+                    };
+                    let Some((delay, bytes)) = parsed else {
+                        return Err(NdsError::RecordingError(format!(
+                            "bad timing line '{}'",
+                            line
+                        )));
+                    };
+                    time += delay;
+                    let (chunk, after) = rest.split_at(bytes.min(rest.len()));
+                    events.push((time, CastEvent::Output(lossy(chunk))));
+                    rest = after;
+                }
+                if !rest.is_empty() {
+                    events.push((time, CastEvent::Output(lossy(rest))));
+                }
+            }
+            None => events.push((0.0, CastEvent::Output(lossy(body)))),
+        }
+
+        Ok(Self {
+            width,
+            height,
+            title: None,
+            events,
+        })
+    }
+
+    /// Cap pauses between events at `limit` seconds, like `asciinema play -i`
+    pub fn limit_idle(&mut self, limit: f64) {
+        let mut last = 0.0;
+        let mut shift = 0.0;
+        for (time, _) in &mut self.events {
+            let gap = *time - last;
+            last = *time;
+            if gap > limit {
+                shift += gap - limit;
+            }
+            *time -= shift;
+        }
+    }
+
+    /// Seconds from the start to the last event
+    pub fn duration(&self) -> f64 {
+        self.events.last().map_or(0.0, |(time, _)| *time)
+    }
+}
+
+/// The COLUMNS and LINES `script` notes on its start line
+fn script_size(line: &str) -> Option<(u16, u16)> {
+    let value = |key: &str| -> Option<u16> {
+        let start = line.find(key)? + key.len();
+        let rest = line[start..].trim_start_matches('"');
+        let digits: String = rest.chars().take_while(char::is_ascii_digit).collect();
+        digits.parse().ok()
+    };
+    Some((value("COLUMNS=")?, value("LINES=")?))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn lossy(data: &[u8]) -> String {
+    String::from_utf8_lossy(data).into_owned()
+}
+
+/// Minutes and seconds, e.g. `3:07`
+fn format_time(seconds: f64) -> String {
+    let seconds = seconds.max(0.0) as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// Plays a recording back in the terminal, drawing it the way the
+/// scrollback viewer draws output, with pausing, seeking and speed control
+pub struct Player {
+    cast: Cast,
+    screen: Screen,
+    /// Index of the next event to play
+    next: usize,
+    /// Where playback is, in seconds of the recording
+    position: f64,
+    speed: f64,
+    paused: bool,
+    /// Shown in the status bar until the next key
+    notice: Option<String>,
+}
+
+impl Player {
+    pub fn new(cast: Cast, speed: f64) -> Self {
+        let screen = Screen::new(cast.width, cast.height);
+        Self {
+            cast,
+            screen,
+            next: 0,
+            position: 0.0,
+            speed: speed.clamp(MIN_SPEED, MAX_SPEED),
+            paused: false,
+            notice: None,
+        }
+    }
+
+    /// Play events up to `time`, starting over from a blank screen to go back
+    fn seek(&mut self, time: f64) {
+        let time = time.clamp(0.0, self.cast.duration());
+        if time < self.position {
+            self.screen = Screen::new(self.cast.width, self.cast.height);
+            self.next = 0;
+        }
+        while let Some((at, event)) = self.cast.events.get(self.next) {
+            if *at > time {
+                break;
+            }
+            match event {
+                CastEvent::Output(data) => self.screen.process(data.as_bytes()),
+                CastEvent::Resize(cols, rows) => self.screen.resize(*cols, *rows),
+            }
+            self.next += 1;
+        }
+        self.position = time;
+    }
+
+    fn at_end(&self) -> bool {
+        self.next >= self.cast.events.len()
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        let mut stdout = io::stdout();
+        terminal::enable_raw_mode()?;
+        execute!(stdout, EnterAlternateScreen)?;
+        let backend = CrosstermBackend::new(stdout);
+        let mut terminal = Terminal::new(backend)?;
+
+        let result = self.event_loop(&mut terminal);
+
+        terminal::disable_raw_mode()?;
+        execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+        terminal.show_cursor()?;
+
+        result
+    }
+
+    fn event_loop<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<()> {
+        // The first event, usually at 0, is on screen from the start
+        self.seek(0.0);
+        let mut clock = Instant::now();
+        loop {
+            if !self.paused {
+                let elapsed = clock.elapsed().as_secs_f64() * self.speed;
+                self.seek(self.position + elapsed);
+                if self.at_end() {
+                    self.paused = true;
+                    self.notice = Some("End of recording".to_string());
+                }
+            }
+            clock = Instant::now();
+            terminal.draw(|f| self.draw(f))?;
+
+            // Sleep until the next event is due, or a key comes
+            let wait = match self.cast.events.get(self.next) {
+                Some((at, _)) if !self.paused => {
+                    Duration::from_secs_f64(((at - self.position) / self.speed).max(0.0))
+                        .min(FRAME_INTERVAL)
+                }
+                _ => FRAME_INTERVAL,
+            };
+            if event::poll(wait)? {
+                if let Event::Key(key) = event::read()? {
+                    self.notice = None;
+                    // The time up to the key counts, paused or not
+                    if !self.paused {
+                        let elapsed = clock.elapsed().as_secs_f64() * self.speed;
+                        self.seek(self.position + elapsed);
+                    }
+                    clock = Instant::now();
+                    if self.handle_key(key) {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Act on a key; returns true to quit
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return true,
+            KeyCode::Char(' ') | KeyCode::Char('p') => {
+                if self.at_end() {
+                    // Play again from the start
+                    self.seek(0.0);
+                }
+                self.paused = !self.paused;
+            }
+            KeyCode::Right | KeyCode::Char('l') => self.seek(self.position + SEEK_STEP),
+            KeyCode::Left | KeyCode::Char('h') => self.seek(self.position - SEEK_STEP),
+            KeyCode::PageDown | KeyCode::Char('f') => self.seek(self.position + SEEK_PAGE),
+            KeyCode::PageUp | KeyCode::Char('b') => self.seek(self.position - SEEK_PAGE),
+            KeyCode::Home | KeyCode::Char('g') => self.seek(0.0),
+            KeyCode::End | KeyCode::Char('G') => self.seek(self.cast.duration()),
+            KeyCode::Char('.') => {
+                // Step through one event at a time while paused
+                self.paused = true;
+                if let Some((at, _)) = self.cast.events.get(self.next) {
+                    self.seek(*at);
+                }
+            }
+            KeyCode::Char('+') | KeyCode::Char('=') => {
+                self.speed = (self.speed * 2.0).min(MAX_SPEED);
+            }
+            KeyCode::Char('-') => self.speed = (self.speed / 2.0).max(MIN_SPEED),
+            _ => {}
+        }
+        false
+    }
+
+    fn draw(&self, f: &mut Frame) {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Min(1),
+                Constraint::Length(1),
+                Constraint::Length(1),
+            ])
+            .split(f.area());
+
+        // The recording's screen from the top left, cut to fit
+        let (_, rows) = self.screen.size();
+        let lines: Vec<Line> = (0..rows.min(chunks[0].height))
+            .map(|row| ansi_to_line(&self.screen.row_ansi(row)))
+            .collect();
+        f.render_widget(Paragraph::new(lines), chunks[0]);
+        let (row, col) = self.screen.cursor();
+        if self.screen.cursor_visible() && row < chunks[0].height && col < chunks[0].width {
+            f.set_cursor_position((chunks[0].x + col, chunks[0].y + row));
+        }
+
+        let duration = self.cast.duration();
+        let mut status = format!(
+            "{}{} / {} ({}%)  {}x",
+            self.cast
+                .title
+                .as_ref()
+                .map(|title| format!("{}  ", title))
+                .unwrap_or_default(),
+            format_time(self.position),
+            format_time(duration),
+            if duration > 0.0 {
+                (self.position * 100.0 / duration) as u64
+            } else {
+                100
+            },
+            self.speed
+        );
+        if self.paused {
+            status.push_str("  [paused]");
+        }
+        if let Some(notice) = &self.notice {
+            status = format!("{}  {}", status, notice);
+        }
+        let status = Paragraph::new(status).style(Theme::current().status_bar);
+        f.render_widget(status, chunks[1]);
+
+        let help = "space:pause ←/→:5s PgUp/PgDn:1m g/G:start/end .:step +/-:speed q:quit";
+        let help = Paragraph::new(help).style(Theme::current().dim);
+        f.render_widget(help, chunks[2]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAST: &str = r#"{"version": 2, "width": 20, "height": 3, "title": "demo"}
+[0.5, "o", "one\r\n"]
+[1.0, "i", "typed"]
+[10.0, "o", "two\r\n"]
+[12.0, "r", "30x4"]
+[12.5, "o", "three"]
+"#;
+
+    #[test]
+    fn parses_asciicast_and_seeks_both_ways() {
+        let cast = Cast::parse_asciicast(CAST).unwrap();
+        assert_eq!((cast.width, cast.height), (20, 3));
+        assert_eq!(cast.title.as_deref(), Some("demo"));
+        assert_eq!(cast.events.len(), 4);
+        assert_eq!(cast.events[2], (12.0, CastEvent::Resize(30, 4)));
+
+        let mut player = Player::new(cast, 1.0);
+        player.seek(11.0);
+        assert_eq!(player.screen.row_text(1), "two");
+        player.seek(13.0);
+        assert_eq!(player.screen.size(), (30, 4));
+        assert_eq!(player.screen.row_text(2), "three");
+        assert!(player.at_end());
+        // Going back replays from a blank screen
+        player.seek(1.0);
+        assert_eq!(player.screen.row_text(0), "one");
+        assert_eq!(player.screen.row_text(1), "");
+        assert_eq!(player.screen.size(), (20, 3));
+    }
+
+    #[test]
+    fn limits_idle_time() {
+        let mut cast = Cast::parse_asciicast(CAST).unwrap();
+        cast.limit_idle(2.0);
+        let times: Vec<f64> = cast.events.iter().map(|(time, _)| *time).collect();
+        assert_eq!(times, vec![0.5, 2.5, 4.5, 5.0]);
+    }
+
+    #[test]
+    fn parses_typescript_with_timing() {
+        let script = b"Script started on 2026-10-18 [TERM=\"xterm\" COLUMNS=\"100\" LINES=\"30\"]\nab\r\ncd\r\n\nScript done on 2026-10-18 [COMMAND_EXIT_CODE=\"0\"]\n";
+        let cast = Cast::parse_typescript(script, Some("0.25 4\n1.5 5\n")).unwrap();
+        let advanced = "H 0 START_TIME x\nO 0.25 4\nI 0.5 3\nO 1.5 5\n";
+        assert_eq!(
+            Cast::parse_typescript(script, Some(advanced))
+                .unwrap()
+                .events,
+            cast.events
+        );
+        assert_eq!((cast.width, cast.height), (100, 30));
+        assert_eq!(
+            cast.events,
+            vec![
+                (0.25, CastEvent::Output("ab\r\n".to_string())),
+                (1.75, CastEvent::Output("cd\r\n\n".to_string())),
+            ]
+        );
+
+        let cast = Cast::parse_typescript(b"plain", None).unwrap();
+        assert_eq!(
+            cast.events,
+            vec![(0.0, CastEvent::Output("plain".to_string()))]
+        );
+        assert!(Cast::parse_asciicast("{\"version\": 1}").is_err());
+    }
+}
@@ -0,0 +1,130 @@
+//! Which session a command-line argument means.
+//!
+//! Everything that takes a session — `attach`, `kill`, `info`, the server's
+//! JSON-RPC methods, the picker — goes through `resolve`, so they all read
+//! `3`, `ab12` and `build` the same way.
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{NdsError, Result};
+use crate::session::Session;
+
+/// How arguments are matched, in config.json as
+/// `"matching": { "exact_wins": true, "ignore_id_case": false }`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResolveOptions {
+    /// A name equal to the argument picks that session even when other
+    /// names start with it, so `web` beats `web-2` (default: on)
+    pub exact_wins: bool,
+    /// Match IDs without regard to case, for IDs typed from a screenshot or
+    /// a ticket in capitals (default: off)
+    pub ignore_id_case: bool,
+}
+
+impl Default for ResolveOptions {
+    fn default() -> Self {
+        Self {
+            exact_wins: true,
+            ignore_id_case: false,
+        }
+    }
+}
+
+/// What an argument matched on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MatchKind {
+    /// A bare number, the session's index
+    Index,
+    /// The whole ID
+    Id,
+    /// The start of the ID
+    IdPrefix,
+    /// The whole name, ignoring case
+    Name,
+    /// The start of the name, ignoring case
+    NamePrefix,
+}
+
+/// The one session an argument picked, and how
+#[derive(Debug, Clone)]
+pub struct ResolvedSession {
+    pub session: Session,
+    pub matched_by: MatchKind,
+}
+
+/// The session in `sessions` that `query` means.
+///
+/// Tried in order: a bare number as an index, the whole ID, the whole name
+/// (with `exact_wins`), the start of IDs, then the start of names. The
+/// first step that matches anything decides; if it matched more than one
+/// session the result is `AmbiguousSession` with all of them.
+pub fn resolve(
+    query: &str,
+    sessions: &[Session],
+    options: &ResolveOptions,
+) -> Result<ResolvedSession> {
+    let id_eq = |id: &str| {
+        if options.ignore_id_case {
+            id.eq_ignore_ascii_case(query)
+        } else {
+            id == query
+        }
+    };
+    let id_prefix = |id: &str| {
+        id.get(..query.len()).is_some_and(|start| {
+            if options.ignore_id_case {
+                start.eq_ignore_ascii_case(query)
+            } else {
+                start == query
+            }
+        })
+    };
+    let lower = query.to_lowercase();
+    let name_eq = |name: &str| name.to_lowercase() == lower;
+    let name_prefix = |name: &str| name.to_lowercase().starts_with(&lower);
+
+    if let Some(session) = Session::find_by_index(query, sessions) {
+        return Ok(ResolvedSession {
+            session: session.clone(),
+            matched_by: MatchKind::Index,
+        });
+    }
+
+    let by_id = |matches: &dyn Fn(&str) -> bool| -> Vec<&Session> {
+        sessions.iter().filter(|s| matches(&s.id)).collect()
+    };
+    let by_name = |matches: &dyn Fn(&str) -> bool| -> Vec<&Session> {
+        sessions
+            .iter()
+            .filter(|s| s.name.as_deref().is_some_and(matches))
+            .collect()
+    };
+
+    let mut steps: Vec<(MatchKind, Vec<&Session>)> = vec![(MatchKind::Id, by_id(&id_eq))];
+    if options.exact_wins {
+        steps.push((MatchKind::Name, by_name(&name_eq)));
+    }
+    steps.push((MatchKind::IdPrefix, by_id(&id_prefix)));
+    steps.push((MatchKind::NamePrefix, by_name(&name_prefix)));
+
+    for (matched_by, matches) in steps {
+        match matches[..] {
+            [] => continue,
+            [session] => {
+                return Ok(ResolvedSession {
+                    session: session.clone(),
+                    matched_by,
+                })
+            }
+            _ => {
+                return Err(NdsError::AmbiguousSession {
+                    query: query.to_string(),
+                    matches: matches.into_iter().cloned().collect(),
+                })
+            }
+        }
+    }
+    Err(NdsError::SessionNotFound(query.to_string()))
+}
@@ -1,163 +1,1110 @@
+use base64::Engine;
 use crossterm::{
-    cursor::{Hide, MoveTo, Show},
-    event::{self, Event, KeyCode, KeyEvent},
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, MouseButton,
+        MouseEvent, MouseEventKind,
+    },
     execute,
-    style::{Color, Print, ResetColor, SetForegroundColor},
-    terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
+    terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
 };
+use ratatui::{
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Direction, Layout},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::Paragraph,
+    Frame, Terminal,
+};
+use regex::Regex;
 use std::io::{self, Write};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 use crate::error::Result;
+use crate::keymap::{Action, Bindings, CopyAction, KeyMap, ViewerAction};
+use crate::scrollback_buffer::{
+    is_printable, pieces, plain_text, Piece, ScrollbackBuffer, ScrollbackPage,
+};
+use crate::ui::Theme;
+
+/// Where the viewer gets its lines from, a page at a time
+pub trait ScrollbackSource {
+    /// Up to `count` lines from line number `from` on (see `ScrollbackPage`)
+    fn page(&mut self, from: u64, count: usize) -> Result<ScrollbackPage>;
+
+    /// Number of the first line from `from` on (or back, unless `forward`)
+    /// that matches `pattern`
+    fn search(&mut self, pattern: &Regex, from: u64, forward: bool) -> Result<Option<u64>>;
+
+    /// Number of the first line from `from` on (or back, unless `forward`)
+    /// that a prompt started on, as marked by shell integration
+    fn prompt(&mut self, from: u64, forward: bool) -> Result<Option<u64>>;
+
+    /// Keep text yanked in copy mode for `nds paste`. Sources that don't
+    /// belong to a session have nowhere to keep it.
+    fn set_paste_buffer(&mut self, _text: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Most text copy mode yanks at once, so it fits in one frame to the daemon
+pub const MAX_COPY_BYTES: usize = 512 * 1024;
+
+/// Lines a turn of the mouse wheel scrolls
+const WHEEL_LINES: u64 = 3;
+
+/// Lines asked for at a time when saving scrollback; pages also stop short
+/// of `MAX_PAGE_BYTES`
+const SAVE_PAGE_LINES: usize = 10_000;
+
+/// Write all of `source`'s scrollback to `out`, a line at a time, without
+/// escape sequences if `strip_ansi`. Returns how many lines were written.
+pub fn write_scrollback<S: ScrollbackSource>(
+    source: &mut S,
+    out: &mut dyn Write,
+    strip_ansi: bool,
+) -> Result<u64> {
+    let mut from = 0;
+    let mut written = 0;
+    loop {
+        let page = source.page(from, SAVE_PAGE_LINES)?;
+        if page.lines.is_empty() {
+            break;
+        }
+        for line in &page.lines {
+            if strip_ansi {
+                writeln!(out, "{}", plain_text(line))?;
+            } else {
+                writeln!(out, "{}", line)?;
+            }
+        }
+        written += page.lines.len() as u64;
+        from = page.first + page.lines.len() as u64;
+    }
+    out.flush()?;
+    Ok(written)
+}
+
+impl ScrollbackSource for ScrollbackBuffer {
+    fn page(&mut self, from: u64, count: usize) -> Result<ScrollbackPage> {
+        Ok(ScrollbackBuffer::page(self, from, count))
+    }
+
+    fn search(&mut self, pattern: &Regex, from: u64, forward: bool) -> Result<Option<u64>> {
+        Ok(ScrollbackBuffer::search(self, pattern, from, forward))
+    }
+
+    fn prompt(&mut self, from: u64, forward: bool) -> Result<Option<u64>> {
+        Ok(ScrollbackBuffer::prompt(self, from, forward))
+    }
+}
+
+/// A search being typed after `/` or `?`. Each edit jumps to the first
+/// match from where the search started.
+struct SearchPrompt {
+    text: String,
+    forward: bool,
+    /// Where the viewport was, to go back to if the search is cancelled
+    origin: u64,
+    /// `text` compiled, if it is a valid, non-empty regex
+    pattern: Option<Regex>,
+    found: Option<u64>,
+}
+
+/// Copy mode: a cursor moved vi-style, and where a selection started
+struct CopyMode {
+    line: u64,
+    /// Character on the line, kept while passing shorter lines
+    column: usize,
+    /// Where `v` or `V` started the selection
+    anchor: Option<(u64, usize)>,
+    /// Whether the selection is of whole lines (`V`)
+    whole_lines: bool,
+}
+
+impl CopyMode {
+    /// The selection's first and last characters, in order. Without a
+    /// selection it is the line the cursor is on.
+    fn selection(&self) -> ((u64, usize), (u64, usize)) {
+        let cursor = (self.line, self.column);
+        match self.anchor {
+            Some(anchor) if !self.whole_lines => (anchor.min(cursor), anchor.max(cursor)),
+            Some((line, _)) => ((line.min(self.line), 0), (line.max(self.line), usize::MAX)),
+            None => ((self.line, 0), (self.line, usize::MAX)),
+        }
+    }
+}
 
-pub struct ScrollbackViewer {
+/// Pages through scrollback, fetching only the lines on screen. Opens at the
+/// most recent output.
+pub struct ScrollbackViewer<S> {
+    source: S,
+    /// Lines on screen, from line number `viewport_start`
     lines: Vec<String>,
-    viewport_start: usize,
+    viewport_start: u64,
     viewport_height: usize,
-    total_lines: usize,
+    oldest: u64,
+    end: u64,
+    /// The last search, highlighted on screen and repeated by `n` and `N`
+    search: Option<Regex>,
+    forward: bool,
+    /// Line of the match last jumped to
+    current_match: Option<u64>,
+    prompt: Option<SearchPrompt>,
+    /// Shown in the status bar until the next key
+    notice: Option<String>,
+    /// Show lines without their colors and attributes
+    plain: bool,
+    copy: Option<CopyMode>,
+    /// The file name being typed after `s`
+    save_path: Option<String>,
 }
 
-impl ScrollbackViewer {
-    pub fn new(content: &[u8]) -> Self {
-        // Convert bytes to lines
-        let text = String::from_utf8_lossy(content);
-        let lines: Vec<String> = text.lines().map(|s| s.to_string()).collect();
-        let total_lines = lines.len();
-
+impl<S: ScrollbackSource> ScrollbackViewer<S> {
+    pub fn new(source: S) -> Self {
         // Get terminal height
         let (_, height) = terminal::size().unwrap_or((80, 24));
-        let viewport_height = (height - 3) as usize; // Leave room for status bar
+        let viewport_height = height.saturating_sub(2).max(1) as usize; // Leave room for status bar
 
         ScrollbackViewer {
-            lines,
-            viewport_start: 0,
+            source,
+            lines: Vec::new(),
+            viewport_start: u64::MAX,
             viewport_height,
-            total_lines,
+            oldest: 0,
+            end: 0,
+            search: None,
+            forward: true,
+            current_match: None,
+            prompt: None,
+            notice: None,
+            plain: false,
+            copy: None,
+            save_path: None,
+        }
+    }
+
+    /// Fetch what is on screen, keeping the viewport within the lines kept.
+    /// Starting past the end shows the last page.
+    fn load(&mut self) -> Result<()> {
+        if self.viewport_start > self.end.saturating_sub(self.viewport_height as u64) {
+            // Find out how far the scrollback goes now
+            let page = self.source.page(u64::MAX, 0)?;
+            self.oldest = page.oldest;
+            self.end = page.end;
         }
+        let start = self
+            .viewport_start
+            .min(self.end.saturating_sub(self.viewport_height as u64));
+        let page = self.source.page(start, self.viewport_height)?;
+        self.viewport_start = page.first;
+        self.oldest = page.oldest;
+        self.end = page.end;
+        self.lines = page.lines;
+        Ok(())
+    }
+
+    /// Number of the line at the top of the last page
+    fn last_page(&self) -> u64 {
+        self.end
+            .saturating_sub(self.viewport_height as u64)
+            .max(self.oldest)
     }
 
     pub fn run(&mut self) -> Result<()> {
         // Enter alternate screen
         let mut stdout = io::stdout();
         terminal::enable_raw_mode()?;
-        execute!(stdout, EnterAlternateScreen, Hide)?;
+        execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+        let backend = CrosstermBackend::new(stdout);
+        let mut terminal = Terminal::new(backend)?;
 
-        let result = self.event_loop();
+        let result = self.event_loop(&mut terminal);
 
         // Clean up
-        execute!(stdout, LeaveAlternateScreen, Show)?;
         terminal::disable_raw_mode()?;
+        execute!(
+            terminal.backend_mut(),
+            LeaveAlternateScreen,
+            DisableMouseCapture
+        )?;
+        terminal.show_cursor()?;
 
         result
     }
 
-    fn event_loop(&mut self) -> Result<()> {
-        let mut stdout = io::stdout();
-
+    fn event_loop<B: Backend + Write>(&mut self, terminal: &mut Terminal<B>) -> Result<()> {
         loop {
-            self.draw(&mut stdout)?;
+            // Leave room for the status and help lines
+            let height = terminal.size()?.height;
+            self.viewport_height = height.saturating_sub(2).max(1) as usize;
+            self.load()?;
+            terminal.draw(|f| self.draw(f))?;
 
-            if let Event::Key(key) = event::read()? {
-                match self.handle_key(key) {
-                    true => break, // Exit requested
-                    false => continue,
+            let key = match event::read()? {
+                Event::Key(key) => key,
+                Event::Mouse(mouse) => {
+                    self.handle_mouse(mouse);
+                    continue;
                 }
+                _ => continue,
+            };
+            self.notice = None;
+            if self.prompt.is_some() {
+                self.handle_prompt_key(key)?;
+                continue;
+            }
+            if self.save_path.is_some() {
+                self.handle_save_key(key);
+                continue;
+            }
+            if self.copy.is_some() {
+                if let Some(text) = self.handle_copy_key(key)? {
+                    self.yank(terminal.backend_mut(), &text);
+                }
+                continue;
+            }
+            if self.handle_key(key)? {
+                break; // Exit requested
             }
         }
 
         Ok(())
     }
 
-    fn handle_key(&mut self, key: KeyEvent) -> bool {
-        match key.code {
-            KeyCode::Char('q') | KeyCode::Esc => return true, // Exit
+    fn open_prompt(&mut self, forward: bool) {
+        self.prompt = Some(SearchPrompt {
+            text: String::new(),
+            forward,
+            origin: self.viewport_start,
+            pattern: None,
+            found: None,
+        });
+    }
 
-            // Navigation
-            KeyCode::Up | KeyCode::Char('k') => {
-                if self.viewport_start > 0 {
-                    self.viewport_start -= 1;
+    fn handle_prompt_key(&mut self, key: KeyEvent) -> Result<()> {
+        let Some(mut prompt) = self.prompt.take() else {
+            return Ok(());
+        };
+        match key.code {
+            KeyCode::Esc => {
+                self.viewport_start = prompt.origin;
+                return Ok(());
+            }
+            KeyCode::Enter => {
+                if prompt.text.is_empty() {
+                    // Like less and vim: an empty search repeats the last one
+                    self.forward = prompt.forward;
+                    return self.repeat_search(true);
                 }
+                match Regex::new(&prompt.text) {
+                    Ok(pattern) => {
+                        self.search = Some(pattern);
+                        self.forward = prompt.forward;
+                        self.current_match = prompt.found;
+                        if prompt.found.is_none() {
+                            self.notice = Some(format!("Pattern not found: {}", prompt.text));
+                        }
+                    }
+                    Err(e) => self.notice = Some(format!("Invalid pattern: {}", e)),
+                }
+                return Ok(());
             }
-            KeyCode::Down | KeyCode::Char('j') => {
-                if self.viewport_start + self.viewport_height < self.total_lines {
-                    self.viewport_start += 1;
+            KeyCode::Backspace => {
+                if prompt.text.pop().is_none() {
+                    // Backspacing over the `/` cancels, as in less
+                    self.viewport_start = prompt.origin;
+                    return Ok(());
                 }
             }
-            KeyCode::PageUp | KeyCode::Char('b') => {
-                self.viewport_start = self.viewport_start.saturating_sub(self.viewport_height);
+            KeyCode::Char(c) => prompt.text.push(c),
+            _ => {
+                self.prompt = Some(prompt);
+                return Ok(());
             }
-            KeyCode::PageDown | KeyCode::Char(' ') | KeyCode::Char('f') => {
-                let new_start = self.viewport_start + self.viewport_height;
-                if new_start + self.viewport_height <= self.total_lines {
-                    self.viewport_start = new_start;
-                } else if self.total_lines > self.viewport_height {
-                    self.viewport_start = self.total_lines - self.viewport_height;
+        }
+
+        // Search as the pattern is typed
+        prompt.pattern = Regex::new(&prompt.text)
+            .ok()
+            .filter(|_| !prompt.text.is_empty());
+        prompt.found = match &prompt.pattern {
+            Some(pattern) => self.source.search(pattern, prompt.origin, prompt.forward)?,
+            None => None,
+        };
+        // A match on the last page can't go to the top; `load` keeps it on screen
+        self.viewport_start = prompt.found.unwrap_or(prompt.origin);
+        self.prompt = Some(prompt);
+        Ok(())
+    }
+
+    /// Jump to the next match of the last search in its direction, or the
+    /// other way if `same_direction` is false (`n` and `N`)
+    fn repeat_search(&mut self, same_direction: bool) -> Result<()> {
+        let Some(pattern) = &self.search else {
+            self.notice = Some("No previous search".to_string());
+            return Ok(());
+        };
+        let forward = self.forward == same_direction;
+        let from = match (self.current_match, forward) {
+            (Some(line), true) => line + 1,
+            (Some(line), false) => match line.checked_sub(1) {
+                Some(line) => line,
+                None => {
+                    self.notice = Some("Pattern not found".to_string());
+                    return Ok(());
                 }
+            },
+            // Nothing jumped to yet: search from the top of the screen
+            (None, _) => self.viewport_start,
+        };
+        match self.source.search(pattern, from, forward)? {
+            Some(line) => {
+                self.current_match = Some(line);
+                self.viewport_start = line;
             }
-            KeyCode::Home | KeyCode::Char('g') => {
-                self.viewport_start = 0;
+            None => self.notice = Some(format!("Pattern not found: {}", pattern)),
+        }
+        Ok(())
+    }
+
+    /// Enter copy mode on the match last jumped to, if it's on screen, or
+    /// else the bottom line
+    fn enter_copy_mode(&mut self) {
+        let bottom = self.viewport_start + self.lines.len().saturating_sub(1) as u64;
+        let line = self
+            .current_match
+            .filter(|&line| (self.viewport_start..=bottom).contains(&line))
+            .unwrap_or(bottom);
+        self.copy = Some(CopyMode {
+            line,
+            column: 0,
+            anchor: None,
+            whole_lines: false,
+        });
+    }
+
+    /// Scroll with the wheel. Clicking puts the copy mode cursor on the
+    /// character clicked, and dragging selects up to where the mouse is.
+    fn handle_mouse(&mut self, mouse: MouseEvent) {
+        if self.prompt.is_some() || self.save_path.is_some() {
+            return;
+        }
+        match mouse.kind {
+            MouseEventKind::ScrollUp => {
+                self.viewport_start = self
+                    .viewport_start
+                    .saturating_sub(WHEEL_LINES)
+                    .max(self.oldest);
             }
-            KeyCode::End | KeyCode::Char('G') => {
-                if self.total_lines > self.viewport_height {
-                    self.viewport_start = self.total_lines - self.viewport_height;
+            MouseEventKind::ScrollDown => {
+                self.viewport_start = (self.viewport_start + WHEEL_LINES).min(self.last_page());
+            }
+            MouseEventKind::Down(MouseButton::Left) => {
+                if let Some((line, column)) = self.position_at(mouse.column, mouse.row) {
+                    self.copy = Some(CopyMode {
+                        line,
+                        column,
+                        anchor: None,
+                        whole_lines: false,
+                    });
+                }
+            }
+            MouseEventKind::Drag(MouseButton::Left) => {
+                let row = mouse.row.min(self.lines.len().saturating_sub(1) as u16);
+                let Some((line, column)) = self.position_at(mouse.column, row) else {
+                    return;
+                };
+                let Some(copy) = &mut self.copy else {
+                    return;
+                };
+                if copy.anchor.is_none() {
+                    copy.anchor = Some((copy.line, copy.column));
+                    copy.whole_lines = false;
+                }
+                copy.line = line;
+                copy.column = column;
+                // Dragging onto the top line or past the bottom one scrolls
+                if mouse.row == 0 {
+                    self.viewport_start = self.viewport_start.saturating_sub(1).max(self.oldest);
+                } else if mouse.row as usize >= self.lines.len() {
+                    self.viewport_start = (self.viewport_start + 1).min(self.last_page());
                 }
             }
             _ => {}
         }
-        false
     }
 
-    fn draw(&self, stdout: &mut io::Stdout) -> Result<()> {
-        execute!(stdout, Clear(ClearType::All), MoveTo(0, 0))?;
+    /// The line and character shown at `column`, `row` on the screen, or
+    /// the line's last character if it ends before `column`
+    fn position_at(&self, column: u16, row: u16) -> Option<(u64, usize)> {
+        let text = plain_text(self.lines.get(row as usize)?);
+        let mut right = 0;
+        let mut last = 0;
+        for (i, ch) in text.chars().enumerate() {
+            right += ch.width().unwrap_or(0);
+            if right > column as usize {
+                return Some((self.viewport_start + row as u64, i));
+            }
+            last = i;
+        }
+        Some((self.viewport_start + row as u64, last))
+    }
+
+    /// The text of line `line` if it is on screen
+    fn line_text(&self, line: u64) -> Option<String> {
+        let index = usize::try_from(line.checked_sub(self.viewport_start)?).ok()?;
+        self.lines.get(index).map(|line| plain_text(line))
+    }
 
-        // Draw content
-        let end = (self.viewport_start + self.viewport_height).min(self.total_lines);
-        for i in self.viewport_start..end {
-            execute!(stdout, Print(&self.lines[i]), Print("\r\n"))?;
+    /// Move the cursor or change the selection; returns the text to copy
+    /// once it is yanked
+    fn handle_copy_key(&mut self, key: KeyEvent) -> Result<Option<String>> {
+        let Some(mut copy) = self.copy.take() else {
+            return Ok(None);
+        };
+        let chars: Vec<char> = self
+            .line_text(copy.line)
+            .unwrap_or_default()
+            .chars()
+            .collect();
+        let last_column = chars.len().saturating_sub(1);
+        let last_line = self.end.saturating_sub(1).max(self.oldest);
+        let height = self.viewport_height as u64;
+        match KeyMap::current().copy.action(&key) {
+            Some(CopyAction::Leave) => return Ok(None),
+
+            Some(CopyAction::Left) => {
+                copy.column = copy.column.min(last_column).saturating_sub(1);
+            }
+            Some(CopyAction::Right) => copy.column = (copy.column + 1).min(last_column),
+            Some(CopyAction::Up) => copy.line = copy.line.saturating_sub(1).max(self.oldest),
+            Some(CopyAction::Down) => copy.line = (copy.line + 1).min(last_line),
+            Some(CopyAction::PageUp) => {
+                copy.line = copy.line.saturating_sub(height).max(self.oldest);
+            }
+            Some(CopyAction::PageDown) => copy.line = (copy.line + height).min(last_line),
+            Some(CopyAction::LineStart) => copy.column = 0,
+            Some(CopyAction::LineEnd) => copy.column = last_column,
+            Some(CopyAction::NextWord) => copy.column = next_word(&chars, copy.column),
+            Some(CopyAction::PreviousWord) => copy.column = previous_word(&chars, copy.column),
+            Some(CopyAction::Top) => copy.line = self.oldest,
+            Some(CopyAction::Bottom) => copy.line = last_line,
+
+            // Selection
+            Some(action @ (CopyAction::Select | CopyAction::SelectLines)) => {
+                let whole_lines = action == CopyAction::SelectLines;
+                if copy.anchor.is_some() && copy.whole_lines == whole_lines {
+                    copy.anchor = None;
+                } else {
+                    copy.anchor
+                        .get_or_insert((copy.line, copy.column.min(last_column)));
+                    copy.whole_lines = whole_lines;
+                }
+            }
+            Some(CopyAction::Copy) => {
+                let text = self.selected_text(&copy)?;
+                if text.len() > MAX_COPY_BYTES {
+                    self.notice = Some(format!(
+                        "Selection is too big to copy (over {} KiB)",
+                        MAX_COPY_BYTES / 1024
+                    ));
+                    self.copy = Some(copy);
+                    return Ok(None);
+                }
+                return Ok(Some(text));
+            }
+            _ => {}
         }
 
-        // Fill empty lines if needed
-        let displayed = end - self.viewport_start;
-        for _ in displayed..self.viewport_height {
-            execute!(stdout, Print("~\r\n"))?;
+        // Keep the cursor on screen
+        if copy.line < self.viewport_start {
+            self.viewport_start = copy.line;
+        } else if copy.line >= self.viewport_start + height {
+            self.viewport_start = copy.line + 1 - height;
         }
+        self.copy = Some(copy);
+        Ok(None)
+    }
 
-        // Draw status bar
-        let (width, _) = terminal::size().unwrap_or((80, 24));
-        let position = if self.total_lines == 0 {
-            "Empty".to_string()
+    /// The plain text of the selection, its lines joined with newlines
+    fn selected_text(&mut self, copy: &CopyMode) -> Result<String> {
+        let ((first, start), (last, end)) = copy.selection();
+        let mut lines = Vec::new();
+        let mut next = first;
+        let mut bytes = 0;
+        // A page stops short of long lines, so keep asking until it's all in
+        while next <= last && bytes <= MAX_COPY_BYTES {
+            let count = usize::try_from(last - next + 1).unwrap_or(usize::MAX);
+            let page = self.source.page(next, count)?;
+            if page.lines.is_empty() {
+                break;
+            }
+            next = page.first + page.lines.len() as u64;
+            for (number, line) in (page.first..).zip(page.lines) {
+                let chars: Vec<char> = plain_text(&line).chars().collect();
+                let from = if number == first { start } else { 0 };
+                let to = if number == last {
+                    end.saturating_add(1)
+                } else {
+                    usize::MAX
+                };
+                let text: String = chars[from.min(chars.len())..to.min(chars.len())]
+                    .iter()
+                    .collect();
+                bytes += text.len() + 1;
+                lines.push(text);
+            }
+        }
+        Ok(lines.join("\n"))
+    }
+
+    /// Copy yanked text to the terminal's clipboard with OSC 52 and to the
+    /// session's paste buffer, then leave copy mode
+    fn yank(&mut self, out: &mut impl Write, text: &str) {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(text);
+        let _ = write!(out, "\x1b]52;c;{}\x07", encoded);
+        let _ = out.flush();
+        let lines = text.lines().count().max(1);
+        self.notice = Some(match self.source.set_paste_buffer(text) {
+            Ok(()) if lines == 1 => format!("Copied {} characters", text.chars().count()),
+            Ok(()) => format!("Copied {} lines", lines),
+            Err(e) => format!("Copied to the clipboard only: {}", e),
+        });
+        self.copy = None;
+    }
+
+    /// Edit the file name typed after `s`, and save there on Enter
+    fn handle_save_key(&mut self, key: KeyEvent) {
+        let Some(mut path) = self.save_path.take() else {
+            return;
+        };
+        match key.code {
+            KeyCode::Esc => return,
+            KeyCode::Enter if !path.is_empty() => {
+                self.notice = Some(match self.save(&path) {
+                    Ok(lines) => format!("Saved {} lines to {}", lines, path),
+                    Err(e) => format!("Failed to save to {}: {}", path, e),
+                });
+                return;
+            }
+            KeyCode::Backspace => {
+                path.pop();
+            }
+            KeyCode::Char(c) => path.push(c),
+            _ => {}
+        }
+        self.save_path = Some(path);
+    }
+
+    /// Save all of the scrollback to `path`, as plain text if that is what
+    /// is being shown
+    fn save(&mut self, path: &str) -> Result<u64> {
+        let mut file = io::BufWriter::new(std::fs::File::create(path)?);
+        write_scrollback(&mut self.source, &mut file, self.plain)
+    }
+
+    /// Jump to the prompt of the next command below the top of the screen,
+    /// or the one above it (`]` and `[`)
+    fn jump_to_prompt(&mut self, forward: bool) -> Result<()> {
+        let from = if forward {
+            self.viewport_start + 1
         } else {
-            let percent = if self.total_lines <= self.viewport_height {
-                100
+            match self.viewport_start.checked_sub(1) {
+                Some(line) => line,
+                None => return Ok(()),
+            }
+        };
+        match self.source.prompt(from, forward)? {
+            Some(line) => self.viewport_start = line,
+            None => {
+                let which = if forward { "next" } else { "previous" };
+                self.notice = Some(format!(
+                    "No {} prompt (prompts are marked with `nds shell-init`)",
+                    which
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Result<bool> {
+        let height = self.viewport_height as u64;
+        let Some(action) = KeyMap::current().viewer.action(&key) else {
+            return Ok(false);
+        };
+        match action {
+            ViewerAction::Quit => return Ok(true),
+
+            // Search
+            ViewerAction::Search => self.open_prompt(true),
+            ViewerAction::SearchBackward => self.open_prompt(false),
+            ViewerAction::NextMatch => self.repeat_search(true)?,
+            ViewerAction::PreviousMatch => self.repeat_search(false)?,
+            ViewerAction::Plain => self.plain = !self.plain,
+            ViewerAction::CopyMode => self.enter_copy_mode(),
+            ViewerAction::NextPrompt => self.jump_to_prompt(true)?,
+            ViewerAction::PreviousPrompt => self.jump_to_prompt(false)?,
+            ViewerAction::Save => {
+                let name = chrono::Local::now().format("nds-scrollback-%Y%m%d-%H%M%S.log");
+                self.save_path = Some(name.to_string());
+            }
+
+            // Navigation
+            ViewerAction::Up => {
+                self.viewport_start = self.viewport_start.saturating_sub(1).max(self.oldest);
+            }
+            ViewerAction::Down => {
+                self.viewport_start = (self.viewport_start + 1).min(self.last_page());
+            }
+            ViewerAction::PageUp => {
+                self.viewport_start = self.viewport_start.saturating_sub(height).max(self.oldest);
+            }
+            ViewerAction::PageDown => {
+                self.viewport_start = (self.viewport_start + height).min(self.last_page());
+            }
+            ViewerAction::Top => {
+                self.viewport_start = self.oldest;
+            }
+            ViewerAction::Bottom => {
+                // Also picks up whatever was written since
+                self.viewport_start = u64::MAX;
+            }
+        }
+        Ok(false)
+    }
+
+    /// Line `number`, `line`, as it is shown: in its own colors unless plain
+    /// text was asked for, with what `pattern` matches and the copy mode
+    /// selection highlighted
+    fn render_line(
+        &self,
+        number: u64,
+        line: &str,
+        pattern: Option<&Regex>,
+        current: bool,
+    ) -> Line<'static> {
+        let mut cells = if self.plain {
+            plain_text(line)
+                .chars()
+                .map(|ch| (ch, Style::default()))
+                .collect()
+        } else {
+            styled_chars(line)
+        };
+        if let Some(pattern) = pattern {
+            // The match jumped to stands out from the rest
+            let theme = Theme::current();
+            let highlight = if current {
+                theme.search_current
             } else {
-                ((self.viewport_start + self.viewport_height) * 100 / self.total_lines).min(100)
+                theme.search_match
             };
+            let text: String = cells.iter().map(|&(ch, _)| ch).collect();
+            let starts: Vec<usize> = text.char_indices().map(|(at, _)| at).collect();
+            for found in pattern.find_iter(&text).filter(|m| !m.is_empty()) {
+                let first = starts.partition_point(|&at| at < found.start());
+                let last = starts.partition_point(|&at| at < found.end());
+                for (_, style) in &mut cells[first..last] {
+                    *style = style.patch(highlight);
+                }
+            }
+        }
+        if let Some(copy) = &self.copy {
+            let ((first, start), (last, end)) = copy.selection();
+            if copy.anchor.is_some() && (first..=last).contains(&number) {
+                let from = if number == first { start } else { 0 };
+                let to = if number == last {
+                    end.saturating_add(1)
+                } else {
+                    usize::MAX
+                };
+                let selected = Theme::current().copy_selection;
+                for (_, style) in cells.iter_mut().take(to).skip(from) {
+                    *style = style.patch(selected);
+                }
+            }
+            if copy.line == number {
+                if cells.is_empty() {
+                    cells.push((' ', Style::default()));
+                }
+                let column = copy.column.min(cells.len() - 1);
+                cells[column].1 = cells[column].1.add_modifier(Modifier::REVERSED);
+            }
+        }
+        cells_to_line(cells)
+    }
+
+    fn draw(&self, f: &mut Frame) {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Min(1),
+                Constraint::Length(1),
+                Constraint::Length(1),
+            ])
+            .split(f.area());
+
+        // Content, highlighting the search being typed or the last one
+        let pattern = match &self.prompt {
+            Some(prompt) => prompt.pattern.as_ref(),
+            None => self.search.as_ref(),
+        };
+        let current = self.prompt.as_ref().map_or(self.current_match, |p| p.found);
+        let mut lines: Vec<Line> = (self.viewport_start..)
+            .zip(&self.lines)
+            .map(|(number, line)| self.render_line(number, line, pattern, current == Some(number)))
+            .collect();
+        // Fill empty lines if needed
+        lines.resize(self.viewport_height.max(lines.len()), Line::from("~"));
+        f.render_widget(Paragraph::new(lines), chunks[0]);
+
+        // Status bar, counting lines from the oldest one kept
+        let total_lines = self.end - self.oldest;
+        let mut position = if total_lines == 0 {
+            "Empty".to_string()
+        } else {
+            let start = self.viewport_start - self.oldest;
+            let end = start + self.lines.len() as u64;
             format!(
                 "Lines {}-{}/{} ({}%)",
-                self.viewport_start + 1,
+                start + 1,
                 end,
-                self.total_lines,
-                percent
+                total_lines,
+                (end * 100 / total_lines).min(100)
             )
         };
+        if self.plain {
+            position.push_str("  [plain]");
+        }
+        match &self.copy {
+            Some(CopyMode { anchor: None, .. }) => position.push_str("  -- COPY --"),
+            Some(CopyMode {
+                whole_lines: false, ..
+            }) => position.push_str("  -- VISUAL --"),
+            Some(_) => position.push_str("  -- VISUAL LINE --"),
+            None => {}
+        }
+        if let Some(notice) = &self.notice {
+            position = format!("{}  {}", position, notice);
+        }
+        let status = Paragraph::new(position).style(Theme::current().status_bar);
+        f.render_widget(status, chunks[1]);
 
-        execute!(
-            stdout,
-            SetForegroundColor(Color::Black),
-            crossterm::style::SetBackgroundColor(Color::White),
-            Print(format!("{:<width$}", position, width = width as usize)),
-            ResetColor,
-            Print("\r\n")
-        )?;
+        // The search or file name being typed, or the help line
+        let typing = match (&self.prompt, &self.save_path) {
+            (Some(prompt), _) => {
+                let slash = if prompt.forward { '/' } else { '?' };
+                Some(format!("{}{}", slash, prompt.text))
+            }
+            (None, Some(path)) => Some(format!("Save to: {}", path)),
+            (None, None) => None,
+        };
+        if let Some(text) = typing {
+            let cursor = chunks[2].x + UnicodeWidthStr::width(text.as_str()) as u16;
+            f.render_widget(Paragraph::new(text), chunks[2]);
+            f.set_cursor_position((cursor.min(chunks[2].right().saturating_sub(1)), chunks[2].y));
+        } else {
+            let keymap = KeyMap::current();
+            let help = if self.copy.is_some() {
+                help_text(&keymap.copy, COPY_HELP)
+            } else {
+                help_text(&keymap.viewer, VIEWER_HELP)
+            };
+            let help = Paragraph::new(help).style(Theme::current().dim);
+            f.render_widget(help, chunks[2]);
+        }
+    }
+}
 
-        // Draw help line
-        execute!(
-            stdout,
-            SetForegroundColor(Color::DarkGrey),
-            Print("↑/k:up ↓/j:down PgUp/b:page-up PgDn/f:page-down g:top G:bottom q:quit"),
-            ResetColor
-        )?;
+/// What the viewer's keys do, for the help line
+const VIEWER_HELP: &[(ViewerAction, &str)] = &[
+    (ViewerAction::Up, "up"),
+    (ViewerAction::Down, "down"),
+    (ViewerAction::PageUp, "page-up"),
+    (ViewerAction::PageDown, "page-down"),
+    (ViewerAction::Top, "top"),
+    (ViewerAction::Bottom, "bottom"),
+    (ViewerAction::Search, "search"),
+    (ViewerAction::SearchBackward, "search-back"),
+    (ViewerAction::NextMatch, "next"),
+    (ViewerAction::PreviousMatch, "prev"),
+    (ViewerAction::PreviousPrompt, "prev-prompt"),
+    (ViewerAction::NextPrompt, "next-prompt"),
+    (ViewerAction::CopyMode, "copy"),
+    (ViewerAction::Save, "save"),
+    (ViewerAction::Plain, "plain"),
+    (ViewerAction::Quit, "quit"),
+];
 
-        stdout.flush()?;
-        Ok(())
+/// What copy mode's keys do, for the help line
+const COPY_HELP: &[(CopyAction, &str)] = &[
+    (CopyAction::Left, "left"),
+    (CopyAction::Down, "down"),
+    (CopyAction::Up, "up"),
+    (CopyAction::Right, "right"),
+    (CopyAction::NextWord, "word"),
+    (CopyAction::PreviousWord, "word-back"),
+    (CopyAction::LineStart, "start"),
+    (CopyAction::LineEnd, "end"),
+    (CopyAction::Top, "top"),
+    (CopyAction::Bottom, "bottom"),
+    (CopyAction::Select, "select"),
+    (CopyAction::SelectLines, "select-lines"),
+    (CopyAction::Copy, "copy"),
+    (CopyAction::Leave, "leave"),
+];
+
+/// A help line of `keys:what` pairs under the keys `bindings` gives each
+/// action, leaving out actions with no key
+fn help_text<A: Action>(bindings: &Bindings<A>, actions: &[(A, &str)]) -> String {
+    let pairs: Vec<String> = actions
+        .iter()
+        .map(|(action, what)| (bindings.label(*action), what))
+        .filter(|(keys, _)| !keys.is_empty())
+        .map(|(keys, what)| format!("{}:{}", keys, what))
+        .collect();
+    pairs.join(" ")
+}
+
+/// Column of the start of the next word after `column`, as vi's `w` on one
+/// line
+fn next_word(chars: &[char], column: usize) -> usize {
+    let mut at = column;
+    while at < chars.len() && !chars[at].is_whitespace() {
+        at += 1;
+    }
+    while at < chars.len() && chars[at].is_whitespace() {
+        at += 1;
+    }
+    at.min(chars.len().saturating_sub(1))
+}
+
+/// Column of the start of the word before `column`, as vi's `b`
+fn previous_word(chars: &[char], column: usize) -> usize {
+    let mut at = column.min(chars.len());
+    while at > 0 && chars[at - 1].is_whitespace() {
+        at -= 1;
+    }
+    while at > 0 && !chars[at - 1].is_whitespace() {
+        at -= 1;
+    }
+    at
+}
+
+/// Parse a line of output into characters and the style SGR sequences gave
+/// them; other escape sequences and control characters are dropped. The
+/// characters are those of `plain_text(line)`.
+pub fn styled_chars(line: &str) -> Vec<(char, Style)> {
+    let mut cells = Vec::new();
+    let mut style = Style::default();
+    for piece in pieces(line) {
+        match piece {
+            Piece::Text(text) => cells.extend(
+                text.chars()
+                    .filter(|&ch| is_printable(ch))
+                    .map(|ch| (ch, style)),
+            ),
+            Piece::Csi(params, 'm') => style = apply_sgr(style, params),
+            _ => {}
+        }
+    }
+    cells
+}
+
+/// A line of output as ratatui spans, in the colors and attributes it was
+/// written with
+pub fn ansi_to_line(line: &str) -> Line<'static> {
+    cells_to_line(styled_chars(line))
+}
+
+/// Group styled characters into spans, expanding tabs to the next multiple
+/// of 8 columns
+fn cells_to_line(cells: Vec<(char, Style)>) -> Line<'static> {
+    let mut spans: Vec<Span<'static>> = Vec::new();
+    let mut text = String::new();
+    let mut current = Style::default();
+    let mut column = 0;
+    for (ch, style) in cells {
+        if style != current && !text.is_empty() {
+            spans.push(Span::styled(std::mem::take(&mut text), current));
+        }
+        current = style;
+        if ch == '\t' {
+            let spaces = 8 - column % 8;
+            text.extend(std::iter::repeat_n(' ', spaces));
+            column += spaces;
+        } else {
+            text.push(ch);
+            column += ch.width().unwrap_or(0);
+        }
+    }
+    if !text.is_empty() {
+        spans.push(Span::styled(text, current));
+    }
+    Line::from(spans)
+}
+
+/// `style` after the SGR sequence with parameters `params` (`1;31` etc.)
+fn apply_sgr(mut style: Style, params: &str) -> Style {
+    // Sub-parameters (`38:2::255:0:0`) are read like parameters
+    let mut params = params
+        .split([';', ':'])
+        .map(|p| p.parse::<u16>().unwrap_or(0));
+    // An empty sequence, `ESC [ m`, resets
+    let mut next = Some(params.next().unwrap_or(0));
+    while let Some(param) = next {
+        style = match param {
+            0 => Style::default(),
+            1 => style.add_modifier(Modifier::BOLD),
+            2 => style.add_modifier(Modifier::DIM),
+            3 => style.add_modifier(Modifier::ITALIC),
+            4 => style.add_modifier(Modifier::UNDERLINED),
+            5 => style.add_modifier(Modifier::SLOW_BLINK),
+            6 => style.add_modifier(Modifier::RAPID_BLINK),
+            7 => style.add_modifier(Modifier::REVERSED),
+            8 => style.add_modifier(Modifier::HIDDEN),
+            9 => style.add_modifier(Modifier::CROSSED_OUT),
+            22 => style.remove_modifier(Modifier::BOLD | Modifier::DIM),
+            23 => style.remove_modifier(Modifier::ITALIC),
+            24 => style.remove_modifier(Modifier::UNDERLINED),
+            25 => style.remove_modifier(Modifier::SLOW_BLINK | Modifier::RAPID_BLINK),
+            27 => style.remove_modifier(Modifier::REVERSED),
+            28 => style.remove_modifier(Modifier::HIDDEN),
+            29 => style.remove_modifier(Modifier::CROSSED_OUT),
+            30..=37 => style.fg(ansi_color(param - 30)),
+            38 => match extended_color(&mut params) {
+                Some(color) => style.fg(color),
+                None => style,
+            },
+            39 => Style { fg: None, ..style },
+            40..=47 => style.bg(ansi_color(param - 40)),
+            48 => match extended_color(&mut params) {
+                Some(color) => style.bg(color),
+                None => style,
+            },
+            49 => Style { bg: None, ..style },
+            90..=97 => style.fg(ansi_color(param - 90 + 8)),
+            100..=107 => style.bg(ansi_color(param - 100 + 8)),
+            _ => style,
+        };
+        next = params.next();
+    }
+    style
+}
+
+/// The color `38` or `48` goes on to give: `5;n` from the 256-color palette
+/// or `2;r;g;b`
+fn extended_color(params: &mut impl Iterator<Item = u16>) -> Option<Color> {
+    match params.next()? {
+        5 => Some(Color::Indexed(params.next()? as u8)),
+        2 => {
+            let mut channel = || params.next().map(|c| c as u8);
+            Some(Color::Rgb(channel()?, channel()?, channel()?))
+        }
+        _ => None,
+    }
+}
+
+/// One of the 16 standard colors by its number
+fn ansi_color(n: u16) -> Color {
+    match n {
+        0 => Color::Black,
+        1 => Color::Red,
+        2 => Color::Green,
+        3 => Color::Yellow,
+        4 => Color::Blue,
+        5 => Color::Magenta,
+        6 => Color::Cyan,
+        7 => Color::Gray,
+        8 => Color::DarkGray,
+        9 => Color::LightRed,
+        10 => Color::LightGreen,
+        11 => Color::LightYellow,
+        12 => Color::LightBlue,
+        13 => Color::LightMagenta,
+        14 => Color::LightCyan,
+        _ => Color::White,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ScrollbackViewer;
+    use crate::scrollback_buffer::ScrollbackBuffer;
+    use crossterm::event::{
+        KeyCode, KeyEvent, KeyModifiers, MouseButton, MouseEvent, MouseEventKind,
+    };
+
+    fn press(viewer: &mut ScrollbackViewer<ScrollbackBuffer>, keys: &str) -> Option<String> {
+        let mut yanked = None;
+        for c in keys.chars() {
+            viewer.load().unwrap();
+            let key = KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE);
+            yanked = viewer.handle_copy_key(key).unwrap().or(yanked);
+        }
+        yanked
+    }
+
+    #[test]
+    fn copy_mode_yanks_the_selection_as_plain_text() {
+        let mut scrollback = ScrollbackBuffer::new(1024);
+        scrollback.push(b"$ make\r\n\x1b[31merror:\x1b[0m one two\r\nthree four\r\n$ ");
+        let mut viewer = ScrollbackViewer::new(scrollback);
+        viewer.load().unwrap();
+
+        // From the start of "one" to the end of "three"
+        viewer.enter_copy_mode();
+        assert_eq!(press(&mut viewer, "kkwvj0lll"), None);
+        assert_eq!(press(&mut viewer, "ly").as_deref(), Some("one two\nthree"));
+
+        // Whole lines, without a selection or with `V`
+        viewer.enter_copy_mode();
+        assert_eq!(press(&mut viewer, "ky").as_deref(), Some("three four"));
+        viewer.enter_copy_mode();
+        assert_eq!(
+            press(&mut viewer, "kVky").as_deref(),
+            Some("error: one two\nthree four")
+        );
+    }
+
+    #[test]
+    fn dragging_selects_and_the_wheel_scrolls() {
+        let mut scrollback = ScrollbackBuffer::new(1024);
+        scrollback.push(b"$ make\r\n\x1b[31merror:\x1b[0m one two\r\nthree four\r\n$ ");
+        let mut viewer = ScrollbackViewer::new(scrollback);
+        viewer.viewport_height = 4;
+        viewer.load().unwrap();
+        let mut mouse = |kind, column, row| {
+            viewer.handle_mouse(MouseEvent {
+                kind,
+                column,
+                row,
+                modifiers: KeyModifiers::NONE,
+            });
+            viewer.load().unwrap();
+        };
+
+        // From "one" on the second line to past the end of "three four"
+        mouse(MouseEventKind::Down(MouseButton::Left), 7, 1);
+        mouse(MouseEventKind::Drag(MouseButton::Left), 4, 2);
+        mouse(MouseEventKind::Drag(MouseButton::Left), 40, 2);
+        mouse(MouseEventKind::Up(MouseButton::Left), 40, 2);
+        assert_eq!(
+            press(&mut viewer, "y").as_deref(),
+            Some("one two\nthree four")
+        );
+
+        // Opened at the bottom, the wheel goes up as far as there is
+        viewer.viewport_height = 2;
+        viewer.viewport_start = u64::MAX;
+        viewer.load().unwrap();
+        assert_eq!(viewer.viewport_start, 2);
+        viewer.handle_mouse(MouseEvent {
+            kind: MouseEventKind::ScrollUp,
+            column: 0,
+            row: 0,
+            modifiers: KeyModifiers::NONE,
+        });
+        assert_eq!(viewer.viewport_start, 0);
     }
 }
@@ -0,0 +1,492 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+
+use crate::compression::{Compressor, Packed};
+
+/// Output a session's daemon keeps for the scrollback viewer when
+/// `scrollback_limit` isn't configured
+pub const DEFAULT_SCROLLBACK_LIMIT: usize = 10 * 1024 * 1024;
+
+/// OSC 133;A, which shell integration (`nds shell-init`) sends as each
+/// prompt starts
+const PROMPT_MARK: &[u8] = b"\x1b]133;A";
+
+/// Most text one page can carry, so it fits in a single reply however long
+/// the lines are
+pub const MAX_PAGE_BYTES: usize = 512 * 1024;
+
+/// A run of lines from a session's scrollback. Lines are numbered from the
+/// first one the daemon kept, so numbers stay put as old lines are dropped.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScrollbackPage {
+    /// Number of the first line in `lines`
+    pub first: u64,
+    /// Number of the oldest line still kept
+    pub oldest: u64,
+    /// One past the newest line; a line still being written counts
+    pub end: u64,
+    /// The lines, escape sequences and all, without their line endings
+    pub lines: Vec<String>,
+}
+
+/// A session's output split into lines, kept by its daemon so any client can
+/// page through it, however recently it attached. The oldest whole lines go
+/// once the output takes up more than `limit` bytes.
+///
+/// Lines are kept in blocks. With a compressor, each block is compressed
+/// once it is full and only decompressed again when it is read, so a long
+/// history takes a fraction of the memory.
+#[derive(Debug)]
+pub struct ScrollbackBuffer {
+    /// Complete lines, oldest first; only the last block is written to
+    blocks: VecDeque<Block>,
+    /// Lines in `blocks`
+    count: usize,
+    /// The last line, not yet ended with a newline
+    partial: Vec<u8>,
+    /// Where each chunk of output added to `partial` starts in it. Chunks end
+    /// between escape sequences, so these are safe places to cut a line
+    /// too long to keep whole.
+    partial_chunks: Vec<usize>,
+    /// Lines dropped from the front, so the number of the oldest line kept
+    dropped: u64,
+    /// Numbers of the lines prompts started on, oldest first
+    prompts: VecDeque<u64>,
+    bytes: usize,
+    limit: usize,
+    compressor: Option<Compressor>,
+}
+
+#[derive(Debug, Default)]
+struct Block {
+    lines: BlockLines,
+    count: usize,
+    /// Output the lines came from, a newline each included
+    bytes: usize,
+}
+
+#[derive(Debug)]
+enum BlockLines {
+    Open(VecDeque<Vec<u8>>),
+    /// Each line followed by a newline, compressed
+    Packed(Packed),
+}
+
+impl Default for BlockLines {
+    fn default() -> Self {
+        BlockLines::Open(VecDeque::new())
+    }
+}
+
+impl ScrollbackBuffer {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            blocks: VecDeque::new(),
+            count: 0,
+            partial: Vec::new(),
+            partial_chunks: Vec::new(),
+            dropped: 0,
+            prompts: VecDeque::new(),
+            bytes: 0,
+            limit,
+            compressor: None,
+        }
+    }
+
+    /// Compress blocks of lines with `compressor` as they fill up
+    pub fn set_compression(&mut self, compressor: Compressor) {
+        self.compressor = Some(compressor);
+    }
+
+    /// Add output as the PTY produced it
+    pub fn push(&mut self, data: &[u8]) {
+        let mut rest = data;
+        while let Some(newline) = rest.iter().position(|&b| b == b'\n') {
+            self.mark_prompt(&rest[..newline]);
+            self.partial.extend_from_slice(&rest[..newline]);
+            if self.partial.last() == Some(&b'\r') {
+                self.partial.pop();
+            }
+            let line = std::mem::take(&mut self.partial);
+            self.push_line(line);
+            self.partial_chunks.clear();
+            rest = &rest[newline + 1..];
+        }
+        self.mark_prompt(rest);
+        if !rest.is_empty() {
+            self.partial_chunks.push(self.partial.len());
+        }
+        self.partial.extend_from_slice(rest);
+        self.trim();
+    }
+
+    fn push_line(&mut self, line: Vec<u8>) {
+        if !self.blocks.back().is_some_and(Block::is_open) {
+            self.blocks.push_back(Block::default());
+        }
+        let block = self.blocks.back_mut().expect("a block was just added");
+        self.bytes += line.len() + 1;
+        self.count += 1;
+        block.bytes += line.len() + 1;
+        block.count += 1;
+        if let BlockLines::Open(lines) = &mut block.lines {
+            lines.push_back(line);
+        }
+        if let Some(compressor) = &self.compressor {
+            if block.bytes >= compressor.threshold {
+                block.seal(compressor);
+            }
+        }
+    }
+
+    /// Note a prompt starting if `text`, going on the line being written,
+    /// has the shell's mark for it. Output is split on whole escape
+    /// sequences, so a mark is never cut in two.
+    fn mark_prompt(&mut self, text: &[u8]) {
+        if !text.windows(PROMPT_MARK.len()).any(|w| w == PROMPT_MARK) {
+            return;
+        }
+        let line = self.dropped + self.count as u64;
+        if self.prompts.back() != Some(&line) {
+            self.prompts.push_back(line);
+        }
+    }
+
+    fn trim(&mut self) {
+        while self.bytes + self.partial.len() > self.limit {
+            let excess = self.bytes + self.partial.len() - self.limit;
+            let Some(front) = self.blocks.front_mut() else {
+                break;
+            };
+            // A whole block that has to go isn't decompressed first
+            let whole = excess >= front.bytes || front.count == 0;
+            let line = if whole {
+                None
+            } else {
+                front.pop_front(self.compressor.as_ref())
+            };
+            match line {
+                Some(line) => {
+                    self.bytes -= line.len() + 1;
+                    self.count -= 1;
+                    self.dropped += 1;
+                }
+                None => {
+                    let block = self.blocks.pop_front().expect("checked above");
+                    self.bytes -= block.bytes;
+                    self.count -= block.count;
+                    self.dropped += block.count as u64;
+                }
+            }
+        }
+        while self
+            .prompts
+            .front()
+            .is_some_and(|&line| line < self.dropped)
+        {
+            self.prompts.pop_front();
+        }
+        // A single line longer than the limit keeps only its end, cut where
+        // a chunk started so no escape sequence or character is cut in half
+        if self.partial.len() > self.limit {
+            let excess = self.partial.len() - self.limit;
+            let cut = match self.partial_chunks.iter().find(|&&start| start >= excess) {
+                Some(&start) => start,
+                // One chunk bigger than the limit: keep whole characters
+                None => (excess..self.partial.len())
+                    .find(|&i| self.partial[i] & 0xc0 != 0x80)
+                    .unwrap_or(self.partial.len()),
+            };
+            self.partial.drain(..cut);
+            self.partial_chunks.retain(|&start| start >= cut);
+            for start in &mut self.partial_chunks {
+                *start -= cut;
+            }
+        }
+    }
+
+    /// Number of the oldest line kept
+    pub fn oldest(&self) -> u64 {
+        self.dropped
+    }
+
+    /// One past the number of the newest line
+    pub fn end(&self) -> u64 {
+        self.partial_number() + u64::from(!self.partial.is_empty())
+    }
+
+    /// Bytes the lines take up in memory, compressed or not
+    pub fn stored_bytes(&self) -> usize {
+        let blocks: usize = self.blocks.iter().map(Block::stored).sum();
+        blocks + self.partial.len()
+    }
+
+    /// Number the line being written has
+    fn partial_number(&self) -> u64 {
+        self.dropped + self.count as u64
+    }
+
+    /// Each block with the number of its first line
+    fn numbered_blocks(&self) -> Vec<(u64, &Block)> {
+        let mut first = self.dropped;
+        self.blocks
+            .iter()
+            .map(|block| {
+                let numbered = (first, block);
+                first += block.count as u64;
+                numbered
+            })
+            .collect()
+    }
+
+    /// Number of the first line from `from` on (or back, unless `forward`)
+    /// whose text matches `pattern`, escape sequences aside. Blocks are
+    /// decompressed one at a time as the search gets to them.
+    pub fn search(&self, pattern: &Regex, from: u64, forward: bool) -> Option<u64> {
+        let matches = |line: &[u8]| pattern.is_match(&plain_text(&String::from_utf8_lossy(line)));
+        let partial = self.partial_number();
+        let partial_matches = !self.partial.is_empty() && matches(&self.partial);
+        let compressor = self.compressor.as_ref();
+
+        if forward {
+            let from = from.max(self.oldest());
+            for (first, block) in self.numbered_blocks() {
+                if first + block.count as u64 <= from {
+                    continue;
+                }
+                let skip = (from - first.min(from)) as usize;
+                if let Some(i) = block
+                    .lines(compressor)
+                    .iter()
+                    .skip(skip)
+                    .position(|l| matches(l))
+                {
+                    return Some(first + (skip + i) as u64);
+                }
+            }
+            (from <= partial && partial_matches).then_some(partial)
+        } else {
+            if from < self.oldest() {
+                return None;
+            }
+            if from >= partial && partial_matches {
+                return Some(partial);
+            }
+            for (first, block) in self.numbered_blocks().into_iter().rev() {
+                if first > from {
+                    continue;
+                }
+                let take = usize::try_from(from - first).map_or(block.count, |before| {
+                    before.saturating_add(1).min(block.count)
+                });
+                let lines = block.lines(compressor);
+                if let Some(i) = lines.iter().take(take).rposition(|l| matches(l)) {
+                    return Some(first + i as u64);
+                }
+            }
+            None
+        }
+    }
+
+    /// Number of the first line a prompt started on from `from` on (or back,
+    /// unless `forward`), for shells that mark their prompts
+    pub fn prompt(&self, from: u64, forward: bool) -> Option<u64> {
+        if forward {
+            self.prompts.iter().copied().find(|&line| line >= from)
+        } else {
+            self.prompts
+                .iter()
+                .copied()
+                .rev()
+                .find(|&line| line <= from)
+        }
+    }
+
+    /// Up to `count` lines from line number `from` on, or from the oldest
+    /// line if `from` has been dropped. Stops early rather than go over
+    /// `MAX_PAGE_BYTES`.
+    pub fn page(&self, from: u64, count: usize) -> ScrollbackPage {
+        let first = from.clamp(self.oldest(), self.end());
+        let mut lines = Vec::new();
+        let mut bytes = 0;
+        let mut add = |line: &[u8]| {
+            let line = &line[..line.len().min(MAX_PAGE_BYTES)];
+            if lines.len() == count || (!lines.is_empty() && bytes + line.len() > MAX_PAGE_BYTES) {
+                return false;
+            }
+            bytes += line.len();
+            lines.push(String::from_utf8_lossy(line).into_owned());
+            true
+        };
+
+        let mut full = false;
+        for (start, block) in self.numbered_blocks() {
+            if full || start + block.count as u64 <= first {
+                continue;
+            }
+            let skip = (first - start.min(first)) as usize;
+            let block_lines = block.lines(self.compressor.as_ref());
+            full = !block_lines.iter().skip(skip).all(|line| add(line));
+        }
+        if !full && !self.partial.is_empty() && first <= self.partial_number() {
+            add(&self.partial);
+        }
+        ScrollbackPage {
+            first,
+            oldest: self.oldest(),
+            end: self.end(),
+            lines,
+        }
+    }
+}
+
+impl Block {
+    fn is_open(&self) -> bool {
+        matches!(self.lines, BlockLines::Open(_))
+    }
+
+    /// Compress the lines, now that no more are coming
+    fn seal(&mut self, compressor: &Compressor) {
+        let BlockLines::Open(lines) = &mut self.lines else {
+            return;
+        };
+        let mut data = Vec::with_capacity(self.bytes);
+        for line in lines.drain(..) {
+            data.extend_from_slice(&line);
+            data.push(b'\n');
+        }
+        self.lines = BlockLines::Packed(Packed::pack(data, compressor));
+    }
+
+    /// The lines, decompressed if need be
+    fn lines(&self, compressor: Option<&Compressor>) -> Cow<'_, VecDeque<Vec<u8>>> {
+        match &self.lines {
+            BlockLines::Open(lines) => Cow::Borrowed(lines),
+            BlockLines::Packed(packed) => match packed.to_vec(compressor) {
+                Ok(data) => Cow::Owned(
+                    data.split(|&b| b == b'\n')
+                        .take(self.count)
+                        .map(<[u8]>::to_vec)
+                        .collect(),
+                ),
+                Err(e) => {
+                    eprintln!("Warning: failed to decompress scrollback: {}", e);
+                    Cow::Owned(VecDeque::new())
+                }
+            },
+        }
+    }
+
+    /// Take the oldest line out, decompressing the block for good first.
+    /// `None` if there isn't one, or it can't be read back.
+    fn pop_front(&mut self, compressor: Option<&Compressor>) -> Option<Vec<u8>> {
+        if !self.is_open() {
+            let lines = self.lines(compressor).into_owned();
+            if lines.len() != self.count {
+                return None;
+            }
+            self.lines = BlockLines::Open(lines);
+        }
+        let BlockLines::Open(lines) = &mut self.lines else {
+            unreachable!("opened above");
+        };
+        let line = lines.pop_front()?;
+        self.bytes -= line.len() + 1;
+        self.count -= 1;
+        Some(line)
+    }
+
+    fn stored(&self) -> usize {
+        match &self.lines {
+            BlockLines::Open(_) => self.bytes,
+            BlockLines::Packed(packed) => packed.stored(),
+        }
+    }
+}
+
+/// A run of text or one escape sequence in a line of output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Piece<'a> {
+    /// Text, control characters and all
+    Text(&'a str),
+    /// A CSI sequence's parameters and final character (`m` for SGR)
+    Csi(&'a str, char),
+    /// Any other escape sequence
+    Escape,
+}
+
+/// Split `line` into text and the escape sequences between it
+pub fn pieces(line: &str) -> Vec<Piece<'_>> {
+    let mut pieces = Vec::new();
+    let mut chars = line.char_indices().peekable();
+    let mut text_start = 0;
+    while let Some((at, ch)) = chars.next() {
+        if ch != '\x1b' {
+            continue;
+        }
+        if text_start < at {
+            pieces.push(Piece::Text(&line[text_start..at]));
+        }
+        match chars.next() {
+            // CSI: parameters and intermediates up to a final byte
+            Some((params, '[')) => {
+                let params = params + 1;
+                let mut piece = Piece::Escape;
+                for (end, c) in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        piece = Piece::Csi(&line[params..end], c);
+                        break;
+                    }
+                }
+                pieces.push(piece);
+            }
+            // OSC, DCS and the like run to BEL or ST
+            Some((_, ']' | 'P' | 'X' | '^' | '_')) => {
+                while let Some((_, c)) = chars.next() {
+                    if c == '\x07' || (c == '\x1b' && chars.next_if(|&(_, c)| c == '\\').is_some())
+                    {
+                        break;
+                    }
+                }
+                pieces.push(Piece::Escape);
+            }
+            // Intermediates then a final character, as in ESC ( B
+            Some((_, '\x20'..='\x2f')) => {
+                while chars
+                    .next_if(|(_, c)| ('\x20'..='\x2f').contains(c))
+                    .is_some()
+                {}
+                chars.next();
+                pieces.push(Piece::Escape);
+            }
+            // The character after ESC was the final one
+            _ => pieces.push(Piece::Escape),
+        }
+        text_start = chars.peek().map_or(line.len(), |&(at, _)| at);
+    }
+    if text_start < line.len() {
+        pieces.push(Piece::Text(&line[text_start..]));
+    }
+    pieces
+}
+
+/// Whether `ch` takes a place on screen: printable characters and tabs
+pub fn is_printable(ch: char) -> bool {
+    ch == '\t' || !ch.is_control()
+}
+
+/// `line` as it reads on screen: escape sequences and control characters
+/// other than tabs taken out
+pub fn plain_text(line: &str) -> String {
+    pieces(line)
+        .into_iter()
+        .filter_map(|piece| match piece {
+            Piece::Text(text) => Some(text),
+            _ => None,
+        })
+        .flat_map(str::chars)
+        .filter(|&ch| is_printable(ch))
+        .collect()
+}
@@ -0,0 +1,838 @@
+//! `nds server`: an optional per-user daemon that keeps track of every
+//! session and answers for all of them on one control socket.
+//!
+//! Without it, each command reads every file in `~/.nds/sessions/` and
+//! connects to every session's socket to see which are alive, and two
+//! commands creating or killing sessions at once can trip over each other.
+//! While the server runs, `nds list` and `nds kill` ask it instead. It keeps
+//! the sessions in memory, rereads the directory only when its modification
+//! time changes (sessions created or changed by commands that bypassed it),
+//! notices exits from the daemons' pids, and creates and kills sessions one
+//! at a time. Clients can also subscribe to hear about sessions being
+//! created, attached, detached, changed, killed and exiting as it happens.
+//!
+//! Session daemons are unchanged and don't depend on the server: it starts
+//! them by running `nds new`, so it never forks with threads running, and
+//! stopping it leaves every session as it was.
+//!
+//! Requests and responses are JSON-RPC 2.0 objects, one per line.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::config::Config;
+use crate::error::{NdsError, Result};
+use crate::manager::SessionManager;
+use crate::pty::ClientSummary;
+use crate::resolve::{self, ResolveOptions, ResolvedSession};
+use crate::session::{ForegroundProcess, Session};
+
+/// Set to `off` to have a command ignore a running server
+pub const BYPASS_VAR: &str = "NDS_SERVER";
+
+/// How often the server looks for exited sessions and directory changes
+const TICK: Duration = Duration::from_millis(500);
+
+/// Reread the sessions directory at least this often, in case a change
+/// landed within the same modification time as the last read
+const RESCAN: Duration = Duration::from_secs(5);
+
+/// How long a client waits for an answer; creating and killing take a while
+const CALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long `nds server start` waits for the new server to answer
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+// JSON-RPC error codes
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// The method ran and failed
+pub const CALL_FAILED: i64 = -32000;
+/// No session matched
+pub const SESSION_NOT_FOUND: i64 = -32001;
+/// More than one session matched; `data` has the query and every match
+pub const SESSION_AMBIGUOUS: i64 = -32002;
+
+/// The server's control socket, next to the sessions' sockets
+pub fn socket_path() -> Result<PathBuf> {
+    Ok(Session::socket_dir()?.join("server.sock"))
+}
+
+/// Set in the server itself, whose calls into `SessionManager` must do the
+/// work rather than ask it
+static IN_SERVER: AtomicBool = AtomicBool::new(false);
+
+/// Whether this process should leave the server out
+fn bypassed() -> bool {
+    IN_SERVER.load(Ordering::Relaxed) || std::env::var(BYPASS_VAR).is_ok_and(|value| value == "off")
+}
+
+/// Something that happened to a session, as sent to subscribers and given
+/// by `SessionManager::subscribe_sessions`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum SessionChange {
+    /// A session appeared, created through the server or not
+    Created { session: Session },
+    /// A client attached to a session nobody was attached to
+    Attached { session: Session },
+    /// The last client detached from a session
+    Detached { session: Session },
+    /// A session's metadata changed otherwise: renamed, tagged, ...
+    Changed { session: Session },
+    /// A session was killed through the server
+    Killed { id: String },
+    /// A session's daemon went away without being killed through the server
+    Exited { id: String },
+}
+
+/// What `status` returns
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerStatus {
+    pub pid: u32,
+    pub started_at: DateTime<Utc>,
+    pub sessions: usize,
+    pub subscribers: usize,
+}
+
+/// What `create` takes; everything is optional
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CreateParams {
+    pub name: Option<String>,
+    pub shell: Option<String>,
+    /// Directory to start the shell in (default: the server's)
+    pub dir: Option<PathBuf>,
+    /// Initial terminal size as COLSxROWS
+    pub size: Option<String>,
+}
+
+/// What `info`, `kill` and other methods on one session take
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionParams {
+    /// An ID, an ID prefix or a name
+    pub session: String,
+}
+
+/// What `rename` takes; an empty name removes it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RenameParams {
+    pub session: String,
+    pub name: String,
+}
+
+/// What `send-input` takes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputParams {
+    pub session: String,
+    /// Typed as is; end it with `\r` to press Enter
+    pub text: String,
+}
+
+/// What `capture` takes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaptureParams {
+    pub session: String,
+    /// All the output the daemon kept, instead of the screen
+    #[serde(default)]
+    pub scrollback: bool,
+    /// Keep escape sequences in the scrollback
+    #[serde(default)]
+    pub ansi: bool,
+}
+
+/// What `info` returns: the session's metadata, who is attached and what
+/// is running in it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    #[serde(flatten)]
+    pub session: Session,
+    pub clients: Vec<ClientSummary>,
+    pub foreground: Option<ForegroundProcess>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// A failed call, as it goes in the response's `error`
+#[derive(Debug)]
+struct CallError {
+    code: i64,
+    message: String,
+    data: Option<Value>,
+}
+
+impl CallError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+}
+
+impl From<NdsError> for CallError {
+    fn from(e: NdsError) -> Self {
+        match e {
+            NdsError::SessionNotFound(_) => Self::new(SESSION_NOT_FOUND, e.to_string()),
+            NdsError::AmbiguousSession {
+                ref query,
+                ref matches,
+            } => Self {
+                data: Some(json!({ "query": query, "matches": matches })),
+                ..Self::new(SESSION_AMBIGUOUS, e.to_string())
+            },
+            _ => Self::new(CALL_FAILED, e.to_string()),
+        }
+    }
+}
+
+fn response(id: Value, result: std::result::Result<Value, CallError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(e) => {
+            let mut error = json!({ "code": e.code, "message": e.message });
+            if let Some(data) = e.data {
+                error["data"] = data;
+            }
+            json!({ "jsonrpc": "2.0", "id": id, "error": error })
+        }
+    }
+}
+
+fn write_line(stream: &mut impl Write, message: &Value) -> std::io::Result<()> {
+    let mut line = message.to_string();
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    stream.flush()
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The sessions the server knows about
+#[derive(Debug, Default)]
+pub struct Registry {
+    sessions: BTreeMap<String, Session>,
+    /// Each session's metadata file as last read, to tell what changed
+    files: BTreeMap<String, String>,
+    /// The sessions directory's modification time as of the last read
+    dir_mtime: Option<SystemTime>,
+    last_scan: Option<Instant>,
+}
+
+impl Registry {
+    /// The live sessions, oldest first
+    pub fn sessions(&self) -> Vec<Session> {
+        let mut sessions: Vec<Session> = self.sessions.values().cloned().collect();
+        sessions.sort_by_key(|s| s.created_at);
+        sessions
+    }
+
+    /// Catch up with `dir`: reread it if it changed, and drop sessions whose
+    /// daemon has gone
+    pub fn refresh(&mut self, dir: &Path) -> Vec<SessionChange> {
+        let mut events = Vec::new();
+        let mtime = fs::metadata(dir).and_then(|m| m.modified()).ok();
+        let due = self.last_scan.is_none_or(|last| last.elapsed() >= RESCAN);
+        if due || mtime != self.dir_mtime {
+            self.dir_mtime = mtime;
+            self.last_scan = Some(Instant::now());
+            self.scan(dir, &mut events);
+        }
+
+        let exited: Vec<String> = self
+            .sessions
+            .values()
+            .filter(|session| !Session::is_process_alive(session.pid))
+            .map(|session| session.id.clone())
+            .collect();
+        for id in exited {
+            self.forget(&id);
+            let _ = Session::cleanup(&id);
+            events.push(SessionChange::Exited { id });
+        }
+        events
+    }
+
+    /// Read every session's metadata in `dir`, noting what is new, changed
+    /// and gone
+    fn scan(&mut self, dir: &Path, events: &mut Vec<SessionChange>) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        let mut present = BTreeSet::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|s| s.to_str()) != Some("json") {
+                continue;
+            }
+            let Ok(content) = fs::read_to_string(&path) else {
+                continue;
+            };
+            let Ok(session) = serde_json::from_str::<Session>(&content) else {
+                continue;
+            };
+            present.insert(session.id.clone());
+            match self.files.get(&session.id) {
+                Some(known) if *known == content => continue,
+                Some(_) => {
+                    let was_attached = self.sessions.get(&session.id).map(|s| s.attached);
+                    let session = session.clone();
+                    events.push(match (was_attached, session.attached) {
+                        (Some(false), true) => SessionChange::Attached { session },
+                        (Some(true), false) => SessionChange::Detached { session },
+                        _ => SessionChange::Changed { session },
+                    });
+                }
+                // Only the pid is checked: a session being born may not
+                // have its socket up yet
+                None if !Session::is_process_alive(session.pid) => {
+                    let _ = Session::cleanup(&session.id);
+                    continue;
+                }
+                None => events.push(SessionChange::Created {
+                    session: session.clone(),
+                }),
+            }
+            self.files.insert(session.id.clone(), content);
+            self.sessions.insert(session.id.clone(), session);
+        }
+
+        let gone: Vec<String> = self
+            .sessions
+            .keys()
+            .filter(|id| !present.contains(*id))
+            .cloned()
+            .collect();
+        for id in gone {
+            self.forget(&id);
+            events.push(SessionChange::Exited { id });
+        }
+    }
+
+    fn forget(&mut self, id: &str) {
+        self.sessions.remove(id);
+        self.files.remove(id);
+    }
+
+    /// The session `query` picks, the way `SessionManager::resolve` would
+    pub fn resolve(&self, query: &str, options: &ResolveOptions) -> Result<ResolvedSession> {
+        resolve::resolve(query, &self.sessions(), options)
+    }
+}
+
+struct Server {
+    registry: Mutex<Registry>,
+    sessions_dir: PathBuf,
+    subscribers: Mutex<Vec<Sender<SessionChange>>>,
+    /// Held while creating or killing, so those happen one at a time
+    changing: Mutex<()>,
+    started_at: DateTime<Utc>,
+    socket_path: PathBuf,
+}
+
+impl Server {
+    fn refresh(&self) {
+        let events = lock(&self.registry).refresh(&self.sessions_dir);
+        self.publish(events);
+    }
+
+    fn publish(&self, events: Vec<SessionChange>) {
+        if events.is_empty() {
+            return;
+        }
+        lock(&self.subscribers)
+            .retain(|subscriber| events.iter().all(|e| subscriber.send(e.clone()).is_ok()));
+    }
+
+    fn subscribe(&self) -> Receiver<SessionChange> {
+        let (tx, rx) = mpsc::channel();
+        lock(&self.subscribers).push(tx);
+        rx
+    }
+
+    fn call(&self, method: &str, params: Value) -> std::result::Result<Value, CallError> {
+        match method {
+            "list" => {
+                self.refresh();
+                let sessions = lock(&self.registry).sessions();
+                to_value(sessions)
+            }
+            "info" => {
+                let params: SessionParams = parse_params(params)?;
+                let id = self.resolve(&params.session)?;
+                to_value(SessionInfo {
+                    session: Session::load(&id)?,
+                    clients: SessionManager::session_clients(&id).unwrap_or_default(),
+                    foreground: SessionManager::session_foreground(&id).unwrap_or_default(),
+                })
+            }
+            "create" => {
+                let params: CreateParams = parse_params(params)?;
+                to_value(self.create(params)?)
+            }
+            "kill" => {
+                let params: SessionParams = parse_params(params)?;
+                self.kill(&params.session)?;
+                Ok(Value::Null)
+            }
+            "rename" => {
+                let params: RenameParams = parse_params(params)?;
+                to_value(self.rename(&params.session, &params.name)?)
+            }
+            "send-input" => {
+                let params: InputParams = parse_params(params)?;
+                let id = self.resolve(&params.session)?;
+                to_value(SessionManager::send_input(&id, &params.text)?)
+            }
+            "capture" => {
+                let params: CaptureParams = parse_params(params)?;
+                let id = self.resolve(&params.session)?;
+                if params.scrollback {
+                    let mut out = Vec::new();
+                    SessionManager::capture_scrollback(&id, &mut out, !params.ansi)?;
+                    let text = String::from_utf8_lossy(&out);
+                    to_value(text.lines().collect::<Vec<_>>())
+                } else {
+                    to_value(SessionManager::capture_screen(&id)?)
+                }
+            }
+            "status" => to_value(ServerStatus {
+                pid: std::process::id(),
+                started_at: self.started_at,
+                sessions: lock(&self.registry).sessions.len(),
+                subscribers: lock(&self.subscribers).len(),
+            }),
+            _ => Err(CallError::new(
+                METHOD_NOT_FOUND,
+                format!("no method '{}'", method),
+            )),
+        }
+    }
+
+    /// The ID of the session `query` picks
+    fn resolve(&self, query: &str) -> Result<String> {
+        self.refresh();
+        let options = Config::load().unwrap_or_default().matching;
+        Ok(lock(&self.registry).resolve(query, &options)?.session.id)
+    }
+
+    /// Start a session by running `nds new`, and return it once it is known
+    fn create(&self, params: CreateParams) -> Result<Session> {
+        let _changing = lock(&self.changing);
+        let mut command = Command::new(std::env::current_exe()?);
+        command.args(["new", "--print-id"]);
+        if let Some(shell) = &params.shell {
+            command.args(["--shell", shell]);
+        }
+        if let Some(size) = &params.size {
+            command.args(["--size", size]);
+        }
+        // After `--`, so a name starting with '-' isn't taken for an option
+        if let Some(name) = &params.name {
+            command.args(["--", name]);
+        }
+        if let Some(dir) = &params.dir {
+            command.current_dir(dir);
+        }
+        let output = command.stdin(Stdio::null()).output()?;
+        if !output.status.success() {
+            let message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            return Err(NdsError::ServerError(if message.is_empty() {
+                format!("nds new failed ({})", output.status)
+            } else {
+                message
+            }));
+        }
+        let id = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+        self.refresh();
+        let session = lock(&self.registry).sessions.get(&id).cloned();
+        session.map_or_else(|| Session::load(&id), Ok)
+    }
+
+    fn kill(&self, query: &str) -> Result<()> {
+        let _changing = lock(&self.changing);
+        let id = self.resolve(query)?;
+        SessionManager::kill_session(&id)?;
+        lock(&self.registry).forget(&id);
+        self.publish(vec![SessionChange::Killed { id }]);
+        Ok(())
+    }
+
+    fn rename(&self, query: &str, name: &str) -> Result<Session> {
+        let _changing = lock(&self.changing);
+        let id = self.resolve(query)?;
+        SessionManager::rename_session(&id, name)?;
+        // Subscribers hear about it from the next refresh, as a change
+        self.refresh();
+        Session::load(&id)
+    }
+
+    fn stop(&self) -> ! {
+        let _ = fs::remove_file(&self.socket_path);
+        std::process::exit(0);
+    }
+}
+
+fn to_value(value: impl Serialize) -> std::result::Result<Value, CallError> {
+    serde_json::to_value(value).map_err(|e| NdsError::from(e).into())
+}
+
+fn parse_params<T: serde::de::DeserializeOwned>(
+    params: Value,
+) -> std::result::Result<T, CallError> {
+    let params = if params.is_null() { json!({}) } else { params };
+    serde_json::from_value(params).map_err(|e| CallError::new(INVALID_PARAMS, e.to_string()))
+}
+
+/// Answer one client's requests until it hangs up, or stream events to it
+/// once it subscribes
+fn serve_client(server: &Server, stream: UnixStream) -> std::io::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request: Request = match serde_json::from_str::<Value>(&line) {
+            Err(e) => {
+                let e = CallError::new(PARSE_ERROR, e.to_string());
+                write_line(&mut writer, &response(Value::Null, Err(e)))?;
+                continue;
+            }
+            Ok(value) => match serde_json::from_value(value) {
+                Ok(request) => request,
+                Err(e) => {
+                    let e = CallError::new(INVALID_REQUEST, e.to_string());
+                    write_line(&mut writer, &response(Value::Null, Err(e)))?;
+                    continue;
+                }
+            },
+        };
+
+        match request.method.as_str() {
+            "subscribe-events" | "subscribe" => {
+                let events = server.subscribe();
+                write_line(&mut writer, &response(request.id, Ok(Value::Null)))?;
+                for event in events {
+                    let notification = json!({
+                        "jsonrpc": "2.0",
+                        "method": "event",
+                        "params": event,
+                    });
+                    write_line(&mut writer, &notification)?;
+                }
+                return Ok(());
+            }
+            "stop" => {
+                write_line(&mut writer, &response(request.id, Ok(Value::Null)))?;
+                server.stop();
+            }
+            method => {
+                let result = server.call(method, request.params);
+                write_line(&mut writer, &response(request.id, result))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run the server in this process until it is stopped
+pub fn run() -> Result<()> {
+    IN_SERVER.store(true, Ordering::Relaxed);
+
+    let socket_path = socket_path()?;
+    if UnixStream::connect(&socket_path).is_ok() {
+        return Err(NdsError::ServerError(
+            "the server is already running".into(),
+        ));
+    }
+    let _ = fs::remove_file(&socket_path);
+    let listener = UnixListener::bind(&socket_path)?;
+    fs::set_permissions(&socket_path, fs::Permissions::from_mode(0o600))?;
+    Session::clear_legacy_socket_dir();
+
+    let server = Arc::new(Server {
+        registry: Mutex::new(Registry::default()),
+        sessions_dir: Session::session_dir()?,
+        subscribers: Mutex::new(Vec::new()),
+        changing: Mutex::new(()),
+        started_at: Utc::now(),
+        socket_path,
+    });
+    server.refresh();
+
+    let ticker = server.clone();
+    thread::spawn(move || loop {
+        thread::sleep(TICK);
+        ticker.refresh();
+    });
+
+    for stream in listener.incoming().flatten() {
+        let server = server.clone();
+        thread::spawn(move || {
+            let _ = serve_client(&server, stream);
+        });
+    }
+    Ok(())
+}
+
+/// Start the server in the background and wait until it answers. Returns
+/// its pid.
+pub fn start() -> Result<u32> {
+    if let Some(mut client) = ServerClient::connect() {
+        let status = client.status()?;
+        return Err(NdsError::ServerError(format!(
+            "the server is already running (pid {})",
+            status.pid
+        )));
+    }
+    Command::new(std::env::current_exe()?)
+        .args(["server", "run"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()?;
+
+    let deadline = Instant::now() + START_TIMEOUT;
+    loop {
+        if let Some(mut client) = ServerClient::connect() {
+            return Ok(client.status()?.pid);
+        }
+        if Instant::now() >= deadline {
+            return Err(NdsError::ServerError(
+                "the server didn't start; try `nds server run` to see why".into(),
+            ));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Params for a call from the command line: one JSON object, or KEY=VALUE
+/// pairs whose values are JSON if they parse as JSON and strings otherwise
+pub fn params_from_args(args: &[String]) -> Result<Value> {
+    if let [arg] = args {
+        if arg.trim_start().starts_with('{') {
+            return Ok(serde_json::from_str(arg)?);
+        }
+    }
+    let mut params = serde_json::Map::new();
+    for arg in args {
+        let (key, value) = arg.split_once('=').ok_or_else(|| {
+            NdsError::ServerError(format!(
+                "expected KEY=VALUE or a JSON object, got '{}'",
+                arg
+            ))
+        })?;
+        let value = serde_json::from_str(value).unwrap_or_else(|_| Value::from(value));
+        params.insert(key.to_string(), value);
+    }
+    Ok(Value::Object(params))
+}
+
+/// A connection to the running server
+pub struct ServerClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    next_id: u64,
+}
+
+impl ServerClient {
+    /// Connect to the server, if one is running and this process wasn't
+    /// told to ignore it
+    pub fn connect() -> Option<Self> {
+        if bypassed() {
+            return None;
+        }
+        let stream = UnixStream::connect(socket_path().ok()?).ok()?;
+        stream.set_read_timeout(Some(CALL_TIMEOUT)).ok()?;
+        Some(Self {
+            reader: BufReader::new(stream.try_clone().ok()?),
+            writer: stream,
+            next_id: 1,
+        })
+    }
+
+    /// Call `method` and return its result
+    pub fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        write_line(&mut self.writer, &request)?;
+
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(NdsError::ServerError("the server hung up".into()));
+            }
+            let message: Value = serde_json::from_str(&line)?;
+            if message.get("id") != Some(&json!(id)) {
+                continue;
+            }
+            if let Some(error) = message.get("error") {
+                let text = error["message"].as_str().unwrap_or("unknown error");
+                return Err(match error["code"].as_i64() {
+                    Some(SESSION_NOT_FOUND) => NdsError::SessionNotFound(text.to_string()),
+                    Some(SESSION_AMBIGUOUS) => NdsError::AmbiguousSession {
+                        query: error["data"]["query"]
+                            .as_str()
+                            .unwrap_or_default()
+                            .to_string(),
+                        matches: serde_json::from_value(error["data"]["matches"].clone())
+                            .unwrap_or_default(),
+                    },
+                    _ => NdsError::ServerError(text.to_string()),
+                });
+            }
+            return Ok(message.get("result").cloned().unwrap_or(Value::Null));
+        }
+    }
+
+    /// The live sessions, oldest first
+    pub fn list(&mut self) -> Result<Vec<Session>> {
+        Ok(serde_json::from_value(self.call("list", Value::Null)?)?)
+    }
+
+    pub fn create(&mut self, params: &CreateParams) -> Result<Session> {
+        Ok(serde_json::from_value(
+            self.call("create", serde_json::to_value(params)?)?,
+        )?)
+    }
+
+    /// Kill the session `session` picks (an ID, an ID prefix or a name)
+    pub fn kill(&mut self, session: &str) -> Result<()> {
+        self.call("kill", json!({ "session": session }))?;
+        Ok(())
+    }
+
+    pub fn status(&mut self) -> Result<ServerStatus> {
+        Ok(serde_json::from_value(self.call("status", Value::Null)?)?)
+    }
+
+    pub fn stop(&mut self) -> Result<()> {
+        self.call("stop", Value::Null)?;
+        Ok(())
+    }
+
+    /// Events from now on, until the server stops
+    pub fn subscribe(mut self) -> Result<impl Iterator<Item = Result<SessionChange>>> {
+        self.call("subscribe-events", Value::Null)?;
+        self.writer.set_read_timeout(None)?;
+        Ok(self.reader.lines().map(|line| {
+            let message: Value = serde_json::from_str(&line?)?;
+            Ok(serde_json::from_value(message["params"].clone())?)
+        }))
+    }
+}
+
+/// Changes to sessions as they happen, from `SessionManager::subscribe_sessions`.
+/// They come from `nds server` while it runs; without it, or once it stops,
+/// a thread here watches the sessions directory the way the server does.
+pub struct SessionChanges {
+    events: Receiver<SessionChange>,
+    /// Tells the watching thread to stop
+    closed: Arc<AtomicBool>,
+    /// The subscription to the server, shut down to stop its thread
+    server: Option<UnixStream>,
+}
+
+impl SessionChanges {
+    pub(crate) fn start() -> Result<Self> {
+        let dir = Session::session_dir()?;
+        let (tx, rx) = mpsc::channel();
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let client = ServerClient::connect();
+        let server = client.as_ref().and_then(|c| c.writer.try_clone().ok());
+        let from_server = client.and_then(|client| client.subscribe().ok());
+
+        let watcher_closed = closed.clone();
+        thread::spawn(move || {
+            if let Some(events) = from_server {
+                for event in events {
+                    let Ok(event) = event else {
+                        break;
+                    };
+                    if tx.send(event).is_err() {
+                        return;
+                    }
+                }
+                // The server stopped; carry on without it
+            }
+            watch(&dir, &tx, &watcher_closed);
+        });
+
+        Ok(Self {
+            events: rx,
+            closed,
+            server,
+        })
+    }
+
+    /// The next change, or `None` if there is none within `timeout`
+    pub fn next_timeout(&self, timeout: Duration) -> Option<SessionChange> {
+        self.events.recv_timeout(timeout).ok()
+    }
+}
+
+impl Iterator for SessionChanges {
+    type Item = SessionChange;
+
+    /// Waits for the next change
+    fn next(&mut self) -> Option<SessionChange> {
+        self.events.recv().ok()
+    }
+}
+
+impl Drop for SessionChanges {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+        if let Some(server) = &self.server {
+            let _ = server.shutdown(std::net::Shutdown::Both);
+        }
+    }
+}
+
+/// Send what changes in `dir` until `closed` or nobody is listening. What
+/// is there to begin with isn't news.
+fn watch(dir: &Path, events: &Sender<SessionChange>, closed: &AtomicBool) {
+    let mut registry = Registry::default();
+    let _ = registry.refresh(dir);
+    while !closed.load(Ordering::Relaxed) {
+        thread::sleep(TICK);
+        for event in registry.refresh(dir) {
+            if events.send(event).is_err() {
+                return;
+            }
+        }
+    }
+}
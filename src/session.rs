@@ -1,10 +1,23 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
 use std::os::unix::net::UnixStream;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use crate::error::{NdsError, Result};
+use crate::pty::protocol::PROTOCOL_VERSION;
+use crate::pty::{
+    peer_credentials, ClientSummary, PtyProcess, RedrawStrategy, ResizePolicy, RespawnPolicy,
+    ShareRole,
+};
+use crate::remote::RemoteEndpoint;
+use crate::session_env::SessionEnv;
+use crate::stats::{self, SessionStats};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Session {
@@ -14,8 +27,159 @@ pub struct Session {
     pub created_at: DateTime<Utc>,
     pub attached: bool,
     pub socket_path: PathBuf,
+    /// Name of the Linux abstract-namespace socket the daemon listens on
+    /// instead of `socket_path`, when `abstract_sockets` is configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub abstract_socket: Option<String>,
     pub shell: String,
     pub working_dir: String,
+    /// Last time the daemon saw shell output or client input
+    #[serde(default)]
+    pub last_activity: Option<DateTime<Utc>>,
+    /// Last time the shell or command wrote output
+    #[serde(default)]
+    pub last_output: Option<DateTime<Utc>>,
+    /// Last time a client sent input
+    #[serde(default)]
+    pub last_input: Option<DateTime<Utc>>,
+    /// When the session rang the bell with nobody watching; cleared once a
+    /// client attaches
+    #[serde(default)]
+    pub bell: Option<DateTime<Utc>>,
+    /// Redraw strategy for this session; a repaint from the screen when unset
+    #[serde(default)]
+    pub redraw: Option<RedrawStrategy>,
+    /// Small number that can be used instead of the ID (`nds a 2`)
+    #[serde(default)]
+    pub index: Option<u32>,
+    /// Environment the session's shell was started with
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Variables given with `--env`/`--env-file`, re-applied if the shell is restarted
+    #[serde(default)]
+    pub extra_env: BTreeMap<String, String>,
+    /// The terminal variables of the client that last attached, and anything
+    /// set with `nds setenv`, applied when the shell is started again. An
+    /// empty value unsets the variable.
+    #[serde(default)]
+    pub client_env: BTreeMap<String, String>,
+    /// Last known terminal size (cols, rows), used when no client is attached
+    #[serde(default)]
+    pub size: Option<(u16, u16)>,
+    /// Free-form labels used to filter and group sessions
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Group the session belongs to (`nds group`), attached to as `@group`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Free-form description of what the session is for
+    #[serde(default)]
+    pub note: Option<String>,
+    /// Command run in place of an interactive shell (`nds run`)
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+    /// How the shell or command ended, once it has exited
+    #[serde(default)]
+    pub exit_status: Option<ExitStatus>,
+    /// Whether the daemon starts the shell or command again when it exits
+    #[serde(default)]
+    pub respawn: RespawnPolicy,
+    /// How many times the shell or command has been started again
+    #[serde(default)]
+    pub respawn_count: u32,
+    /// How the PTY is sized when several clients are attached
+    #[serde(default)]
+    pub resize: ResizePolicy,
+    /// Socket protocol spoken by the session's daemon; 0 for daemons from
+    /// before the framed protocol, which record none
+    #[serde(default)]
+    pub protocol: u8,
+    /// Whether a client attaching while others are attached waits for one of
+    /// them to let it in
+    #[serde(default)]
+    pub approve_attach: bool,
+    /// Other users the session is shared with (`nds share`), by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub shared_with: BTreeMap<String, ShareRole>,
+    /// Sessions that input typed here with broadcasting on (`~b`) is typed
+    /// into too (`nds link`), by ID
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub links: Vec<String>,
+    /// Where remote clients reach the daemon, if it listens on TCP
+    /// (`nds new --listen`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote: Option<RemoteEndpoint>,
+}
+
+/// How a session's shell or command ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitStatus {
+    /// Exited with this code
+    Code(i32),
+    /// Terminated by this signal number
+    Signal(i32),
+}
+
+impl ExitStatus {
+    /// Exit code as a shell would report it (128 + signal number for signals)
+    pub fn code(&self) -> i32 {
+        match self {
+            ExitStatus::Code(code) => *code,
+            ExitStatus::Signal(signal) => 128 + signal,
+        }
+    }
+
+    /// Signal number if the process was terminated by a signal
+    pub fn signal(&self) -> Option<i32> {
+        match self {
+            ExitStatus::Code(_) => None,
+            ExitStatus::Signal(signal) => Some(*signal),
+        }
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitStatus::Code(code) => write!(f, "code {}", code),
+            ExitStatus::Signal(signal) => match nix::sys::signal::Signal::try_from(*signal) {
+                Ok(name) => write!(f, "{}", name),
+                Err(_) => write!(f, "signal {}", signal),
+            },
+        }
+    }
+}
+
+/// The process group in the foreground of a session's PTY: the shell at its
+/// prompt, or the job it is running
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ForegroundProcess {
+    pub pgid: i32,
+    /// Command line of the group's leader
+    pub command: Vec<String>,
+}
+
+impl ForegroundProcess {
+    /// The command as it would be typed (`cargo build` rather than
+    /// `/home/me/.cargo/bin/cargo build`), cut to `max_chars`
+    pub fn label(&self, max_chars: usize) -> String {
+        let mut words = self.command.iter();
+        let program = words
+            .next()
+            .map(|p| p.rsplit('/').next().unwrap_or(p).trim_start_matches('-'))
+            .unwrap_or_default();
+        let label = std::iter::once(program)
+            .chain(words.map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if label.chars().count() <= max_chars {
+            return label;
+        }
+        let mut cut: String = label.chars().take(max_chars.saturating_sub(1)).collect();
+        cut.push('…');
+        cut
+    }
 }
 
 impl Session {
@@ -32,8 +196,32 @@ impl Session {
             created_at: Utc::now(),
             attached: false, // Sessions start detached
             socket_path,
+            abstract_socket: None,
             shell,
             working_dir,
+            last_activity: None,
+            last_output: None,
+            last_input: None,
+            bell: None,
+            redraw: None,
+            index: None,
+            env: BTreeMap::new(),
+            extra_env: BTreeMap::new(),
+            client_env: BTreeMap::new(),
+            size: None,
+            tags: Vec::new(),
+            note: None,
+            command: None,
+            exit_status: None,
+            respawn: RespawnPolicy::Never,
+            respawn_count: 0,
+            resize: ResizePolicy::default(),
+            protocol: PROTOCOL_VERSION,
+            approve_attach: false,
+            shared_with: BTreeMap::new(),
+            links: Vec::new(),
+            group: None,
+            remote: None,
         }
     }
 
@@ -50,8 +238,32 @@ impl Session {
             created_at: Utc::now(),
             attached: false, // Sessions start detached
             socket_path,
+            abstract_socket: None,
             shell,
             working_dir,
+            last_activity: None,
+            last_output: None,
+            last_input: None,
+            bell: None,
+            redraw: None,
+            index: None,
+            env: BTreeMap::new(),
+            extra_env: BTreeMap::new(),
+            client_env: BTreeMap::new(),
+            size: None,
+            tags: Vec::new(),
+            note: None,
+            command: None,
+            exit_status: None,
+            respawn: RespawnPolicy::Never,
+            respawn_count: 0,
+            resize: ResizePolicy::default(),
+            protocol: PROTOCOL_VERSION,
+            approve_attach: false,
+            shared_with: BTreeMap::new(),
+            links: Vec::new(),
+            group: None,
+            remote: None,
         }
     }
 
@@ -62,6 +274,290 @@ impl Session {
         }
     }
 
+    /// Index as shown in listings, or `-` for sessions created before indexes
+    pub fn index_label(&self) -> String {
+        self.index
+            .map(|i| i.to_string())
+            .unwrap_or_else(|| "-".to_string())
+    }
+
+    /// Find the session a bare number refers to
+    pub fn find_by_index<'a>(query: &str, sessions: &'a [Session]) -> Option<&'a Session> {
+        let index: u32 = query.parse().ok()?;
+        sessions.iter().find(|s| s.index == Some(index))
+    }
+
+    fn index_dir() -> Result<PathBuf> {
+        let dir = Self::session_dir()?.join("index");
+        if !dir.exists() {
+            fs::create_dir_all(&dir)
+                .map_err(|e| NdsError::DirectoryCreationError(e.to_string()))?;
+        }
+        Ok(dir)
+    }
+
+    /// Claim the lowest free index for a session.
+    ///
+    /// Each index is a claim file holding the owner's ID and daemon PID.
+    /// Claims whose daemon is gone are reclaimed, and the whole scan runs
+    /// under an exclusive lock so concurrent `nds new` calls never share one.
+    pub fn allocate_index(id: &str, pid: i32) -> Result<u32> {
+        let dir = Self::index_dir()?;
+        let lock = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(".lock"))?;
+        // Held until we return; closing the file releases it
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let mut index = 1;
+        loop {
+            let path = dir.join(index.to_string());
+            let claim_is_live = fs::read_to_string(&path)
+                .ok()
+                .and_then(|claim| claim.split_whitespace().nth(1)?.parse::<i32>().ok())
+                .is_some_and(Self::is_process_alive);
+
+            if !claim_is_live {
+                let mut file = fs::File::create(&path)?;
+                writeln!(file, "{} {}", id, pid)?;
+                return Ok(index);
+            }
+            index += 1;
+        }
+    }
+
+    /// Give up any index claimed by the session so it can be reused
+    pub fn release_index(id: &str) -> Result<()> {
+        let dir = Self::index_dir()?;
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let owned = fs::read_to_string(&path)
+                .map(|claim| claim.split_whitespace().next() == Some(id))
+                .unwrap_or(false);
+            if owned {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reject names that can't be typed or displayed safely: empty names,
+    /// control characters and path separators
+    pub fn validate_name(name: &str) -> Result<()> {
+        if name.trim().is_empty() {
+            return Err(NdsError::InvalidSessionName(
+                "name must not be empty".to_string(),
+            ));
+        }
+        if name.chars().any(char::is_control) {
+            return Err(NdsError::InvalidSessionName(format!(
+                "{:?} contains control characters",
+                name
+            )));
+        }
+        if name.contains('/') || name.contains('\\') {
+            return Err(NdsError::InvalidSessionName(format!(
+                "'{}' contains a path separator",
+                name
+            )));
+        }
+        Ok(())
+    }
+
+    /// Tags are single words so they can be listed and typed without quoting
+    pub fn validate_tag(tag: &str) -> Result<()> {
+        if tag.is_empty() {
+            return Err(NdsError::InvalidTag("tag must not be empty".to_string()));
+        }
+        if tag
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || c == ',')
+        {
+            return Err(NdsError::InvalidTag(format!(
+                "{:?} may not contain whitespace, commas or control characters",
+                tag
+            )));
+        }
+        Ok(())
+    }
+
+    /// Group names are single words like tags, and can't start with the `@`
+    /// that marks them on the command line
+    pub fn validate_group(group: &str) -> Result<()> {
+        if group.is_empty() {
+            return Err(NdsError::InvalidGroup(
+                "group must not be empty".to_string(),
+            ));
+        }
+        if group.starts_with('@')
+            || group
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control() || c == ',')
+        {
+            return Err(NdsError::InvalidGroup(format!(
+                "{:?} may not start with '@' or contain whitespace, commas or control characters",
+                group
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether the session carries any of `tags` (an empty filter matches all)
+    pub fn matches_tags(&self, tags: &[String]) -> bool {
+        tags.is_empty() || tags.iter().any(|tag| self.tags.contains(tag))
+    }
+
+    /// Tags formatted for listings, e.g. `#build #ci`
+    pub fn tags_label(&self) -> String {
+        self.tags
+            .iter()
+            .map(|tag| format!("#{}", tag))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Time of the last recorded activity, falling back to creation time
+    pub fn last_active(&self) -> DateTime<Utc> {
+        self.last_activity.unwrap_or(self.created_at)
+    }
+
+    /// How long the session has gone without input or output
+    pub fn idle_duration(&self) -> chrono::Duration {
+        (Utc::now() - self.last_active()).max(chrono::Duration::zero())
+    }
+
+    /// How long it has been since a client sent input, however much the
+    /// session has printed since
+    pub fn input_idle_duration(&self) -> chrono::Duration {
+        let last_input = self.last_input.unwrap_or(self.created_at);
+        (Utc::now() - last_input).max(chrono::Duration::zero())
+    }
+
+    /// Change the given session's metadata. The file is read, changed and
+    /// written back under the metadata lock, so the daemon's periodic
+    /// writes and commands such as `rename`, `tag` or `share` never undo
+    /// each other's changes.
+    pub fn update<T>(id: &str, change: impl FnOnce(&mut Session) -> T) -> Result<T> {
+        let dir = Self::session_dir()?;
+        // The metadata file itself is replaced on every save, so a lock on
+        // it would be lost with the old file; one lock file covers them all
+        let lock = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .mode(0o600)
+            .open(dir.join(".lock"))?;
+        // Held until we return; closing the file releases it
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let content = fs::read_to_string(dir.join(format!("{}.json", id)))?;
+        let mut session: Session = serde_json::from_str(&content)?;
+        let result = change(&mut session);
+        session.save()?;
+        Ok(result)
+    }
+
+    /// Persist the times of the last output and input seen for the given
+    /// session; `None` leaves a time as it was
+    pub fn update_activity(
+        id: &str,
+        output: Option<DateTime<Utc>>,
+        input: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        Self::update(id, |session| {
+            session.last_output = output.or(session.last_output);
+            session.last_input = input.or(session.last_input);
+            session.last_activity = session.last_output.max(session.last_input);
+        })
+    }
+
+    /// Mark the given session as having rung the bell at `at`, or clear the
+    /// mark with `None`
+    pub fn set_bell(id: &str, at: Option<DateTime<Utc>>) -> Result<()> {
+        Self::update(id, |session| session.bell = at)
+    }
+
+    /// Remember the size of the most recently resized client
+    pub fn update_size(id: &str, cols: u16, rows: u16) -> Result<()> {
+        Self::update(id, |session| session.size = Some((cols, rows)))
+    }
+
+    /// Merge `vars` into the environment the shell gets when started again,
+    /// returning those that changed. One not recorded before counts as empty.
+    pub fn update_client_env(
+        id: &str,
+        vars: &BTreeMap<String, String>,
+    ) -> Result<BTreeMap<String, String>> {
+        Self::update(id, |session| {
+            let changed = vars
+                .iter()
+                .filter(|(key, value)| {
+                    session.client_env.get(*key).map_or("", String::as_str) != value.as_str()
+                })
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            session.client_env.extend(vars.clone());
+            changed
+        })
+    }
+
+    /// Record how the session's shell or command ended
+    pub fn record_exit(id: &str, status: ExitStatus) -> Result<()> {
+        Self::update(id, |session| session.exit_status = Some(status))
+    }
+
+    /// Record that the shell or command was restarted on request
+    pub fn record_restart(id: &str) -> Result<()> {
+        Self::update(id, |session| session.exit_status = None)
+    }
+
+    /// Change how the PTY is sized for the attached clients
+    pub fn set_resize_policy(id: &str, policy: ResizePolicy) -> Result<()> {
+        Self::update(id, |session| session.resize = policy)
+    }
+
+    /// Turn attach approval on or off
+    pub fn set_approve_attach(id: &str, approve: bool) -> Result<()> {
+        Self::update(id, |session| session.approve_attach = approve)
+    }
+
+    /// Share the session with `user`, or stop sharing it with `None`
+    pub fn set_share(id: &str, user: &str, role: Option<ShareRole>) -> Result<()> {
+        Self::update(id, |session| match role {
+            Some(role) => {
+                session.shared_with.insert(user.to_string(), role);
+            }
+            None => {
+                session.shared_with.remove(user);
+            }
+        })
+    }
+
+    /// Link the session to `other`, or unlink it with `linked` false; only
+    /// this session's side is recorded
+    pub fn set_link(id: &str, other: &str, linked: bool) -> Result<()> {
+        Self::update(id, |session| {
+            session.links.retain(|link| link != other);
+            if linked {
+                session.links.push(other.to_string());
+            }
+        })
+    }
+
+    /// Record that the shell or command was started again after exiting
+    pub fn record_respawn(id: &str) -> Result<()> {
+        Self::update(id, |session| {
+            session.exit_status = None;
+            session.respawn_count += 1;
+        })
+    }
+
     pub fn session_dir() -> Result<PathBuf> {
         let dir = if let Ok(nds_home) = std::env::var("NDS_HOME") {
             PathBuf::from(nds_home).join("sessions")
@@ -83,25 +579,68 @@ impl Session {
         Ok(dir)
     }
 
+    /// Where sessions' sockets go: `$XDG_RUNTIME_DIR/nds`, which is local,
+    /// private and emptied at logout, or failing that a 0700 `nds-<uid>`
+    /// directory in TMPDIR. Metadata and history stay in `~/.nds`, which may
+    /// be on NFS or readable by others. With `NDS_HOME` set, everything is
+    /// kept there.
     pub fn socket_dir() -> Result<PathBuf> {
-        let dir = if let Ok(nds_home) = std::env::var("NDS_HOME") {
-            PathBuf::from(nds_home).join("sockets")
-        } else {
-            directories::BaseDirs::new()
-                .ok_or_else(|| {
-                    NdsError::DirectoryCreationError("Could not find home directory".to_string())
-                })?
-                .home_dir()
-                .join(".nds")
-                .join("sockets")
+        if let Ok(nds_home) = std::env::var("NDS_HOME") {
+            let dir = PathBuf::from(nds_home).join("sockets");
+            if !dir.exists() {
+                fs::create_dir_all(&dir)
+                    .map_err(|e| NdsError::DirectoryCreationError(e.to_string()))?;
+            }
+            return Ok(dir);
+        }
+
+        let dir = match std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from) {
+            Some(runtime) if runtime.is_absolute() && runtime.is_dir() => runtime.join("nds"),
+            _ => std::env::temp_dir().join(format!("nds-{}", nix::unistd::Uid::effective())),
         };
+        private_dir(&dir, 0o700)?;
+        Ok(dir)
+    }
 
-        if !dir.exists() {
-            fs::create_dir_all(&dir)
-                .map_err(|e| NdsError::DirectoryCreationError(e.to_string()))?;
+    /// Remove what is left of `~/.nds/sockets`, where older versions put
+    /// sockets. Daemons still running from then keep theirs, which clients
+    /// find through the path in the session's metadata; the directory goes
+    /// once they have all exited. Daemons and the server do this as they
+    /// start.
+    pub fn clear_legacy_socket_dir() {
+        let Some(dir) =
+            directories::BaseDirs::new().map(|base| base.home_dir().join(".nds").join("sockets"))
+        else {
+            return;
+        };
+        let Ok(entries) = fs::read_dir(&dir) else {
+            return;
+        };
+        let Ok(session_dir) = Self::session_dir() else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Some(id) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".sock"))
+            else {
+                continue;
+            };
+            if !session_dir.join(format!("{}.json", id)).exists() {
+                let _ = fs::remove_file(&path);
+            }
         }
+        // Fails, harmlessly, while a socket is still in use
+        let _ = fs::remove_dir(&dir);
+    }
 
-        Ok(dir)
+    /// Where shared sessions' second sockets go: not under the home
+    /// directory, which other users usually can't get into, nor `TMPDIR`,
+    /// which may be private too
+    pub fn shared_socket_dir() -> PathBuf {
+        PathBuf::from("/tmp").join(format!("nds-shared-{}", nix::unistd::Uid::effective()))
     }
 
     pub fn metadata_path(&self) -> Result<PathBuf> {
@@ -111,7 +650,18 @@ impl Session {
     pub fn save(&self) -> Result<()> {
         let path = self.metadata_path()?;
         let json = serde_json::to_string_pretty(self)?;
-        fs::write(path, json)?;
+        // Write beside the metadata and rename over it, so a concurrent
+        // reader (the daemon updates this file) never sees it half written
+        let tmp_path = path.with_extension(format!("json.{}.tmp", std::process::id()));
+        // The recorded environment may hold tokens, keep metadata private
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        file.write_all(json.as_bytes())?;
+        fs::rename(&tmp_path, &path)?;
         Ok(())
     }
 
@@ -150,8 +700,7 @@ impl Session {
                     if let Ok(session) = serde_json::from_str::<Session>(&content) {
                         // Check both process and socket health
                         let process_alive = Self::is_process_alive(session.pid);
-                        let socket_healthy = session.socket_path.exists()
-                            && Self::is_socket_healthy(&session.socket_path);
+                        let socket_healthy = session.is_socket_healthy();
 
                         if process_alive && socket_healthy {
                             sessions.push(session);
@@ -174,11 +723,31 @@ impl Session {
         Ok(sessions)
     }
 
-    /// Check if a socket is healthy by attempting to connect
-    fn is_socket_healthy(socket_path: &PathBuf) -> bool {
+    /// Live sessions as their metadata files have them, without asking their
+    /// daemons anything or cleaning up after dead ones, which is quick
+    /// enough for every shell prompt. Whether clients are attached is as
+    /// they last recorded it.
+    pub fn list_cached() -> Result<Vec<Session>> {
+        let dir = Self::session_dir()?;
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut sessions: Vec<Session> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().and_then(|s| s.to_str()) == Some("json"))
+            .filter_map(|path| fs::read_to_string(path).ok())
+            .filter_map(|content| serde_json::from_str::<Session>(&content).ok())
+            .filter(|session| Self::is_process_alive(session.pid))
+            .collect();
+        sessions.sort_by_key(|s| s.created_at);
+        Ok(sessions)
+    }
+
+    /// Check if the session's socket is healthy by attempting to connect
+    pub fn is_socket_healthy(&self) -> bool {
         use std::time::Duration;
 
-        match UnixStream::connect(socket_path) {
+        match self.dial() {
             Ok(socket) => {
                 // Set a very short timeout for the health check
                 let _ = socket.set_read_timeout(Some(Duration::from_millis(50)));
@@ -191,20 +760,35 @@ impl Session {
 
     pub fn cleanup(id: &str) -> Result<()> {
         let metadata_path = Self::session_dir()?.join(format!("{}.json", id));
+        // Sessions started by older versions have their socket elsewhere;
+        // abstract sockets go away with their daemon
+        let recorded_socket = fs::read_to_string(&metadata_path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<Session>(&content).ok())
+            .filter(|session| session.abstract_socket.is_none())
+            .map(|session| session.socket_path);
         if metadata_path.exists() {
             fs::remove_file(metadata_path)?;
         }
 
         let socket_path = Self::socket_dir()?.join(format!("{}.sock", id));
-        if socket_path.exists() {
-            fs::remove_file(socket_path)?;
+        for path in recorded_socket.iter().chain([&socket_path]) {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
         }
 
+        let _ = fs::remove_file(Self::shared_socket_dir().join(format!("{}.sock", id)));
+
+        // Client count file written by older versions
         let status_path = Self::session_dir()?.join(format!("{}.status", id));
         if status_path.exists() {
             fs::remove_file(status_path)?;
         }
 
+        let _ = Self::release_index(id);
+        let _ = SessionEnv::cleanup(id);
+
         Ok(())
     }
 
@@ -214,20 +798,51 @@ impl Session {
     }
 
     pub fn mark_attached(&mut self) -> Result<()> {
-        self.attached = true;
-        self.save()
+        self.set_attached(true)
     }
 
     pub fn mark_detached(&mut self) -> Result<()> {
-        self.attached = false;
-        self.save()
+        self.set_attached(false)
+    }
+
+    /// Record whether a client is attached, picking up metadata changed by
+    /// other processes (rename, tags, notes, daemon updates) on the way
+    fn set_attached(&mut self, attached: bool) -> Result<()> {
+        *self = Self::update(&self.id, |session| {
+            session.attached = attached;
+            session.clone()
+        })?;
+        Ok(())
+    }
+
+    /// Where the daemon listens, for showing: the socket's path, or `@name`
+    /// for an abstract socket
+    pub fn socket_label(&self) -> String {
+        match &self.abstract_socket {
+            Some(name) => format!("@{}", name),
+            None => self.socket_path.display().to_string(),
+        }
+    }
+
+    /// Name of the abstract socket a session's daemon listens on when
+    /// `abstract_sockets` is configured; the uid keeps users apart
+    pub fn abstract_socket_name(id: &str) -> String {
+        format!("nds-{}-{}", nix::unistd::Uid::effective(), id)
+    }
+
+    /// Connect to whichever kind of socket the daemon listens on
+    fn dial(&self) -> std::io::Result<UnixStream> {
+        match &self.abstract_socket {
+            Some(name) => connect_abstract(name),
+            None => UnixStream::connect(&self.socket_path),
+        }
     }
 
     pub fn connect_socket(&self) -> Result<UnixStream> {
         use std::time::Duration;
 
         // Check if socket file exists first
-        if !self.socket_path.exists() {
+        if self.abstract_socket.is_none() && !self.socket_path.exists() {
             return Err(NdsError::SocketError(format!(
                 "Session socket does not exist: {}",
                 self.socket_path.display()
@@ -235,7 +850,7 @@ impl Session {
         }
 
         // Try to connect with a timeout
-        match UnixStream::connect(&self.socket_path) {
+        match self.dial() {
             Ok(socket) => {
                 // Set socket timeout to prevent hanging
                 socket
@@ -271,32 +886,86 @@ impl Session {
         }
     }
 
+    /// Clients attached right now, as reported by the session's daemon
+    pub fn clients(&self) -> Result<Vec<ClientSummary>> {
+        PtyProcess::list_clients(self)
+    }
+
+    /// Number of attached clients; a daemon that doesn't answer has none
     pub fn get_client_count(&self) -> usize {
-        // Read client count from a status file instead of connecting to the socket
-        // This avoids disrupting active sessions
-        let status_path = Self::session_dir()
-            .ok()
-            .and_then(|dir| Some(dir.join(format!("{}.status", self.id))));
+        self.clients().map(|clients| clients.len()).unwrap_or(0)
+    }
 
-        if let Some(path) = status_path {
-            if let Ok(content) = fs::read_to_string(path) {
-                if let Ok(count) = content.trim().parse::<usize>() {
-                    return count;
-                }
-            }
-        }
+    /// What is running in the foreground of the session's PTY, as reported
+    /// by its daemon; `None` once the shell has exited
+    pub fn foreground_process(&self) -> Result<Option<ForegroundProcess>> {
+        PtyProcess::foreground_process(self)
+    }
 
-        // Fallback: assume 0 if detached, 1 if attached
-        if self.attached {
-            1
-        } else {
-            0
+    /// CPU, memory and process count of the daemon and everything under it,
+    /// measured over `stats::SAMPLE_INTERVAL`; `None` if the daemon is gone
+    pub fn stats(&self) -> Option<SessionStats> {
+        stats::collect(&[self.pid]).remove(&self.pid)
+    }
+
+    /// Short label for what is running in the session, if its daemon says
+    pub fn foreground_label(&self, max_chars: usize) -> Option<String> {
+        self.foreground_process()
+            .ok()
+            .flatten()
+            .map(|process| process.label(max_chars))
+    }
+}
+
+/// Create `dir` with `mode` unless it exists, and check that it is a
+/// directory we own; in /tmp anyone could have made it first. Its mode is
+/// set to `mode` either way.
+pub(crate) fn private_dir(dir: &Path, mode: u32) -> Result<()> {
+    if let Err(e) = fs::DirBuilder::new().mode(mode).create(dir) {
+        if e.kind() != std::io::ErrorKind::AlreadyExists {
+            return Err(NdsError::DirectoryCreationError(format!(
+                "{}: {}",
+                dir.display(),
+                e
+            )));
         }
     }
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() || metadata.uid() != nix::unistd::Uid::effective().as_raw() {
+        return Err(NdsError::PermissionDenied(format!(
+            "{} belongs to someone else",
+            dir.display()
+        )));
+    }
+    if metadata.mode() & 0o777 != mode {
+        fs::set_permissions(dir, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
 
-    pub fn update_client_count(session_id: &str, count: usize) -> Result<()> {
-        let status_path = Self::session_dir()?.join(format!("{}.status", session_id));
-        fs::write(status_path, count.to_string())?;
-        Ok(())
+/// Connect to an abstract-namespace socket. Such names have no permissions
+/// and anyone could have taken one first, so only a listener run by us (or
+/// root) is trusted.
+#[cfg(target_os = "linux")]
+fn connect_abstract(name: &str) -> std::io::Result<UnixStream> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    let stream = UnixStream::connect_addr(&SocketAddr::from_abstract_name(name)?)?;
+    let owner = peer_credentials(&stream)?.uid;
+    if owner != nix::unistd::Uid::effective().as_raw() && owner != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("@{} is held by uid {}, not us", name, owner),
+        ));
     }
+    Ok(stream)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn connect_abstract(name: &str) -> std::io::Result<UnixStream> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("@{}: abstract sockets are only available on Linux", name),
+    ))
 }
@@ -0,0 +1,259 @@
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::PathBuf;
+
+use crate::error::{NdsError, Result};
+
+/// Snapshot of a shell's environment written by the prompt hook
+#[derive(Debug, Clone)]
+pub struct LiveEnvironment {
+    pub captured_at: DateTime<Utc>,
+    pub vars: BTreeMap<String, String>,
+}
+
+// Exchange files between `nds env` and the shell integration hook:
+// ~/.nds/env/<id>.live holds the shell's environment as of its last prompt,
+// ~/.nds/env/<id>.pending holds export statements to run at the next prompt.
+pub struct SessionEnv;
+
+impl SessionEnv {
+    pub fn env_dir() -> Result<PathBuf> {
+        let dir = if let Ok(nds_home) = std::env::var("NDS_HOME") {
+            PathBuf::from(nds_home).join("env")
+        } else {
+            directories::BaseDirs::new()
+                .ok_or_else(|| {
+                    NdsError::DirectoryCreationError("Could not find home directory".to_string())
+                })?
+                .home_dir()
+                .join(".nds")
+                .join("env")
+        };
+
+        if !dir.exists() {
+            fs::create_dir_all(&dir)
+                .map_err(|e| NdsError::DirectoryCreationError(e.to_string()))?;
+            // Environments routinely contain tokens, keep them private
+            fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+        }
+
+        Ok(dir)
+    }
+
+    fn live_path(session_id: &str) -> Result<PathBuf> {
+        Ok(Self::env_dir()?.join(format!("{}.live", session_id)))
+    }
+
+    fn pending_path(session_id: &str) -> Result<PathBuf> {
+        Ok(Self::env_dir()?.join(format!("{}.pending", session_id)))
+    }
+
+    /// Environment reported by the shell hook, if the session has one
+    pub fn load_live(session_id: &str) -> Result<Option<LiveEnvironment>> {
+        let path = Self::live_path(session_id)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let captured_at: DateTime<Utc> = fs::metadata(&path)?.modified()?.into();
+        let content = fs::read_to_string(&path)?;
+        Ok(Some(LiveEnvironment {
+            captured_at,
+            vars: parse_env_output(&content),
+        }))
+    }
+
+    /// Export statements waiting for the session's next prompt
+    pub fn pending_exports(session_id: &str) -> Result<Vec<String>> {
+        let path = Self::pending_path(session_id)?;
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        Ok(fs::read_to_string(path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| line.to_string())
+            .collect())
+    }
+
+    /// Queue `export KEY=VAL` statements for the shell hook to run
+    pub fn queue_exports(session_id: &str, vars: &[(String, String)]) -> Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(Self::pending_path(session_id)?)?;
+
+        for (key, value) in vars {
+            writeln!(file, "export {}={}", key, shell_quote(value))?;
+        }
+        Ok(())
+    }
+
+    /// Queue variables from a newly attached terminal for the shell hook:
+    /// exported, or unset if empty because the terminal doesn't have them
+    pub fn queue_updates(session_id: &str, vars: &BTreeMap<String, String>) -> Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(Self::pending_path(session_id)?)?;
+
+        for (key, value) in vars {
+            if value.is_empty() {
+                writeln!(file, "unset {}", key)?;
+            } else {
+                writeln!(file, "export {}={}", key, shell_quote(value))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the exchange files of a session that is gone
+    pub fn cleanup(session_id: &str) -> Result<()> {
+        for path in [
+            Self::live_path(session_id)?,
+            Self::pending_path(session_id)?,
+        ] {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Prompt hook that publishes the live environment and applies queued
+    /// exports, for `eval "$(nds env hook bash)"` in the shell's rc file
+    pub fn hook_script(shell: &str) -> Option<String> {
+        let install = match shell {
+            "bash" => BASH_INSTALL,
+            "zsh" => ZSH_INSTALL,
+            _ => return None,
+        };
+        Some(format!("{}{}", HOOK_BODY, install))
+    }
+}
+
+const HOOK_BODY: &str = r#"__nds_env_hook() {
+  [ -n "$NDS_SESSION_ID" ] || return 0
+  local dir="${NDS_HOME:-$HOME/.nds}/env"
+  local pending="$dir/$NDS_SESSION_ID.pending"
+  if [ -f "$pending" ] && mv "$pending" "$pending.$$" 2>/dev/null; then
+    . "$pending.$$"
+    rm -f "$pending.$$"
+  fi
+  [ -d "$dir" ] && (umask 077; env > "$dir/$NDS_SESSION_ID.live")
+  return 0
+}
+"#;
+
+const BASH_INSTALL: &str = r#"case ";$PROMPT_COMMAND;" in
+  *";__nds_env_hook;"*) ;;
+  *) PROMPT_COMMAND="__nds_env_hook${PROMPT_COMMAND:+;$PROMPT_COMMAND}" ;;
+esac
+"#;
+
+const ZSH_INSTALL: &str = r#"autoload -Uz add-zsh-hook
+add-zsh-hook precmd __nds_env_hook
+"#;
+
+/// Variables that describe the terminal a client is attached from, passed
+/// on to the session's shell when it is next started
+pub const TERMINAL_VARS: &[&str] = &["TERM", "COLORTERM", "LANG", "LC_ALL", "LC_CTYPE"];
+
+/// This process's terminal variables, with an empty value for any not set
+pub fn terminal_env() -> BTreeMap<String, String> {
+    TERMINAL_VARS
+        .iter()
+        .map(|&key| (key.to_string(), std::env::var(key).unwrap_or_default()))
+        .collect()
+}
+
+/// The environment a client passes on when it attaches: its terminal
+/// variables and those in `update`, with an empty value for any not set
+pub fn client_env(update: &[String]) -> BTreeMap<String, String> {
+    let mut env = terminal_env();
+    for key in update {
+        env.insert(key.clone(), std::env::var(key).unwrap_or_default());
+    }
+    env
+}
+
+/// Parse `KEY=VALUE` pairs as given on the command line
+pub fn parse_assignment(input: &str) -> Result<(String, String)> {
+    let (key, value) = input.split_once('=').ok_or_else(|| {
+        NdsError::InvalidEnvironment(format!("'{}' is not in KEY=VALUE form", input))
+    })?;
+
+    let valid_key = key
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_key {
+        return Err(NdsError::InvalidEnvironment(format!(
+            "'{}' is not a valid variable name",
+            key
+        )));
+    }
+
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Parse a dotenv-style file: `KEY=VALUE` lines with optional `export`
+/// prefix and surrounding quotes; blank lines and `#` comments are skipped
+pub fn parse_env_file(content: &str) -> Result<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+    for (lineno, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+
+        let (key, value) = parse_assignment(line).map_err(|e| match e {
+            NdsError::InvalidEnvironment(msg) => {
+                NdsError::InvalidEnvironment(format!("line {}: {}", lineno + 1, msg))
+            }
+            other => other,
+        })?;
+        let value = value.trim();
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|q| value.strip_prefix(*q).and_then(|v| v.strip_suffix(*q)))
+            .unwrap_or(value);
+        vars.push((key, value.to_string()));
+    }
+    Ok(vars)
+}
+
+/// Quote a value for POSIX shells
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r#"'\''"#))
+}
+
+/// Parse the output of `env`; lines without `=` continue a multi-line value
+pub fn parse_env_output(content: &str) -> BTreeMap<String, String> {
+    let mut vars = BTreeMap::new();
+    let mut last_key: Option<String> = None;
+
+    for line in content.lines() {
+        match line.split_once('=') {
+            Some((key, value)) if !key.is_empty() && !key.contains(' ') => {
+                vars.insert(key.to_string(), value.to_string());
+                last_key = Some(key.to_string());
+            }
+            _ => {
+                if let Some(value) = last_key.as_ref().and_then(|k| vars.get_mut(k)) {
+                    value.push('\n');
+                    value.push_str(line);
+                }
+            }
+        }
+    }
+
+    vars
+}
@@ -0,0 +1,230 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::error::{NdsError, Result};
+use crate::scrollback_buffer::{is_printable, pieces, Piece};
+
+/// How big a session's log gets before it is rotated, when `max_bytes`
+/// is not set
+pub const DEFAULT_LOG_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+/// How many rotated logs are kept per session, when `keep` is not set
+pub const DEFAULT_LOG_KEEP: u32 = 3;
+
+/// The `logging` setting in config.json. Having it at all turns logging on;
+/// `"logging": {}` logs with the defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// Rotate a log once it reaches this many bytes (default: 10 MiB)
+    pub max_bytes: Option<u64>,
+    /// Rotated logs to keep, as `<session>.log.1` and so on (default: 3)
+    pub keep: Option<u32>,
+    /// Log text without colors and other escape sequences
+    pub strip_ansi: bool,
+}
+
+/// Where session logs go: `~/.nds/logs`, or `$NDS_HOME/logs`
+pub fn log_dir() -> Result<PathBuf> {
+    let dir = if let Ok(nds_home) = std::env::var("NDS_HOME") {
+        PathBuf::from(nds_home).join("logs")
+    } else {
+        directories::BaseDirs::new()
+            .ok_or_else(|| {
+                NdsError::DirectoryCreationError("Could not find home directory".to_string())
+            })?
+            .home_dir()
+            .join(".nds")
+            .join("logs")
+    };
+
+    if !dir.exists() {
+        fs::create_dir_all(&dir).map_err(|e| NdsError::DirectoryCreationError(e.to_string()))?;
+    }
+
+    Ok(dir)
+}
+
+/// The log of the session with ID `session_id`
+pub fn log_path(session_id: &str) -> Result<PathBuf> {
+    Ok(log_dir()?.join(format!("{}.log", session_id)))
+}
+
+/// Rotated log number `n` of the one at `path`, e.g. `abc.log.2`
+pub fn rotated_path(path: &Path, n: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// A session's output, appended to its log by the daemon whether or not
+/// anyone is attached
+pub struct SessionLog {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_bytes: u64,
+    keep: u32,
+    strip_ansi: bool,
+}
+
+impl SessionLog {
+    /// Append to the log at `path`, carrying on from what is there
+    pub fn open(path: &Path, config: &LogConfig) -> io::Result<Self> {
+        let file = append(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            written: file.metadata()?.len(),
+            file,
+            max_bytes: config.max_bytes.unwrap_or(DEFAULT_LOG_MAX_BYTES),
+            keep: config.keep.unwrap_or(DEFAULT_LOG_KEEP),
+            strip_ansi: config.strip_ansi,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Log output from the PTY, rotating the log once it is full. Output is
+    /// split on whole escape sequences, so stripping them works piece by piece.
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.strip_ansi {
+            let text = log_text(&String::from_utf8_lossy(data));
+            self.file.write_all(text.as_bytes())?;
+            self.written += text.len() as u64;
+        } else {
+            self.file.write_all(data)?;
+            self.written += data.len() as u64;
+        }
+        if self.max_bytes > 0 && self.written >= self.max_bytes {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    /// Shift `log.1` to `log.2` and so on, dropping the oldest, and start
+    /// a new log
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep > 0 {
+            for n in (1..self.keep).rev() {
+                let from = rotated_path(&self.path, n);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        self.file = append(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+/// Logs hold whatever went past on screen, so only the owner reads them
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)
+}
+
+/// Output as text for a log: escape sequences and control characters
+/// taken out, but not newlines
+pub fn log_text(data: &str) -> String {
+    pieces(data)
+        .into_iter()
+        .filter_map(|piece| match piece {
+            Piece::Text(text) => Some(text),
+            _ => None,
+        })
+        .flat_map(str::chars)
+        .filter(|&ch| ch == '\n' || is_printable(ch))
+        .collect()
+}
+
+/// Offset in `file` where its last `lines` lines start, reading back from
+/// the end a block at a time
+pub fn tail_offset(file: &mut File, lines: usize) -> io::Result<u64> {
+    const BLOCK: u64 = 64 * 1024;
+    let len = file.metadata()?.len();
+    if lines == 0 {
+        return Ok(len);
+    }
+    let mut wanted = lines;
+    let mut end = len;
+    let mut block = Vec::new();
+    while end > 0 {
+        let start = end.saturating_sub(BLOCK);
+        block.resize((end - start) as usize, 0);
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut block)?;
+        for (i, &byte) in block.iter().enumerate().rev() {
+            let at = start + i as u64;
+            // A newline ending the file ends the last line, not another one
+            if byte == b'\n' && at + 1 != len {
+                wanted -= 1;
+                if wanted == 0 {
+                    return Ok(at + 1);
+                }
+            }
+        }
+        end = start;
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tail(content: &str, lines: usize) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("x.log");
+        fs::write(&path, content).unwrap();
+        let mut file = File::open(&path).unwrap();
+        let offset = tail_offset(&mut file, lines).unwrap();
+        content[offset as usize..].to_string()
+    }
+
+    #[test]
+    fn tails_the_last_lines() {
+        assert_eq!(tail("a\nb\nc\n", 2), "b\nc\n");
+        assert_eq!(tail("a\nb\nc", 2), "b\nc");
+        assert_eq!(tail("a\nb\nc\n", 5), "a\nb\nc\n");
+        assert_eq!(tail("a\nb\nc\n", 0), "");
+        assert_eq!(tail("", 3), "");
+    }
+
+    #[test]
+    fn rotates_and_strips_escapes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc.log");
+        let config = LogConfig {
+            max_bytes: Some(10),
+            keep: Some(2),
+            strip_ansi: true,
+        };
+        let mut log = SessionLog::open(&path, &config).unwrap();
+        log.write(b"\x1b[31mfirst\x1b[0m\r\n").unwrap();
+        log.write(b"second line\r\n").unwrap();
+        log.write(b"third\r\n").unwrap();
+        log.write(b"fourth line\r\n").unwrap();
+
+        let read = |p: &Path| fs::read_to_string(p).unwrap_or_default();
+        assert_eq!(read(&path), "");
+        assert_eq!(read(&rotated_path(&path, 1)), "third\nfourth line\n");
+        assert_eq!(read(&rotated_path(&path, 2)), "first\nsecond line\n");
+
+        // The oldest goes once there are more than `keep`
+        log.write(b"fifth line!\r\n").unwrap();
+        assert_eq!(read(&rotated_path(&path, 2)), "third\nfourth line\n");
+        assert!(!rotated_path(&path, 3).exists());
+    }
+}
@@ -0,0 +1,94 @@
+//! Order of sessions in `nds list` and the picker.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::session::Session;
+
+/// Order of sessions in `nds list` and the picker
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionSort {
+    /// Oldest first
+    #[default]
+    Created,
+    /// By name, then ID for unnamed sessions
+    Name,
+    /// Longest without input or output first
+    Idle,
+    /// Most attached clients first, asking each daemon
+    Clients,
+}
+
+impl SessionSort {
+    pub fn apply(self, sessions: &mut [Session]) {
+        match self {
+            SessionSort::Created => sessions.sort_by_key(|s| s.created_at),
+            SessionSort::Name => sessions.sort_by_key(|s| s.display_name().to_lowercase()),
+            SessionSort::Idle => sessions.sort_by_key(|s| s.last_active()),
+            SessionSort::Clients => {
+                sessions.sort_by_cached_key(|s| std::cmp::Reverse(s.get_client_count()))
+            }
+        }
+    }
+
+    /// The sort after this one, for cycling through them with `s` in the
+    /// picker
+    pub fn next(self) -> Self {
+        match self {
+            SessionSort::Created => SessionSort::Name,
+            SessionSort::Name => SessionSort::Idle,
+            SessionSort::Idle => SessionSort::Clients,
+            SessionSort::Clients => SessionSort::Created,
+        }
+    }
+}
+
+/// How `nds list` and the picker order sessions unless told otherwise, in
+/// config.json as `"list": { "sort": "idle", "reverse": false }`. The picker
+/// saves the sort picked with `s` here.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ListConfig {
+    pub sort: SessionSort,
+    /// Turn the order around
+    pub reverse: bool,
+}
+
+impl ListConfig {
+    /// Put `sessions` in this order
+    pub fn apply(self, sessions: &mut [Session]) {
+        self.sort.apply(sessions);
+        if self.reverse {
+            sessions.reverse();
+        }
+    }
+}
+
+impl fmt::Display for SessionSort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionSort::Created => f.write_str("created"),
+            SessionSort::Name => f.write_str("name"),
+            SessionSort::Idle => f.write_str("idle"),
+            SessionSort::Clients => f.write_str("clients"),
+        }
+    }
+}
+
+impl std::str::FromStr for SessionSort {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "created" => Ok(SessionSort::Created),
+            "name" => Ok(SessionSort::Name),
+            "idle" => Ok(SessionSort::Idle),
+            "clients" => Ok(SessionSort::Clients),
+            _ => Err(format!(
+                "unknown sort '{}' (expected created, name, idle or clients)",
+                s
+            )),
+        }
+    }
+}
@@ -0,0 +1,55 @@
+/// Shells `nds shell-init` has a script for
+pub const SHELLS: [&str; 3] = ["bash", "zsh", "fish"];
+
+/// Script that marks prompt starts (`133;A`), prompt ends (`133;B`),
+/// command starts (`133;C`) and command exits (`133;D`), for
+/// `eval "$(nds shell-init bash)"` in the shell's rc file. It does nothing
+/// outside nds sessions.
+pub fn script(shell: &str) -> Option<&'static str> {
+    match shell {
+        "bash" => Some(BASH),
+        "zsh" => Some(ZSH),
+        "fish" => Some(FISH),
+        _ => None,
+    }
+}
+
+const BASH: &str = r#"if [ -n "$NDS_SESSION_ID" ]; then
+  __nds_prompt_mark() {
+    local status=$?
+    printf '\033]133;D;%s\007\033]133;A\007' "$status"
+    return $status
+  }
+  case ";$PROMPT_COMMAND;" in
+    *";__nds_prompt_mark;"*) ;;
+    *)
+      PROMPT_COMMAND="__nds_prompt_mark${PROMPT_COMMAND:+;$PROMPT_COMMAND}"
+      PS1="$PS1"'\[\033]133;B\007\]'
+      PS0="$PS0"'\033]133;C\007'
+      ;;
+  esac
+fi
+"#;
+
+const ZSH: &str = r#"if [[ -n "$NDS_SESSION_ID" ]]; then
+  __nds_prompt_mark() { print -Pn '\e]133;D;%?\a\e]133;A\a' }
+  __nds_command_mark() { print -n '\e]133;C\a' }
+  autoload -Uz add-zsh-hook
+  add-zsh-hook precmd __nds_prompt_mark
+  add-zsh-hook preexec __nds_command_mark
+  [[ "$PS1" == *$'\e]133;B'* ]] || PS1="$PS1"$'%{\e]133;B\a%}'
+fi
+"#;
+
+const FISH: &str = r#"if set -q NDS_SESSION_ID
+  function __nds_prompt_mark --on-event fish_prompt
+    printf '\e]133;A\a'
+  end
+  function __nds_command_mark --on-event fish_preexec
+    printf '\e]133;C\a'
+  end
+  function __nds_exit_mark --on-event fish_postexec
+    printf '\e]133;D;%s\a' $status
+  end
+end
+"#;
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How long CPU time is measured over to work out a session's CPU use
+pub const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Resource use of a session: its daemon and every process under it
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SessionStats {
+    /// CPU use over the sample, where 100 is one core kept busy
+    pub cpu_percent: f64,
+    /// Resident memory of all of the session's processes added up
+    pub rss_bytes: u64,
+    /// Processes under the daemon: the shell and whatever it runs
+    pub processes: usize,
+}
+
+impl fmt::Display for SessionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.1}% CPU, {} RSS, {} process{}",
+            self.cpu_percent,
+            format_bytes(self.rss_bytes),
+            self.processes,
+            if self.processes == 1 { "" } else { "es" }
+        )
+    }
+}
+
+/// What one process looks like at a point in time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProcessSample {
+    pub ppid: i32,
+    /// User and system CPU time used so far
+    pub cpu_time: Duration,
+    pub rss_bytes: u64,
+}
+
+/// Resource use of the sessions whose daemons have these PIDs, measured
+/// together over one `SAMPLE_INTERVAL`. Daemons that are gone are left out.
+pub fn collect(daemon_pids: &[i32]) -> HashMap<i32, SessionStats> {
+    let before = snapshot();
+    let started = Instant::now();
+    std::thread::sleep(SAMPLE_INTERVAL);
+    let after = snapshot();
+    let elapsed = started.elapsed();
+
+    daemon_pids
+        .iter()
+        .filter(|pid| after.contains_key(pid))
+        .map(|&pid| (pid, session_stats(pid, &before, &after, elapsed)))
+        .collect()
+}
+
+/// Resource use of the tree under `daemon_pid` between two snapshots taken
+/// `elapsed` apart
+pub fn session_stats(
+    daemon_pid: i32,
+    before: &HashMap<i32, ProcessSample>,
+    after: &HashMap<i32, ProcessSample>,
+    elapsed: Duration,
+) -> SessionStats {
+    let tree = process_tree(daemon_pid, after);
+    let mut cpu = Duration::ZERO;
+    let mut rss_bytes = 0;
+    for pid in &tree {
+        let now = &after[pid];
+        // A process started during the sample used all its CPU time in it
+        let earlier = before.get(pid).map(|p| p.cpu_time).unwrap_or_default();
+        cpu += now.cpu_time.saturating_sub(earlier);
+        rss_bytes += now.rss_bytes;
+    }
+    let cpu_percent = if elapsed.is_zero() {
+        0.0
+    } else {
+        cpu.as_secs_f64() / elapsed.as_secs_f64() * 100.0
+    };
+    SessionStats {
+        cpu_percent,
+        rss_bytes,
+        processes: tree.len().saturating_sub(1),
+    }
+}
+
+/// `root` and every process descended from it
+fn process_tree(root: i32, table: &HashMap<i32, ProcessSample>) -> Vec<i32> {
+    if !table.contains_key(&root) {
+        return Vec::new();
+    }
+    let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+    for (&pid, process) in table {
+        children.entry(process.ppid).or_default().push(pid);
+    }
+    let mut tree = vec![root];
+    let mut next = 0;
+    while next < tree.len() {
+        if let Some(kids) = children.get(&tree[next]) {
+            tree.extend(kids);
+        }
+        next += 1;
+    }
+    tree
+}
+
+/// Every process on the system, read from /proc on Linux or `ps` on macOS
+pub fn snapshot() -> HashMap<i32, ProcessSample> {
+    #[cfg(target_os = "linux")]
+    {
+        let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64;
+        let Ok(entries) = std::fs::read_dir("/proc") else {
+            return HashMap::new();
+        };
+        entries
+            .flatten()
+            .filter_map(|entry| entry.file_name().to_str()?.parse::<i32>().ok())
+            .filter_map(|pid| {
+                let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+                Some((pid, parse_proc_stat(&stat, ticks, page_size)?))
+            })
+            .collect()
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        use std::process::Command;
+        let Ok(output) = Command::new("ps")
+            .args(["-axo", "pid=,ppid=,rss=,time="])
+            .output()
+        else {
+            return HashMap::new();
+        };
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(parse_ps_line)
+            .collect()
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "linux")))]
+    {
+        HashMap::new()
+    }
+}
+
+/// Parse a line of /proc/[pid]/stat, given clock ticks per second and the
+/// page size
+pub fn parse_proc_stat(stat: &str, ticks: u64, page_size: u64) -> Option<ProcessSample> {
+    // The command name is in parentheses and may itself hold spaces or ')'
+    let rest = &stat[stat.rfind(')')? + 1..];
+    let fields: Vec<&str> = rest.split_whitespace().collect();
+    // Counting from the state: ppid is 1, utime 11, stime 12 and rss 21
+    let ppid = fields.get(1)?.parse().ok()?;
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    let rss_pages: u64 = fields.get(21)?.parse().ok()?;
+    Some(ProcessSample {
+        ppid,
+        cpu_time: Duration::from_secs_f64((utime + stime) as f64 / ticks as f64),
+        rss_bytes: rss_pages * page_size,
+    })
+}
+
+/// Parse a line of `ps -o pid=,ppid=,rss=,time=`: RSS in KiB and CPU time
+/// as `[[days-]hours:]minutes:seconds`
+pub fn parse_ps_line(line: &str) -> Option<(i32, ProcessSample)> {
+    let mut fields = line.split_whitespace();
+    let pid = fields.next()?.parse().ok()?;
+    let ppid = fields.next()?.parse().ok()?;
+    let rss_kib: u64 = fields.next()?.parse().ok()?;
+    let time = fields.next()?;
+
+    let (days, clock) = match time.split_once('-') {
+        Some((days, clock)) => (days.parse::<f64>().ok()?, clock),
+        None => (0.0, time),
+    };
+    let mut seconds = 0.0;
+    for part in clock.split(':') {
+        seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+    }
+    seconds += days * 86_400.0;
+
+    Some((
+        pid,
+        ProcessSample {
+            ppid,
+            cpu_time: Duration::from_secs_f64(seconds),
+            rss_bytes: rss_kib * 1024,
+        },
+    ))
+}
+
+/// Bytes in the largest unit that keeps them above one, e.g. `12.3M`
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["K", "M", "G", "T"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", value, UNITS[unit])
+}
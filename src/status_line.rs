@@ -0,0 +1,70 @@
+//! `nds status-line`: a few characters on the sessions for a shell prompt,
+//! tmux's status bar or starship, from what the metadata files say
+
+use std::fmt;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::session::Session;
+
+/// How recently a detached session must have printed to count as active.
+/// Daemons only write the time of the last output every 30 seconds.
+pub const ACTIVE_WITHIN: Duration = Duration::minutes(2);
+
+/// What `nds status-line` shows, as `[web] 3 !1 +2`: the session it runs
+/// in, how many sessions there are, how many rang the bell with nobody
+/// attached, and how many detached ones are printing. Counts of none are
+/// left out, and it is empty when there are no sessions at all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusLine {
+    /// Name (or ID) of the session this runs in
+    pub current: Option<String>,
+    pub sessions: usize,
+    pub bells: usize,
+    pub active: usize,
+}
+
+impl StatusLine {
+    /// The line for `sessions` at `now`, from inside the session with ID
+    /// `current_id` if there is one
+    pub fn new(sessions: &[Session], current_id: Option<&str>, now: DateTime<Utc>) -> Self {
+        let current = current_id.and_then(|id| sessions.iter().find(|session| session.id == id));
+        let others = || {
+            sessions
+                .iter()
+                .filter(move |session| Some(session.id.as_str()) != current_id)
+        };
+        StatusLine {
+            current: current.map(|session| session.name.clone().unwrap_or(session.id.clone())),
+            sessions: sessions.len(),
+            bells: others().filter(|session| session.bell.is_some()).count(),
+            active: others()
+                .filter(|session| !session.attached)
+                .filter(|session| {
+                    session
+                        .last_output
+                        .is_some_and(|at| now - at <= ACTIVE_WITHIN)
+                })
+                .count(),
+        }
+    }
+}
+
+impl fmt::Display for StatusLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.sessions == 0 {
+            return Ok(());
+        }
+        if let Some(current) = &self.current {
+            write!(f, "[{}] ", current)?;
+        }
+        write!(f, "{}", self.sessions)?;
+        if self.bells > 0 {
+            write!(f, " !{}", self.bells)?;
+        }
+        if self.active > 0 {
+            write!(f, " +{}", self.active)?;
+        }
+        Ok(())
+    }
+}
@@ -50,6 +50,107 @@ fn test_session_serialization() {
     assert_eq!(session.pid, deserialized.pid);
 }
 
+#[test]
+fn test_session_metadata_without_new_fields() {
+    // Metadata written by older versions lacks the optional fields
+    let json = r#"{"id":"abc12345","name":null,"pid":1,"created_at":"2024-01-01T00:00:00Z",
+        "attached":false,"socket_path":"/tmp/x.sock","shell":"/bin/sh","working_dir":"/"}"#;
+    let session: Session = serde_json::from_str(json).unwrap();
+    assert!(session.last_activity.is_none());
+    assert!(session.redraw.is_none());
+}
+
+#[test]
+fn test_session_links_are_recorded() {
+    let mut session = Session::new("abcd1234".to_string(), 1, PathBuf::from("/tmp/x.sock"));
+    assert!(session.links.is_empty());
+    // Left out of metadata unless used, for older versions' sake
+    assert!(!serde_json::to_string(&session).unwrap().contains("links"));
+
+    session.links = vec!["ef567890".to_string()];
+    let json = serde_json::to_string(&session).unwrap();
+    let loaded: Session = serde_json::from_str(&json).unwrap();
+    assert_eq!(loaded.links, ["ef567890"]);
+}
+
+#[test]
+fn test_abstract_socket_is_recorded() {
+    let mut session = Session::new("abcd1234".to_string(), 1, PathBuf::from("/tmp/x.sock"));
+    assert_eq!(session.socket_label(), "/tmp/x.sock");
+    // Left out of metadata unless used, for older versions' sake
+    assert!(!serde_json::to_string(&session)
+        .unwrap()
+        .contains("abstract_socket"));
+
+    session.abstract_socket = Some(Session::abstract_socket_name(&session.id));
+    assert!(session.socket_label().starts_with("@nds-"));
+    assert!(session.socket_label().ends_with("-abcd1234"));
+    let json = serde_json::to_string(&session).unwrap();
+    let loaded: Session = serde_json::from_str(&json).unwrap();
+    assert_eq!(loaded.abstract_socket, session.abstract_socket);
+}
+
+#[test]
+fn test_private_dir_is_ours_and_private() {
+    use crate::session::private_dir;
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path().join("nds");
+    private_dir(&dir, 0o700).unwrap();
+    let mode =
+        |path: &std::path::Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode(&dir), 0o700);
+
+    // An existing directory left open to others is closed up
+    std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+    private_dir(&dir, 0o700).unwrap();
+    assert_eq!(mode(&dir), 0o700);
+
+    // Something else in its place is not used
+    let file = temp_dir.path().join("file");
+    std::fs::write(&file, "").unwrap();
+    assert!(private_dir(&file, 0o700).is_err());
+}
+
+#[test]
+fn test_find_session_by_index() {
+    let mut first = Session::new("aaaa1111".to_string(), 1, PathBuf::from("/tmp/a.sock"));
+    first.index = Some(1);
+    let mut second = Session::new("bbbb2222".to_string(), 2, PathBuf::from("/tmp/b.sock"));
+    second.index = Some(2);
+    let legacy = Session::new("cccc3333".to_string(), 3, PathBuf::from("/tmp/c.sock"));
+    let sessions = vec![first, second, legacy];
+
+    assert_eq!(
+        Session::find_by_index("2", &sessions).unwrap().id,
+        "bbbb2222"
+    );
+    assert!(Session::find_by_index("3", &sessions).is_none());
+    assert!(Session::find_by_index("bbbb", &sessions).is_none());
+    assert_eq!(sessions[2].index_label(), "-");
+}
+
+#[test]
+fn test_session_name_validation() {
+    assert!(Session::validate_name("project-dev").is_ok());
+    assert!(Session::validate_name("my work 2").is_ok());
+    assert!(Session::validate_name("").is_err());
+    assert!(Session::validate_name("   ").is_err());
+    assert!(Session::validate_name("a/b").is_err());
+    assert!(Session::validate_name("a\\b").is_err());
+    assert!(Session::validate_name("bell\x07").is_err());
+    assert!(Session::validate_name("two\nlines").is_err());
+}
+
+#[test]
+fn test_session_redraw_serialization() {
+    let mut session = Session::new("abc12345".to_string(), 1, PathBuf::from("/tmp/x.sock"));
+    session.redraw = Some(crate::pty::RedrawStrategy::VtSnapshot);
+    let json = serde_json::to_string(&session).unwrap();
+    assert!(json.contains("\"redraw\":\"vt-snapshot\""));
+}
+
 #[test]
 #[ignore] // This test requires exclusive access to NDS_HOME env var
 fn test_session_creation_and_cleanup() {
@@ -77,3 +178,1041 @@ fn test_session_creation_and_cleanup() {
     // File should be gone
     assert!(!session_file.exists());
 }
+
+#[test]
+fn test_env_assignment_parsing() {
+    use crate::session_env::parse_assignment;
+
+    assert_eq!(
+        parse_assignment("TOKEN=abc=def").unwrap(),
+        ("TOKEN".to_string(), "abc=def".to_string())
+    );
+    assert_eq!(
+        parse_assignment("_EMPTY=").unwrap(),
+        ("_EMPTY".to_string(), String::new())
+    );
+    assert!(parse_assignment("NOVALUE").is_err());
+    assert!(parse_assignment("1BAD=x").is_err());
+    assert!(parse_assignment("BAD-KEY=x").is_err());
+    assert!(parse_assignment("=x").is_err());
+}
+
+#[test]
+fn test_env_shell_quoting() {
+    use crate::session_env::shell_quote;
+
+    assert_eq!(shell_quote("plain"), "'plain'");
+    assert_eq!(shell_quote("it's $HOME"), r#"'it'\''s $HOME'"#);
+    assert_eq!(shell_quote(""), "''");
+}
+
+#[test]
+fn test_env_output_parsing() {
+    use crate::session_env::parse_env_output;
+
+    let vars = parse_env_output("HOME=/home/u\nMULTI=first\nsecond line\nEMPTY=\n");
+    assert_eq!(vars.get("HOME").map(String::as_str), Some("/home/u"));
+    assert_eq!(
+        vars.get("MULTI").map(String::as_str),
+        Some("first\nsecond line")
+    );
+    assert_eq!(vars.get("EMPTY").map(String::as_str), Some(""));
+    assert_eq!(vars.len(), 3);
+}
+
+#[test]
+fn test_tag_validation_and_matching() {
+    assert!(Session::validate_tag("build").is_ok());
+    assert!(Session::validate_tag("team/infra").is_ok());
+    assert!(Session::validate_tag("").is_err());
+    assert!(Session::validate_tag("two words").is_err());
+    assert!(Session::validate_tag("a,b").is_err());
+
+    let mut session = Session::new("tagged01".to_string(), 1, PathBuf::from("/tmp/t.sock"));
+    session.tags = vec!["build".to_string(), "ci".to_string()];
+    assert!(session.matches_tags(&[]));
+    assert!(session.matches_tags(&["ci".to_string(), "other".to_string()]));
+    assert!(!session.matches_tags(&["other".to_string()]));
+    assert_eq!(session.tags_label(), "#build #ci");
+}
+
+#[test]
+fn test_picker_groups_sessions_by_tag() {
+    use crate::interactive::{group_by_tag, PickerRow};
+
+    let make = |id: &str, tags: &[&str]| {
+        let mut session = Session::new(id.to_string(), 1, PathBuf::from("/tmp/t.sock"));
+        session.tags = tags.iter().map(|t| t.to_string()).collect();
+        session
+    };
+
+    let mut untagged = vec![make("a", &[]), make("b", &[])];
+    assert_eq!(
+        group_by_tag(&mut untagged),
+        vec![PickerRow::Session(0), PickerRow::Session(1)]
+    );
+
+    let mut sessions = vec![make("a", &[]), make("b", &["web"]), make("c", &["api"])];
+    let rows = group_by_tag(&mut sessions);
+    let ids: Vec<_> = sessions.iter().map(|s| s.id.as_str()).collect();
+    assert_eq!(ids, vec!["c", "b", "a"]);
+    assert_eq!(
+        rows,
+        vec![
+            PickerRow::Group("#api".to_string()),
+            PickerRow::Session(0),
+            PickerRow::Group("#web".to_string()),
+            PickerRow::Session(1),
+            PickerRow::Group("untagged".to_string()),
+            PickerRow::Session(2),
+        ]
+    );
+}
+
+#[test]
+fn test_picker_puts_groups_before_tags() {
+    use crate::interactive::{group_by_tag, PickerRow};
+
+    let make = |id: &str, group: Option<&str>, tags: &[&str]| {
+        let mut session = Session::new(id.to_string(), 1, PathBuf::from("/tmp/t.sock"));
+        session.group = group.map(str::to_string);
+        session.tags = tags.iter().map(|t| t.to_string()).collect();
+        session
+    };
+
+    let mut sessions = vec![
+        make("a", None, &[]),
+        make("b", None, &["web"]),
+        make("c", Some("infra"), &["web"]),
+        make("d", Some("infra"), &[]),
+    ];
+    let rows = group_by_tag(&mut sessions);
+    let ids: Vec<_> = sessions.iter().map(|s| s.id.as_str()).collect();
+    assert_eq!(ids, vec!["c", "d", "b", "a"]);
+    assert_eq!(
+        rows,
+        vec![
+            PickerRow::Group("@infra".to_string()),
+            PickerRow::Session(0),
+            PickerRow::Session(1),
+            PickerRow::Group("#web".to_string()),
+            PickerRow::Session(2),
+            PickerRow::Group("untagged".to_string()),
+            PickerRow::Session(3),
+        ]
+    );
+
+    assert!(Session::validate_group("infra").is_ok());
+    assert!(Session::validate_group("").is_err());
+    assert!(Session::validate_group("@infra").is_err());
+    assert!(Session::validate_group("two words").is_err());
+}
+
+#[test]
+fn test_session_list_filter_and_marks() {
+    use crate::ui::SessionList;
+
+    let make = |id: &str, name: &str| {
+        let mut session = Session::new(id.to_string(), 1, PathBuf::from("/tmp/t.sock"));
+        session.name = Some(name.to_string());
+        session
+    };
+    let sessions = vec![make("aaa111", "web"), make("bbb222", "db")];
+    let mut list = SessionList::new(sessions, Some("aaa111".to_string()));
+    assert_eq!(list.shown(), 2);
+
+    list.set_query("db");
+    assert_eq!(list.shown(), 1);
+    assert_eq!(list.selected().unwrap().id, "bbb222");
+    list.set_query("zzz");
+    assert!(list.selected().is_none());
+    list.set_query("");
+    assert_eq!(list.shown(), 2);
+
+    // With nothing marked, `x` would kill the selected session
+    assert!(list.select("bbb222"));
+    let ids = |sessions: Vec<Session>| sessions.into_iter().map(|s| s.id).collect::<Vec<_>>();
+    assert_eq!(ids(list.marked_or_selected()), ["bbb222"]);
+    list.toggle_mark();
+    list.toggle_mark();
+    assert_eq!(ids(list.marked_or_selected()).len(), 2);
+}
+
+#[test]
+fn test_session_list_mouse() {
+    use crate::ui::SessionList;
+    use crossterm::event::{KeyModifiers, MouseButton, MouseEvent, MouseEventKind};
+    use ratatui::{backend::TestBackend, Terminal};
+
+    let make = |id: &str, name: &str, note: Option<&str>| {
+        let mut session = Session::new(id.to_string(), 1, PathBuf::from("/tmp/t.sock"));
+        session.name = Some(name.to_string());
+        session.note = note.map(str::to_string);
+        session
+    };
+    let sessions = vec![
+        make("aaa111", "web", Some("two\nlines")),
+        make("bbb222", "db", None),
+        make("ccc333", "cache", None),
+    ];
+    let mut list = SessionList::new(sessions, None);
+    let mut terminal = Terminal::new(TestBackend::new(160, 10)).unwrap();
+    terminal.draw(|f| list.render(f, f.area())).unwrap();
+    let click = |list: &mut SessionList, row| {
+        list.mouse(MouseEvent {
+            kind: MouseEventKind::Down(MouseButton::Left),
+            column: 5,
+            row,
+            modifiers: KeyModifiers::NONE,
+        })
+    };
+
+    // web's note takes the row under it
+    assert!(!click(&mut list, 1));
+    assert!(!click(&mut list, 3));
+    assert_eq!(list.selected().unwrap().id, "ccc333");
+    assert!(click(&mut list, 3));
+    assert!(!click(&mut list, 2));
+    assert_eq!(list.selected().unwrap().id, "bbb222");
+    // Below the last session
+    assert!(!click(&mut list, 8));
+    assert_eq!(list.selected().unwrap().id, "bbb222");
+}
+
+#[test]
+fn test_status_line() {
+    use crate::status_line::StatusLine;
+
+    let now = chrono::Utc::now();
+    let make = |id: &str, name: Option<&str>| {
+        let mut session = Session::new(id.to_string(), 1, PathBuf::from("/tmp/t.sock"));
+        session.name = name.map(str::to_string);
+        session
+    };
+    let mut web = make("aaa111", Some("web"));
+    web.bell = Some(now);
+    let mut build = make("bbb222", None);
+    build.last_output = Some(now - chrono::Duration::seconds(40));
+    let mut watched = make("ccc333", Some("logs"));
+    watched.last_output = Some(now);
+    watched.attached = true;
+    let mut quiet = make("ddd444", None);
+    quiet.last_output = Some(now - chrono::Duration::hours(1));
+    let sessions = [web, build, watched, quiet];
+
+    let outside = StatusLine::new(&sessions, None, now);
+    assert_eq!(outside.to_string(), "4 !1 +1");
+    // Its own bell and output don't count
+    let inside = StatusLine::new(&sessions, Some("aaa111"), now);
+    assert_eq!(inside.to_string(), "[web] 4 +1");
+    assert_eq!(
+        StatusLine::new(&sessions, Some("bbb222"), now).to_string(),
+        "[bbb222] 4 !1"
+    );
+    assert_eq!(StatusLine::new(&[], None, now).to_string(), "");
+}
+
+#[test]
+fn test_theme_config() {
+    use crate::ui::Theme;
+    use ratatui::style::{Color, Modifier, Style};
+
+    // Only what is given changes
+    let theme: Theme = serde_json::from_str(
+        r##"{"selected": "bold black on #e0e0e0", "current": "Blue underline", "current_icon": "*"}"##,
+    )
+    .unwrap();
+    assert_eq!(
+        theme.selected,
+        Style::default()
+            .fg(Color::Black)
+            .bg(Color::Rgb(0xe0, 0xe0, 0xe0))
+            .add_modifier(Modifier::BOLD)
+    );
+    assert_eq!(
+        theme.current,
+        Style::default()
+            .fg(Color::Blue)
+            .add_modifier(Modifier::UNDERLINED)
+    );
+    assert_eq!(theme.current_icon, "*");
+    assert_eq!(theme.attached, Theme::default().attached);
+
+    // Saved the way it is written
+    let saved = serde_json::to_string(&theme).unwrap();
+    assert!(saved.contains(r##""selected":"bold black on #e0e0e0""##));
+    assert_eq!(serde_json::from_str::<Theme>(&saved).unwrap(), theme);
+
+    assert!(serde_json::from_str::<Theme>(r#"{"mark": "blinking"}"#).is_err());
+    assert!(serde_json::from_str::<Theme>(r#"{"mark": "red on"}"#).is_err());
+}
+
+#[test]
+fn test_keymap_config() {
+    use crate::keymap::{Escape, Key, KeyMap, PickerAction, ViewerAction};
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+    let press = |code, modifiers| KeyEvent::new(code, modifiers);
+    let keymap: KeyMap = serde_json::from_str(
+        r#"{
+            "prefix": "`",
+            "escape": {"detach": "ctrl-x", "scrollback": ["h", "y"]},
+            "picker": {"down": ["down", "ctrl-n"], "kill": "k", "sort": []},
+            "viewer": {"search": "ctrl-s"}
+        }"#,
+    )
+    .unwrap();
+
+    assert_eq!(keymap.prefix_byte(), b'`');
+    assert_eq!(keymap.escape(0x18), Some(Escape::Detach));
+    assert_eq!(keymap.escape(b'd'), None);
+    assert_eq!(keymap.escape(b'y'), Some(Escape::Scrollback));
+    assert_eq!(keymap.escape(b's'), Some(Escape::Switch));
+    assert_eq!(keymap.escape_label(Escape::Detach), "`C-x");
+
+    // A key given to another action is no longer up's
+    let picker = &keymap.picker;
+    let ctrl_n = press(KeyCode::Char('n'), KeyModifiers::CONTROL);
+    assert_eq!(picker.action(&ctrl_n), Some(PickerAction::Down));
+    let k = press(KeyCode::Char('k'), KeyModifiers::NONE);
+    assert_eq!(picker.action(&k), Some(PickerAction::Kill));
+    assert_eq!(picker.label(PickerAction::Up), "↑");
+    let s = press(KeyCode::Char('s'), KeyModifiers::NONE);
+    assert_eq!(picker.action(&s), None);
+    assert_eq!(picker.label(PickerAction::Quit), "q/Esc");
+
+    // Shift is in the character typed
+    let shifted = press(KeyCode::Char('G'), KeyModifiers::SHIFT);
+    assert_eq!(keymap.viewer.action(&shifted), Some(ViewerAction::Bottom));
+    let ctrl_s = press(KeyCode::Char('s'), KeyModifiers::CONTROL);
+    assert_eq!(keymap.viewer.action(&ctrl_s), Some(ViewerAction::Search));
+    assert_eq!("shift-g".parse::<Key>().unwrap(), "G".parse().unwrap());
+    assert_eq!("-".parse::<Key>().unwrap().code, KeyCode::Char('-'));
+    assert_eq!("Ctrl-PgDn".parse::<Key>().unwrap().code, KeyCode::PageDown);
+
+    // Saved the way it is written
+    let saved = serde_json::to_string(&keymap).unwrap();
+    assert!(saved.contains(r#""down":["down","ctrl-n"]"#));
+    assert_eq!(serde_json::from_str::<KeyMap>(&saved).unwrap(), keymap);
+
+    for bad in [
+        r#"{"picker": {"jump": "j"}}"#,
+        r#"{"picker": {"up": "hyper-k"}}"#,
+        r#"{"picker": {"up": ""}}"#,
+        r#"{"escape": {"detach": "up"}}"#,
+        r#"{"prefix": "enter"}"#,
+    ] {
+        assert!(serde_json::from_str::<KeyMap>(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn test_theme_without_color() {
+    use crate::ui::Theme;
+    use ratatui::style::Modifier;
+
+    let theme = Theme::default().resolve(true);
+    for style in [
+        theme.current,
+        theme.selected,
+        theme.status_bar,
+        theme.search_match,
+    ] {
+        assert_eq!((style.fg, style.bg), (None, None));
+    }
+    assert!(theme.current.add_modifier.contains(Modifier::BOLD));
+    assert!(theme
+        .search_current
+        .add_modifier
+        .contains(Modifier::REVERSED));
+    assert!(theme
+        .search_match
+        .add_modifier
+        .contains(Modifier::UNDERLINED));
+    assert_eq!(theme.attached_icon, Theme::default().attached_icon);
+
+    // NO_COLOR only applies when the config doesn't say
+    let colored = Theme {
+        color: Some(true),
+        ..Theme::default()
+    };
+    assert_eq!(colored.clone().resolve(true), colored);
+    let plain = Theme {
+        color: Some(false),
+        ..Theme::default()
+    };
+    assert_eq!(plain.resolve(false).current.fg, None);
+    assert_eq!(Theme::default().resolve(false), Theme::default());
+}
+
+#[test]
+fn test_fuzzy_match_ranks_word_starts_and_runs() {
+    use crate::fuzzy::fuzzy_match;
+
+    let found = fuzzy_match("bf", "build-fix").unwrap();
+    assert_eq!(found.positions, vec![0, 6]);
+    assert!(found.score > fuzzy_match("bf", "abuffer").unwrap().score);
+
+    // The tightest place the query fits, ignoring case
+    assert_eq!(
+        fuzzy_match("API", "a-rapid").unwrap().positions,
+        vec![3, 4, 5]
+    );
+    assert!(fuzzy_match("xyz", "build-fix").is_none());
+    assert!(fuzzy_match("fb", "build-fix").is_none());
+    assert_eq!(
+        fuzzy_match("", "anything").unwrap().positions,
+        Vec::<usize>::new()
+    );
+}
+
+#[test]
+fn test_fuzzy_match_session_fields() {
+    use crate::fuzzy::{highlight, match_session, SessionField};
+    use ratatui::style::{Modifier, Style};
+
+    let mut session = Session::new("abc123".to_string(), 1, PathBuf::from("/tmp/t.sock"));
+    session.name = Some("web".to_string());
+    session.working_dir = "/home/me/project".to_string();
+
+    let found = match_session("web", &session, "vim").unwrap();
+    assert_eq!(found.field, SessionField::Name);
+    assert_eq!(found.display_name_positions(&session), vec![0, 1, 2]);
+
+    // "web [abc123]": the ID starts after the name and " ["
+    let found = match_session("c12", &session, "vim").unwrap();
+    assert_eq!(found.field, SessionField::Id);
+    assert_eq!(found.display_name_positions(&session), vec![7, 8, 9]);
+
+    let found = match_session("proj", &session, "vim").unwrap();
+    assert_eq!(found.field, SessionField::WorkingDir);
+    assert!(found.display_name_positions(&session).is_empty());
+    assert_eq!(
+        found.positions_in(SessionField::WorkingDir),
+        &[9, 10, 11, 12]
+    );
+
+    let found = match_session("vi", &session, "vim").unwrap();
+    assert_eq!(found.field, SessionField::Command);
+    assert!(match_session("zzz", &session, "vim").is_none());
+
+    let bold = Style::default().add_modifier(Modifier::BOLD);
+    let spans = highlight("vim", &[0, 1], Style::default(), bold);
+    let parts: Vec<_> = spans
+        .iter()
+        .map(|s| (s.content.as_ref(), s.style))
+        .collect();
+    assert_eq!(parts, vec![("vi", bold), ("m", Style::default())]);
+}
+
+#[test]
+fn test_text_input_editing() {
+    use crate::text_input::{InputEvent, TextInput};
+    use crossterm::event::{KeyCode, KeyModifiers};
+
+    let none = KeyModifiers::NONE;
+    let mut input = TextInput::new("old");
+    assert_eq!(
+        input.handle_key(KeyCode::Backspace, none),
+        InputEvent::Continue
+    );
+    assert_eq!(
+        input.handle_key(KeyCode::Char('x'), none),
+        InputEvent::Continue
+    );
+    assert_eq!(input.value(), "olx");
+
+    assert_eq!(
+        input.handle_key(KeyCode::Char('u'), KeyModifiers::CONTROL),
+        InputEvent::Continue
+    );
+    assert_eq!(input.value(), "");
+    input.handle_key(KeyCode::Char('n'), none);
+    assert_eq!(
+        input.handle_key(KeyCode::Enter, none),
+        InputEvent::Submit("n".to_string())
+    );
+    assert_eq!(input.handle_key(KeyCode::Esc, none), InputEvent::Cancel);
+    assert_eq!(
+        input.handle_key(KeyCode::Char('c'), KeyModifiers::CONTROL),
+        InputEvent::Cancel
+    );
+}
+
+#[test]
+fn test_env_file_parsing() {
+    use crate::session_env::parse_env_file;
+
+    let vars = parse_env_file("# settings\n\nexport A=1\nB=\"two words\"\nC='x=y'\nE=\n").unwrap();
+    assert_eq!(
+        vars,
+        vec![
+            ("A".to_string(), "1".to_string()),
+            ("B".to_string(), "two words".to_string()),
+            ("C".to_string(), "x=y".to_string()),
+            ("E".to_string(), String::new()),
+        ]
+    );
+
+    let err = parse_env_file("A=1\nnot an assignment\n").unwrap_err();
+    assert!(err.to_string().contains("line 2"));
+}
+
+#[test]
+fn test_config_defaults_and_shell_resolution() {
+    use crate::config::{validate_shell, Config};
+
+    let config: Config = serde_json::from_str("{}").unwrap();
+    assert!(config.default_shell.is_none());
+
+    let config: Config = serde_json::from_str(r#"{"default_shell": "/bin/zsh"}"#).unwrap();
+    assert_eq!(config.default_shell.as_deref(), Some("/bin/zsh"));
+    // An explicit --shell always wins
+    assert_eq!(config.resolve_shell(Some("/bin/sh")), "/bin/sh");
+
+    assert!(validate_shell("/bin/sh").is_ok());
+    assert!(validate_shell("sh").is_ok());
+    assert!(validate_shell("/nonexistent/shell").is_err());
+    assert!(validate_shell("/etc/passwd").is_err());
+}
+
+#[test]
+fn test_hooks_config_and_env() {
+    use crate::config::Config;
+    use crate::hooks::{hook_env, Hook};
+    use crate::session::ExitStatus;
+
+    let config: Config =
+        serde_json::from_str(r#"{"hooks": {"on-exit": "notify", "on-attach": "log"}}"#).unwrap();
+    assert_eq!(config.hooks.command(Hook::Exit), Some("notify"));
+    assert_eq!(config.hooks.command(Hook::Attach), Some("log"));
+    assert_eq!(config.hooks.command(Hook::Create), None);
+
+    let mut session = Session::new("hook1234".to_string(), 42, PathBuf::from("/tmp/s.sock"));
+    session.tags = vec!["build".to_string(), "ci".to_string()];
+    session.exit_status = Some(ExitStatus::Signal(9));
+    let env = hook_env(Hook::Exit, &session);
+    let get = |name: &str| {
+        env.iter()
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v.as_str())
+    };
+    assert_eq!(get("NDS_HOOK"), Some("on-exit"));
+    assert_eq!(get("NDS_SESSION_ID"), Some("hook1234"));
+    assert_eq!(get("NDS_SESSION_PID"), Some("42"));
+    assert_eq!(get("NDS_SESSION_TAGS"), Some("build,ci"));
+    assert_eq!(get("NDS_EXIT_CODE"), Some("137"));
+    assert_eq!(get("NDS_EXIT_SIGNAL"), Some("9"));
+
+    // Only on-exit is told how the shell ended
+    let env = hook_env(Hook::Attach, &session);
+    assert!(!env.iter().any(|(k, _)| *k == "NDS_EXIT_CODE"));
+}
+
+#[test]
+fn test_exit_status_codes_and_serialization() {
+    use crate::session::ExitStatus;
+
+    assert_eq!(ExitStatus::Code(3).code(), 3);
+    // Signals are reported the way shells do
+    assert_eq!(ExitStatus::Signal(15).code(), 143);
+
+    let json = serde_json::to_string(&ExitStatus::Signal(9)).unwrap();
+    assert_eq!(json, r#"{"signal":9}"#);
+    let parsed: ExitStatus = serde_json::from_str(r#"{"code":0}"#).unwrap();
+    assert_eq!(parsed, ExitStatus::Code(0));
+
+    assert_eq!(ExitStatus::Code(3).to_string(), "code 3");
+    assert_eq!(ExitStatus::Signal(9).to_string(), "SIGKILL");
+}
+
+#[test]
+fn test_foreground_process_label() {
+    use crate::session::ForegroundProcess;
+
+    let process = ForegroundProcess {
+        pgid: 42,
+        command: vec![
+            "/home/me/.cargo/bin/cargo".to_string(),
+            "build".to_string(),
+            "--release".to_string(),
+        ],
+    };
+    assert_eq!(process.label(40), "cargo build --release");
+    assert_eq!(process.label(8), "cargo b…");
+
+    // Login shells are shown without their leading dash
+    let shell = ForegroundProcess {
+        pgid: 7,
+        command: vec!["-bash".to_string()],
+    };
+    assert_eq!(shell.label(20), "bash");
+}
+
+#[test]
+fn test_stats_parsing() {
+    use crate::stats::{format_bytes, parse_proc_stat, parse_ps_line};
+    use std::time::Duration;
+
+    // The command name may hold spaces and parentheses
+    let stat = "1234 (my (odd) cmd) S 42 1234 1234 0 -1 4194304 100 0 0 0 \
+                250 50 0 0 20 0 1 0 100 10000000 300 18446744073709551615";
+    let sample = parse_proc_stat(stat, 100, 4096).unwrap();
+    assert_eq!(sample.ppid, 42);
+    assert_eq!(sample.cpu_time, Duration::from_secs(3));
+    assert_eq!(sample.rss_bytes, 300 * 4096);
+    assert!(parse_proc_stat("1234 (cut", 100, 4096).is_none());
+
+    let (pid, sample) = parse_ps_line("  501   1  2048  1:02.50").unwrap();
+    assert_eq!(pid, 501);
+    assert_eq!(sample.ppid, 1);
+    assert_eq!(sample.rss_bytes, 2048 * 1024);
+    assert_eq!(sample.cpu_time, Duration::from_millis(62_500));
+    let (_, sample) = parse_ps_line("7 1 0 1-00:00:01").unwrap();
+    assert_eq!(sample.cpu_time, Duration::from_secs(86_401));
+
+    assert_eq!(format_bytes(512), "512B");
+    assert_eq!(format_bytes(1536), "1.5K");
+    assert_eq!(format_bytes(45 * 1024 * 1024), "45.0M");
+}
+
+#[test]
+fn test_session_stats_cover_the_process_tree() {
+    use crate::stats::{session_stats, ProcessSample};
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    let sample = |ppid, cpu_ms, rss_bytes| ProcessSample {
+        ppid,
+        cpu_time: Duration::from_millis(cpu_ms),
+        rss_bytes,
+    };
+    // Daemon 10 runs shell 11, which runs 12; 20 belongs to something else
+    let before = HashMap::from([
+        (10, sample(1, 100, 0)),
+        (11, sample(10, 100, 0)),
+        (20, sample(1, 0, 0)),
+    ]);
+    let after = HashMap::from([
+        (10, sample(1, 110, 1000)),
+        (11, sample(10, 140, 2000)),
+        (12, sample(11, 50, 3000)),
+        (20, sample(1, 1000, 9000)),
+    ]);
+
+    let stats = session_stats(10, &before, &after, Duration::from_millis(200));
+    assert_eq!(stats.processes, 2);
+    assert_eq!(stats.rss_bytes, 6000);
+    // 10 + 40 + 50 ms of CPU in 200 ms
+    assert!((stats.cpu_percent - 50.0).abs() < 0.01);
+}
+
+#[test]
+fn test_session_sort() {
+    use crate::session_sort::SessionSort;
+
+    let now = chrono::Utc::now();
+    let mut busy = Session::new("busy".to_string(), 1, PathBuf::from("/tmp/a.sock"));
+    busy.name = Some("zeta".to_string());
+    busy.created_at = now - chrono::Duration::days(3);
+    busy.last_activity = Some(now);
+    let mut quiet = Session::new("quiet".to_string(), 2, PathBuf::from("/tmp/b.sock"));
+    quiet.name = Some("alpha".to_string());
+    quiet.created_at = now - chrono::Duration::days(1);
+    let mut sessions = vec![busy, quiet];
+
+    let ids = |sessions: &[Session]| sessions.iter().map(|s| s.id.clone()).collect::<Vec<_>>();
+    SessionSort::Idle.apply(&mut sessions);
+    assert_eq!(ids(&sessions), ["quiet", "busy"]);
+    SessionSort::Created.apply(&mut sessions);
+    assert_eq!(ids(&sessions), ["busy", "quiet"]);
+    SessionSort::Name.apply(&mut sessions);
+    assert_eq!(ids(&sessions), ["quiet", "busy"]);
+
+    assert_eq!("idle".parse::<SessionSort>(), Ok(SessionSort::Idle));
+    assert_eq!("clients".parse::<SessionSort>(), Ok(SessionSort::Clients));
+    assert!("size".parse::<SessionSort>().is_err());
+}
+
+#[test]
+fn test_list_config_order() {
+    use crate::session_sort::{ListConfig, SessionSort};
+
+    let mut sessions: Vec<Session> = ["b", "a", "c"]
+        .iter()
+        .map(|id| Session::new(id.to_string(), 1, PathBuf::from("/tmp/a.sock")))
+        .collect();
+    let order = ListConfig {
+        sort: SessionSort::Name,
+        reverse: true,
+    };
+    order.apply(&mut sessions);
+    let ids: Vec<_> = sessions.iter().map(|s| s.id.as_str()).collect();
+    assert_eq!(ids, ["c", "b", "a"]);
+
+    // As written to config.json, and read back with anything left out
+    let json = serde_json::to_string(&order).unwrap();
+    assert_eq!(json, r#"{"sort":"name","reverse":true}"#);
+    let read: ListConfig = serde_json::from_str(r#"{"sort":"clients"}"#).unwrap();
+    assert_eq!(read.sort, SessionSort::Clients);
+    assert!(!read.reverse);
+
+    // `s` in the picker goes through every sort and back
+    let mut sort = SessionSort::default();
+    for _ in 0..4 {
+        sort = sort.next();
+    }
+    assert_eq!(sort, SessionSort::Created);
+}
+
+#[test]
+fn test_ansi_to_line() {
+    use crate::scrollback::ansi_to_line;
+    use ratatui::style::{Color, Modifier, Style};
+    use ratatui::text::Span;
+
+    let line = ansi_to_line("\x1b[1;31mred\x1b[22m \x1b[38;5;42mx\x1b[0m\x1b[Kok\ttab");
+    assert_eq!(
+        line.spans,
+        vec![
+            Span::styled(
+                "red",
+                Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)
+            ),
+            Span::styled(
+                " ",
+                Style::default()
+                    .fg(Color::Red)
+                    .remove_modifier(Modifier::BOLD | Modifier::DIM)
+            ),
+            Span::styled(
+                "x",
+                Style::default()
+                    .fg(Color::Indexed(42))
+                    .remove_modifier(Modifier::BOLD | Modifier::DIM)
+            ),
+            // Tabs go to the next multiple of 8 columns
+            Span::raw("ok tab"),
+        ]
+    );
+
+    let line = ansi_to_line("\x1b[48;2;1;2;3;4mu\x1b[m");
+    assert_eq!(
+        line.spans,
+        vec![Span::styled(
+            "u",
+            Style::default()
+                .bg(Color::Rgb(1, 2, 3))
+                .add_modifier(Modifier::UNDERLINED)
+        )]
+    );
+}
+
+#[test]
+fn test_remote_hello_and_reply() {
+    use crate::remote::{hello_line, parse_hello, parse_reply, session_matches, tokens_match};
+    use crate::resolve::ResolveOptions;
+
+    let line = hello_line("s3cret", "my session");
+    assert_eq!(parse_hello(&line), Some(("s3cret", "my session")));
+    assert_eq!(parse_hello("nds-remote s3cret\n"), None);
+    assert_eq!(parse_hello("GET / HTTP/1.1\n"), None);
+
+    assert_eq!(parse_reply("ok abcd1234\n").unwrap(), "abcd1234");
+    let error = parse_reply("error authentication failed\n").unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Permission denied: authentication failed"
+    );
+
+    assert!(tokens_match("abc", "abc"));
+    assert!(!tokens_match("abd", "abc"));
+    assert!(!tokens_match("ab", "abc"));
+
+    let session = |id: &str, name: &str, index: u32| {
+        let mut session = Session::new(id.to_string(), 1, PathBuf::from("/tmp/x.sock"));
+        session.name = Some(name.to_string());
+        session.index = Some(index);
+        session
+    };
+    let sessions = [
+        session("abcd1234", "build", 1),
+        session("abef5678", "web", 2),
+    ];
+    let options = ResolveOptions::default();
+    assert!(session_matches("abcd", "abcd1234", &sessions, &options));
+    assert!(session_matches("Build", "abcd1234", &sessions, &options));
+    assert!(session_matches("2", "abef5678", &sessions, &options));
+    assert!(!session_matches("ab", "abcd1234", &sessions, &options));
+    assert!(!session_matches("1234", "abcd1234", &sessions, &options));
+    assert!(!session_matches(
+        "ABCD1234", "abcd1234", &sessions, &options
+    ));
+    let ignore_id_case = ResolveOptions {
+        ignore_id_case: true,
+        ..Default::default()
+    };
+    assert!(session_matches(
+        "ABCD1234",
+        "abcd1234",
+        &sessions,
+        &ignore_id_case
+    ));
+}
+
+#[test]
+fn test_ssh_remote_command_is_quoted() {
+    use crate::remote::ok_line;
+    use crate::remote::ssh::{remote_command, shell_quote};
+
+    assert_eq!(shell_quote("abcd1234"), "abcd1234");
+    assert_eq!(shell_quote("my session"), "'my session'");
+    assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    assert_eq!(shell_quote("$(rm -rf ~)"), "'$(rm -rf ~)'");
+    assert_eq!(shell_quote(""), "''");
+
+    assert_eq!(
+        remote_command(&["attach", "--raw", "--framed", "web server"]),
+        "nds attach --raw --framed 'web server'"
+    );
+    assert_eq!(ok_line("abcd1234"), "ok abcd1234\n");
+}
+
+#[test]
+fn test_remote_bridge_copies_both_ways() {
+    use crate::remote::bridge;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::net::UnixStream;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut far = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (near, _) = listener.accept().unwrap();
+    let (local, mut daemon) = UnixStream::pair().unwrap();
+    let bridged = std::thread::spawn(move || bridge(near, local));
+
+    far.write_all(b"input").unwrap();
+    let mut buf = [0u8; 5];
+    daemon.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"input");
+
+    daemon.write_all(b"output").unwrap();
+    let mut buf = [0u8; 6];
+    far.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"output");
+
+    // The far end going away ends the bridge and closes the local side
+    drop(far);
+    bridged.join().unwrap().unwrap();
+    assert_eq!(daemon.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn test_memory_transport_behaves_like_a_socket() {
+    use crate::transport::{MemoryTransport, Transport};
+    use std::io::ErrorKind;
+    use std::net::Shutdown;
+    use std::time::Duration;
+
+    let (a, b) = MemoryTransport::pair();
+    a.send(b"ping").unwrap();
+    let mut buf = [0u8; 16];
+    assert_eq!(b.recv(&mut buf).unwrap(), 4);
+    assert_eq!(&buf[..4], b"ping");
+
+    // Reads take what is there, up to the buffer
+    b.send(b"pong!").unwrap();
+    let mut small = [0u8; 3];
+    assert_eq!(a.recv(&mut small).unwrap(), 3);
+    assert_eq!(a.recv(&mut buf).unwrap(), 2);
+
+    // Nothing arriving within the read timeout is WouldBlock
+    a.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+    assert_eq!(a.recv(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+
+    // A clone reads the same stream, and shutting down writes ends it
+    let clone = b.try_clone().unwrap();
+    a.send(b"x").unwrap();
+    assert_eq!(clone.recv(&mut buf).unwrap(), 1);
+    a.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(b.recv(&mut buf).unwrap(), 0);
+    assert!(a.raw_fd().is_none());
+
+    // Once every handle to one end is gone, the other can't send
+    drop(b);
+    drop(clone);
+    assert_eq!(a.send(b"y").unwrap_err().kind(), ErrorKind::BrokenPipe);
+    a.set_read_timeout(None).unwrap();
+    assert_eq!(a.recv(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn test_server_registry_follows_the_sessions_directory() {
+    use crate::resolve::ResolveOptions;
+    use crate::server::{Registry, SessionChange};
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    let dir = TempDir::new().unwrap();
+    // Make each change visible even within the directory's mtime granularity
+    let touch = |bump: u64| {
+        let mtime = SystemTime::now() + Duration::from_secs(bump);
+        fs::File::open(dir.path())
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+    };
+    let write = |session: &Session, bump: u64| {
+        let path = dir.path().join(format!("{}.json", session.id));
+        fs::write(&path, serde_json::to_string(session).unwrap()).unwrap();
+        touch(bump);
+    };
+
+    // This process stands in for the session's daemon, so it is alive
+    let mut session = Session::new(
+        "ab12cd34".to_string(),
+        std::process::id() as i32,
+        PathBuf::from("/tmp/ab12cd34.sock"),
+    );
+    write(&session, 1);
+
+    let mut registry = Registry::default();
+    let events = registry.refresh(dir.path());
+    assert!(
+        matches!(&events[..], [SessionChange::Created { session }] if session.id == "ab12cd34")
+    );
+    assert!(registry.refresh(dir.path()).is_empty());
+
+    session.name = Some("Build".to_string());
+    write(&session, 2);
+    let events = registry.refresh(dir.path());
+    assert!(matches!(&events[..], [SessionChange::Changed { session }]
+        if session.name.as_deref() == Some("Build")));
+
+    session.attached = true;
+    write(&session, 3);
+    let events = registry.refresh(dir.path());
+    assert!(matches!(&events[..], [SessionChange::Attached { .. }]));
+    session.attached = false;
+    write(&session, 4);
+    let events = registry.refresh(dir.path());
+    assert!(matches!(&events[..], [SessionChange::Detached { .. }]));
+
+    let options = ResolveOptions::default();
+    assert_eq!(
+        registry.resolve("ab12", &options).unwrap().session.id,
+        "ab12cd34"
+    );
+    assert_eq!(
+        registry.resolve("build", &options).unwrap().session.id,
+        "ab12cd34"
+    );
+    assert!(registry.resolve("nope", &options).is_err());
+
+    fs::remove_file(dir.path().join("ab12cd34.json")).unwrap();
+    touch(5);
+    let events = registry.refresh(dir.path());
+    assert!(matches!(&events[..], [SessionChange::Exited { id }] if id == "ab12cd34"));
+    assert!(registry.sessions().is_empty());
+
+    let event = serde_json::to_value(SessionChange::Killed { id: "x".into() }).unwrap();
+    assert_eq!(event, serde_json::json!({ "event": "killed", "id": "x" }));
+}
+
+#[test]
+fn test_rpc_params_from_args() {
+    use crate::server::params_from_args;
+    use serde_json::json;
+
+    let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        params_from_args(&args(&["session=web", "scrollback=true", "text=a=b"])).unwrap(),
+        json!({ "session": "web", "scrollback": true, "text": "a=b" })
+    );
+    assert_eq!(
+        params_from_args(&args(&[r#"{"session": "web"}"#])).unwrap(),
+        json!({ "session": "web" })
+    );
+    assert_eq!(params_from_args(&[]).unwrap(), json!({}));
+    assert!(params_from_args(&args(&["web"])).is_err());
+}
+
+#[test]
+fn test_resolve_session() {
+    use crate::error::NdsError;
+    use crate::resolve::{resolve, MatchKind, ResolveOptions};
+
+    let session = |id: &str, name: Option<&str>, index: u32| {
+        let mut session = Session::new(id.to_string(), 1, PathBuf::from("/tmp/x.sock"));
+        session.name = name.map(str::to_string);
+        session.index = Some(index);
+        session
+    };
+    let sessions = vec![
+        session("ab12cd34", Some("web"), 1),
+        session("ab99ef00", Some("web-2"), 2),
+        session("cd34ab12", Some("Build"), 3),
+        session("ef56ab78", Some("ab"), 4),
+    ];
+    let options = ResolveOptions::default();
+    let pick = |query: &str, options: &ResolveOptions| {
+        resolve(query, &sessions, options).map(|r| (r.session.id, r.matched_by))
+    };
+
+    assert_eq!(
+        pick("2", &options).unwrap(),
+        ("ab99ef00".to_string(), MatchKind::Index)
+    );
+    assert_eq!(
+        pick("cd34ab12", &options).unwrap(),
+        ("cd34ab12".to_string(), MatchKind::Id)
+    );
+    assert_eq!(
+        pick("cd3", &options).unwrap(),
+        ("cd34ab12".to_string(), MatchKind::IdPrefix)
+    );
+    assert_eq!(
+        pick("bu", &options).unwrap(),
+        ("cd34ab12".to_string(), MatchKind::NamePrefix)
+    );
+
+    // An exact name wins over longer names and over ID prefixes...
+    assert_eq!(
+        pick("WEB", &options).unwrap(),
+        ("ab12cd34".to_string(), MatchKind::Name)
+    );
+    assert_eq!(
+        pick("ab", &options).unwrap(),
+        ("ef56ab78".to_string(), MatchKind::Name)
+    );
+    // ...unless that is turned off
+    let prefixes_only = ResolveOptions {
+        exact_wins: false,
+        ..options
+    };
+    match pick("web", &prefixes_only) {
+        Err(NdsError::AmbiguousSession { query, matches }) => {
+            assert_eq!(query, "web");
+            assert_eq!(matches.len(), 2);
+        }
+        other => panic!("expected an ambiguous match, got {:?}", other),
+    }
+    assert!(matches!(
+        pick("ab", &prefixes_only),
+        Err(NdsError::AmbiguousSession { .. })
+    ));
+
+    // IDs are matched case-sensitively unless asked otherwise
+    assert!(matches!(
+        pick("CD34", &options),
+        Err(NdsError::SessionNotFound(_))
+    ));
+    let any_case = ResolveOptions {
+        ignore_id_case: true,
+        ..options
+    };
+    assert_eq!(
+        pick("CD34", &any_case).unwrap(),
+        ("cd34ab12".to_string(), MatchKind::IdPrefix)
+    );
+}
@@ -0,0 +1,180 @@
+use crate::ui::Theme;
+use crate::Result;
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+    backend::{Backend, CrosstermBackend},
+    layout::{Alignment, Constraint, Direction, Layout, Rect},
+    widgets::{Block, Borders, Paragraph},
+    Frame, Terminal,
+};
+use std::io;
+
+/// What a key press did to a [`TextInput`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputEvent {
+    /// Keep editing
+    Continue,
+    /// Enter was pressed with this value
+    Submit(String),
+    /// Esc or Ctrl+C was pressed
+    Cancel,
+}
+
+/// Single-line text field drawn as a bordered box
+#[derive(Debug, Clone, Default)]
+pub struct TextInput {
+    value: String,
+}
+
+impl TextInput {
+    pub fn new(initial: &str) -> Self {
+        Self {
+            value: initial.to_string(),
+        }
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn handle_key(&mut self, code: KeyCode, modifiers: KeyModifiers) -> InputEvent {
+        match code {
+            KeyCode::Esc => InputEvent::Cancel,
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => InputEvent::Cancel,
+            // Ctrl+U clears the pre-filled value, as in a shell
+            KeyCode::Char('u') if modifiers.contains(KeyModifiers::CONTROL) => {
+                self.value.clear();
+                InputEvent::Continue
+            }
+            KeyCode::Enter => InputEvent::Submit(self.value.clone()),
+            KeyCode::Backspace => {
+                self.value.pop();
+                InputEvent::Continue
+            }
+            KeyCode::Char(c) => {
+                self.value.push(c);
+                InputEvent::Continue
+            }
+            _ => InputEvent::Continue,
+        }
+    }
+
+    pub fn render(&self, f: &mut Frame, area: Rect, title: &str) {
+        let theme = Theme::current();
+        let input_block = Block::default()
+            .title(title.to_string())
+            .borders(Borders::ALL)
+            .border_style(theme.input);
+
+        let input = Paragraph::new(self.value.as_str())
+            .style(theme.text)
+            .block(input_block);
+
+        f.render_widget(input, area);
+
+        // Put the terminal cursor at the end of the text
+        let x = area.x + 1 + self.value.chars().count() as u16;
+        f.set_cursor_position((x.min(area.right().saturating_sub(2)), area.y + 1));
+    }
+}
+
+/// Full-screen prompt for a single line of text.
+///
+/// `on_submit` is called with the entered text; if it fails the error is
+/// shown and the prompt stays open. Returns `None` when cancelled.
+pub fn prompt<F>(header: &str, title: &str, initial: &str, on_submit: F) -> Result<Option<String>>
+where
+    F: FnMut(&str) -> Result<()>,
+{
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let backend = CrosstermBackend::new(stdout);
+    let mut terminal = Terminal::new(backend)?;
+
+    let result = run_prompt(&mut terminal, header, title, initial, on_submit);
+
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+
+    result
+}
+
+fn run_prompt<B, F>(
+    terminal: &mut Terminal<B>,
+    header: &str,
+    title: &str,
+    initial: &str,
+    mut on_submit: F,
+) -> Result<Option<String>>
+where
+    B: Backend,
+    F: FnMut(&str) -> Result<()>,
+{
+    let mut input = TextInput::new(initial);
+    let mut error: Option<String> = None;
+
+    loop {
+        terminal.draw(|f| draw_prompt(f, &input, header, title, error.as_deref()))?;
+
+        if let Event::Key(key) = event::read()? {
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match input.handle_key(key.code, key.modifiers) {
+                InputEvent::Continue => error = None,
+                InputEvent::Cancel => return Ok(None),
+                InputEvent::Submit(value) => match on_submit(&value) {
+                    Ok(()) => return Ok(Some(value)),
+                    Err(e) => error = Some(e.to_string()),
+                },
+            }
+        }
+    }
+}
+
+fn draw_prompt(f: &mut Frame, input: &TextInput, header: &str, title: &str, error: Option<&str>) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .margin(1)
+        .constraints([
+            Constraint::Length(3),
+            Constraint::Length(3),
+            Constraint::Min(0),
+            Constraint::Length(3),
+        ])
+        .split(f.area());
+    let theme = Theme::current();
+
+    let header = Paragraph::new(header.to_string())
+        .style(theme.title)
+        .alignment(Alignment::Center)
+        .block(
+            Block::default()
+                .borders(Borders::BOTTOM)
+                .border_style(theme.dim),
+        );
+    f.render_widget(header, chunks[0]);
+
+    input.render(f, chunks[1], title);
+
+    if let Some(error) = error {
+        let message = Paragraph::new(error.to_string()).style(theme.warning);
+        f.render_widget(message, chunks[2]);
+    }
+
+    let footer = Paragraph::new("[Enter] Confirm  [Ctrl+U] Clear  [Esc] Cancel")
+        .style(theme.dim)
+        .alignment(Alignment::Center)
+        .block(
+            Block::default()
+                .borders(Borders::TOP)
+                .border_style(theme.dim),
+        );
+    f.render_widget(footer, chunks[3]);
+}
@@ -0,0 +1,252 @@
+//! Byte streams clients and daemons talk over.
+//!
+//! A client's `Connection` runs over any `Transport`: a session's Unix
+//! socket, TLS over TCP for remote attach, or an in-memory pipe in tests.
+//! Reads and writes take `&self`, as they do on `&UnixStream`, so one
+//! thread can wait for output while another sends input.
+//!
+//! Session daemons still accept Unix sockets only, because they ask the
+//! kernel who is on the other end; other transports reach them through
+//! `remote::bridge`.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// A stream between a client and a daemon
+pub trait Transport: fmt::Debug + Send + Sync {
+    /// Read what has arrived, waiting up to the read timeout for something
+    /// to. `Ok(0)` is the end of the stream. `WouldBlock` means the
+    /// timeout passed, or that only transport bookkeeping arrived.
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Send all of `buf`
+    fn send(&self, buf: &[u8]) -> io::Result<()>;
+
+    /// Whether data already taken off the wire is waiting, which polling
+    /// the fd wouldn't show
+    fn buffered(&self) -> bool {
+        false
+    }
+
+    /// The fd to poll for readability, if there is one
+    fn raw_fd(&self) -> Option<RawFd>;
+
+    /// Another handle to the same stream, e.g. for a thread that only reads
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+
+    /// Say goodbye and close the stream
+    fn close(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+/// `Read` on a shared transport, so `read_exact` and friends work on it
+impl Read for &dyn Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv(buf)
+    }
+}
+
+/// `Write` on a shared transport; every write is sent whole
+impl Write for &dyn Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Sockets, which already do all of this through `&self`
+macro_rules! socket_transport {
+    ($socket:ty) => {
+        impl Transport for $socket {
+            fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+                (&*self).read(buf)
+            }
+
+            fn send(&self, buf: &[u8]) -> io::Result<()> {
+                (&*self).write_all(buf)?;
+                (&*self).flush()
+            }
+
+            fn raw_fd(&self) -> Option<RawFd> {
+                Some(self.as_raw_fd())
+            }
+
+            fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+                Ok(Box::new(<$socket>::try_clone(self)?))
+            }
+
+            fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+                <$socket>::set_read_timeout(self, timeout)
+            }
+
+            fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+                <$socket>::set_write_timeout(self, timeout)
+            }
+
+            fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+                <$socket>::shutdown(self, how)
+            }
+        }
+    };
+}
+
+socket_transport!(UnixStream);
+socket_transport!(TcpStream);
+
+/// One end of an in-memory stream, for tests that play a client or a
+/// daemon without sockets or timing. It has no fd, so it can't be bridged.
+#[derive(Debug, Clone)]
+pub struct MemoryTransport {
+    end: Arc<MemoryEnd>,
+}
+
+#[derive(Debug)]
+struct MemoryEnd {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+/// Bytes going one way
+#[derive(Debug, Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    ready: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PipeState {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+
+impl Pipe {
+    fn lock(&self) -> MutexGuard<'_, PipeState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+impl MemoryTransport {
+    /// Two connected ends, like `UnixStream::pair`
+    pub fn pair() -> (Self, Self) {
+        let (a, b) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+        let end = |incoming: &Arc<Pipe>, outgoing: &Arc<Pipe>| MemoryTransport {
+            end: Arc::new(MemoryEnd {
+                incoming: incoming.clone(),
+                outgoing: outgoing.clone(),
+                read_timeout: Mutex::new(None),
+            }),
+        };
+        (end(&a, &b), end(&b, &a))
+    }
+}
+
+/// Once every handle to an end is gone, the other end reads the end of the
+/// stream and can't send any more
+impl Drop for MemoryEnd {
+    fn drop(&mut self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = *self
+            .end
+            .read_timeout
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let pipe = &self.end.incoming;
+        let mut state = pipe.lock();
+        while state.data.is_empty() && !state.closed {
+            state = match deadline {
+                None => pipe
+                    .ready
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner),
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Err(io::ErrorKind::WouldBlock.into());
+                    }
+                    pipe.ready
+                        .wait_timeout(state, left)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+            };
+        }
+        let n = buf.len().min(state.data.len());
+        for (slot, byte) in buf.iter_mut().zip(state.data.drain(..n)) {
+            *slot = byte;
+        }
+        Ok(n)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<()> {
+        let pipe = &self.end.outgoing;
+        let mut state = pipe.lock();
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.data.extend(buf);
+        pipe.ready.notify_all();
+        Ok(())
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self
+            .end
+            .read_timeout
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = timeout;
+        Ok(())
+    }
+
+    /// Sends never wait, so there is nothing to time out
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            self.end.incoming.close();
+        }
+        if matches!(how, Shutdown::Write | Shutdown::Both) {
+            self.end.outgoing.close();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+//! Pieces shared by the full-screen session picker (`nds`, `nds list -i`)
+//! and the switcher opened with `~s` inside a session.
+
+mod session_list;
+mod theme;
+
+pub use session_list::{group_by_tag, PickerRow, SessionList};
+pub use theme::Theme;
+
+use ratatui::text::{Line, Span};
+
+use crate::keymap::{KeyMap, PickerAction};
+
+/// A footer line of `(key, what it does)` pairs
+pub fn help_line(keys: &[(String, &str)], theme: &Theme) -> Line<'static> {
+    let mut spans = Vec::new();
+    for (n, (key, action)) in keys.iter().enumerate() {
+        if n > 0 {
+            spans.push(Span::raw("  "));
+        }
+        spans.push(Span::styled(format!("{} ", key), theme.dim));
+        spans.push(Span::styled(action.to_string(), theme.help));
+    }
+    Line::from(spans)
+}
+
+/// Footer pairs for picker actions, under the keys they are bound to;
+/// actions bound to no key are left out
+pub fn picker_help(actions: &[(&[PickerAction], &'static str)]) -> Vec<(String, &'static str)> {
+    let keys = &KeyMap::current().picker;
+    actions
+        .iter()
+        .filter_map(|(actions, what)| {
+            let labels: Vec<String> = actions
+                .iter()
+                .map(|action| keys.label(*action))
+                .filter(|label| !label.is_empty())
+                .collect();
+            (!labels.is_empty()).then(|| (labels.join(" "), *what))
+        })
+        .collect()
+}
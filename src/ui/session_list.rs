@@ -0,0 +1,690 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use chrono::Timelike;
+use crossterm::event::{KeyCode, KeyEvent, MouseButton, MouseEvent, MouseEventKind};
+use ratatui::{
+    layout::{Alignment, Constraint, Direction, Layout, Position, Rect},
+    style::Modifier,
+    text::{Line, Span},
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    Frame,
+};
+
+use crate::config::Config;
+use crate::error::Result;
+use crate::fuzzy::{highlight, match_session, SessionField, SessionMatch};
+use crate::manager::{SessionManager, FOREGROUND_WIDTH};
+use crate::resolve::{resolve, ResolveOptions};
+use crate::session::Session;
+use crate::session_sort::ListConfig;
+use crate::text_input::{InputEvent, TextInput};
+
+use super::Theme;
+
+/// A line in the picker: either a group or tag heading, or a session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PickerRow {
+    Group(String),
+    Session(usize),
+}
+
+/// Rows of the highlighted session's screen shown under the list, at most
+const PREVIEW_LINES: u16 = 20;
+/// How often the preview is captured again while the selection stays put
+const PREVIEW_REFRESH: Duration = Duration::from_secs(1);
+/// Two clicks on a session closer together than this are a double click
+const DOUBLE_CLICK: Duration = Duration::from_millis(400);
+
+/// The highlighted session's screen, as its daemon last reported it
+struct Preview {
+    session_id: String,
+    /// The screen's rows, or why there are none
+    lines: std::result::Result<Vec<String>, String>,
+    captured_at: Instant,
+}
+
+/// The list of sessions the picker and the in-session switcher both show:
+/// sessions under their group and tag headings in the configured order,
+/// the `/` filter, marks, and a preview of the highlighted session
+pub struct SessionList {
+    sessions: Vec<Session>,
+    /// Attached clients per session, asked of each daemon once per listing
+    client_counts: Vec<usize>,
+    /// What each session is running, asked at the same time
+    running: Vec<String>,
+    rows: Vec<PickerRow>,
+    /// The rows without a filter, under their headings
+    grouped: Vec<PickerRow>,
+    /// What the `/` filter is set to; empty shows every session
+    query: String,
+    /// Where the filter matched each session
+    matches: Vec<Option<SessionMatch>>,
+    /// How sessions are ordered under their headings
+    order: ListConfig,
+    state: ListState,
+    current_session_id: Option<String>,
+    /// Sessions marked with space, by ID
+    marked: HashSet<String>,
+    preview: Option<Preview>,
+    theme: Theme,
+    /// Where the list was last drawn, to find the row clicked on
+    list_area: Rect,
+    /// When a row was last clicked, and which, for double clicks
+    last_click: Option<(Instant, usize)>,
+}
+
+impl SessionList {
+    /// List `sessions` in the configured order, `current_session_id` being
+    /// the one this was opened from
+    pub fn new(sessions: Vec<Session>, current_session_id: Option<String>) -> Self {
+        let mut list = Self {
+            sessions: Vec::new(),
+            client_counts: Vec::new(),
+            running: Vec::new(),
+            rows: Vec::new(),
+            grouped: Vec::new(),
+            query: String::new(),
+            matches: Vec::new(),
+            order: Config::load().unwrap_or_default().list,
+            state: ListState::default(),
+            current_session_id,
+            marked: HashSet::new(),
+            preview: None,
+            theme: Theme::current().clone(),
+            list_area: Rect::default(),
+            last_click: None,
+        };
+        list.show(sessions);
+        list
+    }
+
+    /// List `sessions`, keeping the selection on the same session if it is
+    /// still there
+    pub fn show(&mut self, mut sessions: Vec<Session>) {
+        let selected = self.selected().map(|session| session.id.clone());
+        let row = self.state.selected().unwrap_or(0);
+
+        self.order.apply(&mut sessions);
+        self.grouped = group_by_tag(&mut sessions);
+        self.client_counts = sessions.iter().map(|s| s.get_client_count()).collect();
+        self.running = sessions
+            .iter()
+            .map(|s| s.foreground_label(FOREGROUND_WIDTH).unwrap_or_default())
+            .collect();
+        self.marked
+            .retain(|id| sessions.iter().any(|session| session.id == *id));
+        self.sessions = sessions;
+        self.filter_rows();
+
+        let same = selected.and_then(|id| {
+            self.rows
+                .iter()
+                .position(|r| matches!(r, PickerRow::Session(i) if self.sessions[*i].id == id))
+        });
+        // Otherwise the session that took its place, or the last one
+        let near = || {
+            let sessions = || {
+                self.rows
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, r)| matches!(r, PickerRow::Session(_)))
+                    .map(|(i, _)| i)
+            };
+            sessions()
+                .find(|i| *i >= row)
+                .or_else(|| sessions().next_back())
+        };
+        self.state.select(same.or_else(near));
+    }
+
+    /// How many sessions the filter lets through
+    pub fn shown(&self) -> usize {
+        self.rows
+            .iter()
+            .filter(|row| matches!(row, PickerRow::Session(_)))
+            .count()
+    }
+
+    pub fn selected(&self) -> Option<&Session> {
+        match self.state.selected().and_then(|row| self.rows.get(row)) {
+            Some(PickerRow::Session(i)) => Some(&self.sessions[*i]),
+            _ => None,
+        }
+    }
+
+    pub fn theme(&self) -> &Theme {
+        &self.theme
+    }
+
+    /// Narrow the rows to the sessions the filter matches, best first
+    fn filter_rows(&mut self) {
+        if self.query.is_empty() {
+            self.matches = vec![None; self.sessions.len()];
+            self.rows = self.grouped.clone();
+            return;
+        }
+        self.matches = self
+            .sessions
+            .iter()
+            .zip(&self.running)
+            .map(|(session, running)| match_session(&self.query, session, running))
+            .collect();
+        let mut found: Vec<(usize, i32)> = self
+            .matches
+            .iter()
+            .enumerate()
+            .filter_map(|(i, found)| found.as_ref().map(|found| (i, found.score)))
+            .collect();
+        found.sort_by_key(|(_, score)| std::cmp::Reverse(*score));
+        self.rows = found
+            .into_iter()
+            .map(|(i, _)| PickerRow::Session(i))
+            .collect();
+    }
+
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    /// Filter by `query`, selecting the best match
+    pub fn set_query(&mut self, query: &str) {
+        if self.query == query {
+            return;
+        }
+        self.query = query.to_string();
+        self.filter_rows();
+        self.state.select(
+            self.rows
+                .iter()
+                .position(|r| matches!(r, PickerRow::Session(_))),
+        );
+    }
+
+    /// Pass a key to the filter being typed in `input`, Up and Down moving
+    /// through what it matches; false once it is closed
+    pub fn filter_key(&mut self, input: &mut TextInput, key: KeyEvent) -> bool {
+        match key.code {
+            KeyCode::Down => self.next(),
+            KeyCode::Up => self.previous(),
+            _ => match input.handle_key(key.code, key.modifiers) {
+                InputEvent::Continue => self.set_query(input.value()),
+                InputEvent::Cancel => {
+                    self.set_query("");
+                    return false;
+                }
+                // The list stays filtered
+                InputEvent::Submit(_) => return false,
+            },
+        }
+        true
+    }
+
+    /// Order the sessions by `order` instead of the configured order
+    pub fn sort_by(&mut self, order: ListConfig) {
+        self.order = order;
+        self.show(self.sessions.clone());
+    }
+
+    /// Move on to the next sort, remembering it for next time and for
+    /// `nds list`
+    pub fn cycle_sort(&mut self) -> Result<()> {
+        self.sort_by(ListConfig {
+            sort: self.order.sort.next(),
+            ..self.order
+        });
+        Config::save_setting("list", &self.order)
+    }
+
+    /// Mark the selected session, or unmark it, and move on
+    pub fn toggle_mark(&mut self) {
+        if let Some(id) = self.selected().map(|session| session.id.clone()) {
+            if !self.marked.remove(&id) {
+                self.marked.insert(id);
+            }
+            self.next();
+        }
+    }
+
+    /// The marked sessions, or else the selected one
+    pub fn marked_or_selected(&self) -> Vec<Session> {
+        if self.marked.is_empty() {
+            return self.selected().cloned().into_iter().collect();
+        }
+        self.sessions
+            .iter()
+            .filter(|session| self.marked.contains(&session.id))
+            .cloned()
+            .collect()
+    }
+
+    pub fn next(&mut self) {
+        self.step(1);
+    }
+
+    pub fn previous(&mut self) {
+        self.step(self.rows.len().saturating_sub(1));
+    }
+
+    /// Select the session `query` resolves to, if it does
+    pub fn select(&mut self, query: &str) -> bool {
+        let Ok(resolved) = resolve(query, &self.sessions, &ResolveOptions::default()) else {
+            return false;
+        };
+        let row = self.rows.iter().position(|row| {
+            matches!(row, PickerRow::Session(i) if self.sessions[*i].id == resolved.session.id)
+        });
+        if row.is_some() {
+            self.state.select(row);
+        }
+        row.is_some()
+    }
+
+    /// Select the session clicked on, or move the selection with the wheel;
+    /// true when a session was double-clicked
+    pub fn mouse(&mut self, event: MouseEvent) -> bool {
+        match event.kind {
+            MouseEventKind::ScrollDown => self.next(),
+            MouseEventKind::ScrollUp => self.previous(),
+            MouseEventKind::Down(MouseButton::Left) => {
+                let Some(row) = self
+                    .row_at(event.column, event.row)
+                    .filter(|&row| matches!(self.rows[row], PickerRow::Session(_)))
+                else {
+                    self.last_click = None;
+                    return false;
+                };
+                let double = self
+                    .last_click
+                    .is_some_and(|(at, clicked)| clicked == row && at.elapsed() < DOUBLE_CLICK);
+                self.state.select(Some(row));
+                // A third click starts over
+                self.last_click = (!double).then(|| (Instant::now(), row));
+                return double;
+            }
+            _ => {}
+        }
+        false
+    }
+
+    /// The row drawn at `column`, `row` on the screen, if any
+    fn row_at(&self, column: u16, row: u16) -> Option<usize> {
+        if !self.list_area.contains(Position::new(column, row)) {
+            return None;
+        }
+        let mut bottom = self.list_area.y;
+        for (i, picker_row) in self.rows.iter().enumerate().skip(self.state.offset()) {
+            bottom += match picker_row {
+                PickerRow::Group(_) => 1,
+                PickerRow::Session(i) => 1 + note_line(&self.sessions[*i]).is_some() as u16,
+            };
+            if row < bottom {
+                return Some(i);
+            }
+        }
+        None
+    }
+
+    /// Move the selection by `offset` rows (mod the row count), skipping
+    /// group headings
+    fn step(&mut self, offset: usize) {
+        let len = self.rows.len();
+        if len == 0 {
+            return;
+        }
+        let mut i = self.state.selected().unwrap_or(0);
+        for _ in 0..len {
+            i = (i + offset) % len;
+            if matches!(self.rows[i], PickerRow::Session(_)) {
+                break;
+            }
+        }
+        self.state.select(Some(i));
+    }
+
+    /// Capture the highlighted session's screen if it isn't the one shown,
+    /// or that one has been up a while
+    pub fn refresh_preview(&mut self) {
+        let Some(id) = self.selected().map(|session| session.id.clone()) else {
+            self.preview = None;
+            return;
+        };
+        let current = self.preview.as_ref().is_some_and(|preview| {
+            preview.session_id == id && preview.captured_at.elapsed() < PREVIEW_REFRESH
+        });
+        if !current {
+            let lines = SessionManager::capture_screen(&id).map_err(|e| e.to_string());
+            self.preview = Some(Preview {
+                session_id: id,
+                lines,
+                captured_at: Instant::now(),
+            });
+        }
+    }
+
+    /// Draw `title` over the list, with the filter if one is set, and on
+    /// the right how many sessions there are and how they are ordered
+    pub fn render_header(&self, f: &mut Frame, area: Rect, title: &str) {
+        let mut spans = vec![Span::raw(title.to_string())];
+        if !self.query.is_empty() {
+            spans.push(Span::raw("  "));
+            spans.push(Span::styled(format!("/{}", self.query), self.theme.notice));
+        }
+        let header = Paragraph::new(Line::from(spans))
+            .style(self.theme.dim)
+            .alignment(Alignment::Left)
+            .block(
+                Block::default()
+                    .borders(Borders::BOTTOM)
+                    .border_style(self.theme.dim),
+            );
+        f.render_widget(header, area);
+
+        let summary = Paragraph::new(self.summary())
+            .style(self.theme.dim)
+            .alignment(Alignment::Right);
+        f.render_widget(summary, Rect { height: 1, ..area });
+    }
+
+    /// How many sessions there are and how they are ordered
+    fn summary(&self) -> String {
+        let count = if self.query.is_empty() {
+            format!("{} sessions", self.sessions.len())
+        } else {
+            format!("{} of {} sessions", self.shown(), self.sessions.len())
+        };
+        format!(
+            "{} · by {}{}",
+            count,
+            self.order.sort,
+            if self.order.reverse { ", reversed" } else { "" }
+        )
+    }
+
+    /// Draw the list in `area`, with the highlighted session's screen under
+    /// it if there's room
+    pub fn render(&mut self, f: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self
+            .rows
+            .iter()
+            .map(|row| match row {
+                PickerRow::Group(tag) => ListItem::new(Line::from(Span::styled(
+                    format!(" {}", tag),
+                    self.theme.heading,
+                ))),
+                PickerRow::Session(i) => self.item(*i, area.width as usize),
+            })
+            .collect();
+
+        let sessions_list = List::new(items)
+            .block(Block::default().borders(Borders::NONE))
+            .highlight_style(self.theme.selected)
+            .highlight_symbol("");
+
+        let preview_height = (PREVIEW_LINES + 1).min(area.height / 2);
+        let (list_area, preview_area) = if preview_height >= 4 && self.preview.is_some() {
+            let areas = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Min(0), Constraint::Length(preview_height)])
+                .split(area);
+            (areas[0], Some(areas[1]))
+        } else {
+            (area, None)
+        };
+
+        f.render_stateful_widget(sessions_list, list_area, &mut self.state);
+        self.list_area = list_area;
+
+        if let Some((area, preview)) = preview_area.zip(self.preview.as_ref()) {
+            let name = self
+                .sessions
+                .iter()
+                .find(|session| session.id == preview.session_id)
+                .map_or_else(|| preview.session_id.clone(), Session::display_name);
+            let block = Block::default()
+                .borders(Borders::TOP)
+                .border_style(self.theme.dim)
+                .title(Span::styled(format!(" {} ", name), self.theme.detached));
+            let shown = area.height.saturating_sub(1) as usize;
+            let text: Vec<Line> = match &preview.lines {
+                Ok(lines) => lines[lines.len().saturating_sub(shown)..]
+                    .iter()
+                    .map(|line| Line::from(line.as_str()))
+                    .collect(),
+                Err(e) => vec![Line::from(Span::styled(
+                    format!("No preview: {}", e),
+                    self.theme.dim,
+                ))],
+            };
+            f.render_widget(Paragraph::new(text).block(block), area);
+        }
+    }
+
+    /// The row for session `i`, `width` columns wide
+    fn item(&self, i: usize, width: usize) -> ListItem<'static> {
+        let theme = &self.theme;
+        let session = &self.sessions[i];
+        let client_count = self.client_counts[i];
+        let running = &self.running[i];
+        let found = self.matches[i].as_ref();
+
+        let now = chrono::Utc::now().timestamp();
+        let created = session.created_at.timestamp();
+        let duration = now - created;
+        let uptime = format_duration(duration as u64);
+
+        // Check if this is the current attached session
+        let is_current = self.current_session_id.as_ref() == Some(&session.id);
+        let mark = if self.marked.contains(&session.id) {
+            theme.mark_icon.as_str()
+        } else {
+            " "
+        };
+
+        // Status indicator and the style of the status text on the right
+        let (status_icon, status_style) = if is_current {
+            (&theme.current_icon, theme.current)
+        } else if client_count > 0 {
+            (&theme.attached_icon, theme.attached)
+        } else if session.bell.is_some() {
+            (&theme.bell_icon, theme.bell)
+        } else {
+            (&theme.detached_icon, theme.detached)
+        };
+        let name_style = if is_current {
+            theme.current
+        } else {
+            theme.text
+        };
+
+        // Build the status text that appears on the right
+        let status_text = if is_current {
+            if client_count > 0 {
+                format!(
+                    "CURRENT SESSION · {} CLIENT{}",
+                    client_count,
+                    if client_count == 1 { "" } else { "S" }
+                )
+            } else {
+                "CURRENT SESSION".to_string()
+            }
+        } else if client_count > 0 {
+            format!(
+                "{} CLIENT{}",
+                client_count,
+                if client_count == 1 { "" } else { "S" }
+            )
+        } else if session.bell.is_some() {
+            "DETACHED · BELL".to_string()
+        } else {
+            "DETACHED".to_string()
+        };
+
+        // Format created time
+        let now = chrono::Local::now();
+        let local_time: chrono::DateTime<chrono::Local> = session.created_at.into();
+        let duration = now.signed_duration_since(local_time);
+
+        let created_time = if duration.num_days() > 0 {
+            format!(
+                "{}d, {:02}:{:02}",
+                duration.num_days(),
+                local_time.hour(),
+                local_time.minute()
+            )
+        } else {
+            local_time.format("%H:%M:%S").to_string()
+        };
+
+        // Truncate working dir if too long
+        let mut working_dir = session.working_dir.clone();
+        let mut dir_positions = found.map_or(Vec::new(), |f| {
+            f.positions_in(SessionField::WorkingDir).to_vec()
+        });
+        if working_dir.len() > 30 {
+            let cut = session.working_dir.len() - 27;
+            working_dir = format!("...{}", &session.working_dir[cut..]);
+            dir_positions = dir_positions
+                .into_iter()
+                .filter(|at| *at >= cut)
+                .map(|at| at - cut + 3)
+                .collect();
+        }
+        let name_positions = found.map_or(Vec::new(), |f| f.display_name_positions(session));
+        let command_positions = found.map_or(&[][..], |f| f.positions_in(SessionField::Command));
+
+        // Build left side with fixed widths
+        let left_side = format!(
+            " {} {:<25} │ PID {:<6} │ {:<8} │ {:<8} │ {:<30} │ {:<width$}",
+            status_icon,
+            session.display_name(),
+            session.pid,
+            uptime,
+            created_time,
+            working_dir,
+            running,
+            width = FOREGROUND_WIDTH
+        );
+
+        // Calculate padding for right alignment
+        let left_len = left_side.chars().count();
+        let status_len = status_text.chars().count();
+        let padding = width.saturating_sub(left_len + status_len + 2);
+
+        let separator = || Span::styled(" │ ", theme.dim);
+        let mut spans = vec![
+            Span::styled(mark.to_string(), theme.mark),
+            Span::styled(
+                format!("{} ", status_icon),
+                status_style.add_modifier(Modifier::BOLD),
+            ),
+        ];
+        spans.extend(self.column(&session.display_name(), 25, &name_positions, name_style));
+        spans.extend(vec![
+            separator(),
+            Span::styled(format!("PID {:<6}", session.pid), theme.dim),
+            separator(),
+            Span::styled(format!("{:<8}", uptime), theme.dim),
+            separator(),
+            Span::styled(format!("{:<8}", created_time), theme.dim),
+            separator(),
+        ]);
+        spans.extend(self.column(&working_dir, 30, &dir_positions, theme.dim));
+        spans.push(separator());
+        spans.extend(self.column(running, FOREGROUND_WIDTH, command_positions, theme.text));
+        spans.extend(vec![
+            Span::raw(" ".repeat(padding)),
+            Span::styled(
+                status_text,
+                if is_current || client_count > 0 || session.bell.is_some() {
+                    status_style
+                } else {
+                    theme.dim.add_modifier(Modifier::DIM)
+                },
+            ),
+        ]);
+        let mut content = vec![Line::from(spans)];
+        if let Some(note) = note_line(session) {
+            content.push(Line::from(Span::styled(
+                format!("     {}", note),
+                theme.dim.add_modifier(Modifier::ITALIC),
+            )));
+        }
+        ListItem::new(content)
+    }
+
+    /// A column of `width` characters holding `text`, with the characters a
+    /// filter matched picked out
+    fn column(
+        &self,
+        text: &str,
+        width: usize,
+        positions: &[usize],
+        style: ratatui::style::Style,
+    ) -> Vec<Span<'static>> {
+        let mut spans = highlight(text, positions, style, self.theme.matched);
+        let len = text.chars().count();
+        if len < width {
+            spans.push(Span::styled(" ".repeat(width - len), style));
+        }
+        spans
+    }
+}
+
+/// The line of a session's note shown under it; only the first one fits
+fn note_line(session: &Session) -> Option<&str> {
+    session.note.as_deref().and_then(|note| note.lines().next())
+}
+
+/// Order sessions by their group, or else their first tag, and build the
+/// picker rows, with a heading per group and tag. Without any groups or tags
+/// the list is left as it is.
+pub fn group_by_tag(sessions: &mut [Session]) -> Vec<PickerRow> {
+    if sessions
+        .iter()
+        .all(|s| s.group.is_none() && s.tags.is_empty())
+    {
+        return (0..sessions.len()).map(PickerRow::Session).collect();
+    }
+
+    // Groups first, then tags, then sessions with neither
+    sessions.sort_by(|a, b| heading_key(a).cmp(&heading_key(b)));
+
+    let mut rows = Vec::new();
+    let mut current_heading = None;
+    for (i, session) in sessions.iter().enumerate() {
+        let heading = match heading_key(session) {
+            (0, group) => format!("@{}", group),
+            (1, tag) => format!("#{}", tag),
+            _ => "untagged".to_string(),
+        };
+        if current_heading.as_ref() != Some(&heading) {
+            rows.push(PickerRow::Group(heading.clone()));
+            current_heading = Some(heading);
+        }
+        rows.push(PickerRow::Session(i));
+    }
+    rows
+}
+
+/// Where a session goes in the picker: its group, its first tag or neither,
+/// in that order
+fn heading_key(session: &Session) -> (u8, &str) {
+    match (&session.group, session.tags.first()) {
+        (Some(group), _) => (0, group),
+        (None, Some(tag)) => (1, tag),
+        (None, None) => (2, ""),
+    }
+}
+
+fn format_duration(seconds: u64) -> String {
+    if seconds < 60 {
+        format!("{}s", seconds)
+    } else if seconds < 3600 {
+        format!("{}m", seconds / 60)
+    } else if seconds < 86400 {
+        format!("{}h {}m", seconds / 3600, (seconds % 3600) / 60)
+    } else {
+        format!("{}d {}h", seconds / 86400, (seconds % 86400) / 3600)
+    }
+}
@@ -0,0 +1,241 @@
+use std::sync::OnceLock;
+
+use ratatui::style::{Color, Modifier, Style};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+/// Colors and icons of the picker, the `~s` switcher, text prompts and the
+/// scrollback viewer. Each style is written as words: a color (a name,
+/// `#rrggbb` or a 0-255 index), `on` and a background color, and any of
+/// `bold`, `dim`, `italic`, `underline`, `reverse` and `crossed-out`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Theme {
+    /// `false` drops every color, keeping bold, underline and the like;
+    /// unset, colors are on unless `NO_COLOR` is set
+    pub color: Option<bool>,
+    /// The session this picker was opened from
+    #[serde(with = "style_spec")]
+    pub current: Style,
+    /// Sessions with clients attached
+    #[serde(with = "style_spec")]
+    pub attached: Style,
+    /// Sessions that rang the bell while nobody was attached
+    #[serde(with = "style_spec")]
+    pub bell: Style,
+    #[serde(with = "style_spec")]
+    pub detached: Style,
+    /// Group and tag headings
+    #[serde(with = "style_spec")]
+    pub heading: Style,
+    /// The mark on sessions marked with space
+    #[serde(with = "style_spec")]
+    pub mark: Style,
+    /// The highlighted row
+    #[serde(with = "style_spec")]
+    pub selected: Style,
+    /// Characters the `/` filter matched
+    #[serde(with = "style_spec")]
+    pub matched: Style,
+    /// Session names, and text being typed
+    #[serde(with = "style_spec")]
+    pub text: Style,
+    /// Everything else: other columns, borders, help
+    #[serde(with = "style_spec")]
+    pub dim: Style,
+    /// What keys do, in the footer
+    #[serde(with = "style_spec")]
+    pub help: Style,
+    /// What an action did, and the filter in the title
+    #[serde(with = "style_spec")]
+    pub notice: Style,
+    /// Questions before doing something that can't be undone, and errors
+    #[serde(with = "style_spec")]
+    pub warning: Style,
+    /// Headings of full-screen prompts
+    #[serde(with = "style_spec")]
+    pub title: Style,
+    /// The box around text being typed
+    #[serde(with = "style_spec")]
+    pub input: Style,
+    /// What a search in the scrollback viewer found
+    #[serde(with = "style_spec")]
+    pub search_match: Style,
+    /// The match the viewer is on
+    #[serde(with = "style_spec")]
+    pub search_current: Style,
+    /// Text selected in the viewer's copy mode
+    #[serde(with = "style_spec")]
+    pub copy_selection: Style,
+    /// The viewer's status bar
+    #[serde(with = "style_spec")]
+    pub status_bar: Style,
+    pub current_icon: String,
+    pub attached_icon: String,
+    pub bell_icon: String,
+    pub detached_icon: String,
+    pub mark_icon: String,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            color: None,
+            current: Style::default()
+                .fg(Color::Cyan)
+                .add_modifier(Modifier::BOLD),
+            attached: Style::default().fg(Color::Green),
+            bell: Style::default().fg(Color::Yellow),
+            detached: Style::default().fg(Color::Gray),
+            heading: Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD),
+            mark: Style::default()
+                .fg(Color::Magenta)
+                .add_modifier(Modifier::BOLD),
+            // Reversed rather than a background color, which only reads on
+            // dark terminals
+            selected: Style::default().add_modifier(Modifier::REVERSED | Modifier::BOLD),
+            matched: Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD | Modifier::UNDERLINED),
+            // The terminal's own foreground, whatever its background
+            text: Style::default(),
+            dim: Style::default().fg(Color::DarkGray),
+            help: Style::default().fg(Color::Gray),
+            notice: Style::default().fg(Color::Yellow),
+            warning: Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+            title: Style::default()
+                .fg(Color::Cyan)
+                .add_modifier(Modifier::BOLD),
+            input: Style::default().fg(Color::Yellow),
+            search_match: Style::default().fg(Color::Black).bg(Color::Gray),
+            search_current: Style::default().fg(Color::Black).bg(Color::Yellow),
+            copy_selection: Style::default().fg(Color::Black).bg(Color::LightBlue),
+            status_bar: Style::default().fg(Color::Black).bg(Color::White),
+            current_icon: "★".to_string(),
+            attached_icon: "●".to_string(),
+            bell_icon: "!".to_string(),
+            detached_icon: "○".to_string(),
+            mark_icon: "✓".to_string(),
+        }
+    }
+}
+
+impl Theme {
+    /// The theme from the config file, read once per process, without
+    /// colors if they are off
+    pub fn current() -> &'static Theme {
+        static THEME: OnceLock<Theme> = OnceLock::new();
+        THEME.get_or_init(|| {
+            let theme = Config::load().unwrap_or_default().theme;
+            let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+            if theme.color == Some(true) {
+                // crossterm drops colors under NO_COLOR by itself
+                crossterm::style::force_color_output(true);
+            }
+            theme.resolve(no_color)
+        })
+    }
+
+    /// This theme as drawn: without colors if `color` is `false`, or it is
+    /// unset and `no_color` (`NO_COLOR` is set). Styles that only a color
+    /// set apart are reversed or underlined instead.
+    pub fn resolve(self, no_color: bool) -> Theme {
+        if self.color.unwrap_or(!no_color) {
+            return self;
+        }
+        let plain = |style: Style| Style::default().add_modifier(style.add_modifier);
+        let reversed = Style::default().add_modifier(Modifier::REVERSED);
+        Theme {
+            current: plain(self.current),
+            attached: plain(self.attached),
+            bell: plain(self.bell),
+            detached: plain(self.detached),
+            heading: plain(self.heading),
+            mark: plain(self.mark),
+            selected: plain(self.selected).add_modifier(Modifier::REVERSED),
+            matched: plain(self.matched).add_modifier(Modifier::UNDERLINED),
+            text: plain(self.text),
+            dim: plain(self.dim),
+            help: plain(self.help),
+            notice: plain(self.notice),
+            warning: plain(self.warning),
+            title: plain(self.title),
+            input: plain(self.input),
+            search_match: plain(self.search_match).add_modifier(Modifier::UNDERLINED),
+            search_current: reversed,
+            copy_selection: reversed,
+            status_bar: reversed,
+            ..self
+        }
+    }
+}
+
+/// Styles as the words they are written as in the config file
+mod style_spec {
+    use ratatui::style::{Color, Modifier, Style};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    const MODIFIERS: &[(&str, Modifier)] = &[
+        ("bold", Modifier::BOLD),
+        ("dim", Modifier::DIM),
+        ("italic", Modifier::ITALIC),
+        ("underline", Modifier::UNDERLINED),
+        ("reverse", Modifier::REVERSED),
+        ("crossed-out", Modifier::CROSSED_OUT),
+    ];
+
+    /// Read a style such as `bold yellow on #282828`
+    pub fn parse(spec: &str) -> Result<Style, String> {
+        let mut style = Style::default();
+        let mut words = spec.split_whitespace().map(str::to_lowercase);
+        while let Some(word) = words.next() {
+            if let Some((_, modifier)) = MODIFIERS.iter().find(|(name, _)| *name == word) {
+                style = style.add_modifier(*modifier);
+            } else if word == "on" {
+                let Some(background) = words.next() else {
+                    return Err(format!("'{}': 'on' needs a color after it", spec));
+                };
+                style = style.bg(color(&background, spec)?);
+            } else {
+                style = style.fg(color(&word, spec)?);
+            }
+        }
+        Ok(style)
+    }
+
+    fn color(word: &str, spec: &str) -> Result<Color, String> {
+        word.parse().map_err(|_| {
+            format!(
+                "'{}': '{}' is not a color, bold, dim, italic, underline, reverse or crossed-out",
+                spec, word
+            )
+        })
+    }
+
+    /// Write a style the way [`parse`] reads it
+    pub fn format(style: &Style) -> String {
+        let mut words: Vec<String> = MODIFIERS
+            .iter()
+            .filter(|(_, modifier)| style.add_modifier.contains(*modifier))
+            .map(|(name, _)| name.to_string())
+            .collect();
+        if let Some(foreground) = style.fg {
+            words.push(foreground.to_string().to_lowercase());
+        }
+        if let Some(background) = style.bg {
+            words.push(format!("on {}", background.to_string().to_lowercase()));
+        }
+        words.join(" ")
+    }
+
+    pub fn serialize<S: Serializer>(style: &Style, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format(style))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Style, D::Error> {
+        parse(&String::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
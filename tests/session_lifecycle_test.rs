@@ -130,6 +130,7 @@ fn test_multiple_sessions() {
     // Kill all multi-test sessions
     let mut cmd = Command::cargo_bin("nds").unwrap();
     cmd.arg("kill")
+        .arg("--force")
         .arg("multi-test-1")
         .arg("multi-test-2")
         .arg("multi-test-3")
@@ -168,7 +169,793 @@ fn test_history_command() {
     let mut cmd = Command::cargo_bin("nds").unwrap();
     cmd.arg("history").arg("--all").assert().success();
 
+    // A negative age would expire everything, so it is refused
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.args(["history", "clean", "--keep-days=-1", "--dry-run"])
+        .assert()
+        .failure();
+
     // Clean up
     let mut cmd = Command::cargo_bin("nds").unwrap();
     cmd.arg("kill").arg("history-test").assert().success();
 }
+
+#[test]
+fn test_kill_requires_confirmation_for_multiple_sessions() {
+    for i in 1..=2 {
+        let mut cmd = Command::cargo_bin("nds").unwrap();
+        cmd.arg("new")
+            .arg(format!("confirm-test-{}", i))
+            .arg("--no-attach")
+            .assert()
+            .success();
+    }
+
+    thread::sleep(Duration::from_millis(200));
+
+    // Without a terminal to confirm on, a multi-session kill is refused
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("kill")
+        .arg("confirm-test-*")
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("confirm-test-1"))
+        .stderr(predicate::str::contains("--force"));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("kill")
+        .arg("confirm-test-*")
+        .arg("--force")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Successfully killed 2 session(s)"));
+}
+
+#[test]
+fn test_new_attach_if_exists_reuses_named_session() {
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("new")
+        .arg("reuse-test")
+        .arg("--no-attach")
+        .arg("--attach-if-exists")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Created session:"));
+
+    thread::sleep(Duration::from_millis(100));
+
+    // Running the same command again must not create a duplicate
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("new")
+        .arg("reuse-test")
+        .arg("--no-attach")
+        .arg("--attach-if-exists")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("already exists"))
+        .stdout(predicate::str::contains("Created session:").not());
+
+    // The name is taken whatever its case, so it is found whatever its case
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("new")
+        .arg("Reuse-Test")
+        .arg("--no-attach")
+        .arg("--attach-if-exists")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("already exists"))
+        .stdout(predicate::str::contains("Created session:").not());
+
+    // Exactly one session carries the name, so a plain kill resolves it
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("kill").arg("reuse-test").assert().success();
+}
+
+#[test]
+fn test_duplicate_and_invalid_names_are_rejected() {
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("new")
+        .arg("dup-name-test")
+        .arg("--no-attach")
+        .assert()
+        .success();
+
+    thread::sleep(Duration::from_millis(100));
+
+    // Same name (in any case) is refused
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("new")
+        .arg("DUP-name-test")
+        .arg("--no-attach")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("already used"));
+
+    // Path separators are refused on rename
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("rename")
+        .arg("dup-name-test")
+        .arg("dup/name")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("path separator"));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("kill").arg("dup-name-test").assert().success();
+}
+
+#[test]
+fn test_session_is_addressable_by_index() {
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("new")
+        .arg("index-test")
+        .arg("--no-attach")
+        .assert()
+        .success();
+
+    thread::sleep(Duration::from_millis(100));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    let output = cmd.arg("info").arg("index-test").output().unwrap();
+    let output_str = String::from_utf8_lossy(&output.stdout);
+    let index = output_str
+        .lines()
+        .find_map(|line| line.strip_prefix("Index: "))
+        .expect("info should show the session index")
+        .trim()
+        .to_string();
+    assert!(index.parse::<u32>().is_ok());
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("info")
+        .arg(&index)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("index-test"));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("kill").arg(&index).assert().success();
+}
+
+#[test]
+fn test_tags_filter_list_and_kill() {
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("new")
+        .arg("tag-test-tagged")
+        .arg("--no-attach")
+        .assert()
+        .success();
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("new")
+        .arg("tag-test-plain")
+        .arg("--no-attach")
+        .assert()
+        .success();
+
+    thread::sleep(Duration::from_millis(100));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("tag")
+        .arg("tag-test-tagged")
+        .arg("tagtest-ci")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("#tagtest-ci"));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("tag")
+        .arg("tag-test-tagged")
+        .arg("bad tag")
+        .assert()
+        .failure();
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("list")
+        .arg("--tag")
+        .arg("tagtest-ci")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("tag-test-tagged"))
+        .stdout(predicate::str::contains("tag-test-plain").not());
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("kill")
+        .arg("--tag")
+        .arg("tagtest-ci")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Killed session"));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("kill").arg("tag-test-plain").assert().success();
+}
+
+#[test]
+fn test_selftest_passes() {
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("selftest")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("checks passed"));
+}
+
+#[test]
+fn test_session_note() {
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("new")
+        .arg("note-test")
+        .arg("--no-attach")
+        .assert()
+        .success();
+
+    thread::sleep(Duration::from_millis(100));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("note")
+        .arg("note-test")
+        .arg("nightly import job")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("info")
+        .arg("note-test")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Note: nightly import job"));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("note").arg("note-test").arg("").assert().success();
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("note")
+        .arg("note-test")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("has no note"));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("kill").arg("note-test").assert().success();
+}
+
+#[test]
+fn test_run_streams_output_and_returns_exit_code() {
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("run")
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg("echo run-output; exit 7")
+        .assert()
+        .code(7)
+        .stdout(predicate::str::contains("run-output"));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("run")
+        .arg("--")
+        .arg("/nonexistent/command")
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_wait_returns_exit_code_of_detached_run() {
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("run")
+        .arg("--name")
+        .arg("wait-test")
+        .arg("--detach")
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg("sleep 1; exit 5")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("wait").arg("wait-test").assert().code(5);
+
+    // The exit status stays available until the session is killed
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("wait").arg("wait-test").assert().code(5);
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("kill").arg("wait-test").assert().success();
+}
+
+#[test]
+fn test_run_respawns_on_failure() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let marker = dir.path().join("ran-once");
+    let script = format!(
+        "if [ -f {0} ]; then exit 0; fi; touch {0}; exit 1",
+        marker.display()
+    );
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("run")
+        .arg("--name")
+        .arg("respawn-test")
+        .arg("--detach")
+        .arg("--respawn")
+        .arg("on-failure")
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg(&script)
+        .assert()
+        .success();
+
+    // Fails the first time, then succeeds after the one-second backoff
+    thread::sleep(Duration::from_secs(3));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("info")
+        .arg("respawn-test")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Exited: code 0"))
+        .stdout(predicate::str::contains("respawned 1 time(s)"));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("kill").arg("respawn-test").assert().success();
+}
+
+#[test]
+fn test_restart_reruns_command_in_same_session() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let marker = dir.path().join("ran-once");
+    let script = format!(
+        "if [ -f {0} ]; then exit 2; fi; touch {0}; exit 1",
+        marker.display()
+    );
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("run")
+        .arg("--name")
+        .arg("restart-test")
+        .arg("--detach")
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg(&script)
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("wait").arg("restart-test").assert().code(1);
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("restart")
+        .arg("restart-test")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Restarted the shell of session"));
+
+    // The same session runs the command again
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("wait").arg("restart-test").assert().code(2);
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("kill").arg("restart-test").assert().success();
+}
+
+#[test]
+fn test_detach_outside_a_session() {
+    // Without a session argument, `nds detach` needs to run inside one
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.env_remove("NDS_SESSION_ID")
+        .arg("detach")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Not inside an nds session"));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("new")
+        .arg("detach-test")
+        .arg("--no-attach")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("detach")
+        .arg("detach-test")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "Detached all clients from session",
+        ));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("clients")
+        .arg("list")
+        .arg("detach-test")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("No clients attached"));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("detach")
+        .arg("detach-test")
+        .arg("--client")
+        .arg("deadbeef")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("No client deadbeef attached"));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("kill").arg("detach-test").assert().success();
+}
+
+#[test]
+fn test_resize_policy_per_session() {
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("new")
+        .arg("resize-test")
+        .arg("--no-attach")
+        .arg("--resize")
+        .arg("largest")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("resize")
+        .arg("resize-test")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("largest"));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("resize")
+        .arg("resize-test")
+        .arg("--cols")
+        .arg("100")
+        .arg("--rows")
+        .arg("30")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("PTY is now 100x30"));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("info")
+        .arg("resize-test")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Resize: 100x30"));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("resize")
+        .arg("resize-test")
+        .arg("--policy")
+        .arg("biggest")
+        .assert()
+        .failure();
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("kill").arg("resize-test").assert().success();
+}
+
+#[test]
+fn test_raw_attach_streams_input_and_output() {
+    use std::io::{Read, Write};
+    use std::process::Stdio;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("new")
+        .arg("raw-attach-test")
+        .arg("--no-attach")
+        .assert()
+        .success();
+
+    thread::sleep(Duration::from_millis(100));
+
+    let mut client = std::process::Command::new(assert_cmd::cargo::cargo_bin("nds"))
+        .args(["attach", "raw-attach-test", "--raw"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to start raw attach");
+    // The end of stdin stops the input but not the output
+    client
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"echo raw-$((6*7))\r")
+        .unwrap();
+
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let mut stdout = client.stdout.take().unwrap();
+    let reader = {
+        let output = Arc::clone(&output);
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            while let Ok(n @ 1..) = stdout.read(&mut buf) {
+                output.lock().unwrap().extend_from_slice(&buf[..n]);
+            }
+        })
+    };
+
+    // The shell may take a while to start and run the command
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_secs(10)
+        && !String::from_utf8_lossy(&output.lock().unwrap()).contains("raw-42")
+    {
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    // Detaching is what ends a raw attach
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("detach").arg("raw-attach-test").assert().success();
+    assert!(client.wait().unwrap().success());
+    reader.join().unwrap();
+
+    let output_str = String::from_utf8_lossy(&output.lock().unwrap()).to_string();
+    assert!(output_str.contains("raw-42"), "{}", output_str);
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("kill").arg("raw-attach-test").assert().success();
+}
+
+#[test]
+fn test_watch_runs_command_on_activity_and_silence() {
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("run")
+        .arg("--name")
+        .arg("watch-test")
+        .arg("--detach")
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg("sleep 1; echo tick; sleep 30")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("watch")
+        .arg("watch-test")
+        .arg("--exec")
+        .arg("echo fired $NDS_WATCH_EVENT $NDS_SESSION_NAME")
+        .timeout(Duration::from_secs(10))
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("fired activity watch-test"));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("watch")
+        .arg("watch-test")
+        .arg("--for")
+        .arg("silence")
+        .arg("--seconds")
+        .arg("1")
+        .arg("--exec")
+        .arg("echo fired $NDS_WATCH_EVENT")
+        .timeout(Duration::from_secs(10))
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("fired silence"));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("kill").arg("watch-test").assert().success();
+}
+
+#[test]
+fn test_hooks_run_on_create_and_exit() {
+    // Hooks come from config.json, so give this test a home of its own
+    let home = tempfile::tempdir().unwrap();
+    let log = home.path().join("hooks.log");
+    let config = serde_json::json!({
+        "hooks": {
+            "on-create": format!("echo $NDS_HOOK $NDS_SESSION_NAME >> {}", log.display()),
+            "on-exit": format!("echo $NDS_HOOK $NDS_EXIT_CODE >> {}", log.display()),
+        }
+    });
+    std::fs::write(home.path().join("config.json"), config.to_string()).unwrap();
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.env("NDS_HOME", home.path())
+        .arg("run")
+        .arg("--name")
+        .arg("hook-test")
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg("exit 3")
+        .timeout(Duration::from_secs(10))
+        .assert()
+        .code(3);
+
+    // Hooks run in the background
+    let mut logged = String::new();
+    for _ in 0..50 {
+        logged = std::fs::read_to_string(&log).unwrap_or_default();
+        if logged.lines().count() >= 2 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(logged.contains("on-create hook-test"), "{}", logged);
+    assert!(logged.contains("on-exit 3"), "{}", logged);
+}
+
+#[test]
+fn test_capture_scrollback_and_screen() {
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("run")
+        .arg("--name")
+        .arg("capture-test")
+        .arg("--detach")
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg("printf '\\033[31mred\\033[0m\\n'; seq 1 3; sleep 30")
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(1000));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("capture")
+        .arg("capture-test")
+        .arg("--scrollback")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\x1b[31mred\x1b[0m\n1\n2\n3\n"));
+
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("capture.log");
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("capture")
+        .arg("capture-test")
+        .arg("--scrollback")
+        .arg("--strip-ansi")
+        .arg("-o")
+        .arg(&file)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Saved 4 lines of scrollback"));
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "red\n1\n2\n3\n");
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("capture")
+        .arg("capture-test")
+        .assert()
+        .success()
+        .stdout("red\n1\n2\n3\n");
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("kill").arg("capture-test").assert().success();
+}
+
+#[test]
+fn test_debug_dump_reports_daemon_state() {
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("run")
+        .arg("--name")
+        .arg("dump-test")
+        .arg("--detach")
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg("printf '\\033[?2004h'; seq 1 3; sleep 30")
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(1000));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    let output = cmd
+        .arg("debug")
+        .arg("dump")
+        .arg("dump-test")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let dump: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(dump["clients"], serde_json::json!([]));
+    assert_eq!(dump["terminal"]["modes"], serde_json::json!([2004]));
+    assert_eq!(dump["pty"]["last_error"], serde_json::Value::Null);
+    assert!(dump["buffers"]["detached_output_bytes"].as_u64().unwrap() > 0);
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.arg("kill").arg("dump-test").assert().success();
+}
+
+#[test]
+fn test_logs_with_logging_on() {
+    // Logging is turned on in config.json, so give this test a home of its own
+    let home = tempfile::tempdir().unwrap();
+    let config = serde_json::json!({ "logging": { "strip_ansi": true } });
+    std::fs::write(home.path().join("config.json"), config.to_string()).unwrap();
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.env("NDS_HOME", home.path())
+        .arg("run")
+        .arg("--name")
+        .arg("log-test")
+        .arg("--detach")
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg("printf '\\033[1mbold\\033[0m\\n'; seq 1 3; sleep 30")
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(1000));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.env("NDS_HOME", home.path())
+        .arg("logs")
+        .arg("log-test")
+        .arg("-n")
+        .arg("2")
+        .assert()
+        .success()
+        .stdout("2\n3\n");
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.env("NDS_HOME", home.path())
+        .arg("logs")
+        .arg("log-test")
+        .assert()
+        .success()
+        .stdout("bold\n1\n2\n3\n");
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.env("NDS_HOME", home.path())
+        .arg("kill")
+        .arg("log-test")
+        .assert()
+        .success();
+}
+
+#[test]
+fn test_setenv_queues_changed_volatile_vars() {
+    // The pending exports live under NDS_HOME, so give this test a home of its own
+    let home = tempfile::tempdir().unwrap();
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.env("NDS_HOME", home.path())
+        .env("SSH_AUTH_SOCK", "/tmp/agent.old")
+        .env("DISPLAY", ":0")
+        .arg("run")
+        .arg("--name")
+        .arg("setenv-test")
+        .arg("--detach")
+        .arg("--")
+        .arg("sleep")
+        .arg("30")
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(500));
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.env("NDS_HOME", home.path())
+        .arg("setenv")
+        .arg("setenv-test")
+        .arg("SSH_AUTH_SOCK=/tmp/agent.new")
+        .arg("DISPLAY=")
+        .arg("TERM=vt100")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "DISPLAY SSH_AUTH_SOCK at its next prompt",
+        ));
+
+    // Only the volatile variables that changed reach the running shell
+    let pending: Vec<_> = std::fs::read_dir(home.path().join("env"))
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "pending"))
+        .collect();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(
+        std::fs::read_to_string(pending[0].path()).unwrap(),
+        "unset DISPLAY\nexport SSH_AUTH_SOCK='/tmp/agent.new'\n"
+    );
+
+    let mut cmd = Command::cargo_bin("nds").unwrap();
+    cmd.env("NDS_HOME", home.path())
+        .arg("kill")
+        .arg("setenv-test")
+        .assert()
+        .success();
+}
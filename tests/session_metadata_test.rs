@@ -0,0 +1,36 @@
+use detached_shell::Session;
+use std::path::PathBuf;
+use std::thread;
+
+#[test]
+fn test_concurrent_updates_keep_every_change() {
+    // The only test in this binary, so it can have NDS_HOME to itself
+    let home = tempfile::tempdir().unwrap();
+    std::env::set_var("NDS_HOME", home.path());
+
+    let session = Session::new(
+        "metadata".to_string(),
+        std::process::id() as i32,
+        PathBuf::from("/tmp/metadata.sock"),
+    );
+    session.save().unwrap();
+
+    // Like the daemon's activity writes racing `nds tag` and `nds link`
+    let writers: Vec<_> = (0..8)
+        .map(|n| {
+            thread::spawn(move || {
+                for i in 0..20 {
+                    Session::update("metadata", |s| s.tags.push(format!("t{}-{}", n, i))).unwrap();
+                    Session::update_activity("metadata", Some(chrono::Utc::now()), None).unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    let session = Session::load("metadata").unwrap();
+    assert_eq!(session.tags.len(), 8 * 20);
+    assert!(session.last_output.is_some());
+}